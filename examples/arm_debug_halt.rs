@@ -1,11 +1,16 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Result};
 use chrono;
 use log::{debug, error, info, trace, warn};
 use spin::mutex::Mutex;
+use std::time::Duration;
 
 extern crate libjtag;
 
+use libjtag::config::{Backend, Config};
 use libjtag::interface::ftdi_bitbang::FtdiBitBang;
+use libjtag::interface::ftdi_builder::FtdiBuilder;
+use libjtag::interface::ftdi_mpsse::FtdiMpsse;
+use libjtag::interface::JtagInterface;
 use libjtag::jtag::dap::*;
 use libjtag::jtag::jtag::{Jtag, TAP};
 use libjtag::target::arm64::*;
@@ -32,48 +37,65 @@ fn setup_logger() -> Result<(), fern::InitError> {
 }
 
 #[inline(never)]
+// --config board.toml で別のboardを使う。省略時はARM-USB-OCD-Hの組み込みprofile
+fn load_config() -> Result<Config> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => Ok(Config::builtin("arm-usb-ocd-h").unwrap()),
+        [option, path] if option == "--config" => Ok(Config::from_path(path)?),
+        _ => bail!("usage: arm_debug_halt [--config board.toml]"),
+    }
+}
+
 fn main() -> Result<()> {
     setup_logger().unwrap();
 
-    let interface = FtdiBitBang::new(0x15ba, 0x002a, 0, 1, 2, 3, 4, 5, 7);
-    let jtag = Mutex::new(Jtag::new(interface));
-    let tap = TAP {
-        jtag: &jtag,
-        ir_len: 4,
-    };
+    let config = load_config()?;
+    match config.adapter.backend {
+        Backend::BitBang => run(
+            FtdiBuilder::<FtdiBitBang>::from_config(&config.adapter).open()?,
+            &config,
+        ),
+        Backend::Mpsse => run(
+            FtdiBuilder::<FtdiMpsse>::from_config(&config.adapter).open()?,
+            &config,
+        ),
+    }
+}
 
-    let dap = DAP::new(tap);
-    let dap = Mutex::new(dap);
-    const MEMAP_DEBUG_BASE_CORE0: u64 = 0x80010000;
-    const MEMAP_CTI_BASE_CORE0: u64 = 0x80018000;
-    let mut target = A64Target {
-        dap: &dap,
-        baseaddr: MEMAP_DEBUG_BASE_CORE0,
-    };
+fn run<I: JtagInterface>(interface: I, config: &Config) -> Result<()> {
+    let mut jtag = Jtag::new(interface);
+    jtag.initialize()?;
+    let jtag = Mutex::new(jtag);
+    let tap = TAP::new_with_config(&jtag, &config.chain)?;
+
+    let dap = DAP::try_new_with_config(tap, &config.target)?;
+    let dap = DapHandle::new(dap);
+    let mut target = A64Target::new_with_config(dap.clone(), &config.target, 0)?;
     let mut cti_core0 = Cti {
-        dap: &dap,
-        baseaddr: MEMAP_CTI_BASE_CORE0,
+        dap: dap.clone(),
+        baseaddr: config.target.core(0)?.cti,
     };
 
-    // unlock oslock
-    target.oslar_write(0);
+    // check power and double lock, then unlock oslock
+    target.prepare_debug(true)?;
 
     debug!("Enter debug state");
-    let mut edscr = target.edscr_read();
-    debug!("before EDSCR.HDE = {:?}", target.edscr_read().HDE());
+    let mut edscr = target.edscr_read()?;
+    debug!("before EDSCR.HDE = {:?}", target.edscr_read()?.HDE());
     edscr.set_hde(1);
-    target.edscr_write(edscr);
-    debug!("after : EDSCR.HDE = {:?}", target.edscr_read().HDE());
+    target.edscr_write(edscr)?;
+    debug!("after : EDSCR.HDE = {:?}", target.edscr_read()?.HDE());
     // send halt to core0
-    cti_core0.enable();
-    cti_core0.channel_gate_disable(0);
-    cti_core0.output_trigger_enable(0, 0);
-    cti_core0.generate_pulse(0);
+    cti_core0.setup_default_routing()?;
+    cti_core0.halt_core()?;
+    let reason = target.wait_for_halt(PollBudget::Duration(Duration::from_millis(100)))?;
+    info!("halted: {:?}", reason);
 
     debug!("Read EDSCR to check state");
-    let edscr = target.edscr_read();
+    let edscr = target.edscr_read()?;
     debug!("RW bits: {:#b}", edscr.RW());
-    debug!("STATUS bits: {:#b}", edscr.STATUS());
+    debug!("halt status: {:?}", HaltStatus::from(&edscr));
 
     Ok(())
 }
@@ -7,7 +7,7 @@ use spin::mutex::Mutex;
 
 extern crate libjtag;
 
-use libjtag::interface::ftdi_bitbang::FtdiBitBang;
+use libjtag::interface::ftdi_bitbang::{BitBangPins, FtdiBitBang};
 use libjtag::jtag::dap::*;
 use libjtag::jtag::jtag::{Jtag, TAP};
 
@@ -36,17 +36,16 @@ fn setup_logger() -> Result<(), fern::InitError> {
 fn main() -> Result<()> {
     setup_logger().unwrap();
 
-    let interface = FtdiBitBang::new(0x15ba, 0x002a, 0, 1, 2, 3, 4, 5, 7);
-    let jtag = Mutex::new(Jtag::new(interface));
-    let tap = TAP {
-        jtag: &jtag,
-        ir_len: 4,
-    };
-    let mut dap = DAP::new(tap);
+    let interface = FtdiBitBang::new(0x15ba, 0x002a, BitBangPins::default(), Some(100_000))?;
+    let mut jtag = Jtag::new(interface);
+    jtag.initialize()?;
+    let jtag = Mutex::new(jtag);
+    let tap = TAP::new(&jtag, 4);
+    let mut dap = DAP::try_new(tap)?;
 
     const MEMAP_DEBUG_BASE_CORE0: u64 = 0x80010000;
-    dap.memap_tar_u64_write(MEMAP_DEBUG_BASE_CORE0 + Armv8DebugRegisterOffset::MIDR_EL1 as u64);
-    let (ack, data) = dap.memap_bd0(0, true);
+    dap.memap_tar_u64_write(MEMAP_DEBUG_BASE_CORE0 + Armv8DebugRegisterOffset::MIDR_EL1 as u64)?;
+    let (ack, data) = dap.memap_bd0(0, true)?;
     println!("MIDR ACK: {:?}", ack);
     println!("MIDR DATA: {:#x}", data);
 
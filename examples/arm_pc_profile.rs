@@ -0,0 +1,56 @@
+use anyhow::Result;
+use log::info;
+use spin::mutex::Mutex;
+use std::time::Duration;
+
+extern crate libjtag;
+
+use libjtag::interface::ftdi_bitbang::{BitBangPins, FtdiBitBang};
+use libjtag::jtag::dap::*;
+use libjtag::jtag::jtag::{Jtag, TAP};
+use libjtag::target::arm64::*;
+
+fn setup_logger() -> Result<(), fern::InitError> {
+    fern::Dispatch::new()
+        .format(|out, message, record| {
+            out.finish(format_args!(
+                "[{}][{}] {}",
+                record.target(),
+                record.level(),
+                message
+            ))
+        })
+        .level(log::LevelFilter::Info)
+        .chain(std::io::stdout())
+        .apply()?;
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    setup_logger().unwrap();
+
+    let interface = FtdiBitBang::new(0x15ba, 0x002a, BitBangPins::default(), Some(1_000_000))?;
+    let mut jtag = Jtag::new(interface);
+    jtag.initialize()?;
+    let jtag = Mutex::new(jtag);
+    let tap = TAP::new(&jtag, 4);
+
+    let dap = DapHandle::new(DAP::try_new(tap)?);
+    const MEMAP_DEBUG_BASE_CORE0: u64 = 0x80010000;
+    let mut target = A64Target::new(dap.clone(), MEMAP_DEBUG_BASE_CORE0);
+    target.prepare_debug(true)?;
+
+    // coreは止めずに1秒間PCを集める
+    let samples = target.profile(Duration::from_secs(1), Duration::from_millis(1))?;
+    info!("{} samples", samples.len());
+    for (pc, count) in pc_histogram(&samples).iter().take(20) {
+        println!(
+            "{:#018x} {:>6} {:>5.1}%",
+            pc,
+            count,
+            *count as f64 * 100.0 / samples.len() as f64
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,41 @@
+use anyhow::Result;
+use std::time::Instant;
+
+extern crate libjtag;
+
+use libjtag::interface::ftdi_bitbang::{BitBangPins, FtdiBitBang};
+use libjtag::jtag::jtag::Jtag;
+
+// FtdiBitBangでDR shiftを繰り返して1回あたりの時間を測る
+// 35bitはDPACC/APACC 1回分の長さ
+const DR_BITS: [usize; 3] = [35, 256, 4096];
+const ITERATIONS: usize = 100;
+
+fn main() -> Result<()> {
+    env_logger::init();
+
+    let interface = FtdiBitBang::new(0x15ba, 0x002a, BitBangPins::default(), Some(1_000_000))?;
+    let mut jtag = Jtag::new(interface);
+    jtag.initialize()?;
+
+    for bits in DR_BITS.iter() {
+        let mut data = vec![false; *bits];
+        let start = Instant::now();
+        for i in 0..ITERATIONS {
+            for (j, x) in data.iter_mut().enumerate() {
+                *x = (i + j) % 3 == 0;
+            }
+            jtag.read_write_dr(&mut data, true, false, false)?;
+        }
+        let elapsed = start.elapsed();
+        println!(
+            "{} x {}bit DR shifts in {:?} ({:?} per shift, {:.1} kbit/s)",
+            ITERATIONS,
+            bits,
+            elapsed,
+            elapsed / ITERATIONS as u32,
+            (bits * ITERATIONS) as f64 / elapsed.as_secs_f64() / 1000.0
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,179 @@
+// payloadをSRAMに置いて実行し、終わったところで止めてmailboxを読む
+// boot_payload [--config board.toml] --image payload.bin --load ADDR --entry ADDR --done ADDR --mailbox ADDR
+//
+// 1. profileの[target] resetに従ってcore 0を止める("srst"ならSRSTでresetしてreset catch)
+// 2. imageをMEM-APで書いて読み戻す
+// 3. PCをentryにし、doneにhardware breakpointを置いてresume
+// 4. breakpointで止まったらmailboxの32bitを読み、breakpointを消してdetach
+use anyhow::{bail, Context, Result};
+use log::info;
+use spin::mutex::Mutex;
+use std::path::PathBuf;
+use std::time::Duration;
+
+extern crate libjtag;
+
+use libjtag::config::{Backend, Config};
+use libjtag::interface::ftdi_bitbang::FtdiBitBang;
+use libjtag::interface::ftdi_builder::FtdiBuilder;
+use libjtag::interface::ftdi_mpsse::FtdiMpsse;
+use libjtag::interface::{InterfaceError, JtagInterface};
+use libjtag::jtag::dap::*;
+use libjtag::jtag::jtag::{Jtag, TAP};
+use libjtag::target::arm64::*;
+use libjtag::tools::memfile;
+
+// payloadが終わるまで待つ時間
+const RUN_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Payload {
+    pub image: PathBuf,
+    // binaryを置くaddress
+    pub load: u64,
+    pub entry: u64,
+    // ここに来たら終わり。breakpointを置く
+    pub done: u64,
+    // payloadが結果を書く32bit
+    pub mailbox: u64,
+}
+
+fn parse_addr(text: &str) -> Result<u64> {
+    let value = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    value.with_context(|| format!("invalid address {:?}", text))
+}
+
+// --config board.toml で別のboardを使う。省略時はARM-USB-OCD-Hの組み込みprofile
+pub fn parse_args(args: &[String]) -> Result<(Config, Payload)> {
+    let mut config = None;
+    let mut image = None;
+    let (mut load, mut entry, mut done, mut mailbox) = (None, None, None, None);
+    let mut args = args.iter();
+    while let Some(option) = args.next() {
+        let value = match args.next() {
+            Some(value) => value,
+            None => bail!("{} needs a value", option),
+        };
+        match option.as_str() {
+            "--config" => config = Some(Config::from_path(value)?),
+            "--image" => image = Some(PathBuf::from(value)),
+            "--load" => load = Some(parse_addr(value)?),
+            "--entry" => entry = Some(parse_addr(value)?),
+            "--done" => done = Some(parse_addr(value)?),
+            "--mailbox" => mailbox = Some(parse_addr(value)?),
+            _ => bail!("unknown option {}", option),
+        }
+    }
+    let payload = match (image, load, entry, done, mailbox) {
+        (Some(image), Some(load), Some(entry), Some(done), Some(mailbox)) => Payload {
+            image,
+            load,
+            entry,
+            done,
+            mailbox,
+        },
+        _ => bail!(
+            "usage: boot_payload [--config board.toml] --image FILE --load ADDR --entry ADDR --done ADDR --mailbox ADDR"
+        ),
+    };
+    let config = config.unwrap_or_else(|| Config::builtin("arm-usb-ocd-h").unwrap());
+    Ok((config, payload))
+}
+
+// coreを止めてpayloadを走らせ、mailboxの値を返す
+// 途中で失敗した場合もDebugSessionのdropでbreakpointを消してdetachする
+pub fn boot<T, M>(
+    core: CoreHandle<T>,
+    memory: &DapHandle<M>,
+    policy: ResetPolicy,
+    iface_reset: impl FnOnce() -> Result<(), InterfaceError>,
+    payload: &Payload,
+) -> Result<u32>
+where
+    T: DebugPort + MemoryAccessPort,
+    M: MemoryAccessPort,
+{
+    let mut session = DebugSession::new(core);
+    let reason = session.attach(policy, iface_reset)?;
+    info!("halted: {:?}", reason);
+
+    let written = memfile::load_memory(
+        &mut *memory.lock(),
+        &payload.image,
+        Some(payload.load),
+        &mut |_, _| (),
+    )?;
+    info!(
+        "loaded and verified {} bytes at {:#x}",
+        written, payload.load
+    );
+
+    session.target.write_pc(payload.entry)?;
+    session.target.set_breakpoint(0, payload.done)?;
+    session.resume()?;
+    let reason = session
+        .target
+        .wait_for_halt(PollBudget::Duration(RUN_TIMEOUT))?;
+    let pc = session.target.read_pc()?;
+    if reason != HaltReason::Breakpoint || pc != payload.done {
+        bail!("payload stopped at {:#x} ({:?})", pc, reason);
+    }
+    let value = memory.lock().try_mem_read_u32(payload.mailbox)?;
+    session.close()?;
+    Ok(value)
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (config, payload) = parse_args(&args)?;
+    let value = match config.adapter.backend {
+        Backend::BitBang => run(
+            FtdiBuilder::<FtdiBitBang>::from_config(&config.adapter).open()?,
+            &config,
+            &payload,
+        ),
+        Backend::Mpsse => run(
+            FtdiBuilder::<FtdiMpsse>::from_config(&config.adapter).open()?,
+            &config,
+            &payload,
+        ),
+    }?;
+    println!("mailbox {:#x} = {:#010x}", payload.mailbox, value);
+    Ok(())
+}
+
+fn run<I: JtagInterface>(interface: I, config: &Config, payload: &Payload) -> Result<u32> {
+    let mut jtag = Jtag::new(interface);
+    jtag.initialize()?;
+    let jtag = Mutex::new(jtag);
+
+    let dap = DapHandle::new(DAP::try_new_with_config(
+        TAP::new_with_config(&jtag, &config.chain)?,
+        &config.target,
+    )?);
+    let mut memory =
+        DAP::try_new_with_config(TAP::new_with_config(&jtag, &config.chain)?, &config.target)?;
+    memory.select_ap(config.target.memory_ap.unwrap_or(config.target.ap));
+    let memory = DapHandle::new(memory);
+
+    let base = config.target.core(0)?;
+    let core = CoreHandle::new(
+        A64Target::new_with_config(dap.clone(), &config.target, 0)?,
+        Cti {
+            dap,
+            baseaddr: base.cti,
+        },
+    );
+    boot(
+        core,
+        &memory,
+        config.target.reset,
+        || jtag.lock().reset_target(),
+        payload,
+    )
+}
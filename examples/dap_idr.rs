@@ -6,7 +6,7 @@ use spin::mutex::Mutex;
 
 extern crate libjtag;
 
-use libjtag::interface::ftdi_bitbang::FtdiBitBang;
+use libjtag::interface::ftdi_bitbang::{BitBangPins, FtdiBitBang};
 use libjtag::jtag::dap::*;
 use libjtag::jtag::jtag::{Jtag, TAP};
 
@@ -36,14 +36,13 @@ fn setup_logger() -> Result<(), fern::InitError> {
 fn main() -> Result<()> {
     setup_logger().unwrap();
 
-    let interface = FtdiBitBang::new(0x15ba, 0x002a, 0, 1, 2, 3, 4, 5, 7);
-    let jtag = Mutex::new(Jtag::new(interface));
-    let mut tap = TAP {
-        jtag: &jtag,
-        ir_len: 4,
-    };
+    let interface = FtdiBitBang::new(0x15ba, 0x002a, BitBangPins::default(), Some(100_000))?;
+    let mut jtag = Jtag::new(interface);
+    jtag.initialize()?;
+    let jtag = Mutex::new(jtag);
+    let tap = TAP::new(&jtag, 4);
 
-    let mut dap = DAP::new(tap);
+    let mut dap = DAP::try_new(tap)?;
     let address = MemapAddress::IDR as u8;
 
     // set APBANK to 0xF0
@@ -52,13 +51,13 @@ fn main() -> Result<()> {
     select.set_apbanksel(((address & 0xF0) >> 4) as u32);
     select.set_dpbanksel(0);
 
-    dap.dpacc(select.0, DpAddress::SELECT.into(), false);
-    let (ack, _) = dap.dpacc(0, DpAddress::RDBUFF.into(), true);
+    dap.dpacc(select.0, DpAddress::SELECT.into(), false)?;
+    let (ack, _) = dap.dpacc(0, DpAddress::RDBUFF.into(), true)?;
     println!("DP SELECT ACK: {:?}", ack);
 
     // read MEM-AP IDR
-    dap.apacc(0, (address & 0xF) >> 2, true);
-    let (ack, data) = dap.dpacc(0, DpAddress::RDBUFF.into(), true);
+    dap.apacc(0, (address & 0xF) >> 2, true)?;
+    let (ack, data) = dap.dpacc(0, DpAddress::RDBUFF.into(), true)?;
     println!("MEM-AP IDR ACK: {:?}", ack);
     println!("MEM-AP IDR DATA: {:#x}", data);
 
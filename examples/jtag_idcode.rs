@@ -5,7 +5,8 @@ use log::{debug, error, info, trace, warn};
 
 extern crate libjtag;
 
-use libjtag::interface::ftdi_bitbang::FtdiBitBang;
+use libjtag::interface::ftdi_bitbang::{BitBangPins, FtdiBitBang};
+use libjtag::jtag::bits;
 use libjtag::jtag::jtag::Jtag;
 
 fn setup_logger() -> Result<(), fern::InitError> {
@@ -33,33 +34,34 @@ fn setup_logger() -> Result<(), fern::InitError> {
 fn main() -> Result<()> {
     setup_logger().unwrap();
 
-    let interface = FtdiBitBang::new(0x15ba, 0x002a, 0, 1, 2, 3, 4, 5, 7);
-    // let interface = FtdiMpsse::new(0x15ba, 0x002a, 4, 5);
+    let interface = FtdiBitBang::new(0x15ba, 0x002a, BitBangPins::default(), Some(100_000))?;
+    // let interface = FtdiMpsse::new(0x15ba, 0x002a, 4, 5, Some(1_000_000))?;
     let mut jtag = Jtag::new(interface);
+    jtag.initialize()?;
 
     // move to reset
-    jtag.write_tms(&[true; 10]);
+    jtag.write_tms(&[true; 10])?;
 
     // move to Shift-IR
-    jtag.write_tms(&[false, true, true, false, false]);
+    jtag.write_tms(&[false, true, true, false, false])?;
 
     // set IDCODE instruction(0b1110)
-    jtag.raw_write_data(&[false, true, true, true], true);
+    jtag.raw_write_data(&[false, true, true, true], true)?;
 
     // move to Run/Idle via Update-IR from Exit-IR
-    jtag.write_tms(&[true, false]);
+    jtag.write_tms(&[true, false])?;
 
     // move to Shift-DR
-    jtag.write_tms(&[true, false, false]);
+    jtag.write_tms(&[true, false, false])?;
 
     // read IDCODE(32bit) from DR
     let mut data = [false; 32];
-    jtag.raw_read_data(&mut data, true);
+    jtag.raw_read_data(&mut data, true)?;
 
     // move to Run/Idle via Update-DR from Exit-DR
-    jtag.write_tms(&[true, false]);
+    jtag.write_tms(&[true, false])?;
 
-    let idcode = data.iter().rev().fold(0, |x, y| (x << 1) | *y as u32);
+    let idcode = bits::from_bools_lsb_first(&data) as u32;
     println!("IDCODE: {:#x}", idcode);
 
     Ok(())
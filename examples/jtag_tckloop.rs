@@ -9,7 +9,7 @@ use log::{debug, error, info, trace, warn};
 
 extern crate libjtag;
 
-use libjtag::interface::ftdi_bitbang::FtdiBitBang;
+use libjtag::interface::ftdi_bitbang::{BitBangPins, FtdiBitBang};
 use libjtag::jtag::jtag::Jtag;
 
 fn setup_logger() -> Result<(), fern::InitError> {
@@ -37,11 +37,12 @@ fn setup_logger() -> Result<(), fern::InitError> {
 fn main() -> Result<()> {
     setup_logger().unwrap();
 
-    let interface = FtdiBitBang::new(0x15ba, 0x002a, 0, 1, 2, 3, 4, 5, 7);
+    let interface = FtdiBitBang::new(0x15ba, 0x002a, BitBangPins::default(), Some(100_000))?;
     let mut jtag = Jtag::new(interface);
+    jtag.initialize()?;
 
     loop {
-        jtag.interface.raw_write(&[JtagBit::NONE; 10]);
+        jtag.interface.raw_write(&[JtagBit::NONE; 10])?;
         thread::sleep(time::Duration::from_millis(10));
     }
 
@@ -0,0 +1,53 @@
+use anyhow::Result;
+use std::time::{Duration, Instant};
+
+extern crate libjtag;
+
+use libjtag::interface::ftdi_mpsse::FtdiMpsse;
+use libjtag::jtag::jtag::Jtag;
+
+// 4096bitのDR shiftを繰り返して転送速度を測る
+const DR_BITS: usize = 4096;
+const ITERATIONS: usize = 100;
+
+fn main() -> Result<()> {
+    env_logger::init();
+
+    let interface = FtdiMpsse::new(0x15ba, 0x002a, 4, 5, Some(10_000_000))?;
+    let mut jtag = Jtag::new(interface);
+    jtag.initialize()?;
+
+    let mut data = [false; DR_BITS];
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        for (j, x) in data.iter_mut().enumerate() {
+            *x = (i + j) % 3 == 0;
+        }
+        jtag.read_write_dr(&mut data, true, false, false)?;
+    }
+    report("bool", start.elapsed());
+
+    // 同じbit列をbyteに詰めたまま送る
+    let mut packed = [0u8; DR_BITS / 8];
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        for (j, x) in packed.iter_mut().enumerate() {
+            *x = (0..8).fold(0, |acc, k| acc | (((i + j * 8 + k) % 3 == 0) as u8) << k);
+        }
+        jtag.read_write_dr_packed(&mut packed, DR_BITS, true)?;
+    }
+    report("packed", start.elapsed());
+    Ok(())
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let bits = (DR_BITS * ITERATIONS) as f64;
+    println!(
+        "{}: {} x {}bit DR shifts in {:?} ({:.1} kbit/s)",
+        name,
+        ITERATIONS,
+        DR_BITS,
+        elapsed,
+        bits / elapsed.as_secs_f64() / 1000.0
+    );
+}
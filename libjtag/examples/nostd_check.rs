@@ -0,0 +1,35 @@
+// libjtagがallocなしのno_std環境でbuildできることを確認する
+// cargo build -p libjtag --example nostd_check --no-default-features
+#![no_std]
+
+use libjtag::interface::gpio::{GpioBitbang, InputOutputPin};
+use libjtag::interface::InterfaceError;
+use libjtag::jtag::jtag::Jtag;
+
+// SIOのGPIO_OUT_SET/GPIO_OUT_CLR/GPIO_INを叩くpin(RP2040を想定)
+pub struct SioPin {
+    mask: u32,
+}
+
+const SIO_BASE: usize = 0xd000_0000;
+const GPIO_IN: usize = SIO_BASE + 0x04;
+const GPIO_OUT_SET: usize = SIO_BASE + 0x14;
+const GPIO_OUT_CLR: usize = SIO_BASE + 0x18;
+
+impl InputOutputPin for SioPin {
+    fn set(&self, high: bool) {
+        let register = if high { GPIO_OUT_SET } else { GPIO_OUT_CLR };
+        unsafe { (register as *mut u32).write_volatile(self.mask) }
+    }
+    fn is_high(&self) -> bool {
+        unsafe { (GPIO_IN as *const u32).read_volatile() & self.mask != 0 }
+    }
+}
+
+pub fn first_idcode() -> Result<Option<u32>, InterfaceError> {
+    let pin = |n: u32| SioPin { mask: 1 << n };
+    let interface = GpioBitbang::new(pin(2), pin(3), pin(4), pin(5));
+    let mut jtag = Jtag::new(interface);
+    let result = jtag.initialize()?;
+    Ok(result.devices().first().and_then(|x| x.raw()))
+}
@@ -0,0 +1,623 @@
+// adapter/chain/targetの設定をTOMLのprofileから読む
+// 読んだ値は検証してからFtdiBuilder/TAP/DAP/A64Targetにそのまま渡せる形にする
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::interface::pins::Signal as Pin;
+use crate::jtag::dap::{AddrRange, QuirkSet};
+use crate::target::arm64::{CoreBase, ResetPolicy, BCM2711_CORES};
+
+// ADBUS/ACBUSの16bit
+const PIN_POSITION_MAX: i64 = 15;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    // fileが読めない
+    Io(String),
+    // TOMLとして読めない、または型が合わない
+    Parse(String),
+    // 値はあるが使えない。keyは"adapter.pins.tck"のようなdotted key
+    Invalid { key: String, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(message) => write!(f, "failed to read config: {}", message),
+            ConfigError::Parse(message) => write!(f, "failed to parse config: {}", message),
+            ConfigError::Invalid { key, reason } => write!(f, "{}: {}", key, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn invalid(key: &str, reason: String) -> ConfigError {
+    ConfigError::Invalid {
+        key: key.to_string(),
+        reason,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    BitBang,
+    Mpsse,
+}
+
+impl FromStr for Backend {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bitbang" => Ok(Backend::BitBang),
+            "mpsse" => Ok(Backend::Mpsse),
+            x => Err(invalid(
+                "adapter.backend",
+                format!("unknown backend {:?}", x),
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdapterConfig {
+    pub backend: Backend,
+    pub vid: u16,
+    pub pid: u16,
+    pub serial: Option<String>,
+    pub tck_hz: Option<u32>,
+    // 位置の重複は検証済み
+    pub pins: Vec<(Pin, u8)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChainConfig {
+    // Jtag::devices()と同じくTDOに近い順
+    pub ir_lens: Vec<usize>,
+    // ir_lensの中で使うTAPの位置
+    pub tap: usize,
+}
+
+impl ChainConfig {
+    // chainにTAPが1つしかない場合
+    pub fn single(ir_len: usize) -> Self {
+        ChainConfig {
+            ir_lens: vec![ir_len],
+            tap: 0,
+        }
+    }
+
+    pub fn ir_len(&self) -> usize {
+        self.ir_lens[self.tap]
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cores {
+    // ROM tableから探す
+    Discover,
+    List(Vec<CoreBase>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TargetConfig {
+    // debug registerにつながるAP
+    pub ap: u8,
+    // system memoryにつながるAP
+    pub memory_ap: Option<u8>,
+    pub cores: Cores,
+    // IDCODEから分かるquirkに足す
+    pub quirks: QuirkSet,
+    // [[target.memory]]。WriteGuard::from_configで書き込みを断る範囲にする
+    pub memory: Vec<MemoryRegion>,
+    // "halt"か"srst"。attachでcoreを止める方法
+    pub reset: ResetPolicy,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryRegion {
+    pub name: String,
+    pub range: AddrRange,
+    pub writable: bool,
+    // writableでも書き込みを断る。PMICの設定registerなど壊すと戻せない範囲
+    pub protected: bool,
+}
+
+impl TargetConfig {
+    // ROM tableの探索はまだ無いので、"discover"の場合はここでerrorにする
+    pub fn cores(&self) -> Result<&[CoreBase], ConfigError> {
+        match &self.cores {
+            Cores::List(cores) => Ok(cores),
+            Cores::Discover => Err(invalid(
+                "target.cores",
+                "core discovery is not supported yet, list the core bases".to_string(),
+            )),
+        }
+    }
+
+    pub fn core(&self, n: usize) -> Result<CoreBase, ConfigError> {
+        let cores = self.cores()?;
+        cores.get(n).copied().ok_or_else(|| {
+            invalid(
+                "target.cores",
+                format!("core {} does not exist ({} cores)", n, cores.len()),
+            )
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub adapter: AdapterConfig,
+    pub chain: ChainConfig,
+    pub target: TargetConfig,
+}
+
+// 組み込みのprofile
+// Olimex ARM-USB-OCD-H
+pub const ARM_USB_OCD_H: &str = r#"
+[adapter]
+backend = "bitbang"
+vid = 0x15ba
+pid = 0x002a
+tck_hz = 100000
+
+[adapter.pins]
+tck = 0
+tdi = 1
+tdo = 2
+tms = 3
+srst = 4
+trst = 5
+rtck = 7
+
+[chain]
+ir_len = 4
+
+[target]
+ap = 0
+memory_ap = 1
+
+[[target.cores]]
+debug = 0x80010000
+cti = 0x80018000
+"#;
+
+pub const BUILTIN_PROFILES: [&str; 2] = ["arm-usb-ocd-h", "rpi4"];
+
+impl Config {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::Io(format!("{}: {}", path.display(), e)))?;
+        text.parse()
+    }
+
+    // BUILTIN_PROFILESの名前から作る
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "arm-usb-ocd-h" => ARM_USB_OCD_H.parse().ok(),
+            // ARM-USB-OCD-HでRaspberry Pi 4の4coreを繋ぐ
+            "rpi4" => {
+                let mut config: Config = ARM_USB_OCD_H.parse().ok()?;
+                config.target.cores = Cores::List(BCM2711_CORES.to_vec());
+                Some(config)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let raw: RawConfig = toml::from_str(s).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        raw.validate()
+    }
+}
+
+// TOMLのそのままの形
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    adapter: RawAdapter,
+    chain: RawChain,
+    target: RawTarget,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawAdapter {
+    backend: Option<String>,
+    vid: u16,
+    pid: u16,
+    serial: Option<String>,
+    tck_hz: Option<u32>,
+    #[serde(default)]
+    pins: BTreeMap<String, i64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawChain {
+    ir_len: Option<usize>,
+    ir_lens: Option<Vec<usize>>,
+    tap: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCore {
+    debug: u64,
+    cti: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawCores {
+    Name(String),
+    List(Vec<RawCore>),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTarget {
+    #[serde(default)]
+    ap: u8,
+    memory_ap: Option<u8>,
+    cores: RawCores,
+    #[serde(default)]
+    quirks: Vec<String>,
+    #[serde(default)]
+    memory: Vec<RawMemory>,
+    reset: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawMemory {
+    name: String,
+    start: u64,
+    size: u64,
+    #[serde(default = "default_writable")]
+    writable: bool,
+    #[serde(default)]
+    protected: bool,
+}
+
+fn default_writable() -> bool {
+    true
+}
+
+impl RawConfig {
+    fn validate(self) -> Result<Config, ConfigError> {
+        Ok(Config {
+            adapter: self.adapter.validate()?,
+            chain: self.chain.validate()?,
+            target: self.target.validate()?,
+        })
+    }
+}
+
+impl RawAdapter {
+    fn validate(self) -> Result<AdapterConfig, ConfigError> {
+        let backend = match self.backend {
+            Some(backend) => backend.parse()?,
+            None => Backend::BitBang,
+        };
+        if self.tck_hz == Some(0) {
+            return Err(invalid("adapter.tck_hz", "must not be 0".to_string()));
+        }
+        let mut pins: Vec<(Pin, u8)> = Vec::new();
+        for (name, position) in self.pins.iter() {
+            let key = format!("adapter.pins.{}", name);
+            let pin = *Pin::ALL
+                .iter()
+                .find(|x| x.name() == name.as_str())
+                .ok_or_else(|| invalid(&key, "unknown pin".to_string()))?;
+            if !(0..=PIN_POSITION_MAX).contains(position) {
+                return Err(invalid(&key, format!("invalid position {}", position)));
+            }
+            let position = *position as u8;
+            if let Some((other, _)) = pins.iter().find(|x| x.1 == position) {
+                return Err(invalid(
+                    &key,
+                    format!("shares position {} with {}", position, other.name()),
+                ));
+            }
+            pins.push((pin, position));
+        }
+        Ok(AdapterConfig {
+            backend,
+            vid: self.vid,
+            pid: self.pid,
+            serial: self.serial,
+            tck_hz: self.tck_hz,
+            pins,
+        })
+    }
+}
+
+impl RawChain {
+    fn validate(self) -> Result<ChainConfig, ConfigError> {
+        let chain = match (self.ir_len, self.ir_lens) {
+            (Some(_), Some(_)) => {
+                return Err(invalid(
+                    "chain.ir_len",
+                    "give either ir_len or ir_lens".to_string(),
+                ))
+            }
+            (Some(ir_len), None) => ChainConfig {
+                ir_lens: vec![ir_len],
+                tap: self.tap.unwrap_or(0),
+            },
+            (None, Some(ir_lens)) => ChainConfig {
+                ir_lens,
+                tap: self.tap.unwrap_or(0),
+            },
+            (None, None) => return Err(invalid("chain.ir_len", "missing".to_string())),
+        };
+        if let Some(ir_len) = chain.ir_lens.iter().find(|x| **x < 2) {
+            // IR-Captureの01が入らない
+            return Err(invalid(
+                "chain.ir_len",
+                format!("invalid IR length {}", ir_len),
+            ));
+        }
+        if chain.tap >= chain.ir_lens.len() {
+            return Err(invalid(
+                "chain.tap",
+                format!(
+                    "TAP {} does not exist ({} TAPs)",
+                    chain.tap,
+                    chain.ir_lens.len()
+                ),
+            ));
+        }
+        Ok(chain)
+    }
+}
+
+impl RawTarget {
+    fn validate(self) -> Result<TargetConfig, ConfigError> {
+        let cores = match self.cores {
+            RawCores::Name(name) if name == "discover" => Cores::Discover,
+            RawCores::Name(name) => {
+                return Err(invalid(
+                    "target.cores",
+                    format!("expected \"discover\" or a list of cores: {:?}", name),
+                ))
+            }
+            RawCores::List(cores) if cores.is_empty() => {
+                return Err(invalid("target.cores", "no cores".to_string()))
+            }
+            RawCores::List(cores) => Cores::List(
+                cores
+                    .iter()
+                    .map(|x| CoreBase {
+                        debug: x.debug,
+                        cti: x.cti,
+                    })
+                    .collect(),
+            ),
+        };
+        let mut quirks = QuirkSet::empty();
+        for name in self.quirks.iter() {
+            quirks |= QuirkSet::from_name(name)
+                .ok_or_else(|| invalid("target.quirks", format!("unknown quirk {:?}", name)))?;
+        }
+        let mut memory: Vec<MemoryRegion> = Vec::new();
+        for region in self.memory {
+            let key = format!("target.memory.{}", region.name);
+            let range = AddrRange::new(region.start, region.size).ok_or_else(|| {
+                invalid(
+                    &key,
+                    format!("invalid range {:#x} + {:#x}", region.start, region.size),
+                )
+            })?;
+            if memory.iter().any(|x| x.name == region.name) {
+                return Err(invalid(&key, "duplicate name".to_string()));
+            }
+            memory.push(MemoryRegion {
+                name: region.name,
+                range,
+                writable: region.writable,
+                protected: region.protected,
+            });
+        }
+        let reset = match self.reset.as_deref() {
+            None | Some("halt") => ResetPolicy::Halt,
+            Some("srst") => ResetPolicy::ResetCatch,
+            Some(name) => {
+                return Err(invalid(
+                    "target.reset",
+                    format!("expected \"halt\" or \"srst\": {:?}", name),
+                ))
+            }
+        };
+        Ok(TargetConfig {
+            ap: self.ap,
+            memory_ap: self.memory_ap,
+            cores,
+            quirks,
+            memory,
+            reset,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::ftdi::ProbeError;
+    use crate::interface::ftdi_builder::{FtdiBuilder, FtdiOpen};
+    use crate::interface::mock::MockInterface;
+    use crate::jtag::jtag::{Jtag, TAP};
+    use spin::mutex::Mutex;
+
+    fn invalid_key(text: &str) -> String {
+        match text.parse::<Config>() {
+            Err(ConfigError::Invalid { key, .. }) => key,
+            x => panic!("unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn builtin_test() {
+        for name in BUILTIN_PROFILES.iter() {
+            assert!(Config::builtin(name).is_some(), "{}", name);
+        }
+        let config = Config::builtin("arm-usb-ocd-h").unwrap();
+        assert_eq!((0x15ba, 0x002a), (config.adapter.vid, config.adapter.pid));
+        assert!(config.adapter.pins.contains(&(Pin::Rtck, 7)));
+        assert_eq!(4, config.chain.ir_len());
+        assert_eq!(0x8001_0000, config.target.core(0).unwrap().debug);
+
+        let config = Config::builtin("rpi4").unwrap();
+        assert_eq!(&BCM2711_CORES[..], config.target.cores().unwrap());
+        assert!(Config::builtin("unknown").is_none());
+    }
+
+    #[test]
+    fn duplicate_pin_test() {
+        let text = ARM_USB_OCD_H.replace("trst = 5", "trst = 4");
+        assert_eq!("adapter.pins.trst", invalid_key(&text));
+        let text = ARM_USB_OCD_H.replace("rtck = 7", "rtck = 16");
+        assert_eq!("adapter.pins.rtck", invalid_key(&text));
+        let text = ARM_USB_OCD_H.replace("rtck = 7", "nrst = 7");
+        assert_eq!("adapter.pins.nrst", invalid_key(&text));
+    }
+
+    #[test]
+    fn missing_ir_len_test() {
+        let text = ARM_USB_OCD_H.replace("ir_len = 4", "");
+        assert_eq!("chain.ir_len", invalid_key(&text));
+        let text = ARM_USB_OCD_H.replace("ir_len = 4", "ir_lens = [4, 5]\ntap = 2");
+        assert_eq!("chain.tap", invalid_key(&text));
+        let text = ARM_USB_OCD_H.replace("ir_len = 4", "ir_len = 4\nir_length = 4");
+        assert!(matches!(text.parse::<Config>(), Err(ConfigError::Parse(_))));
+    }
+
+    #[test]
+    fn cores_test() {
+        let text = ARM_USB_OCD_H.replace(
+            "[[target.cores]]\ndebug = 0x80010000\ncti = 0x80018000",
+            "cores = \"discover\"",
+        );
+        let config: Config = text.parse().unwrap();
+        assert_eq!(Cores::Discover, config.target.cores);
+        assert!(config.target.cores().is_err());
+
+        let text = text.replace("\"discover\"", "\"all\"");
+        assert_eq!("target.cores", invalid_key(&text));
+    }
+
+    #[test]
+    fn quirks_test() {
+        let config = Config::builtin("arm-usb-ocd-h").unwrap();
+        assert_eq!(QuirkSet::empty(), config.target.quirks);
+
+        let text = ARM_USB_OCD_H.replace(
+            "memory_ap = 1",
+            "memory_ap = 1\nquirks = [\"extra_read_after_select\", \"double_abort_write\"]",
+        );
+        let config: Config = text.parse().unwrap();
+        assert_eq!(
+            QuirkSet::EXTRA_READ_AFTER_SELECT | QuirkSet::DOUBLE_ABORT_WRITE,
+            config.target.quirks
+        );
+
+        let text = ARM_USB_OCD_H.replace("memory_ap = 1", "memory_ap = 1\nquirks = [\"unknown\"]");
+        assert_eq!("target.quirks", invalid_key(&text));
+    }
+
+    #[test]
+    fn reset_test() {
+        let config = Config::builtin("arm-usb-ocd-h").unwrap();
+        assert_eq!(ResetPolicy::Halt, config.target.reset);
+
+        let text = ARM_USB_OCD_H.replace("memory_ap = 1", "memory_ap = 1\nreset = \"srst\"");
+        let config: Config = text.parse().unwrap();
+        assert_eq!(ResetPolicy::ResetCatch, config.target.reset);
+
+        let text = ARM_USB_OCD_H.replace("memory_ap = 1", "memory_ap = 1\nreset = \"trst\"");
+        assert_eq!("target.reset", invalid_key(&text));
+    }
+
+    #[test]
+    fn memory_test() {
+        let config = Config::builtin("arm-usb-ocd-h").unwrap();
+        assert!(config.target.memory.is_empty());
+
+        let text = format!(
+            "{}\n{}",
+            ARM_USB_OCD_H,
+            r#"
+[[target.memory]]
+name = "sram"
+start = 0x10000000
+size = 0x10000
+
+[[target.memory]]
+name = "rom"
+start = 0
+size = 0x8000
+writable = false
+
+[[target.memory]]
+name = "pmic"
+start = 0x7e804000
+size = 0x100
+protected = true
+"#
+        );
+        let config: Config = text.parse().unwrap();
+        assert_eq!(
+            MemoryRegion {
+                name: "pmic".to_string(),
+                range: AddrRange::new(0x7e80_4000, 0x100).unwrap(),
+                writable: true,
+                protected: true,
+            },
+            config.target.memory[2]
+        );
+        assert!(config.target.memory[0].writable && !config.target.memory[1].writable);
+
+        let zero = text.replace("size = 0x100\n", "size = 0\n");
+        assert_eq!("target.memory.pmic", invalid_key(&zero));
+        let duplicate = text.replace("name = \"rom\"", "name = \"sram\"");
+        assert_eq!("target.memory.sram", invalid_key(&duplicate));
+    }
+
+    struct AnyPins;
+
+    impl FtdiOpen for AnyPins {
+        const REQUIRED_PINS: &'static [Pin] = &[Pin::Tck, Pin::Tdi, Pin::Tdo, Pin::Tms];
+        const FIXED_PINS: &'static [(Pin, u8)] = &[];
+
+        fn open_with(_: &FtdiBuilder<Self>) -> Result<Self, ProbeError> {
+            Ok(AnyPins)
+        }
+    }
+
+    #[test]
+    fn construct_test() {
+        let text = ARM_USB_OCD_H.replace("ir_len = 4", "ir_lens = [5, 4]\ntap = 1");
+        let config: Config = text.parse().unwrap();
+
+        let builder = FtdiBuilder::<AnyPins>::from_config(&config.adapter);
+        assert_eq!(Some(100_000), builder.initial_tck_hz());
+        assert_eq!(Some(&5), builder.pins().unwrap().get(&Pin::Trst));
+
+        let jtag = Mutex::new(Jtag::new(MockInterface::new()));
+        let tap = TAP::new_with_config(&jtag, &config.chain).unwrap();
+        assert_eq!(4, tap.ir_len);
+        assert_eq!((1, 5), (tap.devices_before, tap.ir_before));
+        assert_eq!((0, 0), (tap.devices_after, tap.ir_after));
+    }
+}
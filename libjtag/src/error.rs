@@ -0,0 +1,264 @@
+// libjtagの各層が返すerror
+// JtagErrorをDapErrorが、DapErrorとJtagErrorをDebugErrorが包む
+use core::fmt;
+
+use crate::jtag::dap::{AddrRange, CtrlStatus};
+use crate::jtag::jtag_state_machine::JtagState;
+use crate::target::arm64::{AuthStatus, HaltReason};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JtagError {
+    // USBが抜けた場合などdevice側のエラー
+    Io,
+    // 要求したbyte数を書ききれなかった
+    ShortWrite,
+    // 読み出しが揃わなかった
+    Timeout,
+    // 指定したindexやlengthがtargetの範囲外
+    OutOfRange,
+    // BYPASSやIDCODEが全て0か全て1で、chainにdeviceが見えない
+    EmptyChain,
+    // backendがその操作(pin)を持っていない
+    Unsupported,
+    // IR-Captureの下位2bitが01でない。chainが切れているかIR長が違う
+    BadIrCapture,
+    // Jtag::initializeを呼ぶ前にscanを行った
+    NotInitialized,
+    // change_stateがその2状態間の経路を持っていない
+    UnsupportedTransition { from: JtagState, to: JtagState },
+    // MPSSEが不正なcommandを受け取り、0xFAとそのopcodeを返してきた
+    MpsseProtocol { bad_opcode: u8 },
+    // verify_stateでIR-Captureが01にならず、TAPとstate machineの状態がずれている
+    Desynced,
+}
+
+impl fmt::Display for JtagError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JtagError::Io => write!(f, "interface I/O error"),
+            JtagError::ShortWrite => write!(f, "short write to interface"),
+            JtagError::Timeout => write!(f, "interface read timed out"),
+            JtagError::OutOfRange => write!(f, "argument out of range for target"),
+            JtagError::EmptyChain => write!(
+                f,
+                "no device on the chain (TDO read as all zeros or all ones)"
+            ),
+            JtagError::Unsupported => write!(f, "operation not supported by interface"),
+            JtagError::BadIrCapture => write!(
+                f,
+                "unexpected IR capture pattern (broken chain or wrong IR length)"
+            ),
+            JtagError::NotInitialized => {
+                write!(
+                    f,
+                    "JTAG chain not initialized (call Jtag::initialize first)"
+                )
+            }
+            JtagError::UnsupportedTransition { from, to } => {
+                write!(f, "no TAP state transition from {:?} to {:?}", from, to)
+            }
+            JtagError::MpsseProtocol { bad_opcode } => {
+                write!(f, "MPSSE rejected command {:#04x} as invalid", bad_opcode)
+            }
+            JtagError::Desynced => write!(
+                f,
+                "TAP state is out of sync with the host (call Jtag::resync)"
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for JtagError {}
+
+#[cfg(feature = "std")]
+impl From<safe_ftdi::error::Error> for JtagError {
+    fn from(e: safe_ftdi::error::Error) -> Self {
+        log::error!("ftdi error: {}", e);
+        JtagError::Io
+    }
+}
+
+// try_系の関数が返すerror
+// JTAG-DPのACKではOKとFAULTを区別できないので、FaultはCTRL/STATのsticky flagから判断する
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DapError {
+    Interface(JtagError),
+    WaitTimeout,
+    InvalidAck,
+    // 立っていたsticky flag。返す前にABORTで落としてある
+    Fault { sticky: CtrlStatus },
+    // InvalidAckが続いた後のpingにも失敗した。Jtag/DAPを初期化し直す必要がある
+    LinkLost,
+    // DPIDR.VERSIONが古く、そのDP registerがない
+    UnsupportedDpVersion { version: u32, required: u32 },
+    // pingで読んだIDCODEがinitialize時にscanした値と違う。別のtargetに繋がっている
+    IdcodeMismatch { expected: u32, found: u32 },
+    // DPIDRが全て0か全て1で、DPが応答しない。JTAG-APではportに何も繋がっていない
+    NoTarget,
+    // debug/system power domainの電源が入らない
+    PowerUpTimeout,
+    // WriteGuardが断った。addrは書き込み範囲の中で最初に引っかかったaddress
+    WriteProtected { addr: u64, region: AddrRange },
+    // verify_tarで読み返したTARが書いた値と違う。MEM-APが上位bitを実装していない等
+    TarMismatch { expected: u64, found: u64 },
+}
+
+impl From<JtagError> for DapError {
+    fn from(e: JtagError) -> Self {
+        DapError::Interface(e)
+    }
+}
+
+impl fmt::Display for DapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DapError::Interface(e) => write!(f, "{}", e),
+            DapError::WaitTimeout => write!(f, "DAP kept responding WAIT"),
+            DapError::InvalidAck => write!(f, "DAP returned an invalid ACK"),
+            DapError::Fault { sticky } => write!(f, "DAP transaction faulted: {}", sticky),
+            DapError::LinkLost => write!(f, "JTAG link lost (IDCODE ping failed)"),
+            DapError::UnsupportedDpVersion { version, required } => write!(
+                f,
+                "DP register not implemented in DPv{} (DPv{} or later required)",
+                version, required
+            ),
+            DapError::IdcodeMismatch { expected, found } => write!(
+                f,
+                "IDCODE changed from {:#010x} to {:#010x}",
+                expected, found
+            ),
+            DapError::NoTarget => write!(
+                f,
+                "no target responded (DPIDR read as all zeros or all ones)"
+            ),
+            DapError::PowerUpTimeout => {
+                write!(f, "debug power-up was not acknowledged by target")
+            }
+            DapError::WriteProtected { addr, region } => write!(
+                f,
+                "write to {:#x} refused, protected region {}",
+                addr, region
+            ),
+            DapError::TarMismatch { expected, found } => write!(
+                f,
+                "TAR read back as {:#x} after writing {:#x}",
+                found, expected
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DapError {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DebugError {
+    Interface(JtagError),
+    Dap(DapError),
+    // poll_untilの条件が満たされなかった
+    Timeout,
+    // coreがhaltしなかった。最後に読んだEDPRSR/EDSCRを持つ
+    HaltTimeout { edprsr: u32, edscr: u32 },
+    // DBGAUTHSTATUS/EDPRSRがhaltを許していない
+    InvasiveDebugDisabled { auth: AuthStatus },
+    // AsyncSessionのworker threadが既に終了している
+    SessionClosed,
+    // 今のELでは足りないsystem registerや命令。secureはEDSCR.SDDのためにEL3へ移れないこと
+    InsufficientPrivilege { current_el: u8, secure: bool },
+    // haltしているcoreが必要な操作を、走っているcoreに対して行おうとした
+    NotHalted,
+    // EDPRSR.PUが0で、coreのdebug registerに触れない
+    CorePoweredDown,
+    // EDPRSR.DLKが1。OS Double Lockは外部からは外せない
+    DoubleLocked,
+    // OSLARに書いてもEDPRSR.OSLKが落ちない
+    OsLocked,
+    // EDITRで実行させた命令がabortした(EDSCR.ERR)
+    Fault,
+    // AArch32のcoreにA64命令しかない操作を行おうとした
+    WrongExecutionState,
+    // EDSCR.RXOが立った。RXfullのままDBGDTRRXが書かれ、値が失われた
+    DtrOverrun,
+    // EDSCR.TXUが立った。TXfullでないのにDBGDTRTXが読まれた
+    DtrUnderrun,
+    // flash algorithmが戻り先のHLT以外で止まった
+    UnexpectedHalt { reason: HaltReason, pc: u64 },
+    // flash algorithmが0以外を返した。addrは書いていたpageの先頭
+    AlgorithmFailed { addr: u64, code: u64 },
+    // ATでvaを引けなかった(PAR_EL1.F)
+    TranslationFault { va: u64 },
+}
+
+impl From<JtagError> for DebugError {
+    fn from(e: JtagError) -> Self {
+        DebugError::Interface(e)
+    }
+}
+
+impl From<DapError> for DebugError {
+    fn from(e: DapError) -> Self {
+        DebugError::Dap(e)
+    }
+}
+
+impl fmt::Display for DebugError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DebugError::Interface(e) => write!(f, "{}", e),
+            DebugError::Dap(e) => write!(f, "{}", e),
+            DebugError::Timeout => write!(f, "polling timed out"),
+            DebugError::HaltTimeout { edprsr, edscr } => write!(
+                f,
+                "core did not halt (EDPRSR: {:#010x}, EDSCR: {:#010x})",
+                edprsr, edscr
+            ),
+            DebugError::InvasiveDebugDisabled { auth } => {
+                write!(f, "invasive debug is disabled ({})", auth)
+            }
+            DebugError::SessionClosed => write!(f, "debug session worker has exited"),
+            DebugError::InsufficientPrivilege {
+                current_el,
+                secure: true,
+            } => write!(
+                f,
+                "EL3 is not accessible from EL{}: secure debug is disabled (EDSCR.SDD)",
+                current_el
+            ),
+            DebugError::InsufficientPrivilege {
+                current_el,
+                secure: false,
+            } => write!(
+                f,
+                "not accessible from EL{}, raise the exception level with DCPS first",
+                current_el
+            ),
+            DebugError::NotHalted => write!(f, "core is not halted"),
+            DebugError::CorePoweredDown => write!(f, "core is powered down (EDPRSR.PU == 0)"),
+            DebugError::DoubleLocked => write!(f, "OS Double Lock is set (EDPRSR.DLK == 1)"),
+            DebugError::OsLocked => write!(f, "OS Lock did not clear after writing OSLAR"),
+            DebugError::Fault => write!(f, "instruction aborted on the core (EDSCR.ERR)"),
+            DebugError::WrongExecutionState => {
+                write!(f, "operation not available in the core's execution state")
+            }
+            DebugError::DtrOverrun => write!(f, "DBGDTRRX overrun (EDSCR.RXO)"),
+            DebugError::DtrUnderrun => write!(f, "DBGDTRTX underrun (EDSCR.TXU)"),
+            DebugError::UnexpectedHalt { reason, pc } => write!(
+                f,
+                "flash algorithm halted unexpectedly ({:?} at {:#x})",
+                reason, pc
+            ),
+            DebugError::AlgorithmFailed { addr, code } => write!(
+                f,
+                "flash algorithm returned {:#x} while programming {:#x}",
+                code, addr
+            ),
+            DebugError::TranslationFault { va } => {
+                write!(f, "no translation for virtual address {:#x}", va)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DebugError {}
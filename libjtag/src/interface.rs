@@ -1,44 +1,323 @@
+use bitflags::bitflags;
+
+use crate::jtag::stats::UsbStats;
 use crate::jtag::JtagBit;
 
+pub mod bits;
+#[cfg(feature = "std")]
+pub mod conformance;
+#[cfg(feature = "std")]
+pub mod ftdi;
 #[cfg(feature = "std")]
 pub mod ftdi_bitbang;
 #[cfg(feature = "std")]
+pub mod ftdi_builder;
+#[cfg(feature = "std")]
 pub mod ftdi_mpsse;
+pub mod gpio;
+#[cfg(feature = "std")]
+pub mod jlink;
+#[cfg(feature = "std")]
+pub mod mock;
+#[cfg(feature = "std")]
+pub mod pins;
+#[cfg(feature = "std")]
+pub mod remote_bitbang;
+#[cfg(feature = "std")]
+pub mod replay;
+#[cfg(feature = "std")]
+pub mod trace;
+
+// 以前からの名前。interfaceとtargetの関数はこの名前で返す
+pub use crate::error::JtagError as InterfaceError;
+
+// defaultの実装はallocを使わず、この長さずつraw_write/raw_readする
+const RAW_CHUNK_SIZE: usize = 64;
+
+fn data_pins(chunk: &[bool], pins: &mut [JtagBit], value: JtagBit) {
+    for (x, y) in pins.iter_mut().zip(chunk) {
+        *x = if *y { value } else { JtagBit::empty() };
+    }
+}
+
+bitflags! {
+    #[derive(Default)]
+    pub struct CapFlags: u32 {
+        // write_data_packed/read_data_packedをbool列に変換せずに送れる
+        const SUPPORTS_PACKED_IO = 1 << 0;
+        // clock_idleをTMSのbit列ではなく専用のcommandで送れる
+        const SUPPORTS_IDLE_CLOCK = 1 << 1;
+        const HAS_SRST = 1 << 2;
+        const HAS_TRST = 1 << 3;
+        const ADAPTIVE_CLOCKING = 1 << 4;
+    }
+}
+
+// JtagやDAPがbackendに合わせて送り方を選ぶための情報
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InterfaceCaps {
+    pub flags: CapFlags,
+    // 1回のwrite_data/read_dataで渡してよいbit数。usize::MAXなら制限なし
+    pub max_bits_per_transfer: usize,
+}
+
+impl InterfaceCaps {
+    pub const fn new(flags: CapFlags) -> Self {
+        InterfaceCaps {
+            flags,
+            max_bits_per_transfer: usize::MAX,
+        }
+    }
+
+    pub fn contains(&self, flags: CapFlags) -> bool {
+        self.flags.contains(flags)
+    }
+}
+
+impl Default for InterfaceCaps {
+    fn default() -> Self {
+        InterfaceCaps::new(CapFlags::empty())
+    }
+}
 
 pub trait JtagInterface {
-    fn write_tms(&self, tms: &[bool]) {
-        let data: Vec<_> = tms
-            .iter()
-            .map(|x| if *x { JtagBit::TMS } else { JtagBit::empty() })
-            .collect();
-        self.raw_write(data.as_slice());
-    }
-    fn write_data(&self, tdi: &[bool], exit: bool) {
-        let mut data: Vec<_> = tdi
-            .iter()
-            .map(|x| if *x { JtagBit::TDI } else { JtagBit::empty() })
-            .collect();
-        if exit {
-            let last = data.last_mut().unwrap();
-            *last = *last | JtagBit::TMS;
-        }
-        self.raw_write(data.as_slice());
-    }
-    fn read_data(&self, tditdo: &mut [bool], exit: bool) {
-        let mut data: Vec<_> = tditdo
-            .iter()
-            .map(|x| if *x { JtagBit::TDI } else { JtagBit::empty() })
-            .collect();
-        if exit {
-            let last = data.last_mut().unwrap();
-            *last = *last | JtagBit::TMS;
-        }
-        self.raw_read(data.as_mut_slice());
-        for i in 0..tditdo.len() {
-            tditdo[i] = data[i].contains(JtagBit::TDO);
-        }
-    }
-
-    fn raw_write(&self, data: &[JtagBit]);
-    fn raw_read(&self, data: &mut [JtagBit]);
+    // defaultはraw_write/raw_readだけを持つbackendとして振る舞う
+    fn capabilities(&self) -> InterfaceCaps {
+        InterfaceCaps::default()
+    }
+
+    fn write_tms(&self, tms: &[bool]) -> Result<(), InterfaceError> {
+        let mut data = [JtagBit::empty(); RAW_CHUNK_SIZE];
+        for chunk in tms.chunks(RAW_CHUNK_SIZE) {
+            data_pins(chunk, &mut data, JtagBit::TMS);
+            self.raw_write(&data[..chunk.len()])?;
+        }
+        Ok(())
+    }
+    fn write_data(&self, tdi: &[bool], exit: bool) -> Result<(), InterfaceError> {
+        let mut data = [JtagBit::empty(); RAW_CHUNK_SIZE];
+        let chunks = tdi.len().div_ceil(RAW_CHUNK_SIZE);
+        for (i, chunk) in tdi.chunks(RAW_CHUNK_SIZE).enumerate() {
+            data_pins(chunk, &mut data, JtagBit::TDI);
+            if exit && i + 1 == chunks {
+                data[chunk.len() - 1] |= JtagBit::TMS;
+            }
+            self.raw_write(&data[..chunk.len()])?;
+        }
+        Ok(())
+    }
+    fn read_data(&self, tditdo: &mut [bool], exit: bool) -> Result<(), InterfaceError> {
+        let mut data = [JtagBit::empty(); RAW_CHUNK_SIZE];
+        let chunks = tditdo.len().div_ceil(RAW_CHUNK_SIZE);
+        for (i, chunk) in tditdo.chunks_mut(RAW_CHUNK_SIZE).enumerate() {
+            data_pins(chunk, &mut data, JtagBit::TDI);
+            if exit && i + 1 == chunks {
+                data[chunk.len() - 1] |= JtagBit::TMS;
+            }
+            self.raw_read(&mut data[..chunk.len()])?;
+            for (x, y) in chunk.iter_mut().zip(data.iter()) {
+                *x = y.contains(JtagBit::TDO);
+            }
+        }
+        Ok(())
+    }
+
+    // tdiはLSB firstでbyteに詰めたbit列で、先頭からlen bitをshiftする
+    // defaultはbool列に変換してwrite_dataを呼ぶ。byte列のまま送れるbackendは上書きする
+    #[cfg(feature = "alloc")]
+    fn write_data_packed(&self, tdi: &[u8], len: usize, exit: bool) -> Result<(), InterfaceError> {
+        if tdi.len() < bits::byte_len(len) {
+            return Err(InterfaceError::OutOfRange);
+        }
+        let mut data = alloc::vec![false; len];
+        bits::unpack(tdi, &mut data);
+        self.write_data(&data, exit)
+    }
+    #[cfg(not(feature = "alloc"))]
+    fn write_data_packed(&self, tdi: &[u8], len: usize, exit: bool) -> Result<(), InterfaceError> {
+        if tdi.len() < bits::byte_len(len) {
+            return Err(InterfaceError::OutOfRange);
+        }
+        let mut data = [false; RAW_CHUNK_SIZE];
+        let chunks = len.div_ceil(RAW_CHUNK_SIZE);
+        for i in 0..chunks {
+            let start = i * RAW_CHUNK_SIZE;
+            let data = &mut data[..(len - start).min(RAW_CHUNK_SIZE)];
+            bits::unpack(&tdi[start / 8..], data);
+            self.write_data(data, exit && i + 1 == chunks)?;
+        }
+        Ok(())
+    }
+    // 読んだTDOも同じく詰めて返す。最後のbyteのlen bit目以降は0になる
+    #[cfg(feature = "alloc")]
+    fn read_data_packed(
+        &self,
+        tditdo: &mut [u8],
+        len: usize,
+        exit: bool,
+    ) -> Result<(), InterfaceError> {
+        if tditdo.len() < bits::byte_len(len) {
+            return Err(InterfaceError::OutOfRange);
+        }
+        let mut data = alloc::vec![false; len];
+        bits::unpack(tditdo, &mut data);
+        self.read_data(&mut data, exit)?;
+        bits::pack(&data, tditdo);
+        Ok(())
+    }
+    #[cfg(not(feature = "alloc"))]
+    fn read_data_packed(
+        &self,
+        tditdo: &mut [u8],
+        len: usize,
+        exit: bool,
+    ) -> Result<(), InterfaceError> {
+        if tditdo.len() < bits::byte_len(len) {
+            return Err(InterfaceError::OutOfRange);
+        }
+        let mut data = [false; RAW_CHUNK_SIZE];
+        let chunks = len.div_ceil(RAW_CHUNK_SIZE);
+        for i in 0..chunks {
+            let start = i * RAW_CHUNK_SIZE;
+            let data = &mut data[..(len - start).min(RAW_CHUNK_SIZE)];
+            bits::unpack(&tditdo[start / 8..], data);
+            self.read_data(data, exit && i + 1 == chunks)?;
+            bits::pack(data, &mut tditdo[start / 8..]);
+        }
+        Ok(())
+    }
+
+    // TMS=LのままTCKをn回入れる。専用のcommandがあるbackendは上書きする
+    fn clock_idle(&self, n: usize) -> Result<(), InterfaceError> {
+        let tms = [false; RAW_CHUNK_SIZE];
+        let mut rest = n;
+        while rest > 0 {
+            let length = rest.min(RAW_CHUNK_SIZE);
+            self.write_tms(&tms[..length])?;
+            rest -= length;
+        }
+        Ok(())
+    }
+
+    fn raw_write(&self, data: &[JtagBit]) -> Result<(), InterfaceError>;
+    fn raw_read(&self, data: &mut [JtagBit]) -> Result<(), InterfaceError>;
+
+    // USBなどの下の層で数えている転送量。数えていないbackendはNone
+    fn usb_stats(&self) -> Option<UsbStats> {
+        None
+    }
+    fn reset_usb_stats(&self) {}
+
+    // 溜めている書き込みを全て送る。readは常にその場で送られる
+    fn flush(&self) -> Result<(), InterfaceError> {
+        Ok(())
+    }
+
+    // TRST/SRSTはactive low。trueでassertし、falseで解除するまで保持する
+    fn assert_trst(&self, _level: bool) -> Result<(), InterfaceError> {
+        Err(InterfaceError::Unsupported)
+    }
+    fn assert_srst(&self, _level: bool) -> Result<(), InterfaceError> {
+        Err(InterfaceError::Unsupported)
+    }
+    #[cfg(feature = "std")]
+    fn pulse_srst(&self, duration_ms: u32) -> Result<(), InterfaceError> {
+        self.assert_srst(true)?;
+        std::thread::sleep(std::time::Duration::from_millis(duration_ms as u64));
+        self.assert_srst(false)
+    }
+    // 待つ手段がないので、no_stdのbackendは自分で実装する
+    #[cfg(not(feature = "std"))]
+    fn pulse_srst(&self, _duration_ms: u32) -> Result<(), InterfaceError> {
+        Err(InterfaceError::Unsupported)
+    }
+}
+
+// 実行時にbackendを選ぶためのBox<dyn JtagInterface>
+// defaultの実装に任せると中身が上書きした送り方を使わなくなるので、全てをそのまま渡す
+#[cfg(feature = "alloc")]
+impl<T: JtagInterface + ?Sized> JtagInterface for alloc::boxed::Box<T> {
+    fn capabilities(&self) -> InterfaceCaps {
+        (**self).capabilities()
+    }
+    fn write_tms(&self, tms: &[bool]) -> Result<(), InterfaceError> {
+        (**self).write_tms(tms)
+    }
+    fn write_data(&self, tdi: &[bool], exit: bool) -> Result<(), InterfaceError> {
+        (**self).write_data(tdi, exit)
+    }
+    fn read_data(&self, tditdo: &mut [bool], exit: bool) -> Result<(), InterfaceError> {
+        (**self).read_data(tditdo, exit)
+    }
+    fn write_data_packed(&self, tdi: &[u8], len: usize, exit: bool) -> Result<(), InterfaceError> {
+        (**self).write_data_packed(tdi, len, exit)
+    }
+    fn read_data_packed(
+        &self,
+        tditdo: &mut [u8],
+        len: usize,
+        exit: bool,
+    ) -> Result<(), InterfaceError> {
+        (**self).read_data_packed(tditdo, len, exit)
+    }
+    fn clock_idle(&self, n: usize) -> Result<(), InterfaceError> {
+        (**self).clock_idle(n)
+    }
+    fn raw_write(&self, data: &[JtagBit]) -> Result<(), InterfaceError> {
+        (**self).raw_write(data)
+    }
+    fn raw_read(&self, data: &mut [JtagBit]) -> Result<(), InterfaceError> {
+        (**self).raw_read(data)
+    }
+    fn usb_stats(&self) -> Option<UsbStats> {
+        (**self).usb_stats()
+    }
+    fn reset_usb_stats(&self) {
+        (**self).reset_usb_stats()
+    }
+    fn flush(&self) -> Result<(), InterfaceError> {
+        (**self).flush()
+    }
+    fn assert_trst(&self, level: bool) -> Result<(), InterfaceError> {
+        (**self).assert_trst(level)
+    }
+    fn assert_srst(&self, level: bool) -> Result<(), InterfaceError> {
+        (**self).assert_srst(level)
+    }
+    fn pulse_srst(&self, duration_ms: u32) -> Result<(), InterfaceError> {
+        (**self).pulse_srst(duration_ms)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::replay::tests::{SimDap, AP_IDR, IDCODE};
+    use super::*;
+    use crate::jtag::dap::*;
+    use crate::jtag::jtag::{Jtag, TAP};
+    use crate::target::arm64::A64Target;
+    use spin::mutex::Mutex;
+
+    #[test]
+    fn boxed_interface_test() {
+        let interface: Box<dyn JtagInterface> = Box::new(SimDap::new());
+        // 中身が上書きしたcapabilitiesがそのまま見える
+        assert_eq!(
+            InterfaceCaps::new(CapFlags::SUPPORTS_IDLE_CLOCK),
+            interface.capabilities()
+        );
+
+        let mut jtag = Jtag::new(interface);
+        let devices = jtag.scan().unwrap().devices().to_vec();
+        assert_eq!(1, devices.len());
+        assert_eq!(Some(IDCODE), devices[0].raw());
+
+        let jtag = Mutex::new(jtag);
+        let mut dap = DAP::new(TAP::new(&jtag, 4));
+        assert_eq!(AP_IDR, dap.memap_idr_read().unwrap().1);
+
+        // A64Targetまで同じ型で組める。SimDapのAPはCSW以外を0で返す
+        let mut target = A64Target::new(DapHandle::new(dap), 0x8001_0000);
+        assert_eq!(0, target.edscr_read().unwrap().0);
+    }
 }
@@ -0,0 +1,65 @@
+// interfaceが送るbyte列とbool列の相互変換
+// 各bitはLSB firstで詰める(bit0が先に出る)
+
+// 8bitまでのbit列を1byteにする
+pub fn pack_byte(bits: &[bool]) -> u8 {
+    bits.iter()
+        .take(8)
+        .enumerate()
+        .fold(0u8, |x, (j, y)| x | ((*y as u8) << j))
+}
+
+// bit列をbyte列に詰める。outはbyte_len(bits.len()) byte以上必要
+pub fn pack(bits: &[bool], out: &mut [u8]) {
+    for (byte, chunk) in out.iter_mut().zip(bits.chunks(8)) {
+        *byte = pack_byte(chunk);
+    }
+}
+
+// byte列からout.len() bit取り出す
+pub fn unpack(bytes: &[u8], out: &mut [bool]) {
+    for (i, bit) in out.iter_mut().enumerate() {
+        *bit = bytes[i / 8] & (1 << (i % 8)) != 0;
+    }
+}
+
+// MPSSEのbit modeのように、shiftで読んだbitがbyteの上位側に詰まっている場合
+pub fn unpack_msb_aligned(byte: u8, out: &mut [bool]) {
+    let length = out.len();
+    for (j, bit) in out.iter_mut().enumerate() {
+        *bit = byte & (1 << (8 - length + j)) != 0;
+    }
+}
+
+pub const fn byte_len(bits: usize) -> usize {
+    bits.div_ceil(8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_test() {
+        let bits = [
+            true, false, true, true, false, false, false, false, // 0x0d
+            false, true, true,
+        ];
+        let mut out = [0u8; 2];
+        pack(&bits, &mut out);
+        assert_eq!(out, [0x0d, 0x06]);
+        assert_eq!(byte_len(bits.len()), 2);
+
+        let mut back = [false; 11];
+        unpack(&out, &mut back);
+        assert_eq!(back, bits);
+    }
+
+    #[test]
+    fn unpack_msb_aligned_test() {
+        // 3bit読むと bit5,6,7 に入る
+        let mut out = [false; 3];
+        unpack_msb_aligned(0b1010_0000, &mut out);
+        assert_eq!(out, [true, false, true]);
+    }
+}
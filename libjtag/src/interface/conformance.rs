@@ -0,0 +1,475 @@
+// JtagInterface実装の適合性テスト
+// 新しいバックエンドはこのsuiteが全てPASSすることを受け入れ条件とする
+use std::cmp;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::{InterfaceError, JtagInterface};
+use crate::jtag::bits;
+
+const LONG_TRANSFER_BITS: usize = 4096;
+const TIMING_LIMIT: Duration = Duration::from_secs(30);
+const EVIDENCE_BITS_MAX: usize = 64;
+
+#[derive(Clone, Copy, Debug)]
+pub enum HarnessKind {
+    // TDIとTDOを直結したもの
+    Loopback,
+    // BYPASSとIDCODEだけを持つTAPが1つだけ繋がっているもの
+    SingleTap { idcode: u32, ir_len: usize },
+}
+
+pub trait LoopbackHarness {
+    fn kind(&self) -> HarnessKind;
+}
+
+impl LoopbackHarness for HarnessKind {
+    fn kind(&self) -> HarnessKind {
+        *self
+    }
+}
+
+#[derive(Debug)]
+pub struct CheckResult {
+    pub name: String,
+    pub passed: bool,
+    pub evidence: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ConformanceReport {
+    pub results: Vec<CheckResult>,
+}
+
+impl ConformanceReport {
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|x| x.passed)
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &CheckResult> {
+        self.results.iter().filter(|x| !x.passed)
+    }
+
+    fn push(&mut self, name: String, passed: bool, evidence: String) {
+        self.results.push(CheckResult {
+            name,
+            passed,
+            evidence,
+        });
+    }
+
+    fn compare(&mut self, name: String, expected: &[bool], actual: &[bool]) {
+        match expected.iter().zip(actual).position(|(x, y)| x != y) {
+            None if expected.len() == actual.len() => {
+                self.push(name, true, format!("{} bits matched", expected.len()))
+            }
+            None => self.push(
+                name,
+                false,
+                format!("length {} != {}", expected.len(), actual.len()),
+            ),
+            Some(i) => {
+                let start = i - cmp::min(i, EVIDENCE_BITS_MAX / 2);
+                self.push(
+                    name,
+                    false,
+                    format!(
+                        "first mismatch at bit {}: expected {} actual {} (from bit {})",
+                        i,
+                        bits_to_string(&expected[start..]),
+                        bits_to_string(&actual[start..]),
+                        start
+                    ),
+                )
+            }
+        }
+    }
+}
+
+impl fmt::Display for ConformanceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for result in &self.results {
+            writeln!(
+                f,
+                "[{}] {}: {}",
+                if result.passed { "PASS" } else { "FAIL" },
+                result.name,
+                result.evidence
+            )?;
+        }
+        let passed = self.results.iter().filter(|x| x.passed).count();
+        write!(f, "{}/{} checks passed", passed, self.results.len())
+    }
+}
+
+// LSB first
+fn bits_to_string(bits: &[bool]) -> String {
+    bits.iter()
+        .take(EVIDENCE_BITS_MAX)
+        .map(|x| if *x { '1' } else { '0' })
+        .collect()
+}
+
+fn u32_to_bits(value: u32, len: usize) -> Vec<bool> {
+    bits::to_bools_lsb_first(value as u64, len).collect()
+}
+
+fn bits_to_u32(bits: &[bool]) -> u32 {
+    bits::from_bools_lsb_first(bits) as u32
+}
+
+// x^7 + x^6 + 1
+fn prbs7(len: usize) -> Vec<bool> {
+    let mut lfsr: u8 = 0x7f;
+    (0..len)
+        .map(|_| {
+            let bit = ((lfsr >> 6) ^ (lfsr >> 5)) & 1;
+            lfsr = ((lfsr << 1) | bit) & 0x7f;
+            bit != 0
+        })
+        .collect()
+}
+
+fn shift(iface: &dyn JtagInterface, tdi: &[bool], exit: bool) -> Result<Vec<bool>, InterfaceError> {
+    let mut data = tdi.to_vec();
+    iface.read_data(&mut data, exit)?;
+    Ok(data)
+}
+
+// Test-Logic-Resetから各stateへ向かうTMS列
+const STATE_PATHS: [(&str, &[bool]); 16] = [
+    ("Reset", &[true]),
+    ("RunIdle", &[false]),
+    ("SelectDRScan", &[false, true]),
+    ("CaptureDR", &[false, true, false]),
+    ("ShiftDR", &[false, true, false, false]),
+    ("Exit1DR", &[false, true, false, true]),
+    ("PauseDR", &[false, true, false, true, false]),
+    ("Exit2DR", &[false, true, false, true, false, true]),
+    ("UpdateDR", &[false, true, false, true, true]),
+    ("SelectIRScan", &[false, true, true]),
+    ("CaptureIR", &[false, true, true, false]),
+    ("ShiftIR", &[false, true, true, false, false]),
+    ("Exit1IR", &[false, true, true, false, true]),
+    ("PauseIR", &[false, true, true, false, true, false]),
+    ("Exit2IR", &[false, true, true, false, true, false, true]),
+    ("UpdateIR", &[false, true, true, false, true, true]),
+];
+
+pub fn run(iface: &mut dyn JtagInterface, harness: &dyn LoopbackHarness) -> ConformanceReport {
+    let mut report = ConformanceReport::default();
+    let result = match harness.kind() {
+        HarnessKind::Loopback => run_loopback(iface, &mut report),
+        HarnessKind::SingleTap { idcode, ir_len } => {
+            run_single_tap(iface, idcode, ir_len, &mut report)
+        }
+    };
+    // interfaceが失敗したら残りのcheckは実行しない
+    if let Err(e) = result {
+        report.push("interface error".to_string(), false, e.to_string());
+    }
+    report
+}
+
+fn run_loopback(
+    iface: &dyn JtagInterface,
+    report: &mut ConformanceReport,
+) -> Result<(), InterfaceError> {
+    for exit in [false, true] {
+        for bit in [false, true] {
+            let tdi = [bit];
+            let tdo = shift(iface, &tdi, exit)?;
+            report.compare(
+                format!("single bit {} exit={}", bit as u8, exit),
+                &tdi,
+                &tdo,
+            );
+        }
+
+        // 3byte, 部分byte
+        for (value, len) in [(0x000f_3ca5, 24), (0x0000_1b35, 13)] {
+            let tdi = u32_to_bits(value, len);
+            let tdo = shift(iface, &tdi, exit)?;
+            report.compare(format!("{} bits exit={}", len, exit), &tdi, &tdo);
+        }
+    }
+
+    // 最初と最後の1bitだけが立ったパターンでbit順を確認する
+    for value in [0x01, 0x80] {
+        let tdi = u32_to_bits(value, 8);
+        let tdo = shift(iface, &tdi, true)?;
+        report.compare(format!("bit order {:#04x}", value), &tdi, &tdo);
+    }
+
+    // TMSだけを動かした後でもデータパスが壊れないこと
+    for (name, path) in STATE_PATHS.iter() {
+        iface.write_tms(&[true; 5])?;
+        iface.write_tms(path)?;
+        let tdi = u32_to_bits(0x5a, 8);
+        let tdo = shift(iface, &tdi, false)?;
+        report.compare(format!("data after TMS to {}", name), &tdi, &tdo);
+    }
+
+    let tdi = prbs7(LONG_TRANSFER_BITS);
+    let start = Instant::now();
+    let tdo = shift(iface, &tdi, true)?;
+    let elapsed = start.elapsed();
+    report.compare(format!("prbs7 {} bits", LONG_TRANSFER_BITS), &tdi, &tdo);
+    report_timing(report, LONG_TRANSFER_BITS, elapsed);
+    Ok(())
+}
+
+fn read_idcode(iface: &dyn JtagInterface) -> Result<u32, InterfaceError> {
+    // Reset -> ShiftDR
+    iface.write_tms(&[true; 5])?;
+    iface.write_tms(&[false, true, false, false])?;
+    let tdo = shift(iface, &[false; 32], true)?;
+    // Exit1DR -> UpdateDR -> RunIdle
+    iface.write_tms(&[true, false])?;
+    Ok(bits_to_u32(&tdo))
+}
+
+fn load_bypass(iface: &dyn JtagInterface, ir_len: usize) -> Result<(), InterfaceError> {
+    // Reset -> ShiftIR
+    iface.write_tms(&[true; 5])?;
+    iface.write_tms(&[false, true, true, false, false])?;
+    shift(iface, &vec![true; ir_len], true)?;
+    // Exit1IR -> UpdateIR -> RunIdle -> ShiftDR
+    iface.write_tms(&[true, false, true, false, false])
+}
+
+// BYPASSを通すとTDOは1bit遅れて、先頭にcaptureした0が出てくる
+fn shift_bypass(
+    iface: &dyn JtagInterface,
+    pattern: &[bool],
+) -> Result<(Vec<bool>, Vec<bool>), InterfaceError> {
+    let mut tdi = pattern.to_vec();
+    tdi.push(false);
+    let tdo = shift(iface, &tdi, true)?;
+    iface.write_tms(&[true, false])?;
+
+    let mut expected = vec![false];
+    expected.extend_from_slice(pattern);
+    Ok((expected, tdo))
+}
+
+fn run_single_tap(
+    iface: &dyn JtagInterface,
+    idcode: u32,
+    ir_len: usize,
+    report: &mut ConformanceReport,
+) -> Result<(), InterfaceError> {
+    let expected = u32_to_bits(idcode, 32);
+
+    let actual = read_idcode(iface)?;
+    report.compare(
+        "IDCODE after reset".to_string(),
+        &expected,
+        &u32_to_bits(actual, 32),
+    );
+
+    // exitなしのshiftでShiftDRに留まり、続きのshiftが繋がること
+    iface.write_tms(&[true; 5])?;
+    iface.write_tms(&[false, true, false, false])?;
+    let mut tdo = shift(iface, &[false; 13], false)?;
+    tdo.extend(shift(iface, &[false; 19], true)?);
+    iface.write_tms(&[true, false])?;
+    report.compare("IDCODE split 13+19 bits".to_string(), &expected, &tdo);
+
+    for (name, path) in STATE_PATHS.iter() {
+        iface.write_tms(&[true; 5])?;
+        iface.write_tms(path)?;
+        let actual = read_idcode(iface)?;
+        report.compare(
+            format!("IDCODE after TMS to {}", name),
+            &expected,
+            &u32_to_bits(actual, 32),
+        );
+    }
+
+    for (value, len) in [(0x01, 8), (0x80, 8), (0x0000_1b35, 13), (0x000f_3ca5, 24)] {
+        load_bypass(iface, ir_len)?;
+        let (expected, tdo) = shift_bypass(iface, &u32_to_bits(value, len))?;
+        report.compare(format!("BYPASS {} bits {:#x}", len, value), &expected, &tdo);
+    }
+
+    load_bypass(iface, ir_len)?;
+    let start = Instant::now();
+    let (expected, tdo) = shift_bypass(iface, &prbs7(LONG_TRANSFER_BITS))?;
+    let elapsed = start.elapsed();
+    report.compare(
+        format!("BYPASS prbs7 {} bits", LONG_TRANSFER_BITS),
+        &expected,
+        &tdo,
+    );
+    report_timing(report, LONG_TRANSFER_BITS, elapsed);
+    Ok(())
+}
+
+fn report_timing(report: &mut ConformanceReport, bits: usize, elapsed: Duration) {
+    let rate = bits as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    report.push(
+        format!("timing {} bits", bits),
+        elapsed < TIMING_LIMIT,
+        format!("{:?} ({:.0} bits/s)", elapsed, rate),
+    );
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::jtag::jtag_state_machine::{JtagState as JS, JtagStateMachine};
+    use crate::jtag::JtagBit as JB;
+    use core::cell::RefCell;
+    use rust_fsm::StateMachine;
+
+    struct LoopbackInterface;
+    impl JtagInterface for LoopbackInterface {
+        fn raw_write(&self, _data: &[JB]) -> Result<(), InterfaceError> {
+            Ok(())
+        }
+        fn raw_read(&self, data: &mut [JB]) -> Result<(), InterfaceError> {
+            for x in data.iter_mut() {
+                if x.contains(JB::TDI) {
+                    *x |= JB::TDO;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    // TDIとTDOを逆順につないでしまったloopback(bit順の検出用)
+    struct ReversedInterface;
+    impl JtagInterface for ReversedInterface {
+        fn raw_write(&self, _data: &[JB]) -> Result<(), InterfaceError> {
+            Ok(())
+        }
+        fn raw_read(&self, data: &mut [JB]) -> Result<(), InterfaceError> {
+            let tdi: Vec<_> = data.iter().map(|x| x.contains(JB::TDI)).collect();
+            for (x, y) in data.iter_mut().zip(tdi.iter().rev()) {
+                if *y {
+                    *x |= JB::TDO;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    const IR_IDCODE: u32 = 0b1110;
+
+    struct SimTapState {
+        state_machine: StateMachine<JtagStateMachine>,
+        ir: u32,
+        ir_shift: u32,
+        dr_shift: u64,
+    }
+
+    // IDCODEとBYPASSだけを持つTAPの簡易モデル
+    pub(crate) struct SimTap {
+        idcode: u32,
+        ir_len: usize,
+        tap: RefCell<SimTapState>,
+    }
+
+    impl SimTap {
+        pub fn new(idcode: u32, ir_len: usize) -> Self {
+            SimTap {
+                idcode,
+                ir_len,
+                tap: RefCell::new(SimTapState {
+                    state_machine: StateMachine::new(),
+                    ir: IR_IDCODE,
+                    ir_shift: 0,
+                    dr_shift: 0,
+                }),
+            }
+        }
+
+        pub fn clock(&self, pins: JB) -> bool {
+            let mut tap = self.tap.borrow_mut();
+            let tdi = pins.contains(JB::TDI) as u64;
+            let dr_len = if tap.ir == IR_IDCODE { 32 } else { 1 };
+            let tdo = match tap.state_machine.state() {
+                JS::ShiftDR => {
+                    let tdo = tap.dr_shift & 1 != 0;
+                    tap.dr_shift = (tap.dr_shift >> 1) | (tdi << (dr_len - 1));
+                    tdo
+                }
+                JS::ShiftIR => {
+                    let tdo = tap.ir_shift & 1 != 0;
+                    tap.ir_shift = (tap.ir_shift >> 1) | ((tdi as u32) << (self.ir_len - 1));
+                    tdo
+                }
+                _ => false,
+            };
+            tap.state_machine.consume(&pins.contains(JB::TMS)).unwrap();
+            match tap.state_machine.state() {
+                JS::Reset => tap.ir = IR_IDCODE,
+                JS::CaptureDR => {
+                    tap.dr_shift = if tap.ir == IR_IDCODE {
+                        self.idcode as u64
+                    } else {
+                        0
+                    }
+                }
+                JS::CaptureIR => tap.ir_shift = 0b01,
+                JS::UpdateIR => tap.ir = tap.ir_shift,
+                _ => (),
+            }
+            tdo
+        }
+    }
+
+    impl JtagInterface for SimTap {
+        fn raw_write(&self, data: &[JB]) -> Result<(), InterfaceError> {
+            for x in data {
+                self.clock(*x);
+            }
+            Ok(())
+        }
+        fn raw_read(&self, data: &mut [JB]) -> Result<(), InterfaceError> {
+            for x in data.iter_mut() {
+                if self.clock(*x) {
+                    *x |= JB::TDO;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn loopback_conformance_test() {
+        let report = run(&mut LoopbackInterface, &HarnessKind::Loopback);
+        assert!(report.passed(), "{}", report);
+    }
+
+    #[test]
+    fn loopback_bit_order_failure_test() {
+        let report = run(&mut ReversedInterface, &HarnessKind::Loopback);
+        assert!(!report.passed());
+        assert!(report
+            .failures()
+            .any(|x| x.name == "bit order 0x01" && x.evidence.contains("bit 0")));
+    }
+
+    #[test]
+    fn single_tap_conformance_test() {
+        let harness = HarnessKind::SingleTap {
+            idcode: 0x4ba0_0477,
+            ir_len: 4,
+        };
+        let report = run(&mut SimTap::new(0x4ba0_0477, 4), &harness);
+        assert!(report.passed(), "{}", report);
+    }
+
+    #[test]
+    fn single_tap_wrong_idcode_test() {
+        let harness = HarnessKind::SingleTap {
+            idcode: 0x4ba0_0477,
+            ir_len: 4,
+        };
+        let report = run(&mut SimTap::new(0x5ba0_0477, 4), &harness);
+        assert!(!report.passed());
+        assert!(report.failures().any(|x| x.name == "IDCODE after reset"));
+    }
+}
@@ -0,0 +1,489 @@
+// 接続されているFTDIのadapterを列挙し、同じVID/PIDのものから1つを選ぶ
+use libftdi1_sys as ftdic;
+use log::warn;
+use safe_ftdi;
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw;
+use std::ptr;
+use std::str::FromStr;
+
+use super::pins::PinError;
+use super::InterfaceError;
+
+// list_devicesで探すVID/PID
+// libftdiのftdi_usb_find_allはVID/PIDが完全に一致するものしか返さない
+pub const KNOWN_PROBES: &[(u16, u16)] = &[
+    (0x0403, 0x6001),
+    (0x0403, 0x6010),
+    (0x0403, 0x6011),
+    (0x0403, 0x6014),
+    (0x0403, 0x6015),
+    // Olimex ARM-USB-OCD, ARM-USB-TINY, ARM-USB-TINY-H, ARM-USB-OCD-H
+    (0x15ba, 0x0003),
+    (0x15ba, 0x0004),
+    (0x15ba, 0x002a),
+    (0x15ba, 0x002b),
+];
+
+// USBの文字列descriptorの最大長
+const STRING_LEN: usize = 128;
+// USB 3.0のhubを辿れる段数
+const PORT_DEPTH_MAX: usize = 7;
+
+// libftdi1-sysにはbus/portを取る関数がないので、libftdiが使っているlibusbから直接呼ぶ
+#[link(name = "usb-1.0")]
+extern "C" {
+    fn libusb_get_bus_number(dev: *mut ftdic::libusb_device) -> u8;
+    fn libusb_get_port_numbers(
+        dev: *mut ftdic::libusb_device,
+        port_numbers: *mut u8,
+        port_numbers_len: raw::c_int,
+    ) -> raw::c_int;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProbeInfo {
+    pub vid: u16,
+    pub pid: u16,
+    pub serial: Option<String>,
+    pub description: Option<String>,
+    pub bus: u8,
+    // root hubから辿ったport番号
+    pub ports: Vec<u8>,
+}
+
+impl ProbeInfo {
+    // Linuxのsysfsと同じ"bus-port.port"の形
+    pub fn path(&self) -> String {
+        let ports: Vec<String> = self.ports.iter().map(|x| x.to_string()).collect();
+        format!("{}-{}", self.bus, ports.join("."))
+    }
+}
+
+impl fmt::Display for ProbeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}:{:04x} usb:{}", self.vid, self.pid, self.path())?;
+        if let Some(serial) = &self.serial {
+            write!(f, " serial:{}", serial)?;
+        }
+        if let Some(description) = &self.description {
+            write!(f, " desc:{:?}", description)?;
+        }
+        Ok(())
+    }
+}
+
+// adapterを探して開く時のerror
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProbeError {
+    // DeviceSelectorやdescription/serialの書式が違う
+    InvalidSelector(String),
+    // selectorに一致するadapterがない。candidatesは同じVID/PIDで見つかったもの
+    NotFound {
+        selector: DeviceSelector,
+        candidates: Vec<ProbeInfo>,
+    },
+    // selectorに一致するadapterが複数ある
+    Ambiguous {
+        selector: DeviceSelector,
+        candidates: Vec<ProbeInfo>,
+    },
+    // libftdiが返したerror
+    Ftdi(String),
+    Pin(PinError),
+    // 開いた後の初期化に失敗した
+    Interface(InterfaceError),
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProbeError::InvalidSelector(message) => write!(f, "{}", message),
+            ProbeError::NotFound {
+                selector,
+                candidates,
+            } => write!(
+                f,
+                "no probe matches {}, candidates:{}",
+                selector,
+                candidate_list(candidates)
+            ),
+            ProbeError::Ambiguous {
+                selector,
+                candidates,
+            } => write!(
+                f,
+                "{} probes match {}, use a more specific selector:{}",
+                candidates.len(),
+                selector,
+                candidate_list(candidates)
+            ),
+            ProbeError::Ftdi(message) => write!(f, "{}", message),
+            ProbeError::Pin(e) => write!(f, "{}", e),
+            ProbeError::Interface(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProbeError {}
+
+impl From<safe_ftdi::error::Error> for ProbeError {
+    fn from(e: safe_ftdi::error::Error) -> Self {
+        ProbeError::Ftdi(e.to_string())
+    }
+}
+
+impl From<PinError> for ProbeError {
+    fn from(e: PinError) -> Self {
+        ProbeError::Pin(e)
+    }
+}
+
+impl From<InterfaceError> for ProbeError {
+    fn from(e: InterfaceError) -> Self {
+        ProbeError::Interface(e)
+    }
+}
+
+// 同じVID/PIDのadapterが複数ある場合にどれを開くか
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceSelector {
+    BySerial(String),
+    ByDescription(String),
+    // VID/PIDが一致したものの中での順番
+    ByIndex(usize),
+    ByBusPort { bus: u8, ports: Vec<u8> },
+}
+
+impl DeviceSelector {
+    fn matches(&self, index: usize, probe: &ProbeInfo) -> bool {
+        match self {
+            DeviceSelector::BySerial(serial) => probe.serial.as_ref() == Some(serial),
+            DeviceSelector::ByDescription(description) => {
+                probe.description.as_ref() == Some(description)
+            }
+            DeviceSelector::ByIndex(n) => index == *n,
+            DeviceSelector::ByBusPort { bus, ports } => probe.bus == *bus && probe.ports == *ports,
+        }
+    }
+}
+
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceSelector::BySerial(serial) => write!(f, "serial:{}", serial),
+            DeviceSelector::ByDescription(description) => write!(f, "desc:{}", description),
+            DeviceSelector::ByIndex(n) => write!(f, "index:{}", n),
+            DeviceSelector::ByBusPort { bus, ports } => {
+                let ports: Vec<String> = ports.iter().map(|x| x.to_string()).collect();
+                write!(f, "usb:{}-{}", bus, ports.join("."))
+            }
+        }
+    }
+}
+
+// Displayと同じ"serial:S", "desc:D", "index:N", "usb:BUS-PORT.PORT"の形
+impl FromStr for DeviceSelector {
+    type Err = ProbeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |message: String| Err(ProbeError::InvalidSelector(message));
+        let (kind, value) = match s.split_once(':') {
+            Some(x) => x,
+            None => {
+                return invalid(format!(
+                    "expected KIND:VALUE (serial, desc, index or usb): {}",
+                    s
+                ))
+            }
+        };
+        let selector = match kind {
+            "serial" => DeviceSelector::BySerial(value.to_string()),
+            "desc" => DeviceSelector::ByDescription(value.to_string()),
+            "index" => match value.parse() {
+                Ok(n) => DeviceSelector::ByIndex(n),
+                Err(_) => return invalid(format!("invalid probe index: {}", value)),
+            },
+            "usb" => {
+                let parsed = value.split_once('-').and_then(|(bus, ports)| {
+                    let bus = bus.parse().ok()?;
+                    let ports = ports
+                        .split('.')
+                        .map(|x| x.parse().ok())
+                        .collect::<Option<Vec<u8>>>()?;
+                    Some(DeviceSelector::ByBusPort { bus, ports })
+                });
+                match parsed {
+                    Some(selector) => selector,
+                    None => return invalid(format!("expected usb:BUS-PORT[.PORT...]: {}", value)),
+                }
+            }
+            _ => return invalid(format!("unknown probe selector: {}", kind)),
+        };
+        Ok(selector)
+    }
+}
+
+fn candidate_list(probes: &[ProbeInfo]) -> String {
+    if probes.is_empty() {
+        return " (no probes found)".to_string();
+    }
+    probes
+        .iter()
+        .enumerate()
+        .map(|(i, x)| format!("\n  {}: {}", i, x))
+        .collect()
+}
+
+// selectorに一致するものがちょうど1つの場合にその位置を返す
+pub fn select(probes: &[ProbeInfo], selector: &DeviceSelector) -> Result<usize, ProbeError> {
+    let matched: Vec<usize> = probes
+        .iter()
+        .enumerate()
+        .filter(|(i, x)| selector.matches(*i, x))
+        .map(|(i, _)| i)
+        .collect();
+    match matched.len() {
+        1 => Ok(matched[0]),
+        0 => Err(ProbeError::NotFound {
+            selector: selector.clone(),
+            candidates: probes.to_vec(),
+        }),
+        _ => Err(ProbeError::Ambiguous {
+            selector: selector.clone(),
+            candidates: matched.iter().map(|i| probes[*i].clone()).collect(),
+        }),
+    }
+}
+
+fn error_string(context: *mut ftdic::ftdi_context) -> String {
+    let message = unsafe { CStr::from_ptr(ftdic::ftdi_get_error_string(context)) };
+    message.to_string_lossy().into_owned()
+}
+
+fn c_string(buffer: &[raw::c_char]) -> Option<String> {
+    let value = unsafe { CStr::from_ptr(buffer.as_ptr()) }.to_string_lossy();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.into_owned())
+    }
+}
+
+// ftdi_usb_find_allの結果。dropで一覧を解放する
+struct DeviceList<'a> {
+    context: &'a mut safe_ftdi::Context,
+    list: *mut ftdic::ftdi_device_list,
+}
+
+impl<'a> DeviceList<'a> {
+    fn find(context: &'a mut safe_ftdi::Context, vid: u16, pid: u16) -> Result<Self, ProbeError> {
+        let mut list = ptr::null_mut();
+        let rc = unsafe {
+            ftdic::ftdi_usb_find_all(
+                context.get_ftdi_context(),
+                &mut list,
+                raw::c_int::from(vid),
+                raw::c_int::from(pid),
+            )
+        };
+        if rc < 0 {
+            return Err(ProbeError::Ftdi(format!(
+                "failed to list {:04x}:{:04x}: {}",
+                vid,
+                pid,
+                error_string(context.get_ftdi_context())
+            )));
+        }
+        Ok(DeviceList { context, list })
+    }
+
+    fn devices(&self) -> Vec<*mut ftdic::libusb_device> {
+        let mut devices = Vec::new();
+        let mut node = self.list;
+        while !node.is_null() {
+            unsafe {
+                devices.push((*node).dev);
+                node = (*node).next;
+            }
+        }
+        devices
+    }
+
+    fn info(&self, dev: *mut ftdic::libusb_device, vid: u16, pid: u16) -> ProbeInfo {
+        let mut manufacturer = [0 as raw::c_char; STRING_LEN];
+        let mut description = [0 as raw::c_char; STRING_LEN];
+        let mut serial = [0 as raw::c_char; STRING_LEN];
+        // 権限がなく文字列を読めないadapterも一覧には出す
+        let rc = unsafe {
+            ftdic::ftdi_usb_get_strings(
+                self.context.get_ftdi_context(),
+                dev,
+                manufacturer.as_mut_ptr(),
+                STRING_LEN as raw::c_int,
+                description.as_mut_ptr(),
+                STRING_LEN as raw::c_int,
+                serial.as_mut_ptr(),
+                STRING_LEN as raw::c_int,
+            )
+        };
+        if rc < 0 {
+            description[0] = 0;
+            serial[0] = 0;
+        }
+        let mut ports = [0u8; PORT_DEPTH_MAX];
+        let depth = unsafe {
+            libusb_get_port_numbers(dev, ports.as_mut_ptr(), PORT_DEPTH_MAX as raw::c_int)
+        };
+        ProbeInfo {
+            vid,
+            pid,
+            serial: c_string(&serial),
+            description: c_string(&description),
+            bus: unsafe { libusb_get_bus_number(dev) },
+            ports: ports[..depth.max(0) as usize].to_vec(),
+        }
+    }
+
+    fn open(&self, dev: *mut ftdic::libusb_device) -> Result<(), ProbeError> {
+        let context = self.context.get_ftdi_context();
+        if unsafe { ftdic::ftdi_usb_open_dev(context, dev) } < 0 {
+            return Err(ProbeError::Ftdi(format!(
+                "failed to open the probe: {}",
+                error_string(context)
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Drop for DeviceList<'a> {
+    fn drop(&mut self) {
+        if !self.list.is_null() {
+            unsafe { ftdic::ftdi_list_free(&mut self.list) };
+        }
+    }
+}
+
+// vid/pidが一致するadapterを列挙する
+pub fn find_devices(vid: u16, pid: u16) -> Result<Vec<ProbeInfo>, ProbeError> {
+    let mut context = safe_ftdi::Context::new()?;
+    let list = DeviceList::find(&mut context, vid, pid)?;
+    Ok(list
+        .devices()
+        .into_iter()
+        .map(|x| list.info(x, vid, pid))
+        .collect())
+}
+
+// KNOWN_PROBESのadapterを全て列挙する。列挙に失敗したVID/PIDは飛ばす
+pub fn list_devices() -> Vec<ProbeInfo> {
+    let mut probes = Vec::new();
+    for (vid, pid) in KNOWN_PROBES.iter() {
+        match find_devices(*vid, *pid) {
+            Ok(found) => probes.extend(found),
+            Err(e) => warn!("{}", e),
+        }
+    }
+    probes
+}
+
+// vid/pidが一致するadapterのうちselectorで選んだものを開く
+pub fn open_selected(
+    vid: u16,
+    pid: u16,
+    selector: &DeviceSelector,
+) -> Result<safe_ftdi::Context, ProbeError> {
+    let mut context = safe_ftdi::Context::new()?;
+    {
+        let list = DeviceList::find(&mut context, vid, pid)?;
+        let devices = list.devices();
+        let probes: Vec<ProbeInfo> = devices.iter().map(|x| list.info(*x, vid, pid)).collect();
+        let index = select(&probes, selector)?;
+        list.open(devices[index])?;
+    }
+    Ok(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probe(serial: &str, description: &str, bus: u8, ports: &[u8]) -> ProbeInfo {
+        ProbeInfo {
+            vid: 0x15ba,
+            pid: 0x002a,
+            serial: Some(serial.to_string()),
+            description: Some(description.to_string()),
+            bus,
+            ports: ports.to_vec(),
+        }
+    }
+
+    #[test]
+    fn select_test() {
+        let probes = [
+            probe("OL1", "Olimex OpenOCD JTAG ARM-USB-TINY-H", 1, &[2]),
+            probe("OL2", "Olimex OpenOCD JTAG ARM-USB-TINY-H", 1, &[3, 1]),
+            probe("OL3", "custom", 2, &[1]),
+        ];
+        let by = |s: &str| s.parse::<DeviceSelector>().unwrap();
+        assert_eq!(1, select(&probes, &by("serial:OL2")).unwrap());
+        assert_eq!(2, select(&probes, &by("desc:custom")).unwrap());
+        assert_eq!(0, select(&probes, &by("index:0")).unwrap());
+        assert_eq!(1, select(&probes, &by("usb:1-3.1")).unwrap());
+        assert_eq!("1-3.1", probes[1].path());
+
+        // 一致しない場合も複数一致する場合も候補を示す
+        let error = format!("{}", select(&probes, &by("serial:OL4")).unwrap_err());
+        assert!(error.contains("no probe matches serial:OL4"), "{}", error);
+        assert!(
+            error.contains("serial:OL1") && error.contains("serial:OL3"),
+            "{}",
+            error
+        );
+        let error = format!(
+            "{}",
+            select(&probes, &by("desc:Olimex OpenOCD JTAG ARM-USB-TINY-H")).unwrap_err()
+        );
+        assert!(error.starts_with("2 probes match"), "{}", error);
+        assert!(
+            error.contains("usb:1-2") && error.contains("usb:1-3.1"),
+            "{}",
+            error
+        );
+        assert!(!error.contains("OL3"), "{}", error);
+        assert!(matches!(
+            select(&probes, &by("index:3")),
+            Err(ProbeError::NotFound { .. })
+        ));
+        let error = format!("{}", select(&[], &by("index:0")).unwrap_err());
+        assert!(error.contains("no probes found"), "{}", error);
+    }
+
+    #[test]
+    fn selector_parse_test() {
+        for text in [
+            "serial:A1B2",
+            "desc:ARM-USB-OCD-H",
+            "index:1",
+            "usb:3-1.4.2",
+        ]
+        .iter()
+        {
+            let selector: DeviceSelector = text.parse().unwrap();
+            assert_eq!(*text, selector.to_string());
+        }
+        assert_eq!(
+            DeviceSelector::ByBusPort {
+                bus: 3,
+                ports: vec![1, 4]
+            },
+            "usb:3-1.4".parse().unwrap()
+        );
+        for text in ["A1B2", "index:x", "usb:3", "usb:3-1.a", "port:1"].iter() {
+            assert!(text.parse::<DeviceSelector>().is_err(), "{}", text);
+        }
+    }
+}
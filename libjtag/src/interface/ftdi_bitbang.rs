@@ -1,243 +1,701 @@
-use anyhow::{Context, Result};
+use libftdi1_sys as ftdic;
 use log::{debug, error, info, warn};
 use safe_ftdi;
+use std::cell::{Cell, RefCell};
 use std::cmp;
-use std::collections::HashMap;
+use std::os::raw;
+use std::time::{Duration, Instant};
 use std::{thread, time};
 
-use crate::interface::JtagInterface;
+use crate::interface::ftdi::{DeviceSelector, ProbeError};
+use crate::interface::ftdi_builder::{FtdiBuilder, FtdiOpen, Pin};
+use crate::interface::pins::{PinMap, Signal};
+use crate::interface::{CapFlags, InterfaceCaps, InterfaceError, JtagInterface};
+use crate::jtag::stats::{UsbCounter, UsbStats};
 use crate::jtag::JtagBit;
 
+// synchronous bitbangでは書いたbyteと同じ数のsampleが返ってくる
+// rx bufferを溢れさせないよう、chunk sizeずつ書いては読み戻す
+// builderで指定しなければ、deviceのchunk sizeとこの小さい方を使う
 const CHUNK_SIZE: usize = 512;
+// raw_writeだけが続く場合でも、これを超えたら送る
+const QUEUE_MAX: usize = 64 * CHUNK_SIZE;
+// 1 TCK周期にTCK low/highの2byteを送る
+const BYTES_PER_TCK: u32 = 2;
+const BAUDRATE_MIN: u32 = 183;
+const BAUDRATE_MAX: u32 = 3_000_000;
+// 従来の10000 baud相当
+const DEFAULT_TCK_HZ: u32 = 5_000;
 
-struct FtdiJtagPin {
-    position: u8,
-    input: bool,
+// adaptive clockingでRTCKがTCKに追いつくのを待つ時間
+const RTCK_TIMEOUT: Duration = Duration::from_millis(100);
+// 書いた分のsampleが揃うのを待つ時間
+const READ_TIMEOUT: Duration = Duration::from_millis(1000);
+
+// 範囲外はclampし、(baudrate, 実際のTCK周波数)を返す
+fn bitbang_baudrate(hz: u32) -> (u32, u32) {
+    let baudrate = hz.saturating_mul(BYTES_PER_TCK);
+    let baudrate = baudrate.clamp(BAUDRATE_MIN, BAUDRATE_MAX);
+    (baudrate, baudrate / BYTES_PER_TCK)
+}
+
+// FtdiBitBangが使うdevice側の操作
+// テストでsafe_ftdi::Contextを差し替えられるようにする
+pub trait BitBangDevice {
+    fn write_data(&self, data: &[u8]) -> Result<usize, InterfaceError>;
+    fn read_data(&self, data: &mut [u8]) -> Result<usize, InterfaceError>;
+    // synchronous bitbangで、bitmaskの1を出力にする
+    fn set_bitmode(&self, bitmask: u8) -> Result<(), InterfaceError>;
+    fn set_baudrate(&self, baudrate: u32) -> Result<(), InterfaceError>;
+    // 1回のUSB転送の最大byte数。readとwriteの小さい方
+    fn chunk_size(&self) -> Result<usize, InterfaceError>;
+    fn set_chunk_size(&self, size: usize) -> Result<(), InterfaceError>;
+}
+
+impl BitBangDevice for safe_ftdi::Context {
+    fn write_data(&self, data: &[u8]) -> Result<usize, InterfaceError> {
+        Ok(safe_ftdi::Context::write_data(self, data)? as usize)
+    }
+    fn read_data(&self, data: &mut [u8]) -> Result<usize, InterfaceError> {
+        Ok(safe_ftdi::Context::read_data(self, data)? as usize)
+    }
+    fn set_bitmode(&self, bitmask: u8) -> Result<(), InterfaceError> {
+        Ok(safe_ftdi::Context::set_bitmode(
+            self,
+            bitmask,
+            safe_ftdi::mpsse::MpsseMode::BITMODE_SYNCBB,
+        )?)
+    }
+    fn set_baudrate(&self, baudrate: u32) -> Result<(), InterfaceError> {
+        Ok(safe_ftdi::Context::set_baudrate(self, baudrate)?)
+    }
+    fn chunk_size(&self) -> Result<usize, InterfaceError> {
+        let context = self.get_ftdi_context();
+        let mut read: raw::c_uint = 0;
+        let mut write: raw::c_uint = 0;
+        let rc = unsafe { ftdic::ftdi_read_data_get_chunksize(context, &mut read) };
+        if rc < 0 {
+            return Err(InterfaceError::Io);
+        }
+        let rc = unsafe { ftdic::ftdi_write_data_get_chunksize(context, &mut write) };
+        if rc < 0 {
+            return Err(InterfaceError::Io);
+        }
+        Ok(cmp::min(read, write) as usize)
+    }
+    fn set_chunk_size(&self, size: usize) -> Result<(), InterfaceError> {
+        let context = self.get_ftdi_context();
+        let size = size as raw::c_uint;
+        let rc = unsafe { ftdic::ftdi_read_data_set_chunksize(context, size) };
+        if rc < 0 {
+            return Err(InterfaceError::Io);
+        }
+        let rc = unsafe { ftdic::ftdi_write_data_set_chunksize(context, size) };
+        if rc < 0 {
+            return Err(InterfaceError::Io);
+        }
+        Ok(())
+    }
+}
+
+pub struct FtdiBitBang<D = safe_ftdi::Context> {
+    device: D,
+    pins: PinMap,
+    // assert_trst/assert_srstで保持しているreset
+    reset: Cell<JtagBit>,
+    srst_open_drain: bool,
+    // TCKの各edgeでRTCKが追いつくまで待つ
+    adaptive_clocking: bool,
+    // まだ送っていないpinの値。flushかraw_readでまとめて送る
+    queue: RefCell<Vec<u8>>,
+    // 1回のwrite_dataで送り、読み戻すbyte数
+    chunk_size: usize,
+    usb: UsbCounter,
 }
 
-impl FtdiJtagPin {
-    pub fn to_bit(&self) -> u8 {
-        1 << self.position
+impl FtdiOpen for FtdiBitBang<safe_ftdi::Context> {
+    const REQUIRED_PINS: &'static [Pin] = &[
+        Pin::Tck,
+        Pin::Tdi,
+        Pin::Tdo,
+        Pin::Tms,
+        Pin::Srst,
+        Pin::Trst,
+        Pin::Rtck,
+    ];
+    const FIXED_PINS: &'static [(Pin, u8)] = &[];
+
+    fn open_with(builder: &FtdiBuilder<Self>) -> Result<Self, ProbeError> {
+        let pins = builder.pin_map()?;
+        let device = builder.open_device()?;
+        let chunk_size = match builder.configured_chunk_size() {
+            Some(size) => {
+                device.set_chunk_size(size)?;
+                device.chunk_size()?
+            }
+            None => cmp::min(device.chunk_size()?, CHUNK_SIZE),
+        };
+        if chunk_size == 0 {
+            return Err(ProbeError::Ftdi("invalid USB chunk size 0".to_string()));
+        }
+        debug!("bitbang chunk size {} bytes", chunk_size);
+
+        let mut ftdi_bitbang = FtdiBitBang {
+            device: device,
+            pins: pins,
+            reset: Cell::new(JtagBit::empty()),
+            srst_open_drain: builder.is_srst_open_drain(),
+            adaptive_clocking: builder.is_adaptive_clocking(),
+            queue: RefCell::new(Vec::new()),
+            chunk_size,
+            usb: UsbCounter::new(),
+        };
+        // open前のsampleが残っていることがあるので、最初に1回だけ捨てる
+        ftdi_bitbang.purge_rx()?;
+        // TRST/SRSTを解除した状態から始める
+        ftdi_bitbang.apply_reset()?;
+        ftdi_bitbang.set_tck_hz(builder.initial_tck_hz().unwrap_or(DEFAULT_TCK_HZ))?;
+        Ok(ftdi_bitbang)
     }
 }
 
-struct FtdiJtagPins {
-    tck: FtdiJtagPin,
-    tms: FtdiJtagPin,
-    tdi: FtdiJtagPin,
-    tdo: FtdiJtagPin,
-    rtck: FtdiJtagPin,
-    trst: FtdiJtagPin,
-    srst: FtdiJtagPin,
+// newで使うpinの位置
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BitBangPins {
+    pub tck: u8,
+    pub tdi: u8,
+    pub tdo: u8,
+    pub tms: u8,
+    pub srst: u8,
+    pub trst: u8,
+    pub rtck: u8,
 }
 
-pub struct FtdiBitBang {
-    device: safe_ftdi::Context,
-    pins: HashMap<String, FtdiJtagPin>,
+// ARM-USB-OCD-Hの配置
+impl Default for BitBangPins {
+    fn default() -> Self {
+        BitBangPins {
+            tck: 0,
+            tdi: 1,
+            tdo: 2,
+            tms: 3,
+            srst: 4,
+            trst: 5,
+            rtck: 7,
+        }
+    }
 }
 
-impl FtdiBitBang {
+impl FtdiBitBang<safe_ftdi::Context> {
+    pub fn builder() -> FtdiBuilder<Self> {
+        FtdiBuilder::new()
+    }
+
     pub fn new(
         vid: u16,
         pid: u16,
-        tck: u8,
-        tdi: u8,
-        tdo: u8,
-        tms: u8,
-        srst: u8,
-        trst: u8,
-        rtck: u8,
-    ) -> Self {
-        // pins
-        let mut pins: HashMap<String, FtdiJtagPin> = HashMap::new();
-        pins.insert(
-            "tck".to_string(),
-            FtdiJtagPin {
-                position: tck,
-                input: false,
-            },
-        );
-        pins.insert(
-            "tdi".to_string(),
-            FtdiJtagPin {
-                position: tdi,
-                input: false,
-            },
-        );
-        pins.insert(
-            "tdo".to_string(),
-            FtdiJtagPin {
-                position: tdo,
-                input: true,
-            },
-        );
-        pins.insert(
-            "tms".to_string(),
-            FtdiJtagPin {
-                position: tms,
-                input: false,
-            },
-        );
-        pins.insert(
-            "srst".to_string(),
-            FtdiJtagPin {
-                position: srst,
-                input: false,
-            },
-        );
-        pins.insert(
-            "trst".to_string(),
-            FtdiJtagPin {
-                position: trst,
-                input: false,
-            },
+        pins: BitBangPins,
+        tck_hz: Option<u32>,
+    ) -> Result<Self, ProbeError> {
+        Self::pin_builder(vid, pid, &pins, tck_hz).open()
+    }
+
+    // 同じVID/PIDのadapterが複数つながっている場合に使う
+    pub fn new_with_selector(
+        vid: u16,
+        pid: u16,
+        selector: DeviceSelector,
+        pins: BitBangPins,
+        tck_hz: Option<u32>,
+    ) -> Result<Self, ProbeError> {
+        Self::pin_builder(vid, pid, &pins, tck_hz)
+            .selector(selector)
+            .open()
+    }
+
+    fn pin_builder(
+        vid: u16,
+        pid: u16,
+        pins: &BitBangPins,
+        tck_hz: Option<u32>,
+    ) -> FtdiBuilder<Self> {
+        let positions = [
+            (Pin::Tck, pins.tck),
+            (Pin::Tdi, pins.tdi),
+            (Pin::Tdo, pins.tdo),
+            (Pin::Tms, pins.tms),
+            (Pin::Srst, pins.srst),
+            (Pin::Trst, pins.trst),
+            (Pin::Rtck, pins.rtck),
+        ];
+        let mut builder = Self::builder().vid(vid).pid(pid);
+        for (pin, position) in positions.iter() {
+            builder = builder.pin(*pin, *position);
+        }
+        match tck_hz {
+            Some(hz) => builder.tck_hz(hz),
+            None => builder,
+        }
+    }
+}
+
+impl<D: BitBangDevice> FtdiBitBang<D> {
+    // 実際に設定されたTCKの周波数を返す
+    pub fn set_tck_hz(&mut self, hz: u32) -> Result<u32, InterfaceError> {
+        let (baudrate, achieved) = bitbang_baudrate(hz);
+        if achieved != hz {
+            info!("TCK {}Hz requested, using {}Hz", hz, achieved);
+        }
+        self.device.set_baudrate(baudrate)?;
+        Ok(achieved)
+    }
+
+    // open drainのSRSTは常にLを出し、解除はdirectionで行う
+    fn pins_to_u8(&self, pins: &JtagBit) -> u8 {
+        let mut pins = *pins | self.reset.get();
+        if self.srst_open_drain {
+            pins.insert(JtagBit::SRST);
+        }
+        self.pins.pins_to_u8(&pins)
+    }
+
+    // 保持しているresetをpinに反映する
+    // open drainのSRSTは解除時に入力にしてHi-Zにする
+    fn apply_reset(&self) -> Result<(), InterfaceError> {
+        // bitmodeを変える前に、溜めている値を今のbitmodeで送る
+        self.flush()?;
+        // 割り当てのないpinも今まで通り出力にする
+        let mut bitmask = !self.pins.input_mask() as u8;
+        if self.srst_open_drain && !self.reset.get().contains(JtagBit::SRST) {
+            bitmask &= !(self.pins.mask(Signal::Srst) as u8);
+        }
+        self.device.set_bitmode(bitmask)?;
+        // TCKはLのままなのでclockは進まない
+        self.queue
+            .borrow_mut()
+            .push(self.pins_to_u8(&JtagBit::empty()));
+        self.flush()
+    }
+
+    fn write_all(&self, data: &[u8]) -> Result<(), InterfaceError> {
+        let res = self.device.write_data(data)?;
+        self.usb.wrote(res);
+        if res != data.len() {
+            return Err(InterfaceError::ShortWrite);
+        }
+        Ok(())
+    }
+
+    fn read_data(&self, data: &mut [u8]) -> Result<usize, InterfaceError> {
+        let length = self.device.read_data(data)?;
+        self.usb.read(length);
+        Ok(length)
+    }
+
+    // 読み残したsampleを捨てる。openした時とerrorの後だけ行う
+    fn purge_rx(&self) -> Result<(), InterfaceError> {
+        let mut tmp = [0; CHUNK_SIZE];
+        while self.read_data(&mut tmp)? > 0 {}
+        Ok(())
+    }
+
+    // 書いたbyte数のsampleが揃うまで読む。1回のread_dataでは一部しか返らないことがある
+    fn read_samples(&self, samples: &mut [u8]) -> Result<(), InterfaceError> {
+        let mut start = Instant::now();
+        let mut done = 0;
+        while done < samples.len() {
+            let length = self.read_data(&mut samples[done..])?;
+            if length > 0 {
+                done += length;
+                start = Instant::now();
+            } else if start.elapsed() > READ_TIMEOUT {
+                error!("{} of {} samples read", done, samples.len());
+                return Err(InterfaceError::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    // queueをchunk_sizeずつ送り、その都度sampleを読み戻す
+    // 失敗した場合は読み残しがあるかもしれないので捨てる
+    fn transfer(&self) -> Result<Vec<u8>, InterfaceError> {
+        let queue = self.queue.replace(Vec::new());
+        let mut samples = vec![0; queue.len()];
+        let result = queue
+            .chunks(self.chunk_size)
+            .zip(samples.chunks_mut(self.chunk_size))
+            .try_for_each(|(chunk, sample)| {
+                self.write_all(chunk)?;
+                self.read_samples(sample)
+            });
+        if let Err(e) = result {
+            warn!("bitbang transfer failed: {:?}, purging rx", e);
+            let _ = self.purge_rx();
+            return Err(e);
+        }
+        Ok(samples)
+    }
+
+    fn push_clock(&self, pins: &JtagBit) {
+        let value = self.pins_to_u8(pins);
+        let mut queue = self.queue.borrow_mut();
+        queue.push(value);
+        queue.push(value | self.pins.mask(Signal::Tck) as u8);
+    }
+
+    // 1byte書いて、synchronous bitbangで読み戻したpinの値を返す
+    fn write_sample(&self, value: u8) -> Result<u8, InterfaceError> {
+        self.write_all(&[value])?;
+        let start = Instant::now();
+        let mut sample = [0];
+        while self.read_data(&mut sample)? == 0 {
+            if start.elapsed() > RTCK_TIMEOUT {
+                return Err(InterfaceError::Timeout);
+            }
+        }
+        Ok(sample[0])
+    }
+
+    // RTCKがTCKと同じになるまで同じ値を書き続け、その時のpinの値を返す
+    // 最初のsampleは書く前の値なので使わない
+    fn wait_rtck(&self, value: u8) -> Result<u8, InterfaceError> {
+        let tck = value & self.pins.mask(Signal::Tck) as u8 != 0;
+        let rtck = self.pins.mask(Signal::Rtck) as u8;
+        let start = Instant::now();
+        self.write_sample(value)?;
+        loop {
+            let sample = self.write_sample(value)?;
+            if (sample & rtck != 0) == tck {
+                return Ok(sample);
+            }
+            if start.elapsed() > RTCK_TIMEOUT {
+                error!("RTCK did not follow TCK={}", tck as u8);
+                return Err(InterfaceError::Timeout);
+            }
+        }
+    }
+
+    // adaptive clockingで1 TCK進める
+    // TDOは非adaptiveの時と同じくTCKを立ち上げる前の値
+    fn adaptive_clock(&self, pins: &JtagBit) -> Result<JtagBit, InterfaceError> {
+        let value = self.pins_to_u8(pins);
+        let sample = self.wait_rtck(value)?;
+        self.wait_rtck(value | self.pins.mask(Signal::Tck) as u8)?;
+        Ok(self.pins.u8_to_pins(sample))
+    }
+}
+
+impl<D: BitBangDevice> JtagInterface for FtdiBitBang<D> {
+    // 全てのbitをpinの値として送る。transferでchunk_sizeずつに分ける
+    fn capabilities(&self) -> InterfaceCaps {
+        let mut flags = CapFlags::empty();
+        flags.set(
+            CapFlags::HAS_SRST,
+            self.pins.position(Signal::Srst).is_some(),
         );
-        pins.insert(
-            "rtck".to_string(),
-            FtdiJtagPin {
-                position: rtck,
-                input: true,
-            },
+        flags.set(
+            CapFlags::HAS_TRST,
+            self.pins.position(Signal::Trst).is_some(),
         );
+        flags.set(CapFlags::ADAPTIVE_CLOCKING, self.adaptive_clocking);
+        InterfaceCaps::new(flags)
+    }
 
-        let mut device = safe_ftdi::Context::new().unwrap();
-        device
-            .open(vid, pid)
-            .with_context(|| format!("failed to open {:#04x}:{:#04x}", vid, pid))
-            .unwrap();
-        device.set_baudrate(10000).unwrap();
-        // set gpio in/out
-        let bitmask = !pins
-            .iter()
-            .filter(|x| x.1.input)
-            .fold(0, |x, y| x + y.1.to_bit());
-        device
-            .set_bitmode(bitmask, safe_ftdi::mpsse::MpsseMode::BITMODE_SYNCBB)
-            .unwrap();
-
-        // device.set_read_chunk_size(CHUNK_SIZE).unwrap();
-        // device.set_write_chunk_size(CHUNK_SIZE).unwrap();
+    fn raw_read(&self, data: &mut [JtagBit]) -> Result<(), InterfaceError> {
+        if self.adaptive_clocking {
+            self.flush()?;
+            for pins in data.iter_mut() {
+                *pins = self.adaptive_clock(pins)?;
+            }
+            return Ok(());
+        }
 
-        FtdiBitBang {
-            device: device,
-            pins: pins,
+        // 溜めている書き込みと一緒に送る
+        let offset = self.queue.borrow().len();
+        for pins in data.iter() {
+            self.push_clock(pins);
         }
+        let samples = self.transfer()?;
+        // TCKを立ち上げるbyteのsampleが、立ち上げる前のpinの値
+        for (i, pins) in data.iter_mut().enumerate() {
+            *pins = self.pins.u8_to_pins(samples[offset + 2 * i + 1]);
+        }
+        Ok(())
     }
+    fn raw_write(&self, data: &[JtagBit]) -> Result<(), InterfaceError> {
+        if self.adaptive_clocking {
+            self.flush()?;
+            for pins in data {
+                self.adaptive_clock(pins)?;
+            }
+            return Ok(());
+        }
 
-    fn pins_to_u8(&self, pins: &JtagBit) -> u8 {
-        let mut data = 0;
-        data = data
-            | if pins.contains(JtagBit::TMS) {
-                1 << self.pins["tms"].position
-            } else {
-                0
-            };
-        data = data
-            | if pins.contains(JtagBit::TCK) {
-                1 << self.pins["tck"].position
-            } else {
-                0
-            };
-        data = data
-            | if pins.contains(JtagBit::TDI) {
-                1 << self.pins["tdi"].position
-            } else {
-                0
-            };
-        data = data
-            | if pins.contains(JtagBit::TDO) {
-                1 << self.pins["tdo"].position
-            } else {
-                0
-            };
-        data = data
-            | if pins.contains(JtagBit::TRST) {
-                1 << self.pins["trst"].position
-            } else {
-                0
-            };
-        data = data
-            | if pins.contains(JtagBit::SRST) {
-                1 << self.pins["srst"].position
-            } else {
-                0
-            };
-        data
-    }
-
-    fn u8_to_pins(&self, pins: u8) -> JtagBit {
-        let jtagpin = {
-            JtagBit::empty()
-                | if (pins & (1 << self.pins["tms"].position)) != 0 {
-                    JtagBit::TMS
-                } else {
-                    JtagBit::NONE
+        for pins in data {
+            self.push_clock(pins);
+        }
+        if self.queue.borrow().len() >= QUEUE_MAX {
+            self.flush()?;
+        }
+        Ok(())
+    }
+    fn flush(&self) -> Result<(), InterfaceError> {
+        if self.queue.borrow().is_empty() {
+            return Ok(());
+        }
+        self.transfer()?;
+        Ok(())
+    }
+
+    fn usb_stats(&self) -> Option<UsbStats> {
+        Some(self.usb.snapshot())
+    }
+    fn reset_usb_stats(&self) {
+        self.usb.reset()
+    }
+
+    fn assert_trst(&self, level: bool) -> Result<(), InterfaceError> {
+        let mut reset = self.reset.get();
+        reset.set(JtagBit::TRST, level);
+        self.reset.set(reset);
+        self.apply_reset()
+    }
+    fn assert_srst(&self, level: bool) -> Result<(), InterfaceError> {
+        let mut reset = self.reset.get();
+        reset.set(JtagBit::SRST, level);
+        self.reset.set(reset);
+        self.apply_reset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jtag::jtag::Jtag;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    // synchronous bitbangのFT232R
+    // 1byte書くごとに、書く前のpinの値を1byte読み戻せる
+    // TDOはTDIをそのまま返し、RTCKはTCKから2 sample遅れて追従する
+    struct DelayedRtck {
+        // 最後に書いた値から順に並べた履歴
+        history: RefCell<VecDeque<u8>>,
+        pending: RefCell<VecDeque<u8>>,
+        writes: Cell<usize>,
+        // write_dataを呼んだ回数
+        transfers: Cell<usize>,
+        // 1回のread_dataで返す最大のbyte数
+        read_limit: Cell<usize>,
+        chunk: Cell<usize>,
+        // これより長いwrite_dataや、読まれずに溜まったsampleはerrorにする
+        max_write: Cell<usize>,
+        rx_fifo: Cell<usize>,
+        // RTCKを返さないtarget
+        stuck: bool,
+    }
+
+    const TCK: u8 = 1 << 0;
+    const TDI: u8 = 1 << 1;
+    const TDO: u8 = 1 << 2;
+    const RTCK: u8 = 1 << 7;
+    const RTCK_DELAY: usize = 2;
+
+    impl DelayedRtck {
+        fn new(stuck: bool) -> Self {
+            DelayedRtck {
+                history: RefCell::new(VecDeque::from(vec![0; RTCK_DELAY + 1])),
+                pending: RefCell::new(VecDeque::new()),
+                writes: Cell::new(0),
+                transfers: Cell::new(0),
+                read_limit: Cell::new(usize::MAX),
+                chunk: Cell::new(CHUNK_SIZE),
+                max_write: Cell::new(usize::MAX),
+                rx_fifo: Cell::new(usize::MAX),
+                stuck,
+            }
+        }
+    }
+
+    impl BitBangDevice for DelayedRtck {
+        fn write_data(&self, data: &[u8]) -> Result<usize, InterfaceError> {
+            if data.len() > self.max_write.get() {
+                return Err(InterfaceError::Io);
+            }
+            let mut history = self.history.borrow_mut();
+            for x in data {
+                let last = history[0];
+                let mut sample = last & !(TDO | RTCK);
+                if last & TDI != 0 {
+                    sample |= TDO;
                 }
-                | if (pins & (1 << self.pins["tck"].position)) != 0 {
-                    JtagBit::TCK
-                } else {
-                    JtagBit::NONE
+                if history[RTCK_DELAY] & TCK != 0 && !self.stuck {
+                    sample |= RTCK;
                 }
-                | if (pins & (1 << self.pins["tdi"].position)) != 0 {
+                self.pending.borrow_mut().push_back(sample);
+                history.push_front(*x);
+                history.truncate(RTCK_DELAY + 1);
+            }
+            self.writes.set(self.writes.get() + data.len());
+            self.transfers.set(self.transfers.get() + 1);
+            if self.pending.borrow().len() > self.rx_fifo.get() {
+                // 溢れたsampleは失われる
+                return Err(InterfaceError::Io);
+            }
+            Ok(data.len())
+        }
+        fn read_data(&self, data: &mut [u8]) -> Result<usize, InterfaceError> {
+            let mut pending = self.pending.borrow_mut();
+            let length = cmp::min(data.len(), pending.len());
+            let length = cmp::min(length, self.read_limit.get());
+            for x in data[..length].iter_mut() {
+                *x = pending.pop_front().unwrap();
+            }
+            Ok(length)
+        }
+        fn set_bitmode(&self, _bitmask: u8) -> Result<(), InterfaceError> {
+            Ok(())
+        }
+        fn set_baudrate(&self, _baudrate: u32) -> Result<(), InterfaceError> {
+            Ok(())
+        }
+        fn chunk_size(&self) -> Result<usize, InterfaceError> {
+            Ok(self.chunk.get())
+        }
+        fn set_chunk_size(&self, size: usize) -> Result<(), InterfaceError> {
+            self.chunk.set(size);
+            Ok(())
+        }
+    }
+
+    fn bitbang(stuck: bool, adaptive_clocking: bool) -> FtdiBitBang<DelayedRtck> {
+        let pins = PinMap::new(vec![
+            (Signal::Tck, 0),
+            (Signal::Tdi, 1),
+            (Signal::Tdo, 2),
+            (Signal::Tms, 3),
+            (Signal::Srst, 4),
+            (Signal::Trst, 5),
+            (Signal::Rtck, 7),
+        ])
+        .unwrap();
+        FtdiBitBang {
+            device: DelayedRtck::new(stuck),
+            pins,
+            reset: Cell::new(JtagBit::empty()),
+            srst_open_drain: false,
+            adaptive_clocking,
+            queue: RefCell::new(Vec::new()),
+            chunk_size: CHUNK_SIZE,
+            usb: UsbCounter::new(),
+        }
+    }
+
+    fn adaptive(stuck: bool) -> FtdiBitBang<DelayedRtck> {
+        bitbang(stuck, true)
+    }
+
+    #[test]
+    fn batching_test() {
+        let bitbang = bitbang(false, false);
+        // writeだけなら送らない
+        bitbang.raw_write(&[JtagBit::TMS; 2]).unwrap();
+        assert_eq!(0, bitbang.device.transfers.get());
+
+        // 溜めた分とまとめてCHUNK_SIZEずつ送り、少しずつしか返らなくても全て読む
+        bitbang.device.read_limit.set(100);
+        let mut data: Vec<JtagBit> = (0..300)
+            .map(|i| {
+                if i % 3 == 0 {
                     JtagBit::TDI
                 } else {
-                    JtagBit::NONE
+                    JtagBit::empty()
                 }
-                | if (pins & (1 << self.pins["tdo"].position)) != 0 {
-                    JtagBit::TDO
-                } else {
-                    JtagBit::NONE
-                }
-                | if (pins & (1 << self.pins["trst"].position)) != 0 {
-                    JtagBit::TRST
-                } else {
-                    JtagBit::NONE
-                }
-                | if (pins & (1 << self.pins["srst"].position)) != 0 {
-                    JtagBit::SRST
-                } else {
-                    JtagBit::NONE
-                }
-        };
+            })
+            .collect();
+        bitbang.raw_read(&mut data).unwrap();
+        for (i, x) in data.iter().enumerate() {
+            assert_eq!(i % 3 == 0, x.contains(JtagBit::TDO));
+        }
+        assert_eq!(2, bitbang.device.transfers.get());
+        assert_eq!(2 * (2 + 300), bitbang.device.writes.get());
+        // 読み残しはない
+        assert!(bitbang.device.pending.borrow().is_empty());
 
-        jtagpin
+        bitbang.flush().unwrap();
+        assert_eq!(2, bitbang.device.transfers.get());
+        bitbang.raw_write(&[JtagBit::TMS]).unwrap();
+        bitbang.flush().unwrap();
+        assert_eq!(3, bitbang.device.transfers.get());
+        assert!(bitbang.device.pending.borrow().is_empty());
     }
-}
 
-impl JtagInterface for FtdiBitBang {
-    fn raw_read(&self, data: &mut [JtagBit]) {
-        // purge rx data
-        // TODO: read_data実行時間が遅い原因を探る
-        let mut tmp = [0; CHUNK_SIZE];
-        self.device.read_data(&mut tmp).unwrap();
+    #[test]
+    fn long_shift_test() {
+        let mut bitbang = bitbang(false, false);
+        bitbang.device.set_chunk_size(256).unwrap();
+        bitbang.device.max_write.set(256);
+        bitbang.device.rx_fifo.set(256);
+        bitbang.device.read_limit.set(100);
+        bitbang.chunk_size = bitbang.device.chunk_size().unwrap();
 
-        // debug!("raw_read: {:?}", data);
+        let mut jtag = Jtag::new(bitbang);
+        jtag.initialize().unwrap();
+        let pattern: Vec<bool> = (0..10_000).map(|i| i % 7 < 3 || i % 11 == 0).collect();
+        let mut data = pattern.clone();
+        jtag.read_write_dr(&mut data, true, false, false).unwrap();
+        // TDOはTDIをそのまま返すので、1bitも欠けずにずれてもいない
+        assert_eq!(pattern, data);
+        assert!(jtag.interface.device.transfers.get() > 2 * 10_000 / 256);
+        assert!(jtag.interface.device.pending.borrow().is_empty());
 
-        let mut buffer: Vec<u8> = Vec::new();
+        // writeだけなら溜めておき、flushでchunkに分けて送る
+        let transfers = jtag.interface.device.transfers.get();
+        jtag.idle_cycles(10_000).unwrap();
+        assert_eq!(transfers, jtag.interface.device.transfers.get());
+        jtag.interface.flush().unwrap();
+        let transfers = jtag.interface.device.transfers.get() - transfers;
+        assert_eq!((2 * 10_000usize).div_ceil(256), transfers);
+        assert!(jtag.interface.device.pending.borrow().is_empty());
 
-        for i in 0..data.len() {
-            let pin = self.pins_to_u8(&data[i]);
-            buffer.push(pin);
-            buffer.push(pin | self.pins["tck"].to_bit());
-        }
-        self.device.write_data(buffer.as_slice()).unwrap();
-        self.device.read_data(buffer.as_mut_slice()).unwrap();
-        for i in 0..data.len() {
-            data[i] = self.u8_to_pins(buffer[2 * i + 1]);
-        }
+        // chunkに分けずに送ると溢れる
+        jtag.interface.chunk_size = 4096;
+        jtag.idle_cycles(10_000).unwrap();
+        assert_eq!(Err(InterfaceError::Io), jtag.interface.flush());
     }
-    fn raw_write(&self, data: &[JtagBit]) {
-        // with clock version
-        let mut vec = Vec::with_capacity(data.len() * 2);
 
-        // debug!("raw_write: {:?}", data);
-
-        for d in data {
-            let pin_value = self.pins_to_u8(&d);
-            vec.push(pin_value);
-            vec.push(pin_value | (self.pins["tck"].to_bit()));
+    #[test]
+    fn adaptive_clocking_test() {
+        let bitbang = adaptive(false);
+        let mut data = [JtagBit::TDI, JtagBit::TMS, JtagBit::TDI | JtagBit::TMS];
+        bitbang.raw_read(&mut data).unwrap();
+        for (x, tdi) in data.iter().zip([true, false, true].iter()) {
+            assert_eq!(*tdi, x.contains(JtagBit::TDO));
         }
+        // 各edgeで、変化前の値が見える1 sampleとRTCKの遅れ2 sampleを待つ
+        // 最初のTCK lowはRTCKも既にlowなので待たない
+        let edge = 1 + RTCK_DELAY + 1;
+        assert_eq!(2 + (3 * 2 - 1) * edge, bitbang.device.writes.get());
+
+        let writes = bitbang.device.writes.get();
+        bitbang.raw_write(&[JtagBit::TMS]).unwrap();
+        assert_eq!(
+            writes + 2 * (1 + RTCK_DELAY + 1),
+            bitbang.device.writes.get()
+        );
+
+        // RTCKが返ってこなければtimeout
+        let bitbang = adaptive(true);
+        assert_eq!(
+            Err(InterfaceError::Timeout),
+            bitbang.raw_write(&[JtagBit::TMS])
+        );
+    }
 
-        self.device.write_data(vec.as_slice()).unwrap();
+    #[test]
+    fn baudrate_test() {
+        assert_eq!((10_000, 5_000), bitbang_baudrate(DEFAULT_TCK_HZ));
+        assert_eq!((BAUDRATE_MIN, 91), bitbang_baudrate(0));
+        assert_eq!((BAUDRATE_MAX, 1_500_000), bitbang_baudrate(u32::MAX));
     }
 }
@@ -0,0 +1,319 @@
+use libftdi1_sys as ftdic;
+use safe_ftdi;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::marker::PhantomData;
+use std::os::raw;
+use std::ptr;
+
+use super::ftdi::{self, DeviceSelector, ProbeError};
+use super::ftdi_bitbang::FtdiBitBang;
+use super::ftdi_mpsse::FtdiMpsse;
+use super::pins::{PinError, PinMap};
+use super::JtagInterface;
+use crate::config::{AdapterConfig, Backend};
+
+// FT2232の既定値
+const DEFAULT_VID: u16 = 0x0403;
+const DEFAULT_PID: u16 = 0x6010;
+
+// 以前からのbuilderの呼び方を残す
+pub use super::pins::Signal as Pin;
+
+// builderからinterfaceを作る
+pub trait FtdiOpen: Sized {
+    // 必ず割り当てる必要のあるpin
+    const REQUIRED_PINS: &'static [Pin];
+    // backendで位置が決まっているpin
+    const FIXED_PINS: &'static [(Pin, u8)];
+
+    fn open_with(builder: &FtdiBuilder<Self>) -> Result<Self, ProbeError>;
+}
+
+pub struct FtdiBuilder<I> {
+    vid: u16,
+    pid: u16,
+    pins: Vec<(Pin, u8)>,
+    description: Option<String>,
+    serial: Option<String>,
+    selector: Option<DeviceSelector>,
+    tck_hz: Option<u32>,
+    srst_open_drain: bool,
+    adaptive_clocking: bool,
+    chunk_size: Option<usize>,
+    interface: PhantomData<I>,
+}
+
+impl<I: FtdiOpen> Default for FtdiBuilder<I> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I: FtdiOpen> FtdiBuilder<I> {
+    pub fn new() -> Self {
+        FtdiBuilder {
+            vid: DEFAULT_VID,
+            pid: DEFAULT_PID,
+            pins: Vec::new(),
+            description: None,
+            serial: None,
+            selector: None,
+            tck_hz: None,
+            srst_open_drain: false,
+            adaptive_clocking: false,
+            chunk_size: None,
+            interface: PhantomData,
+        }
+    }
+
+    // profileのadapterから作る。backendの選択は呼び出し側で行う
+    pub fn from_config(adapter: &AdapterConfig) -> Self {
+        let mut builder = Self::new().vid(adapter.vid).pid(adapter.pid);
+        if let Some(serial) = &adapter.serial {
+            builder = builder.serial(serial);
+        }
+        if let Some(hz) = adapter.tck_hz {
+            builder = builder.tck_hz(hz);
+        }
+        for (pin, position) in adapter.pins.iter() {
+            builder = builder.pin(*pin, *position);
+        }
+        builder
+    }
+
+    pub fn vid(mut self, vid: u16) -> Self {
+        self.vid = vid;
+        self
+    }
+    pub fn pid(mut self, pid: u16) -> Self {
+        self.pid = pid;
+        self
+    }
+    pub fn pin(mut self, pin: Pin, position: u8) -> Self {
+        self.pins.retain(|x| x.0 != pin);
+        self.pins.push((pin, position));
+        self
+    }
+    // 同じVID/PIDのadapterが複数ある場合に使う
+    pub fn description(mut self, description: &str) -> Self {
+        self.description = Some(description.to_string());
+        self
+    }
+    pub fn serial(mut self, serial: &str) -> Self {
+        self.serial = Some(serial.to_string());
+        self
+    }
+    // 同じdescriptionやserialのadapterはindexやUSBのportで選ぶ
+    // 設定した場合はdescription/serialより優先する
+    pub fn selector(mut self, selector: DeviceSelector) -> Self {
+        self.selector = Some(selector);
+        self
+    }
+    pub fn tck_hz(mut self, hz: u32) -> Self {
+        self.tck_hz = Some(hz);
+        self
+    }
+
+    // SRSTを解除する時はHを出さずにHi-Zにする
+    // targetがSRSTを他の回路と共有している場合に使う
+    pub fn srst_open_drain(mut self, open_drain: bool) -> Self {
+        self.srst_open_drain = open_drain;
+        self
+    }
+
+    // TCKの各edgeでtargetがRTCKを返すのを待つ
+    // coreのclockが遅いARM9/ARM11などで使う
+    pub fn adaptive_clocking(mut self, enable: bool) -> Self {
+        self.adaptive_clocking = enable;
+        self
+    }
+
+    // libftdiの1回のUSB転送のbyte数。bitbangではrx bufferに収まる値にする
+    pub fn chunk_size(mut self, size: usize) -> Self {
+        self.chunk_size = Some(size);
+        self
+    }
+
+    pub fn open(&self) -> Result<I, ProbeError> {
+        I::open_with(self)
+    }
+
+    pub fn initial_tck_hz(&self) -> Option<u32> {
+        self.tck_hz
+    }
+
+    pub fn is_srst_open_drain(&self) -> bool {
+        self.srst_open_drain
+    }
+
+    pub fn is_adaptive_clocking(&self) -> bool {
+        self.adaptive_clocking
+    }
+
+    pub fn configured_chunk_size(&self) -> Option<usize> {
+        self.chunk_size
+    }
+
+    // pinの割り当てを確認して返す
+    pub fn pins(&self) -> Result<HashMap<Pin, u8>, PinError> {
+        let mut pins: HashMap<Pin, u8> = HashMap::new();
+        for (pin, position) in I::FIXED_PINS.iter().chain(self.pins.iter()) {
+            if *position > 15 {
+                return Err(PinError::InvalidPosition {
+                    signal: *pin,
+                    position: *position,
+                });
+            }
+            match pins.get(pin) {
+                Some(fixed) if *fixed != *position => {
+                    return Err(PinError::Fixed {
+                        signal: *pin,
+                        position: *fixed,
+                    })
+                }
+                _ => {}
+            }
+            if let Some((other, _)) = pins.iter().find(|x| *x.1 == *position && *x.0 != *pin) {
+                return Err(PinError::Shared {
+                    first: *other,
+                    second: *pin,
+                    position: *position,
+                });
+            }
+            pins.insert(*pin, *position);
+        }
+        for pin in I::REQUIRED_PINS {
+            if !pins.contains_key(pin) {
+                return Err(PinError::Missing(*pin));
+            }
+        }
+        Ok(pins)
+    }
+
+    pub fn pin_map(&self) -> Result<PinMap, PinError> {
+        PinMap::new(self.pins()?)
+    }
+
+    pub fn open_device(&self) -> Result<safe_ftdi::Context, ProbeError> {
+        if let Some(selector) = &self.selector {
+            return ftdi::open_selected(self.vid, self.pid, selector);
+        }
+        let mut device = safe_ftdi::Context::new()?;
+        if self.description.is_none() && self.serial.is_none() {
+            device.open(self.vid, self.pid)?;
+            return Ok(device);
+        }
+
+        let c_string = |x: &Option<String>| {
+            x.as_deref()
+                .map(CString::new)
+                .transpose()
+                .map_err(|_| ProbeError::InvalidSelector(format!("{:?} contains NUL", x)))
+        };
+        let description = c_string(&self.description)?;
+        let serial = c_string(&self.serial)?;
+        let as_ptr = |x: &Option<CString>| x.as_ref().map_or(ptr::null(), |x| x.as_ptr());
+        let context = device.get_ftdi_context();
+        let rc = unsafe {
+            ftdic::ftdi_usb_open_desc(
+                context,
+                raw::c_int::from(self.vid),
+                raw::c_int::from(self.pid),
+                as_ptr(&description),
+                as_ptr(&serial),
+            )
+        };
+        if rc < 0 {
+            let message = unsafe { CStr::from_ptr(ftdic::ftdi_get_error_string(context)) };
+            return Err(ProbeError::Ftdi(format!(
+                "failed to open {:#06x}:{:#06x} (description {:?}, serial {:?}): {}",
+                self.vid,
+                self.pid,
+                self.description,
+                self.serial,
+                message.to_string_lossy()
+            )));
+        }
+        Ok(device)
+    }
+}
+
+// adapter.backendを実行時に選んで開く。selectorは複数のadapterから1つを選ぶ場合に渡す
+pub fn create_interface(
+    adapter: &AdapterConfig,
+    selector: Option<DeviceSelector>,
+) -> Result<Box<dyn JtagInterface>, ProbeError> {
+    fn open<I: FtdiOpen + JtagInterface + 'static>(
+        adapter: &AdapterConfig,
+        selector: Option<DeviceSelector>,
+    ) -> Result<Box<dyn JtagInterface>, ProbeError> {
+        let mut builder = FtdiBuilder::<I>::from_config(adapter);
+        if let Some(selector) = selector {
+            builder = builder.selector(selector);
+        }
+        Ok(Box::new(builder.open()?))
+    }
+    match adapter.backend {
+        Backend::BitBang => open::<FtdiBitBang>(adapter, selector),
+        Backend::Mpsse => open::<FtdiMpsse>(adapter, selector),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FourPins;
+
+    impl FtdiOpen for FourPins {
+        const REQUIRED_PINS: &'static [Pin] = &[Pin::Tck, Pin::Tdi, Pin::Tdo, Pin::Tms];
+        const FIXED_PINS: &'static [(Pin, u8)] = &[(Pin::Tck, 0)];
+
+        fn open_with(_: &FtdiBuilder<Self>) -> Result<Self, ProbeError> {
+            Ok(FourPins)
+        }
+    }
+
+    #[test]
+    fn pins_test() {
+        let builder = FtdiBuilder::<FourPins>::new()
+            .pin(Pin::Tdi, 1)
+            .pin(Pin::Tdo, 2)
+            .pin(Pin::Tms, 3);
+        let pins = builder.pins().unwrap();
+        assert_eq!(Some(&0), pins.get(&Pin::Tck));
+        assert_eq!(Some(&3), pins.get(&Pin::Tms));
+
+        // 同じ位置の割り当て
+        let builder = builder.pin(Pin::Srst, 3);
+        assert_eq!(
+            Err(PinError::Shared {
+                first: Pin::Tms,
+                second: Pin::Srst,
+                position: 3
+            }),
+            builder.pins()
+        );
+        // 後から指定した値で上書きされる
+        assert!(builder.pin(Pin::Srst, 4).pins().is_ok());
+
+        // 固定位置と異なる
+        let builder = FtdiBuilder::<FourPins>::new()
+            .pin(Pin::Tck, 5)
+            .pin(Pin::Tdi, 1)
+            .pin(Pin::Tdo, 2)
+            .pin(Pin::Tms, 3);
+        assert_eq!(
+            Err(PinError::Fixed {
+                signal: Pin::Tck,
+                position: 0
+            }),
+            builder.pins()
+        );
+
+        // 足りない
+        let builder = FtdiBuilder::<FourPins>::new().pin(Pin::Tdi, 1);
+        assert_eq!(Err(PinError::Missing(Pin::Tdo)), builder.pins());
+    }
+}
@@ -1,34 +1,103 @@
-use anyhow::{Context, Result};
 use log::{debug, error, info, warn};
 use safe_ftdi;
+use std::cell::Cell;
 use std::cmp;
-use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
-use super::JtagInterface;
+use super::ftdi::{DeviceSelector, ProbeError};
+use super::ftdi_builder::{FtdiBuilder, FtdiOpen, Pin};
+use super::pins::{PinMap, Signal};
+use super::{bits, CapFlags, InterfaceCaps, InterfaceError, JtagInterface};
+use crate::jtag::stats::{UsbCounter, UsbStats};
 use crate::jtag::JtagBit;
 
+// 1回のbatchで読み出すbyte数
 const CHUNK_SIZE: usize = 512;
+// batchの読み出しが揃うまで待つ時間
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
 
-struct FtdiJtagPin {
-    position: u8,
-    input: bool,
-    initial_value: bool,
+// TCK = base / ((1 + divisor) * 2)
+const MPSSE_BASE_CLOCK_HZ: u32 = 60_000_000;
+// divide-by-5を有効にした時のbase clock
+const MPSSE_BASE_CLOCK_DIV5_HZ: u32 = 12_000_000;
+const MPSSE_DIVISOR_MAX: u32 = 0xFFFF;
+
+// 60MHzで届かない低速側だけ12MHzを使う
+// (divide-by-5を使うか, divisor, 実際の周波数)を返す
+fn mpsse_clock_divisor(hz: u32) -> (bool, u16, u32) {
+    let hz = cmp::max(hz, 1);
+    let minimum = MPSSE_BASE_CLOCK_HZ / ((1 + MPSSE_DIVISOR_MAX) * 2);
+    let (div5, base) = if hz < minimum {
+        (true, MPSSE_BASE_CLOCK_DIV5_HZ)
+    } else {
+        (false, MPSSE_BASE_CLOCK_HZ)
+    };
+    // 要求より速くならないように切り上げる
+    let divisor = (base / 2).div_ceil(hz).saturating_sub(1);
+    let divisor = cmp::min(divisor, MPSSE_DIVISOR_MAX);
+    (div5, divisor as u16, base / ((1 + divisor) * 2))
+}
+
+// ADBUSのTDIとTMS
+const GPIO_TDI: u16 = 1 << 1;
+const GPIO_TMS: u16 = 1 << 3;
+// 0x4B/0x6Bで1 commandに送れるTMSのbit数。bit 7はTDIの値になる
+const MPSSE_TMS_BITS_MAX: usize = 7;
+// 不正なcommandを受け取ると、MPSSEはこのbyteに続けてそのopcodeを返す
+const MPSSE_BAD_COMMAND: u8 = 0xFA;
+
+// 受信したbyte列から0xFAの応答を探し、不正だったopcodeを返す
+// 0xFAは普通のTDOにも現れるので、byte数が合わない時にだけ使う
+fn find_bad_command(received: &[u8]) -> Option<u8> {
+    received
+        .windows(2)
+        .find(|x| x[0] == MPSSE_BAD_COMMAND)
+        .map(|x| x[1])
 }
 
-impl FtdiJtagPin {
-    pub fn to_bit(&self) -> u8 {
-        1 << self.position
+// FtdiMpsseが使うdevice側の操作
+// テストでsafe_ftdi::Contextを差し替えられるようにする
+pub trait MpsseDevice {
+    fn write_data(&self, data: &[u8]) -> Result<usize, InterfaceError>;
+    fn read_data(&self, data: &mut [u8]) -> Result<usize, InterfaceError>;
+    fn purge_usb_rx_buffer(&self) -> Result<(), InterfaceError>;
+    fn purge_usb_tx_buffer(&self) -> Result<(), InterfaceError>;
+}
+
+impl MpsseDevice for safe_ftdi::Context {
+    fn write_data(&self, data: &[u8]) -> Result<usize, InterfaceError> {
+        Ok(safe_ftdi::Context::write_data(self, data)? as usize)
+    }
+    fn read_data(&self, data: &mut [u8]) -> Result<usize, InterfaceError> {
+        Ok(safe_ftdi::Context::read_data(self, data)? as usize)
+    }
+    fn purge_usb_rx_buffer(&self) -> Result<(), InterfaceError> {
+        Ok(safe_ftdi::Context::purge_usb_rx_buffer(self)?)
+    }
+    fn purge_usb_tx_buffer(&self) -> Result<(), InterfaceError> {
+        Ok(safe_ftdi::Context::purge_usb_tx_buffer(self)?)
     }
 }
 
-pub struct FtdiMpsse {
-    device: safe_ftdi::Context,
-    pins: HashMap<String, FtdiJtagPin>,
+pub struct FtdiMpsse<D = safe_ftdi::Context> {
+    device: D,
+    pins: PinMap,
+    // 最後に0x80/0x82で設定したADBUS/ACBUSのvalueとdirection
+    gpio: Cell<u16>,
+    direction: Cell<u16>,
+    srst_open_drain: bool,
+    // 0x96でRTCKを待つようにしてある
+    adaptive_clocking: bool,
+    usb: UsbCounter,
+    // 送ったcommandに対して、まだ受け取っていないbyte数
+    outstanding: Cell<usize>,
 }
 
 enum MpsseOpcode {
+    ClockDataBytesNoReadOutFalling = 0x19,
     ClockDataBitsNoReadOutOutRising = 0x1A,
     ClockDataBitsNoReadOutOutFalling = 0x1B,
+    ClockDataBytesInandOutLSBfirstInRisingOutFalling = 0x39,
     ClockDataBitsInandOutLSBfirstInRisingOutFalling = 0x3B,
     ClockDataBitsInandOutLSBfirstInFallingOutRaising = 0x3E,
     ClockDataToTMSpinNoReadOutRising = 0x4A,
@@ -38,147 +107,492 @@ enum MpsseOpcode {
     ClockDataToTMSpinWithReadInRisingOutFalling = 0x6E,
     ClockDataToTMSpinWithReadInFallingOutFalling = 0x6F,
     ClockForNbitsWithNoDataTransfer = 0x8E,
+    ClockForNx8bitsWithNoDataTransfer = 0x8F,
 }
 
-impl FtdiMpsse {
-    pub fn new(vid: u16, pid: u16, srst: u8, trst: u8) -> Self {
-        // pins
-        let mut pins: HashMap<String, FtdiJtagPin> = HashMap::new();
-
-        pins.insert(
-            "tck".to_string(),
-            FtdiJtagPin {
-                position: 0,
-                input: false,
-                initial_value: false,
-            },
-        );
-        pins.insert(
-            "tdi".to_string(),
-            FtdiJtagPin {
-                position: 1,
-                input: false,
-                initial_value: false,
-            },
-        );
-        pins.insert(
-            "tdo".to_string(),
-            FtdiJtagPin {
-                position: 2,
-                input: true,
-                initial_value: false,
-            },
-        );
-        pins.insert(
-            "tms".to_string(),
-            FtdiJtagPin {
-                position: 3,
-                input: false,
-                initial_value: true,
-            },
-        );
-        pins.insert(
-            "srst".to_string(),
-            FtdiJtagPin {
-                position: srst,
-                input: false,
-                initial_value: false,
-            },
-        );
-        pins.insert(
-            "trst".to_string(),
-            FtdiJtagPin {
-                position: trst,
-                input: false,
-                initial_value: false,
-            },
-        );
-        pins.insert(
-            "rtck".to_string(),
-            FtdiJtagPin {
-                position: 7,
-                input: true,
-                initial_value: false,
-            },
+// shiftするTDIをbool列とLSB firstで詰めたbyte列のどちらからでも取り出す
+trait TdiBits {
+    fn bit(&self, index: usize) -> bool;
+    // startから8bit以内をbyteに詰める
+    fn byte(&self, start: usize, length: usize) -> u8;
+    // startから8*count bitをbyte列にして追加する
+    fn extend_bytes(&self, start: usize, count: usize, out: &mut Vec<u8>);
+}
+
+impl TdiBits for [bool] {
+    fn bit(&self, index: usize) -> bool {
+        self[index]
+    }
+    fn byte(&self, start: usize, length: usize) -> u8 {
+        bits::pack_byte(&self[start..start + length])
+    }
+    fn extend_bytes(&self, start: usize, count: usize, out: &mut Vec<u8>) {
+        out.extend(
+            self[start..start + count * 8]
+                .chunks(8)
+                .map(bits::pack_byte),
         );
+    }
+}
+
+// Shift::Bytes/Bitsの先頭は常にbyte境界にある
+impl TdiBits for [u8] {
+    fn bit(&self, index: usize) -> bool {
+        self[index / 8] & (1 << (index % 8)) != 0
+    }
+    fn byte(&self, start: usize, length: usize) -> u8 {
+        debug_assert_eq!(start % 8, 0);
+        self[start / 8] & (0xffu16 >> (8 - length)) as u8
+    }
+    fn extend_bytes(&self, start: usize, count: usize, out: &mut Vec<u8>) {
+        debug_assert_eq!(start % 8, 0);
+        out.extend_from_slice(&self[start / 8..start / 8 + count]);
+    }
+}
+
+// 読んだTDOを書き込む側
+trait TdoBits {
+    fn set_bit(&mut self, index: usize, level: bool);
+    fn set_bytes(&mut self, start: usize, response: &[u8]);
+    // LSB firstのbit modeで読んだlength bitはbyteの上位側に詰まっている
+    fn set_msb_aligned(&mut self, start: usize, length: usize, response: u8);
+}
+
+impl TdoBits for [bool] {
+    fn set_bit(&mut self, index: usize, level: bool) {
+        self[index] = level;
+    }
+    fn set_bytes(&mut self, start: usize, response: &[u8]) {
+        bits::unpack(response, &mut self[start..start + response.len() * 8]);
+    }
+    fn set_msb_aligned(&mut self, start: usize, length: usize, response: u8) {
+        bits::unpack_msb_aligned(response, &mut self[start..start + length]);
+    }
+}
+
+impl TdoBits for [u8] {
+    fn set_bit(&mut self, index: usize, level: bool) {
+        let mask = 1 << (index % 8);
+        if level {
+            self[index / 8] |= mask;
+        } else {
+            self[index / 8] &= !mask;
+        }
+    }
+    fn set_bytes(&mut self, start: usize, response: &[u8]) {
+        self[start / 8..start / 8 + response.len()].copy_from_slice(response);
+    }
+    fn set_msb_aligned(&mut self, start: usize, length: usize, response: u8) {
+        self[start / 8] = response >> (8 - length);
+    }
+}
+
+// 1回のshiftをbyte mode, bit mode, TMSのcommandに分ける
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Shift {
+    // (先頭のbit, byte数)
+    Bytes(usize, usize),
+    // (先頭のbit, bit数)
+    Bits(usize, usize),
+    // exit時の最後のbit
+    Tms(usize),
+}
+
+impl Shift {
+    // readした時に返ってくるbyte数
+    fn response_len(&self) -> usize {
+        match self {
+            Shift::Bytes(_, count) => *count,
+            _ => 1,
+        }
+    }
+
+    fn command<I: TdiBits + ?Sized>(&self, tdi: &I, read: bool) -> Vec<u8> {
+        match *self {
+            Shift::Bytes(start, count) => {
+                let opcode = if read {
+                    MpsseOpcode::ClockDataBytesInandOutLSBfirstInRisingOutFalling
+                } else {
+                    MpsseOpcode::ClockDataBytesNoReadOutFalling
+                };
+                let length = (count - 1) as u16;
+                let mut command = vec![opcode as u8, (length & 0xff) as u8, (length >> 8) as u8];
+                tdi.extend_bytes(start, count, &mut command);
+                command
+            }
+            Shift::Bits(start, length) => {
+                let opcode = if read {
+                    MpsseOpcode::ClockDataBitsInandOutLSBfirstInRisingOutFalling
+                } else {
+                    MpsseOpcode::ClockDataBitsNoReadOutOutFalling
+                };
+                vec![opcode as u8, (length - 1) as u8, tdi.byte(start, length)]
+            }
+            Shift::Tms(index) => {
+                let opcode = if read {
+                    MpsseOpcode::ClockDataToTMSpinWithReadInFallingOutRising
+                } else {
+                    MpsseOpcode::ClockDataToTMSpinNoReadOutFalling
+                };
+                // TMSを1bit立て、bit7でTDIを指定する
+                vec![opcode as u8, 0, 3 | if tdi.bit(index) { 0x80 } else { 0 }]
+            }
+        }
+    }
+
+    fn unpack<O: TdoBits + ?Sized>(&self, response: &[u8], tdo: &mut O) {
+        match *self {
+            Shift::Bytes(start, count) => tdo.set_bytes(start, &response[..count]),
+            Shift::Bits(start, length) => tdo.set_msb_aligned(start, length, response[0]),
+            Shift::Tms(index) => tdo.set_bit(index, response[0] & 0x80 != 0),
+        }
+    }
+}
+
+// 8bit以上はbyte modeで送り、残りをbit modeで送る
+fn shift_segments(len: usize, exit: bool) -> Vec<Shift> {
+    let data_len = len - if exit { 1 } else { 0 };
+    let mut segments = Vec::new();
+    let mut start = 0;
+    // 1 commandで返ってくるbyte数をCHUNK_SIZEに収める
+    while data_len - start >= 8 {
+        let count = cmp::min((data_len - start) / 8, CHUNK_SIZE);
+        segments.push(Shift::Bytes(start, count));
+        start += count * 8;
+    }
+    if start < data_len {
+        segments.push(Shift::Bits(start, data_len - start));
+    }
+    if exit {
+        segments.push(Shift::Tms(data_len));
+    }
+    segments
+}
 
-        let mut device = safe_ftdi::Context::new().unwrap();
-        device
-            .open(vid, pid)
-            .with_context(|| format!("failed to open {:#04x}:{:#04x}", vid, pid))
-            .unwrap();
-        device.set_baudrate(1000).unwrap();
+impl FtdiOpen for FtdiMpsse<safe_ftdi::Context> {
+    const REQUIRED_PINS: &'static [Pin] = &[Pin::Srst, Pin::Trst];
+    // MPSSEのJTAG信号はADBUS0-3に固定される
+    const FIXED_PINS: &'static [(Pin, u8)] = &[
+        (Pin::Tck, 0),
+        (Pin::Tdi, 1),
+        (Pin::Tdo, 2),
+        (Pin::Tms, 3),
+        (Pin::Rtck, 7),
+    ];
 
-        device
-            .set_bitmode(0, safe_ftdi::mpsse::MpsseMode::BITMODE_MPSSE)
-            .unwrap();
+    fn open_with(builder: &FtdiBuilder<Self>) -> Result<Self, ProbeError> {
+        let pins = builder.pin_map()?;
+        let device = builder.open_device()?;
+        device.set_baudrate(1000)?;
+        device.set_bitmode(0, safe_ftdi::mpsse::MpsseMode::BITMODE_MPSSE)?;
 
-        let ftdi_mpsse = FtdiMpsse {
+        let mut ftdi_mpsse = FtdiMpsse {
             device: device,
             pins: pins,
+            gpio: Cell::new(0),
+            direction: Cell::new(0),
+            srst_open_drain: builder.is_srst_open_drain(),
+            adaptive_clocking: builder.is_adaptive_clocking(),
+            usb: UsbCounter::new(),
+            outstanding: Cell::new(0),
         };
 
-        ftdi_mpsse.init_mpsse();
+        ftdi_mpsse.init_mpsse(builder.is_adaptive_clocking())?;
+        // tck_hzがNoneの場合は最も遅いclockにする
+        ftdi_mpsse.set_tck_hz(builder.initial_tck_hz().unwrap_or(0))?;
+
+        Ok(ftdi_mpsse)
+    }
+}
+
+impl FtdiMpsse<safe_ftdi::Context> {
+    pub fn builder() -> FtdiBuilder<Self> {
+        FtdiBuilder::new()
+    }
+
+    pub fn new(
+        vid: u16,
+        pid: u16,
+        srst: u8,
+        trst: u8,
+        tck_hz: Option<u32>,
+    ) -> Result<Self, ProbeError> {
+        Self::pin_builder(vid, pid, srst, trst, tck_hz).open()
+    }
 
-        ftdi_mpsse
+    // 同じVID/PIDのadapterが複数つながっている場合に使う
+    pub fn new_with_selector(
+        vid: u16,
+        pid: u16,
+        selector: DeviceSelector,
+        srst: u8,
+        trst: u8,
+        tck_hz: Option<u32>,
+    ) -> Result<Self, ProbeError> {
+        Self::pin_builder(vid, pid, srst, trst, tck_hz)
+            .selector(selector)
+            .open()
+    }
+
+    fn pin_builder(
+        vid: u16,
+        pid: u16,
+        srst: u8,
+        trst: u8,
+        tck_hz: Option<u32>,
+    ) -> FtdiBuilder<Self> {
+        let builder = Self::builder()
+            .vid(vid)
+            .pid(pid)
+            .pin(Pin::Srst, srst)
+            .pin(Pin::Trst, trst);
+        match tck_hz {
+            Some(hz) => builder.tck_hz(hz),
+            None => builder,
+        }
     }
 
-    fn sync_rxbuffer(&self) {
+    fn sync_rxbuffer(&self) -> Result<(), InterfaceError> {
         // sync rx buffer
-        self.device.write_data(&[0xAA]).unwrap();
+        self.device.write_data(&[0xAA])?;
 
         let mut tmp = [0];
-        self.device.read_data(&mut tmp).unwrap();
+        self.device.read_data(&mut tmp)?;
         let mut before_data = tmp[0];
         loop {
-            self.device.read_data(&mut tmp).unwrap();
+            self.device.read_data(&mut tmp)?;
             let mut next_data = tmp[0];
-            if before_data == 0xFA && next_data == 0xAA {
+            if before_data == MPSSE_BAD_COMMAND && next_data == 0xAA {
                 break;
             } else {
                 before_data = next_data;
             }
         }
+        Ok(())
     }
 
-    fn init_mpsse(&self) {
-        self.sync_rxbuffer();
-        // use 60MHz clock
-        self.device.write_data(&[0x8A]).unwrap();
-        // disable adaptive clock
-        self.device.write_data(&[0x97]).unwrap();
+    fn init_mpsse(&self, adaptive_clocking: bool) -> Result<(), InterfaceError> {
+        self.sync_rxbuffer()?;
+        // 0x96: enable adaptive clock, 0x97: disable
+        self.device
+            .write_data(&[if adaptive_clocking { 0x96 } else { 0x97 }])?;
         // disable 3 phase clock
-        self.device.write_data(&[0x97]).unwrap();
+        self.device.write_data(&[0x97])?;
         // setup direction
-        let direction = !self
-            .pins
-            .iter()
-            .filter(|x| x.1.input)
-            .fold(0 as u16, |x, y| x + y.1.to_bit() as u16);
-        // TODO: directionとvalueを自動設定できるようにする
+        // TODO: directionとvalueをPinMapから自動設定できるようにする
         let direction = 0x0a1b;
-        let direction_low = (direction & 0xff) as u8;
-        let direction_high = (direction >> 8) as u8;
-        let value = self
-            .pins
-            .iter()
-            .filter(|x| x.1.initial_value)
-            .fold(0 as u16, |x, y| x + y.1.to_bit() as u16);
         let value = 0x0808;
-        let value_low = (value & 0xff) as u8;
-        let value_high = (value >> 8) as u8;
         debug!("value: {:#4x}", value);
         debug!("direction: {:#4x}", direction);
-        self.device
-            .write_data(&[0x80, value_low, direction_low])
-            .unwrap();
-        self.device
-            .write_data(&[0x82, value_high, direction_high])
-            .unwrap();
-        // setup clock speed(min)
-        self.device.write_data(&[0x86, 0xFF, 0xFF]).unwrap();
+        self.gpio.set(value);
+        self.direction.set(direction);
+        // TRST/SRSTを解除した状態から始める
+        self.reset_level(Signal::Trst, false, false);
+        self.reset_level(Signal::Srst, false, self.srst_open_drain);
+        self.write_gpio()?;
         // disable loopback
-        self.device.write_data(&[0x85]).unwrap();
+        self.device.write_data(&[0x85])?;
+        Ok(())
+    }
+}
+
+impl<D: MpsseDevice> FtdiMpsse<D> {
+    // 実際に設定されたTCKの周波数を返す
+    pub fn set_tck_hz(&mut self, hz: u32) -> Result<u32, InterfaceError> {
+        let (div5, divisor, achieved) = mpsse_clock_divisor(hz);
+        if achieved != hz {
+            info!("TCK {}Hz requested, using {}Hz", hz, achieved);
+        }
+        // 0x8A: disable clock divide by 5, 0x8B: enable
+        let div5 = if div5 { 0x8B } else { 0x8A };
+        self.write_all(&[div5, 0x86, (divisor & 0xff) as u8, (divisor >> 8) as u8])?;
+        Ok(achieved)
+    }
+
+    // 0x80/0x82でADBUS/ACBUSをまとめて設定する
+    fn write_gpio(&self) -> Result<(), InterfaceError> {
+        let value = self.gpio.get();
+        let direction = self.direction.get();
+        self.write_all(&[
+            0x80,
+            (value & 0xff) as u8,
+            (direction & 0xff) as u8,
+            0x82,
+            (value >> 8) as u8,
+            (direction >> 8) as u8,
+        ])
+    }
+
+    // assertはL、解除はH(open drainならHi-Z)
+    // 他のpinは最後に設定した値のままにする
+    fn reset_level(&self, signal: Signal, asserted: bool, open_drain: bool) -> bool {
+        let bit = self.pins.mask(signal);
+        if bit == 0 {
+            return false;
+        }
+        let value = self.gpio.get();
+        let direction = self.direction.get();
+        self.gpio.set(if asserted || open_drain {
+            value & !bit
+        } else {
+            value | bit
+        });
+        self.direction.set(if asserted || !open_drain {
+            direction | bit
+        } else {
+            direction & !bit
+        });
+        true
+    }
+
+    fn drive_reset(
+        &self,
+        signal: Signal,
+        asserted: bool,
+        open_drain: bool,
+    ) -> Result<(), InterfaceError> {
+        if !self.reset_level(signal, asserted, open_drain) {
+            return Err(InterfaceError::Unsupported);
+        }
+        self.write_gpio()
+    }
+
+    // commandの後はTMS/TDIが最後のbitのまま保持される
+    fn track(&self, bit: u16, level: bool) {
+        let value = self.gpio.get();
+        self.gpio
+            .set(if level { value | bit } else { value & !bit });
+    }
+    fn track_tms(&self, level: bool) {
+        self.track(GPIO_TMS, level)
+    }
+    fn track_tdi<I: TdiBits + ?Sized>(&self, tdi: &I, len: usize) {
+        if len > 0 {
+            self.track(GPIO_TDI, tdi.bit(len - 1))
+        }
+    }
+
+    fn shift_write<I: TdiBits + ?Sized>(
+        &self,
+        tdi: &I,
+        len: usize,
+        exit: bool,
+    ) -> Result<(), InterfaceError> {
+        let commands: Vec<u8> = shift_segments(len, exit)
+            .iter()
+            .flat_map(|x| x.command(tdi, false))
+            .collect();
+        self.write_all(commands.as_slice())?;
+        self.track_tdi(tdi, len);
+        if exit {
+            self.track_tms(true);
+        }
+        Ok(())
+    }
+
+    // tdiはshift前のtdoの写し
+    fn shift_read<I: TdiBits + ?Sized, O: TdoBits + ?Sized>(
+        &self,
+        tdi: &I,
+        tdo: &mut O,
+        len: usize,
+        exit: bool,
+    ) -> Result<(), InterfaceError> {
+        // https://gist.github.com/bjornvaktaren/d2461738ec44e3ad8b3bae4ce69445b4#file-minimal_spi-cpp-L96
+        self.device.purge_usb_tx_buffer()?;
+        self.device.purge_usb_rx_buffer()?;
+        if self.outstanding.get() != 0 {
+            warn!(
+                "dropped {} bytes left from the previous read",
+                self.outstanding.get()
+            );
+            self.outstanding.set(0);
+        }
+
+        let segments = shift_segments(len, exit);
+        // 返ってくるbyte数がCHUNK_SIZEに収まるようにまとめて送る
+        let mut batch_start = 0;
+        while batch_start < segments.len() {
+            let mut expected = 0;
+            let mut batch_end = batch_start;
+            while batch_end < segments.len()
+                && (batch_end == batch_start
+                    || expected + segments[batch_end].response_len() <= CHUNK_SIZE)
+            {
+                expected += segments[batch_end].response_len();
+                batch_end += 1;
+            }
+            let batch = &segments[batch_start..batch_end];
+            let commands: Vec<u8> = batch.iter().flat_map(|x| x.command(tdi, true)).collect();
+            self.write_all(commands.as_slice())?;
+
+            let received = self.read_exact(expected)?;
+            debug!("read {:?} bytes: {:02x?}", received.len(), received);
+            let mut offset = 0;
+            for segment in batch {
+                let length = segment.response_len();
+                segment.unpack(&received[offset..offset + length], tdo);
+                offset += length;
+            }
+            batch_start = batch_end;
+        }
+
+        debug!("read/write {:?} bits", len);
+        self.track_tdi(tdi, len);
+        if exit {
+            self.track_tms(true);
+        }
+        Ok(())
+    }
+
+    fn write_all(&self, data: &[u8]) -> Result<(), InterfaceError> {
+        let res = self.device.write_data(data)?;
+        self.usb.wrote(res);
+        if res != data.len() {
+            return Err(InterfaceError::ShortWrite);
+        }
+        Ok(())
+    }
+
+    // 要求したbyte数が揃うまで読み続ける
+    // 多すぎる、または揃わない場合は0xFAの応答が混ざっていないか調べる
+    fn read_exact(&self, length: usize) -> Result<Vec<u8>, InterfaceError> {
+        self.outstanding.set(length);
+        let mut received = Vec::with_capacity(length);
+        let mut buffer = [0; CHUNK_SIZE];
+        let deadline = Instant::now() + READ_TIMEOUT;
+        while received.len() < length {
+            // 余計なbyteも受け取れるように、残りの長さではなくbuffer全体で読む
+            let res = self.device.read_data(&mut buffer)?;
+            self.usb.read(res);
+            received.extend_from_slice(&buffer[..res]);
+            self.outstanding.set(length.saturating_sub(received.len()));
+            if res == 0 && Instant::now() > deadline {
+                warn!("read timed out: {} of {} bytes", received.len(), length);
+                return Err(self.recover(&received, InterfaceError::Timeout));
+            }
+        }
+        if received.len() != length {
+            warn!("expected {} bytes, received {}", length, received.len());
+            return Err(self.recover(&received, InterfaceError::Io));
+        }
+        Ok(received)
+    }
+
+    // 受信bufferを捨てて次のcommandに備え、0xFAがあればMpsseProtocolを返す
+    fn recover(&self, received: &[u8], fallback: InterfaceError) -> InterfaceError {
+        debug!("unexpected response: {:02x?}", received);
+        if let Err(e) = self.device.purge_usb_rx_buffer() {
+            warn!("failed to purge rx buffer: {}", e);
+        }
+        self.outstanding.set(0);
+        match find_bad_command(received) {
+            Some(bad_opcode) => {
+                error!("MPSSE rejected command {:#04x}", bad_opcode);
+                InterfaceError::MpsseProtocol { bad_opcode }
+            }
+            None => fallback,
+        }
     }
 
     // fn separate(&self, data: &[JtagBit]) -> Vec<Vec<JtagBit>>{
@@ -193,145 +607,476 @@ impl FtdiMpsse {
     // }
 }
 
-impl JtagInterface for FtdiMpsse {
-    fn write_tms(&self, tms: &[bool]) {
+impl<D: MpsseDevice> JtagInterface for FtdiMpsse<D> {
+    // read_dataはCHUNK_SIZEずつのbatchに分けるので長さの制限はない
+    fn capabilities(&self) -> InterfaceCaps {
+        let mut flags = CapFlags::SUPPORTS_PACKED_IO | CapFlags::SUPPORTS_IDLE_CLOCK;
+        flags.set(
+            CapFlags::HAS_SRST,
+            self.pins.position(Signal::Srst).is_some(),
+        );
+        flags.set(
+            CapFlags::HAS_TRST,
+            self.pins.position(Signal::Trst).is_some(),
+        );
+        flags.set(CapFlags::ADAPTIVE_CLOCKING, self.adaptive_clocking);
+        InterfaceCaps::new(flags)
+    }
+
+    fn write_tms(&self, tms: &[bool]) -> Result<(), InterfaceError> {
+        // TMSを送っている間も、直前のshiftで出したTDIを保持する
+        let tdi = if self.gpio.get() & GPIO_TDI != 0 {
+            0x80
+        } else {
+            0
+        };
         let mut commands: Vec<u8> = Vec::new();
-        // Clock Data TMS pin(no read) command can only send 6 bits at a command
-        const MPSSE_TMS_BITS_MAX: usize = 7;
-        for i in (0..tms.len()).step_by(MPSSE_TMS_BITS_MAX) {
-            let rest = tms.len() - i;
-            let length = cmp::min(rest, MPSSE_TMS_BITS_MAX) as u8;
-            // debug!(
-            //     "length {:?}, rest {:?}, tms.len() {:?}",
-            //     length,
-            //     rest,
-            //     tms.len()
-            // );
-            let mut byte1 = 0;
-            for j in 0..length {
-                byte1 = byte1 | ((tms[i + j as usize] as u8) << j);
-            }
+        for chunk in tms.chunks(MPSSE_TMS_BITS_MAX) {
+            let byte1 = chunk
+                .iter()
+                .enumerate()
+                .fold(tdi, |x, (j, y)| x | ((*y as u8) << j));
             commands.push(MpsseOpcode::ClockDataToTMSpinNoReadOutFalling as u8);
-            commands.push(length - 1);
+            commands.push(chunk.len() as u8 - 1);
             commands.push(byte1);
         }
-        // debug!("write_tms tms: {:?}", tms);
-        // debug!("write_tms commands: {:?}", commands);
-        let res = self.device.write_data(commands.as_slice()).unwrap() as usize;
-        if res != commands.len() {
-            panic!("write failed at write_tms");
+        self.write_all(commands.as_slice())?;
+        if let Some(last) = tms.last() {
+            self.track_tms(*last);
         }
+        Ok(())
     }
 
-    fn write_data(&self, tdi: &[bool], exit: bool) {
+    fn clock_idle(&self, n: usize) -> Result<(), InterfaceError> {
+        // 0x8E/0x8FはTMSのpinを今の値のまま保持するので、Lになっている場合だけ使える
+        if self.gpio.get() & GPIO_TMS != 0 {
+            return self.write_tms(&vec![false; n]);
+        }
         let mut commands: Vec<u8> = Vec::new();
-        let tdi_length = tdi.len() - if exit { 1 } else { 0 };
-        for i in (0..tdi_length).step_by(8) {
-            let rest = tdi_length - i;
-            let length = cmp::min(rest, 8) as u8;
-            let mut byte1 = 0;
-            for j in 0..length {
-                byte1 = byte1 | ((tdi[i + j as usize] as u8) << j);
-            }
-            commands.push(MpsseOpcode::ClockDataBitsInandOutLSBfirstInRisingOutFalling as u8);
-            commands.push(length - 1);
-            commands.push(byte1);
+        let mut rest = n;
+        while rest >= 8 {
+            let count = cmp::min(rest / 8, 0x10000);
+            let length = (count - 1) as u16;
+            commands.push(MpsseOpcode::ClockForNx8bitsWithNoDataTransfer as u8);
+            commands.push((length & 0xff) as u8);
+            commands.push((length >> 8) as u8);
+            rest -= count * 8;
         }
-        if exit {
-            // Append TMS bit
-            let byte1 = 3 | if *tdi.last().unwrap() { 0x80 } else { 0 };
-            commands.push(MpsseOpcode::ClockDataToTMSpinNoReadOutFalling as u8);
-            commands.push(0);
-            commands.push(byte1);
+        if rest > 0 {
+            commands.push(MpsseOpcode::ClockForNbitsWithNoDataTransfer as u8);
+            commands.push(rest as u8 - 1);
         }
-        // debug!(
-        //     "write_data commands({} bytes): {:?}",
-        //     commands.len(),
-        //     commands
-        // );
-        self.device.write_data(commands.as_slice()).unwrap();
+        self.write_all(commands.as_slice())
     }
-    fn read_data(&self, tditdo: &mut [bool], exit: bool) {
-        // sync
-        // TODO: improve
-        let mut buffer = [0; CHUNK_SIZE];
-        let res = self.device.read_data(&mut buffer).unwrap();
-        debug!("read {:?} bytes", res);
-        // self.sync_rxbuffer();
 
-        // TODO: CHUNK_SIZE超えを処理する
-        let mut commands: Vec<u8> = Vec::new();
-        // "Clock Data Bits In and Out LSB first" command cannot send tms
-        let tditdo_length = tditdo.len() - if exit { 1 } else { 0 };
-        for i in (0..tditdo_length).step_by(8) {
-            let rest = tditdo_length - i;
-            let length = cmp::min(rest, 8) as u8;
-            // debug!(
-            //     "length {:?}, rest {:?}, tditdo_length {:?}, tditdo.len() {:?}",
-            //     length,
-            //     rest,
-            //     tditdo_length,
-            //     tditdo.len()
-            // );
-            let mut byte1 = 0;
-            for j in 0..length {
-                byte1 = byte1 | ((tditdo[i + j as usize] as u8) << j);
+    fn write_data(&self, tdi: &[bool], exit: bool) -> Result<(), InterfaceError> {
+        self.shift_write(tdi, tdi.len(), exit)
+    }
+
+    fn read_data(&self, tditdo: &mut [bool], exit: bool) -> Result<(), InterfaceError> {
+        let tdi = tditdo.to_vec();
+        self.shift_read(tdi.as_slice(), tditdo, tdi.len(), exit)
+    }
+
+    // byte列のままcommandに積めるのでbool列を経由しない
+    fn write_data_packed(&self, tdi: &[u8], len: usize, exit: bool) -> Result<(), InterfaceError> {
+        if tdi.len() < bits::byte_len(len) {
+            return Err(InterfaceError::OutOfRange);
+        }
+        self.shift_write(tdi, len, exit)
+    }
+
+    fn read_data_packed(
+        &self,
+        tditdo: &mut [u8],
+        len: usize,
+        exit: bool,
+    ) -> Result<(), InterfaceError> {
+        let length = bits::byte_len(len);
+        if tditdo.len() < length {
+            return Err(InterfaceError::OutOfRange);
+        }
+        let tdi = tditdo[..length].to_vec();
+        self.shift_read(tdi.as_slice(), tditdo, len, exit)?;
+        if !len.is_multiple_of(8) {
+            tditdo[length - 1] &= (1 << (len % 8)) - 1;
+        }
+        Ok(())
+    }
+
+    // MPSSEはTMS/TDIをbit毎に混ぜて送れない
+    fn raw_read(&self, _data: &mut [JtagBit]) -> Result<(), InterfaceError> {
+        Err(InterfaceError::Unsupported)
+    }
+
+    fn raw_write(&self, _data: &[JtagBit]) -> Result<(), InterfaceError> {
+        Err(InterfaceError::Unsupported)
+    }
+
+    fn usb_stats(&self) -> Option<UsbStats> {
+        Some(self.usb.snapshot())
+    }
+    fn reset_usb_stats(&self) {
+        self.usb.reset()
+    }
+
+    fn assert_trst(&self, level: bool) -> Result<(), InterfaceError> {
+        self.drive_reset(Signal::Trst, level, false)
+    }
+    fn assert_srst(&self, level: bool) -> Result<(), InterfaceError> {
+        self.drive_reset(Signal::Srst, level, self.srst_open_drain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
+
+    // TDIとTDOを直結した状態を模し、読み出しをchunk byteずつ返すdevice
+    struct ChunkedLoopback {
+        chunk: usize,
+        pending: RefCell<VecDeque<u8>>,
+        reads: Cell<usize>,
+        written: RefCell<Vec<u8>>,
+        // 次のwriteの応答の途中(先頭から1byte後)に混ぜるbyte列
+        inject: RefCell<Vec<u8>>,
+    }
+
+    impl ChunkedLoopback {
+        fn new(chunk: usize) -> Self {
+            ChunkedLoopback {
+                chunk,
+                pending: RefCell::new(VecDeque::new()),
+                reads: Cell::new(0),
+                written: RefCell::new(Vec::new()),
+                inject: RefCell::new(Vec::new()),
             }
-            commands.push(MpsseOpcode::ClockDataBitsInandOutLSBfirstInRisingOutFalling as u8);
-            commands.push(length - 1);
-            commands.push(byte1);
         }
-        if exit {
-            // Append TMS bit
-            let byte1 = 3 | if *tditdo.last().unwrap() { 0x80 } else { 0 };
-            // commands.push(MpsseOpcode::ClockDataToTMSpinWithReadInFallingOutRising as u8);
-            commands.push(0x6B);
-            commands.push(0);
-            commands.push(byte1);
+    }
+
+    impl MpsseDevice for ChunkedLoopback {
+        fn write_data(&self, data: &[u8]) -> Result<usize, InterfaceError> {
+            self.written.borrow_mut().extend_from_slice(data);
+            let mut pending = self.pending.borrow_mut();
+            let mut i = 0;
+            while i < data.len() {
+                match data[i] {
+                    0x8E => i += 2,
+                    0x19 | 0x39 => {
+                        let count = (data[i + 1] as usize | (data[i + 2] as usize) << 8) + 1;
+                        if data[i] == 0x39 {
+                            pending.extend(&data[i + 3..i + 3 + count]);
+                        }
+                        i += 3 + count;
+                    }
+                    opcode => {
+                        let length = data[i + 1] as u32 + 1;
+                        match opcode {
+                            0x3B => pending.push_back(data[i + 2] << (8 - length)),
+                            0x6B => pending.push_back(data[i + 2] & 0x80),
+                            _ => (),
+                        }
+                        i += 3;
+                    }
+                }
+            }
+            let at = cmp::min(1, pending.len());
+            for x in self.inject.borrow_mut().drain(..).rev() {
+                pending.insert(at, x);
+            }
+            Ok(data.len())
         }
-        // debug!("read_data tditdo({} bits): {:?}", tditdo.len(), tditdo);
-        // debug!(
-        //     "read_data commands({} bytes): {:?}",
-        //     commands.len(),
-        //     commands
-        // );
-        // https://gist.github.com/bjornvaktaren/d2461738ec44e3ad8b3bae4ce69445b4#file-minimal_spi-cpp-L96
-        self.device.purge_usb_tx_buffer().unwrap();
-        self.device.purge_usb_rx_buffer().unwrap();
-        self.device.write_data(commands.as_mut_slice()).unwrap();
-        let mut buffer = [0; CHUNK_SIZE];
-        let buffer_idx_max = tditdo.len() / 8 + if (tditdo.len() % 8) != 0 { 1 } else { 0 };
-        // let res = self.device.read_data(&mut buffer[0..buffer_idx_max]).unwrap();
-        let res = self.device.read_data(&mut buffer).unwrap();
-
-        // [u8] to [bool]
-        for i in 0..tditdo.len() {
-            let index = i / 8;
-            let position = i % 8;
-            tditdo[i] = buffer[index] & (1 << position) != 0;
-        }
-
-        debug!("read/write {:?} bits, buffer {:?} bytes", tditdo.len(), res);
-        debug!("{:?}", buffer);
-        let mut reversed_buffer = Vec::new();
-        reversed_buffer.extend_from_slice(&buffer[0..(res as usize)]);
-        reversed_buffer.reverse();
-        debug!(
-            "read buffer: {:?}",
-            reversed_buffer
-                .iter()
-                .map(|x| format!("{:02x}", x))
-                .collect::<String>()
+        fn read_data(&self, data: &mut [u8]) -> Result<usize, InterfaceError> {
+            self.reads.set(self.reads.get() + 1);
+            let mut pending = self.pending.borrow_mut();
+            let length = cmp::min(cmp::min(self.chunk, data.len()), pending.len());
+            for x in data[..length].iter_mut() {
+                *x = pending.pop_front().unwrap();
+            }
+            Ok(length)
+        }
+        fn purge_usb_rx_buffer(&self) -> Result<(), InterfaceError> {
+            self.pending.borrow_mut().clear();
+            Ok(())
+        }
+        fn purge_usb_tx_buffer(&self) -> Result<(), InterfaceError> {
+            Ok(())
+        }
+    }
+
+    fn loopback(chunk: usize) -> FtdiMpsse<ChunkedLoopback> {
+        FtdiMpsse {
+            device: ChunkedLoopback::new(chunk),
+            pins: PinMap::default(),
+            gpio: Cell::new(0),
+            direction: Cell::new(0),
+            srst_open_drain: false,
+            adaptive_clocking: false,
+            usb: UsbCounter::new(),
+            outstanding: Cell::new(0),
+        }
+    }
+
+    #[test]
+    fn clock_divisor_test() {
+        assert_eq!((false, 0, 30_000_000), mpsse_clock_divisor(30_000_000));
+        // 上限を超えたら最速にする
+        assert_eq!((false, 0, 30_000_000), mpsse_clock_divisor(100_000_000));
+        assert_eq!((false, 29, 1_000_000), mpsse_clock_divisor(1_000_000));
+        // 割り切れない場合は遅い方に丸める
+        assert_eq!((false, 2, 10_000_000), mpsse_clock_divisor(12_000_000));
+        // 60MHzのdivisorで届かない場合は12MHzを使う
+        assert_eq!((false, 0xFFFF, 457), mpsse_clock_divisor(457));
+        assert_eq!((true, 59999, 100), mpsse_clock_divisor(100));
+        assert_eq!((true, 0xFFFF, 91), mpsse_clock_divisor(0));
+    }
+
+    #[test]
+    fn read_data_chunked_test() {
+        let mpsse = loopback(3);
+        // CHUNK_SIZE*8bitを超え、端数のあるshift
+        let pattern: Vec<bool> = (0..CHUNK_SIZE * 8 + 13)
+            .map(|i| (i * 7 + i / 3) % 5 < 2)
+            .collect();
+        for exit in [false, true].iter() {
+            let mut tditdo = pattern.clone();
+            mpsse.read_data(&mut tditdo, *exit).unwrap();
+            assert_eq!(pattern, tditdo);
+        }
+        assert!(mpsse.device.reads.get() > CHUNK_SIZE.div_ceil(3));
+    }
+
+    #[test]
+    fn read_data_short_test() {
+        let mpsse = loopback(3);
+        // TMSで送る最後のbitだけのshift
+        for pattern in [vec![true], vec![false, true, true], vec![true; 9]].iter() {
+            let mut tditdo = pattern.clone();
+            mpsse.read_data(&mut tditdo, true).unwrap();
+            assert_eq!(*pattern, tditdo);
+        }
+    }
+
+    #[test]
+    fn bad_command_test() {
+        let mpsse = loopback(CHUNK_SIZE);
+        let pattern: Vec<bool> = (0..35).map(|i| i % 3 == 0).collect();
+        // 0x39の応答の途中に0xFAと不正だったopcodeが混ざる
+        mpsse.device.inject.borrow_mut().extend(&[0xFA, 0xAB]);
+        let mut tditdo = pattern.clone();
+        assert_eq!(
+            Err(InterfaceError::MpsseProtocol { bad_opcode: 0xAB }),
+            mpsse.read_data(&mut tditdo, true)
+        );
+        // 残りは捨ててあり、次のshiftは正しく読める
+        assert!(mpsse.device.pending.borrow().is_empty());
+        assert_eq!(0, mpsse.outstanding.get());
+        let mut tditdo = pattern.clone();
+        mpsse.read_data(&mut tditdo, true).unwrap();
+        assert_eq!(pattern, tditdo);
+
+        // 0xFAを含まない余計なbyte
+        mpsse.device.inject.borrow_mut().push(0x55);
+        let mut tditdo = pattern.clone();
+        assert_eq!(Err(InterfaceError::Io), mpsse.read_data(&mut tditdo, true));
+
+        assert_eq!(None, find_bad_command(&[0x12, 0xFA]));
+        assert_eq!(Some(0x00), find_bad_command(&[0x12, 0xFA, 0x00, 0xFA]));
+    }
+
+    // bit数ごとに送るcommand列を確認する
+    fn commands(len: usize, exit: bool, read: bool) -> Vec<u8> {
+        let mpsse = loopback(CHUNK_SIZE);
+        let mut tditdo: Vec<bool> = (0..len).map(|i| i % 3 == 0).collect();
+        if read {
+            mpsse.read_data(&mut tditdo, exit).unwrap();
+        } else {
+            mpsse.write_data(&tditdo, exit).unwrap();
+        }
+        mpsse.device.written.into_inner()
+    }
+
+    #[test]
+    fn command_stream_test() {
+        // 0b1001_0010_0100_1001, ...のbit列
+        let bytes = [0x49u8, 0x92, 0x24];
+        assert_eq!(vec![0x6B, 0, 0x83], commands(1, true, true));
+        assert_eq!(vec![0x3B, 0, 0x01], commands(1, false, true));
+        assert_eq!(vec![0x3B, 6, 0x49, 0x6B, 0, 0x03], commands(8, true, true));
+        assert_eq!(vec![0x19, 0, 0, 0x49], commands(8, false, false));
+        assert_eq!(
+            vec![0x39, 0, 0, 0x49, 0x6B, 0, 0x03],
+            commands(9, true, true)
         );
+        let mut expected = vec![
+            0x39, 3, 0, 0x49, 0x92, 0x24, 0x49, 0x3B, 1, 0x02, 0x6B, 0, 0x03,
+        ];
+        assert_eq!(expected, commands(35, true, true));
+        expected[0] = 0x19;
+        expected[7] = 0x1B;
+        expected[10] = 0x4B;
+        assert_eq!(expected, commands(35, true, false));
+
+        // 511byteと7bitとTMS
+        let written = commands(4096, true, true);
+        assert_eq!([0x39, 0xFE, 0x01], written[..3]);
+        for (i, x) in written[3..3 + 511].iter().enumerate() {
+            assert_eq!(bytes[i % 3], *x);
+        }
+        assert_eq!([0x3B, 6, 0x12, 0x6B, 0, 0x83], written[3 + 511..]);
+        // exitしない場合は512byteを1 commandで送る
+        assert_eq!([0x39, 0xFF, 0x01], commands(4096, false, true)[..3]);
+    }
+
+    #[test]
+    fn packed_test() {
+        for len in [1, 3, 8, 9, 35, 100, CHUNK_SIZE * 8 + 13].iter() {
+            let pattern: Vec<bool> = (0..*len).map(|i| (i * 7 + i / 3) % 5 < 2).collect();
+            let mut packed = vec![0u8; bits::byte_len(*len)];
+            bits::pack(&pattern, &mut packed);
+            for exit in [false, true].iter() {
+                // bool列と同じcommandを送り、同じTDOを返す
+                let by_bool = loopback(3);
+                let mut tditdo = pattern.clone();
+                by_bool.read_data(&mut tditdo, *exit).unwrap();
+                let by_byte = loopback(3);
+                let mut result = packed.clone();
+                by_byte.read_data_packed(&mut result, *len, *exit).unwrap();
+                assert_eq!(by_bool.device.written, by_byte.device.written);
+                assert_eq!(packed, result, "{} bits", len);
+                assert_eq!(by_bool.gpio.get(), by_byte.gpio.get());
 
-        // debug!("tditdo: {:?}", tditdo);
+                let by_bool = loopback(3);
+                by_bool.write_data(&pattern, *exit).unwrap();
+                let by_byte = loopback(3);
+                by_byte.write_data_packed(&packed, *len, *exit).unwrap();
+                assert_eq!(by_bool.device.written, by_byte.device.written);
+            }
+        }
+        // len以降のbitは送らず、読んだ後は0になる
+        let mpsse = loopback(3);
+        let mut tditdo = [0xffu8, 0xff];
+        mpsse.read_data_packed(&mut tditdo, 11, true).unwrap();
+        assert_eq!([0xff, 0x07], tditdo);
+        assert_eq!(
+            Err(InterfaceError::OutOfRange),
+            mpsse.write_data_packed(&[0; 1], 9, true)
+        );
     }
 
-    fn raw_read(&self, data: &mut [JtagBit]) {
-        unimplemented!();
+    #[test]
+    fn tms_stream_test() {
+        let mpsse = loopback(CHUNK_SIZE);
+        let tms = |len: usize| -> Vec<u8> {
+            mpsse.device.written.borrow_mut().clear();
+            let tms: Vec<bool> = (0..len).map(|i| i % 3 != 1).collect();
+            mpsse.write_tms(&tms).unwrap();
+            mpsse.device.written.borrow().clone()
+        };
+        // 0b...101_101
+        assert_eq!(vec![0x4B, 0, 0x01], tms(1));
+        assert_eq!(vec![0x4B, 4, 0x0d], tms(5));
+        assert_eq!(vec![0x4B, 6, 0x6d], tms(7));
+        assert_eq!(vec![0x4B, 6, 0x6d, 0x4B, 0, 0x00], tms(8));
+        assert_eq!(vec![0x4B, 6, 0x6d, 0x4B, 5, 0x36], tms(13));
+
+        // 最後にTDIへHを出した後は、bit 7でHを保持する
+        mpsse.write_data(&[false, true], false).unwrap();
+        assert_eq!(vec![0x4B, 0, 0x81], tms(1));
+        assert_eq!(vec![0x4B, 4, 0x8d], tms(5));
+        assert_eq!(vec![0x4B, 6, 0xed], tms(7));
+        assert_eq!(vec![0x4B, 6, 0xed, 0x4B, 0, 0x80], tms(8));
+        assert_eq!(vec![0x4B, 6, 0xed, 0x4B, 5, 0xb6], tms(13));
+        // exitのbitでTDIをLにした場合
+        let mut tditdo = [true, false];
+        mpsse.read_data(&mut tditdo, true).unwrap();
+        assert_eq!(vec![0x4B, 0, 0x01], tms(1));
     }
 
-    fn raw_write(&self, data: &[JtagBit]) {
-        unimplemented!();
+    #[test]
+    fn raw_access_test() {
+        let mpsse = loopback(CHUNK_SIZE);
+        let mut data = [JtagBit::TMS; 4];
+        assert_eq!(Err(InterfaceError::Unsupported), mpsse.raw_write(&data));
+        assert_eq!(Err(InterfaceError::Unsupported), mpsse.raw_read(&mut data));
+        assert!(mpsse.device.written.borrow().is_empty());
+    }
+
+    #[test]
+    fn clock_idle_test() {
+        let mpsse = loopback(CHUNK_SIZE);
+        let idle = |n: usize| -> Vec<u8> {
+            mpsse.device.written.borrow_mut().clear();
+            mpsse.clock_idle(n).unwrap();
+            mpsse.device.written.borrow().clone()
+        };
+        assert_eq!(Vec::<u8>::new(), idle(0));
+        assert_eq!(vec![0x8E, 0], idle(1));
+        assert_eq!(vec![0x8E, 6], idle(7));
+        assert_eq!(vec![0x8F, 0, 0], idle(8));
+        assert_eq!(vec![0x8F, 1, 0, 0x8E, 3], idle(20));
+        assert_eq!(vec![0x8F, 0xff, 0xff, 0x8F, 0, 0], idle(0x80008));
+
+        // TMSがHのままなら、TMSのcommandでLを出す
+        mpsse.write_tms(&[true]).unwrap();
+        assert_eq!(vec![0x4B, 2, 0x00], idle(3));
+        assert_eq!(vec![0x8E, 2], idle(3));
+    }
+
+    #[test]
+    fn usb_stats_test() {
+        let mpsse = loopback(4);
+        mpsse.write_tms(&[true; 8]).unwrap();
+        let mut tditdo = [true; 20];
+        mpsse.read_data(&mut tditdo, true).unwrap();
+        // TMSの2 commandと、shiftの3 command(0x39, 0x3B, 0x6B)をそれぞれ1回で書く
+        // 返ってくる4byteは1回で読める
+        assert_eq!(
+            Some(UsbStats {
+                writes: 2,
+                bytes_written: 6 + 11,
+                reads: 1,
+                bytes_read: 4,
+            }),
+            mpsse.usb_stats()
+        );
+        mpsse.reset_usb_stats();
+        assert_eq!(Some(UsbStats::default()), mpsse.usb_stats());
+    }
+
+    #[test]
+    fn reset_test() {
+        let mut mpsse = loopback(CHUNK_SIZE);
+        assert_eq!(Err(InterfaceError::Unsupported), mpsse.assert_srst(true));
+        mpsse.pins = PinMap::new(vec![(Signal::Srst, 9), (Signal::Trst, 4)]).unwrap();
+        mpsse.gpio.set(0x0200);
+        mpsse.direction.set(0x021b);
+
+        // TMSを1にしたままSRSTを操作する
+        mpsse.write_tms(&[false, true]).unwrap();
+        mpsse.device.written.borrow_mut().clear();
+        mpsse.assert_srst(true).unwrap();
+        mpsse.assert_trst(true).unwrap();
+        mpsse.assert_srst(false).unwrap();
+        assert_eq!(
+            vec![
+                0x80, 0x08, 0x1b, 0x82, 0x00, 0x02, // SRST L
+                0x80, 0x08, 0x1b, 0x82, 0x00, 0x02, // TRST L
+                0x80, 0x08, 0x1b, 0x82, 0x02, 0x02, // SRST H
+            ],
+            *mpsse.device.written.borrow()
+        );
+
+        // open drainでは解除時にHi-Zにする
+        mpsse.srst_open_drain = true;
+        mpsse.device.written.borrow_mut().clear();
+        mpsse.write_tms(&[false]).unwrap();
+        mpsse.assert_srst(false).unwrap();
+        assert_eq!(
+            [0x80, 0x00, 0x1b, 0x82, 0x00, 0x00],
+            mpsse.device.written.borrow()[3..]
+        );
     }
 }
@@ -0,0 +1,191 @@
+// MCUのGPIOを直接叩くbitbang interface
+// allocを使わないので、RP2040などのprobe上でも動かせる
+use core::cell::Cell;
+use core::hint;
+
+use super::{CapFlags, InterfaceCaps, InterfaceError, JtagInterface};
+use crate::jtag::JtagBit;
+
+// 1本のGPIO。SIOのset/clear registerのように&selfで操作できるものを想定する
+pub trait InputOutputPin {
+    fn set(&self, high: bool);
+    fn is_high(&self) -> bool;
+}
+
+pub struct GpioBitbang<P: InputOutputPin> {
+    tck: P,
+    tms: P,
+    tdi: P,
+    tdo: P,
+    srst: Option<P>,
+    trst: Option<P>,
+    // assert_trst/assert_srstで保持しているreset
+    reset: Cell<JtagBit>,
+    // TCKの半周期に回すspin loopの回数
+    half_period: u32,
+}
+
+impl<P: InputOutputPin> GpioBitbang<P> {
+    pub fn new(tck: P, tms: P, tdi: P, tdo: P) -> Self {
+        tck.set(false);
+        GpioBitbang {
+            tck,
+            tms,
+            tdi,
+            tdo,
+            srst: None,
+            trst: None,
+            reset: Cell::new(JtagBit::empty()),
+            half_period: 0,
+        }
+    }
+
+    pub fn with_reset(mut self, srst: Option<P>, trst: Option<P>) -> Self {
+        self.srst = srst;
+        self.trst = trst;
+        self.set_reset(&JtagBit::empty());
+        self
+    }
+
+    pub fn set_half_period(&mut self, cycles: u32) {
+        self.half_period = cycles;
+    }
+
+    fn wait(&self) {
+        for _ in 0..self.half_period {
+            hint::spin_loop();
+        }
+    }
+
+    // TRST/SRSTはactive low
+    fn set_reset(&self, pins: &JtagBit) {
+        let reset = *pins | self.reset.get();
+        if let Some(srst) = &self.srst {
+            srst.set(!reset.contains(JtagBit::SRST));
+        }
+        if let Some(trst) = &self.trst {
+            trst.set(!reset.contains(JtagBit::TRST));
+        }
+    }
+
+    fn hold_reset(&self, pin: &Option<P>, bit: JtagBit, level: bool) -> Result<(), InterfaceError> {
+        if pin.is_none() {
+            return Err(InterfaceError::Unsupported);
+        }
+        let mut reset = self.reset.get();
+        reset.set(bit, level);
+        self.reset.set(reset);
+        self.set_reset(&JtagBit::empty());
+        Ok(())
+    }
+
+    // TCKを1回立ち上げてTDOを返す
+    // TDOは立ち下がりで変わるので、ftdi_bitbangと同じく立ち上がり後に読む
+    fn clock(&self, pins: &JtagBit) -> bool {
+        self.tms.set(pins.contains(JtagBit::TMS));
+        self.tdi.set(pins.contains(JtagBit::TDI));
+        self.set_reset(pins);
+        self.tck.set(false);
+        self.wait();
+        self.tck.set(true);
+        let tdo = self.tdo.is_high();
+        self.wait();
+        tdo
+    }
+}
+
+impl<P: InputOutputPin> JtagInterface for GpioBitbang<P> {
+    fn capabilities(&self) -> InterfaceCaps {
+        let mut flags = CapFlags::empty();
+        flags.set(CapFlags::HAS_SRST, self.srst.is_some());
+        flags.set(CapFlags::HAS_TRST, self.trst.is_some());
+        InterfaceCaps::new(flags)
+    }
+
+    fn raw_write(&self, data: &[JtagBit]) -> Result<(), InterfaceError> {
+        for pins in data {
+            self.clock(pins);
+        }
+        Ok(())
+    }
+
+    fn raw_read(&self, data: &mut [JtagBit]) -> Result<(), InterfaceError> {
+        for pins in data.iter_mut() {
+            let tdo = self.clock(pins);
+            pins.set(JtagBit::TDO, tdo);
+        }
+        Ok(())
+    }
+
+    fn assert_trst(&self, level: bool) -> Result<(), InterfaceError> {
+        self.hold_reset(&self.trst, JtagBit::TRST, level)
+    }
+    fn assert_srst(&self, level: bool) -> Result<(), InterfaceError> {
+        self.hold_reset(&self.srst, JtagBit::SRST, level)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::interface::conformance::tests::SimTap;
+    use crate::interface::conformance::{run, HarnessKind};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // 4本のpinとSimTapを繋いだ配線。TCKの立ち上がりでTAPを進める
+    #[derive(Default)]
+    struct Wires {
+        tms: Cell<bool>,
+        tdi: Cell<bool>,
+        tdo: Cell<bool>,
+        tck: Cell<bool>,
+    }
+
+    struct SimPin {
+        wires: Rc<Wires>,
+        tap: Rc<SimTap>,
+        name: &'static str,
+    }
+
+    impl InputOutputPin for SimPin {
+        fn set(&self, high: bool) {
+            let wires = &self.wires;
+            match self.name {
+                "tms" => wires.tms.set(high),
+                "tdi" => wires.tdi.set(high),
+                "tck" => {
+                    if high && !wires.tck.get() {
+                        let mut pins = JtagBit::empty();
+                        pins.set(JtagBit::TMS, wires.tms.get());
+                        pins.set(JtagBit::TDI, wires.tdi.get());
+                        wires.tdo.set(self.tap.clock(pins));
+                    }
+                    wires.tck.set(high);
+                }
+                _ => (),
+            }
+        }
+        fn is_high(&self) -> bool {
+            self.wires.tdo.get()
+        }
+    }
+
+    #[test]
+    fn single_tap_conformance_test() {
+        let wires = Rc::new(Wires::default());
+        let tap = Rc::new(SimTap::new(0x4ba0_0477, 4));
+        let pin = |name| SimPin {
+            wires: wires.clone(),
+            tap: tap.clone(),
+            name,
+        };
+        let mut iface = GpioBitbang::new(pin("tck"), pin("tms"), pin("tdi"), pin("tdo"));
+        let harness = HarnessKind::SingleTap {
+            idcode: 0x4ba0_0477,
+            ir_len: 4,
+        };
+        let report = run(&mut iface, &harness);
+        assert!(report.passed(), "{}", report);
+    }
+}
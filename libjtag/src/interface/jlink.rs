@@ -0,0 +1,352 @@
+// SEGGER J-LinkをUSB経由で使うbackend
+// JTAGの操作は全てEMU_CMD_HW_JTAG3にまとめ、TMS/TDIを詰めて送りTDOを受け取る
+use log::{error, info, warn};
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use super::{bits, CapFlags, InterfaceCaps, InterfaceError, JtagInterface};
+use crate::jtag::stats::{UsbCounter, UsbStats};
+use crate::jtag::JtagBit;
+
+#[allow(dead_code)]
+#[repr(u8)]
+enum JLinkCommand {
+    Version = 0x01,
+    SetSpeed = 0x05,
+    SelectInterface = 0xC7,
+    HwJtag3 = 0xCF,
+    HwReset0 = 0xDC,
+    HwReset1 = 0xDD,
+    HwTrst0 = 0xDE,
+    HwTrst1 = 0xDF,
+}
+
+const INTERFACE_JTAG: u8 = 0;
+// 1回のHW_JTAG3で送るbit数。TMSとTDIそれぞれこのbyte数まで
+const JTAG3_MAX_BYTES: usize = 512;
+const JTAG3_MAX_BITS: usize = JTAG3_MAX_BYTES * 8;
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+// J-LinkのUSB bulk endpointへの読み書き
+// rusbなどで実装し、serialで指定したprobeを開く
+pub trait JLinkDevice: Sized {
+    // serialがNoneなら最初に見つかったJ-Linkを開く
+    fn open(serial: Option<&str>) -> Result<Self, InterfaceError>;
+    fn write_data(&self, data: &[u8]) -> Result<usize, InterfaceError>;
+    fn read_data(&self, data: &mut [u8]) -> Result<usize, InterfaceError>;
+}
+
+pub struct JLink<D: JLinkDevice> {
+    device: D,
+    // TMSだけを送る間は直前のTDIを保持する
+    tdi: Cell<bool>,
+    usb: UsbCounter,
+}
+
+// HW_JTAG3のcommandを組み立てる: [0xCF, 0, bit数(LE 16bit), TMS..., TDI...]
+fn jtag3_command(tms: &[bool], tdi: &[bool]) -> Vec<u8> {
+    let length = bits::byte_len(tms.len());
+    let mut command = vec![0; 4 + length * 2];
+    command[0] = JLinkCommand::HwJtag3 as u8;
+    command[2] = (tms.len() & 0xff) as u8;
+    command[3] = (tms.len() >> 8) as u8;
+    bits::pack(tms, &mut command[4..4 + length]);
+    bits::pack(tdi, &mut command[4 + length..]);
+    command
+}
+
+impl<D: JLinkDevice> JLink<D> {
+    pub fn open(serial: Option<&str>, speed_khz: u16) -> Result<Self, InterfaceError> {
+        Self::new(D::open(serial)?, speed_khz)
+    }
+
+    pub fn new(device: D, speed_khz: u16) -> Result<Self, InterfaceError> {
+        let jlink = JLink {
+            device,
+            tdi: Cell::new(false),
+            usb: UsbCounter::new(),
+        };
+        jlink.select_interface(INTERFACE_JTAG)?;
+        jlink.set_speed_khz(speed_khz)?;
+        Ok(jlink)
+    }
+
+    pub fn set_speed_khz(&self, khz: u16) -> Result<(), InterfaceError> {
+        info!("J-Link speed: {}kHz", khz);
+        self.write_all(&[
+            JLinkCommand::SetSpeed as u8,
+            (khz & 0xff) as u8,
+            (khz >> 8) as u8,
+        ])
+    }
+
+    // 切り替える前のinterfaceが4byteで返る
+    fn select_interface(&self, interface: u8) -> Result<(), InterfaceError> {
+        self.write_all(&[JLinkCommand::SelectInterface as u8, interface])?;
+        self.read_exact(4)?;
+        Ok(())
+    }
+
+    fn write_all(&self, data: &[u8]) -> Result<(), InterfaceError> {
+        let res = self.device.write_data(data)?;
+        self.usb.wrote(res);
+        if res != data.len() {
+            return Err(InterfaceError::ShortWrite);
+        }
+        Ok(())
+    }
+
+    fn read_exact(&self, length: usize) -> Result<Vec<u8>, InterfaceError> {
+        let mut received = vec![0; length];
+        let mut offset = 0;
+        let deadline = Instant::now() + READ_TIMEOUT;
+        while offset < length {
+            let res = self.device.read_data(&mut received[offset..])?;
+            self.usb.read(res);
+            offset += res;
+            if res == 0 && Instant::now() > deadline {
+                warn!("read timed out: {} of {} bytes", offset, length);
+                return Err(InterfaceError::Timeout);
+            }
+        }
+        Ok(received)
+    }
+
+    // tmsとtdiは同じ長さ。HW_JTAG3は書くだけでも必ずTDOとstatusを返す
+    fn shift(
+        &self,
+        tms: &[bool],
+        tdi: &[bool],
+        mut tdo: Option<&mut [bool]>,
+    ) -> Result<(), InterfaceError> {
+        for (i, (tms, tdi)) in tms
+            .chunks(JTAG3_MAX_BITS)
+            .zip(tdi.chunks(JTAG3_MAX_BITS))
+            .enumerate()
+        {
+            self.write_all(&jtag3_command(tms, tdi))?;
+            let length = bits::byte_len(tms.len());
+            let response = self.read_exact(length + 1)?;
+            if response[length] != 0 {
+                error!("HW_JTAG3 failed: status {:#x}", response[length]);
+                return Err(InterfaceError::Io);
+            }
+            if let Some(tdo) = tdo.as_deref_mut() {
+                let start = i * JTAG3_MAX_BITS;
+                bits::unpack(&response, &mut tdo[start..start + tms.len()]);
+            }
+        }
+        if let Some(last) = tdi.last() {
+            self.tdi.set(*last);
+        }
+        Ok(())
+    }
+
+    fn data_tms(length: usize, exit: bool) -> Vec<bool> {
+        let mut tms = vec![false; length];
+        if exit {
+            if let Some(last) = tms.last_mut() {
+                *last = true;
+            }
+        }
+        tms
+    }
+}
+
+impl<D: JLinkDevice> JtagInterface for JLink<D> {
+    fn capabilities(&self) -> InterfaceCaps {
+        InterfaceCaps::new(CapFlags::HAS_SRST | CapFlags::HAS_TRST)
+    }
+
+    fn write_tms(&self, tms: &[bool]) -> Result<(), InterfaceError> {
+        let tdi = vec![self.tdi.get(); tms.len()];
+        self.shift(tms, &tdi, None)
+    }
+
+    fn write_data(&self, tdi: &[bool], exit: bool) -> Result<(), InterfaceError> {
+        self.shift(&Self::data_tms(tdi.len(), exit), tdi, None)
+    }
+
+    fn read_data(&self, tditdo: &mut [bool], exit: bool) -> Result<(), InterfaceError> {
+        let tdi = tditdo.to_vec();
+        self.shift(&Self::data_tms(tdi.len(), exit), &tdi, Some(tditdo))
+    }
+
+    fn raw_write(&self, data: &[JtagBit]) -> Result<(), InterfaceError> {
+        let tms: Vec<bool> = data.iter().map(|x| x.contains(JtagBit::TMS)).collect();
+        let tdi: Vec<bool> = data.iter().map(|x| x.contains(JtagBit::TDI)).collect();
+        self.shift(&tms, &tdi, None)
+    }
+
+    fn raw_read(&self, data: &mut [JtagBit]) -> Result<(), InterfaceError> {
+        let tms: Vec<bool> = data.iter().map(|x| x.contains(JtagBit::TMS)).collect();
+        let tdi: Vec<bool> = data.iter().map(|x| x.contains(JtagBit::TDI)).collect();
+        let mut tdo = vec![false; data.len()];
+        self.shift(&tms, &tdi, Some(&mut tdo))?;
+        for (x, y) in data.iter_mut().zip(tdo) {
+            x.set(JtagBit::TDO, y);
+        }
+        Ok(())
+    }
+
+    fn usb_stats(&self) -> Option<UsbStats> {
+        Some(self.usb.snapshot())
+    }
+
+    fn reset_usb_stats(&self) {
+        self.usb.reset();
+    }
+
+    fn assert_trst(&self, level: bool) -> Result<(), InterfaceError> {
+        let command = if level {
+            JLinkCommand::HwTrst0
+        } else {
+            JLinkCommand::HwTrst1
+        };
+        self.write_all(&[command as u8])
+    }
+
+    fn assert_srst(&self, level: bool) -> Result<(), InterfaceError> {
+        let command = if level {
+            JLinkCommand::HwReset0
+        } else {
+            JLinkCommand::HwReset1
+        };
+        self.write_all(&[command as u8])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    // TDIとTDOを直結したJ-Linkを模し、送られたbyte列を記録する
+    struct Loopback {
+        written: RefCell<Vec<u8>>,
+        pending: RefCell<VecDeque<u8>>,
+        status: u8,
+    }
+
+    impl Loopback {
+        fn with_status(status: u8) -> Self {
+            Loopback {
+                written: RefCell::new(Vec::new()),
+                pending: RefCell::new(VecDeque::new()),
+                status,
+            }
+        }
+    }
+
+    impl JLinkDevice for Loopback {
+        fn open(_serial: Option<&str>) -> Result<Self, InterfaceError> {
+            Ok(Loopback::with_status(0))
+        }
+
+        fn write_data(&self, data: &[u8]) -> Result<usize, InterfaceError> {
+            self.written.borrow_mut().extend_from_slice(data);
+            let mut pending = self.pending.borrow_mut();
+            match data[0] {
+                0xC7 => pending.extend(&[0, 0, 0, 0]),
+                0xCF => {
+                    let length = bits::byte_len(data[2] as usize | (data[3] as usize) << 8);
+                    pending.extend(&data[4 + length..4 + length * 2]);
+                    pending.push_back(self.status);
+                }
+                _ => {}
+            }
+            Ok(data.len())
+        }
+
+        fn read_data(&self, data: &mut [u8]) -> Result<usize, InterfaceError> {
+            let mut pending = self.pending.borrow_mut();
+            let length = data.len().min(pending.len());
+            for x in data[..length].iter_mut() {
+                *x = pending.pop_front().unwrap();
+            }
+            Ok(length)
+        }
+    }
+
+    fn opened() -> JLink<Loopback> {
+        let jlink = JLink::<Loopback>::open(None, 4000).unwrap();
+        jlink.device.written.borrow_mut().clear();
+        jlink
+    }
+
+    #[test]
+    fn open_test() {
+        let jlink = JLink::<Loopback>::open(Some("000123456789"), 4000).unwrap();
+        assert_eq!(
+            *jlink.device.written.borrow(),
+            vec![0xC7, 0x00, 0x05, 0xA0, 0x0F]
+        );
+    }
+
+    #[test]
+    fn write_tms_test() {
+        let jlink = opened();
+        jlink.write_tms(&[true, true, false, true, false]).unwrap();
+        assert_eq!(
+            *jlink.device.written.borrow(),
+            vec![0xCF, 0x00, 0x05, 0x00, 0x0B, 0x00]
+        );
+    }
+
+    #[test]
+    fn read_data_test() {
+        let jlink = opened();
+        let tdi = [
+            true, false, true, true, false, false, false, true, true, false,
+        ];
+        let mut tditdo = tdi;
+        jlink.read_data(&mut tditdo, true).unwrap();
+        assert_eq!(tditdo, tdi);
+        assert_eq!(
+            *jlink.device.written.borrow(),
+            vec![0xCF, 0x00, 0x0A, 0x00, 0x00, 0x02, 0x8D, 0x01]
+        );
+
+        // TMSを送る間は最後のTDIを保持する
+        jlink.device.written.borrow_mut().clear();
+        jlink.write_tms(&[true, false]).unwrap();
+        assert_eq!(
+            *jlink.device.written.borrow(),
+            vec![0xCF, 0x00, 0x02, 0x00, 0x01, 0x00]
+        );
+    }
+
+    #[test]
+    fn read_data_split_test() {
+        let jlink = opened();
+        let mut tditdo: Vec<bool> = (0..JTAG3_MAX_BITS + 12).map(|i| i % 3 == 0).collect();
+        let tdi = tditdo.clone();
+        jlink.read_data(&mut tditdo, false).unwrap();
+        assert_eq!(tditdo, tdi);
+        let written = jlink.device.written.borrow();
+        assert_eq!(written[..4], [0xCF, 0x00, 0x00, 0x10]);
+        let second = 4 + JTAG3_MAX_BYTES * 2;
+        assert_eq!(written[second..second + 4], [0xCF, 0x00, 0x0C, 0x00]);
+        assert_eq!(written.len(), second + 4 + 2 * 2);
+    }
+
+    #[test]
+    fn status_error_test() {
+        let jlink = JLink::new(Loopback::with_status(1), 1000).unwrap();
+        assert_eq!(
+            jlink.write_data(&[true, false], true),
+            Err(InterfaceError::Io)
+        );
+    }
+
+    #[test]
+    fn reset_test() {
+        let jlink = opened();
+        jlink.assert_srst(true).unwrap();
+        jlink.assert_srst(false).unwrap();
+        jlink.assert_trst(true).unwrap();
+        jlink.assert_trst(false).unwrap();
+        assert_eq!(*jlink.device.written.borrow(), vec![0xDC, 0xDD, 0xDE, 0xDF]);
+    }
+}
@@ -0,0 +1,337 @@
+// hardwareなしでlibjtagの上のcodeをテストするためのinterface
+// 全ての呼び出しを記録し、readには予め登録したTDOを返す
+use core::cell::{Cell, RefCell};
+use rust_fsm::StateMachine;
+use std::collections::HashMap;
+
+use super::{CapFlags, InterfaceCaps, InterfaceError, JtagInterface};
+use crate::jtag::jtag_state_machine::{JtagState, JtagStateMachine};
+use crate::jtag::JtagBit;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockCall {
+    WriteTms(Vec<bool>),
+    WriteData {
+        tdi: Vec<bool>,
+        exit: bool,
+    },
+    ReadData {
+        tdi: Vec<bool>,
+        exit: bool,
+        tdo: Vec<bool>,
+    },
+    // SUPPORTS_IDLE_CLOCKの時にJtagがclock_idleで送ったcycle数
+    ClockIdle(usize),
+    RawWrite(Vec<JtagBit>),
+    RawRead {
+        pins: Vec<JtagBit>,
+        tdo: Vec<bool>,
+    },
+    Trst(bool),
+    Srst(bool),
+}
+
+impl MockCall {
+    // この呼び出しでTMSに出したbit列
+    pub fn tms(&self) -> Vec<bool> {
+        let data_tms = |len: usize, exit: bool| {
+            let mut tms = vec![false; len];
+            if let Some(last) = tms.last_mut() {
+                *last = exit;
+            }
+            tms
+        };
+        match self {
+            MockCall::WriteTms(tms) => tms.clone(),
+            MockCall::ClockIdle(n) => vec![false; *n],
+            MockCall::WriteData { tdi, exit } => data_tms(tdi.len(), *exit),
+            MockCall::ReadData { tdi, exit, .. } => data_tms(tdi.len(), *exit),
+            MockCall::RawWrite(pins) | MockCall::RawRead { pins, .. } => {
+                pins.iter().map(|x| x.contains(JtagBit::TMS)).collect()
+            }
+            MockCall::Trst(_) | MockCall::Srst(_) => Vec::new(),
+        }
+    }
+}
+
+pub struct MockInterface {
+    transcript: RefCell<Vec<MockCall>>,
+    // n回目のreadで返すTDO。登録がなければ全て0を返す
+    responses: RefCell<HashMap<usize, Vec<bool>>>,
+    reads: Cell<usize>,
+    fail: Cell<Option<InterfaceError>>,
+    state_machine: RefCell<StateMachine<JtagStateMachine>>,
+    // 残りこのbit数の後のTMSを1bit取りこぼす
+    drop_tms: Cell<Option<usize>>,
+    // trueならShift-IRでの未登録のreadにIR-Captureの01を返す
+    ir_capture: Cell<bool>,
+    caps: Cell<InterfaceCaps>,
+}
+
+// packed I/Oはdefaultの変換を使うので持たない
+pub const MOCK_CAPS: InterfaceCaps = InterfaceCaps::new(CapFlags::from_bits_truncate(
+    CapFlags::SUPPORTS_IDLE_CLOCK.bits() | CapFlags::HAS_SRST.bits() | CapFlags::HAS_TRST.bits(),
+));
+
+impl Default for MockInterface {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockInterface {
+    pub fn new() -> Self {
+        MockInterface {
+            transcript: RefCell::new(Vec::new()),
+            responses: RefCell::new(HashMap::new()),
+            reads: Cell::new(0),
+            fail: Cell::new(None),
+            state_machine: RefCell::new(StateMachine::new()),
+            drop_tms: Cell::new(None),
+            ir_capture: Cell::new(false),
+            caps: Cell::new(MOCK_CAPS),
+        }
+    }
+
+    // n回目(0始まり)のread_data/raw_readで返すTDOを登録する
+    pub fn script_read(&self, n: usize, tdo: &[bool]) {
+        self.responses.borrow_mut().insert(n, tdo.to_vec());
+    }
+
+    // 次のreadで返すTDOを登録する
+    pub fn script_next_read(&self, tdo: &[bool]) {
+        self.script_read(self.reads.get(), tdo);
+    }
+
+    // 以降の全ての呼び出しをerrorで失敗させる
+    pub fn fail_with(&self, error: Option<InterfaceError>) {
+        self.fail.set(error);
+    }
+
+    // 次からn bit目(0始まり)のTMSをTAPが取りこぼしたことにする
+    // transcriptには残るが、state()はそのbitで遷移しない
+    pub fn drop_tms_bit(&self, n: usize) {
+        self.drop_tms.set(Some(n));
+    }
+
+    pub fn set_ir_capture(&self, enable: bool) {
+        self.ir_capture.set(enable);
+    }
+
+    // 機能の少ないbackendを模す
+    pub fn set_capabilities(&self, caps: InterfaceCaps) {
+        self.caps.set(caps);
+    }
+
+    pub fn reads(&self) -> usize {
+        self.reads.get()
+    }
+
+    pub fn transcript(&self) -> Vec<MockCall> {
+        self.transcript.borrow().clone()
+    }
+
+    pub fn clear(&self) {
+        self.transcript.borrow_mut().clear();
+    }
+
+    // TAPが今いるはずのstate
+    pub fn state(&self) -> JtagState {
+        *self.state_machine.borrow().state()
+    }
+
+    pub fn tms_sequence(&self) -> Vec<bool> {
+        self.transcript
+            .borrow()
+            .iter()
+            .flat_map(|x| x.tms())
+            .collect()
+    }
+
+    pub fn expect_tms_sequence(&self, tms: &[bool]) {
+        let actual = self.tms_sequence();
+        assert_eq!(
+            tms,
+            actual.as_slice(),
+            "unexpected TMS sequence, transcript: {:?}",
+            self.transcript.borrow()
+        );
+    }
+
+    // WriteData/ReadDataで送ったTDIを順に返す
+    pub fn tdi_sequence(&self) -> Vec<Vec<bool>> {
+        self.transcript
+            .borrow()
+            .iter()
+            .filter_map(|x| match x {
+                MockCall::WriteData { tdi, .. } | MockCall::ReadData { tdi, .. } => {
+                    Some(tdi.clone())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn record(&self, call: MockCall) -> Result<(), InterfaceError> {
+        if let Some(e) = self.fail.get() {
+            return Err(e);
+        }
+        {
+            let mut state_machine = self.state_machine.borrow_mut();
+            if call == MockCall::Trst(true) {
+                *state_machine = StateMachine::new();
+            }
+            for tms in call.tms() {
+                match self.drop_tms.get() {
+                    Some(0) => {
+                        self.drop_tms.set(None);
+                        continue;
+                    }
+                    Some(n) => self.drop_tms.set(Some(n - 1)),
+                    None => (),
+                }
+                let _ = state_machine.consume(&tms);
+            }
+        }
+        self.transcript.borrow_mut().push(call);
+        Ok(())
+    }
+
+    fn next_response(&self, len: usize) -> Vec<bool> {
+        let n = self.reads.get();
+        self.reads.set(n + 1);
+        let mut tdo = match self.responses.borrow_mut().remove(&n) {
+            Some(tdo) => tdo,
+            None if self.ir_capture.get() && self.state() == JtagState::ShiftIR => {
+                vec![true, false]
+            }
+            None => Vec::new(),
+        };
+        tdo.resize(len, false);
+        tdo
+    }
+}
+
+impl JtagInterface for MockInterface {
+    fn capabilities(&self) -> InterfaceCaps {
+        self.caps.get()
+    }
+    fn clock_idle(&self, n: usize) -> Result<(), InterfaceError> {
+        self.record(MockCall::ClockIdle(n))
+    }
+    fn write_tms(&self, tms: &[bool]) -> Result<(), InterfaceError> {
+        self.record(MockCall::WriteTms(tms.to_vec()))
+    }
+    fn write_data(&self, tdi: &[bool], exit: bool) -> Result<(), InterfaceError> {
+        self.record(MockCall::WriteData {
+            tdi: tdi.to_vec(),
+            exit,
+        })
+    }
+    fn read_data(&self, tditdo: &mut [bool], exit: bool) -> Result<(), InterfaceError> {
+        if let Some(e) = self.fail.get() {
+            return Err(e);
+        }
+        let tdo = self.next_response(tditdo.len());
+        let tdi = tditdo.to_vec();
+        tditdo.copy_from_slice(&tdo);
+        self.record(MockCall::ReadData { tdi, exit, tdo })
+    }
+
+    fn raw_write(&self, pins: &[JtagBit]) -> Result<(), InterfaceError> {
+        self.record(MockCall::RawWrite(pins.to_vec()))
+    }
+    fn raw_read(&self, pins: &mut [JtagBit]) -> Result<(), InterfaceError> {
+        if let Some(e) = self.fail.get() {
+            return Err(e);
+        }
+        let tdo = self.next_response(pins.len());
+        let call = MockCall::RawRead {
+            pins: pins.to_vec(),
+            tdo: tdo.clone(),
+        };
+        for (x, y) in pins.iter_mut().zip(tdo) {
+            x.set(JtagBit::TDO, y);
+        }
+        self.record(call)
+    }
+
+    fn assert_trst(&self, level: bool) -> Result<(), InterfaceError> {
+        self.record(MockCall::Trst(level))
+    }
+    fn assert_srst(&self, level: bool) -> Result<(), InterfaceError> {
+        self.record(MockCall::Srst(level))
+    }
+    // testを待たせないように時間は記録しない
+    fn pulse_srst(&self, _duration_ms: u32) -> Result<(), InterfaceError> {
+        self.assert_srst(true)?;
+        self.assert_srst(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transcript_test() {
+        let mock = MockInterface::new();
+        mock.script_read(1, &[true, false, true]);
+
+        mock.write_tms(&[false, true, false, false]).unwrap();
+        assert_eq!(JtagState::ShiftDR, mock.state());
+        let mut data = [true, true];
+        mock.read_data(&mut data, false).unwrap();
+        assert_eq!([false, false], data);
+        let mut data = [false; 4];
+        mock.read_data(&mut data, true).unwrap();
+        assert_eq!([true, false, true, false], data);
+        assert_eq!(JtagState::Exit1DR, mock.state());
+        assert_eq!(2, mock.reads());
+
+        mock.expect_tms_sequence(&[
+            false, true, false, false, false, false, false, false, false, true,
+        ]);
+        assert_eq!(
+            MockCall::ReadData {
+                tdi: vec![true, true],
+                exit: false,
+                tdo: vec![false, false],
+            },
+            mock.transcript()[1]
+        );
+
+        mock.fail_with(Some(InterfaceError::Io));
+        assert_eq!(Err(InterfaceError::Io), mock.write_tms(&[true]));
+        assert_eq!(3, mock.transcript().len());
+    }
+
+    #[test]
+    fn drop_tms_test() {
+        let mock = MockInterface::new();
+        mock.set_ir_capture(true);
+        mock.write_tms(&[false, true, true, false, false]).unwrap();
+        let mut data = [true; 4];
+        mock.read_data(&mut data, true).unwrap();
+        assert_eq!([true, false, false, false], data);
+
+        // Pause-IRから出る1つ目のTMS=1を落とすと、1clock遅れてExit2-IRに着く
+        mock.drop_tms_bit(1);
+        mock.write_tms(&[false, true, true]).unwrap();
+        assert_eq!(JtagState::Exit2IR, mock.state());
+        mock.write_tms(&[true]).unwrap();
+        assert_eq!(JtagState::UpdateIR, mock.state());
+        assert_eq!(None, mock.drop_tms.get());
+    }
+
+    #[test]
+    fn raw_test() {
+        let mock = MockInterface::new();
+        mock.script_next_read(&[false, true]);
+        let mut pins = [JtagBit::TMS, JtagBit::TDI];
+        mock.raw_read(&mut pins).unwrap();
+        assert_eq!([JtagBit::TMS, JtagBit::TDI | JtagBit::TDO], pins);
+        assert_eq!(JtagState::RunIdle, mock.state());
+        mock.expect_tms_sequence(&[true, false]);
+    }
+}
@@ -0,0 +1,279 @@
+// FTDIのbackendで共通のpin割り当て
+// JtagBitとADBUS/ACBUSのbit列を相互に変換する
+use std::fmt;
+
+use crate::jtag::JtagBit;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Signal {
+    Tck,
+    Tdi,
+    Tdo,
+    Tms,
+    Srst,
+    Trst,
+    Rtck,
+}
+
+const SIGNAL_COUNT: usize = 7;
+
+impl Signal {
+    pub const ALL: [Signal; SIGNAL_COUNT] = [
+        Signal::Tck,
+        Signal::Tdi,
+        Signal::Tdo,
+        Signal::Tms,
+        Signal::Srst,
+        Signal::Trst,
+        Signal::Rtck,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Signal::Tck => "tck",
+            Signal::Tdi => "tdi",
+            Signal::Tdo => "tdo",
+            Signal::Tms => "tms",
+            Signal::Srst => "srst",
+            Signal::Trst => "trst",
+            Signal::Rtck => "rtck",
+        }
+    }
+
+    // adapterから見て入力になるsignal
+    pub fn is_input(&self) -> bool {
+        matches!(self, Signal::Tdo | Signal::Rtck)
+    }
+
+    // 起動直後に出力する値(Test-Logic-Resetに留まるようにTMSはH)
+    pub fn initial_value(&self) -> bool {
+        matches!(self, Signal::Tms)
+    }
+
+    // TRST/SRSTはactive low
+    pub fn is_active_low(&self) -> bool {
+        matches!(self, Signal::Srst | Signal::Trst)
+    }
+
+    // 対応するJtagBit。RTCKはJtagBitに無い
+    fn bit(&self) -> JtagBit {
+        match self {
+            Signal::Tck => JtagBit::TCK,
+            Signal::Tdi => JtagBit::TDI,
+            Signal::Tdo => JtagBit::TDO,
+            Signal::Tms => JtagBit::TMS,
+            Signal::Srst => JtagBit::SRST,
+            Signal::Trst => JtagBit::TRST,
+            Signal::Rtck => JtagBit::NONE,
+        }
+    }
+}
+
+// pinの割り当てが使えない
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PinError {
+    // ADBUS/ACBUSの16bitの外
+    InvalidPosition {
+        signal: Signal,
+        position: u8,
+    },
+    // 出力同士が同じ位置
+    Shared {
+        first: Signal,
+        second: Signal,
+        position: u8,
+    },
+    // backendで位置が決まっているpinを別の位置にした
+    Fixed {
+        signal: Signal,
+        position: u8,
+    },
+    // 必ず割り当てる必要のあるpinがない
+    Missing(Signal),
+}
+
+impl fmt::Display for PinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PinError::InvalidPosition { signal, position } => write!(
+                f,
+                "{} is assigned to invalid position {}",
+                signal.name(),
+                position
+            ),
+            PinError::Shared {
+                first,
+                second,
+                position,
+            } => write!(
+                f,
+                "{} and {} share position {}",
+                first.name(),
+                second.name(),
+                position
+            ),
+            PinError::Fixed { signal, position } => {
+                write!(f, "{} is fixed to position {}", signal.name(), position)
+            }
+            PinError::Missing(signal) => write!(f, "{} is not assigned", signal.name()),
+        }
+    }
+}
+
+impl std::error::Error for PinError {}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PinMap {
+    positions: [Option<u8>; SIGNAL_COUNT],
+    // 変換で毎回計算しないように持っておくmask
+    masks: [u16; SIGNAL_COUNT],
+}
+
+impl PinMap {
+    // 出力同士が同じ位置に割り当てられていればerror
+    pub fn new<I: IntoIterator<Item = (Signal, u8)>>(pins: I) -> Result<Self, PinError> {
+        let mut map = PinMap::default();
+        for (signal, position) in pins {
+            if position > 15 {
+                return Err(PinError::InvalidPosition { signal, position });
+            }
+            if !signal.is_input() {
+                let other = Signal::ALL.iter().find(|x| {
+                    **x != signal && !x.is_input() && map.position(**x) == Some(position)
+                });
+                if let Some(other) = other {
+                    return Err(PinError::Shared {
+                        first: *other,
+                        second: signal,
+                        position,
+                    });
+                }
+            }
+            map.positions[signal as usize] = Some(position);
+            map.masks[signal as usize] = 1 << position;
+        }
+        Ok(map)
+    }
+
+    pub fn position(&self, signal: Signal) -> Option<u8> {
+        self.positions[signal as usize]
+    }
+
+    // 割り当てのないsignalは0
+    pub fn mask(&self, signal: Signal) -> u16 {
+        self.masks[signal as usize]
+    }
+
+    fn fold(&self, f: impl Fn(Signal) -> bool) -> u16 {
+        Signal::ALL
+            .iter()
+            .filter(|x| f(**x))
+            .fold(0, |x, y| x | self.mask(*y))
+    }
+
+    // 出力にするpinのmask
+    pub fn output_mask(&self) -> u16 {
+        self.fold(|x| !x.is_input())
+    }
+
+    pub fn input_mask(&self) -> u16 {
+        self.fold(|x| x.is_input())
+    }
+
+    pub fn initial_value(&self) -> u16 {
+        self.fold(|x| x.initial_value())
+    }
+
+    // JtagBitのTRST/SRSTはassertを表すので、pinにはLを出す
+    pub fn pins_to_u8(&self, pins: &JtagBit) -> u8 {
+        self.fold(|x| !x.bit().is_empty() && pins.contains(x.bit()) != x.is_active_low()) as u8
+    }
+
+    pub fn u8_to_pins(&self, value: u8) -> JtagBit {
+        let value = value as u16;
+        Signal::ALL
+            .iter()
+            .filter(|x| self.mask(**x) != 0)
+            .filter(|x| (value & self.mask(**x) != 0) != x.is_active_low())
+            .fold(JtagBit::empty(), |x, y| x | y.bit())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitbang_pins() -> PinMap {
+        PinMap::new(vec![
+            (Signal::Tck, 0),
+            (Signal::Tdi, 1),
+            (Signal::Tdo, 2),
+            (Signal::Tms, 3),
+            (Signal::Srst, 4),
+            (Signal::Trst, 5),
+            (Signal::Rtck, 7),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let pins = bitbang_pins();
+        let all = JtagBit::TCK
+            | JtagBit::TDI
+            | JtagBit::TDO
+            | JtagBit::TMS
+            | JtagBit::SRST
+            | JtagBit::TRST;
+        for bits in 0..=all.bits() {
+            let bits = match JtagBit::from_bits(bits) {
+                Some(x) => x,
+                None => continue,
+            };
+            assert_eq!(bits, pins.u8_to_pins(pins.pins_to_u8(&bits)));
+        }
+        // 何もassertしていなければTRST/SRSTはH
+        assert_eq!(0x30, pins.pins_to_u8(&JtagBit::empty()));
+        assert_eq!(
+            0x29,
+            pins.pins_to_u8(&(JtagBit::TCK | JtagBit::TMS | JtagBit::SRST))
+        );
+    }
+
+    #[test]
+    fn metadata_test() {
+        let pins = bitbang_pins();
+        assert_eq!(0x3b, pins.output_mask());
+        assert_eq!(0x84, pins.input_mask());
+        assert_eq!(0x08, pins.initial_value());
+        assert_eq!(Some(7), pins.position(Signal::Rtck));
+
+        // 割り当てのないsignalは変換されない
+        let pins = PinMap::new(vec![(Signal::Srst, 9), (Signal::Trst, 4)]).unwrap();
+        assert_eq!(0x0200, pins.mask(Signal::Srst));
+        assert_eq!(0x0210, pins.output_mask());
+        assert_eq!(0, pins.initial_value());
+        assert_eq!(0x10, pins.pins_to_u8(&(JtagBit::TMS | JtagBit::SRST)));
+    }
+
+    #[test]
+    fn collision_test() {
+        assert_eq!(
+            Err(PinError::Shared {
+                first: Signal::Tck,
+                second: Signal::Tms,
+                position: 0
+            }),
+            PinMap::new(vec![(Signal::Tck, 0), (Signal::Tms, 0)])
+        );
+        assert_eq!(
+            Err(PinError::InvalidPosition {
+                signal: Signal::Srst,
+                position: 16
+            }),
+            PinMap::new(vec![(Signal::Srst, 16)])
+        );
+        // 入力同士は同じpinを読んでもよい
+        assert!(PinMap::new(vec![(Signal::Tdo, 2), (Signal::Rtck, 2)]).is_ok());
+    }
+}
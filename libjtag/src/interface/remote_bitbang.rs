@@ -0,0 +1,222 @@
+// OpenOCDのremote_bitbang protocolでTCP越しにTAPを操作する
+// VerilatorやQEMUのsimulationに繋ぐために使う
+use log::{debug, error};
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use super::{CapFlags, InterfaceCaps, InterfaceError, JtagInterface};
+use crate::jtag::JtagBit;
+
+const TCK: u8 = 1 << 2;
+const TMS: u8 = 1 << 1;
+const TDI: u8 = 1 << 0;
+// TDOを1文字で返させる
+const READ: u8 = b'R';
+// 'r' + (trst << 1 | srst)
+const RESET: u8 = b'r';
+
+pub struct RemoteBitbang {
+    stream: TcpStream,
+    // 最後に送ったTRST/SRST
+    reset: Cell<(bool, bool)>,
+    // assert_trst/assert_srstで保持しているTRST/SRST
+    held: Cell<(bool, bool)>,
+}
+
+impl RemoteBitbang {
+    // 接続先の表示は呼び出し側で付ける
+    pub fn connect(addr: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        debug!("connected to {}", addr);
+        Ok(RemoteBitbang {
+            stream,
+            reset: Cell::new((false, false)),
+            held: Cell::new((false, false)),
+        })
+    }
+
+    // TRST/SRSTが含まれていればassertする
+    fn push_reset(&self, commands: &mut Vec<u8>, pins: &JtagBit) {
+        let held = self.held.get();
+        let reset = (
+            pins.contains(JtagBit::TRST) || held.0,
+            pins.contains(JtagBit::SRST) || held.1,
+        );
+        if reset != self.reset.get() {
+            commands.push(RESET + ((reset.0 as u8) << 1) + reset.1 as u8);
+            self.reset.set(reset);
+        }
+    }
+
+    // TCKの立ち上がりで1bitを送る。readならその直前にTDOを読む
+    fn push_bit(&self, commands: &mut Vec<u8>, pins: &JtagBit, read: bool) {
+        self.push_reset(commands, pins);
+        let value = if pins.contains(JtagBit::TMS) { TMS } else { 0 }
+            | if pins.contains(JtagBit::TDI) { TDI } else { 0 };
+        commands.push(b'0' + value);
+        if read {
+            commands.push(READ);
+        }
+        commands.push(b'0' + (value | TCK));
+    }
+
+    fn write_all(&self, commands: &[u8]) -> Result<(), InterfaceError> {
+        (&self.stream).write_all(commands).map_err(|e| {
+            error!("remote_bitbang write error: {}", e);
+            InterfaceError::Io
+        })
+    }
+}
+
+impl JtagInterface for RemoteBitbang {
+    // 'r'..'u'のreset commandはserver側が必ず受け付ける
+    fn capabilities(&self) -> InterfaceCaps {
+        InterfaceCaps::new(CapFlags::HAS_SRST | CapFlags::HAS_TRST)
+    }
+
+    fn raw_write(&self, data: &[JtagBit]) -> Result<(), InterfaceError> {
+        let mut commands = Vec::with_capacity(data.len() * 2);
+        for pins in data {
+            self.push_bit(&mut commands, pins, false);
+        }
+        self.write_all(&commands)
+    }
+
+    fn raw_read(&self, data: &mut [JtagBit]) -> Result<(), InterfaceError> {
+        let mut commands = Vec::with_capacity(data.len() * 3);
+        for pins in data.iter() {
+            self.push_bit(&mut commands, pins, true);
+        }
+        self.write_all(&commands)?;
+
+        let mut tdo = vec![0; data.len()];
+        (&self.stream).read_exact(&mut tdo).map_err(|e| {
+            error!("remote_bitbang read error: {}", e);
+            InterfaceError::Timeout
+        })?;
+        for (pins, x) in data.iter_mut().zip(tdo.iter()) {
+            match x {
+                b'0' => pins.remove(JtagBit::TDO),
+                b'1' => pins.insert(JtagBit::TDO),
+                _ => {
+                    error!("unexpected response {:#x}", x);
+                    return Err(InterfaceError::Io);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn assert_trst(&self, level: bool) -> Result<(), InterfaceError> {
+        self.held.set((level, self.held.get().1));
+        let mut commands = Vec::new();
+        self.push_reset(&mut commands, &JtagBit::empty());
+        self.write_all(&commands)
+    }
+    fn assert_srst(&self, level: bool) -> Result<(), InterfaceError> {
+        self.held.set((self.held.get().0, level));
+        let mut commands = Vec::new();
+        self.push_reset(&mut commands, &JtagBit::empty());
+        self.write_all(&commands)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::conformance::tests::SimTap;
+    use crate::interface::conformance::{run, HarnessKind};
+    use std::io::BufReader;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // remote_bitbangのserver側。Rへの応答は次のTCK立ち上がりで返す
+    fn serve<F>(clock: F) -> String
+    where
+        F: Fn(JtagBit) -> bool + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut pending = false;
+            let mut tck = false;
+            for x in BufReader::new(stream).bytes() {
+                let x = match x {
+                    Ok(x) => x,
+                    Err(_) => return,
+                };
+                match x {
+                    b'0'..=b'7' => {
+                        let value = x - b'0';
+                        let rising = !tck && value & TCK != 0;
+                        tck = value & TCK != 0;
+                        if !rising {
+                            continue;
+                        }
+                        let mut pins = JtagBit::empty();
+                        pins.set(JtagBit::TMS, value & TMS != 0);
+                        pins.set(JtagBit::TDI, value & TDI != 0);
+                        let tdo = clock(pins);
+                        if pending {
+                            writer.write_all(&[b'0' + tdo as u8]).unwrap();
+                            pending = false;
+                        }
+                    }
+                    READ => pending = true,
+                    b'Q' => return,
+                    _ => (),
+                }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn loopback_conformance_test() {
+        let addr = serve(|pins| pins.contains(JtagBit::TDI));
+        let mut iface = RemoteBitbang::connect(&addr).unwrap();
+        let report = run(&mut iface, &HarnessKind::Loopback);
+        assert!(report.passed(), "{}", report);
+    }
+
+    #[test]
+    fn single_tap_conformance_test() {
+        let addr = serve(|pins| {
+            thread_local!(static TAP: SimTap = SimTap::new(0x4ba0_0477, 4));
+            TAP.with(|tap| tap.clock(pins))
+        });
+        let mut iface = RemoteBitbang::connect(&addr).unwrap();
+        let harness = HarnessKind::SingleTap {
+            idcode: 0x4ba0_0477,
+            ir_len: 4,
+        };
+        let report = run(&mut iface, &harness);
+        assert!(report.passed(), "{}", report);
+    }
+
+    #[test]
+    fn reset_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let iface = RemoteBitbang::connect(&addr).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        iface
+            .raw_write(&[JtagBit::TRST | JtagBit::TMS, JtagBit::TRST, JtagBit::TDI])
+            .unwrap();
+        let mut buffer = [0; 8];
+        stream.read_exact(&mut buffer).unwrap();
+        assert_eq!(b"t2604r15", &buffer);
+
+        // 解除するまでclockしてもSRSTを保持する
+        iface.assert_srst(true).unwrap();
+        iface.raw_write(&[JtagBit::TMS]).unwrap();
+        iface.assert_srst(false).unwrap();
+        let mut buffer = [0; 4];
+        stream.read_exact(&mut buffer).unwrap();
+        assert_eq!(b"s26r", &buffer);
+    }
+}
@@ -0,0 +1,722 @@
+// 実機とのsessionをraw_write/raw_read単位で記録し、後からmockとして再生する
+// 特定のboardでしか起きない不具合のsessionをbug reportに添付してもらい、回帰テストにする
+use rust_fsm::StateMachine;
+use std::cell::{Cell, RefCell};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use super::{CapFlags, InterfaceCaps, InterfaceError, JtagInterface};
+use crate::jtag::jtag_state_machine::{JtagState, JtagStateMachine};
+use crate::jtag::JtagBit;
+
+const MAGIC: &[u8; 4] = b"JRPL";
+const VERSION: u8 = 1;
+
+const TAG_RAW_WRITE: u8 = 0;
+const TAG_RAW_READ: u8 = 1;
+const TAG_TRST: u8 = 2;
+const TAG_SRST: u8 = 3;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplayEvent {
+    RawWrite(Vec<JtagBit>),
+    // pinsは渡された値のまま。tdoは返ってきたTDO
+    RawRead { pins: Vec<JtagBit>, tdo: Vec<bool> },
+    Trst(bool),
+    Srst(bool),
+}
+
+// replay fileの読み書きのerror
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplayError {
+    // fileが読めない、書けない
+    Io(String),
+    // 先頭がMAGICでない
+    NotReplayFile,
+    UnsupportedVersion(u8),
+    // eventの途中でfileが終わった
+    Truncated { offset: usize },
+    UnknownEvent { tag: u8, offset: usize },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Io(message) => write!(f, "{}", message),
+            ReplayError::NotReplayFile => write!(f, "not a replay file"),
+            ReplayError::UnsupportedVersion(version) => {
+                write!(f, "unsupported replay file version: {}", version)
+            }
+            ReplayError::Truncated { offset } => {
+                write!(f, "replay file is truncated at offset {}", offset)
+            }
+            ReplayError::UnknownEvent { tag, offset } => {
+                write!(f, "unknown event {:#04x} at offset {}", tag, offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+// 1つのsession。capsは記録した時のbackendの値で、再生する時もこれを返す
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recording {
+    pub caps: InterfaceCaps,
+    pub events: Vec<ReplayEvent>,
+}
+
+// header: "JRPL", version, caps.flags(u32 LE), caps.max_bits_per_transfer(u64 LE)
+// event: tag(u8)の後に、raw_write/raw_readならTCK数(u32 LE)と1cycle 1byteのpin、TRST/SRSTならlevel(u8)
+// raw_readのpinのbyteにはTDOを入れる
+impl Recording {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        out.extend(self.caps.flags.bits().to_le_bytes());
+        out.extend((self.caps.max_bits_per_transfer as u64).to_le_bytes());
+        for event in self.events.iter() {
+            match event {
+                ReplayEvent::RawWrite(pins) => {
+                    out.push(TAG_RAW_WRITE);
+                    out.extend((pins.len() as u32).to_le_bytes());
+                    out.extend(pins.iter().map(|x| (*x - JtagBit::TDO).bits() as u8));
+                }
+                ReplayEvent::RawRead { pins, tdo } => {
+                    out.push(TAG_RAW_READ);
+                    out.extend((pins.len() as u32).to_le_bytes());
+                    out.extend(pins.iter().zip(tdo).map(|(x, y)| {
+                        let mut pins = *x;
+                        pins.set(JtagBit::TDO, *y);
+                        pins.bits() as u8
+                    }));
+                }
+                ReplayEvent::Trst(level) => out.extend([TAG_TRST, *level as u8]),
+                ReplayEvent::Srst(level) => out.extend([TAG_SRST, *level as u8]),
+            }
+        }
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ReplayError> {
+        let mut reader = Reader { data, position: 0 };
+        if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(ReplayError::NotReplayFile);
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(ReplayError::UnsupportedVersion(version));
+        }
+        let flags = CapFlags::from_bits_truncate(u32::from_le_bytes(reader.array()?));
+        let max_bits = u64::from_le_bytes(reader.array()?);
+        let caps = InterfaceCaps {
+            flags,
+            max_bits_per_transfer: usize::try_from(max_bits).unwrap_or(usize::MAX),
+        };
+        let mut events = Vec::new();
+        while reader.position < data.len() {
+            let event = match reader.u8()? {
+                TAG_RAW_WRITE => {
+                    let pins = reader.pins()?;
+                    ReplayEvent::RawWrite(pins)
+                }
+                TAG_RAW_READ => {
+                    let pins = reader.pins()?;
+                    let tdo = pins.iter().map(|x| x.contains(JtagBit::TDO)).collect();
+                    let pins = pins.iter().map(|x| *x - JtagBit::TDO).collect();
+                    ReplayEvent::RawRead { pins, tdo }
+                }
+                TAG_TRST => ReplayEvent::Trst(reader.u8()? != 0),
+                TAG_SRST => ReplayEvent::Srst(reader.u8()? != 0),
+                tag => {
+                    return Err(ReplayError::UnknownEvent {
+                        tag,
+                        offset: reader.position - 1,
+                    })
+                }
+            };
+            events.push(event);
+        }
+        Ok(Recording { caps, events })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ReplayError> {
+        let path = path.as_ref();
+        fs::write(path, self.to_bytes())
+            .map_err(|e| ReplayError::Io(format!("failed to write {}: {}", path.display(), e)))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ReplayError> {
+        let path = path.as_ref();
+        let data = fs::read(path)
+            .map_err(|e| ReplayError::Io(format!("failed to read {}: {}", path.display(), e)))?;
+        Self::from_bytes(&data)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ReplayError> {
+        let end = self.position + len;
+        if end > self.data.len() {
+            return Err(ReplayError::Truncated {
+                offset: self.position,
+            });
+        }
+        let result = &self.data[self.position..end];
+        self.position = end;
+        Ok(result)
+    }
+    fn u8(&mut self) -> Result<u8, ReplayError> {
+        Ok(self.take(1)?[0])
+    }
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], ReplayError> {
+        let mut result = [0; N];
+        result.copy_from_slice(self.take(N)?);
+        Ok(result)
+    }
+    fn pins(&mut self) -> Result<Vec<JtagBit>, ReplayError> {
+        let len = u32::from_le_bytes(self.array()?) as usize;
+        Ok(self
+            .take(len)?
+            .iter()
+            .map(|x| JtagBit::from_bits_truncate(*x as u32))
+            .collect())
+    }
+}
+
+// 内側のbackendへの呼び出しを全て記録する
+// write_tms/write_data/read_dataはdefaultの実装でraw_write/raw_readに分けるので、
+// 内側のbackendが専用のcommandを持っていても使わない。その分遅くなる
+pub struct RecordingInterface<T> {
+    inner: T,
+    events: RefCell<Vec<ReplayEvent>>,
+}
+
+impl<T: JtagInterface> RecordingInterface<T> {
+    pub fn new(inner: T) -> Self {
+        RecordingInterface {
+            inner,
+            events: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn recording(&self) -> Recording {
+        Recording {
+            caps: self.inner.capabilities(),
+            events: self.events.borrow().clone(),
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ReplayError> {
+        self.recording().save(path)
+    }
+}
+
+impl<T: JtagInterface> JtagInterface for RecordingInterface<T> {
+    fn capabilities(&self) -> InterfaceCaps {
+        self.inner.capabilities()
+    }
+    fn flush(&self) -> Result<(), InterfaceError> {
+        self.inner.flush()
+    }
+
+    fn raw_write(&self, data: &[JtagBit]) -> Result<(), InterfaceError> {
+        self.inner.raw_write(data)?;
+        self.events
+            .borrow_mut()
+            .push(ReplayEvent::RawWrite(data.to_vec()));
+        Ok(())
+    }
+    fn raw_read(&self, data: &mut [JtagBit]) -> Result<(), InterfaceError> {
+        let pins = data.to_vec();
+        self.inner.raw_read(data)?;
+        let tdo = data.iter().map(|x| x.contains(JtagBit::TDO)).collect();
+        self.events
+            .borrow_mut()
+            .push(ReplayEvent::RawRead { pins, tdo });
+        Ok(())
+    }
+
+    fn assert_trst(&self, level: bool) -> Result<(), InterfaceError> {
+        self.inner.assert_trst(level)?;
+        self.events.borrow_mut().push(ReplayEvent::Trst(level));
+        Ok(())
+    }
+    fn assert_srst(&self, level: bool) -> Result<(), InterfaceError> {
+        self.inner.assert_srst(level)?;
+        self.events.borrow_mut().push(ReplayEvent::Srst(level));
+        Ok(())
+    }
+}
+
+// 再生する時に違っていても良いもの
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReplayTolerance {
+    // raw_writeのTDI。TMSで遷移する間やidle cycleのTDIはtargetに影響しない
+    pub ignore_write_tdi: bool,
+    // Run-Test/IdleでTMS=0だけを送るraw_write。記録側と再生側のどちらにあっても読み飛ばす
+    pub ignore_idle: bool,
+}
+
+// 記録したTDOを返しながら、書かれたbitが記録と一致することを確かめる
+// 一致しなければpanicする
+pub struct ReplayInterface {
+    recording: Recording,
+    tolerance: ReplayTolerance,
+    position: Cell<usize>,
+    // ignore_idleでidle cycleを見分けるためのTAPのstate
+    state_machine: RefCell<StateMachine<JtagStateMachine>>,
+}
+
+impl ReplayInterface {
+    pub fn new(recording: Recording) -> Self {
+        Self::new_with_tolerance(recording, ReplayTolerance::default())
+    }
+
+    pub fn new_with_tolerance(recording: Recording, tolerance: ReplayTolerance) -> Self {
+        ReplayInterface {
+            recording,
+            tolerance,
+            position: Cell::new(0),
+            state_machine: RefCell::new(StateMachine::new()),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ReplayError> {
+        Ok(Self::new(Recording::load(path)?))
+    }
+
+    // まだ再生していないeventの数
+    pub fn remaining(&self) -> usize {
+        self.recording.events.len() - self.position.get()
+    }
+
+    // 記録を全て再生し終えたことを確かめる
+    pub fn finish(&self) {
+        self.skip_idle();
+        assert_eq!(
+            0,
+            self.remaining(),
+            "replay finished {} events early, next: {:?}",
+            self.remaining(),
+            self.recording.events.get(self.position.get())
+        );
+    }
+
+    fn is_idle(&self, pins: &[JtagBit]) -> bool {
+        self.tolerance.ignore_idle
+            && *self.state_machine.borrow().state() == JtagState::RunIdle
+            && pins.iter().all(|x| !x.contains(JtagBit::TMS))
+    }
+
+    fn skip_idle(&self) {
+        while let Some(ReplayEvent::RawWrite(pins)) = self.recording.events.get(self.position.get())
+        {
+            if !self.is_idle(pins) {
+                break;
+            }
+            self.position.set(self.position.get() + 1);
+        }
+    }
+
+    fn next(&self, actual: &ReplayEvent) -> &ReplayEvent {
+        self.skip_idle();
+        let position = self.position.get();
+        let expected = self.recording.events.get(position).unwrap_or_else(|| {
+            panic!(
+                "replay ran past the end of the recording ({} events): {:?}",
+                position, actual
+            )
+        });
+        self.position.set(position + 1);
+        expected
+    }
+
+    fn check_pins(&self, expected: &[JtagBit], actual: &[JtagBit], ignore_tdi: bool) {
+        let mut mask = JtagBit::TMS | JtagBit::TDI | JtagBit::TRST | JtagBit::SRST;
+        if ignore_tdi {
+            mask -= JtagBit::TDI;
+        }
+        let matched = expected.len() == actual.len()
+            && expected
+                .iter()
+                .zip(actual)
+                .all(|(x, y)| *x & mask == *y & mask);
+        if !matched {
+            panic!(
+                "replay mismatch at event {}:\n expected TMS {} TDI {}\n actual   TMS {} TDI {}",
+                self.position.get() - 1,
+                pins_to_string(expected, JtagBit::TMS),
+                pins_to_string(expected, JtagBit::TDI),
+                pins_to_string(actual, JtagBit::TMS),
+                pins_to_string(actual, JtagBit::TDI)
+            );
+        }
+    }
+
+    fn consume_tms(&self, pins: &[JtagBit]) {
+        let mut state_machine = self.state_machine.borrow_mut();
+        for x in pins {
+            let _ = state_machine.consume(&x.contains(JtagBit::TMS));
+        }
+    }
+
+    fn check_reset(&self, actual: ReplayEvent) {
+        let expected = self.next(&actual);
+        if *expected != actual {
+            panic!(
+                "replay mismatch at event {}: expected {:?}, actual {:?}",
+                self.position.get() - 1,
+                expected,
+                actual
+            );
+        }
+        if actual == ReplayEvent::Trst(true) {
+            *self.state_machine.borrow_mut() = StateMachine::new();
+        }
+    }
+}
+
+fn pins_to_string(pins: &[JtagBit], bit: JtagBit) -> String {
+    pins.iter()
+        .map(|x| if x.contains(bit) { '1' } else { '0' })
+        .collect()
+}
+
+impl JtagInterface for ReplayInterface {
+    fn capabilities(&self) -> InterfaceCaps {
+        self.recording.caps
+    }
+
+    fn raw_write(&self, data: &[JtagBit]) -> Result<(), InterfaceError> {
+        if self.is_idle(data) {
+            return Ok(());
+        }
+        let actual = ReplayEvent::RawWrite(data.to_vec());
+        match self.next(&actual) {
+            ReplayEvent::RawWrite(pins) => {
+                self.check_pins(pins, data, self.tolerance.ignore_write_tdi)
+            }
+            expected => panic!(
+                "replay mismatch at event {}: expected {:?}, actual raw_write",
+                self.position.get() - 1,
+                expected
+            ),
+        }
+        self.consume_tms(data);
+        Ok(())
+    }
+
+    fn raw_read(&self, data: &mut [JtagBit]) -> Result<(), InterfaceError> {
+        let actual = ReplayEvent::RawRead {
+            pins: data.to_vec(),
+            tdo: Vec::new(),
+        };
+        match self.next(&actual) {
+            ReplayEvent::RawRead { pins, tdo } => {
+                self.check_pins(pins, data, false);
+                for (x, y) in data.iter_mut().zip(tdo) {
+                    x.set(JtagBit::TDO, *y);
+                }
+            }
+            expected => panic!(
+                "replay mismatch at event {}: expected {:?}, actual raw_read",
+                self.position.get() - 1,
+                expected
+            ),
+        }
+        self.consume_tms(data);
+        Ok(())
+    }
+
+    fn assert_trst(&self, level: bool) -> Result<(), InterfaceError> {
+        self.check_reset(ReplayEvent::Trst(level));
+        Ok(())
+    }
+    fn assert_srst(&self, level: bool) -> Result<(), InterfaceError> {
+        self.check_reset(ReplayEvent::Srst(level));
+        Ok(())
+    }
+    // 記録した時に待った時間は再生しない
+    fn pulse_srst(&self, _duration_ms: u32) -> Result<(), InterfaceError> {
+        self.assert_srst(true)?;
+        self.assert_srst(false)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::jtag::dap::{MemoryAccessPort, DAP};
+    use crate::jtag::idcode::TapDevice;
+    use crate::jtag::jtag::{Jtag, TAP};
+    use crate::jtag::JtagBit as JB;
+    use spin::mutex::Mutex;
+
+    const IR_IDCODE: u8 = 0b1110;
+    const IR_DPACC: u8 = 0b1010;
+    const IR_APACC: u8 = 0b1011;
+    const IR_ABORT: u8 = 0b1000;
+    pub(crate) const IDCODE: u32 = 0x4ba0_0477;
+    pub(crate) const AP_IDR: u32 = 0x2477_0002;
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/scan_dap_idr.replay");
+
+    struct SimDapState {
+        state_machine: StateMachine<JtagStateMachine>,
+        ir: u8,
+        ir_shift: u8,
+        dr_shift: u64,
+        ctrlstat: u32,
+        select: u32,
+        csw: u32,
+        // 次のCapture-DRで返すread data
+        rdbuff: u32,
+        // APACCにWAITを返し続ける
+        ap_wait: bool,
+        // ABORT命令で書かれた値
+        aborts: Vec<u32>,
+    }
+
+    // IR長4のJTAG-DPをpinの単位で模したもの。AP0はCSWとIDRだけを持つ
+    pub(crate) struct SimDap {
+        state: RefCell<SimDapState>,
+    }
+
+    impl SimDap {
+        pub(crate) fn new() -> Self {
+            SimDap {
+                state: RefCell::new(SimDapState {
+                    state_machine: StateMachine::new(),
+                    ir: IR_IDCODE,
+                    ir_shift: 0,
+                    dr_shift: 0,
+                    ctrlstat: 0,
+                    select: 0,
+                    csw: 0,
+                    rdbuff: 0,
+                    ap_wait: false,
+                    aborts: Vec::new(),
+                }),
+            }
+        }
+
+        pub(crate) fn set_ap_wait(&self, wait: bool) {
+            self.state.borrow_mut().ap_wait = wait;
+        }
+
+        pub(crate) fn aborts(&self) -> Vec<u32> {
+            self.state.borrow().aborts.clone()
+        }
+
+        fn dr_len(ir: u8) -> usize {
+            match ir {
+                IR_IDCODE => 32,
+                IR_DPACC | IR_APACC | IR_ABORT => 35,
+                _ => 1,
+            }
+        }
+
+        fn update_dr(dap: &mut SimDapState) {
+            let read = dap.dr_shift & 1 != 0;
+            let a = ((dap.dr_shift >> 1) & 3) as u8;
+            let data = (dap.dr_shift >> 3) as u32;
+            match (dap.ir, a, read) {
+                (IR_DPACC, 0, true) => dap.rdbuff = IDCODE,
+                (IR_DPACC, 1, true) => dap.rdbuff = dap.ctrlstat,
+                // power-upの要求にすぐACKを返す
+                (IR_DPACC, 1, false) => dap.ctrlstat = data | (data & (1 << 28 | 1 << 30)) << 1,
+                (IR_DPACC, 2, false) => dap.select = data,
+                (IR_ABORT, 0, false) => dap.aborts.push(data),
+                (IR_APACC, _, _) if dap.ap_wait => (),
+                (IR_APACC, a, read) => match ((dap.select >> 4) & 0xf, a, read) {
+                    (0, 0, true) => dap.rdbuff = dap.csw,
+                    (0, 0, false) => dap.csw = data,
+                    (0xf, 3, true) => dap.rdbuff = AP_IDR,
+                    (_, _, true) => dap.rdbuff = 0,
+                    _ => (),
+                },
+                _ => (),
+            }
+        }
+
+        fn clock(&self, pins: JB) -> bool {
+            let mut dap = self.state.borrow_mut();
+            let tdi = pins.contains(JB::TDI) as u64;
+            let dr_len = Self::dr_len(dap.ir);
+            let tdo = match dap.state_machine.state() {
+                JtagState::ShiftDR => {
+                    let tdo = dap.dr_shift & 1 != 0;
+                    dap.dr_shift = (dap.dr_shift >> 1) | (tdi << (dr_len - 1));
+                    tdo
+                }
+                JtagState::ShiftIR => {
+                    let tdo = dap.ir_shift & 1 != 0;
+                    dap.ir_shift = (dap.ir_shift >> 1) | ((tdi as u8) << 3);
+                    tdo
+                }
+                _ => false,
+            };
+            dap.state_machine.consume(&pins.contains(JB::TMS)).unwrap();
+            match dap.state_machine.state() {
+                JtagState::Reset => dap.ir = IR_IDCODE,
+                JtagState::CaptureDR => {
+                    dap.dr_shift = match dap.ir {
+                        IR_IDCODE => IDCODE as u64,
+                        IR_APACC if dap.ap_wait => 0b001,
+                        // ap_wait以外ではACKは常にOK/FAULT
+                        IR_DPACC | IR_APACC => 0b010 | (dap.rdbuff as u64) << 3,
+                        _ => 0,
+                    }
+                }
+                JtagState::UpdateDR => Self::update_dr(&mut dap),
+                JtagState::CaptureIR => dap.ir_shift = 0b0001,
+                JtagState::UpdateIR => dap.ir = dap.ir_shift,
+                _ => (),
+            }
+            tdo
+        }
+    }
+
+    impl JtagInterface for SimDap {
+        fn capabilities(&self) -> InterfaceCaps {
+            InterfaceCaps::new(CapFlags::SUPPORTS_IDLE_CLOCK)
+        }
+        fn raw_write(&self, data: &[JB]) -> Result<(), InterfaceError> {
+            for x in data {
+                self.clock(*x);
+            }
+            Ok(())
+        }
+        fn raw_read(&self, data: &mut [JB]) -> Result<(), InterfaceError> {
+            for x in data.iter_mut() {
+                let tdo = self.clock(*x);
+                x.set(JB::TDO, tdo);
+            }
+            Ok(())
+        }
+    }
+
+    // fixtureと同じ操作。IDCODEとAPのIDRを返す
+    fn scan_and_read_idr<T: JtagInterface>(interface: T) -> (Jtag<T>, Vec<TapDevice>, u32) {
+        let mut jtag = Jtag::new(interface);
+        let devices = jtag.scan().unwrap().devices().to_vec();
+        let jtag = Mutex::new(jtag);
+        let idr = {
+            let mut dap = DAP::new(TAP::new(&jtag, 4));
+            dap.memap_idr_read().unwrap().1
+        };
+        (jtag.into_inner(), devices, idr)
+    }
+
+    // cargo test -p libjtag regenerate_fixture -- --ignored
+    #[test]
+    #[ignore]
+    fn regenerate_fixture() {
+        let (jtag, _, _) = scan_and_read_idr(RecordingInterface::new(SimDap::new()));
+        jtag.interface.save(FIXTURE).unwrap();
+    }
+
+    #[test]
+    fn fixture_replay_test() {
+        let replay = ReplayInterface::load(FIXTURE).unwrap();
+        assert_eq!(
+            InterfaceCaps::new(CapFlags::SUPPORTS_IDLE_CLOCK),
+            replay.capabilities()
+        );
+        let (jtag, devices, idr) = scan_and_read_idr(replay);
+        assert_eq!(1, devices.len());
+        assert_eq!(Some(IDCODE), devices[0].raw());
+        assert_eq!(AP_IDR, idr);
+        jtag.interface.finish();
+    }
+
+    #[test]
+    fn record_test() {
+        let (jtag, devices, idr) = scan_and_read_idr(RecordingInterface::new(SimDap::new()));
+        assert_eq!(Some(IDCODE), devices[0].raw());
+        assert_eq!(AP_IDR, idr);
+        let recording = jtag.interface.recording();
+        assert!(recording
+            .events
+            .iter()
+            .any(|x| matches!(x, ReplayEvent::RawRead { .. })));
+        assert_eq!(
+            recording,
+            Recording::from_bytes(&recording.to_bytes()).unwrap()
+        );
+
+        let mut data = recording.to_bytes();
+        data.truncate(data.len() - 1);
+        assert!(matches!(
+            Recording::from_bytes(&data),
+            Err(ReplayError::Truncated { .. })
+        ));
+        assert_eq!(
+            Err(ReplayError::UnsupportedVersion(2)),
+            Recording::from_bytes(b"JRPL\x02")
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "replay mismatch at event 0")]
+    fn replay_mismatch_test() {
+        let recording = Recording {
+            caps: InterfaceCaps::default(),
+            events: vec![ReplayEvent::RawWrite(vec![JB::TMS, JB::empty()])],
+        };
+        let replay = ReplayInterface::new(recording);
+        replay.write_tms(&[true, true]).unwrap();
+    }
+
+    fn idle_recording() -> Recording {
+        let recorder = RecordingInterface::new(SimDap::new());
+        recorder.write_tms(&[false]).unwrap();
+        recorder.clock_idle(10).unwrap();
+        recorder.write_tms(&[true, false, false]).unwrap();
+        recorder.write_data(&[true, false], true).unwrap();
+        recorder.recording()
+    }
+
+    #[test]
+    fn tolerance_test() {
+        // idle cycleの数とTDIが違っても良い
+        let tolerance = ReplayTolerance {
+            ignore_write_tdi: true,
+            ignore_idle: true,
+        };
+        let replay = ReplayInterface::new_with_tolerance(idle_recording(), tolerance);
+        replay.write_tms(&[false]).unwrap();
+        replay.clock_idle(3).unwrap();
+        replay.write_tms(&[true, false, false]).unwrap();
+        replay.write_data(&[false, true], true).unwrap();
+        replay.finish();
+
+        // 記録通りなら許容しなくても通る
+        let replay = ReplayInterface::new(idle_recording());
+        replay.write_tms(&[false]).unwrap();
+        replay.clock_idle(10).unwrap();
+        replay.write_tms(&[true, false, false]).unwrap();
+        replay.write_data(&[true, false], true).unwrap();
+        replay.finish();
+    }
+
+    #[test]
+    #[should_panic(expected = "replay mismatch")]
+    fn idle_mismatch_test() {
+        let replay = ReplayInterface::new(idle_recording());
+        replay.write_tms(&[false]).unwrap();
+        replay.clock_idle(3).unwrap();
+        replay.write_tms(&[true, false, false]).unwrap();
+    }
+}
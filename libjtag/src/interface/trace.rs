@@ -0,0 +1,450 @@
+// 内側のinterfaceへの呼び出しを全て記録し、VCDかJSON linesでfileに書き出すinterface
+// 失敗したsessionをOpenOCDなどで取った正常なcaptureと比べるために使う
+use log::error;
+use std::cell::{Cell, RefCell};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use super::{InterfaceCaps, InterfaceError, JtagInterface};
+use crate::jtag::JtagBit;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceFormat {
+    // TCK/TMS/TDI/TDO/TRST/SRSTの波形
+    Vcd,
+    // 1行に1回の呼び出し
+    JsonLines,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceOp {
+    WriteTms,
+    WriteData,
+    ReadData,
+    RawWrite,
+    RawRead,
+    Trst,
+    Srst,
+}
+
+impl TraceOp {
+    fn name(&self) -> &'static str {
+        match self {
+            TraceOp::WriteTms => "write_tms",
+            TraceOp::WriteData => "write_data",
+            TraceOp::ReadData => "read_data",
+            TraceOp::RawWrite => "raw_write",
+            TraceOp::RawRead => "raw_read",
+            TraceOp::Trst => "trst",
+            TraceOp::Srst => "srst",
+        }
+    }
+}
+
+// 1回の呼び出しの記録。tms/tdi/tdoはTCK 1cycleにつき1bitで、先にshiftした順
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEntry {
+    pub op: TraceOp,
+    // TracingInterface::newからの経過時間
+    pub time_us: u64,
+    pub tms: Vec<bool>,
+    pub tdi: Vec<bool>,
+    // readでなければ空
+    pub tdo: Vec<bool>,
+    // Trst/Srstでassertしたか
+    pub level: bool,
+}
+
+pub struct TracingInterface<T> {
+    inner: T,
+    format: TraceFormat,
+    path: PathBuf,
+    start: Instant,
+    entries: RefCell<Vec<TraceEntry>>,
+    // write_tmsの間はTDIを直前の値のまま保持する
+    tdi: Cell<bool>,
+    // 最後にflushした時のentryの数
+    flushed: Cell<usize>,
+}
+
+impl<T> TracingInterface<T> {
+    // fileはflushかDropの時に書き出す
+    pub fn new(inner: T, format: TraceFormat, path: impl AsRef<Path>) -> Self {
+        TracingInterface {
+            inner,
+            format,
+            path: path.as_ref().to_path_buf(),
+            start: Instant::now(),
+            entries: RefCell::new(Vec::new()),
+            tdi: Cell::new(false),
+            flushed: Cell::new(0),
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn entries(&self) -> Vec<TraceEntry> {
+        self.entries.borrow().clone()
+    }
+
+    pub fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        let entries = self.entries.borrow();
+        match self.format {
+            TraceFormat::Vcd => write_vcd(out, &entries),
+            TraceFormat::JsonLines => write_json_lines(out, &entries),
+        }
+    }
+
+    // これまでのsession全体でfileを上書きする。errorにはfileの名前を含める
+    pub fn flush(&self) -> io::Result<()> {
+        let with_path = |e: io::Error, action: &str| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to {} {}: {}", action, self.path.display(), e),
+            )
+        };
+        let file = File::create(&self.path).map_err(|e| with_path(e, "create"))?;
+        let mut out = BufWriter::new(file);
+        self.write_to(&mut out)
+            .and_then(|_| out.flush())
+            .map_err(|e| with_path(e, "write"))?;
+        self.flushed.set(self.entries.borrow().len());
+        Ok(())
+    }
+
+    fn record(&self, op: TraceOp, tms: Vec<bool>, tdi: Vec<bool>, tdo: Vec<bool>) {
+        if let Some(x) = tdi.last() {
+            self.tdi.set(*x);
+        }
+        self.entries.borrow_mut().push(TraceEntry {
+            op,
+            time_us: self.start.elapsed().as_micros() as u64,
+            tms,
+            tdi,
+            tdo,
+            level: false,
+        });
+    }
+
+    fn record_reset(&self, op: TraceOp, level: bool) {
+        self.entries.borrow_mut().push(TraceEntry {
+            op,
+            time_us: self.start.elapsed().as_micros() as u64,
+            tms: Vec::new(),
+            tdi: Vec::new(),
+            tdo: Vec::new(),
+            level,
+        });
+    }
+}
+
+impl<T> Drop for TracingInterface<T> {
+    fn drop(&mut self) {
+        if self.flushed.get() == self.entries.borrow().len() {
+            return;
+        }
+        if let Err(e) = self.flush() {
+            error!("failed to write trace: {}", e);
+        }
+    }
+}
+
+// write_data/read_dataでは最後のbitでだけTMSを上げる
+fn data_tms(len: usize, exit: bool) -> Vec<bool> {
+    let mut tms = vec![false; len];
+    if let Some(last) = tms.last_mut() {
+        *last = exit;
+    }
+    tms
+}
+
+fn pins_bits(pins: &[JtagBit], bit: JtagBit) -> Vec<bool> {
+    pins.iter().map(|x| x.contains(bit)).collect()
+}
+
+impl<T: JtagInterface> JtagInterface for TracingInterface<T> {
+    fn write_tms(&self, tms: &[bool]) -> Result<(), InterfaceError> {
+        self.inner.write_tms(tms)?;
+        let tdi = vec![self.tdi.get(); tms.len()];
+        self.record(TraceOp::WriteTms, tms.to_vec(), tdi, Vec::new());
+        Ok(())
+    }
+    fn write_data(&self, tdi: &[bool], exit: bool) -> Result<(), InterfaceError> {
+        self.inner.write_data(tdi, exit)?;
+        let tms = data_tms(tdi.len(), exit);
+        self.record(TraceOp::WriteData, tms, tdi.to_vec(), Vec::new());
+        Ok(())
+    }
+    fn read_data(&self, tditdo: &mut [bool], exit: bool) -> Result<(), InterfaceError> {
+        let tdi = tditdo.to_vec();
+        self.inner.read_data(tditdo, exit)?;
+        let tms = data_tms(tdi.len(), exit);
+        self.record(TraceOp::ReadData, tms, tdi, tditdo.to_vec());
+        Ok(())
+    }
+
+    fn raw_write(&self, data: &[JtagBit]) -> Result<(), InterfaceError> {
+        self.inner.raw_write(data)?;
+        let tms = pins_bits(data, JtagBit::TMS);
+        let tdi = pins_bits(data, JtagBit::TDI);
+        self.record(TraceOp::RawWrite, tms, tdi, Vec::new());
+        Ok(())
+    }
+    fn raw_read(&self, data: &mut [JtagBit]) -> Result<(), InterfaceError> {
+        let tms = pins_bits(data, JtagBit::TMS);
+        let tdi = pins_bits(data, JtagBit::TDI);
+        self.inner.raw_read(data)?;
+        let tdo = pins_bits(data, JtagBit::TDO);
+        self.record(TraceOp::RawRead, tms, tdi, tdo);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), InterfaceError> {
+        self.inner.flush()
+    }
+
+    fn capabilities(&self) -> InterfaceCaps {
+        self.inner.capabilities()
+    }
+
+    fn assert_trst(&self, level: bool) -> Result<(), InterfaceError> {
+        self.inner.assert_trst(level)?;
+        self.record_reset(TraceOp::Trst, level);
+        Ok(())
+    }
+    fn assert_srst(&self, level: bool) -> Result<(), InterfaceError> {
+        self.inner.assert_srst(level)?;
+        self.record_reset(TraceOp::Srst, level);
+        Ok(())
+    }
+    fn pulse_srst(&self, duration_ms: u32) -> Result<(), InterfaceError> {
+        self.inner.pulse_srst(duration_ms)?;
+        self.record_reset(TraceOp::Srst, true);
+        self.record_reset(TraceOp::Srst, false);
+        Ok(())
+    }
+}
+
+// VCDのsignal。idはVCDの識別子
+const VCD_SIGNALS: [(&str, char); 6] = [
+    ("tck", '!'),
+    ("tms", '"'),
+    ("tdi", '#'),
+    ("tdo", '$'),
+    ("trst", '%'),
+    ("srst", '&'),
+];
+const TCK: usize = 0;
+const TMS: usize = 1;
+const TDI: usize = 2;
+const TDO: usize = 3;
+const TRST: usize = 4;
+const SRST: usize = 5;
+
+fn vcd_value(x: bool) -> char {
+    if x {
+        '1'
+    } else {
+        '0'
+    }
+}
+
+// 値が変わった時だけ書き、同じ時刻の変化は1つの#timeにまとめる
+struct VcdWriter<'a> {
+    out: &'a mut dyn Write,
+    time: u64,
+    written_time: Option<u64>,
+    values: [char; 6],
+}
+
+impl<'a> VcdWriter<'a> {
+    fn set(&mut self, signal: usize, value: char) -> io::Result<()> {
+        if self.values[signal] == value {
+            return Ok(());
+        }
+        if self.written_time != Some(self.time) {
+            writeln!(self.out, "#{}", self.time)?;
+            self.written_time = Some(self.time);
+        }
+        self.values[signal] = value;
+        writeln!(self.out, "{}{}", value, VCD_SIGNALS[signal].1)
+    }
+}
+
+// 時刻は実時間ではなく、TCKの半周期を1とする
+// TCKの立ち下がりでTMS/TDIを変え、立ち上がりでTDOを見る。readでない間のTDOはx
+pub fn write_vcd(out: &mut dyn Write, entries: &[TraceEntry]) -> io::Result<()> {
+    writeln!(out, "$version libjtag TracingInterface $end")?;
+    writeln!(out, "$timescale 1 ns $end")?;
+    writeln!(out, "$scope module jtag $end")?;
+    for (name, id) in VCD_SIGNALS.iter() {
+        writeln!(out, "$var wire 1 {} {} $end", id, name)?;
+    }
+    writeln!(out, "$upscope $end")?;
+    writeln!(out, "$enddefinitions $end")?;
+    writeln!(out, "#0")?;
+    writeln!(out, "$dumpvars")?;
+    let values = ['0', '0', '0', 'x', '0', '0'];
+    for (value, (_, id)) in values.iter().zip(VCD_SIGNALS.iter()) {
+        writeln!(out, "{}{}", value, id)?;
+    }
+    writeln!(out, "$end")?;
+
+    let mut vcd = VcdWriter {
+        out,
+        time: 0,
+        written_time: Some(0),
+        values,
+    };
+    for entry in entries {
+        writeln!(
+            vcd.out,
+            "$comment {} at {} us $end",
+            entry.op.name(),
+            entry.time_us
+        )?;
+        match entry.op {
+            TraceOp::Trst => vcd.set(TRST, vcd_value(entry.level))?,
+            TraceOp::Srst => vcd.set(SRST, vcd_value(entry.level))?,
+            _ => (),
+        }
+        for i in 0..entry.tms.len() {
+            vcd.time += 1;
+            vcd.set(TCK, '0')?;
+            vcd.set(TMS, vcd_value(entry.tms[i]))?;
+            vcd.set(TDI, vcd_value(entry.tdi[i]))?;
+            vcd.time += 1;
+            vcd.set(TCK, '1')?;
+            vcd.set(TDO, entry.tdo.get(i).map_or('x', |x| vcd_value(*x)))?;
+        }
+    }
+    // 最後の立ち上がりを見えるようにする
+    vcd.time += 1;
+    vcd.set(TCK, '0')
+}
+
+fn bit_string(bits: &[bool]) -> String {
+    bits.iter().map(|x| vcd_value(*x)).collect()
+}
+
+pub fn write_json_lines(out: &mut dyn Write, entries: &[TraceEntry]) -> io::Result<()> {
+    for entry in entries {
+        write!(
+            out,
+            "{{\"time_us\":{},\"op\":\"{}\"",
+            entry.time_us,
+            entry.op.name()
+        )?;
+        match entry.op {
+            TraceOp::Trst | TraceOp::Srst => write!(out, ",\"level\":{}", entry.level)?,
+            _ => {
+                write!(out, ",\"tms\":\"{}\"", bit_string(&entry.tms))?;
+                write!(out, ",\"tdi\":\"{}\"", bit_string(&entry.tdi))?;
+                if !entry.tdo.is_empty() {
+                    write!(out, ",\"tdo\":\"{}\"", bit_string(&entry.tdo))?;
+                }
+            }
+        }
+        writeln!(out, "}}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::mock::MockInterface;
+
+    // TMS 1,0 → TDIに1,0を流してTDOに0,1を読む → TRST
+    fn session(format: TraceFormat, path: &Path) -> TracingInterface<MockInterface> {
+        let mock = MockInterface::new();
+        mock.script_read(0, &[false, true]);
+        let iface = TracingInterface::new(mock, format, path);
+        iface.write_tms(&[true, false]).unwrap();
+        let mut data = [true, false];
+        iface.read_data(&mut data, true).unwrap();
+        assert_eq!([false, true], data);
+        iface.assert_trst(true).unwrap();
+        iface
+    }
+
+    fn output(iface: &TracingInterface<MockInterface>) -> String {
+        let mut out = Vec::new();
+        iface.write_to(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    // 時刻の後ろに続く変化
+    fn changes_at(vcd: &str, time: u64) -> Vec<&str> {
+        vcd.lines()
+            .skip_while(|x| *x != format!("#{}", time))
+            .skip(1)
+            .take_while(|x| !x.starts_with('#') && !x.starts_with("$comment"))
+            .collect()
+    }
+
+    #[test]
+    fn vcd_test() {
+        let path = std::env::temp_dir().join("libjtag_trace_vcd_test.vcd");
+        let iface = session(TraceFormat::Vcd, &path);
+        let vcd = output(&iface);
+        let header = [
+            "$version libjtag TracingInterface $end",
+            "$timescale 1 ns $end",
+            "$scope module jtag $end",
+            "$var wire 1 ! tck $end",
+            "$var wire 1 \" tms $end",
+            "$var wire 1 # tdi $end",
+            "$var wire 1 $ tdo $end",
+            "$var wire 1 % trst $end",
+            "$var wire 1 & srst $end",
+            "$upscope $end",
+            "$enddefinitions $end",
+        ];
+        assert_eq!(
+            header.to_vec(),
+            vcd.lines().take(header.len()).collect::<Vec<_>>()
+        );
+
+        // write_tms: TMS=1で立ち上がり、TMS=0で立ち上がり
+        assert_eq!(vec!["1\""], changes_at(&vcd, 1));
+        assert_eq!(vec!["1!"], changes_at(&vcd, 2));
+        assert_eq!(vec!["0!", "0\""], changes_at(&vcd, 3));
+        // read_data: TDI=1を出し、立ち上がりでTDO=0を読む
+        assert_eq!(vec!["0!", "1#"], changes_at(&vcd, 5));
+        assert_eq!(vec!["1!", "0$"], changes_at(&vcd, 6));
+        // 最後のbitでTMSを上げてexitする
+        assert_eq!(vec!["0!", "1\"", "0#"], changes_at(&vcd, 7));
+        assert_eq!(vec!["1!", "1$"], changes_at(&vcd, 8));
+        assert!(vcd.contains("$comment trst at "));
+        assert_eq!(vec!["0!"], changes_at(&vcd, 9));
+        assert!(vcd.lines().any(|x| x == "1%"));
+
+        iface.flush().unwrap();
+        assert_eq!(vcd, std::fs::read_to_string(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn json_lines_test() {
+        let path = std::env::temp_dir().join("libjtag_trace_json_test.jsonl");
+        let iface = session(TraceFormat::JsonLines, &path);
+        let json = output(&iface);
+        let lines: Vec<&str> = json.lines().collect();
+        assert_eq!(3, lines.len());
+        assert!(lines[0].starts_with("{\"time_us\":"));
+        assert!(lines[0].ends_with(",\"op\":\"write_tms\",\"tms\":\"10\",\"tdi\":\"00\"}"));
+        assert!(lines[1]
+            .ends_with(",\"op\":\"read_data\",\"tms\":\"01\",\"tdi\":\"10\",\"tdo\":\"01\"}"));
+        assert!(lines[2].ends_with(",\"op\":\"trst\",\"level\":true}"));
+
+        // Dropで書き出す
+        drop(iface);
+        assert_eq!(json, std::fs::read_to_string(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
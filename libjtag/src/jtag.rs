@@ -1,8 +1,17 @@
 use bitflags::bitflags;
 
+pub mod bits;
+#[cfg(feature = "std")]
+pub mod boundary_scan;
 pub mod dap;
+pub mod idcode;
 pub mod jtag;
 pub mod jtag_state_machine;
+pub mod manufacturer;
+pub mod stats;
+#[cfg(feature = "std")]
+pub mod svf;
+pub mod swj;
 
 pub type JtagPin = u32;
 
@@ -0,0 +1,90 @@
+// JTAGでshiftするbool列と整数の変換
+// bool列の先頭(index 0)が最初にshiftされるbitで、整数のLSBに対応する
+
+// valueの下位len bitをLSBから順に返す
+pub fn to_bools_lsb_first(value: u64, len: usize) -> impl Iterator<Item = bool> {
+    (0..len).map(move |i| i < 64 && (value >> i) & 1 != 0)
+}
+
+// bitsの長さ分だけvalueの下位bitを並べる
+pub fn fill_lsb_first(bits: &mut [bool], value: u64) {
+    let len = bits.len();
+    for (x, y) in bits.iter_mut().zip(to_bools_lsb_first(value, len)) {
+        *x = y;
+    }
+}
+
+// 64bitを超えた分は捨てる
+pub fn from_bools_lsb_first(bits: &[bool]) -> u64 {
+    bits.iter()
+        .take(64)
+        .enumerate()
+        .fold(0, |x, (i, y)| x | (*y as u64) << i)
+}
+
+// bits[start..start + len]を1つの値として読む
+pub fn field(bits: &[bool], start: usize, len: usize) -> u64 {
+    from_bools_lsb_first(&bits[start..start + len])
+}
+
+pub fn set_field(bits: &mut [bool], start: usize, len: usize, value: u64) {
+    fill_lsb_first(&mut bits[start..start + len], value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 依存を増やさないためのxorshift
+    fn xorshift(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let mut state = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..1000 {
+            let len = (xorshift(&mut state) % 65) as usize;
+            let value = xorshift(&mut state);
+            let mask = if len == 64 { !0 } else { (1 << len) - 1 };
+            let bits: Vec<bool> = to_bools_lsb_first(value, len).collect();
+            assert_eq!(len, bits.len());
+            assert_eq!(value & mask, from_bools_lsb_first(&bits), "len {}", len);
+
+            let mut filled = vec![true; len];
+            fill_lsb_first(&mut filled, value);
+            assert_eq!(bits, filled);
+        }
+        assert_eq!(0b0110, from_bools_lsb_first(&[false, true, true, false]));
+        // 64bitを超えた部分は0になる
+        assert_eq!(
+            vec![true; 2],
+            to_bools_lsb_first(!0, 66).take(2).collect::<Vec<_>>()
+        );
+        assert!(!to_bools_lsb_first(!0, 66).nth(65).unwrap());
+    }
+
+    // APACC/DPACCのDR: [2:0]がRnWとA[3:2]、またはACK。[34:3]がdata
+    #[test]
+    fn apacc_layout_test() {
+        let mut state = 0x9e37_79b9_7f4a_7c15;
+        for _ in 0..1000 {
+            let ack = xorshift(&mut state) & 0b111;
+            let data = xorshift(&mut state) & 0xffff_ffff;
+            let mut dr = [false; 35];
+            set_field(&mut dr, 0, 3, ack);
+            set_field(&mut dr, 3, 32, data);
+            assert_eq!(ack, field(&dr, 0, 3));
+            assert_eq!(data, field(&dr, 3, 32));
+            // ACKは最初にshiftされるbitがACK[0]
+            assert_eq!(ack & 1 != 0, dr[0]);
+            assert_eq!(data >> 31 != 0, dr[34]);
+        }
+        // WAIT
+        let dr = [true, false, false];
+        assert_eq!(0b001, field(&dr, 0, 3));
+    }
+}
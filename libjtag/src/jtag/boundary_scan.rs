@@ -0,0 +1,524 @@
+// boundary scanでpackageのpinを読み書きする
+// BSDLはBOUNDARY_LENGTH、BOUNDARY_REGISTER、INSTRUCTION_OPCODEのattributeだけを読む
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::interface::{InterfaceError, JtagInterface};
+use crate::jtag::jtag::TAP;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BoundaryScanError {
+    // BSDLの値が読めない
+    Parse(String),
+    // BOUNDARY_REGISTERにその番号のcellがない
+    MissingCell(usize),
+    LengthMismatch { length: usize, cells: usize },
+    // EXTESTやSAMPLE/PRELOADのopcodeがない
+    MissingOpcode(&'static str),
+    // 駆動できるcellのないpin
+    NoOutputCell(String),
+    Interface(InterfaceError),
+}
+
+impl fmt::Display for BoundaryScanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BoundaryScanError::Parse(message) => write!(f, "{}", message),
+            BoundaryScanError::MissingCell(number) => {
+                write!(f, "boundary cell {} is missing", number)
+            }
+            BoundaryScanError::LengthMismatch { length, cells } => write!(
+                f,
+                "BOUNDARY_LENGTH is {} but {} cells are defined",
+                length, cells
+            ),
+            BoundaryScanError::MissingOpcode(name) => write!(f, "BSDL has no {} opcode", name),
+            BoundaryScanError::NoOutputCell(pin) => write!(f, "pin {} has no output cell", pin),
+            BoundaryScanError::Interface(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for BoundaryScanError {}
+
+impl From<InterfaceError> for BoundaryScanError {
+    fn from(e: InterfaceError) -> Self {
+        BoundaryScanError::Interface(e)
+    }
+}
+
+// BOUNDARY_REGISTERのfunction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CellFunction {
+    Input,
+    Clock,
+    Output2,
+    Output3,
+    Bidir,
+    Control,
+    ControlR,
+    Internal,
+    ObserveOnly,
+}
+
+impl CellFunction {
+    fn parse(name: &str) -> Result<Self, String> {
+        Ok(match name.to_ascii_lowercase().as_str() {
+            "input" => CellFunction::Input,
+            "clock" => CellFunction::Clock,
+            "output2" => CellFunction::Output2,
+            "output3" => CellFunction::Output3,
+            "bidir" => CellFunction::Bidir,
+            "control" => CellFunction::Control,
+            "controlr" => CellFunction::ControlR,
+            "internal" => CellFunction::Internal,
+            "observe_only" => CellFunction::ObserveOnly,
+            _ => return Err(format!("unknown cell function {}", name)),
+        })
+    }
+
+    // 同じpinに複数のcellがある場合、captureした値を使う優先順位
+    // 入力専用のcellが最もpinに近い
+    fn capture_priority(&self) -> Option<u8> {
+        match self {
+            CellFunction::Output2 | CellFunction::Output3 => Some(0),
+            CellFunction::Bidir => Some(1),
+            CellFunction::Input | CellFunction::Clock | CellFunction::ObserveOnly => Some(2),
+            CellFunction::Control | CellFunction::ControlR | CellFunction::Internal => None,
+        }
+    }
+
+    // EXTEST中にpinを駆動するcell
+    fn is_output(&self) -> bool {
+        matches!(
+            self,
+            CellFunction::Output2 | CellFunction::Output3 | CellFunction::Bidir
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BoundaryCell {
+    pub number: usize,
+    // "*"の場合はNone
+    pub port: Option<String>,
+    pub function: CellFunction,
+    // Xの場合はNone
+    pub safe: Option<bool>,
+    // output3/bidirの出力を止めるcontrol cellの番号と、その時の値
+    pub control: Option<(usize, bool)>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Bsdl {
+    pub boundary_length: usize,
+    // cell番号順
+    pub cells: Vec<BoundaryCell>,
+    pub opcodes: HashMap<String, u8>,
+}
+
+// "--"から行末まではcomment
+fn strip_comments(text: &str) -> String {
+    text.lines()
+        .map(|line| line.split("--").next().unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// "..." & "..."を連結した中身
+fn string_value(value: &str) -> String {
+    value.split('"').skip(1).step_by(2).collect()
+}
+
+// "attribute NAME of ENTITY : entity is VALUE"のVALUE
+// isの前後は改行の場合もある
+fn attribute_value(statement: &str) -> Option<&str> {
+    let bytes = statement.as_bytes();
+    statement
+        .to_ascii_lowercase()
+        .match_indices("is")
+        .map(|(i, _)| i)
+        .find(|&i| {
+            i > 0
+                && bytes[i - 1].is_ascii_whitespace()
+                && bytes.get(i + 2).is_some_and(|x| x.is_ascii_whitespace())
+        })
+        .map(|i| &statement[i + 2..])
+}
+
+fn parse_bit(token: &str) -> Result<Option<bool>, String> {
+    Ok(match token {
+        "0" => Some(false),
+        "1" => Some(true),
+        "X" | "x" => None,
+        _ => return Err(format!("invalid bit {}", token)),
+    })
+}
+
+fn parse_number(token: &str) -> Result<usize, String> {
+    token
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid number {}", token.trim()))
+}
+
+// "0 (BC_1, PA0, output3, X, 1, 1, Z)"
+fn parse_cell(text: &str) -> Result<BoundaryCell, String> {
+    let (number, rest) = text
+        .split_once('(')
+        .ok_or_else(|| "missing cell definition".to_string())?;
+    let fields: Vec<&str> = rest
+        .trim_end_matches(')')
+        .split(',')
+        .map(|x| x.trim())
+        .collect();
+    if fields.len() != 4 && fields.len() != 7 {
+        return Err(format!("expected 4 or 7 fields, found {}", fields.len()));
+    }
+    let control = if fields.len() == 7 {
+        let disable = parse_bit(fields[5])?.ok_or_else(|| "disable value is X".to_string())?;
+        Some((parse_number(fields[4])?, disable))
+    } else {
+        None
+    };
+    Ok(BoundaryCell {
+        number: parse_number(number)?,
+        port: match fields[1] {
+            "*" => None,
+            port => Some(port.to_string()),
+        },
+        function: CellFunction::parse(fields[2])?,
+        safe: parse_bit(fields[3])?,
+        control,
+    })
+}
+
+// "EXTEST (0000), SAMPLE (0001, 0010)"
+// 複数のopcodeがある場合は最初のものを使う
+fn parse_opcodes(text: &str) -> Result<HashMap<String, u8>, String> {
+    let mut opcodes = HashMap::new();
+    let mut rest = text;
+    while let Some((name, tail)) = rest.split_once('(') {
+        let (codes, tail) = tail
+            .split_once(')')
+            .ok_or_else(|| format!("missing ')' after {}", name.trim()))?;
+        let code = codes.split(',').next().unwrap_or("").trim();
+        if code.len() > 8 {
+            return Err(format!("opcode {} is longer than 8 bits", code));
+        }
+        let code = u8::from_str_radix(code, 2).map_err(|_| format!("invalid opcode {}", code))?;
+        let name = name.trim().trim_start_matches(',').trim();
+        opcodes.insert(name.to_ascii_uppercase(), code);
+        rest = tail;
+    }
+    Ok(opcodes)
+}
+
+impl Bsdl {
+    pub fn parse(text: &str) -> Result<Self, BoundaryScanError> {
+        let text = strip_comments(text);
+        let mut bsdl = Bsdl::default();
+        for statement in text.split(';') {
+            let statement = statement.trim();
+            let mut words = statement.split_whitespace();
+            if !words
+                .next()
+                .is_some_and(|x| x.eq_ignore_ascii_case("attribute"))
+            {
+                continue;
+            }
+            let name = words.next().unwrap_or("").to_ascii_uppercase();
+            let value = match attribute_value(statement) {
+                Some(x) => x,
+                None => continue,
+            };
+            match name.as_str() {
+                "BOUNDARY_LENGTH" => {
+                    bsdl.boundary_length = value.trim().parse().map_err(|_| {
+                        BoundaryScanError::Parse(format!(
+                            "invalid BOUNDARY_LENGTH {}",
+                            value.trim()
+                        ))
+                    })?
+                }
+                "BOUNDARY_REGISTER" => {
+                    // "," の区切りはcellの中にもあるので"),"で分ける
+                    for cell in string_value(value).split_terminator("),") {
+                        let cell = cell.trim();
+                        if cell.is_empty() {
+                            continue;
+                        }
+                        bsdl.cells.push(parse_cell(cell).map_err(|e| {
+                            BoundaryScanError::Parse(format!("invalid cell \"{}\": {}", cell, e))
+                        })?);
+                    }
+                }
+                "INSTRUCTION_OPCODE" => {
+                    bsdl.opcodes =
+                        parse_opcodes(&string_value(value)).map_err(BoundaryScanError::Parse)?
+                }
+                _ => (),
+            }
+        }
+        bsdl.cells.sort_by_key(|x| x.number);
+        for (i, cell) in bsdl.cells.iter().enumerate() {
+            if cell.number != i {
+                return Err(BoundaryScanError::MissingCell(i));
+            }
+        }
+        if bsdl.cells.len() != bsdl.boundary_length {
+            return Err(BoundaryScanError::LengthMismatch {
+                length: bsdl.boundary_length,
+                cells: bsdl.cells.len(),
+            });
+        }
+        Ok(bsdl)
+    }
+
+    // IEEE 1149.1-2001以降はSAMPLEとPRELOADが別の名前になる
+    pub fn opcode(&self, name: &str) -> Option<u8> {
+        self.opcodes.get(&name.to_ascii_uppercase()).copied()
+    }
+}
+
+// sample_allで読んだpinのlevel
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PinStates {
+    levels: HashMap<String, bool>,
+}
+
+impl PinStates {
+    pub fn get(&self, pin: &str) -> Option<bool> {
+        self.levels.get(pin).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, bool)> {
+        self.levels.iter().map(|(x, y)| (x.as_str(), *y))
+    }
+}
+
+pub struct BoundaryScanChain<'a, T: JtagInterface> {
+    tap: TAP<'a, T>,
+    cells: Vec<BoundaryCell>,
+    extest: u8,
+    sample: u8,
+    // 次にshiftするboundary registerの値。indexがcell番号
+    pending: Vec<bool>,
+    // EXTESTが入っている間はSAMPLEに戻さずにcaptureする
+    extest_loaded: bool,
+}
+
+impl<'a, T: JtagInterface> BoundaryScanChain<'a, T> {
+    // opcodeはBSDLのEXTESTとSAMPLE(なければPRELOAD)を使う
+    pub fn new(tap: TAP<'a, T>, bsdl: &Bsdl) -> Result<Self, BoundaryScanError> {
+        let extest = bsdl
+            .opcode("EXTEST")
+            .ok_or(BoundaryScanError::MissingOpcode("EXTEST"))?;
+        let sample = bsdl
+            .opcode("SAMPLE")
+            .or_else(|| bsdl.opcode("PRELOAD"))
+            .ok_or(BoundaryScanError::MissingOpcode("SAMPLE/PRELOAD"))?;
+        Ok(Self::new_with_opcodes(tap, bsdl, extest, sample))
+    }
+
+    pub fn new_with_opcodes(tap: TAP<'a, T>, bsdl: &Bsdl, extest: u8, sample: u8) -> Self {
+        // safe bitがXのcellは0で埋める
+        let pending = bsdl.cells.iter().map(|x| x.safe.unwrap_or(false)).collect();
+        BoundaryScanChain {
+            tap,
+            cells: bsdl.cells.clone(),
+            extest,
+            sample,
+            pending,
+            extest_loaded: false,
+        }
+    }
+
+    // 次のapplyでshiftするbit列。index 0(TDOに最も近いcell)から順にshiftする
+    pub fn boundary_register(&self) -> &[bool] {
+        &self.pending
+    }
+
+    fn shift(&mut self) -> Result<Vec<bool>, InterfaceError> {
+        let mut data = self.pending.clone();
+        self.tap.read_write_dr(&mut data, true, false, false)?;
+        Ok(data)
+    }
+
+    fn pin_states(&self, capture: &[bool]) -> PinStates {
+        let mut cells: Vec<_> = self
+            .cells
+            .iter()
+            .filter_map(|x| Some((x.function.capture_priority()?, x.port.as_ref()?, x.number)))
+            .collect();
+        cells.sort();
+        let levels = cells
+            .into_iter()
+            .map(|(_, port, number)| (port.clone(), capture[number]))
+            .collect();
+        PinStates { levels }
+    }
+
+    // SAMPLE/PRELOADでpinを読み、同時にpendingをpreloadする
+    // EXTEST中はpinを駆動したままcaptureする
+    pub fn sample_all(&mut self) -> Result<PinStates, InterfaceError> {
+        if !self.extest_loaded {
+            self.tap.write_instruction(self.sample)?;
+        }
+        let capture = self.shift()?;
+        Ok(self.pin_states(&capture))
+    }
+
+    // 出力cellの値を設定し、3-stateならcontrol cellで出力を有効にする
+    // 反映はapplyで行う
+    pub fn set_pin(&mut self, name: &str, level: bool) -> Result<(), BoundaryScanError> {
+        let cell = self
+            .cells
+            .iter()
+            .find(|x| x.function.is_output() && x.port.as_deref() == Some(name))
+            .ok_or_else(|| BoundaryScanError::NoOutputCell(name.to_string()))?;
+        debug!("set pin {} (cell {}) to {}", name, cell.number, level);
+        self.pending[cell.number] = level;
+        if let Some((control, disable)) = cell.control {
+            self.pending[control] = !disable;
+        }
+        Ok(())
+    }
+
+    // 初回は同じ値をpreloadしてからEXTESTに入るので、切り替えの瞬間に不定値が出ない
+    pub fn apply(&mut self) -> Result<(), InterfaceError> {
+        if !self.extest_loaded {
+            self.tap.write_instruction(self.sample)?;
+            self.shift()?;
+            self.tap.write_instruction(self.extest)?;
+            self.extest_loaded = true;
+            warn!("EXTEST loaded: pins are driven by the boundary register");
+        }
+        self.shift()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::mock::MockInterface;
+    use crate::jtag::jtag::tests::initialized;
+    use crate::jtag::jtag::Jtag;
+    use spin::mutex::Mutex;
+
+    // 4bit IR、6cellの架空のdevice
+    // cellは番号順に並んでいない
+    const BSDL: &str = r#"
+entity FAKE is
+  attribute INSTRUCTION_LENGTH of FAKE : entity is 4;
+  attribute INSTRUCTION_OPCODE of FAKE : entity is
+    "EXTEST (0000)," &
+    "SAMPLE (0001, 0101)," &  -- 2つ目は無視する
+    "IDCODE (1110)," &
+    "BYPASS (1111)";
+  attribute BOUNDARY_LENGTH of FAKE : entity is 6;
+  attribute BOUNDARY_REGISTER of FAKE : entity is
+  -- num  cell  port  function  safe ccell disval rslt
+    "5 (BC_1, *,   control, 1)," &
+    "4 (BC_1, LED, output3, X, 5, 1, Z)," &
+    "3 (BC_1, BTN, input,   X)," &
+    "2 (BC_7, IO,  bidir,   0, 0, 1, Z)," &
+    "1 (BC_1, IO,  input,   X)," &
+    "0 (BC_1, *,   control, 1)";
+end FAKE;
+"#;
+
+    fn bits(value: u64, len: usize) -> Vec<bool> {
+        crate::jtag::bits::to_bools_lsb_first(value, len).collect()
+    }
+
+    // IRとDRのshiftで送ったTDIを返す
+    fn shifts(jtag: &Mutex<Jtag<MockInterface>>) -> Vec<Vec<bool>> {
+        let shifts = jtag.lock().interface.tdi_sequence();
+        jtag.lock().interface.clear();
+        shifts
+    }
+
+    #[test]
+    fn parse_test() {
+        let bsdl = Bsdl::parse(BSDL).unwrap();
+        assert_eq!(6, bsdl.boundary_length);
+        assert_eq!(Some(0b0000), bsdl.opcode("EXTEST"));
+        assert_eq!(Some(0b0001), bsdl.opcode("sample"));
+        assert_eq!(None, bsdl.opcode("PRELOAD"));
+        let numbers: Vec<_> = bsdl.cells.iter().map(|x| x.number).collect();
+        assert_eq!(vec![0, 1, 2, 3, 4, 5], numbers);
+        assert_eq!(
+            BoundaryCell {
+                number: 4,
+                port: Some("LED".to_string()),
+                function: CellFunction::Output3,
+                safe: None,
+                control: Some((5, true)),
+            },
+            bsdl.cells[4]
+        );
+        assert_eq!(None, bsdl.cells[0].port);
+
+        let broken = BSDL.replace(
+            "\"0 (BC_1, *,   control, 1)\"",
+            "\"6 (BC_1, *, control, 1)\"",
+        );
+        assert_eq!(Err(BoundaryScanError::MissingCell(0)), Bsdl::parse(&broken));
+    }
+
+    #[test]
+    fn boundary_scan_test() {
+        let bsdl = Bsdl::parse(BSDL).unwrap();
+        let jtag = Mutex::new(initialized(MockInterface::new()));
+        jtag.lock().interface.clear();
+        let tap = TAP::new(&jtag, 4);
+        let mut chain = BoundaryScanChain::new(tap, &bsdl).unwrap();
+        // safe bitで埋め、Xは0にする
+        assert_eq!(
+            &[true, false, false, false, false, true],
+            chain.boundary_register()
+        );
+
+        // cell 3(BTN)と1(IO)が1
+        let reads = jtag.lock().interface.reads();
+        jtag.lock()
+            .interface
+            .script_read(reads + 1, &bits(0b001010, 6));
+        let pins = chain.sample_all().unwrap();
+        assert_eq!(Some(true), pins.get("BTN"));
+        assert_eq!(Some(true), pins.get("IO"));
+        assert_eq!(Some(false), pins.get("LED"));
+        assert_eq!(3, pins.iter().count());
+        assert_eq!(vec![bits(0b0001, 4), bits(0b100001, 6)], shifts(&jtag));
+
+        // LEDの3-stateを解除し、IOは0を出す
+        chain.set_pin("LED", true).unwrap();
+        chain.set_pin("IO", false).unwrap();
+        assert_eq!(
+            Err(BoundaryScanError::NoOutputCell("BTN".to_string())),
+            chain.set_pin("BTN", true)
+        );
+        assert!(chain.set_pin("NC", true).is_err());
+        let register = bits(0b010000, 6);
+        assert_eq!(register.as_slice(), chain.boundary_register());
+
+        // SAMPLEでpreloadしてからEXTEST
+        chain.apply().unwrap();
+        assert_eq!(
+            vec![
+                bits(0b0001, 4),
+                register.clone(),
+                bits(0b0000, 4),
+                register.clone()
+            ],
+            shifts(&jtag)
+        );
+        // EXTEST中はIRを書き換えない
+        chain.apply().unwrap();
+        chain.sample_all().unwrap();
+        assert_eq!(vec![register.clone(), register], shifts(&jtag));
+    }
+}
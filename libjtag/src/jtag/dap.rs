@@ -1,9 +1,36 @@
 use bitfield::{bitfield, bitfield_bitrange, bitfield_fields};
 use bitflags::bitflags;
+use core::fmt;
+#[cfg(feature = "alloc")]
+use core::ops::{Deref, DerefMut};
 use log::{debug, error, info, warn};
 
-use crate::interface::JtagInterface;
-use crate::jtag::jtag::TAP;
+#[cfg(feature = "alloc")]
+use crate::tools::memview::MemView;
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use crate::config::TargetConfig;
+pub use crate::error::DapError;
+use crate::interface::{InterfaceError, JtagInterface};
+use crate::jtag::jtag::{Jtag, TAP};
+use crate::jtag::stats::{DapStats, Timer};
+#[cfg(feature = "defmt")]
+use crate::regfmt::RegDefmt;
+use crate::regfmt::RegFmt;
+
+pub mod handle;
+pub mod jtag_ap;
+pub mod write_guard;
+pub use handle::{DapGuard, DapHandle};
+pub use write_guard::AddrRange;
+#[cfg(feature = "alloc")]
+pub use write_guard::WriteGuard;
+
+// DPACC/APACCのDR長
+const ACC_DR_LEN: usize = 35;
+const ACC_DR_BYTES: usize = ACC_DR_LEN.div_ceil(8);
 
 enum Instruction {
     ABORT = 0b1000,
@@ -28,7 +55,99 @@ impl Into<u8> for DpAddress {
     }
 }
 
+// CTRLSTATの位置(A[3:2]=0b01)にDPBANKSELで切り替わって見えるregister
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DpBank {
+    CtrlStat = 0,
+    // DPv1
+    Dlcr = 1,
+    // DPv2
+    TargetId = 2,
+    Dlpidr = 3,
+    EventStat = 4,
+}
+
+impl DpBank {
+    // そのregisterを持つ最初のDPIDR.VERSION
+    pub fn min_version(&self) -> u32 {
+        match self {
+            DpBank::CtrlStat => 0,
+            DpBank::Dlcr => 1,
+            _ => 2,
+        }
+    }
+}
+
+bitfield! {
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub struct TargetId(u32);
+    impl Debug;
+    pub TREVISION, _: 31, 28;
+    pub TPARTNO, _: 27, 12;
+    pub TDESIGNER, _: 11, 1;
+    pub RAO, _: 0, 0;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub struct Dlpidr(u32);
+    impl Debug;
+    pub TINSTANCE, _: 31, 28;
+    reserved, _: 27, 4;
+    pub PROTVSN, _: 3, 0;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub struct EventStat(u32);
+    impl Debug;
+    // 0ならeventが起きている
+    pub EA, _: 0, 0;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub struct TargetSel(u32);
+    impl Debug;
+    pub TINSTANCE, set_TINSTANCE: 31, 28;
+    pub TPARTNO, set_TPARTNO: 27, 12;
+    pub TDESIGNER, set_TDESIGNER: 11, 1;
+    pub SBO, set_SBO: 0, 0;
+}
+
+impl TargetSel {
+    // TARGETIDとDLPIDR.TINSTANCEで、multidropのどのtargetかを選ぶ
+    pub fn new(targetid: TargetId, instance: u32) -> Self {
+        let mut targetsel = TargetSel(0);
+        targetsel.set_TINSTANCE(instance);
+        targetsel.set_TPARTNO(targetid.TPARTNO());
+        targetsel.set_TDESIGNER(targetid.TDESIGNER());
+        targetsel.set_SBO(1);
+        targetsel
+    }
+}
+
+impl fmt::Display for TargetId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        RegFmt::new(f, "TARGETID")
+            .field("TREVISION", format_args!("{}", self.TREVISION()))
+            .field("TPARTNO", format_args!("{:#06x}", self.TPARTNO()))
+            .field("TDESIGNER", format_args!("{:#05x}", self.TDESIGNER()))
+            .finish()
+    }
+}
+
+impl fmt::Display for Dlpidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        RegFmt::new(f, "DLPIDR")
+            .field("TINSTANCE", format_args!("{}", self.TINSTANCE()))
+            .field("PROTVSN", format_args!("{}", self.PROTVSN()))
+            .finish()
+    }
+}
+
 bitfield! {
+    #[derive(Clone, Copy, PartialEq, Eq)]
     pub struct DpSelect(u32);
     impl Debug;
     pub apsel, set_apsel: 31, 24;
@@ -38,6 +157,7 @@ bitfield! {
 }
 
 bitfield! {
+    #[derive(Clone, Copy, PartialEq)]
     pub struct CtrlStatus(u32);
     impl Debug;
     pub CSYSPWRUPACK, _: 31, 31;
@@ -58,6 +178,38 @@ bitfield! {
     pub ORUNDETECT, _: 0,0;
 }
 
+// transactionの失敗を示すsticky flag。STICKYCMPはpushed compareの結果なので含めない
+const CTRLSTAT_STICKY_ERRORS: u32 = (1 << 7) | (1 << 5) | (1 << 1);
+
+impl CtrlStatus {
+    pub fn sticky_errors(&self) -> CtrlStatus {
+        CtrlStatus(self.0 & CTRLSTAT_STICKY_ERRORS)
+    }
+}
+
+bitfield! {
+    #[derive(Clone, Copy)]
+    pub struct Abort(u32);
+    impl Debug;
+    pub ORUNERRCLR, set_ORUNERRCLR: 4, 4;
+    pub WDERRCLR, set_WDERRCLR: 3, 3;
+    pub STKERRCLR, set_STKERRCLR: 2, 2;
+    pub STKCMPCLR, set_STKCMPCLR: 1, 1;
+    pub DAPABORT, set_DAPABORT: 0, 0;
+}
+
+impl Abort {
+    // CTRL/STATで立っているsticky flagを落とす値
+    pub fn clearing(sticky: CtrlStatus) -> Self {
+        let mut abort = Abort(0);
+        abort.set_ORUNERRCLR(sticky.STICKYORUN());
+        abort.set_WDERRCLR(sticky.WDATAERR());
+        abort.set_STKERRCLR(sticky.STICKYERR());
+        abort.set_STKCMPCLR(sticky.STICKYCMP());
+        abort
+    }
+}
+
 bitfield! {
     pub struct CSW(u32);
     impl Debug;
@@ -67,12 +219,194 @@ bitfield! {
     pub SPDIN, _: 23,23;
     reserved1, _: 22,16;
     pub Type, _: 15,12;
-    pub Mode, _: 11,8;
+    pub Mode, set_Mode: 11,8;
     pub TrInProg, _: 7,7;
     pub DeviceEn, _: 6,6;
-    pub AddrInc, _: 5,4;
+    pub AddrInc, set_AddrInc: 5,4;
     reserved0, _: 3,3;
-    pub SIZE, _: 2,0;
+    pub SIZE, set_SIZE: 2,0;
+}
+
+// CSW.SIZE
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CswSize {
+    Byte = 0b000,
+    Halfword = 0b001,
+    Word = 0b010,
+    Doubleword = 0b011,
+    Bits128 = 0b100,
+    Bits256 = 0b101,
+}
+
+impl CswSize {
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            0b000 => Some(CswSize::Byte),
+            0b001 => Some(CswSize::Halfword),
+            0b010 => Some(CswSize::Word),
+            0b011 => Some(CswSize::Doubleword),
+            0b100 => Some(CswSize::Bits128),
+            0b101 => Some(CswSize::Bits256),
+            _ => None,
+        }
+    }
+}
+
+// CSW.AddrInc
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CswAddrInc {
+    Off = 0b00,
+    Single = 0b01,
+    Packed = 0b10,
+}
+
+impl CswAddrInc {
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            0b00 => Some(CswAddrInc::Off),
+            0b01 => Some(CswAddrInc::Single),
+            0b10 => Some(CswAddrInc::Packed),
+            _ => None,
+        }
+    }
+}
+
+// reservedの値はNone
+impl CSW {
+    pub fn size(&self) -> Option<CswSize> {
+        CswSize::from_bits(self.SIZE())
+    }
+    pub fn addr_inc(&self) -> Option<CswAddrInc> {
+        CswAddrInc::from_bits(self.AddrInc())
+    }
+}
+
+impl fmt::Display for DpSelect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        RegFmt::new(f, "SELECT")
+            .field("APSEL", format_args!("{:#04x}", self.apsel()))
+            .field("APBANKSEL", format_args!("{:#x}", self.apbanksel()))
+            .field("DPBANKSEL", format_args!("{:#x}", self.dpbanksel()))
+            .finish()
+    }
+}
+
+impl fmt::Display for CtrlStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        RegFmt::new(f, "CTRL/STAT")
+            .flag("CSYSPWRUPACK", self.CSYSPWRUPACK())
+            .flag("CSYSPWRUPREQ", self.CSYSPWRUPREQ())
+            .flag("CDBGPWRUPACK", self.CDBGPWRUPACK())
+            .flag("CDBGPWRUPREQ", self.CDBGPWRUPREQ())
+            .flag("CDBGRSTACK", self.CDBGRSTACK())
+            .flag("CDBGRSTREQ", self.CDBGRSTREQ())
+            .nonzero("TRNCNT", self.TRNCNT(), format_args!("{}", self.TRNCNT()))
+            .nonzero(
+                "MASKLANE",
+                self.MASKLANE(),
+                format_args!("{:#06b}", self.MASKLANE()),
+            )
+            .flag("WDATAERR", self.WDATAERR())
+            .flag("READOK", self.READOK())
+            .flag("STICKYERR", self.STICKYERR())
+            .flag("STICKYCMP", self.STICKYCMP())
+            .nonzero(
+                "TRNMODE",
+                self.TRNMODE(),
+                format_args!("{:#04b}", self.TRNMODE()),
+            )
+            .flag("STICKYORUN", self.STICKYORUN())
+            .flag("ORUNDETECT", self.ORUNDETECT())
+            .finish()
+    }
+}
+
+impl fmt::Display for CSW {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut reg = RegFmt::new(f, "CSW");
+        reg.flag("DbgSwEnable", self.DbgSwEnable())
+            .nonzero("PROT", self.PROT(), format_args!("{:#05b}", self.PROT()))
+            .nonzero("CACHE", self.CACHE(), format_args!("{:#06b}", self.CACHE()))
+            .flag("SPIDEN", self.SPDIN())
+            .nonzero("Type", self.Type(), format_args!("{:#x}", self.Type()))
+            .nonzero("Mode", self.Mode(), format_args!("{:#x}", self.Mode()))
+            .flag("TrInProg", self.TrInProg())
+            .flag("DeviceEn", self.DeviceEn());
+        match self.addr_inc() {
+            Some(x) => reg.field("AddrInc", format_args!("{:?}", x)),
+            None => reg.field("AddrInc", format_args!("{:#04b}", self.AddrInc())),
+        };
+        match self.size() {
+            Some(x) => reg.field("Size", format_args!("{:?}", x)),
+            None => reg.field("Size", format_args!("{:#05b}", self.SIZE())),
+        };
+        reg.finish()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for CtrlStatus {
+    fn format(&self, f: defmt::Formatter) {
+        RegDefmt::new(f, "CTRL/STAT")
+            .flag("CSYSPWRUPACK", self.CSYSPWRUPACK())
+            .flag("CSYSPWRUPREQ", self.CSYSPWRUPREQ())
+            .flag("CDBGPWRUPACK", self.CDBGPWRUPACK())
+            .flag("CDBGPWRUPREQ", self.CDBGPWRUPREQ())
+            .flag("CDBGRSTACK", self.CDBGRSTACK())
+            .flag("CDBGRSTREQ", self.CDBGRSTREQ())
+            .nonzero("TRNCNT", self.TRNCNT(), |f| {
+                defmt::write!(f, "{=u32}", self.TRNCNT())
+            })
+            .nonzero("MASKLANE", self.MASKLANE(), |f| {
+                defmt::write!(f, "{=u32:#06b}", self.MASKLANE())
+            })
+            .flag("WDATAERR", self.WDATAERR())
+            .flag("READOK", self.READOK())
+            .flag("STICKYERR", self.STICKYERR())
+            .flag("STICKYCMP", self.STICKYCMP())
+            .nonzero("TRNMODE", self.TRNMODE(), |f| {
+                defmt::write!(f, "{=u32:#04b}", self.TRNMODE())
+            })
+            .flag("STICKYORUN", self.STICKYORUN())
+            .flag("ORUNDETECT", self.ORUNDETECT())
+            .finish()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for CSW {
+    fn format(&self, f: defmt::Formatter) {
+        let mut reg = RegDefmt::new(f, "CSW");
+        reg.flag("DbgSwEnable", self.DbgSwEnable())
+            .nonzero("PROT", self.PROT(), |f| {
+                defmt::write!(f, "{=u32:#05b}", self.PROT())
+            })
+            .nonzero("CACHE", self.CACHE(), |f| {
+                defmt::write!(f, "{=u32:#06b}", self.CACHE())
+            })
+            .flag("SPIDEN", self.SPDIN())
+            .nonzero("Type", self.Type(), |f| {
+                defmt::write!(f, "{=u32:#x}", self.Type())
+            })
+            .nonzero("Mode", self.Mode(), |f| {
+                defmt::write!(f, "{=u32:#x}", self.Mode())
+            })
+            .flag("TrInProg", self.TrInProg())
+            .flag("DeviceEn", self.DeviceEn());
+        match self.addr_inc() {
+            Some(x) => reg.field("AddrInc", |f| defmt::write!(f, "{}", x)),
+            None => reg.field("AddrInc", |f| {
+                defmt::write!(f, "{=u32:#04b}", self.AddrInc())
+            }),
+        };
+        match self.size() {
+            Some(x) => reg.field("Size", |f| defmt::write!(f, "{}", x)),
+            None => reg.field("Size", |f| defmt::write!(f, "{=u32:#05b}", self.SIZE())),
+        };
+        reg.finish()
+    }
 }
 
 #[derive(Debug)]
@@ -93,6 +427,7 @@ impl From<u8> for DapAck {
 }
 
 bitfield! {
+    #[derive(Clone, Copy)]
     pub struct PdIdr(u32);
     impl Debug;
     pub REVISION, _: 31, 28;
@@ -104,6 +439,111 @@ bitfield! {
     pub RAO, _: 0, 0;
 }
 
+impl fmt::Display for PdIdr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        RegFmt::new(f, "DPIDR")
+            .field("REVISION", format_args!("{}", self.REVISION()))
+            .field("PARTNO", format_args!("{:#04x}", self.PARTNO()))
+            .flag("MIN", self.MIN())
+            .field("VERSION", format_args!("{}", self.VERSION()))
+            .field("DESIGNER", format_args!("{:#05x}", self.DESIGNER()))
+            .finish()
+    }
+}
+
+bitfield! {
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub struct ApIdr(u32);
+    impl Debug;
+    pub REVISION, _: 31, 28;
+    pub DESIGNER, _: 27, 17;
+    pub CLASS, _: 16, 13;
+    reserved, _: 12, 8;
+    pub VARIANT, _: 7, 4;
+    pub TYPE, _: 3, 0;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub struct MemApCfg(u32);
+    impl Debug;
+    reserved, _: 31, 3;
+    // 64bitより大きいdataのaccess
+    pub LD, _: 2, 2;
+    // TARhiなど64bit addressのregister
+    pub LA, _: 1, 1;
+    // ADIv5のbig-endianのMEM-AP
+    pub BE, _: 0, 0;
+}
+
+impl fmt::Display for MemApCfg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        RegFmt::new(f, "CFG")
+            .flag("LD", self.LD())
+            .flag("LA", self.LA())
+            .flag("BE", self.BE())
+            .finish()
+    }
+}
+
+// IDR.CLASS
+const AP_CLASS_JTAG_AP: u32 = 0b0000;
+const AP_CLASS_MEM_AP: u32 = 0b1000;
+
+// MEM-APの先にあるbus
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemApBus {
+    Ahb,
+    Apb,
+    Axi,
+}
+
+// IDRから判別したAPの種類
+// 同じdesignerでもbusが違えば用途が違う(APB-APはdebug register、AHB/AXI-APはsystem memory)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApInfo {
+    MemAp { bus: MemApBus, designer: u32 },
+    JtagAp { designer: u32 },
+    Unknown(ApIdr),
+}
+
+impl From<ApIdr> for ApInfo {
+    fn from(idr: ApIdr) -> Self {
+        let designer = idr.DESIGNER();
+        let bus = match idr.TYPE() {
+            // AHB3, AHB5, AHB5 with enhanced HPROT
+            0x1 | 0x5 | 0x8 => Some(MemApBus::Ahb),
+            // APB2/3, APB4/5
+            0x2 | 0x6 => Some(MemApBus::Apb),
+            // AXI3/4, AXI5
+            0x4 | 0x7 => Some(MemApBus::Axi),
+            _ => None,
+        };
+        match (idr.CLASS(), bus, idr.TYPE()) {
+            (AP_CLASS_MEM_AP, Some(bus), _) => ApInfo::MemAp { bus, designer },
+            (AP_CLASS_JTAG_AP, _, 0) => ApInfo::JtagAp { designer },
+            _ => ApInfo::Unknown(idr),
+        }
+    }
+}
+
+// DAP::newで設定するWAITの再試行回数
+pub const DEFAULT_MAX_WAIT_RETRIES: usize = 64;
+// CTRL/STATのpower-up ACKを待つ回数
+const POWERUP_POLL_MAX: usize = 1000;
+// MIN_IDLE_CYCLES_AFTER_POWERUPの時にpower-up後に入れるidle
+pub const POWERUP_IDLE_CYCLES: usize = 100;
+// ABORT.DAPABORT
+const ABORT_DAPABORT: u32 = 1 << 0;
+
+// TARの自動インクリメントは1KB境界を越えることが保証されない
+const MEMAP_AUTOINC_BOUNDARY: u64 = 0x400;
+// mem_fillでCTRL/STATのsticky flagを確認する間隔(DRWへの書き込み回数)
+const MEM_FILL_CHECK_INTERVAL: usize = 64;
+// mem_compareで1度にpipelineで読むword数
+const MEM_COMPARE_CHUNK_WORDS: usize = 64;
+
+#[derive(Clone, Copy)]
 pub enum MemapAddress {
     CSW = 0x00,
     TARlo = 0x04,
@@ -120,327 +560,2571 @@ pub enum MemapAddress {
     IDR = 0xFC,
 }
 
+bitflags! {
+    // SoC毎のDAPの癖に対するworkaround
+    #[derive(Default)]
+    pub struct QuirkSet: u32 {
+        // SELECT書き込み後にRDBUFFをもう1回読まないとSELECTが反映されない
+        const EXTRA_READ_AFTER_SELECT = 1 << 0;
+        // ABORTを2回書かないとstickyフラグがクリアされない
+        const DOUBLE_ABORT_WRITE = 1 << 1;
+        // IDRのbank(0xF)を読むとAPBANKSELが0に戻ってしまう
+        const IDR_BANK_QUIRK = 1 << 2;
+        // power-upのACKの後、POWERUP_IDLE_CYCLESだけidleしないとAPへのaccessが無視される
+        const MIN_IDLE_CYCLES_AFTER_POWERUP = 1 << 3;
+    }
+}
+
+pub struct QuirkEntry {
+    pub idcode: u32,
+    pub mask: u32,
+    pub quirks: QuirkSet,
+}
+
+// IDCODE(DPIDR)とquirkの対応表
+// DPIDRはDPのIPで決まり、SoCのvendorでは区別できないので、
+// 同じDPIDRの他のSoCで有効になっても害の無いquirkだけを載せること
+// 実機で確認できたものだけを足す。未確認のものはTargetConfigのquirksで指定する
+const QUIRK_TABLE: &[QuirkEntry] = &[];
+
+impl QuirkSet {
+    pub fn lookup(table: &[QuirkEntry], idcode: u32) -> QuirkSet {
+        table
+            .iter()
+            .filter(|x| (idcode & x.mask) == (x.idcode & x.mask))
+            .fold(QuirkSet::empty(), |x, y| x | y.quirks)
+    }
+
+    pub fn from_idcode(idcode: u32) -> QuirkSet {
+        Self::lookup(QUIRK_TABLE, idcode)
+    }
+
+    // TargetConfigのquirksで使う名前
+    pub fn from_name(name: &str) -> Option<QuirkSet> {
+        match name {
+            "extra_read_after_select" => Some(QuirkSet::EXTRA_READ_AFTER_SELECT),
+            "double_abort_write" => Some(QuirkSet::DOUBLE_ABORT_WRITE),
+            "idr_bank_quirk" => Some(QuirkSet::IDR_BANK_QUIRK),
+            "min_idle_cycles_after_powerup" => Some(QuirkSet::MIN_IDLE_CYCLES_AFTER_POWERUP),
+            _ => None,
+        }
+    }
+}
+
 pub trait DapInterface {
-    fn apacc(&mut self, data: u32, a: u8, RnW: bool) -> (u8, u32);
-    fn dpacc(&mut self, data: u32, a: u8, RnW: bool) -> (u8, u32);
+    fn apacc(&mut self, data: u32, a: u8, RnW: bool) -> Result<(u8, u32), InterfaceError>;
+    fn dpacc(&mut self, data: u32, a: u8, RnW: bool) -> Result<(u8, u32), InterfaceError>;
+    // linkが生きているかIDCODEで確かめる。できないDPはUnsupported
+    fn ping(&mut self) -> Result<u32, DapError> {
+        Err(InterfaceError::Unsupported.into())
+    }
+    // SWDのmultidropでline resetの直後に書く。ACKは返らない
+    // JTAG-DPにはTARGETSELがないのでUnsupported
+    fn targetsel(&mut self, _targetsel: u32) -> Result<(), InterfaceError> {
+        Err(InterfaceError::Unsupported)
+    }
+    // Run-Test/Idleでcycles回clockを入れる。JTAG以外のDPはUnsupported
+    fn idle(&mut self, _cycles: usize) -> Result<(), InterfaceError> {
+        Err(InterfaceError::Unsupported)
+    }
+    // ABORT registerに書く。SWDではDPの0番地へのwrite
+    // JTAG-DPではDPACCから書けないので、ABORT命令を使う
+    fn abort(&mut self, data: u32) -> Result<(), InterfaceError> {
+        self.dpacc(data, DpAddress::PDIDR_ABORT.into(), false)
+            .map(|_| ())
+    }
+}
+
+// Jtag::pingで読んだIDCODEがinitialize時にscanした値と同じか確かめる
+pub fn check_idcode<T: JtagInterface>(jtag: &mut Jtag<T>) -> Result<u32, DapError> {
+    let found = jtag.ping()?;
+    match jtag.scanned_idcode() {
+        Some(expected) if expected != found => {
+            warn!("ping: IDCODE {:#010x}, expected {:#010x}", found, expected);
+            Err(DapError::IdcodeMismatch { expected, found })
+        }
+        _ => Ok(found),
+    }
 }
 
 struct SWD;
 impl DapInterface for SWD {
-    fn apacc(&mut self, data: u32, a: u8, RnW: bool) -> (u8, u32) {
-        todo!();
+    fn apacc(&mut self, data: u32, a: u8, RnW: bool) -> Result<(u8, u32), InterfaceError> {
+        Err(InterfaceError::Unsupported)
     }
-    fn dpacc(&mut self, data: u32, a: u8, RnW: bool) -> (u8, u32) {
-        todo!();
+    fn dpacc(&mut self, data: u32, a: u8, RnW: bool) -> Result<(u8, u32), InterfaceError> {
+        Err(InterfaceError::Unsupported)
     }
 }
 
 impl<'a, T: JtagInterface> DapInterface for TAP<'a, T> {
-    fn apacc(&mut self, data: u32, a: u8, RnW: bool) -> (u8, u32) {
-        self.write_instruction(Instruction::APACC as u8);
+    fn apacc(&mut self, data: u32, a: u8, RnW: bool) -> Result<(u8, u32), InterfaceError> {
         debug!(
             "apacc: {} {:#08x} to {:?}",
             if RnW { "Read" } else { "Write" },
             data,
             a
         );
-        self.acc(data, a, RnW)
+        self.scan_acc(Instruction::APACC as u8, data, a, RnW)
     }
-    fn dpacc(&mut self, data: u32, a: u8, RnW: bool) -> (u8, u32) {
-        self.write_instruction(Instruction::DPACC as u8);
+    fn dpacc(&mut self, data: u32, a: u8, RnW: bool) -> Result<(u8, u32), InterfaceError> {
         debug!(
             "dpacc: {} {:#08x} to {:?}",
             if RnW { "Read" } else { "Write" },
             data,
             a
         );
-        self.acc(data, a as u8, RnW)
+        self.scan_acc(Instruction::DPACC as u8, data, a, RnW)
+    }
+    // 命令はcacheしていないので、次のdpacc/apaccがIRを書き直す
+    fn ping(&mut self) -> Result<u32, DapError> {
+        check_idcode(&mut self.jtag.lock())
+    }
+    fn idle(&mut self, cycles: usize) -> Result<(), InterfaceError> {
+        self.jtag.lock().idle_cycles(cycles)
+    }
+    // ABORTのscan chainはDPACCと同じ35bitで、A=0, RnW=0。ACKは意味を持たない
+    fn abort(&mut self, data: u32) -> Result<(), InterfaceError> {
+        debug!("abort: {:#08x}", data);
+        self.write_instruction(Instruction::ABORT as u8)?;
+        self.acc(data, DpAddress::PDIDR_ABORT.into(), false)
+            .map(|_| ())
     }
 }
 
 impl<'a, T: JtagInterface> TAP<'a, T> {
-    fn acc(&mut self, data: u32, a: u8, RnW: bool) -> (u8, u32) {
-        // create apacc_data
-        let mut apacc_data = [false; 35];
-        apacc_data[0] = RnW;
-        apacc_data[1] = (a & 1) != 0;
-        apacc_data[2] = (a & 0b10) != 0;
-        let mut tmp = data;
-        for i in 0..32 {
-            apacc_data[3 + i] = (tmp & 1) != 0;
-            tmp = tmp >> 1;
-        }
-        // read write DR
-        self.read_write_dr(&mut apacc_data, true, false, false);
-        // collect
-        let ack = apacc_data[0..3].iter().fold(0, |x, y| (x << 1) | *y as u8);
-        apacc_data.reverse();
-        let result = apacc_data[0..32]
-            .iter()
-            .fold(0, |x, y| (x << 1) | *y as u32);
+    // Jtag::auto_resync_on_errorなら、不正なACKを受けた時にTAPをresyncして1度だけやり直す
+    fn scan_acc(
+        &mut self,
+        instruction: u8,
+        data: u32,
+        a: u8,
+        RnW: bool,
+    ) -> Result<(u8, u32), InterfaceError> {
+        self.write_instruction(instruction)?;
+        let (ack, result) = self.acc(data, a, RnW)?;
+        if !matches!(DapAck::from(ack), DapAck::InvalidAck)
+            || !self.jtag.lock().auto_resync_on_error()
+        {
+            return Ok((ack, result));
+        }
+        warn!("invalid ACK {:#05b}, resyncing TAP and retrying", ack);
+        self.jtag.lock().resync()?;
+        self.write_instruction(instruction)?;
+        self.acc(data, a, RnW)
+    }
+
+    fn acc(&mut self, data: u32, a: u8, RnW: bool) -> Result<(u8, u32), InterfaceError> {
+        // [0]: RnW, [2:1]: A[3:2], [34:3]: data
+        // captureでは[2:0]にACK、[34:3]に前回のreadの結果が入る
+        let request = RnW as u64 | ((a & 3) as u64) << 1 | (data as u64) << 3;
+        let mut apacc_data = [0u8; ACC_DR_BYTES];
+        apacc_data.copy_from_slice(&request.to_le_bytes()[..ACC_DR_BYTES]);
+        self.read_write_dr_packed(&mut apacc_data, ACC_DR_LEN, true)?;
+        let mut response = [0u8; 8];
+        response[..ACC_DR_BYTES].copy_from_slice(&apacc_data);
+        let response = u64::from_le_bytes(response);
+        let ack = (response & 0b111) as u8;
+        let result = (response >> 3) as u32;
 
         debug!(
             "acc debug: data: {:#x}, a: {:#x}, RnW: {:?}, ack: {:#x}, result: {:#x}",
             data, a, RnW, ack, result
         );
 
-        (ack, result)
-    }
-    pub fn abort(&mut self) {
-        self.write_instruction(Instruction::ABORT as u8);
-        let mut buffer = [false; 32];
-        buffer[0] = true;
-        self.read_write_dr(&mut buffer, true, false, true);
+        Ok((ack, result))
     }
 }
 
 pub trait DebugPort: DapInterface {
+    fn quirks(&self) -> QuirkSet {
+        QuirkSet::empty()
+    }
+    fn max_wait_retries(&self) -> usize {
+        0
+    }
+    // 最後に書いたSELECT。Noneならtargetの値が分からないので必ず書く
+    fn cached_select(&self) -> Option<DpSelect> {
+        None
+    }
+    fn set_cached_select(&mut self, _select: Option<DpSelect>) {}
+    // trueならtry_系の関数でAPのtransactionの後にCTRL/STATを確認する
+    fn check_faults(&self) -> bool {
+        false
+    }
+    // trueならInvalidAckをLinkLostとして返す
+    fn link_lost(&self) -> bool {
+        false
+    }
+    // probeで読んだDPIDR。まだ読んでいなければNone
+    fn cached_dpidr(&self) -> Option<PdIdr> {
+        None
+    }
+
+    // ACKがWAITの場合は同じtransactionを再発行する
+    // 再試行回数を使い切った場合はABORTしてDapError::WaitTimeoutを返す
+    fn acc_retry(
+        &mut self,
+        ap: bool,
+        data: u32,
+        a: u8,
+        read: bool,
+    ) -> Result<(DapAck, u32), DapError> {
+        let mut retries = 0;
+        loop {
+            let (ack, result) = if ap {
+                self.apacc(data, a, read)?
+            } else {
+                self.dpacc(data, a, read)?
+            };
+            match DapAck::from(ack) {
+                DapAck::Wait if retries < self.max_wait_retries() => retries += 1,
+                DapAck::Wait => {
+                    warn!("WAIT retries exhausted: ap: {}, a: {:#x}", ap, a);
+                    self.set_cached_select(None);
+                    self.abort(ABORT_DAPABORT)?;
+                    return Err(DapError::WaitTimeout);
+                }
+                ack => return Ok((ack, result)),
+            }
+        }
+    }
+
     // DPレジスタアクセス関数
-    fn dp_abort_write(&mut self) -> DapAck {
-        self.dpacc(0, DpAddress::PDIDR_ABORT.into(), false);
-        let (ack, _) = self.dp_rdbuff_read();
-        ack
+    // DPIDRはreadの時だけABORTと同じaddressに見える
+    fn dp_dpidr_read(&mut self) -> Result<(DapAck, PdIdr), DapError> {
+        self.acc_retry(false, 0, DpAddress::PDIDR_ABORT.into(), true)?;
+        let (ack, result) = self.dp_rdbuff_read()?;
+        Ok((ack, PdIdr(result)))
+    }
+
+    fn dp_abort_write(&mut self) -> Result<DapAck, DapError> {
+        self.dp_abort(Abort(0))
+    }
+
+    fn dp_abort(&mut self, abort: Abort) -> Result<DapAck, DapError> {
+        self.set_cached_select(None);
+        self.abort(abort.0)?;
+        if self.quirks().contains(QuirkSet::DOUBLE_ABORT_WRITE) {
+            self.abort(abort.0)?;
+        }
+        let (ack, _) = self.dp_rdbuff_read()?;
+        Ok(ack)
+    }
+
+    // ACKをResultにする。apならcheck_faultsに従ってsticky flagも確認する
+    fn check_ack(&mut self, ack: DapAck, ap: bool) -> Result<(), DapError> {
+        match ack {
+            DapAck::OkFault => (),
+            DapAck::Wait => return Err(DapError::WaitTimeout),
+            DapAck::InvalidAck if self.link_lost() => return Err(DapError::LinkLost),
+            DapAck::InvalidAck => return Err(DapError::InvalidAck),
+        }
+        if !ap || !self.check_faults() {
+            return Ok(());
+        }
+        self.check_sticky()
+    }
+
+    // sticky flagが立っていればABORTで落としてFaultを返す
+    fn check_sticky(&mut self) -> Result<(), DapError> {
+        let sticky = self.try_dp_ctrlstat_read()?.sticky_errors();
+        if sticky.0 == 0 {
+            return Ok(());
+        }
+        warn!("sticky error: {}", sticky);
+        self.dp_abort(Abort::clearing(sticky))?;
+        Err(DapError::Fault { sticky })
+    }
+
+    fn try_dp_rdbuff_read(&mut self) -> Result<u32, DapError> {
+        let (ack, result) = self.dp_rdbuff_read()?;
+        self.check_ack(ack, false)?;
+        Ok(result)
+    }
+    fn try_dp_ctrlstat_read(&mut self) -> Result<CtrlStatus, DapError> {
+        let (ack, result) = self.dp_ctrlstat_read()?;
+        self.check_ack(ack, false)?;
+        Ok(result)
+    }
+    fn try_dp_ctrlstat_write(&mut self, control: CtrlStatus) -> Result<(), DapError> {
+        let ack = self.dp_ctrlstat_write(control)?;
+        self.check_ack(ack, false)
     }
 
-    fn dp_select_write(&mut self, apsel: u8, apbanksel: u8, dpbanksel: u8) -> DapAck {
+    fn dp_select_write(
+        &mut self,
+        apsel: u8,
+        apbanksel: u8,
+        dpbanksel: u8,
+    ) -> Result<DapAck, DapError> {
         let mut select = DpSelect(0);
         select.set_apsel(apsel as u32);
         select.set_apbanksel((apbanksel & 0x0f) as u32);
         select.set_dpbanksel((dpbanksel & 0x0f) as u32);
+        if self.cached_select() == Some(select) {
+            return Ok(DapAck::OkFault);
+        }
+        self.set_cached_select(None);
 
         // SELECTを実行する
-        self.dpacc(select.0, DpAddress::SELECT.into(), false);
-        // TODO: error check
-        let (ack, _) = self.dp_rdbuff_read();
-        ack
+        self.acc_retry(false, select.0, DpAddress::SELECT.into(), false)?;
+        let (mut ack, _) = self.dp_rdbuff_read()?;
+        if self.quirks().contains(QuirkSet::EXTRA_READ_AFTER_SELECT) {
+            ack = self.dp_rdbuff_read()?.0;
+        }
+        if matches!(ack, DapAck::OkFault) {
+            self.set_cached_select(Some(select));
+        }
+        Ok(ack)
+    }
+
+    fn dp_rdbuff_read(&mut self) -> Result<(DapAck, u32), DapError> {
+        self.acc_retry(false, 0, DpAddress::RDBUFF.into(), true)
     }
 
-    fn dp_rdbuff_read(&mut self) -> (DapAck, u32) {
-        let (ack, data) = self.dpacc(0, DpAddress::RDBUFF.into(), true);
-        (DapAck::from(ack), data)
+    // DPBANKSELを切り替えて読み、CTRL/STATが見えるbank 0に戻す
+    // 他の関数はDPBANKSELが0のままだと仮定している
+    fn dp_banked_read(&mut self, bank: DpBank) -> Result<u32, DapError> {
+        let version = self.cached_dpidr().map_or(0, |x| x.VERSION());
+        if version < bank.min_version() {
+            return Err(DapError::UnsupportedDpVersion {
+                version,
+                required: bank.min_version(),
+            });
+        }
+        // SELECTが分からなければAPSEL/APBANKSELは0にしてしまう
+        let previous = self.cached_select().unwrap_or(DpSelect(0));
+        let (apsel, apbanksel) = (previous.apsel() as u8, previous.apbanksel() as u8);
+        let ack = self.dp_select_write(apsel, apbanksel, bank as u8)?;
+        self.check_ack(ack, false)?;
+        let result = self.dp_ctrlstat_read();
+        // 読めなくてもbankは戻しておく
+        let restored = self.dp_select_write(apsel, apbanksel, 0);
+        let (ack, value) = result?;
+        self.check_ack(ack, false)?;
+        self.check_ack(restored?, false)?;
+        Ok(value.0)
+    }
+    fn dp_targetid_read(&mut self) -> Result<TargetId, DapError> {
+        Ok(TargetId(self.dp_banked_read(DpBank::TargetId)?))
+    }
+    fn dp_dlpidr_read(&mut self) -> Result<Dlpidr, DapError> {
+        Ok(Dlpidr(self.dp_banked_read(DpBank::Dlpidr)?))
+    }
+    fn dp_eventstat_read(&mut self) -> Result<EventStat, DapError> {
+        Ok(EventStat(self.dp_banked_read(DpBank::EventStat)?))
     }
 
-    fn dp_ctrlstat(&mut self, control: CtrlStatus, read: bool) -> (DapAck, CtrlStatus) {
+    // multidropのtargetを選び直すので、SELECTのcacheは捨てる
+    fn dp_targetsel_write(&mut self, targetsel: TargetSel) -> Result<(), DapError> {
+        self.set_cached_select(None);
+        Ok(self.targetsel(targetsel.0)?)
+    }
+
+    fn dp_ctrlstat(
+        &mut self,
+        control: CtrlStatus,
+        read: bool,
+    ) -> Result<(DapAck, CtrlStatus), DapError> {
         // select DPBANKSEL to 0
         // self.dp_select_write(0, 0, 0);
-        self.dpacc(control.0, DpAddress::CTRLSTAT.into(), read);
-        let (ack, result) = self.dp_rdbuff_read();
-        (ack, CtrlStatus(result))
+        self.acc_retry(false, control.0, DpAddress::CTRLSTAT.into(), read)?;
+        let (ack, result) = self.dp_rdbuff_read()?;
+        Ok((ack, CtrlStatus(result)))
     }
-    fn dp_ctrlstat_read(&mut self) -> (DapAck, CtrlStatus) {
+    fn dp_ctrlstat_read(&mut self) -> Result<(DapAck, CtrlStatus), DapError> {
         self.dp_ctrlstat(CtrlStatus(0), true)
     }
-    fn dp_ctrlstat_write(&mut self, control: CtrlStatus) -> DapAck {
-        let (ack, _) = self.dp_ctrlstat(control, false);
-        ack
+    fn dp_ctrlstat_write(&mut self, control: CtrlStatus) -> Result<DapAck, DapError> {
+        let (ack, _) = self.dp_ctrlstat(control, false)?;
+        Ok(ack)
     }
 }
 
 pub trait MemoryAccessPort: DapInterface + DebugPort {
     // MEM-APアクセス関数たち
-    fn memap(&mut self, address: MemapAddress, data: u32, read: bool) -> (DapAck, u32);
+    fn memap(
+        &mut self,
+        address: MemapAddress,
+        data: u32,
+        read: bool,
+    ) -> Result<(DapAck, u32), DapError>;
 
-    fn memap_idr_read(&mut self) -> (DapAck, u32) {
+    fn memap_idr_read(&mut self) -> Result<(DapAck, u32), DapError> {
         self.memap(MemapAddress::IDR, 0, true)
     }
 
-    fn memap_csw_read(&mut self) -> (DapAck, CSW) {
-        let (ack, result) = self.memap(MemapAddress::CSW, 0, true);
-        (ack, CSW(result))
+    fn memap_csw_read(&mut self) -> Result<(DapAck, CSW), DapError> {
+        let (ack, result) = self.memap(MemapAddress::CSW, 0, true)?;
+        Ok((ack, CSW(result)))
+    }
+    fn memap_csw_write(&mut self, csw: CSW) -> Result<DapAck, DapError> {
+        let (ack, _) = self.memap(MemapAddress::CSW, csw.0, false)?;
+        Ok(ack)
+    }
+
+    fn memap_cfg_read(&mut self) -> Result<(DapAck, MemApCfg), DapError> {
+        let (ack, result) = self.memap(MemapAddress::CFG, 0, true)?;
+        Ok((ack, MemApCfg(result)))
+    }
+
+    // CFGはAP毎に固定なので、一度読んだら覚えておく
+    fn cached_cfg(&self) -> Option<MemApCfg> {
+        None
+    }
+    fn set_cached_cfg(&mut self, _cfg: Option<MemApCfg>) {}
+
+    // 最後に書いたTAR。Noneならtargetの値が分からないので必ず書く
+    fn cached_tar(&self) -> Option<u64> {
+        None
+    }
+    fn set_cached_tar(&mut self, _tar: Option<u64>) {}
+    // trueならTARを書いた後に読み返し、違えばTarMismatchを返す
+    fn verify_tar(&self) -> bool {
+        false
     }
-    fn memap_csw_write(&mut self, csw: CSW) -> DapAck {
-        let (ack, _) = self.memap(MemapAddress::CSW, csw.0, false);
-        ack
+
+    // [addr, addr + len)に書く前に呼ぶ。DAPはset_write_guardの範囲ならWriteProtectedを返す
+    fn check_write(&self, _addr: u64, _len: u64) -> Result<(), DapError> {
+        Ok(())
     }
 
-    fn memap_cfg_read(&mut self) -> (DapAck, u32) {
-        self.memap(MemapAddress::CFG, 0, true)
+    // 64bit addressを使う前に確認する
+    // CFGが読めなかった場合は32bit address、little-endianとみなす
+    fn memap_capabilities(&mut self) -> Result<MemApCfg, DapError> {
+        if let Some(cfg) = self.cached_cfg() {
+            return Ok(cfg);
+        }
+        let (ack, cfg) = self.memap_cfg_read()?;
+        if !matches!(ack, DapAck::OkFault) {
+            warn!("CFG read failed: {:?}", ack);
+            return Ok(MemApCfg(0));
+        }
+        debug!("MEM-AP {}", cfg);
+        self.set_cached_cfg(Some(cfg));
+        Ok(cfg)
     }
 
-    fn memap_tar_u64(&mut self, address: u64, read: bool) -> (DapAck, u64) {
+    fn memap_tar_u64(&mut self, address: u64, read: bool) -> Result<(DapAck, u64), DapError> {
         let address_low = (address & 0xffff_ffff) as u32;
         let address_high = (address >> 32) as u32;
         let mut result: u64 = 0;
 
         debug!("set TAR address to {:#16x}", address);
 
-        let (ack, tmp) = self.memap(MemapAddress::TARhi, address_high, read);
+        let (ack, tmp) = self.memap(MemapAddress::TARhi, address_high, read)?;
         result = (tmp as u64) << 32;
-        let (ack, tmp) = self.memap(MemapAddress::TARlo, address_low, read);
+        let (ack, tmp) = self.memap(MemapAddress::TARlo, address_low, read)?;
         result = result | (tmp as u64);
 
-        (ack, result)
+        Ok((ack, result))
     }
 
-    fn memap_tar_u64_read(&mut self) -> (DapAck, u64) {
+    // LAがないMEM-APではTARhiに触るとfaultするのでTARloだけを使う
+    fn memap_tar_u64_read(&mut self) -> Result<(DapAck, u64), DapError> {
+        if self.memap_capabilities()?.LA() == 0 {
+            let (ack, address) = self.memap_tar_u32_read()?;
+            return Ok((ack, address as u64));
+        }
         self.memap_tar_u64(0, true)
     }
 
-    fn memap_tar_u64_write(&mut self, address: u64) -> DapAck {
-        let (ack, _) = self.memap_tar_u64(address, false);
-        let (_, address) = self.memap_tar_u64_read();
-        debug!("verify TAR address {:#16x}", address);
-        ack
+    // 前回書いた値と同じならTARは書かない
+    fn memap_tar_u64_write(&mut self, address: u64) -> Result<DapAck, DapError> {
+        if self.cached_tar() == Some(address) {
+            return Ok(DapAck::OkFault);
+        }
+        let ack = if self.memap_capabilities()?.LA() == 0 {
+            if address > u32::MAX as u64 {
+                error!("MEM-AP without large address: {:#x}", address);
+                return Err(InterfaceError::OutOfRange.into());
+            }
+            self.memap_tar_u32_write(address as u32)?
+        } else {
+            let (ack, _) = self.memap_tar_u64(address, false)?;
+            if self.verify_tar() {
+                let (_, written) = self.memap_tar_u64_read()?;
+                debug!("verify TAR address {:#16x}", written);
+                if written != address {
+                    // targetのTARが分からないので、次のaccessで必ず書き直す
+                    self.set_cached_tar(None);
+                    return Err(DapError::TarMismatch {
+                        expected: address,
+                        found: written,
+                    });
+                }
+            }
+            ack
+        };
+        if matches!(ack, DapAck::OkFault) {
+            self.set_cached_tar(Some(address));
+        }
+        Ok(ack)
     }
 
-    fn memap_tar_u32(&mut self, address: u32, read: bool) -> (DapAck, u32) {
-        let (ack, result) = self.memap(MemapAddress::TARlo, address, read);
-        (ack, result)
+    fn memap_tar_u32(&mut self, address: u32, read: bool) -> Result<(DapAck, u32), DapError> {
+        self.memap(MemapAddress::TARlo, address, read)
     }
 
-    fn memap_tar_u32_read(&mut self) -> (DapAck, u32) {
+    fn memap_tar_u32_read(&mut self) -> Result<(DapAck, u32), DapError> {
         self.memap_tar_u32(0, true)
     }
 
-    fn memap_tar_u32_write(&mut self, address: u32) -> DapAck {
-        let (ack, _) = self.memap_tar_u32(address, false);
-        ack
+    fn memap_tar_u32_write(&mut self, address: u32) -> Result<DapAck, DapError> {
+        let (ack, _) = self.memap_tar_u32(address, false)?;
+        Ok(ack)
     }
 
-    fn memap_bd0(&mut self, data: u32, read: bool) -> (DapAck, u32) {
+    fn memap_bd0(&mut self, data: u32, read: bool) -> Result<(DapAck, u32), DapError> {
         self.memap(MemapAddress::BD0, data, read)
     }
-    fn memap_bd0_read(&mut self) -> (DapAck, u32) {
+    fn memap_bd0_read(&mut self) -> Result<(DapAck, u32), DapError> {
         self.memap_bd0(0, true)
     }
-    fn memap_bd0_write(&mut self, data: u32) -> DapAck {
-        let (ack, _) = self.memap_bd0(data, false);
-        ack
+    fn memap_bd0_write(&mut self, data: u32) -> Result<DapAck, DapError> {
+        let (ack, _) = self.memap_bd0(data, false)?;
+        Ok(ack)
     }
-    fn memap_bd1(&mut self, data: u32, read: bool) -> (DapAck, u32) {
+    fn memap_bd1(&mut self, data: u32, read: bool) -> Result<(DapAck, u32), DapError> {
         self.memap(MemapAddress::BD1, data, read)
     }
-    fn memap_bd1_read(&mut self) -> (DapAck, u32) {
+    fn memap_bd1_read(&mut self) -> Result<(DapAck, u32), DapError> {
         self.memap_bd1(0, true)
     }
-    fn memap_bd1_write(&mut self, data: u32) -> DapAck {
-        let (ack, _) = self.memap_bd1(data, false);
-        ack
+    fn memap_bd1_write(&mut self, data: u32) -> Result<DapAck, DapError> {
+        let (ack, _) = self.memap_bd1(data, false)?;
+        Ok(ack)
     }
-    fn memap_bd2(&mut self, data: u32, read: bool) -> (DapAck, u32) {
+    fn memap_bd2(&mut self, data: u32, read: bool) -> Result<(DapAck, u32), DapError> {
         self.memap(MemapAddress::BD2, data, read)
     }
-    fn memap_bd2_read(&mut self) -> (DapAck, u32) {
+    fn memap_bd2_read(&mut self) -> Result<(DapAck, u32), DapError> {
         self.memap_bd2(0, true)
     }
-    fn memap_bd2_write(&mut self, data: u32) -> DapAck {
-        let (ack, _) = self.memap_bd2(data, false);
-        ack
+    fn memap_bd2_write(&mut self, data: u32) -> Result<DapAck, DapError> {
+        let (ack, _) = self.memap_bd2(data, false)?;
+        Ok(ack)
     }
-    fn memap_bd3(&mut self, data: u32, read: bool) -> (DapAck, u32) {
+    fn memap_bd3(&mut self, data: u32, read: bool) -> Result<(DapAck, u32), DapError> {
         self.memap(MemapAddress::BD3, data, read)
     }
-    fn memap_bd3_read(&mut self) -> (DapAck, u32) {
+    fn memap_bd3_read(&mut self) -> Result<(DapAck, u32), DapError> {
         self.memap_bd3(0, true)
     }
-    fn memap_bd3_write(&mut self, data: u32) -> DapAck {
-        let (ack, _) = self.memap_bd3(data, false);
-        ack
+    fn memap_bd3_write(&mut self, data: u32) -> Result<DapAck, DapError> {
+        let (ack, _) = self.memap_bd3(data, false)?;
+        Ok(ack)
     }
 
-    fn memap_base_u32_read(&mut self) -> (DapAck, u32) {
-        self.memap(MemapAddress::BASElo, 0, true)
+    fn memap_drw(&mut self, data: u32, read: bool) -> Result<(DapAck, u32), DapError> {
+        self.memap(MemapAddress::DRW, data, read)
     }
-    fn memap_base_u64_read(&mut self) -> (DapAck, u64) {
-        let (ack, lo) = self.memap(MemapAddress::BASElo, 0, true);
-        let (ack, hi) = self.memap(MemapAddress::BASEhi, 0, true);
-        let address: u64 = ((hi as u64) << 32) | (lo as u64);
-        (ack, address)
+    // big-endianのMEM-APではbyte順を入れ替えて、呼び出し側からはlittle-endianに見せる
+    fn memap_drw_read(&mut self) -> Result<(DapAck, u32), DapError> {
+        let big_endian = self.memap_capabilities()?.BE() == 1;
+        let (ack, data) = self.memap_drw(0, true)?;
+        Ok((ack, if big_endian { data.swap_bytes() } else { data }))
+    }
+    fn memap_drw_write(&mut self, data: u32) -> Result<DapAck, DapError> {
+        let data = if self.memap_capabilities()?.BE() == 1 {
+            data.swap_bytes()
+        } else {
+            data
+        };
+        let (ack, _) = self.memap_drw(data, false)?;
+        Ok(ack)
     }
-}
 
-// TODO: dpを借用かつmutexを取れるように持つ
-// DAPとAPは1対1で張り付くので、APが複数あるとDAPも複数になるため
-pub struct DAP<T> {
-    dp: T,
-    apnum: u8,
-}
+    // 32bitアクセスとTARの自動インクリメントを設定する
+    fn memap_csw_setup(&mut self, addr_inc: CswAddrInc) -> Result<DapAck, DapError> {
+        self.memap_csw_setup_size(CswSize::Word, addr_inc)
+    }
 
-impl<T: DapInterface> DAP<T> {
-    pub fn new(dp: T) -> Self {
-        let mut dap = DAP { dp: dp, apnum: 0 };
-        dap.init();
-        dap
+    // 既に同じ設定ならCSWは書かない
+    fn memap_csw_setup_size(
+        &mut self,
+        size: CswSize,
+        addr_inc: CswAddrInc,
+    ) -> Result<DapAck, DapError> {
+        let (ack, mut csw) = self.memap_csw_read()?;
+        if !matches!(ack, DapAck::OkFault) {
+            return Ok(ack);
+        }
+        if csw.size() == Some(size) && csw.addr_inc() == Some(addr_inc) {
+            return Ok(ack);
+        }
+        csw.set_SIZE(size as u32);
+        csw.set_AddrInc(addr_inc as u32);
+        self.memap_csw_write(csw)
     }
 
-    fn init(&mut self) {
-        self.dp_select_write(0, 0, 0);
+    // 8/16bitアクセスではDRWのaddress[1:0]に対応するbyte laneを使う
+    fn mem_read_lane(&mut self, addr: u64, size: CswSize) -> Result<(DapAck, u32), DapError> {
+        let ack = self.memap_csw_setup_size(size, CswAddrInc::Off)?;
+        if !matches!(ack, DapAck::OkFault) {
+            return Ok((ack, 0));
+        }
+        let ack = self.memap_tar_u64_write(addr)?;
+        if !matches!(ack, DapAck::OkFault) {
+            return Ok((ack, 0));
+        }
+        let (ack, data) = self.memap_drw_read()?;
+        Ok((ack, data >> ((addr & 3) * 8)))
+    }
 
-        // debug reset
-        // let mut ctrl = CtrlStatus(0);
-        // ctrl.set_CDBGRSTREQ(1);
-        // self.dp_ctrlstat_write(ctrl);
-        // while {
-        //     let (_, ctrl) = self.dp_ctrlstat_read();
-        //     debug!("requesting reset: {:?}", ctrl);
-        //     ctrl.CDBGRSTACK() != 1
-        // } {}
-        // let ctrl = CtrlStatus(0);
-        // self.dp_ctrlstat_write(ctrl);
+    fn mem_write_lane(&mut self, addr: u64, size: CswSize, data: u32) -> Result<DapAck, DapError> {
+        self.check_write(addr, 1 << size as u64)?;
+        let ack = self.memap_csw_setup_size(size, CswAddrInc::Off)?;
+        if !matches!(ack, DapAck::OkFault) {
+            return Ok(ack);
+        }
+        let ack = self.memap_tar_u64_write(addr)?;
+        if !matches!(ack, DapAck::OkFault) {
+            return Ok(ack);
+        }
+        self.memap_drw_write(data << ((addr & 3) * 8))
+    }
 
-        let (ack, ctrl) = self.dp_ctrlstat_read();
-        debug!("first ctrl: {:?}, {:?}", ack, ctrl);
+    fn mem_read_u8(&mut self, addr: u64) -> Result<(DapAck, u8), DapError> {
+        let (ack, data) = self.mem_read_lane(addr, CswSize::Byte)?;
+        Ok((ack, data as u8))
+    }
 
-        let mut ctrl = CtrlStatus(0);
-        ctrl.set_CDBGPWRUPREQ(1);
-        ctrl.set_CSYSPWRUPREQ(1);
-        ctrl.set_STICKYERR(1);
-        self.dp_ctrlstat_write(ctrl);
-        let mut ctrl = CtrlStatus(0);
-        ctrl.set_CDBGPWRUPREQ(1);
-        ctrl.set_CSYSPWRUPREQ(1);
-        ctrl.set_STICKYERR(0);
-        self.dp_ctrlstat_write(ctrl);
+    fn mem_write_u8(&mut self, addr: u64, data: u8) -> Result<DapAck, DapError> {
+        self.mem_write_lane(addr, CswSize::Byte, data as u32)
+    }
 
-        // power up
-        while {
-            let (ack, ctrl) = self.dp_ctrlstat_read();
-            debug!("requesting powerup: {:?}, {:?}", ack, ctrl);
-            ctrl.CDBGPWRUPACK() != 1 || ctrl.CSYSPWRUPACK() != 1
-        } {}
+    // addrは2byte境界であること
+    fn mem_read_u16(&mut self, addr: u64) -> Result<(DapAck, u16), DapError> {
+        if !addr.is_multiple_of(2) {
+            error!("unaligned halfword read: {:#x}", addr);
+            return Err(InterfaceError::OutOfRange.into());
+        }
+        let (ack, data) = self.mem_read_lane(addr, CswSize::Halfword)?;
+        Ok((ack, data as u16))
+    }
 
-        // CSW
-        let (ack, mut data) = self.memap_csw_read();
-        debug!(
-            "read CSW: ack: {:?}, data: {:#08x}: {:?}",
-            ack, data.0, data
-        );
-        // enable DbgSwEnable
-        data.set_DbgSwEnable(1);
-        let ack = self.memap_csw_write(data);
-        debug!("write CSW: ack: {:?}", ack);
-        let (ack, mut data) = self.memap_csw_read();
-        debug!(
-            "read CSW: ack: {:?}, data: {:#08x}: {:?}",
-            ack, data.0, data
-        );
+    fn mem_write_u16(&mut self, addr: u64, data: u16) -> Result<DapAck, DapError> {
+        if !addr.is_multiple_of(2) {
+            error!("unaligned halfword write: {:#x}", addr);
+            return Err(InterfaceError::OutOfRange.into());
+        }
+        self.mem_write_lane(addr, CswSize::Halfword, data as u32)
     }
-}
 
-impl<T: DapInterface> DapInterface for DAP<T> {
-    fn apacc(&mut self, data: u32, a: u8, RnW: bool) -> (u8, u32) {
-        self.dp.apacc(data, a, RnW)
+    // addrは4byte境界であること
+    fn mem_read_u32(&mut self, addr: u64) -> Result<(DapAck, u32), DapError> {
+        if !addr.is_multiple_of(4) {
+            warn!("unaligned memory read: {:#x}", addr);
+        }
+        let ack = self.memap_csw_setup(CswAddrInc::Off)?;
+        if !matches!(ack, DapAck::OkFault) {
+            return Ok((ack, 0));
+        }
+        let ack = self.memap_tar_u64_write(addr)?;
+        if !matches!(ack, DapAck::OkFault) {
+            return Ok((ack, 0));
+        }
+        self.memap_drw_read()
     }
-    fn dpacc(&mut self, data: u32, a: u8, RnW: bool) -> (u8, u32) {
-        self.dp.dpacc(data, a, RnW)
+
+    fn mem_write_u32(&mut self, addr: u64, data: u32) -> Result<DapAck, DapError> {
+        if !addr.is_multiple_of(4) {
+            warn!("unaligned memory write: {:#x}", addr);
+        }
+        self.check_write(addr, 4)?;
+        let ack = self.memap_csw_setup(CswAddrInc::Off)?;
+        if !matches!(ack, DapAck::OkFault) {
+            return Ok(ack);
+        }
+        let ack = self.memap_tar_u64_write(addr)?;
+        if !matches!(ack, DapAck::OkFault) {
+            return Ok(ack);
+        }
+        self.memap_drw_write(data)
     }
-}
 
-impl<T: DapInterface> DebugPort for DAP<T> {}
+    // 失敗した場合はその時点のACKを返す
+    // 1KB毎にTARを書き、DRWをpipelineで読む。OK以外のACKが返ったら残りを1wordずつ読み直す
+    fn mem_read_block(&mut self, addr: u64, buf: &mut [u32]) -> Result<DapAck, DapError> {
+        if !addr.is_multiple_of(4) {
+            warn!("unaligned memory read: {:#x}", addr);
+        }
+        let big_endian = self.memap_capabilities()?.BE() == 1;
+        let ack = self.memap_csw_setup(CswAddrInc::Single)?;
+        if !matches!(ack, DapAck::OkFault) {
+            return Ok(ack);
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let address = addr + done as u64 * 4;
+            let boundary = (address | (MEMAP_AUTOINC_BOUNDARY - 1)) + 1;
+            let words = ((boundary - address).div_ceil(4) as usize).min(buf.len() - done);
+            let ack = self.memap_tar_u64_write(address)?;
+            if !matches!(ack, DapAck::OkFault) {
+                return Ok(ack);
+            }
+            let (read, ack) =
+                self.memap_drw_read_pipelined_partial(&mut buf[done..done + words])?;
+            if big_endian {
+                for x in buf[done..done + read].iter_mut() {
+                    *x = x.swap_bytes();
+                }
+            }
+            done += read;
+            if !matches!(ack, DapAck::OkFault) {
+                warn!("unexpected ACK in pipelined DRW read: {:?}", ack);
+                return self.mem_read_block_slow(addr + done as u64 * 4, &mut buf[done..]);
+            }
+        }
+        Ok(DapAck::OkFault)
+    }
+
+    // TARの自動インクリメントで1wordずつRDBUFFまで読む
+    fn mem_read_block_slow(&mut self, addr: u64, buf: &mut [u32]) -> Result<DapAck, DapError> {
+        let mut ack = DapAck::OkFault;
+        for (i, word) in buf.iter_mut().enumerate() {
+            let address = addr + (i as u64) * 4;
+            // 1KB境界を越える場合はTARを設定しなおす
+            if i == 0 || address.is_multiple_of(MEMAP_AUTOINC_BOUNDARY) {
+                let ack = self.memap_tar_u64_write(address)?;
+                if !matches!(ack, DapAck::OkFault) {
+                    return Ok(ack);
+                }
+            }
+            let (result_ack, data) = self.memap_drw_read()?;
+            *word = data;
+            ack = result_ack;
+            if !matches!(ack, DapAck::OkFault) {
+                return Ok(ack);
+            }
+        }
+        Ok(ack)
+    }
+
+    fn mem_write_block(&mut self, addr: u64, data: &[u32]) -> Result<DapAck, DapError> {
+        if !addr.is_multiple_of(4) {
+            warn!("unaligned memory write: {:#x}", addr);
+        }
+        self.check_write(addr, data.len() as u64 * 4)?;
+        let mut ack = self.memap_csw_setup(CswAddrInc::Single)?;
+        for (i, &word) in data.iter().enumerate() {
+            if !matches!(ack, DapAck::OkFault) {
+                return Ok(ack);
+            }
+            let address = addr + (i as u64) * 4;
+            if i == 0 || address.is_multiple_of(MEMAP_AUTOINC_BOUNDARY) {
+                let ack = self.memap_tar_u64_write(address)?;
+                if !matches!(ack, DapAck::OkFault) {
+                    return Ok(ack);
+                }
+            }
+            ack = self.memap_drw_write(word)?;
+        }
+        Ok(ack)
+    }
+
+    // 以下のtry_系はACKとsticky flagをDapErrorにして返す
+    fn try_memap(&mut self, address: MemapAddress, data: u32, read: bool) -> Result<u32, DapError> {
+        let (ack, result) = self.memap(address, data, read)?;
+        self.check_ack(ack, true)?;
+        Ok(result)
+    }
+    fn try_mem_read_u32(&mut self, addr: u64) -> Result<u32, DapError> {
+        let (ack, result) = self.mem_read_u32(addr)?;
+        self.check_ack(ack, true)?;
+        Ok(result)
+    }
+    fn try_mem_write_u32(&mut self, addr: u64, data: u32) -> Result<(), DapError> {
+        let ack = self.mem_write_u32(addr, data)?;
+        self.check_ack(ack, true)
+    }
+    fn try_mem_read_block(&mut self, addr: u64, buf: &mut [u32]) -> Result<(), DapError> {
+        let ack = self.mem_read_block(addr, buf)?;
+        self.check_ack(ack, true)
+    }
+    fn try_mem_write_block(&mut self, addr: u64, data: &[u32]) -> Result<(), DapError> {
+        let ack = self.mem_write_block(addr, data)?;
+        self.check_ack(ack, true)
+    }
+
+    // addrからlen byteを読み、addressで引けるMemViewにする
+    // 4byte境界に広げてwordで読むので、addrやlenは揃っていなくてよい
+    #[cfg(feature = "alloc")]
+    fn try_mem_read_view(&mut self, addr: u64, len: usize) -> Result<MemView, DapError> {
+        let start = addr & !3;
+        let end = (addr + len as u64 + 3) & !3;
+        let mut words = alloc::vec![0u32; ((end - start) / 4) as usize];
+        self.try_mem_read_block(start, &mut words)?;
+        let offset = (addr - start) as usize;
+        let data: Vec<u8> = words
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .skip(offset)
+            .take(len)
+            .collect();
+        Ok(MemView::new(addr, data))
+    }
+
+    // TARを書いた後に使う。SELECTは書き直さず、RDBUFFも読まない
+    // bus errorはACKに出ないので、呼び出し側でcheck_stickyすること
+    fn memap_drw_stream_write(&mut self, data: u32) -> Result<(), DapError> {
+        self.set_cached_tar(None);
+        let (ack, _) = self.acc_retry(true, data, (MemapAddress::DRW as u8 & 0x0f) >> 2, false)?;
+        self.check_ack(ack, false)
+    }
+
+    // TARを書いた後に使う。APACCのreadは1つ前の結果を返すので、最後の1wordだけRDBUFFで受け取る
+    // big-endianの変換はしない
+    fn memap_drw_read_pipelined(&mut self, buf: &mut [u32]) -> Result<(), DapError> {
+        let (_, ack) = self.memap_drw_read_pipelined_partial(buf)?;
+        self.check_ack(ack, false)
+    }
+
+    // OK以外のACKが返ったらそこで止め、先頭から受け取れたword数とそのACKを返す
+    fn memap_drw_read_pipelined_partial(
+        &mut self,
+        buf: &mut [u32],
+    ) -> Result<(usize, DapAck), DapError> {
+        self.set_cached_tar(None);
+        let a = (MemapAddress::DRW as u8 & 0x0f) >> 2;
+        for i in 0..buf.len() {
+            let (ack, previous) = self.acc_retry(true, 0, a, true)?;
+            if !matches!(ack, DapAck::OkFault) {
+                // 1つ前の結果も受け取れていない
+                return Ok((i.saturating_sub(1), ack));
+            }
+            if i > 0 {
+                buf[i - 1] = previous;
+            }
+        }
+        let last = match buf.last_mut() {
+            Some(x) => x,
+            None => return Ok((0, DapAck::OkFault)),
+        };
+        let (ack, data) = self.dp_rdbuff_read()?;
+        if !matches!(ack, DapAck::OkFault) {
+            return Ok((buf.len() - 1, ack));
+        }
+        *last = data;
+        Ok((buf.len(), ack))
+    }
+
+    // addrからlen byteをpatternで埋める。patternはword境界から始まるlittle-endianの並びとして置く
+    // 4byteに揃わない両端はbyteで書き、間はTARの自動インクリメントでDRWに流し込む
+    fn mem_fill(&mut self, addr: u64, len: u64, pattern: u32) -> Result<(), DapError> {
+        let end = addr.checked_add(len).ok_or(InterfaceError::OutOfRange)?;
+        self.check_write(addr, len)?;
+        let body_start = ((addr + 3) & !3).min(end);
+        let body_end = (end & !3).max(body_start);
+        let pattern_byte = |address: u64| (pattern >> ((address & 3) * 8)) as u8;
+        for address in addr..body_start {
+            let ack = self.mem_write_u8(address, pattern_byte(address))?;
+            self.check_ack(ack, false)?;
+        }
+
+        let word = if self.memap_capabilities()?.BE() == 1 {
+            pattern.swap_bytes()
+        } else {
+            pattern
+        };
+        let ack = self.memap_csw_setup(CswAddrInc::Single)?;
+        self.check_ack(ack, false)?;
+        let mut unchecked = 0;
+        let mut address = body_start;
+        while address < body_end {
+            if address == body_start || address.is_multiple_of(MEMAP_AUTOINC_BOUNDARY) {
+                let ack = self.memap_tar_u64_write(address)?;
+                self.check_ack(ack, false)?;
+            }
+            self.memap_drw_stream_write(word)?;
+            address += 4;
+            unchecked += 1;
+            if unchecked == MEM_FILL_CHECK_INTERVAL {
+                self.check_sticky()?;
+                unchecked = 0;
+            }
+        }
+
+        for address in body_end..end {
+            let ack = self.mem_write_u8(address, pattern_byte(address))?;
+            self.check_ack(ack, false)?;
+        }
+        if unchecked > 0 || body_start == body_end {
+            self.check_sticky()?;
+        }
+        Ok(())
+    }
+
+    // expectedと違う最初のaddressを返す。全て一致すればNone
+    // 両端は含むwordを読み、範囲内のbyteだけを比べる
+    fn mem_compare(&mut self, addr: u64, expected: &[u8]) -> Result<Option<u64>, DapError> {
+        let end = addr
+            .checked_add(expected.len() as u64)
+            .ok_or(InterfaceError::OutOfRange)?;
+        if addr == end {
+            return Ok(None);
+        }
+        let big_endian = self.memap_capabilities()?.BE() == 1;
+        let ack = self.memap_csw_setup(CswAddrInc::Single)?;
+        self.check_ack(ack, false)?;
+        let mut buf = [0u32; MEM_COMPARE_CHUNK_WORDS];
+        let mut address = addr & !3;
+        while address < end {
+            // 1KB境界を越えないように区切り、区切り毎にTARを書く
+            let boundary = (address | (MEMAP_AUTOINC_BOUNDARY - 1)) + 1;
+            let words = ((end.min(boundary) - address).div_ceil(4) as usize).min(buf.len());
+            let ack = self.memap_tar_u64_write(address)?;
+            self.check_ack(ack, false)?;
+            self.memap_drw_read_pipelined(&mut buf[..words])?;
+            for (i, word) in buf[..words].iter().enumerate() {
+                let word = if big_endian { word.swap_bytes() } else { *word };
+                for (j, byte) in word.to_le_bytes().iter().enumerate() {
+                    let x = address + (i * 4 + j) as u64;
+                    if x >= addr && x < end && *byte != expected[(x - addr) as usize] {
+                        return Ok(Some(x));
+                    }
+                }
+            }
+            address += words as u64 * 4;
+        }
+        Ok(None)
+    }
+
+    fn memap_base_u32_read(&mut self) -> Result<(DapAck, u32), DapError> {
+        self.memap(MemapAddress::BASElo, 0, true)
+    }
+    fn memap_base_u64_read(&mut self) -> Result<(DapAck, u64), DapError> {
+        let (ack, lo) = self.memap(MemapAddress::BASElo, 0, true)?;
+        // BASEloで失敗したACKをBASEhiのACKで上書きしない
+        if !matches!(ack, DapAck::OkFault) {
+            return Ok((ack, lo as u64));
+        }
+        let (ack, hi) = self.memap(MemapAddress::BASEhi, 0, true)?;
+        let address: u64 = ((hi as u64) << 32) | (lo as u64);
+        Ok((ack, address))
+    }
+}
+
+// TODO: dpを借用かつmutexを取れるように持つ
+// DAPとAPは1対1で張り付くので、APが複数あるとDAPも複数になるため
+pub struct DAP<T: DapInterface> {
+    pub(crate) dp: T,
+    apnum: u8,
+    quirks: QuirkSet,
+    max_wait_retries: usize,
+    dpidr: PdIdr,
+    select: Option<DpSelect>,
+    // 選択中のAPのCFG
+    cfg: Option<MemApCfg>,
+    // 選択中のAPに最後に書いたTAR
+    tar: Option<u64>,
+    // CSWで自動インクリメントが有効か。分からない間はtrueとして扱う
+    tar_autoinc: bool,
+    verify_tar: bool,
+    // set_collect_statsで有効にした場合だけ数える
+    stats: Option<DapStats>,
+    // dropする時にdebug/system domainの電源要求を取り下げる
+    power_down_on_drop: bool,
+    check_faults: bool,
+    // set_link_checkで設定した、pingするまでに許すInvalidAckの連続回数
+    link_check: Option<usize>,
+    invalid_acks: usize,
+    link_lost: bool,
+    #[cfg(feature = "alloc")]
+    write_guard: Option<WriteGuard>,
+    // unguarded()の間だけtrue
+    #[cfg(feature = "alloc")]
+    unguarded: bool,
+}
+
+impl<T: DapInterface> DAP<T> {
+    // 初期化に失敗したらpanicする。errorを受け取りたい場合はtry_newを使う
+    pub fn new(dp: T) -> Self {
+        Self::try_new(dp).expect("DAP initialization failed")
+    }
+
+    #[cfg(feature = "std")]
+    pub fn new_with_config(dp: T, target: &TargetConfig) -> Self {
+        Self::try_new_with_config(dp, target).expect("DAP initialization failed")
+    }
+
+    pub fn new_with_quirks(dp: T, quirks: QuirkSet) -> Self {
+        Self::try_new_with_quirks(dp, quirks).expect("DAP initialization failed")
+    }
+
+    // DPIDRが読めなければNoTarget、電源が入らなければPowerUpTimeoutを返す
+    pub fn try_new(dp: T) -> Result<Self, DapError> {
+        Self::try_new_with_quirks(dp, QuirkSet::empty())
+    }
+
+    // profileのtarget.apを選んだ状態で返す
+    // target.quirksはIDCODEから分かるquirkに足され、target.memoryはWriteGuardになる
+    #[cfg(feature = "std")]
+    pub fn try_new_with_config(dp: T, target: &TargetConfig) -> Result<Self, DapError> {
+        let mut dap = Self::try_new_with_quirks(dp, target.quirks)?;
+        dap.select_ap(target.ap);
+        dap.set_write_guard(WriteGuard::from_config(target));
+        Ok(dap)
+    }
+
+    pub fn try_new_with_quirks(dp: T, quirks: QuirkSet) -> Result<Self, DapError> {
+        let mut dap = DAP {
+            dp,
+            apnum: 0,
+            quirks,
+            max_wait_retries: DEFAULT_MAX_WAIT_RETRIES,
+            dpidr: PdIdr(0),
+            select: None,
+            cfg: None,
+            tar: None,
+            tar_autoinc: true,
+            verify_tar: false,
+            stats: None,
+            power_down_on_drop: false,
+            check_faults: false,
+            link_check: None,
+            invalid_acks: 0,
+            link_lost: false,
+            #[cfg(feature = "alloc")]
+            write_guard: None,
+            #[cfg(feature = "alloc")]
+            unguarded: false,
+        };
+        dap.init()?;
+        Ok(dap)
+    }
+
+    pub fn set_quirks(&mut self, quirks: QuirkSet) {
+        self.quirks = quirks;
+    }
+
+    pub fn set_max_wait_retries(&mut self, retries: usize) {
+        self.max_wait_retries = retries;
+    }
+
+    // TARを書く度に読み返すので、その分遅くなる
+    pub fn set_verify_tar(&mut self, verify: bool) {
+        self.verify_tar = verify;
+    }
+
+    // try_系の関数でAPのtransaction毎にCTRL/STATを読むので、その分遅くなる
+    pub fn set_check_faults(&mut self, check: bool) {
+        self.check_faults = check;
+    }
+
+    // InvalidAckがthreshold回続いたらDPをpingし、失敗すればtry_系の関数がLinkLostを返す
+    // Noneで無効にする。LinkLostになった状態もここで解除する
+    pub fn set_link_check(&mut self, threshold: Option<usize>) {
+        self.link_check = threshold.map(|n| n.max(1));
+        self.invalid_acks = 0;
+        self.link_lost = false;
+    }
+
+    pub fn is_link_lost(&self) -> bool {
+        self.link_lost
+    }
+
+    fn watch_link(&mut self, ack: u8) {
+        let threshold = match self.link_check {
+            Some(threshold) if !self.link_lost => threshold,
+            _ => return,
+        };
+        if !matches!(DapAck::from(ack), DapAck::InvalidAck) {
+            self.invalid_acks = 0;
+            return;
+        }
+        self.invalid_acks += 1;
+        if self.invalid_acks < threshold {
+            return;
+        }
+        self.invalid_acks = 0;
+        // TAPのIRが変わるので、SELECT等も書き直させる
+        self.select = None;
+        match self.dp.ping() {
+            Ok(idcode) => warn!(
+                "{} invalid ACKs in a row, but IDCODE {:#010x} is still readable",
+                threshold, idcode
+            ),
+            // pingできないDPでは確かめようがないので続ける
+            Err(DapError::Interface(InterfaceError::Unsupported)) => {
+                warn!("{} invalid ACKs in a row", threshold)
+            }
+            Err(e) => {
+                error!("JTAG link lost: {}", e);
+                self.link_lost = true;
+            }
+        }
+    }
+
+    // addressの分かる書き込み(mem_write_*, mem_fill, flashやimageの書き込み)を発行する前に確かめる
+    // 断る範囲の無いguardはNoneと同じ
+    #[cfg(feature = "alloc")]
+    pub fn set_write_guard(&mut self, guard: WriteGuard) {
+        self.write_guard = (!guard.is_empty()).then_some(guard);
+    }
+
+    #[cfg(feature = "alloc")]
+    pub fn write_guard(&self) -> Option<&WriteGuard> {
+        self.write_guard.as_ref()
+    }
+
+    // 返したUnguardedを通した書き込みはguardで止めない。protectedな範囲に書くとwarnを出す
+    // dap.unguarded().try_mem_write_u32(..)のように1回の呼び出しに使う
+    #[cfg(feature = "alloc")]
+    pub fn unguarded(&mut self) -> Unguarded<'_, T> {
+        self.unguarded = true;
+        Unguarded { dap: self }
+    }
+
+    pub fn set_power_down_on_drop(&mut self, power_down: bool) {
+        self.power_down_on_drop = power_down;
+    }
+
+    // CDBGPWRUPREQ/CSYSPWRUPREQを落とす。ACKが落ちるのは待たない
+    pub fn power_down(&mut self) -> Result<(), DapError> {
+        self.dp_ctrlstat_write(CtrlStatus(0))?;
+        Ok(())
+    }
+
+    // 有効にした時点から数え始める。無効にすると数えた値も捨てる
+    pub fn set_collect_stats(&mut self, collect: bool) {
+        self.stats = if collect {
+            Some(self.stats.unwrap_or_default())
+        } else {
+            None
+        };
+    }
+
+    // 有効にしていない場合は全て0を返す
+    pub fn stats(&self) -> DapStats {
+        self.stats.unwrap_or_default()
+    }
+
+    pub fn reset_stats(&mut self) {
+        if let Some(stats) = self.stats.as_mut() {
+            *stats = DapStats::default();
+        }
+    }
+
+    fn record(&mut self, ap: bool, read: bool, ack: u8, timer: Timer) {
+        if let Some(stats) = self.stats.as_mut() {
+            stats.record(ap, read, matches!(DapAck::from(ack), DapAck::Wait), timer);
+        }
+    }
+
+    // debug用のAPB-APとsystem memory用のAXI-APなど、使うAPを切り替える
+    pub fn select_ap(&mut self, apsel: u8) {
+        if apsel != self.apnum {
+            self.cfg = None;
+            self.flush_tar_cache();
+        }
+        self.apnum = apsel;
+    }
+
+    // APSEL 0から順にIDRを読み、IDRが0のAPで止める
+    // 返すVecのindexがAPSEL
+    #[cfg(feature = "alloc")]
+    pub fn enumerate_aps(&mut self, max: u8) -> Result<Vec<ApInfo>, DapError> {
+        let mut aps = Vec::new();
+        for apsel in 0..max {
+            let (ack, idr) = self.ap_access_on(apsel, MemapAddress::IDR as u8, 0, true)?;
+            if !matches!(ack, DapAck::OkFault) {
+                warn!("AP{} IDR read failed: {:?}", apsel, ack);
+                break;
+            }
+            if idr == 0 {
+                break;
+            }
+            let ap = ApInfo::from(ApIdr(idr));
+            info!("AP{}: {:?} (IDR: {:#010x})", apsel, ap, idr);
+            aps.push(ap);
+        }
+        Ok(aps)
+    }
+
+    // SELECTを書き換えずにAPにアクセスしてしまう場合に使う
+    // target側がresetされた時など
+    pub fn flush_select_cache(&mut self) {
+        self.select = None;
+        self.flush_tar_cache();
+    }
+
+    fn flush_tar_cache(&mut self) {
+        self.tar = None;
+        self.tar_autoinc = true;
+    }
+
+    // TARが変わり得るaccessの後でcacheを捨てる
+    fn track_tar(
+        &mut self,
+        address: MemapAddress,
+        data: u32,
+        read: bool,
+        result: &Result<(DapAck, u32), DapError>,
+    ) {
+        let value = match result {
+            Ok((DapAck::OkFault, value)) => *value,
+            _ => return self.flush_tar_cache(),
+        };
+        match address {
+            MemapAddress::TARlo | MemapAddress::TARhi if !read => self.tar = None,
+            MemapAddress::CSW => {
+                let csw = CSW(if read { value } else { data });
+                self.tar_autoinc = csw.addr_inc() != Some(CswAddrInc::Off);
+                if self.tar_autoinc {
+                    self.tar = None;
+                }
+            }
+            MemapAddress::DRW if self.tar_autoinc => self.tar = None,
+            _ => (),
+        }
+    }
+
+    pub fn dpidr(&self) -> PdIdr {
+        self.dpidr
+    }
+
+    // DAPが持つDP(JTAGならTAP)を直接使う。vendor固有のIR/DRのscan向け
+    // fの中でSELECTやAPの状態が変わり得るのでcacheは捨てる
+    // TAPは命令をcacheしないので、次のdpacc/apaccはIRを書き直す
+    pub fn with_tap<R>(&mut self, f: impl FnOnce(&mut T) -> R) -> R {
+        let result = f(&mut self.dp);
+        self.select = None;
+        self.cfg = None;
+        self.flush_tar_cache();
+        result
+    }
+
+    // DPv1以降でDPBANKSELなどが使える
+    pub fn dp_version(&self) -> u32 {
+        self.dpidr.VERSION()
+    }
+
+    // DPIDRを読んでDPが繋がっているか確認する
+    // chainの設定が間違っているとここで全て0か全て1になる
+    pub fn probe(&mut self) -> Result<PdIdr, DapError> {
+        let (ack, dpidr) = self.dp_dpidr_read()?;
+        if dpidr.0 == 0 || dpidr.0 == 0xffff_ffff {
+            error!("invalid DPIDR: {:#010x}, ack: {:?}", dpidr.0, ack);
+            return Err(DapError::NoTarget);
+        }
+        info!(
+            "DPIDR: {:#010x}, DPv{}, designer: {:#05x}, partno: {:#04x}, revision: {}",
+            dpidr.0,
+            dpidr.VERSION(),
+            dpidr.DESIGNER(),
+            dpidr.PARTNO(),
+            dpidr.REVISION()
+        );
+        self.dpidr = dpidr;
+        Ok(dpidr)
+    }
+
+    // addressはAPのregister address(bank込み)
+    fn ap_access(&mut self, address: u8, data: u32, read: bool) -> Result<(DapAck, u32), DapError> {
+        self.ap_access_on(self.apnum, address, data, read)
+    }
+
+    fn ap_access_on(
+        &mut self,
+        apsel: u8,
+        address: u8,
+        data: u32,
+        read: bool,
+    ) -> Result<(DapAck, u32), DapError> {
+        let idr = address == MemapAddress::IDR as u8;
+        let apbanksel = (address & 0xf0) >> 4;
+        let address = (address & 0x0f) >> 2;
+        self.dp_select_write(apsel, apbanksel, 0)?;
+        self.acc_retry(true, data, address, read)?;
+        let (ack, result) = self.dp_rdbuff_read()?;
+        // OK以外が返ったらSELECTが書けているか分からない
+        // IDR_BANK_QUIRKではIDRを読んだ後のAPBANKSELが0になっている
+        if !matches!(ack, DapAck::OkFault)
+            || (idr && self.quirks.contains(QuirkSet::IDR_BANK_QUIRK))
+        {
+            self.flush_select_cache();
+        }
+        Ok((ack, result))
+    }
+
+    // 任意のAPの任意のregisterに触る。MEM-AP以外のAPやvendor固有のregister用
+    // addrの上位4bitがAPBANKSEL、下位4bitがbank内のoffset
+    pub fn ap_register(
+        &mut self,
+        apsel: u8,
+        addr: u8,
+        data: u32,
+        read: bool,
+    ) -> Result<u32, DapError> {
+        // CSWやTARに触るかもしれない
+        self.flush_tar_cache();
+        let (ack, result) = self.ap_access_on(apsel, addr, data, read)?;
+        self.check_ack(ack, true)?;
+        Ok(result)
+    }
+
+    // APACCのreadは1つ前のtransactionの結果を返すので、RDBUFFを挟まずに続けて読む
+    // bufのi番目にaddrsのi番目の結果が入る
+    // bankが揃っていない場合やOK以外のACKが返った場合は1つずつ読む
+    pub fn ap_read_pipelined(&mut self, addrs: &[u8], buf: &mut [u32]) -> Result<DapAck, DapError> {
+        if addrs.len() != buf.len() {
+            return Err(InterfaceError::OutOfRange.into());
+        }
+        let apbanksel = match addrs.first() {
+            Some(address) => address >> 4,
+            None => return Ok(DapAck::OkFault),
+        };
+        if addrs.iter().any(|x| x >> 4 != apbanksel) {
+            return self.ap_read_slow(addrs, buf);
+        }
+        let ack = self.dp_select_write(self.apnum, apbanksel, 0)?;
+        if !matches!(ack, DapAck::OkFault) {
+            return Ok(ack);
+        }
+        for (i, address) in addrs.iter().enumerate() {
+            let (ack, previous) = self.acc_retry(true, 0, (address & 0x0f) >> 2, true)?;
+            match ack {
+                DapAck::OkFault => (),
+                ack => {
+                    // 1つ前の結果も受け取れていないので、そこから読み直す
+                    warn!("unexpected ACK in pipelined read: {:?}", ack);
+                    self.flush_select_cache();
+                    let start = i.saturating_sub(1);
+                    return self.ap_read_slow(&addrs[start..], &mut buf[start..]);
+                }
+            }
+            if i > 0 {
+                buf[i - 1] = previous;
+            }
+        }
+        let (ack, last) = self.dp_rdbuff_read()?;
+        buf[addrs.len() - 1] = last;
+        Ok(ack)
+    }
+
+    fn ap_read_slow(&mut self, addrs: &[u8], buf: &mut [u32]) -> Result<DapAck, DapError> {
+        let mut ack = DapAck::OkFault;
+        for (address, x) in addrs.iter().zip(buf.iter_mut()) {
+            let (result_ack, data) = self.ap_access(*address, 0, true)?;
+            if !matches!(result_ack, DapAck::OkFault) {
+                return Ok(result_ack);
+            }
+            *x = data;
+            ack = result_ack;
+        }
+        Ok(ack)
+    }
+
+    fn wait_powerup(&mut self) -> Result<(), DapError> {
+        for _ in 0..POWERUP_POLL_MAX {
+            let (ack, ctrl) = self.dp_ctrlstat_read()?;
+            debug!("requesting powerup: {:?}, {:?}", ack, ctrl);
+            if ctrl.CDBGPWRUPACK() == 1 && ctrl.CSYSPWRUPACK() == 1 {
+                return Ok(());
+            }
+        }
+        error!("power-up request was not acknowledged");
+        Err(DapError::PowerUpTimeout)
+    }
+
+    fn init(&mut self) -> Result<(), DapError> {
+        let dpidr = self.probe()?;
+        let quirks = QuirkSet::from_idcode(dpidr.0);
+        if !quirks.is_empty() {
+            info!("quirks for DPIDR {:#010x}: {:?}", dpidr.0, quirks);
+        }
+        self.quirks |= quirks;
+        self.dp_select_write(0, 0, 0)?;
+
+        // debug reset
+        // let mut ctrl = CtrlStatus(0);
+        // ctrl.set_CDBGRSTREQ(1);
+        // self.dp_ctrlstat_write(ctrl);
+        // while {
+        //     let (_, ctrl) = self.dp_ctrlstat_read();
+        //     debug!("requesting reset: {:?}", ctrl);
+        //     ctrl.CDBGRSTACK() != 1
+        // } {}
+        // let ctrl = CtrlStatus(0);
+        // self.dp_ctrlstat_write(ctrl);
+
+        let (ack, ctrl) = self.dp_ctrlstat_read()?;
+        debug!("first ctrl: {:?}, {:?}", ack, ctrl);
+
+        let mut ctrl = CtrlStatus(0);
+        ctrl.set_CDBGPWRUPREQ(1);
+        ctrl.set_CSYSPWRUPREQ(1);
+        ctrl.set_STICKYERR(1);
+        self.dp_ctrlstat_write(ctrl)?;
+        let mut ctrl = CtrlStatus(0);
+        ctrl.set_CDBGPWRUPREQ(1);
+        ctrl.set_CSYSPWRUPREQ(1);
+        ctrl.set_STICKYERR(0);
+        self.dp_ctrlstat_write(ctrl)?;
+
+        // power up
+        // targetがresetされたままだとACKが返ってこないので回数を区切る
+        self.wait_powerup()?;
+        if self
+            .quirks
+            .contains(QuirkSet::MIN_IDLE_CYCLES_AFTER_POWERUP)
+        {
+            self.dp.idle(POWERUP_IDLE_CYCLES)?;
+        }
+
+        // CSW
+        let (ack, mut data) = self.memap_csw_read()?;
+        debug!(
+            "read CSW: ack: {:?}, data: {:#08x}: {:?}",
+            ack, data.0, data
+        );
+        // enable DbgSwEnable
+        data.set_DbgSwEnable(1);
+        let ack = self.memap_csw_write(data)?;
+        debug!("write CSW: ack: {:?}", ack);
+        let (ack, data) = self.memap_csw_read()?;
+        debug!(
+            "read CSW: ack: {:?}, data: {:#08x}: {:?}",
+            ack, data.0, data
+        );
+        Ok(())
+    }
+}
+
+impl<T: DapInterface> Drop for DAP<T> {
+    fn drop(&mut self) {
+        if !self.power_down_on_drop {
+            return;
+        }
+        // TAPのdropと同じく、panic中はinterfaceに触らない
+        #[cfg(feature = "std")]
+        if std::thread::panicking() {
+            warn!("skip DAP power down on drop while panicking");
+            return;
+        }
+        if self.link_lost {
+            warn!("skip DAP power down on drop: link is lost");
+            return;
+        }
+        if let Err(e) = self.power_down() {
+            warn!("failed to power down DAP on drop: {}", e);
+        }
+    }
+}
+
+impl<T: DapInterface> DapInterface for DAP<T> {
+    fn apacc(&mut self, data: u32, a: u8, RnW: bool) -> Result<(u8, u32), InterfaceError> {
+        let timer = Timer::start();
+        let (ack, result) = self.dp.apacc(data, a, RnW)?;
+        self.record(true, RnW, ack, timer);
+        self.watch_link(ack);
+        Ok((ack, result))
+    }
+    fn dpacc(&mut self, data: u32, a: u8, RnW: bool) -> Result<(u8, u32), InterfaceError> {
+        // ABORTで中断したtransactionがTARを進めたか分からない
+        if a == DpAddress::PDIDR_ABORT as u8 && !RnW {
+            self.flush_tar_cache();
+        }
+        let timer = Timer::start();
+        let (ack, result) = self.dp.dpacc(data, a, RnW)?;
+        self.record(false, RnW, ack, timer);
+        self.watch_link(ack);
+        Ok((ack, result))
+    }
+    fn ping(&mut self) -> Result<u32, DapError> {
+        self.select = None;
+        self.dp.ping()
+    }
+    fn targetsel(&mut self, targetsel: u32) -> Result<(), InterfaceError> {
+        self.dp.targetsel(targetsel)
+    }
+    fn idle(&mut self, cycles: usize) -> Result<(), InterfaceError> {
+        self.dp.idle(cycles)
+    }
+    fn abort(&mut self, data: u32) -> Result<(), InterfaceError> {
+        self.flush_tar_cache();
+        self.dp.abort(data)
+    }
+}
+
+impl<T: DapInterface> DebugPort for DAP<T> {
+    fn quirks(&self) -> QuirkSet {
+        self.quirks
+    }
+    fn max_wait_retries(&self) -> usize {
+        self.max_wait_retries
+    }
+    fn cached_select(&self) -> Option<DpSelect> {
+        self.select
+    }
+    fn set_cached_select(&mut self, select: Option<DpSelect>) {
+        self.select = select;
+    }
+    fn check_faults(&self) -> bool {
+        self.check_faults
+    }
+    fn link_lost(&self) -> bool {
+        self.link_lost
+    }
+    fn cached_dpidr(&self) -> Option<PdIdr> {
+        match self.dpidr.0 {
+            0 => None,
+            _ => Some(self.dpidr),
+        }
+    }
+}
+
+impl<T: DapInterface> MemoryAccessPort for DAP<T> {
+    fn memap(
+        &mut self,
+        address: MemapAddress,
+        data: u32,
+        read: bool,
+    ) -> Result<(DapAck, u32), DapError> {
+        let result = self.ap_access(address as u8, data, read);
+        self.track_tar(address, data, read, &result);
+        result
+    }
+    fn cached_cfg(&self) -> Option<MemApCfg> {
+        self.cfg
+    }
+    fn set_cached_cfg(&mut self, cfg: Option<MemApCfg>) {
+        self.cfg = cfg;
+    }
+    fn cached_tar(&self) -> Option<u64> {
+        self.tar
+    }
+    fn set_cached_tar(&mut self, tar: Option<u64>) {
+        self.tar = tar;
+    }
+    fn verify_tar(&self) -> bool {
+        self.verify_tar
+    }
+    #[cfg(feature = "alloc")]
+    fn check_write(&self, addr: u64, len: u64) -> Result<(), DapError> {
+        let guard = match &self.write_guard {
+            Some(guard) => guard,
+            None => return Ok(()),
+        };
+        match guard.first_denied(addr, len) {
+            None => Ok(()),
+            Some((x, region)) if self.unguarded => {
+                warn!(
+                    "unguarded write to {:#x} in protected region {} ({})",
+                    x, region.name, region.range
+                );
+                Ok(())
+            }
+            Some((x, region)) => {
+                error!(
+                    "write to {:#x} refused: protected region {} ({})",
+                    x, region.name, region.range
+                );
+                Err(DapError::WriteProtected {
+                    addr: x,
+                    region: region.range,
+                })
+            }
+        }
+    }
+}
+
+// DAP::unguardedが返す。dropでguardを戻す
+#[cfg(feature = "alloc")]
+pub struct Unguarded<'a, T: DapInterface> {
+    dap: &'a mut DAP<T>,
+}
+
+#[cfg(feature = "alloc")]
+impl<'a, T: DapInterface> Deref for Unguarded<'a, T> {
+    type Target = DAP<T>;
+    fn deref(&self) -> &DAP<T> {
+        self.dap
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<'a, T: DapInterface> DerefMut for Unguarded<'a, T> {
+    fn deref_mut(&mut self) -> &mut DAP<T> {
+        self.dap
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<'a, T: DapInterface> Drop for Unguarded<'a, T> {
+    fn drop(&mut self) {
+        self.dap.unguarded = false;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    #[cfg(feature = "std")]
+    use crate::jtag::bits;
+    use std::collections::HashMap;
+
+    // SELECT書き込み後、settle回のtransactionを無視するDP
+    // quirkの必要性と効果を確認するためのpersonality
+    struct QuirkyDp {
+        settle: usize,
+        pending: usize,
+        select: u32,
+        aborts: usize,
+        last_ap_read: u32,
+    }
+
+    impl QuirkyDp {
+        fn new(settle: usize) -> Self {
+            QuirkyDp {
+                settle,
+                pending: 0,
+                select: 0,
+                aborts: 0,
+                last_ap_read: 0,
+            }
+        }
+        fn swallowed(&mut self) -> bool {
+            if self.pending > 0 {
+                self.pending -= 1;
+                true
+            } else {
+                false
+            }
+        }
+    }
+
+    impl DapInterface for QuirkyDp {
+        fn apacc(&mut self, _data: u32, a: u8, _rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            if self.swallowed() {
+                return Ok((0, 0));
+            }
+            // bank 0xF, 0xCのIDRだけを返す
+            let address = (DpSelect(self.select).apbanksel() << 4) as u8 | (a << 2);
+            self.last_ap_read = if address == MemapAddress::IDR as u8 {
+                0x2477_0002
+            } else {
+                0
+            };
+            Ok((0x02, 0))
+        }
+        fn dpacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            if self.swallowed() {
+                return Ok((0, 0));
+            }
+            match (a, rnw) {
+                (0b00, false) => self.aborts += 1,
+                (0b10, false) => {
+                    self.select = data;
+                    self.pending = self.settle;
+                }
+                (0b11, true) => return Ok((0x02, self.last_ap_read)),
+                _ => (),
+            }
+            Ok((0x02, 0))
+        }
+    }
+
+    // メモリを持つMEM-APのモデル
+    // APACCのreadの結果はRDBUFFで読む
+    pub(crate) struct MemApSim {
+        pub select: u32,
+        pub csw: u32,
+        pub tar: u64,
+        pub memory: HashMap<u64, u32>,
+        pub rdbuff: u32,
+        pub dpidr: u32,
+        pub ctrlstat: u32,
+        // falseならpower-upを要求されてもACKを返さない
+        pub powerup_ack: bool,
+        pub tar_writes: usize,
+        pub select_writes: usize,
+        // memoryへの書き込み順
+        pub writes: Vec<(u64, u32)>,
+        // BDから読んだaddressの順
+        pub reads: Vec<u64>,
+        // APSEL毎のIDR。範囲外のAPは0を返す
+        pub ap_idrs: Vec<u32>,
+        pub cfg: u32,
+        // CFG.LAが0の場合はfaultするaccess
+        pub tar_hi_accesses: usize,
+        // このaddressへのDRW/BDの書き込みはbus errorになり、STICKYERRが立つ
+        pub poisoned: Option<u64>,
+        pub ctrlstat_reads: usize,
+        // DPBANKSELが0以外の時にCTRLSTATの位置で見えるregister
+        pub dp_banks: HashMap<u32, u32>,
+        // SELECTに書いた値の順
+        pub selects: Vec<u32>,
+        pub targetsels: Vec<u32>,
+        // trueならIDRを読んだ後にAPBANKSELが0に戻る(IDR_BANK_QUIRKのpersonality)
+        pub idr_resets_bank: bool,
+        // power-up後、このcycle数のidleが入るまでAPへのaccessを無視する
+        // (MIN_IDLE_CYCLES_AFTER_POWERUPのpersonality)
+        pub powerup_idle: usize,
+        pub idle_cycles: usize,
+        // TARhiで実装されているbit。それ以外は書いても0のまま
+        pub tar_hi_mask: u32,
+    }
+
+    impl MemApSim {
+        pub fn new() -> Self {
+            MemApSim {
+                select: 0,
+                csw: 0,
+                tar: 0,
+                memory: HashMap::new(),
+                rdbuff: 0,
+                // Cortex-A72(BCM2711)のJTAG-DP, DPv1
+                dpidr: 0x4ba0_1477,
+                ctrlstat: 0,
+                powerup_ack: true,
+                tar_writes: 0,
+                select_writes: 0,
+                writes: Vec::new(),
+                reads: Vec::new(),
+                ap_idrs: vec![0x2477_0002],
+                cfg: 0,
+                tar_hi_accesses: 0,
+                poisoned: None,
+                ctrlstat_reads: 0,
+                dp_banks: HashMap::new(),
+                selects: Vec::new(),
+                targetsels: Vec::new(),
+                idr_resets_bank: false,
+                powerup_idle: 0,
+                idle_cycles: 0,
+                tar_hi_mask: 0xffff_ffff,
+            }
+        }
+
+        fn increment(&mut self) {
+            if CSW(self.csw).addr_inc() == Some(CswAddrInc::Single) {
+                // 1KBの範囲内で回る実装を模す
+                let boundary = MEMAP_AUTOINC_BOUNDARY;
+                self.tar = (self.tar & !(boundary - 1)) | ((self.tar + 4) & (boundary - 1));
+            }
+        }
+
+        fn word(&self, address: u64) -> u32 {
+            *self.memory.get(&address).unwrap_or(&0)
+        }
+    }
+
+    impl DapInterface for MemApSim {
+        fn apacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            if self.idle_cycles < self.powerup_idle {
+                return Ok((0x02, self.rdbuff));
+            }
+            let address = (DpSelect(self.select).apbanksel() << 4) as u8 | (a << 2);
+            let bd_base = self.tar & !0xf;
+            let result = match (address, rnw) {
+                (0x00, true) => self.csw,
+                (0x00, false) => {
+                    self.csw = data;
+                    0
+                }
+                (0x04, true) => self.tar as u32,
+                (0x04, false) => {
+                    self.tar = (self.tar & !0xffff_ffff) | data as u64;
+                    self.tar_writes += 1;
+                    0
+                }
+                (0x08, true) => {
+                    self.tar_hi_accesses += 1;
+                    (self.tar >> 32) as u32
+                }
+                (0x08, false) => {
+                    self.tar_hi_accesses += 1;
+                    let data = data & self.tar_hi_mask;
+                    self.tar = (self.tar & 0xffff_ffff) | ((data as u64) << 32);
+                    0
+                }
+                (0x0C, true) => {
+                    let result = self.word(self.tar & !3);
+                    self.increment();
+                    result
+                }
+                (0x0C, false) => {
+                    // CSW.SIZEに応じたbyte laneだけを書き換える
+                    let lanes: u32 = match CSW(self.csw).size() {
+                        Some(CswSize::Byte) => 0xff,
+                        Some(CswSize::Halfword) => 0xffff,
+                        _ => 0xffff_ffff,
+                    };
+                    let mask = lanes << ((self.tar & 3) * 8);
+                    let address = self.tar & !3;
+                    if self.poisoned == Some(address) {
+                        self.ctrlstat |= 1 << 5;
+                        self.increment();
+                        return Ok((0x02, self.rdbuff));
+                    }
+                    let data = (self.word(address) & !mask) | (data & mask);
+                    self.writes.push((address, data));
+                    self.memory.insert(address, data);
+                    self.increment();
+                    0
+                }
+                (0x10..=0x1C, true) => {
+                    let address = bd_base + (address - 0x10) as u64;
+                    self.reads.push(address);
+                    self.word(address)
+                }
+                (0x10..=0x1C, false) => {
+                    let address = bd_base + (address - 0x10) as u64;
+                    if self.poisoned == Some(address) {
+                        self.ctrlstat |= 1 << 5;
+                        return Ok((0x02, self.rdbuff));
+                    }
+                    self.writes.push((address, data));
+                    self.memory.insert(address, data);
+                    0
+                }
+                (0xF4, true) => self.cfg,
+                (0xFC, true) => {
+                    let apsel = DpSelect(self.select).apsel() as usize;
+                    if self.idr_resets_bank {
+                        let mut select = DpSelect(self.select);
+                        select.set_apbanksel(0);
+                        self.select = select.0;
+                    }
+                    self.ap_idrs.get(apsel).copied().unwrap_or(0)
+                }
+                _ => 0,
+            };
+            // scanで返ってくるのは1つ前のtransactionの結果
+            let previous = self.rdbuff;
+            if rnw {
+                self.rdbuff = result;
+            }
+            Ok((0x02, previous))
+        }
+        fn dpacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            match (a, rnw) {
+                (0b00, true) => self.rdbuff = self.dpidr,
+                (0b01, true) => match DpSelect(self.select).dpbanksel() {
+                    0 => {
+                        self.ctrlstat_reads += 1;
+                        self.rdbuff = self.ctrlstat;
+                    }
+                    bank => self.rdbuff = *self.dp_banks.get(&bank).unwrap_or(&0),
+                },
+                (0b01, false) => {
+                    // REQをそのままACKに反映する
+                    let req = data & (1 << 28 | 1 << 30);
+                    self.ctrlstat = data | if self.powerup_ack { req << 1 } else { 0 };
+                }
+                (0b10, false) => {
+                    self.select = data;
+                    self.select_writes += 1;
+                    self.selects.push(data);
+                }
+                (0b00, false) => {
+                    // *CLRに対応するsticky flagを落とす
+                    let abort = Abort(data);
+                    let clear = abort.WDERRCLR() << 7
+                        | abort.STKERRCLR() << 5
+                        | abort.STKCMPCLR() << 4
+                        | abort.ORUNERRCLR() << 1;
+                    self.ctrlstat &= !clear;
+                }
+                (0b11, true) => return Ok((0x02, self.rdbuff)),
+                _ => (),
+            }
+            Ok((0x02, 0))
+        }
+        fn targetsel(&mut self, targetsel: u32) -> Result<(), InterfaceError> {
+            self.targetsels.push(targetsel);
+            Ok(())
+        }
+        fn idle(&mut self, cycles: usize) -> Result<(), InterfaceError> {
+            self.idle_cycles += cycles;
+            Ok(())
+        }
+    }
+
+    impl<T: DapInterface> DAP<T> {
+        // pingの失敗を通さずにLinkLostにする
+        pub(crate) fn debug_set_link_lost(&mut self) {
+            self.link_lost = true;
+        }
+    }
+
+    pub(crate) fn memap_dap<T: DapInterface>(dp: T) -> DAP<T> {
+        // initは電源投入待ちを行うので通さない
+        DAP {
+            dp,
+            apnum: 0,
+            quirks: QuirkSet::empty(),
+            max_wait_retries: DEFAULT_MAX_WAIT_RETRIES,
+            dpidr: PdIdr(0),
+            select: None,
+            cfg: None,
+            tar: None,
+            tar_autoinc: true,
+            verify_tar: false,
+            stats: None,
+            power_down_on_drop: false,
+            check_faults: false,
+            link_check: None,
+            invalid_acks: 0,
+            link_lost: false,
+            #[cfg(feature = "alloc")]
+            write_guard: None,
+            #[cfg(feature = "alloc")]
+            unguarded: false,
+        }
+    }
+
+    fn quirky_dap(dp: QuirkyDp, quirks: QuirkSet) -> DAP<QuirkyDp> {
+        // initは電源投入待ちを行うので通さない
+        DAP {
+            dp,
+            apnum: 0,
+            quirks,
+            max_wait_retries: DEFAULT_MAX_WAIT_RETRIES,
+            dpidr: PdIdr(0),
+            select: None,
+            cfg: None,
+            tar: None,
+            tar_autoinc: true,
+            verify_tar: false,
+            stats: None,
+            power_down_on_drop: false,
+            check_faults: false,
+            link_check: None,
+            invalid_acks: 0,
+            link_lost: false,
+            #[cfg(feature = "alloc")]
+            write_guard: None,
+            #[cfg(feature = "alloc")]
+            unguarded: false,
+        }
+    }
+
+    // ABORT以外のtransactionにwait回WAITを返してからMemApSimに渡すDP
+    struct WaitDp {
+        inner: MemApSim,
+        wait: usize,
+        remaining: usize,
+        waits: usize,
+        aborts: usize,
+    }
+
+    impl WaitDp {
+        fn new(wait: usize) -> Self {
+            WaitDp {
+                inner: MemApSim::new(),
+                wait,
+                remaining: wait,
+                waits: 0,
+                aborts: 0,
+            }
+        }
+        fn busy(&mut self) -> bool {
+            if self.remaining > 0 {
+                self.remaining -= 1;
+                self.waits += 1;
+                return true;
+            }
+            self.remaining = self.wait;
+            false
+        }
+    }
+
+    impl DapInterface for WaitDp {
+        fn apacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            if self.busy() {
+                return Ok((0x01, 0));
+            }
+            self.inner.apacc(data, a, rnw)
+        }
+        fn dpacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            if a == DpAddress::PDIDR_ABORT as u8 && !rnw {
+                self.aborts += 1;
+                self.remaining = 0;
+                return Ok((0x02, 0));
+            }
+            if self.busy() {
+                return Ok((0x01, 0));
+            }
+            self.inner.dpacc(data, a, rnw)
+        }
+    }
+
+    // scan数を数え、glitch回目のAPACC(dp_glitch回目のDPACC)でACKを化けさせるDP
+    struct GlitchDp {
+        inner: MemApSim,
+        glitch: Option<usize>,
+        dp_glitch: Option<usize>,
+        ap_scans: usize,
+        dp_scans: usize,
+    }
+
+    impl GlitchDp {
+        fn new(glitch: Option<usize>) -> Self {
+            let mut inner = MemApSim::new();
+            for i in 0..4 {
+                inner
+                    .memory
+                    .insert(0x2000 + i * 4, 0x1111_1111 * (i as u32 + 1));
+            }
+            inner.tar = 0x2000;
+            GlitchDp {
+                inner,
+                glitch,
+                dp_glitch: None,
+                ap_scans: 0,
+                dp_scans: 0,
+            }
+        }
+    }
+
+    impl DapInterface for GlitchDp {
+        fn apacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            self.ap_scans += 1;
+            if self.glitch == Some(self.ap_scans) {
+                return Ok((0x00, 0xdead_beef));
+            }
+            self.inner.apacc(data, a, rnw)
+        }
+        fn dpacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            self.dp_scans += 1;
+            if self.dp_glitch == Some(self.dp_scans) {
+                return Ok((0x00, 0xdead_beef));
+            }
+            self.inner.dpacc(data, a, rnw)
+        }
+    }
+
+    // TARloへの書き込みだけ、続くRDBUFFで無効なACKを返すDP
+    struct TarFaultDp {
+        inner: MemApSim,
+        tar_written: bool,
+    }
+
+    impl TarFaultDp {
+        fn new(inner: MemApSim) -> Self {
+            TarFaultDp {
+                inner,
+                tar_written: false,
+            }
+        }
+    }
+
+    impl DapInterface for TarFaultDp {
+        fn apacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            let bank = DpSelect(self.inner.select).apbanksel();
+            self.tar_written = bank == 0 && a << 2 == MemapAddress::TARlo as u8 && !rnw;
+            self.inner.apacc(data, a, rnw)
+        }
+        fn dpacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            if core::mem::take(&mut self.tar_written) {
+                return Ok((0x00, 0));
+            }
+            self.inner.dpacc(data, a, rnw)
+        }
+    }
+
+    // dead_after回のtransactionの後、ケーブルが抜けたように全て1を返すDP
+    struct DeadLinkDp {
+        inner: MemApSim,
+        dead_after: Option<usize>,
+        transactions: usize,
+        pings: usize,
+    }
+
+    impl DeadLinkDp {
+        fn new(dead_after: Option<usize>) -> Self {
+            DeadLinkDp {
+                inner: MemApSim::new(),
+                dead_after,
+                transactions: 0,
+                pings: 0,
+            }
+        }
+        fn dead(&mut self) -> bool {
+            self.transactions += 1;
+            self.dead_after.is_some_and(|n| self.transactions > n)
+        }
+    }
+
+    impl DapInterface for DeadLinkDp {
+        fn apacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            if self.dead() {
+                return Ok((0b111, 0xffff_ffff));
+            }
+            self.inner.apacc(data, a, rnw)
+        }
+        fn dpacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            if self.dead() {
+                return Ok((0b111, 0xffff_ffff));
+            }
+            self.inner.dpacc(data, a, rnw)
+        }
+        fn ping(&mut self) -> Result<u32, DapError> {
+            self.pings += 1;
+            if self.dead_after.is_some_and(|n| self.transactions > n) {
+                return Err(InterfaceError::EmptyChain.into());
+            }
+            Ok(0x4ba0_0477)
+        }
+    }
+
+    #[test]
+    fn link_check_test() {
+        let mut dap = memap_dap(DeadLinkDp::new(Some(8)));
+        dap.set_link_check(Some(3));
+        for _ in 0..4 {
+            dap.try_dp_ctrlstat_read().unwrap();
+        }
+        assert_eq!(0, dap.dp.pings);
+
+        // 3回続けてInvalidAckになった所でpingし、失敗したらLinkLostにする
+        let mut errors = Vec::new();
+        for _ in 0..4 {
+            errors.push(dap.try_dp_ctrlstat_read().unwrap_err());
+        }
+        assert_eq!(DapError::InvalidAck, errors[0]);
+        assert_eq!(DapError::LinkLost, errors[3]);
+        assert!(dap.is_link_lost());
+        assert_eq!(1, dap.dp.pings);
+        // LinkLostの後はpingし直さない
+        dap.try_dp_ctrlstat_read().unwrap_err();
+        assert_eq!(1, dap.dp.pings);
+
+        // 設定し直すまでLinkLostのまま
+        dap.set_link_check(Some(3));
+        assert!(!dap.is_link_lost());
+        assert_eq!(Err(DapError::InvalidAck), dap.try_dp_ctrlstat_read());
+    }
+
+    #[test]
+    fn link_check_disabled_test() {
+        // 無効ならpingしない
+        let mut dap = memap_dap(DeadLinkDp::new(Some(0)));
+        for _ in 0..8 {
+            assert_eq!(Err(DapError::InvalidAck), dap.try_dp_ctrlstat_read());
+        }
+        assert_eq!(0, dap.dp.pings);
 
-impl<T: DapInterface> MemoryAccessPort for DAP<T> {
-    fn memap(&mut self, address: MemapAddress, data: u32, read: bool) -> (DapAck, u32) {
-        let address = address as u8;
-        let apbanksel = (address & 0xf0) >> 4;
-        let address = (address & 0x0f) >> 2;
-        self.dp_select_write(self.apnum, apbanksel, 0);
-        self.dp.apacc(data, address, read);
-        self.dp_rdbuff_read()
+        // pingできないDPでは諦めない
+        let mut dap = memap_dap(GlitchDp::new(Some(1)));
+        dap.set_link_check(Some(1));
+        dap.dp_select_write(0, 1, 0).unwrap();
+        dap.memap(MemapAddress::BD0, 0, true).unwrap();
+        assert_eq!(1, dap.dp.ap_scans);
+        assert!(!dap.is_link_lost());
     }
-}
 
-mod tests {
-    use super::*;
+    const BD_ADDRS: [u8; 4] = [
+        MemapAddress::BD0 as u8,
+        MemapAddress::BD1 as u8,
+        MemapAddress::BD2 as u8,
+        MemapAddress::BD3 as u8,
+    ];
+    const BD_DATA: [u32; 4] = [0x1111_1111, 0x2222_2222, 0x3333_3333, 0x4444_4444];
+
+    #[test]
+    fn ap_register_test() {
+        let mut dap = memap_dap(MemApSim::new());
+        // 0xF8はbank 0xFのA[3:2]=0b10
+        dap.ap_register(2, MemapAddress::BASElo as u8, 0, true)
+            .unwrap();
+        let select = DpSelect(dap.dp.select);
+        assert_eq!((2, 0xF), (select.apsel(), select.apbanksel()));
+
+        // 0x04はbank 0のTARlo
+        dap.ap_register(2, MemapAddress::TARlo as u8, 0x1234, false)
+            .unwrap();
+        let select = DpSelect(dap.dp.select);
+        assert_eq!((2, 0x0), (select.apsel(), select.apbanksel()));
+        assert_eq!(0x1234, dap.dp.tar);
+        assert_eq!(2, dap.dp.select_writes);
+    }
+
+    #[test]
+    fn memap_base_u64_read_test() {
+        // BASEloの結果を受け取るRDBUFFだけが無効なACKを返す
+        let mut dp = GlitchDp::new(None);
+        dp.dp_glitch = Some(3);
+        let mut dap = memap_dap(dp);
+        let (ack, _) = dap.memap_base_u64_read().unwrap();
+        assert!(matches!(ack, DapAck::InvalidAck));
+        // BASEhiは読まない
+        assert_eq!(1, dap.dp.ap_scans);
+
+        let mut dap = memap_dap(GlitchDp::new(None));
+        let (ack, _) = dap.memap_base_u64_read().unwrap();
+        assert!(matches!(ack, DapAck::OkFault));
+        assert_eq!(2, dap.dp.ap_scans);
+    }
+
+    #[test]
+    fn pipelined_read_test() {
+        let mut dap = memap_dap(GlitchDp::new(None));
+        dap.dp_select_write(0, 1, 0).unwrap();
+        dap.dp.dp_scans = 0;
+        let mut buf = [0; 4];
+        let ack = dap.ap_read_pipelined(&BD_ADDRS, &mut buf).unwrap();
+        assert!(matches!(ack, DapAck::OkFault));
+        assert_eq!(BD_DATA, buf);
+        // RDBUFFは最後に1回だけ読む
+        assert_eq!((4, 1), (dap.dp.ap_scans, dap.dp.dp_scans));
+
+        // 順番を入れ替えても対応がずれない
+        let addrs = [BD_ADDRS[2], BD_ADDRS[0], BD_ADDRS[3], BD_ADDRS[2]];
+        let mut buf = [0; 4];
+        dap.ap_read_pipelined(&addrs, &mut buf).unwrap();
+        assert_eq!([BD_DATA[2], BD_DATA[0], BD_DATA[3], BD_DATA[2]], buf);
+
+        // bankが違うものは1つずつ読む
+        let addrs = [MemapAddress::BD1 as u8, MemapAddress::IDR as u8];
+        let mut buf = [0; 2];
+        dap.ap_read_pipelined(&addrs, &mut buf).unwrap();
+        assert_eq!([BD_DATA[1], 0x2477_0002], buf);
+
+        assert_eq!(
+            Err(DapError::Interface(InterfaceError::OutOfRange)),
+            dap.ap_read_pipelined(&BD_ADDRS, &mut [0; 3]).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn mem_read_block_pipelined_test() {
+        let mut dap = memap_dap(GlitchDp::new(None));
+        let mut buf = [0; 4];
+        dap.mem_read_block(0x2000, &mut buf).unwrap();
+        assert_eq!(BD_DATA, buf);
+        dap.dp.ap_scans = 0;
+        dap.dp.dp_scans = 0;
+        let mut buf = [0; 4];
+        let ack = dap.mem_read_block(0x2000, &mut buf).unwrap();
+        assert!(matches!(ack, DapAck::OkFault));
+        assert_eq!(BD_DATA, buf);
+        // CSWの確認、TAR、DRW 4回。RDBUFFはCSWとTARの後と、最後の1回だけ
+        assert_eq!((6, 3), (dap.dp.ap_scans, dap.dp.dp_scans));
+
+        // 1KB境界でTARを書き直す
+        let mut sim = MemApSim::new();
+        for i in 0..4 {
+            sim.memory.insert(0x3f8 + i * 4, i as u32);
+        }
+        let mut dap = memap_dap(sim);
+        let mut buf = [0; 4];
+        dap.mem_read_block(0x3f8, &mut buf).unwrap();
+        assert_eq!([0, 1, 2, 3], buf);
+        assert_eq!(2, dap.dp.tar_writes);
+
+        // big-endianのMEM-APではbyte順を戻す
+        let mut sim = MemApSim::new();
+        sim.cfg = 0b001;
+        sim.memory.insert(0x1000, 0x4433_2211);
+        let mut dap = memap_dap(sim);
+        let mut buf = [0; 1];
+        dap.mem_read_block(0x1000, &mut buf).unwrap();
+        assert_eq!([0x1122_3344], buf);
+    }
+
+    #[test]
+    fn mem_read_block_fallback_test() {
+        // CFG, CSWの読み書き, TARの後の3回目のDRWが化ける
+        let mut dap = memap_dap(GlitchDp::new(Some(7)));
+        let mut buf = [0; 4];
+        let ack = dap.mem_read_block(0x2000, &mut buf).unwrap();
+        assert!(matches!(ack, DapAck::OkFault));
+        assert_eq!(BD_DATA, buf);
+        // 2つ目のwordから1つずつ読み直す: TARとDRW 3回
+        assert_eq!(7 + 4, dap.dp.ap_scans);
+    }
+
+    #[test]
+    fn pipelined_read_fallback_test() {
+        // 3回目のAPACCが化けたら、2つ目から読み直す
+        let mut dap = memap_dap(GlitchDp::new(Some(3)));
+        let mut buf = [0; 4];
+        let ack = dap.ap_read_pipelined(&BD_ADDRS, &mut buf).unwrap();
+        assert!(matches!(ack, DapAck::OkFault));
+        assert_eq!(BD_DATA, buf);
+        assert_eq!(3 + 3, dap.dp.ap_scans);
+
+        // WAITは同じtransactionを再発行するだけ
+        let mut dap = memap_dap(WaitDp::new(1));
+        for (i, x) in BD_DATA.iter().enumerate() {
+            dap.dp.inner.memory.insert(i as u64 * 4, *x);
+        }
+        let mut buf = [0; 4];
+        dap.ap_read_pipelined(&BD_ADDRS, &mut buf).unwrap();
+        assert_eq!(BD_DATA, buf);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn with_tap_test() {
+        use crate::interface::mock::MockInterface;
+        use crate::jtag::jtag::tests::initialized;
+        use crate::jtag::jtag::TAP;
+        use spin::mutex::Mutex;
+
+        let jtag = Mutex::new(initialized(MockInterface::new()));
+        jtag.lock().interface.clear();
+        let mut dap = memap_dap(TAP::new(&jtag, 4));
+        dap.select = Some(DpSelect(0));
+        dap.dp.dpacc(0, 0b01, true).unwrap();
+        // vendor固有の命令で8bitのDRを読む
+        let mut dr = [false; 8];
+        dap.with_tap(|tap| tap.scan_ir_dr(0b1100, &mut dr)).unwrap();
+        assert!(dap.select.is_none());
+        dap.dp.dpacc(0, 0b01, true).unwrap();
+
+        let tdi = jtag.lock().interface.tdi_sequence();
+        let dpacc: Vec<bool> = bits::to_bools_lsb_first(Instruction::DPACC as u64, 4).collect();
+        let custom: Vec<bool> = bits::to_bools_lsb_first(0b1100, 4).collect();
+        assert_eq!(6, tdi.len());
+        assert_eq!(dpacc, tdi[0]);
+        assert_eq!(custom, tdi[2]);
+        assert_eq!(8, tdi[3].len());
+        // 割り込んだscanの後はDPACCを選び直す
+        assert_eq!(dpacc, tdi[4]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn acc_bit_packing_test() {
+        use crate::interface::mock::MockInterface;
+        use crate::jtag::jtag::tests::initialized;
+        use crate::jtag::jtag::TAP;
+        use spin::mutex::Mutex;
+
+        let jtag = Mutex::new(initialized(MockInterface::new()));
+        // ACK(OK/FAULT), 0xcafe_f00d
+        let mut tdo = vec![false, true, false];
+        tdo.extend(bits::to_bools_lsb_first(0xcafe_f00d, 32));
+        {
+            let jtag = jtag.lock();
+            // 先にIRのcaptureを読む
+            let reads = jtag.interface.reads();
+            jtag.interface.script_read(reads + 1, &tdo);
+            jtag.interface.clear();
+        }
+        let mut tap = TAP::new(&jtag, 4);
+        assert_eq!(Ok((0x02, 0xcafe_f00d)), tap.dpacc(0x1234_5678, 0b10, false));
+
+        let tdi = jtag.lock().interface.tdi_sequence();
+        // DPACCを選んでから35bitのDRをshiftする
+        let ir: Vec<bool> = bits::to_bools_lsb_first(Instruction::DPACC as u64, 4).collect();
+        assert_eq!(ir, tdi[0]);
+        let mut dr = vec![false, false, true];
+        dr.extend(bits::to_bools_lsb_first(0x1234_5678, 32));
+        assert_eq!(dr, tdi[1]);
+
+        // WAITは最初にshiftされるbitがACK[0]になる
+        let mut tdo = vec![true, false, false];
+        tdo.extend(bits::to_bools_lsb_first(0, 32));
+        {
+            let jtag = jtag.lock();
+            let reads = jtag.interface.reads();
+            jtag.interface.script_read(reads + 1, &tdo);
+        }
+        assert_eq!(Ok((0x01, 0)), tap.dpacc(0, 0b11, true));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn auto_resync_test() {
+        use crate::interface::mock::{MockCall, MockInterface};
+        use crate::jtag::jtag::tests::initialized;
+        use crate::jtag::jtag::TAP;
+        use spin::mutex::Mutex;
+
+        let jtag = Mutex::new(initialized(MockInterface::new()));
+        let mut tap = TAP::new(&jtag, 4);
+        let start = jtag.lock().interface.reads();
+        // 未登録のreadは全て0で、ACKも不正になる
+        assert_eq!(Ok((0, 0)), tap.dpacc(0, 0b11, true));
+        assert_eq!(start + 2, jtag.lock().interface.reads());
+
+        jtag.lock().set_auto_resync_on_error(true);
+        let mut tdo = vec![false, true, false];
+        tdo.extend(bits::to_bools_lsb_first(0x1234_5678, 32));
+        {
+            let jtag = jtag.lock();
+            let reads = jtag.interface.reads();
+            // resyncの後のIR, DRで正しいACKが返る
+            jtag.interface.script_read(reads + 3, &tdo);
+            jtag.interface.clear();
+        }
+        assert_eq!(Ok((0x02, 0x1234_5678)), tap.dpacc(0, 0b11, true));
+        let guard = jtag.lock();
+        assert_eq!(start + 6, guard.interface.reads());
+        // DPACCを選び直してから同じDRをもう1度shiftする
+        let tdi = guard.interface.tdi_sequence();
+        assert_eq!(4, tdi.len());
+        assert_eq!(tdi[0..2], tdi[2..4]);
+        assert!(guard
+            .interface
+            .transcript()
+            .contains(&MockCall::WriteTms(vec![true; 5])));
+        // やり直しても不正なままなら、そのACKを返す
+        drop(guard);
+        assert_eq!(Ok((0, 0)), tap.dpacc(0, 0b11, true));
+        assert_eq!(start + 10, jtag.lock().interface.reads());
+    }
+
+    #[test]
+    fn display_test() {
+        assert_eq!(
+            "CTRL/STAT{CSYSPWRUPACK CSYSPWRUPREQ CDBGPWRUPACK CDBGPWRUPREQ STICKYERR}",
+            CtrlStatus(0xf000_0020).to_string()
+        );
+        assert_eq!(
+            "CTRL/STAT{TRNCNT=3 MASKLANE=0b1111 READOK}",
+            CtrlStatus(0x0000_3f40).to_string()
+        );
+        assert_eq!(
+            "CSW{DbgSwEnable PROT=0b011 DeviceEn AddrInc=Single Size=Word}",
+            CSW(0xb000_0052).to_string()
+        );
+        // reservedの値はbitのまま出す
+        assert_eq!(
+            "CSW{TrInProg AddrInc=0b11 Size=0b111}",
+            CSW(0x0000_00b7).to_string()
+        );
+        assert_eq!(
+            "SELECT{APSEL=0x01 APBANKSEL=0xf DPBANKSEL=0x0}",
+            DpSelect(0x0100_00f0).to_string()
+        );
+        assert_eq!(
+            "DPIDR{REVISION=4 PARTNO=0xba VERSION=1 DESIGNER=0x23b}",
+            PdIdr(0x4ba0_1477).to_string()
+        );
+    }
+
+    #[test]
+    fn probe_test() {
+        let mut dap = memap_dap(MemApSim::new());
+        let dpidr = dap.probe().unwrap();
+        assert_eq!(0x4ba0_1477, dpidr.0);
+        assert_eq!(1, dap.dp_version());
+        assert_eq!(0x23b, dpidr.DESIGNER());
+        assert_eq!(0xba, dpidr.PARTNO());
+        assert_eq!(4, dpidr.REVISION());
+
+        // chainが間違っていると全て0か全て1が返ってくる
+        for invalid in [0, 0xffff_ffff].iter() {
+            let mut sim = MemApSim::new();
+            sim.dpidr = *invalid;
+            let mut dap = memap_dap(sim);
+            assert_eq!(Err(DapError::NoTarget), dap.probe().map(|x| x.0));
+            assert_eq!(0, dap.dp_version());
+        }
+        // DAP::try_newは電源投入待ちに進まずにerrorを返す
+        let mut sim = MemApSim::new();
+        sim.dpidr = 0;
+        assert_eq!(Err(DapError::NoTarget), DAP::try_new(sim).map(|_| ()));
+    }
+
+    #[test]
+    fn powerup_test() {
+        let dap = DAP::new(MemApSim::new());
+        let ctrl = CtrlStatus(dap.dp.ctrlstat);
+        assert_eq!((1, 1), (ctrl.CDBGPWRUPACK(), ctrl.CSYSPWRUPACK()));
+        // initでDbgSwEnableを立てる
+        assert_eq!(1, CSW(dap.dp.csw).DbgSwEnable());
+
+        // resetされたままのtargetではtimeoutする
+        let mut sim = MemApSim::new();
+        sim.powerup_ack = false;
+        assert_eq!(Err(DapError::PowerUpTimeout), DAP::try_new(sim).map(|_| ()));
+    }
+
+    #[test]
+    #[should_panic(expected = "DAP initialization failed")]
+    fn new_panics_on_powerup_timeout_test() {
+        let mut sim = MemApSim::new();
+        sim.powerup_ack = false;
+        DAP::new(sim);
+    }
+
+    #[test]
+    fn dp_banked_read_test() {
+        let mut sim = MemApSim::new();
+        // DPv2
+        sim.dpidr = 0x6ba0_2477;
+        sim.dp_banks.insert(2, 0x1002_9477);
+        sim.dp_banks.insert(3, 0x3000_0001);
+        sim.dp_banks.insert(4, 0);
+        let mut dap = memap_dap(sim);
+        dap.probe().unwrap();
+        dap.select_ap(1);
+        dap.try_memap(MemapAddress::CSW, 0, true).unwrap();
+        dap.dp.selects.clear();
+
+        let targetid = dap.dp_targetid_read().unwrap();
+        assert_eq!(
+            (1, 0x0029, 0x23b),
+            (
+                targetid.TREVISION(),
+                targetid.TPARTNO(),
+                targetid.TDESIGNER()
+            )
+        );
+        // APSEL/APBANKSELはそのままでDPBANKSELだけ切り替え、0に戻す
+        assert_eq!(vec![0x0100_0002, 0x0100_0000], dap.dp.selects);
+        assert_eq!(Some(DpSelect(0x0100_0000)), dap.select);
+        let dlpidr = dap.dp_dlpidr_read().unwrap();
+        assert_eq!((3, 1), (dlpidr.TINSTANCE(), dlpidr.PROTVSN()));
+        assert_eq!(0, dap.dp_eventstat_read().unwrap().EA());
+        assert_eq!(
+            vec![
+                0x0100_0002,
+                0x0100_0000,
+                0x0100_0003,
+                0x0100_0000,
+                0x0100_0004,
+                0x0100_0000
+            ],
+            dap.dp.selects
+        );
+        // CTRL/STATはbank 0のまま読める
+        let reads = dap.dp.ctrlstat_reads;
+        dap.try_dp_ctrlstat_read().unwrap();
+        assert_eq!(reads + 1, dap.dp.ctrlstat_reads);
+
+        let targetsel = TargetSel::new(targetid, dlpidr.TINSTANCE());
+        assert_eq!(0x3002_9477, targetsel.0);
+        dap.dp_targetsel_write(targetsel).unwrap();
+        assert_eq!(vec![0x3002_9477], dap.dp.targetsels);
+        assert_eq!(None, dap.select);
+    }
+
+    #[test]
+    fn dp_banked_read_version_test() {
+        // DPv1にはTARGETIDがない
+        let mut dap = memap_dap(MemApSim::new());
+        dap.probe().unwrap();
+        assert_eq!(
+            Err(DapError::UnsupportedDpVersion {
+                version: 1,
+                required: 2
+            }),
+            dap.dp_targetid_read().map(|x| x.0)
+        );
+        assert!(dap.dp.selects.is_empty());
+        assert!(dap.dp_banked_read(DpBank::Dlcr).is_ok());
+
+        // probeしていなければDPv0とみなす
+        let mut dap = memap_dap(MemApSim::new());
+        assert_eq!(
+            Err(DapError::UnsupportedDpVersion {
+                version: 0,
+                required: 1
+            }),
+            dap.dp_banked_read(DpBank::Dlcr)
+        );
+    }
 
     #[test]
     fn bitfield_test() {
@@ -453,4 +3137,771 @@ mod tests {
         select.set_dpbanksel(1);
         assert_eq!(0x0100_0011, select.0);
     }
+
+    #[test]
+    fn quirk_lookup_test() {
+        let table = [
+            QuirkEntry {
+                idcode: 0x0ba0_0477,
+                mask: 0x0fff_ffff,
+                quirks: QuirkSet::EXTRA_READ_AFTER_SELECT,
+            },
+            QuirkEntry {
+                idcode: 0x4ba0_0477,
+                mask: 0xffff_ffff,
+                quirks: QuirkSet::DOUBLE_ABORT_WRITE,
+            },
+        ];
+        // revisionは無視してマッチする
+        assert_eq!(
+            QuirkSet::EXTRA_READ_AFTER_SELECT,
+            QuirkSet::lookup(&table, 0x5ba0_0477)
+        );
+        assert_eq!(
+            QuirkSet::EXTRA_READ_AFTER_SELECT | QuirkSet::DOUBLE_ABORT_WRITE,
+            QuirkSet::lookup(&table, 0x4ba0_0477)
+        );
+        assert_eq!(QuirkSet::empty(), QuirkSet::lookup(&table, 0x4ba0_0478));
+    }
+
+    #[test]
+    fn quirk_init_test() {
+        // quirkの無いDPIDR
+        let dap = DAP::new(MemApSim::new());
+        assert_eq!(QuirkSet::empty(), dap.quirks());
+
+        // 素のArm JTAG-DP r5にはquirkを付けない
+        let mut sim = MemApSim::new();
+        sim.dpidr = 0x5ba0_0477;
+        let dap = DAP::new(sim);
+        assert_eq!(QuirkSet::empty(), dap.quirks());
+    }
+
+    // TargetConfigのquirksで指定できる
+    #[cfg(feature = "std")]
+    #[test]
+    fn quirk_config_test() {
+        let mut config = crate::config::Config::builtin("arm-usb-ocd-h").unwrap();
+        config.target.quirks = QuirkSet::DOUBLE_ABORT_WRITE;
+        let dap = DAP::new_with_config(MemApSim::new(), &config.target);
+        assert_eq!(QuirkSet::DOUBLE_ABORT_WRITE, dap.quirks());
+    }
+
+    #[test]
+    fn extra_read_after_select_quirk_test() {
+        let mut dap = quirky_dap(QuirkyDp::new(2), QuirkSet::empty());
+        let (_, idr) = dap.memap_idr_read().unwrap();
+        assert_ne!(0x2477_0002, idr);
+
+        let mut dap = quirky_dap(QuirkyDp::new(2), QuirkSet::EXTRA_READ_AFTER_SELECT);
+        let (_, idr) = dap.memap_idr_read().unwrap();
+        assert_eq!(0x2477_0002, idr);
+    }
+
+    #[test]
+    fn idr_bank_quirk_test() {
+        let mut sim = MemApSim::new();
+        sim.idr_resets_bank = true;
+        sim.cfg = 0x1;
+        let mut dap = memap_dap(sim);
+        dap.memap_idr_read().unwrap();
+        // SELECTがcacheされたままなのでbank 0のTARを読んでしまう
+        let (_, cfg) = dap.memap_cfg_read().unwrap();
+        assert_ne!(0x1, cfg.0);
+
+        let mut sim = MemApSim::new();
+        sim.idr_resets_bank = true;
+        sim.cfg = 0x1;
+        let mut dap = memap_dap(sim);
+        dap.set_quirks(QuirkSet::IDR_BANK_QUIRK);
+        dap.memap_idr_read().unwrap();
+        let (_, cfg) = dap.memap_cfg_read().unwrap();
+        assert_eq!(0x1, cfg.0);
+    }
+
+    #[test]
+    fn min_idle_cycles_after_powerup_quirk_test() {
+        // idleが足りないとinitのCSWの書き込みが無視される
+        let mut sim = MemApSim::new();
+        sim.powerup_idle = POWERUP_IDLE_CYCLES;
+        let dap = DAP::new(sim);
+        assert_eq!(0, CSW(dap.dp.csw).DbgSwEnable());
+
+        let mut sim = MemApSim::new();
+        sim.powerup_idle = POWERUP_IDLE_CYCLES;
+        let dap = DAP::new_with_quirks(sim, QuirkSet::MIN_IDLE_CYCLES_AFTER_POWERUP);
+        assert_eq!(POWERUP_IDLE_CYCLES, dap.dp.idle_cycles);
+        assert_eq!(1, CSW(dap.dp.csw).DbgSwEnable());
+    }
+
+    #[test]
+    fn double_abort_write_quirk_test() {
+        let mut dap = quirky_dap(QuirkyDp::new(0), QuirkSet::empty());
+        dap.dp_abort_write().unwrap();
+        assert_eq!(1, dap.dp.aborts);
+
+        let mut dap = quirky_dap(QuirkyDp::new(0), QuirkSet::DOUBLE_ABORT_WRITE);
+        dap.dp_abort_write().unwrap();
+        assert_eq!(2, dap.dp.aborts);
+    }
+
+    #[test]
+    fn mem_u32_test() {
+        let mut dap = memap_dap(MemApSim::new());
+        dap.mem_write_u32(0x8000_1000, 0xdead_beef).unwrap();
+        assert_eq!(Some(&0xdead_beef), dap.dp.memory.get(&0x8000_1000));
+        let (ack, data) = dap.mem_read_u32(0x8000_1000).unwrap();
+        assert!(matches!(ack, DapAck::OkFault));
+        assert_eq!(0xdead_beef, data);
+        assert_eq!(Some(CswSize::Word), CSW(dap.dp.csw).size());
+    }
+
+    #[test]
+    fn mem_u8_u16_test() {
+        let mut dap = memap_dap(MemApSim::new());
+        dap.dp.memory.insert(0x1000, 0x4433_2211);
+        assert_eq!(0x33, dap.mem_read_u8(0x1002).unwrap().1);
+        assert_eq!(0x4433, dap.mem_read_u16(0x1002).unwrap().1);
+        assert_eq!(Some(CswSize::Halfword), CSW(dap.dp.csw).size());
+
+        // 書き込みはaddressのbyte laneに置く
+        dap.mem_write_u8(0x1001, 0xaa).unwrap();
+        assert_eq!(Some(&0x4433_aa11), dap.dp.memory.get(&0x1000));
+        dap.mem_write_u16(0x1002, 0xbbcc).unwrap();
+        assert_eq!(Some(&0xbbcc_aa11), dap.dp.memory.get(&0x1000));
+
+        // 32bitアクセスに戻す
+        assert_eq!(0xbbcc_aa11, dap.mem_read_u32(0x1000).unwrap().1);
+        assert_eq!(Some(CswSize::Word), CSW(dap.dp.csw).size());
+
+        assert_eq!(
+            Err(DapError::Interface(InterfaceError::OutOfRange)),
+            dap.mem_read_u16(0x1001).map(|x| x.1)
+        );
+        assert_eq!(
+            Err(DapError::Interface(InterfaceError::OutOfRange)),
+            dap.mem_write_u16(0x1003, 0).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn mem_block_test() {
+        let mut dap = memap_dap(MemApSim::new());
+        // 0x3F8から1KB境界を越えて書く
+        let data: Vec<u32> = (0..8).map(|x| 0x1000_0000 + x).collect();
+        dap.mem_write_block(0x8000_03f8, &data).unwrap();
+        assert_eq!(Some(CswAddrInc::Single), CSW(dap.dp.csw).addr_inc());
+        for (i, x) in data.iter().enumerate() {
+            let address = 0x8000_03f8 + i as u64 * 4;
+            assert_eq!(Some(x), dap.dp.memory.get(&address), "{:#x}", address);
+        }
+
+        let mut buf = [0; 8];
+        let ack = dap.mem_read_block(0x8000_03f8, &mut buf).unwrap();
+        assert!(matches!(ack, DapAck::OkFault));
+        assert_eq!(data.as_slice(), &buf);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn mem_read_view_test() {
+        use crate::tools::memview::Endian;
+        let mut dap = memap_dap(MemApSim::new());
+        dap.dp.memory.insert(0x8000_03fc, 0x4433_2211);
+        dap.dp.memory.insert(0x8000_0400, 0x8877_6655);
+        dap.dp.memory.insert(0x8000_0404, 0xccbb_aa99);
+        // 揃っていない両端を落として、読んだ範囲だけを持つ
+        let view = dap.try_mem_read_view(0x8000_03fd, 7).unwrap();
+        assert_eq!(0x8000_03fd, view.base());
+        assert_eq!(&[0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88], view.bytes());
+        assert_eq!(Ok(0x7766_5544), view.u32_at(0x8000_03ff, Endian::Little));
+        assert_eq!(Ok(0x5566), view.u16_at(0x8000_0400, Endian::Big));
+        assert_eq!(
+            Err(InterfaceError::OutOfRange),
+            view.u16_at(0x8000_0403, Endian::Little)
+        );
+        assert!(dap.try_mem_read_view(0x8000_0000, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn mem_fill_test() {
+        // 両端が揃っておらず、0x3FC -> 0x400の境界を跨ぐ
+        let mut dap = memap_dap(MemApSim::new());
+        dap.dp.memory.insert(0x8000_03f0, 0xaaaa_aaaa);
+        dap.dp.memory.insert(0x8000_0410, 0xbbbb_bbbb);
+        dap.mem_fill(0x8000_03f2, 0x20, 0x4433_2211).unwrap();
+        assert_eq!(Some(&0x4433_aaaa), dap.dp.memory.get(&0x8000_03f0));
+        for address in (0x8000_03f4..0x8000_0410).step_by(4) {
+            assert_eq!(
+                Some(&0x4433_2211),
+                dap.dp.memory.get(&address),
+                "{:#x}",
+                address
+            );
+        }
+        assert_eq!(Some(&0xbbbb_2211), dap.dp.memory.get(&0x8000_0410));
+        // 境界を跨いだ後にwrapして0x8000_0000を書いていない
+        assert_eq!(None, dap.dp.memory.get(&0x8000_0000));
+        assert_eq!(None, dap.mem_compare(0x8000_03f2, &[0x33, 0x44]).unwrap());
+
+        // 64回書く毎と最後にだけCTRL/STATを読む
+        let mut dap = memap_dap(MemApSim::new());
+        dap.mem_fill(0x1000, 256 * 4, 0).unwrap();
+        assert_eq!(256, dap.dp.writes.len());
+        assert_eq!(4, dap.dp.ctrlstat_reads);
+        dap.dp.ctrlstat_reads = 0;
+        dap.mem_fill(0x1000, 100 * 4, 0).unwrap();
+        assert_eq!(2, dap.dp.ctrlstat_reads);
+        dap.dp.ctrlstat_reads = 0;
+        dap.mem_fill(0x1001, 2, 0).unwrap();
+        assert_eq!(1, dap.dp.ctrlstat_reads);
+
+        // bus errorは次の確認で見つかる
+        let mut sim = MemApSim::new();
+        sim.poisoned = Some(0x1000 + 10 * 4);
+        let mut dap = memap_dap(sim);
+        match dap.mem_fill(0x1000, 256 * 4, 0) {
+            Err(DapError::Fault { sticky }) => assert_eq!(1, sticky.STICKYERR()),
+            _ => panic!("bus error was not reported"),
+        }
+        assert_eq!(63, dap.dp.writes.len());
+        assert_eq!(0, dap.dp.ctrlstat & (1 << 5));
+    }
+
+    #[test]
+    fn mem_compare_test() {
+        let mut dap = memap_dap(MemApSim::new());
+        let expected: Vec<u8> = (0..0x40).map(|x| x as u8).collect();
+        let words: Vec<u32> = expected
+            .chunks(4)
+            .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+            .collect();
+        dap.mem_write_block(0x8000_03e0, &words).unwrap();
+        assert_eq!(None, dap.mem_compare(0x8000_03e0, &expected).unwrap());
+        // 揃っていない両端
+        assert_eq!(
+            None,
+            dap.mem_compare(0x8000_03e3, &expected[3..0x3d]).unwrap()
+        );
+
+        // 1KB境界の後の最初の不一致
+        dap.dp.memory.insert(0x8000_0400, 0x2322_ff20);
+        assert_eq!(
+            Some(0x8000_0401),
+            dap.mem_compare(0x8000_03e0, &expected).unwrap()
+        );
+        // 範囲外の不一致は見ない
+        assert_eq!(
+            None,
+            dap.mem_compare(0x8000_03e0, &expected[..0x21]).unwrap()
+        );
+        assert_eq!(None, dap.mem_compare(0x8000_0000, &[]).unwrap());
+    }
+
+    // 呼んだthreadで出たlogを集める。testは並列に走るのでthread毎に分ける
+    #[cfg(feature = "std")]
+    pub(crate) fn capture_logs<R>(f: impl FnOnce() -> R) -> (R, Vec<(log::Level, String)>) {
+        use std::cell::RefCell;
+        use std::sync::Once;
+
+        thread_local! {
+            static CAPTURED: RefCell<Option<Vec<(log::Level, String)>>> = const { RefCell::new(None) };
+        }
+        struct Capture;
+        impl log::Log for Capture {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+            fn log(&self, record: &log::Record) {
+                CAPTURED.with(|x| {
+                    if let Some(logs) = x.borrow_mut().as_mut() {
+                        logs.push((record.level(), record.args().to_string()));
+                    }
+                });
+            }
+            fn flush(&self) {}
+        }
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&Capture).ok();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+
+        CAPTURED.with(|x| *x.borrow_mut() = Some(Vec::new()));
+        let result = f();
+        let logs = CAPTURED.with(|x| x.borrow_mut().take()).unwrap_or_default();
+        (result, logs)
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn write_guard_test() {
+        let pmic = AddrRange::new(0x7e80_4000, 0x100).unwrap();
+        let mut dap = memap_dap(MemApSim::new());
+        dap.set_write_guard(WriteGuard::new().deny("pmic", pmic));
+
+        // 先頭と最後のbyteに掛かる書き込みは何も発行せずに断る
+        let protected = |addr| Err(DapError::WriteProtected { addr, region: pmic });
+        assert_eq!(
+            protected(0x7e80_4000),
+            dap.mem_write_u32(0x7e80_3ffe, 0).map(|_| ())
+        );
+        assert_eq!(
+            protected(0x7e80_4000),
+            dap.mem_write_u8(0x7e80_4000, 0).map(|_| ())
+        );
+        assert_eq!(
+            protected(0x7e80_40ff),
+            dap.mem_write_u8(0x7e80_40ff, 0).map(|_| ())
+        );
+        assert_eq!(
+            protected(0x7e80_40fe),
+            dap.mem_write_u16(0x7e80_40fe, 0).map(|_| ())
+        );
+        assert_eq!(
+            protected(0x7e80_4000),
+            dap.try_mem_write_block(0x7e80_3ff0, &[0; 8])
+        );
+        assert_eq!(protected(0x7e80_40ff), dap.mem_fill(0x7e80_40ff, 0x10, 0));
+        assert!(dap.dp.writes.is_empty());
+        assert_eq!(0, dap.dp.tar_writes);
+
+        // 範囲の外側は書ける
+        dap.try_mem_write_u32(0x7e80_3ffc, 1).unwrap();
+        dap.try_mem_write_u32(0x7e80_4100, 2).unwrap();
+        dap.mem_fill(0x7e80_4104, 0x10, 3).unwrap();
+        assert_eq!(Some(&1), dap.dp.memory.get(&0x7e80_3ffc));
+        assert_eq!(Some(&2), dap.dp.memory.get(&0x7e80_4100));
+
+        // 読み出しは止めない
+        dap.dp.memory.insert(0x7e80_4000, 0x1234_5678);
+        assert_eq!(0x1234_5678, dap.try_mem_read_u32(0x7e80_4000).unwrap());
+        let mut buf = [0; 2];
+        dap.try_mem_read_block(0x7e80_3ffc, &mut buf).unwrap();
+        assert_eq!([1, 0x1234_5678], buf);
+
+        // 断る範囲の無いguardは外したのと同じ
+        dap.set_write_guard(WriteGuard::new());
+        assert!(dap.write_guard().is_none());
+        dap.try_mem_write_u32(0x7e80_4000, 4).unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn unguarded_test() {
+        let pmic = AddrRange::new(0x7e80_4000, 0x100).unwrap();
+        let mut dap = memap_dap(MemApSim::new());
+        dap.set_write_guard(WriteGuard::new().deny("pmic", pmic));
+
+        let (result, logs) = capture_logs(|| dap.unguarded().try_mem_write_u32(0x7e80_40fc, 5));
+        result.unwrap();
+        assert_eq!(Some(&5), dap.dp.memory.get(&0x7e80_40fc));
+        let warning = logs
+            .iter()
+            .find(|(level, _)| *level == log::Level::Warn)
+            .expect("unguarded write was not logged");
+        assert!(warning.1.contains("pmic"), "{}", warning.1);
+
+        // unguarded()を通したのはその1回だけ
+        assert!(matches!(
+            dap.try_mem_write_u32(0x7e80_40fc, 6),
+            Err(DapError::WriteProtected { .. })
+        ));
+        assert_eq!(Some(&5), dap.dp.memory.get(&0x7e80_40fc));
+
+        // protectedでない範囲ではwarnを出さない
+        let (result, logs) = capture_logs(|| dap.unguarded().try_mem_write_u32(0x1000, 7));
+        result.unwrap();
+        assert!(logs.iter().all(|(level, _)| *level != log::Level::Warn));
+    }
+
+    #[test]
+    fn memap_cfg_test() {
+        // LAなし: TARhiには触らず、4GBを越えるaddressはerror
+        let mut dap = memap_dap(MemApSim::new());
+        assert_eq!(0, dap.memap_capabilities().unwrap().LA());
+        dap.mem_write_u32(0x8000_0010, 0x1234_5678).unwrap();
+        let (ack, data) = dap.mem_read_u32(0x8000_0010).unwrap();
+        assert!(matches!(ack, DapAck::OkFault));
+        assert_eq!(0x1234_5678, data);
+        assert_eq!(
+            Err(DapError::Interface(InterfaceError::OutOfRange)),
+            dap.mem_read_u32(0x1_0000_0000).map(|(_, x)| x)
+        );
+        assert_eq!(0, dap.dp.tar_hi_accesses);
+
+        // LAあり: TARhiも書く
+        let mut sim = MemApSim::new();
+        sim.cfg = 0b010;
+        let mut dap = memap_dap(sim);
+        dap.mem_write_u32(0x1_0000_0010, 0xdead_beef).unwrap();
+        assert_eq!(Some(&0xdead_beef), dap.dp.memory.get(&0x1_0000_0010));
+        assert_eq!(0xdead_beef, dap.mem_read_u32(0x1_0000_0010).unwrap().1);
+        assert!(dap.dp.tar_hi_accesses > 0);
+        // CFGは1回だけ読む
+        let select_writes = dap.dp.select_writes;
+        dap.memap_capabilities().unwrap();
+        assert_eq!(select_writes, dap.dp.select_writes);
+
+        // BE: DRWのbyte順を入れ替える
+        let mut sim = MemApSim::new();
+        sim.cfg = 0b001;
+        let mut dap = memap_dap(sim);
+        dap.mem_write_u32(0x8000_0000, 0x1122_3344).unwrap();
+        assert_eq!(Some(&0x4433_2211), dap.dp.memory.get(&0x8000_0000));
+        assert_eq!(0x1122_3344, dap.mem_read_u32(0x8000_0000).unwrap().1);
+
+        // APを切り替えたら読み直す
+        dap.select_ap(1);
+        assert_eq!(None, dap.cached_cfg());
+    }
+
+    #[test]
+    fn tar_cache_test() {
+        let mut sim = MemApSim::new();
+        sim.memory.insert(0x8000_0000, 1);
+        sim.memory.insert(0x8000_0004, 2);
+        let mut dap = memap_dap(sim);
+        for _ in 0..3 {
+            assert_eq!(1, dap.mem_read_u32(0x8000_0000).unwrap().1);
+        }
+        assert_eq!(1, dap.dp.tar_writes);
+
+        // 自動インクリメントでTARが進むので、同じaddressでも書き直す
+        let mut buf = [0u32; 2];
+        dap.mem_read_block(0x8000_0000, &mut buf).unwrap();
+        assert_eq!([1, 2], buf);
+        dap.dp.tar_writes = 0;
+        assert_eq!(1, dap.mem_read_u32(0x8000_0000).unwrap().1);
+        assert_eq!(1, dap.dp.tar_writes);
+        assert!(dap
+            .mem_compare(0x8000_0000, &[1, 0, 0, 0])
+            .unwrap()
+            .is_none());
+        assert_eq!(1, dap.mem_read_u32(0x8000_0000).unwrap().1);
+        assert_eq!(3, dap.dp.tar_writes);
+
+        // ABORTやAPの切り替えの後も書き直す
+        dap.dp_abort_write().unwrap();
+        dap.mem_read_u32(0x8000_0000).unwrap();
+        assert_eq!(4, dap.dp.tar_writes);
+        dap.select_ap(1);
+        dap.select_ap(0);
+        dap.mem_read_u32(0x8000_0000).unwrap();
+        assert_eq!(5, dap.dp.tar_writes);
+
+        // 読み返しはset_verify_tarで有効にした時だけ
+        let mut sim = MemApSim::new();
+        sim.cfg = 0b010;
+        let mut dap = memap_dap(sim);
+        dap.mem_read_u32(0x1_0000_0000).unwrap();
+        assert_eq!(1, dap.dp.tar_hi_accesses);
+        dap.set_verify_tar(true);
+        dap.mem_read_u32(0x1_0000_0010).unwrap();
+        assert_eq!(3, dap.dp.tar_hi_accesses);
+    }
+
+    #[test]
+    fn tar_mismatch_test() {
+        // TARhiの下位8bitしか実装していないMEM-AP
+        let mut sim = MemApSim::new();
+        sim.cfg = 0b010;
+        sim.tar_hi_mask = 0xff;
+        let mut dap = memap_dap(sim);
+        dap.set_verify_tar(true);
+        dap.mem_read_u32(0x10_0000_0000).unwrap();
+        assert_eq!(Some(0x10_0000_0000), dap.cached_tar());
+
+        assert_eq!(
+            Err(DapError::TarMismatch {
+                expected: 0x100_0000_0010,
+                found: 0x10,
+            }),
+            dap.mem_read_u32(0x100_0000_0010).map(|(_, x)| x)
+        );
+        assert_eq!(None, dap.cached_tar());
+        // cacheを捨てたので、同じaddressでも書き直す
+        let tar_writes = dap.dp.tar_writes;
+        assert!(dap.mem_read_u32(0x100_0000_0010).is_err());
+        assert_eq!(tar_writes + 1, dap.dp.tar_writes);
+    }
+
+    #[test]
+    fn tar_ack_test() {
+        // TARが書けなければDRWには触らずにそのACKを返す
+        let mut sim = MemApSim::new();
+        sim.memory.insert(0x1000, 0x1234_5678);
+        let mut dap = memap_dap(TarFaultDp::new(sim));
+        let (ack, data) = dap.mem_read_u32(0x1000).unwrap();
+        assert!(matches!(ack, DapAck::InvalidAck));
+        assert_eq!(0, data);
+        let ack = dap.mem_write_u32(0x1000, 1).unwrap();
+        assert!(matches!(ack, DapAck::InvalidAck));
+
+        let mut buf = [0; 4];
+        let ack = dap.mem_read_block(0x1000, &mut buf).unwrap();
+        assert!(matches!(ack, DapAck::InvalidAck));
+        assert_eq!([0; 4], buf);
+        let ack = dap.mem_write_block(0x1000, &[1, 2, 3, 4]).unwrap();
+        assert!(matches!(ack, DapAck::InvalidAck));
+
+        // 8/16bitも同じ
+        let (ack, data) = dap.mem_read_u8(0x1001).unwrap();
+        assert!(matches!(ack, DapAck::InvalidAck));
+        assert_eq!(0, data);
+        let (ack, data) = dap.mem_read_u16(0x1002).unwrap();
+        assert!(matches!(ack, DapAck::InvalidAck));
+        assert_eq!(0, data);
+        let ack = dap.mem_write_u8(0x1001, 0xff).unwrap();
+        assert!(matches!(ack, DapAck::InvalidAck));
+        let ack = dap.mem_write_u16(0x1002, 0xffff).unwrap();
+        assert!(matches!(ack, DapAck::InvalidAck));
+
+        assert!(dap.dp.inner.writes.is_empty());
+        assert_eq!(Some(&0x1234_5678), dap.dp.inner.memory.get(&0x1000));
+    }
+
+    #[test]
+    fn select_cache_test() {
+        let mut dap = memap_dap(MemApSim::new());
+        for _ in 0..10 {
+            dap.memap_bd0_read().unwrap();
+        }
+        assert_eq!(1, dap.dp.select_writes);
+        // bankが変わる時だけ書く
+        dap.memap_csw_read().unwrap();
+        dap.memap_csw_read().unwrap();
+        assert_eq!(2, dap.dp.select_writes);
+        dap.select_ap(1);
+        dap.memap_csw_read().unwrap();
+        assert_eq!(3, dap.dp.select_writes);
+
+        dap.dp_abort_write().unwrap();
+        dap.memap_csw_read().unwrap();
+        assert_eq!(4, dap.dp.select_writes);
+        dap.flush_select_cache();
+        dap.memap_csw_read().unwrap();
+        assert_eq!(5, dap.dp.select_writes);
+    }
+
+    #[test]
+    fn wait_retry_test() {
+        let mut dap = memap_dap(WaitDp::new(3));
+        dap.dp.inner.memory.insert(0x1000, 0x1234_5678);
+        let (ack, data) = dap.mem_read_u32(0x1000).unwrap();
+        assert!(matches!(ack, DapAck::OkFault));
+        assert_eq!(0x1234_5678, data);
+        assert!(dap.dp.waits > 0);
+        assert_eq!(0, dap.dp.aborts);
+    }
+
+    #[test]
+    fn wait_retry_exhausted_test() {
+        let mut dap = memap_dap(WaitDp::new(3));
+        dap.set_max_wait_retries(2);
+        assert_eq!(
+            Err(DapError::WaitTimeout),
+            dap.dp_rdbuff_read().map(|(_, x)| x)
+        );
+        assert_eq!(3, dap.dp.waits);
+        assert_eq!(1, dap.dp.aborts);
+
+        assert_eq!(
+            Err(DapError::WaitTimeout),
+            dap.mem_read_u32(0x1000).map(|(_, x)| x)
+        );
+        assert_eq!(Err(DapError::WaitTimeout), dap.try_mem_read_u32(0x1000));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn wait_retry_abort_ir_test() {
+        use crate::interface::replay::tests::SimDap;
+        use crate::jtag::jtag::Jtag;
+        use spin::mutex::Mutex;
+
+        let mut jtag = Jtag::new(SimDap::new());
+        jtag.scan().unwrap();
+        let jtag = Mutex::new(jtag);
+        let mut dap = DAP::new(TAP::new(&jtag, 4));
+        dap.set_max_wait_retries(2);
+        jtag.lock().interface.set_ap_wait(true);
+        assert_eq!(
+            Err(DapError::WaitTimeout),
+            dap.memap_idr_read().map(|(_, x)| x)
+        );
+        // DPACCではなくABORT命令でDAPABORTを書く
+        assert_eq!(vec![ABORT_DAPABORT], jtag.lock().interface.aborts());
+    }
+
+    #[test]
+    fn stats_test() {
+        let mut dap = memap_dap(WaitDp::new(1));
+        dap.dp_rdbuff_read().unwrap();
+        // 有効にするまでは数えない
+        assert_eq!(DapStats::default(), dap.stats());
+
+        dap.set_collect_stats(true);
+        // 全てのaccessが1回WAITになる
+        dap.dp_rdbuff_read().unwrap();
+        // SELECT, RDBUFF, APACC, RDBUFF
+        dap.memap_csw_write(CSW(0)).unwrap();
+        let stats = dap.stats();
+        assert_eq!(
+            (6, 2, 0, 2, 5),
+            (
+                stats.dp_reads,
+                stats.dp_writes,
+                stats.ap_reads,
+                stats.ap_writes,
+                stats.wait_acks
+            )
+        );
+        let text = format!("{}", stats);
+        assert!(text.contains("wait acks: 5"), "{}", text);
+
+        dap.reset_stats();
+        assert_eq!(DapStats::default(), dap.stats());
+        dap.set_collect_stats(false);
+        dap.dp_rdbuff_read().unwrap();
+        assert_eq!(DapStats::default(), dap.stats());
+    }
+
+    // dropした後もDPへの書き込みを確認できるDP
+    struct SharedDp {
+        inner: MemApSim,
+        dp_writes: std::rc::Rc<std::cell::RefCell<Vec<(u8, u32)>>>,
+    }
+
+    impl DapInterface for SharedDp {
+        fn apacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            self.inner.apacc(data, a, rnw)
+        }
+        fn dpacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            if !rnw {
+                self.dp_writes.borrow_mut().push((a, data));
+            }
+            self.inner.dpacc(data, a, rnw)
+        }
+    }
+
+    #[test]
+    fn power_down_on_drop_test() {
+        let dp_writes = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let shared = || SharedDp {
+            inner: MemApSim::new(),
+            dp_writes: dp_writes.clone(),
+        };
+        drop(DAP::new(shared()));
+        let ctrlstat = DpAddress::CTRLSTAT as u8;
+        let last = dp_writes.borrow().last().copied();
+        assert_ne!(Some((ctrlstat, 0)), last);
+
+        let mut dap = DAP::new(shared());
+        dap.set_power_down_on_drop(true);
+        dp_writes.borrow_mut().clear();
+        drop(dap);
+        assert_eq!(vec![(ctrlstat, 0)], *dp_writes.borrow());
+    }
+
+    #[test]
+    fn power_down_on_drop_link_lost_test() {
+        let dp_writes = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut dap = DAP::new(SharedDp {
+            inner: MemApSim::new(),
+            dp_writes: dp_writes.clone(),
+        });
+        dap.set_power_down_on_drop(true);
+        dap.debug_set_link_lost();
+        dp_writes.borrow_mut().clear();
+        drop(dap);
+        assert!(dp_writes.borrow().is_empty());
+    }
+
+    #[test]
+    fn check_faults_test() {
+        let mut sim = MemApSim::new();
+        sim.poisoned = Some(0x2000);
+        let mut dap = memap_dap(sim);
+
+        // check_faultsなしではOKと区別できない
+        dap.try_mem_write_u32(0x2000, 0xdead_beef).unwrap();
+        assert_eq!(1, dap.dp.ctrlstat >> 5 & 1);
+        dap.dp.ctrlstat = 0;
+
+        dap.set_check_faults(true);
+        dap.try_mem_write_u32(0x1000, 0x1234_5678).unwrap();
+        let selects = dap.dp.select_writes;
+        match dap.try_mem_write_u32(0x2000, 0xdead_beef) {
+            Err(DapError::Fault { sticky }) => {
+                assert_eq!(
+                    (1, 0, 0),
+                    (sticky.STICKYERR(), sticky.STICKYORUN(), sticky.WDATAERR())
+                )
+            }
+            x => panic!("unexpected result: {:?}", x),
+        }
+        // ABORTでSTICKYERRを落とし、SELECTは書き直す
+        assert_eq!(0, dap.try_dp_ctrlstat_read().unwrap().STICKYERR());
+        assert_eq!(None, dap.select);
+        assert_eq!(0x1234_5678, dap.try_mem_read_u32(0x1000).unwrap());
+        assert_eq!(selects + 1, dap.dp.select_writes);
+        assert_eq!(None, dap.dp.memory.get(&0x2000));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn enumerate_aps_test() {
+        // APB-AP(debug), AHB-AP(system memory)
+        let mut sim = MemApSim::new();
+        sim.ap_idrs = vec![0x4477_0002, 0x8477_0001, 0];
+        let mut dap = memap_dap(sim);
+        let aps = dap.enumerate_aps(8).unwrap();
+        assert_eq!(
+            vec![
+                ApInfo::MemAp {
+                    bus: MemApBus::Apb,
+                    designer: 0x23b
+                },
+                ApInfo::MemAp {
+                    bus: MemApBus::Ahb,
+                    designer: 0x23b
+                },
+            ],
+            aps
+        );
+        // 0のIDRで止まり、それ以降は読まない
+        assert_eq!(2, DpSelect(dap.dp.select).apsel());
+
+        // maxで止まる
+        let mut sim = MemApSim::new();
+        sim.ap_idrs = vec![0x4477_0002; 4];
+        let mut dap = memap_dap(sim);
+        assert_eq!(2, dap.enumerate_aps(2).unwrap().len());
+
+        // 選んだAPにアクセスする
+        dap.select_ap(1);
+        dap.mem_read_u32(0x1000).unwrap();
+        assert_eq!(1, DpSelect(dap.dp.select).apsel());
+    }
+
+    #[test]
+    fn ap_info_test() {
+        assert_eq!(
+            ApInfo::MemAp {
+                bus: MemApBus::Axi,
+                designer: 0x23b
+            },
+            ApInfo::from(ApIdr(0x5477_0004))
+        );
+        assert_eq!(
+            ApInfo::JtagAp { designer: 0x23b },
+            ApInfo::from(ApIdr(0x2476_0010))
+        );
+        // MEM-APだがbusが分からない
+        assert_eq!(
+            ApInfo::Unknown(ApIdr(0x2477_000f)),
+            ApInfo::from(ApIdr(0x2477_000f))
+        );
+    }
 }
@@ -0,0 +1,69 @@
+// targetの各型で共有するDAPへのhandle
+// stdではArc<std::sync::Mutex>で持つので、threadへmoveでき、lock中にpanicしてもdeadlockしない
+// allocなしのno_stdではstaticなspin::Mutexを指す
+#[cfg(feature = "alloc")]
+use alloc::sync::Arc;
+#[cfg(not(feature = "alloc"))]
+use core::ptr::NonNull;
+#[cfg(feature = "std")]
+use log::warn;
+#[cfg(not(feature = "std"))]
+use spin::mutex::Mutex;
+#[cfg(feature = "std")]
+use std::sync::Mutex;
+
+#[cfg(feature = "std")]
+pub type DapGuard<'a, T> = std::sync::MutexGuard<'a, T>;
+#[cfg(not(feature = "std"))]
+pub type DapGuard<'a, T> = spin::mutex::MutexGuard<'a, T>;
+
+pub struct DapHandle<T> {
+    #[cfg(feature = "alloc")]
+    inner: Arc<Mutex<T>>,
+    // &'static Mutex<T>で持つと全ての型にT: 'staticが要るので、pointerにしておく
+    // from_staticからしか作らないので、指す先は常に有効
+    #[cfg(not(feature = "alloc"))]
+    inner: NonNull<Mutex<T>>,
+}
+
+impl<T> DapHandle<T> {
+    #[cfg(feature = "alloc")]
+    pub fn new(dap: T) -> Self {
+        DapHandle {
+            inner: Arc::new(Mutex::new(dap)),
+        }
+    }
+
+    #[cfg(not(feature = "alloc"))]
+    pub fn from_static(dap: &'static Mutex<T>) -> Self {
+        DapHandle {
+            inner: NonNull::from(dap),
+        }
+    }
+
+    // 他のthreadがlock中にpanicしても、DAPはそのまま使い続ける
+    #[cfg(feature = "std")]
+    pub fn lock(&self) -> DapGuard<'_, T> {
+        self.inner.lock().unwrap_or_else(|e| {
+            warn!("DAP lock was poisoned by a panicked thread");
+            e.into_inner()
+        })
+    }
+
+    #[cfg(not(feature = "std"))]
+    pub fn lock(&self) -> DapGuard<'_, T> {
+        unsafe { self.inner.as_ref() }.lock()
+    }
+}
+
+// Tを複製せずにhandleだけを増やす
+impl<T> Clone for DapHandle<T> {
+    fn clone(&self) -> Self {
+        DapHandle {
+            #[cfg(feature = "alloc")]
+            inner: Arc::clone(&self.inner),
+            #[cfg(not(feature = "alloc"))]
+            inner: self.inner,
+        }
+    }
+}
@@ -0,0 +1,241 @@
+// JTAG-APの先に繋がったlegacyなTAPを叩く
+// APのbyte FIFOへTMS/TDIのpacketを書き、TDOのbyteを読み出す
+use bitfield::bitfield;
+use log::warn;
+
+use super::{DapError, DapInterface, DAP};
+use crate::interface::InterfaceError;
+
+#[allow(dead_code)]
+#[repr(u8)]
+enum JtagApAddress {
+    CSW = 0x00,
+    PSEL = 0x04,
+    PSTA = 0x08,
+    // BFIFOnはnbyteを一度に読み書きする
+    BFIFO1 = 0x10,
+    BFIFO2 = 0x14,
+    BFIFO3 = 0x18,
+    BFIFO4 = 0x1C,
+    IDR = 0xFC,
+}
+
+// JTAG-APが持つport数
+pub const JTAG_AP_PORTS: u8 = 8;
+
+bitfield! {
+    #[derive(Clone, Copy)]
+    pub struct JtagApCsw(u32);
+    impl Debug;
+    pub SERACTV, _: 31, 31;
+    pub WFIFOCNT, _: 30, 28;
+    pub RFIFOCNT, _: 26, 24;
+    pub PORTCONNECTED, _: 3, 3;
+    pub SRSTCONNECTED, _: 2, 2;
+    pub TRST_OUT, set_TRST_OUT: 1, 1;
+    pub SRST_OUT, set_SRST_OUT: 0, 0;
+}
+
+// 4byteまでをBFIFOnの1回のaccessにする
+fn bfifo(length: usize) -> u8 {
+    JtagApAddress::BFIFO1 as u8 + (length as u8 - 1) * 4
+}
+
+fn pack_word(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .enumerate()
+        .fold(0, |x, (i, y)| x | (*y as u32) << (i * 8))
+}
+
+pub struct JtagAp<'a, T: DapInterface> {
+    dap: &'a mut DAP<T>,
+    apsel: u8,
+}
+
+impl<'a, T: DapInterface> JtagAp<'a, T> {
+    pub fn new(dap: &'a mut DAP<T>, apsel: u8) -> Self {
+        JtagAp { dap, apsel }
+    }
+
+    fn read(&mut self, address: JtagApAddress) -> Result<u32, DapError> {
+        self.dap.ap_register(self.apsel, address as u8, 0, true)
+    }
+
+    fn write(&mut self, address: JtagApAddress, data: u32) -> Result<(), DapError> {
+        self.dap
+            .ap_register(self.apsel, address as u8, data, false)?;
+        Ok(())
+    }
+
+    pub fn idr_read(&mut self) -> Result<u32, DapError> {
+        self.read(JtagApAddress::IDR)
+    }
+
+    pub fn csw_read(&mut self) -> Result<JtagApCsw, DapError> {
+        Ok(JtagApCsw(self.read(JtagApAddress::CSW)?))
+    }
+
+    pub fn csw_write(&mut self, csw: JtagApCsw) -> Result<(), DapError> {
+        self.write(JtagApAddress::CSW, csw.0)
+    }
+
+    // portを1つだけ選ぶ。選んだportに何も繋がっていなければNoTarget
+    pub fn select_port(&mut self, port: u8) -> Result<(), DapError> {
+        if port >= JTAG_AP_PORTS {
+            return Err(InterfaceError::OutOfRange.into());
+        }
+        self.write(JtagApAddress::PSEL, 1 << port)?;
+        if self.csw_read()?.PORTCONNECTED() == 0 {
+            warn!("JTAG-AP{} port {} is not connected", self.apsel, port);
+            return Err(DapError::NoTarget);
+        }
+        Ok(())
+    }
+
+    // portが切断されたことを示すsticky bit。1を書くと落ちる
+    pub fn port_status(&mut self) -> Result<u8, DapError> {
+        Ok(self.read(JtagApAddress::PSTA)? as u8)
+    }
+
+    pub fn clear_port_status(&mut self, ports: u8) -> Result<(), DapError> {
+        self.write(JtagApAddress::PSTA, ports as u32)
+    }
+
+    // trueでassertし、falseで解除するまで保持する
+    pub fn set_trst(&mut self, level: bool) -> Result<(), DapError> {
+        let mut csw = self.csw_read()?;
+        csw.set_TRST_OUT(level as u32);
+        self.csw_write(csw)
+    }
+
+    pub fn set_srst(&mut self, level: bool) -> Result<(), DapError> {
+        let mut csw = self.csw_read()?;
+        csw.set_SRST_OUT(level as u32);
+        self.csw_write(csw)
+    }
+
+    // FIFOが一杯の間はAPがWAITを返すので、DAPの再試行に任せる
+    pub fn write_bytes(&mut self, data: &[u8]) -> Result<(), DapError> {
+        for chunk in data.chunks(4) {
+            self.dap
+                .ap_register(self.apsel, bfifo(chunk.len()), pack_word(chunk), false)?;
+        }
+        Ok(())
+    }
+
+    // TDOが揃うまではAPがWAITを返す
+    pub fn read_bytes(&mut self, data: &mut [u8]) -> Result<(), DapError> {
+        for chunk in data.chunks_mut(4) {
+            let word = self
+                .dap
+                .ap_register(self.apsel, bfifo(chunk.len()), 0, true)?;
+            for (i, x) in chunk.iter_mut().enumerate() {
+                *x = (word >> (i * 8)) as u8;
+            }
+        }
+        Ok(())
+    }
+
+    // SERACTVが落ちるまで待つ。書いたpacketが全て送られたことを確認する
+    pub fn wait_idle(&mut self, retries: usize) -> Result<(), DapError> {
+        for _ in 0..retries {
+            let csw = self.csw_read()?;
+            if csw.SERACTV() == 0 && csw.WFIFOCNT() == 0 {
+                return Ok(());
+            }
+        }
+        Err(InterfaceError::Timeout.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jtag::dap::tests::memap_dap;
+    use crate::jtag::dap::DpSelect;
+    use std::collections::VecDeque;
+
+    // BFIFOへの書き込みを記録し、読み出しはrx_fifoから返すJTAG-AP
+    struct JtagApSim {
+        select: u32,
+        psel: u32,
+        last_read: u32,
+        // (register address, data)
+        writes: Vec<(u8, u32)>,
+        rx_fifo: VecDeque<u8>,
+    }
+
+    impl JtagApSim {
+        fn new() -> Self {
+            JtagApSim {
+                select: 0,
+                psel: 0,
+                last_read: 0,
+                writes: Vec::new(),
+                rx_fifo: VecDeque::new(),
+            }
+        }
+    }
+
+    impl DapInterface for JtagApSim {
+        fn apacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            let address = (DpSelect(self.select).apbanksel() << 4) as u8 | (a << 2);
+            if !rnw {
+                self.writes.push((address, data));
+                if address == JtagApAddress::PSEL as u8 {
+                    self.psel = data;
+                }
+                return Ok((0x02, 0));
+            }
+            self.last_read = match address {
+                // port 2だけ繋がっている
+                0x00 => ((self.psel == 1 << 2) as u32) << 3,
+                0x10..=0x1C => {
+                    let length = ((address - 0x10) / 4 + 1) as usize;
+                    let bytes: Vec<u8> = self.rx_fifo.drain(..length).collect();
+                    pack_word(&bytes)
+                }
+                _ => 0,
+            };
+            Ok((0x02, 0))
+        }
+        fn dpacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            match (a, rnw) {
+                (0b10, false) => self.select = data,
+                (0b11, true) => return Ok((0x02, self.last_read)),
+                _ => (),
+            }
+            Ok((0x02, 0))
+        }
+    }
+
+    #[test]
+    fn fifo_test() {
+        let mut dap = memap_dap(JtagApSim::new());
+        let mut ap = JtagAp::new(&mut dap, 1);
+        ap.write_bytes(&[0x11, 0x22, 0x33, 0x44, 0x55]).unwrap();
+        assert_eq!(vec![(0x1C, 0x4433_2211), (0x10, 0x55)], dap.dp.writes);
+        assert_eq!(1, DpSelect(dap.dp.select).apsel());
+
+        dap.dp.rx_fifo.extend(&[0xaa, 0xbb, 0xcc, 0xdd, 0xee]);
+        let mut ap = JtagAp::new(&mut dap, 1);
+        let mut tdo = [0u8; 5];
+        ap.read_bytes(&mut tdo).unwrap();
+        assert_eq!([0xaa, 0xbb, 0xcc, 0xdd, 0xee], tdo);
+        assert!(dap.dp.rx_fifo.is_empty());
+    }
+
+    #[test]
+    fn select_port_test() {
+        let mut dap = memap_dap(JtagApSim::new());
+        let mut ap = JtagAp::new(&mut dap, 1);
+        ap.select_port(2).unwrap();
+        assert!(matches!(ap.select_port(3), Err(DapError::NoTarget)));
+        assert!(matches!(
+            ap.select_port(8),
+            Err(DapError::Interface(InterfaceError::OutOfRange))
+        ));
+        assert_eq!(vec![(0x04, 1 << 2), (0x04, 1 << 3)], dap.dp.writes);
+    }
+}
@@ -0,0 +1,190 @@
+// 書き込んではいけないaddress範囲
+// DAP::set_write_guardで設定すると、addressの分かる書き込みを発行する前に断る
+// 読み出しとDRWを直接書くraw APIは対象外
+use core::fmt;
+
+#[cfg(feature = "std")]
+use crate::config::TargetConfig;
+#[cfg(feature = "alloc")]
+use alloc::{string::String, vec::Vec};
+
+// endを含む。u64の最後のbyteまで表せるようにするため
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AddrRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl AddrRange {
+    // sizeが0、またはu64を越える場合はNone
+    pub fn new(start: u64, size: u64) -> Option<Self> {
+        let end = start.checked_add(size.checked_sub(1)?)?;
+        Some(AddrRange { start, end })
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        self.start <= addr && addr <= self.end
+    }
+
+    // 重なる部分
+    pub fn intersect(&self, other: &AddrRange) -> Option<AddrRange> {
+        let start = self.start.max(other.start);
+        let end = self.end.min(other.end);
+        (start <= end).then_some(AddrRange { start, end })
+    }
+}
+
+impl fmt::Display for AddrRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}..={:#x}", self.start, self.end)
+    }
+}
+
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct GuardRegion {
+    pub name: String,
+    pub range: AddrRange,
+    // falseなら書き込みを断る。trueはfalseの範囲の中に書いてよい穴を開ける
+    pub writable: bool,
+}
+
+// どのregionにも入らないaddressは書いてよい
+// debug registerも同じMEM-APの書き込みで触るので、memory mapに無い範囲は止めない
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WriteGuard {
+    regions: Vec<GuardRegion>,
+}
+
+#[cfg(feature = "alloc")]
+impl WriteGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // memory mapのwritable = falseとprotected = trueの範囲を断る
+    #[cfg(feature = "std")]
+    pub fn from_config(target: &TargetConfig) -> Self {
+        let mut guard = Self::new();
+        for region in target.memory.iter() {
+            let writable = region.writable && !region.protected;
+            guard = guard.region(&region.name, region.range, writable);
+        }
+        guard
+    }
+
+    pub fn deny(self, name: &str, range: AddrRange) -> Self {
+        self.region(name, range, false)
+    }
+
+    pub fn allow(self, name: &str, range: AddrRange) -> Self {
+        self.region(name, range, true)
+    }
+
+    fn region(mut self, name: &str, range: AddrRange, writable: bool) -> Self {
+        self.regions.push(GuardRegion {
+            name: String::from(name),
+            range,
+            writable,
+        });
+        self
+    }
+
+    pub fn regions(&self) -> &[GuardRegion] {
+        &self.regions
+    }
+
+    // 断る範囲が無ければ何も止めない
+    pub fn is_empty(&self) -> bool {
+        !self.regions.iter().any(|x| !x.writable)
+    }
+
+    // [addr, addr + len)の中で最初に書いてはいけないaddressと、それを含むregion
+    pub fn first_denied(&self, addr: u64, len: u64) -> Option<(u64, &GuardRegion)> {
+        let write = AddrRange::new(addr, len).or_else(|| {
+            // u64を越える分は切り詰める
+            (len != 0).then_some(AddrRange {
+                start: addr,
+                end: u64::MAX,
+            })
+        })?;
+        self.regions
+            .iter()
+            .filter(|x| !x.writable)
+            .filter_map(|region| {
+                let overlap = region.range.intersect(&write)?;
+                Some((self.first_uncovered(overlap)?, region))
+            })
+            .min_by_key(|(x, _)| *x)
+    }
+
+    // rangeの中でwritableなregionに覆われていない最初のaddress
+    fn first_uncovered(&self, range: AddrRange) -> Option<u64> {
+        let mut addr = range.start;
+        while let Some(allow) = self
+            .regions
+            .iter()
+            .find(|x| x.writable && x.range.contains(addr))
+        {
+            if allow.range.end >= range.end {
+                return None;
+            }
+            addr = allow.range.end + 1;
+        }
+        Some(addr)
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+
+    fn range(start: u64, size: u64) -> AddrRange {
+        AddrRange::new(start, size).unwrap()
+    }
+
+    #[test]
+    fn addr_range_test() {
+        assert_eq!(None, AddrRange::new(0x1000, 0));
+        assert_eq!(None, AddrRange::new(u64::MAX, 2));
+        assert_eq!(
+            Some(AddrRange {
+                start: u64::MAX,
+                end: u64::MAX
+            }),
+            AddrRange::new(u64::MAX, 1)
+        );
+        let a = range(0x1000, 0x100);
+        assert!(a.contains(0x1000) && a.contains(0x10ff) && !a.contains(0x1100));
+        assert_eq!(
+            Some(range(0x1080, 0x80)),
+            a.intersect(&range(0x1080, 0x100))
+        );
+        assert_eq!(None, a.intersect(&range(0x1100, 0x100)));
+    }
+
+    #[test]
+    fn first_denied_test() {
+        let guard = WriteGuard::new()
+            .deny("pmic", range(0x1000, 0x100))
+            .allow("pmic-scratch", range(0x1010, 0x10));
+        assert!(!guard.is_empty());
+        assert_eq!(None, guard.first_denied(0x0ffc, 4));
+        assert_eq!(Some(0x1000), guard.first_denied(0x0ffc, 5).map(|x| x.0));
+        assert_eq!(Some(0x10ff), guard.first_denied(0x10ff, 4).map(|x| x.0));
+        assert_eq!(None, guard.first_denied(0x1100, 4));
+        assert_eq!(None, guard.first_denied(0x1010, 0x10));
+        assert_eq!(Some(0x1020), guard.first_denied(0x1010, 0x11).map(|x| x.0));
+        assert_eq!(None, guard.first_denied(0x1000, 0));
+        let (_, region) = guard.first_denied(0x1000, 1).unwrap();
+        assert_eq!("pmic", region.name);
+
+        assert!(WriteGuard::new().allow("sram", range(0, 0x100)).is_empty());
+        let guard = WriteGuard::new().deny("top", range(u64::MAX, 1));
+        assert_eq!(
+            Some(u64::MAX),
+            guard.first_denied(u64::MAX - 3, 8).map(|x| x.0)
+        );
+    }
+}
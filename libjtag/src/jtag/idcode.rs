@@ -0,0 +1,194 @@
+use core::convert::TryFrom;
+use core::fmt;
+
+use crate::jtag::manufacturer::{default_names, Manufacturer, ManufacturerNames, NoNames};
+
+// JTAGのIDCODE
+// | version(31:28) | part number(27:12) | manufacturer(11:1) | 1 |
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdCode {
+    pub version: u8,
+    pub part_number: u16,
+    // JEP106のcontinuation codeとidentity code
+    pub cc: u8,
+    pub id: u8,
+    pub raw: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidIdCode(pub u32);
+
+impl fmt::Display for InvalidIdCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid IDCODE {:#010x}", self.0)
+    }
+}
+
+// identity codeの0x7fはcontinuationを表すので、IDCODEには現れない
+const JEP106_CONTINUATION: u8 = 0x7f;
+
+impl TryFrom<u32> for IdCode {
+    type Error = InvalidIdCode;
+
+    fn try_from(raw: u32) -> Result<Self, Self::Error> {
+        let manufacturer = Manufacturer::from_idcode(&NoNames, raw);
+        if raw & 1 == 0 || manufacturer.id == JEP106_CONTINUATION {
+            return Err(InvalidIdCode(raw));
+        }
+        Ok(IdCode {
+            version: (raw >> 28) as u8,
+            part_number: ((raw >> 12) & 0xffff) as u16,
+            cc: manufacturer.cc,
+            id: manufacturer.id,
+            raw,
+        })
+    }
+}
+
+impl IdCode {
+    pub fn manufacturer<'a>(&self, names: &'a dyn ManufacturerNames) -> Manufacturer<'a> {
+        Manufacturer {
+            names,
+            cc: self.cc,
+            id: self.id,
+        }
+    }
+
+    // 名前をnamesで引いて表示する
+    pub fn display<'a>(&'a self, names: &'a dyn ManufacturerNames) -> IdCodeDisplay<'a> {
+        IdCodeDisplay {
+            idcode: self,
+            names,
+        }
+    }
+
+    #[cfg(feature = "jep106")]
+    pub fn jep106(&self) -> jep106::JEP106Code {
+        jep106::JEP106Code::new(self.cc, self.id)
+    }
+}
+
+pub struct IdCodeDisplay<'a> {
+    idcode: &'a IdCode,
+    names: &'a dyn ManufacturerNames,
+}
+
+impl fmt::Display for IdCodeDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} part {:#06X} rev {} ({:#010x})",
+            self.idcode.manufacturer(self.names),
+            self.idcode.part_number,
+            self.idcode.version,
+            self.idcode.raw
+        )
+    }
+}
+
+// Jtagのset_manufacturer_namesで変えた名前を使う場合はdisplayを使う
+impl fmt::Display for IdCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.display(default_names()).fmt(f)
+    }
+}
+
+// scanで見つかったdevice
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TapDevice {
+    IdCode(IdCode),
+    // IDCODEを持たず、Test-Logic-ResetでBYPASSが選ばれるdevice
+    Bypass,
+}
+
+impl TapDevice {
+    pub fn idcode(&self) -> Option<&IdCode> {
+        match self {
+            TapDevice::IdCode(idcode) => Some(idcode),
+            TapDevice::Bypass => None,
+        }
+    }
+
+    pub fn raw(&self) -> Option<u32> {
+        self.idcode().map(|x| x.raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        // ARM CoreSight JTAG-DP
+        let arm = IdCode::try_from(0x4ba0_0477).unwrap();
+        assert_eq!(
+            (4, 0xba00, 0x4, 0x3b),
+            (arm.version, arm.part_number, arm.cc, arm.id)
+        );
+        // Xilinx XC7A35T
+        let xilinx = IdCode::try_from(0x0362_d093).unwrap();
+        assert_eq!(
+            (0, 0x362d, 0x0, 0x49),
+            (xilinx.version, xilinx.part_number, xilinx.cc, xilinx.id)
+        );
+        // Altera EP4CE22
+        let altera = IdCode::try_from(0x020f_30dd).unwrap();
+        assert_eq!(
+            (0, 0x20f3, 0x0, 0x6e),
+            (altera.version, altera.part_number, altera.cc, altera.id)
+        );
+
+        // LSBが0ならIDCODEではない
+        assert_eq!(
+            Err(InvalidIdCode(0x4ba0_0476)),
+            IdCode::try_from(0x4ba0_0476)
+        );
+        // TDOが1に張り付いている場合など
+        assert_eq!(
+            Err(InvalidIdCode(0xffff_ffff)),
+            IdCode::try_from(0xffff_ffff)
+        );
+        assert!(IdCode::try_from(0x0000_00ff).is_err());
+    }
+
+    #[cfg(feature = "jep106")]
+    #[test]
+    fn display_test() {
+        let arm = IdCode::try_from(0x4ba0_0477).unwrap();
+        assert_eq!("ARM Ltd part 0xBA00 rev 4 (0x4ba00477)", format!("{}", arm));
+        let xilinx = IdCode::try_from(0x0362_d093).unwrap();
+        assert_eq!(
+            "Xilinx part 0x362D rev 0 (0x0362d093)",
+            format!("{}", xilinx)
+        );
+        assert_eq!(
+            Some("Altera"),
+            IdCode::try_from(0x020f_30dd).unwrap().jep106().get()
+        );
+    }
+
+    struct LocalNames;
+
+    impl ManufacturerNames for LocalNames {
+        fn name(&self, cc: u8, id: u8) -> Option<&str> {
+            match (cc, id) {
+                (0x0, 0x49) => Some("AMD"),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn display_with_names_test() {
+        let xilinx = IdCode::try_from(0x0362_d093).unwrap();
+        assert_eq!(
+            "AMD part 0x362D rev 0 (0x0362d093)",
+            format!("{}", xilinx.display(&LocalNames))
+        );
+        assert_eq!(
+            "0x0:0x49 part 0x362D rev 0 (0x0362d093)",
+            format!("{}", xilinx.display(&NoNames))
+        );
+    }
+}
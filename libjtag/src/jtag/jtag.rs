@@ -1,80 +1,409 @@
 use spin::mutex::Mutex;
 
+#[cfg(feature = "alloc")]
+use alloc::vec;
+
 use core::cmp;
+use core::convert::TryFrom;
 
-use jep106;
 use log::{debug, error, info, warn};
 use rust_fsm::*;
 
-use crate::interface::JtagInterface;
+#[cfg(feature = "std")]
+use crate::config::ChainConfig;
+use crate::interface::{CapFlags, InterfaceError, JtagInterface};
+use crate::jtag::bits;
+use crate::jtag::idcode::{IdCode, TapDevice};
 use crate::jtag::jtag_state_machine::{JtagState as JS, JtagStateMachine};
+use crate::jtag::manufacturer::{default_names, ManufacturerNames};
+use crate::jtag::stats::{JtagStats, Scan, Timer};
+use crate::jtag::swj::{self, SwitchProtocol};
 
 use super::JtagBit as JB;
 
-const TAP_DEVICE_MAX: usize = 2;
+const TAP_DEVICE_MAX: usize = 16;
+const IDCODE_LEN: usize = 32;
+// chainの終端を見つけるためにDRへ流し込むダミーID
+const SCAN_SENTINEL: u32 = 0x0000_00ff;
+// detect_chainで想定する1deviceあたりのIR長の上限
+const IR_LEN_MAX: usize = 32;
+const IR_TOTAL_MAX: usize = TAP_DEVICE_MAX * IR_LEN_MAX;
+// idle cycleをTMSのbit列で送る時の1回の長さ
+const IDLE_CHUNK: usize = 64;
+// reset_targetでSRSTをassertしておく時間
+const SRST_PULSE_MS: u32 = 100;
+
+// detect_chainで調べたchainの構成
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChainInfo {
+    devices: usize,
+    ir_total: usize,
+    // captureから区切りが一意に決まらない場合はNone
+    ir_lens: Option<[usize; TAP_DEVICE_MAX]>,
+}
+
+impl ChainInfo {
+    pub fn devices(&self) -> usize {
+        self.devices
+    }
+
+    pub fn ir_total(&self) -> usize {
+        self.ir_total
+    }
+
+    // Jtag::devices()と同じくTDOに近い順
+    pub fn ir_lens(&self) -> Option<&[usize]> {
+        self.ir_lens.as_ref().map(|x| &x[..self.devices])
+    }
+
+    // IR-Captureでは各TAPの下位2bitが01になるので、bit列の"1, 0"をTAPの先頭とみなす
+    fn split_ir(capture: &[bool], devices: usize) -> Option<[usize; TAP_DEVICE_MAX]> {
+        let mut ir_lens = [0; TAP_DEVICE_MAX];
+        let mut count = 0;
+        let mut start = 0;
+        for i in 1..capture.len() {
+            if !(capture[i] && i + 1 < capture.len() && !capture[i + 1]) {
+                continue;
+            }
+            if count + 1 >= devices {
+                return None;
+            }
+            ir_lens[count] = i - start;
+            count += 1;
+            start = i;
+        }
+        if !capture.starts_with(&[true, false]) || count + 1 != devices {
+            return None;
+        }
+        ir_lens[count] = capture.len() - start;
+        Some(ir_lens)
+    }
+}
+
+// Jtag::scanで見つけたdevice
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScanResult {
+    devices: [TapDevice; TAP_DEVICE_MAX],
+    count: usize,
+    complete: bool,
+}
+
+impl ScanResult {
+    // Jtag::devices()と同じくTDOに近い順
+    pub fn devices(&self) -> &[TapDevice] {
+        &self.devices[..self.count]
+    }
+
+    // chainの終端(sentinel)まで読めたか
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+}
 
 pub struct Jtag<T> {
     pub interface: T,
     state_machine: StateMachine<JtagStateMachine>,
-    idcodes: [Option<u32>; TAP_DEVICE_MAX],
+    devices: [TapDevice; TAP_DEVICE_MAX],
+    device_count: usize,
+    scan_limit: usize,
+    names: &'static dyn ManufacturerNames,
+    verify_ir_capture: bool,
+    auto_resync: bool,
+    switch: SwitchProtocol,
+    // initializeでTAPをresetするまではstate machineとTAPの状態が一致しない
+    initialized: bool,
+    // 最後にwrite_irでchain全体に書いたIR。scanの後に書き戻す
+    last_ir: [bool; IR_TOTAL_MAX],
+    last_ir_len: usize,
+    // chain全体をIDCODEにするIR。設定されていればrescan_preserving_stateでresetしない
+    idcode_ir: [bool; IR_TOTAL_MAX],
+    idcode_ir_len: usize,
+    // chain上の位置毎に、TAP::write_instructionで最後に書いた命令
+    // 他のTAPの命令を書くとBYPASSに埋められるので、その位置はNoneに戻る
+    tap_irs: [Option<u32>; TAP_DEVICE_MAX],
+    // set_collect_statsで有効にした場合だけ数える
+    stats: Option<JtagStats>,
+    // interfaceの操作が失敗したか、verify_stateでずれが見つかった
+    // resyncするまでTAPの状態は分からない
+    desynced: bool,
+}
+
+// scanの前後でchainのIRを書き戻すために覚えておく
+#[derive(Clone, Copy)]
+struct SavedIr {
+    ir: [bool; IR_TOTAL_MAX],
+    len: usize,
+    tap_irs: [Option<u32>; TAP_DEVICE_MAX],
+}
+
+// LSB firstで詰めたbyte列の間でlen bitを移す
+#[cfg(feature = "alloc")]
+fn copy_bits(from: &[u8], from_start: usize, to: &mut [u8], to_start: usize, len: usize) {
+    for i in 0..len {
+        let (src, dst) = (from_start + i, to_start + i);
+        let mask = 1 << (dst % 8);
+        if from[src / 8] & (1 << (src % 8)) != 0 {
+            to[dst / 8] |= mask;
+        } else {
+            to[dst / 8] &= !mask;
+        }
+    }
 }
 
 impl<T: JtagInterface> Jtag<T> {
+    // 生成時はinterfaceに触らない。使う前にinitializeを呼ぶ
     pub fn new(interface: T) -> Self {
-        let jtag_state_machine: StateMachine<JtagStateMachine> = StateMachine::new();
+        Self::new_with_switch(interface, SwitchProtocol::None)
+    }
 
-        let mut jtag = Jtag {
+    // SWJ-DPがSWDやdormantで起動する場合は、initializeでscanの前にJTAGへ切り替える
+    pub fn new_with_switch(interface: T, switch: SwitchProtocol) -> Self {
+        Jtag {
             interface,
-            state_machine: jtag_state_machine,
-            idcodes: [None; TAP_DEVICE_MAX],
-        };
-        jtag.scan();
+            state_machine: StateMachine::new(),
+            devices: [TapDevice::Bypass; TAP_DEVICE_MAX],
+            device_count: 0,
+            scan_limit: TAP_DEVICE_MAX,
+            names: default_names(),
+            verify_ir_capture: false,
+            auto_resync: false,
+            switch,
+            initialized: false,
+            last_ir: [true; IR_TOTAL_MAX],
+            last_ir_len: 0,
+            idcode_ir: [true; IR_TOTAL_MAX],
+            idcode_ir_len: 0,
+            tap_irs: [None; TAP_DEVICE_MAX],
+            stats: None,
+            desynced: false,
+        }
+    }
+
+    // JTAGへ切り替えてTAPをresetし、chainをscanする
+    pub fn initialize(&mut self) -> Result<ScanResult, InterfaceError> {
+        swj::switch_to_jtag(&mut self.interface, self.switch)?;
+        self.forget_ir();
+        let result = self.scan()?;
 
         // set initial state
-        jtag.change_state(JS::Reset);
+        self.change_state(JS::Reset)?;
+        self.desynced = false;
 
-        jtag
+        Ok(result)
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.initialized
+    }
+
+    // trueならTAPの状態が分からないので、resyncかinitializeし直すまで信用しない
+    pub fn is_desynced(&self) -> bool {
+        self.desynced
+    }
+
+    // interfaceが失敗した場合、TAPがどの状態にいるか分からなくなる
+    fn track<R>(&mut self, result: Result<R, InterfaceError>) -> Result<R, InterfaceError> {
+        if result.is_err() {
+            self.desynced = true;
+        }
+        result
+    }
+
+    pub fn set_manufacturer_names(&mut self, names: &'static dyn ManufacturerNames) {
+        self.names = names;
+    }
+
+    pub fn manufacturer_names(&self) -> &'static dyn ManufacturerNames {
+        self.names
+    }
+
+    // TDOに近い順
+    pub fn devices(&self) -> &[TapDevice] {
+        &self.devices[..self.device_count]
+    }
+
+    pub fn set_scan_limit(&mut self, max_devices: usize) {
+        self.scan_limit = cmp::min(max_devices, TAP_DEVICE_MAX);
+    }
+
+    // write_irでIR-Captureの01を確認し、違えばBadIrCaptureにする
+    pub fn set_verify_ir_capture(&mut self, verify: bool) {
+        self.verify_ir_capture = verify;
+    }
+
+    // TAP::apacc/dpaccが不正なACKを受けた時に、resyncしてから1度だけやり直す
+    pub fn set_auto_resync_on_error(&mut self, enable: bool) {
+        self.auto_resync = enable;
+    }
+
+    pub fn auto_resync_on_error(&self) -> bool {
+        self.auto_resync
+    }
+
+    // 有効にした時点から数え始める。無効にすると数えた値も捨てる
+    pub fn set_collect_stats(&mut self, collect: bool) {
+        self.stats = if collect {
+            Some(self.stats.unwrap_or_default())
+        } else {
+            None
+        };
+    }
+
+    // 有効にしていない場合は全て0を返す
+    pub fn stats(&self) -> JtagStats {
+        JtagStats {
+            usb: self.interface.usb_stats(),
+            ..self.stats.unwrap_or_default()
+        }
+    }
+
+    pub fn reset_stats(&mut self) {
+        if let Some(stats) = self.stats.as_mut() {
+            *stats = JtagStats::default();
+        }
+        self.interface.reset_usb_stats();
+    }
+
+    fn record(&mut self, scan: Scan, bits: usize, read: bool, timer: Timer) {
+        if let Some(stats) = self.stats.as_mut() {
+            stats.record(scan, bits, read, timer);
+        }
     }
 
     pub fn state(&self) -> JS {
         *self.state_machine.state()
     }
 
-    pub fn write_tms(&mut self, tms: &[bool]) {
-        self.interface.write_tms(tms);
-        for i in 0..tms.len() {
-            self.state_machine.consume(&tms[i]).unwrap();
+    // TRSTでTAPをTest-Logic-Resetに戻す
+    // TRSTのないbackendではTMSを5回HにしてTest-Logic-Resetに入れる
+    pub fn hardware_reset_tap(&mut self) -> Result<(), InterfaceError> {
+        if !self.interface.capabilities().contains(CapFlags::HAS_TRST) {
+            debug!("interface has no TRST, resetting TAP with TMS");
+            return self.change_state(JS::Reset);
+        }
+        self.assert_trst(true)?;
+        self.assert_trst(false)
+    }
+
+    // assert中のTAPはTest-Logic-Resetに留まる
+    pub fn assert_trst(&mut self, level: bool) -> Result<(), InterfaceError> {
+        self.interface.assert_trst(level)?;
+        if level {
+            self.state_machine = StateMachine::new();
         }
+        Ok(())
     }
 
-    pub fn raw_write_data(&mut self, tdi: &[bool], exit: bool) {
-        self.interface.write_data(tdi, exit);
+    // SRSTでtarget全体をresetする。TAPの状態は変わらない
+    pub fn reset_target(&mut self) -> Result<(), InterfaceError> {
+        if !self.interface.capabilities().contains(CapFlags::HAS_SRST) {
+            return Err(InterfaceError::Unsupported);
+        }
+        self.interface.pulse_srst(SRST_PULSE_MS)
+    }
+
+    // interfaceが失敗した場合、TAPの状態は不明になるのでstate_machineは進めない
+    pub fn write_tms(&mut self, tms: &[bool]) -> Result<(), InterfaceError> {
+        let timer = Timer::start();
+        let result = self.interface.write_tms(tms);
+        self.track(result)?;
+        self.record(Scan::Tms, tms.len(), false, timer);
+        for &tms in tms {
+            self.advance(tms);
+        }
+        Ok(())
+    }
+
+    pub fn raw_write_data(&mut self, tdi: &[bool], exit: bool) -> Result<(), InterfaceError> {
+        let timer = Timer::start();
+        let result = self.interface.write_data(tdi, exit);
+        self.track(result)?;
+        self.record(Scan::data(self.state()), tdi.len(), false, timer);
         if exit {
-            self.state_machine.consume(&true).unwrap();
+            self.advance(true);
         }
+        Ok(())
     }
 
-    pub fn raw_read_data(&mut self, tditdo: &mut [bool], exit: bool) {
-        self.interface.read_data(tditdo, exit);
+    pub fn raw_read_data(&mut self, tditdo: &mut [bool], exit: bool) -> Result<(), InterfaceError> {
+        let timer = Timer::start();
+        let result = self.interface.read_data(tditdo, exit);
+        self.track(result)?;
+        self.record(Scan::data(self.state()), tditdo.len(), true, timer);
         if exit {
-            self.state_machine.consume(&true).unwrap();
+            self.advance(true);
+        }
+        Ok(())
+    }
+
+    // dataはLSB firstで詰めたlen bit
+    pub fn raw_write_data_packed(
+        &mut self,
+        tdi: &[u8],
+        len: usize,
+        exit: bool,
+    ) -> Result<(), InterfaceError> {
+        let timer = Timer::start();
+        let result = self.interface.write_data_packed(tdi, len, exit);
+        self.track(result)?;
+        self.record(Scan::data(self.state()), len, false, timer);
+        if exit {
+            self.advance(true);
+        }
+        Ok(())
+    }
+
+    pub fn raw_read_data_packed(
+        &mut self,
+        tditdo: &mut [u8],
+        len: usize,
+        exit: bool,
+    ) -> Result<(), InterfaceError> {
+        let timer = Timer::start();
+        let result = self.interface.read_data_packed(tditdo, len, exit);
+        self.track(result)?;
+        self.record(Scan::data(self.state()), len, true, timer);
+        if exit {
+            self.advance(true);
+        }
+        Ok(())
+    }
+
+    // 全ての状態がTMSの0/1両方の遷移先を持つので、consumeは失敗しない
+    fn advance(&mut self, tms: bool) {
+        let _ = self.state_machine.consume(&tms);
+    }
+
+    pub fn change_state(&mut self, to: JS) -> Result<(), InterfaceError> {
+        self.transition(to)?;
+        // 安定状態に着いたら、interfaceに溜めた操作をまとめて送る
+        match to {
+            JS::Reset | JS::RunIdle | JS::PauseDR | JS::PauseIR => {
+                let result = self.interface.flush();
+                self.track(result)
+            }
+            _ => Ok(()),
         }
     }
 
-    pub fn change_state(&mut self, to: JS) {
+    fn transition(&mut self, to: JS) -> Result<(), InterfaceError> {
         let from = self.state_machine.state();
 
         if (*from == to) && to != JS::Reset {
             // do nothing
-            return ();
+            return Ok(());
         }
 
         match (from, to) {
-            (_, JS::Reset) => self.write_tms(&[true; 5]),
+            (_, JS::Reset) => {
+                // 全TAPのIRがIDCODE(なければBYPASS)に戻る
+                self.forget_ir();
+                self.write_tms(&[true; 5])
+            }
             (JS::Reset, JS::RunIdle) => self.write_tms(&[false]),
             (JS::Reset, _) => {
-                self.change_state(JS::RunIdle);
-                self.change_state(to);
+                self.transition(JS::RunIdle)?;
+                self.transition(to)
             }
             (JS::RunIdle, JS::RunIdle) => self.write_tms(&[false]),
             (JS::SelectDRScan | JS::SelectIRScan, JS::RunIdle) => {
@@ -90,33 +419,211 @@ impl<T: JtagInterface> Jtag<T> {
             (JS::RunIdle, JS::CaptureDR) => self.write_tms(&[true, false]),
             (JS::RunIdle, JS::ShiftDR) => self.write_tms(&[true, false, false]),
 
+            (JS::RunIdle, JS::PauseDR) => self.write_tms(&[true, false, true, false]),
+            (JS::RunIdle, JS::PauseIR) => self.write_tms(&[true, true, false, true, false]),
+            (JS::Exit1DR, JS::PauseDR) | (JS::Exit1IR, JS::PauseIR) => self.write_tms(&[false]),
+            (JS::PauseDR, JS::Exit2DR) | (JS::PauseIR, JS::Exit2IR) => self.write_tms(&[true]),
+            (JS::Exit2DR, JS::ShiftDR) | (JS::Exit2IR, JS::ShiftIR) => self.write_tms(&[false]),
+            (JS::PauseDR, JS::ShiftDR) | (JS::PauseIR, JS::ShiftIR) => {
+                self.write_tms(&[true, false])
+            }
+            (JS::Exit2DR, JS::UpdateDR) | (JS::Exit2IR, JS::UpdateIR) => self.write_tms(&[true]),
+
             (JS::Exit1DR, JS::UpdateDR) => self.write_tms(&[true]),
             (JS::RunIdle, JS::CaptureIR) => self.write_tms(&[true, true, false]),
             (JS::RunIdle, JS::ShiftIR) => self.write_tms(&[true, true, false, false]),
 
             (JS::Exit1IR, JS::UpdateIR) => self.write_tms(&[true]),
 
-            (_, _) => panic!("not supported from {:?} to {:?}", from, to),
+            (from, to) => Err(InterfaceError::UnsupportedTransition { from: *from, to }),
+        }
+    }
+
+    // chain上の他のdeviceの分を埋める
+    #[cfg(not(feature = "alloc"))]
+    fn shift_fill(&mut self, len: usize, value: bool, exit: bool) -> Result<(), InterfaceError> {
+        let buffer = [value; 32];
+        let mut rest = len;
+        while rest > 0 {
+            let length = cmp::min(rest, buffer.len());
+            rest -= length;
+            self.raw_write_data(&buffer[..length], exit && rest == 0)?;
+        }
+        Ok(())
+    }
+
+    // bypassの分もまとめて1回のread_dataで送る
+    #[cfg(feature = "alloc")]
+    fn shift_dr_padded(
+        &mut self,
+        data: &mut [bool],
+        before: usize,
+        after: usize,
+        exit: bool,
+    ) -> Result<(), InterfaceError> {
+        if before == 0 && after == 0 {
+            return self.raw_read_data(data, exit);
         }
+        let mut buffer = vec![false; before + data.len() + after];
+        buffer[before..before + data.len()].copy_from_slice(data);
+        self.raw_read_data(&mut buffer, exit)?;
+        data.copy_from_slice(&buffer[before..before + data.len()]);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "alloc"))]
+    fn shift_dr_padded(
+        &mut self,
+        data: &mut [bool],
+        before: usize,
+        after: usize,
+        exit: bool,
+    ) -> Result<(), InterfaceError> {
+        self.shift_fill(before, false, false)?;
+        self.raw_read_data(data, exit && after == 0)?;
+        self.shift_fill(after, false, exit)
+    }
+
+    #[cfg(feature = "alloc")]
+    fn shift_dr_padded_packed(
+        &mut self,
+        data: &mut [u8],
+        len: usize,
+        before: usize,
+        after: usize,
+        exit: bool,
+    ) -> Result<(), InterfaceError> {
+        if before == 0 && after == 0 {
+            return self.raw_read_data_packed(data, len, exit);
+        }
+        let total = before + len + after;
+        let mut buffer = vec![0u8; total.div_ceil(8)];
+        copy_bits(data, 0, &mut buffer, before, len);
+        self.raw_read_data_packed(&mut buffer, total, exit)?;
+        copy_bits(&buffer, before, data, 0, len);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "alloc"))]
+    fn shift_dr_padded_packed(
+        &mut self,
+        data: &mut [u8],
+        len: usize,
+        before: usize,
+        after: usize,
+        exit: bool,
+    ) -> Result<(), InterfaceError> {
+        self.shift_fill(before, false, false)?;
+        self.raw_read_data_packed(data, len, exit && after == 0)?;
+        self.shift_fill(after, false, exit)
+    }
+
+    pub fn write_ir(
+        &mut self,
+        ir_bitstream: &mut [bool],
+        exit: bool,
+        reverse: bool,
+    ) -> Result<(), InterfaceError> {
+        self.write_ir_padded(ir_bitstream, 0, 0, exit, reverse)
     }
 
-    pub fn write_ir(&mut self, ir_bitstream: &mut [bool], exit: bool, reverse: bool) {
-        // TODO: remove mut from ir_bitstream
+    // before: 先にshiftされる(TDO側の)IRのbit数
+    // after: 後にshiftされる(TDI側の)IRのbit数
+    // ir_bitstreamはShift-IR中にTDOから読んだbitで置き換える
+    pub fn write_ir_padded(
+        &mut self,
+        ir_bitstream: &mut [bool],
+        before: usize,
+        after: usize,
+        exit: bool,
+        reverse: bool,
+    ) -> Result<(), InterfaceError> {
+        if !self.initialized {
+            return Err(InterfaceError::NotInitialized);
+        }
+        let len = ir_bitstream.len();
+        if before + len + after > IR_TOTAL_MAX {
+            return Err(InterfaceError::OutOfRange);
+        }
         match self.state_machine.state() {
             JS::Reset | JS::RunIdle | JS::ShiftIR => (),
-            _ => self.change_state(JS::RunIdle),
+            _ => self.change_state(JS::RunIdle)?,
         };
-        self.change_state(JS::ShiftIR);
+        self.change_state(JS::ShiftIR)?;
 
+        // 他のTAPはBYPASS(all ones)にする
+        let mut buffer = [true; IR_TOTAL_MAX];
+        let buffer = &mut buffer[..before + len + after];
         if reverse {
             ir_bitstream.reverse();
         }
-        self.raw_write_data(ir_bitstream, exit);
+        buffer[before..before + len].copy_from_slice(ir_bitstream);
+        // 途中で失敗したらIRに何が入ったか分からない
+        self.forget_ir();
+        let mut written = [true; IR_TOTAL_MAX];
+        written[..buffer.len()].copy_from_slice(buffer);
+        self.raw_read_data(buffer, exit)?;
+        self.last_ir = written;
+        self.last_ir_len = buffer.len();
+        ir_bitstream.copy_from_slice(&buffer[before..before + len]);
         if reverse {
             ir_bitstream.reverse();
         }
         // Exit1 -> RunIdle
-        self.change_state(JS::RunIdle);
+        self.change_state(JS::RunIdle)?;
+
+        // 自分と、前後のTAPの先頭を確認する
+        if self.verify_ir_capture
+            && !(Self::ir_capture_ok(&buffer[before..before + len])
+                && (before == 0 || Self::ir_capture_ok(buffer))
+                && (after == 0 || Self::ir_capture_ok(&buffer[before + len..])))
+        {
+            error!("unexpected IR capture: {:?}", buffer);
+            return Err(InterfaceError::BadIrCapture);
+        }
+        Ok(())
+    }
+
+    // TMSを5回1にしてTAPをTest-Logic-Resetへ戻し、state machineもResetからやり直す
+    // 全TAPのIRはIDCODE(なければBYPASS)に戻る
+    pub fn resync(&mut self) -> Result<(), InterfaceError> {
+        self.write_tms(&[true; 5])?;
+        self.state_machine = StateMachine::new();
+        self.forget_ir();
+        let result = self.interface.flush();
+        self.track(result)?;
+        self.desynced = false;
+        Ok(())
+    }
+
+    // 小さなIR scanで、TAPがstate machineの通りの状態にいるか確かめる
+    // Shift-IRにいなければcaptureの01が出てこないのでDesyncedを返す
+    // IRは全て1(BYPASS)で埋めるので、この後の命令は書き直す必要がある
+    pub fn verify_state(&mut self) -> Result<(), InterfaceError> {
+        if !self.initialized {
+            return Err(InterfaceError::NotInitialized);
+        }
+        match self.state_machine.state() {
+            JS::Reset | JS::RunIdle => (),
+            _ => self.change_state(JS::RunIdle)?,
+        };
+        self.change_state(JS::ShiftIR)?;
+        // IR長が分からなくても全TAPが確実にBYPASSになるだけ送る
+        let mut buffer = [true; IR_TOTAL_MAX];
+        self.forget_ir();
+        self.raw_read_data(&mut buffer, true)?;
+        self.change_state(JS::RunIdle)?;
+        if !Self::ir_capture_ok(&buffer) {
+            warn!("TAP state desynchronized, IR capture: {:?}", &buffer[..2]);
+            self.desynced = true;
+            return Err(InterfaceError::Desynced);
+        }
+        Ok(())
+    }
+
+    // IR-CaptureではLSBから1, 0の順に出てくる
+    fn ir_capture_ok(capture: &[bool]) -> bool {
+        capture.first() == Some(&true) && capture.get(1) != Some(&true)
     }
 
     pub fn read_write_dr(
@@ -125,97 +632,514 @@ impl<T: JtagInterface> Jtag<T> {
         exit: bool,
         reverse_input: bool,
         reverse_output: bool,
-    ) {
+    ) -> Result<(), InterfaceError> {
+        self.read_write_dr_padded(data, 0, 0, exit, reverse_input, reverse_output)
+    }
+
+    // before/afterはBYPASS中のdeviceの数(1bitずつ)
+    pub fn read_write_dr_padded(
+        &mut self,
+        data: &mut [bool],
+        before: usize,
+        after: usize,
+        exit: bool,
+        reverse_input: bool,
+        reverse_output: bool,
+    ) -> Result<(), InterfaceError> {
+        if !self.initialized {
+            return Err(InterfaceError::NotInitialized);
+        }
         match self.state_machine.state() {
             JS::Reset | JS::RunIdle | JS::ShiftDR => (),
-            _ => self.change_state(JS::RunIdle),
+            _ => self.change_state(JS::RunIdle)?,
         };
-        self.change_state(JS::ShiftDR);
+        self.change_state(JS::ShiftDR)?;
 
         if reverse_input {
             data.reverse();
         }
 
-        self.raw_read_data(data, exit);
+        // 先にshiftしたbitはTDO側のdeviceに入り、TDOにも先に出てくる
+        self.shift_dr_padded(data, before, after, exit)?;
 
         if reverse_output {
             data.reverse();
         }
         // Exit1 -> RunIdle
-        self.change_state(JS::RunIdle);
+        self.change_state(JS::RunIdle)
     }
 
-    pub fn scan(&mut self) {
-        // IDCODEスキャンを行う
+    // dataはLSB firstで詰めたlen bit。bool列を経由しないので長いDRや頻繁なDAP accessで速い
+    pub fn read_write_dr_packed(
+        &mut self,
+        data: &mut [u8],
+        len: usize,
+        exit: bool,
+    ) -> Result<(), InterfaceError> {
+        self.read_write_dr_padded_packed(data, len, 0, 0, exit)
+    }
+
+    pub fn read_write_dr_padded_packed(
+        &mut self,
+        data: &mut [u8],
+        len: usize,
+        before: usize,
+        after: usize,
+        exit: bool,
+    ) -> Result<(), InterfaceError> {
+        if !self.initialized {
+            return Err(InterfaceError::NotInitialized);
+        }
+        if data.len() < len.div_ceil(8) {
+            return Err(InterfaceError::OutOfRange);
+        }
+        match self.state_machine.state() {
+            JS::Reset | JS::RunIdle | JS::ShiftDR => (),
+            _ => self.change_state(JS::RunIdle)?,
+        };
+        self.change_state(JS::ShiftDR)?;
+        self.shift_dr_padded_packed(data, len, before, after, exit)?;
+        self.change_state(JS::RunIdle)
+    }
+
+    // Run-Test/IdleでTCKをn回入れる。TMS=Lなのでstateは変わらない
+    pub fn idle_cycles(&mut self, n: usize) -> Result<(), InterfaceError> {
+        if self.state() != JS::RunIdle {
+            self.change_state(JS::RunIdle)?;
+        }
+        let timer = Timer::start();
+        let caps = self.interface.capabilities();
+        if caps.contains(CapFlags::SUPPORTS_IDLE_CLOCK) {
+            let result = self.interface.clock_idle(n);
+            self.track(result)?;
+        } else {
+            // 専用のcommandがなければTMS=Lのbit列として送る
+            let tms = [false; IDLE_CHUNK];
+            let chunk = IDLE_CHUNK.min(caps.max_bits_per_transfer).max(1);
+            let mut rest = n;
+            while rest > 0 {
+                let length = rest.min(chunk);
+                let result = self.interface.write_tms(&tms[..length]);
+                self.track(result)?;
+                rest -= length;
+            }
+        }
+        self.record(Scan::Tms, n, false, timer);
+        for _ in 0..n {
+            self.advance(false);
+        }
+        Ok(())
+    }
+
+    // 長いDRをchunk_bitsずつshiftし、chunkの間はPause-DRで止める
+    // 各chunkの最後のbitでExit1-DRへ出るので、TAPから見ると1回のshiftと同じになる
+    pub fn read_write_dr_chunked(
+        &mut self,
+        data: &mut [bool],
+        chunk_bits: usize,
+    ) -> Result<(), InterfaceError> {
+        if !self.initialized {
+            return Err(InterfaceError::NotInitialized);
+        }
+        if chunk_bits == 0 {
+            return Err(InterfaceError::OutOfRange);
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        match self.state_machine.state() {
+            JS::Reset | JS::RunIdle | JS::ShiftDR => (),
+            _ => self.change_state(JS::RunIdle)?,
+        };
+        self.change_state(JS::ShiftDR)?;
+
+        // backendが1回で送れる長さを超えないようにする
+        let chunk_bits = chunk_bits.min(self.interface.capabilities().max_bits_per_transfer.max(1));
+        let mut chunks = data.chunks_mut(chunk_bits).peekable();
+        while let Some(chunk) = chunks.next() {
+            let last = chunks.peek().is_none();
+            self.shift_chunk(chunk, last)?;
+        }
+        // Exit1 -> RunIdle
+        self.change_state(JS::RunIdle)
+    }
+
+    // Shift-DR/Shift-IRで長いscanの一部をshiftする
+    // lastでなければPauseを経由してShiftへ戻るので、次のchunkが同じscanの続きになる
+    // lastならExit1に留まる。呼ぶ側が残りのbitを全て持たなくてよい
+    pub fn shift_chunk(&mut self, chunk: &mut [bool], last: bool) -> Result<(), InterfaceError> {
+        let (pause, exit2, shift) = match self.state_machine.state() {
+            JS::ShiftDR => (JS::PauseDR, JS::Exit2DR, JS::ShiftDR),
+            JS::ShiftIR => (JS::PauseIR, JS::Exit2IR, JS::ShiftIR),
+            from => {
+                return Err(InterfaceError::UnsupportedTransition {
+                    from: *from,
+                    to: JS::Exit1DR,
+                })
+            }
+        };
+        // 0 bitではExit1へ出られない
+        if chunk.is_empty() {
+            return Err(InterfaceError::OutOfRange);
+        }
+        self.raw_read_data(chunk, true)?;
+        if !last {
+            // Exit1 -> Pause -> Exit2 -> Shift
+            self.change_state(pause)?;
+            self.change_state(exit2)?;
+            self.change_state(shift)?;
+        }
+        Ok(())
+    }
+
+    // 先頭のflush分の0に続けて1を流し、1が出てくるまでの遅れを数える
+    fn measure_delay(&mut self, buffer: &mut [bool]) -> Result<Option<usize>, InterfaceError> {
+        let flush = buffer.len() / 2;
+        for (i, x) in buffer.iter_mut().enumerate() {
+            *x = i >= flush;
+        }
+        self.raw_read_data(buffer, true)?;
+        Ok(buffer[flush..].iter().position(|x| *x))
+    }
+
+    // IRに全て1を入れて全TAPをBYPASSにし、chainの長さを調べる
+    pub fn detect_chain(&mut self) -> Result<ChainInfo, InterfaceError> {
+        self.change_state(JS::Reset)?;
+        self.change_state(JS::ShiftIR)?;
+        let mut ir = [false; IR_TOTAL_MAX * 2];
+        let ir_total = self.measure_delay(&mut ir)?;
+        self.change_state(JS::RunIdle)?;
+
+        // BYPASSはCapture-DRで0を取り込む1bitのregister
+        self.change_state(JS::ShiftDR)?;
+        let mut dr = [false; TAP_DEVICE_MAX * 2];
+        let devices = self.measure_delay(&mut dr)?;
+        self.change_state(JS::Reset)?;
+
+        let (ir_total, devices) = match (ir_total, devices) {
+            (Some(ir_total), Some(devices)) if ir_total > 0 && devices > 0 => (ir_total, devices),
+            _ => {
+                warn!("no devices found in chain");
+                return Err(InterfaceError::EmptyChain);
+            }
+        };
+        let chain = ChainInfo {
+            devices,
+            ir_total,
+            ir_lens: ChainInfo::split_ir(&ir[..ir_total], devices),
+        };
+        info!(
+            "{} devices, IR {} bits in total, IR lengths {:?}",
+            devices,
+            ir_total,
+            chain.ir_lens()
+        );
+        Ok(chain)
+    }
+
+    // Test-Logic-ResetでIDCODEを選んでscanし、その前に選ばれていたIRを書き戻す
+    pub fn scan(&mut self) -> Result<ScanResult, InterfaceError> {
+        let saved = self.save_ir();
         debug!("change state to Reset");
-        self.change_state(JS::Reset);
-        debug!("change state to ShiftDR");
-        self.change_state(JS::ShiftDR);
-        // send 0x0ff
-        let mut data = [false; TAP_DEVICE_MAX * 32];
-        let mut dummy_id: u32 = 0x0000_00ff;
-        for i in 0..32 {
-            data[i] = (dummy_id & 1) != 0;
-            dummy_id >>= 1;
+        self.change_state(JS::Reset)?;
+        self.initialized = true;
+        let result = self.scan_idcodes()?;
+        self.restore_ir(saved)?;
+        Ok(result)
+    }
+
+    // set_idcode_irで設定したIRでIDCODEを選んでscanし、resetせずに元のIRへ戻す
+    // 設定していなければscanと同じくTest-Logic-Resetを使う
+    pub fn rescan_preserving_state(&mut self) -> Result<ScanResult, InterfaceError> {
+        if !self.initialized {
+            return Err(InterfaceError::NotInitialized);
+        }
+        if self.idcode_ir_len == 0 {
+            return self.scan();
+        }
+        let saved = self.save_ir();
+        let mut idcode_ir = self.idcode_ir;
+        self.write_ir(&mut idcode_ir[..self.idcode_ir_len], true, false)?;
+        let result = self.scan_idcodes()?;
+        self.restore_ir(saved)?;
+        Ok(result)
+    }
+
+    // initialize時にscanした、TDOに一番近いTAPのIDCODE
+    pub fn scanned_idcode(&self) -> Option<u32> {
+        match self.devices().first() {
+            Some(TapDevice::IdCode(idcode)) => Some(idcode.raw),
+            _ => None,
+        }
+    }
+
+    // TDOに一番近いTAPのIDCODEだけを読む。scanした値との比較はdap::check_idcodeで行う
+    // set_idcode_irで設定したIRを使い、なければTest-Logic-ResetでIDCODEを選ぶ
+    pub fn ping(&mut self) -> Result<u32, InterfaceError> {
+        if !self.initialized {
+            return Err(InterfaceError::NotInitialized);
+        }
+        // IDCODEを持たないTAPでは比べる値がない
+        if self.scanned_idcode().is_none() {
+            return Err(InterfaceError::Unsupported);
+        }
+        let saved = self.save_ir();
+        if self.idcode_ir_len == 0 {
+            self.change_state(JS::Reset)?;
+        } else {
+            let mut idcode_ir = self.idcode_ir;
+            self.write_ir(&mut idcode_ir[..self.idcode_ir_len], true, false)?;
+        }
+        let mut data = [false; IDCODE_LEN];
+        self.read_write_dr(&mut data, true, false, false)?;
+        self.restore_ir(saved)?;
+        let found = bits::field(&data, 0, IDCODE_LEN) as u32;
+        if found == 0 || found == 0xffff_ffff {
+            warn!("ping: IDCODE read as {:#010x}", found);
+            return Err(InterfaceError::EmptyChain);
+        }
+        Ok(found)
+    }
+
+    // chain全体をIDCODEにするIRを、TDOに近いTAPの分から並べて渡す
+    pub fn set_idcode_ir(&mut self, ir: &[bool]) -> Result<(), InterfaceError> {
+        if ir.len() > IR_TOTAL_MAX {
+            return Err(InterfaceError::OutOfRange);
+        }
+        self.idcode_ir[..ir.len()].copy_from_slice(ir);
+        self.idcode_ir_len = ir.len();
+        Ok(())
+    }
+
+    // 最後にwrite_irで書いたchain全体のIR。reset等でIRが変わった後はNone
+    pub fn last_ir(&self) -> Option<&[bool]> {
+        match self.last_ir_len {
+            0 => None,
+            len => Some(&self.last_ir[..len]),
+        }
+    }
+
+    // TAP::write_instructionで書いた、chain上のpositionのTAPの命令
+    pub fn tap_ir(&self, position: usize) -> Option<u32> {
+        self.tap_irs.get(position).copied().flatten()
+    }
+
+    fn set_tap_ir(&mut self, position: usize, ir: u32) {
+        if let Some(x) = self.tap_irs.get_mut(position) {
+            *x = Some(ir);
+        }
+    }
+
+    // reset等でIRが変わり、何が入っているか分からなくなった
+    fn forget_ir(&mut self) {
+        self.last_ir_len = 0;
+        self.tap_irs = [None; TAP_DEVICE_MAX];
+    }
+
+    fn save_ir(&self) -> SavedIr {
+        SavedIr {
+            ir: self.last_ir,
+            len: self.last_ir_len,
+            tap_irs: self.tap_irs,
+        }
+    }
+
+    fn restore_ir(&mut self, saved: SavedIr) -> Result<(), InterfaceError> {
+        if saved.len == 0 {
+            // IDCODEのまま
+            self.forget_ir();
+            return Ok(());
         }
+        debug!("restore IR");
+        let mut ir = saved.ir;
+        self.write_ir(&mut ir[..saved.len], true, false)?;
+        self.tap_irs = saved.tap_irs;
+        Ok(())
+    }
+
+    fn scan_idcodes(&mut self) -> Result<ScanResult, InterfaceError> {
+        debug!("change state to ShiftDR");
+        self.change_state(JS::ShiftDR)?;
+        // 全deviceのDRの後ろからsentinelが出てくるので、1device分多めに読む
+        let mut buffer = [false; (TAP_DEVICE_MAX + 1) * IDCODE_LEN];
+        let data = &mut buffer[..(self.scan_limit + 1) * IDCODE_LEN];
+        bits::set_field(data, 0, IDCODE_LEN, SCAN_SENTINEL as u64);
         debug!("write dummy id");
-        self.read_write_dr(&mut data, true, false, false);
+        self.read_write_dr(data, true, false, false)?;
 
+        self.devices = [TapDevice::Bypass; TAP_DEVICE_MAX];
+        self.device_count = 0;
+        let mut found_sentinel = false;
         let mut i = 0;
-        let mut tap_device_counter = 0;
         let end = data.len();
-        while i < end {
+        while i < end && self.device_count < self.scan_limit {
             if data[i] {
                 // 頭が1ならIDCODEの可能性あり
                 // 残り31bitを調査
-                let idcode = data[(i..i + 32)]
-                    .iter()
-                    .rev()
-                    .fold(0, |x, y| (x << 1) | *y as u32);
-                i += 32;
-                if idcode == 0x0000_00ff {
+                if i + IDCODE_LEN > end {
                     break;
                 }
-                // Continuation code
-                let cc = (idcode >> 8) & 0b1111;
-                // Identity code
-                let id = (idcode >> 1) & 0b0111_1111;
-                info!(
-                    "{} device (IDCODE:{:#08x}) found",
-                    jep106::JEP106Code::new(cc as u8, id as u8)
-                        .get()
-                        .unwrap_or("Unknown"),
-                    idcode
-                );
-                self.idcodes[tap_device_counter] = Some(idcode);
-                tap_device_counter += 1;
+                let idcode = bits::field(data, i, IDCODE_LEN) as u32;
+                i += IDCODE_LEN;
+                if idcode == SCAN_SENTINEL {
+                    found_sentinel = true;
+                    break;
+                }
+                let idcode = match IdCode::try_from(idcode) {
+                    Ok(idcode) => idcode,
+                    Err(e) => {
+                        // TDOが1に張り付いている場合など、以降は信用できない
+                        warn!("{} found, chain may be broken", e);
+                        break;
+                    }
+                };
+                info!("{} found", idcode.display(self.names));
+                self.devices[self.device_count] = TapDevice::IdCode(idcode);
             } else {
+                // BYPASSは1bit
                 info!("bypass device found");
-                self.idcodes[tap_device_counter] = Some(0);
-                tap_device_counter += 1;
+                self.devices[self.device_count] = TapDevice::Bypass;
                 i += 1;
             }
+            self.device_count += 1;
+        }
+        if !found_sentinel {
+            warn!(
+                "scan stopped at {} devices before the end of the chain",
+                self.device_count
+            );
         }
+        Ok(ScanResult {
+            devices: self.devices,
+            count: self.device_count,
+            complete: found_sentinel,
+        })
     }
 }
 
 pub struct TAP<'a, T: JtagInterface> {
     pub jtag: &'a Mutex<Jtag<T>>,
     pub ir_len: usize,
+    // chain上で自分よりTDO側にあるdeviceの数とIR長の合計
+    pub devices_before: usize,
+    pub ir_before: usize,
+    // chain上で自分よりTDI側にあるdeviceの数とIR長の合計
+    pub devices_after: usize,
+    pub ir_after: usize,
+    // IR/DRのscanの後にRun-Test/Idleで入れるTCKの数
+    pub post_scan_idle: usize,
+    // falseならdropしてもTAPをresetしない。同じchainを他のTAPやDAPが使い続ける場合向け
+    pub reset_on_drop: bool,
+    // 最後に書いた命令。他のTAPがIRを書いていたらDRのscanの前に書き直す
+    instruction: Option<u32>,
 }
 
 impl<'a, T: JtagInterface> TAP<'a, T> {
-    // TODO: IRの位置をずらす機能の追加
-    pub fn write_instruction(&mut self, instruction: u8) {
-        let mut ir = [false; 8];
-        let mut tmp = instruction;
-        for i in 0..self.ir_len {
-            ir[i] = (tmp & 1) != 0;
-            tmp = tmp >> 1;
+    // chainにTAPが1つしかない場合
+    pub fn new(jtag: &'a Mutex<Jtag<T>>, ir_len: usize) -> Self {
+        TAP {
+            jtag,
+            ir_len,
+            devices_before: 0,
+            ir_before: 0,
+            devices_after: 0,
+            ir_after: 0,
+            post_scan_idle: 0,
+            reset_on_drop: true,
+            instruction: None,
+        }
+    }
+
+    // ir_lensはJtag::devices()と同じくTDOに近い順に並べる
+    // positionがchainの外ならOutOfRange
+    pub fn in_chain(
+        jtag: &'a Mutex<Jtag<T>>,
+        ir_lens: &[usize],
+        position: usize,
+    ) -> Result<Self, InterfaceError> {
+        let ir_len = *ir_lens.get(position).ok_or(InterfaceError::OutOfRange)?;
+        Ok(TAP {
+            jtag,
+            ir_len,
+            devices_before: position,
+            ir_before: ir_lens[..position].iter().sum(),
+            devices_after: ir_lens.len() - position - 1,
+            ir_after: ir_lens[position + 1..].iter().sum(),
+            post_scan_idle: 0,
+            reset_on_drop: true,
+            instruction: None,
+        })
+    }
+
+    // profileのchainから作る。ir_lensとtapはConfig側で検証済みだが、
+    // ChainConfigを直接組み立てた場合に備えてin_chainの検査はそのまま返す
+    #[cfg(feature = "std")]
+    pub fn new_with_config(
+        jtag: &'a Mutex<Jtag<T>>,
+        chain: &ChainConfig,
+    ) -> Result<Self, InterfaceError> {
+        Self::in_chain(jtag, &chain.ir_lens, chain.tap)
+    }
+
+    // detect_chainでIR長が分かった場合だけ作れる
+    pub fn from_chain(
+        jtag: &'a Mutex<Jtag<T>>,
+        chain: &ChainInfo,
+        position: usize,
+    ) -> Result<Self, InterfaceError> {
+        match chain.ir_lens() {
+            Some(ir_lens) => Self::in_chain(jtag, ir_lens, position),
+            None => Err(InterfaceError::OutOfRange),
+        }
+    }
+
+    // accessの度にidle cycleが要るtarget向け。0なら何も入れない
+    pub fn set_post_scan_idle(&mut self, n: usize) {
+        self.post_scan_idle = n;
+    }
+
+    pub fn set_reset_on_drop(&mut self, reset: bool) {
+        self.reset_on_drop = reset;
+    }
+
+    fn insert_idle(&self, jtag: &mut Jtag<T>) -> Result<(), InterfaceError> {
+        if self.post_scan_idle > 0 {
+            jtag.idle_cycles(self.post_scan_idle)?;
         }
+        Ok(())
+    }
+
+    pub fn write_instruction(&mut self, instruction: u8) -> Result<(), InterfaceError> {
         let mut jtag = self.jtag.lock();
-        jtag.write_ir(&mut ir[0..self.ir_len], true, false);
-        drop(jtag);
+        self.write_ir_locked(&mut jtag, instruction as u32)
+    }
+
+    fn write_ir_locked(&mut self, jtag: &mut Jtag<T>, ir: u32) -> Result<(), InterfaceError> {
+        if self.ir_len > IR_LEN_MAX {
+            return Err(InterfaceError::OutOfRange);
+        }
+        let mut ir_bits = [false; IR_LEN_MAX];
+        let ir_bits = &mut ir_bits[..self.ir_len];
+        bits::fill_lsb_first(ir_bits, ir as u64);
+        self.instruction = None;
+        jtag.write_ir_padded(ir_bits, self.ir_before, self.ir_after, true, false)?;
+        jtag.set_tap_ir(self.devices_before, ir);
+        self.instruction = Some(ir);
+        self.insert_idle(jtag)
+    }
+
+    // 同じchainの他のTAPがIRを書いた後なら、自分の命令を書き直す
+    fn reselect_instruction(&mut self, jtag: &mut Jtag<T>) -> Result<(), InterfaceError> {
+        match self.instruction {
+            Some(ir) if jtag.tap_ir(self.devices_before) != Some(ir) => {
+                debug!(
+                    "IR of TAP {} was overwritten, rewrite {:#x}",
+                    self.devices_before, ir
+                );
+                self.write_ir_locked(jtag, ir)
+            }
+            _ => Ok(()),
+        }
     }
     pub fn read_write_dr(
         &mut self,
@@ -223,49 +1147,239 @@ impl<'a, T: JtagInterface> TAP<'a, T> {
         exit: bool,
         reverse_input: bool,
         reverse_output: bool,
-    ) {
+    ) -> Result<(), InterfaceError> {
+        let mut jtag = self.jtag.lock();
+        self.reselect_instruction(&mut jtag)?;
+        jtag.read_write_dr_padded(
+            data,
+            self.devices_before,
+            self.devices_after,
+            exit,
+            reverse_input,
+            reverse_output,
+        )?;
+        self.insert_idle(&mut jtag)
+    }
+
+    pub fn read_write_dr_packed(
+        &mut self,
+        data: &mut [u8],
+        len: usize,
+        exit: bool,
+    ) -> Result<(), InterfaceError> {
+        let mut jtag = self.jtag.lock();
+        self.reselect_instruction(&mut jtag)?;
+        jtag.read_write_dr_padded_packed(data, len, self.devices_before, self.devices_after, exit)?;
+        self.insert_idle(&mut jtag)
+    }
+
+    // vendor固有の命令向け。IRとDRのscanを1回のlockで行う
+    pub fn scan_ir_dr(&mut self, ir: u32, dr: &mut [bool]) -> Result<(), InterfaceError> {
         let mut jtag = self.jtag.lock();
-        jtag.read_write_dr(data, exit, reverse_input, reverse_output);
-        drop(jtag);
+        self.write_ir_locked(&mut jtag, ir)?;
+        jtag.read_write_dr_padded(
+            dr,
+            self.devices_before,
+            self.devices_after,
+            true,
+            false,
+            false,
+        )?;
+        self.insert_idle(&mut jtag)
     }
 }
 
 impl<'a, T: JtagInterface> Drop for TAP<'a, T> {
     fn drop(&mut self) {
-        let mut jtag = self.jtag.lock();
-        jtag.change_state(JS::Reset);
+        if !self.reset_on_drop {
+            return;
+        }
+        // 別のpanicでunwind中にinterfaceを触ると、interfaceが壊れていた場合に
+        // 二重panicになりprocessごとabortしてしまう。元のpanicを隠さないようにresetは諦める
+        #[cfg(feature = "std")]
+        if std::thread::panicking() {
+            warn!("skip TAP reset on drop while panicking");
+            return;
+        }
+        // lockを持ったまま死んだ場合などにdrop内で永久に待たないようにする
+        match self.jtag.try_lock() {
+            // 状態が分からないTAPにTMSを送っても、Resetに着いたかは分からない
+            Some(jtag) if jtag.is_desynced() => {
+                warn!("skip TAP reset on drop: TAP state is unknown")
+            }
+            Some(mut jtag) => {
+                if let Err(e) = jtag.change_state(JS::Reset) {
+                    warn!("failed to reset TAP on drop: {}", e);
+                }
+            }
+            None => warn!("skip TAP reset on drop: jtag is locked"),
+        }
     }
 }
 
-mod tests {
+#[cfg(all(test, feature = "std"))]
+pub(crate) mod tests {
     use super::*;
+    use crate::interface::mock::{MockCall, MockInterface};
+    use crate::interface::InterfaceCaps;
+    use crate::jtag::dap::{DapError, DapInterface, DAP};
+    use core::cell::{Cell, RefCell};
+
+    // initializeまで済ませたJtag
+    pub(crate) fn initialized<T: JtagInterface>(interface: T) -> Jtag<T> {
+        let mut jtag = Jtag::new(interface);
+        jtag.initialize().unwrap();
+        jtag
+    }
 
-    struct DummyInterface;
-    impl JtagInterface for DummyInterface {
-        fn write_tms(&self, tms: &[bool]) {
-            ()
+    // USBが抜けた後のように、全ての操作でpanicするinterface
+    struct BrokenInterface;
+    impl JtagInterface for BrokenInterface {
+        fn raw_write(&self, _pins: &[JB]) -> Result<(), InterfaceError> {
+            panic!("interface is broken");
         }
-        fn write_data(&self, tdi: &[bool], exit: bool) {
-            ()
+        fn raw_read(&self, _buffer: &mut [JB]) -> Result<(), InterfaceError> {
+            panic!("interface is broken");
         }
-        fn read_data(&self, tditdo: &mut [bool], exit: bool) {
-            ()
+    }
+
+    fn raw_idcodes<T: JtagInterface>(jtag: &Jtag<T>) -> Vec<Option<u32>> {
+        jtag.devices().iter().map(|x| x.raw()).collect()
+    }
+
+    // Test-Logic-Reset直後のchainをscanした時のTDO
+    // 各deviceのDRがTDOに近い順に出てきて、その後ろにsentinelが遅れて出てくる
+    // devicesはTDIに近い順
+    fn chain_mock(devices: &[Option<u32>], scans: usize) -> MockInterface {
+        let mut tdo = Vec::new();
+        for device in devices.iter().rev() {
+            match device {
+                Some(idcode) => tdo.extend(bits::to_bools_lsb_first(*idcode as u64, 32)),
+                None => tdo.push(false),
+            }
         }
+        tdo.extend(bits::to_bools_lsb_first(SCAN_SENTINEL as u64, 32));
+        let mock = MockInterface::new();
+        for n in 0..scans {
+            mock.script_read(n, &tdo);
+        }
+        mock
+    }
+
+    struct SimDevice {
+        idcode: u32,
+        ir_len: usize,
+        ir: u32,
+        ir_shift: u64,
+        dr_shift: u64,
+        // DPACCのDRは前回Update-DRした値をcaptureする
+        dpacc: u64,
+        // vendor固有のIR_USERのDR。これも前回Update-DRした値をcaptureする
+        user: u64,
+    }
 
-        fn raw_write(&self, pins: &[JB]) {
-            ()
+    impl SimDevice {
+        fn new(idcode: u32, ir_len: usize) -> Self {
+            SimDevice {
+                idcode,
+                ir_len,
+                ir: IR_IDCODE,
+                ir_shift: 0,
+                dr_shift: 0,
+                dpacc: 0,
+                user: 0,
+            }
         }
-        fn raw_read(&self, buffer: &mut [JB]) {
-            // return 0x0000_00ff to pass IR scan
-            let mut dummy = 0x0000_00ff;
-            for i in 0..32 {
-                buffer[i] = if (dummy & 1) != 0 {
-                    JB::TDO
-                } else {
-                    JB::empty()
+        fn dr_len(&self) -> usize {
+            match self.ir {
+                IR_IDCODE => 32,
+                IR_DPACC => 35,
+                IR_USER => 16,
+                _ => 1,
+            }
+        }
+    }
+
+    const IR_IDCODE: u32 = 0b1110;
+    const IR_DPACC: u32 = 0b1010;
+    const IR_USER: u32 = 0b0010;
+
+    // IDCODEとBYPASSを持つTAPを並べたchainのモデル
+    // devicesはTDOに近い順
+    struct SimChain {
+        state_machine: RefCell<StateMachine<JtagStateMachine>>,
+        devices: RefCell<Vec<SimDevice>>,
+        // Test-Logic-Resetに入った回数
+        resets: Cell<usize>,
+    }
+
+    impl SimChain {
+        fn new(devices: Vec<SimDevice>) -> Self {
+            SimChain {
+                state_machine: RefCell::new(StateMachine::new()),
+                devices: RefCell::new(devices),
+                resets: Cell::new(0),
+            }
+        }
+
+        fn clock(&self, pins: JB) -> bool {
+            let mut state_machine = self.state_machine.borrow_mut();
+            let mut devices = self.devices.borrow_mut();
+            let state = *state_machine.state();
+            let mut shift_in = pins.contains(JB::TDI);
+            let mut tdo = false;
+            // TDI側から順にshiftする
+            for device in devices.iter_mut().rev() {
+                let dr_len = device.dr_len();
+                let (register, len) = match state {
+                    JS::ShiftDR => (&mut device.dr_shift, dr_len),
+                    JS::ShiftIR => (&mut device.ir_shift, device.ir_len),
+                    _ => break,
                 };
-                dummy >>= 1;
+                tdo = *register & 1 != 0;
+                *register = (*register >> 1) | ((shift_in as u64) << (len - 1));
+                shift_in = tdo;
             }
+            state_machine.consume(&pins.contains(JB::TMS)).unwrap();
+            if state != JS::Reset && *state_machine.state() == JS::Reset {
+                self.resets.set(self.resets.get() + 1);
+            }
+            for device in devices.iter_mut() {
+                match state_machine.state() {
+                    JS::Reset => device.ir = IR_IDCODE,
+                    JS::CaptureDR => {
+                        device.dr_shift = match device.ir {
+                            IR_IDCODE => device.idcode as u64,
+                            IR_DPACC => device.dpacc,
+                            IR_USER => device.user,
+                            _ => 0,
+                        }
+                    }
+                    JS::UpdateDR if device.ir == IR_DPACC => device.dpacc = device.dr_shift,
+                    JS::UpdateDR if device.ir == IR_USER => device.user = device.dr_shift,
+                    JS::CaptureIR => device.ir_shift = 0b01,
+                    JS::UpdateIR => device.ir = device.ir_shift as u32,
+                    _ => (),
+                }
+            }
+            tdo
+        }
+    }
+
+    impl JtagInterface for SimChain {
+        fn raw_write(&self, pins: &[JB]) -> Result<(), InterfaceError> {
+            for x in pins {
+                self.clock(*x);
+            }
+            Ok(())
+        }
+        fn raw_read(&self, buffer: &mut [JB]) -> Result<(), InterfaceError> {
+            for x in buffer.iter_mut() {
+                if self.clock(*x) {
+                    *x |= JB::TDO;
+                }
+            }
+            Ok(())
         }
     }
 
@@ -302,8 +1416,8 @@ mod tests {
 
     #[test]
     fn change_state_test() {
-        let interface = DummyInterface;
-        let mut jtag = Jtag::new(interface);
+        let interface = MockInterface::new();
+        let mut jtag = initialized(interface);
 
         // to Reset
         let froms = [
@@ -327,7 +1441,7 @@ mod tests {
         for s in froms {
             jtag.debug_set_state(s);
             assert_eq!(s, jtag.state());
-            jtag.change_state(JS::Reset);
+            jtag.change_state(JS::Reset).unwrap();
             assert_eq!(
                 JS::Reset,
                 jtag.state(),
@@ -344,7 +1458,7 @@ mod tests {
         for from in froms {
             jtag.debug_set_state(from);
             assert_eq!(from, jtag.state());
-            jtag.change_state(to);
+            jtag.change_state(to).unwrap();
             assert_eq!(
                 to,
                 jtag.state(),
@@ -363,7 +1477,7 @@ mod tests {
             jtag.debug_set_state(from);
             assert_eq!(from, jtag.state());
             println!("{:?}", jtag.state());
-            jtag.change_state(to);
+            jtag.change_state(to).unwrap();
             println!("{:?}", jtag.state());
             assert_eq!(
                 to,
@@ -383,7 +1497,7 @@ mod tests {
             jtag.debug_set_state(from);
             assert_eq!(from, jtag.state());
             println!("{:?}", jtag.state());
-            jtag.change_state(to);
+            jtag.change_state(to).unwrap();
             println!("{:?}", jtag.state());
             assert_eq!(
                 to,
@@ -403,7 +1517,7 @@ mod tests {
             jtag.debug_set_state(from);
             assert_eq!(from, jtag.state());
             println!("{:?}", jtag.state());
-            jtag.change_state(to);
+            jtag.change_state(to).unwrap();
             println!("{:?}", jtag.state());
             assert_eq!(
                 to,
@@ -423,7 +1537,7 @@ mod tests {
             jtag.debug_set_state(from);
             assert_eq!(from, jtag.state());
             println!("{:?}", jtag.state());
-            jtag.change_state(to);
+            jtag.change_state(to).unwrap();
             println!("{:?}", jtag.state());
             assert_eq!(
                 to,
@@ -443,7 +1557,7 @@ mod tests {
             jtag.debug_set_state(from);
             assert_eq!(from, jtag.state());
             println!("{:?}", jtag.state());
-            jtag.change_state(to);
+            jtag.change_state(to).unwrap();
             println!("{:?}", jtag.state());
             assert_eq!(
                 to,
@@ -456,6 +1570,68 @@ mod tests {
             );
         }
 
+        // to PauseDR
+        let froms = [JS::Reset, JS::RunIdle, JS::Exit1DR];
+        let to = JS::PauseDR;
+        for from in froms {
+            jtag.debug_set_state(from);
+            assert_eq!(from, jtag.state());
+            jtag.change_state(to).unwrap();
+            assert_eq!(
+                to,
+                jtag.state(),
+                "expected state {:?} -> {:?}, but actual state {:?} -> {:?}",
+                from,
+                to,
+                from,
+                jtag.state()
+            );
+        }
+
+        // to PauseIR
+        let froms = [JS::Reset, JS::RunIdle, JS::Exit1IR];
+        let to = JS::PauseIR;
+        for from in froms {
+            jtag.debug_set_state(from);
+            assert_eq!(from, jtag.state());
+            jtag.change_state(to).unwrap();
+            assert_eq!(
+                to,
+                jtag.state(),
+                "expected state {:?} -> {:?}, but actual state {:?} -> {:?}",
+                from,
+                to,
+                from,
+                jtag.state()
+            );
+        }
+
+        // Pause/Exit2を経由してShiftへ戻る
+        let round_trips = [
+            (JS::PauseDR, JS::Exit2DR),
+            (JS::Exit2DR, JS::ShiftDR),
+            (JS::PauseDR, JS::ShiftDR),
+            (JS::Exit2DR, JS::UpdateDR),
+            (JS::PauseIR, JS::Exit2IR),
+            (JS::Exit2IR, JS::ShiftIR),
+            (JS::PauseIR, JS::ShiftIR),
+            (JS::Exit2IR, JS::UpdateIR),
+        ];
+        for (from, to) in round_trips {
+            jtag.debug_set_state(from);
+            assert_eq!(from, jtag.state());
+            jtag.change_state(to).unwrap();
+            assert_eq!(
+                to,
+                jtag.state(),
+                "expected state {:?} -> {:?}, but actual state {:?} -> {:?}",
+                from,
+                to,
+                from,
+                jtag.state()
+            );
+        }
+
         // to UpdateIR
         let froms = [JS::Exit1IR];
         let to = JS::UpdateIR;
@@ -463,7 +1639,7 @@ mod tests {
             jtag.debug_set_state(from);
             assert_eq!(from, jtag.state());
             println!("{:?}", jtag.state());
-            jtag.change_state(to);
+            jtag.change_state(to).unwrap();
             println!("{:?}", jtag.state());
             assert_eq!(
                 to,
@@ -476,4 +1652,837 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn tap_drop_while_panicking_test() {
+        // Jtag::newはinterfaceに触らない
+        let jtag = Mutex::new(Jtag::new(BrokenInterface));
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _tap = TAP::new(&jtag, 4);
+            panic!("original error");
+        }));
+
+        // abortせず、元のpanicが観測できること
+        let payload = result.unwrap_err();
+        assert_eq!(Some(&"original error"), payload.downcast_ref::<&str>());
+    }
+
+    #[test]
+    fn tap_drop_desynced_test() {
+        let jtag = Mutex::new(initialized(MockInterface::new()));
+        jtag.lock().interface.set_ir_capture(true);
+        jtag.lock().interface.drop_tms_bit(0);
+        jtag.lock().change_state(JS::ShiftDR).unwrap();
+        assert_eq!(Err(InterfaceError::Desynced), jtag.lock().verify_state());
+        assert!(jtag.lock().is_desynced());
+
+        jtag.lock().interface.clear();
+        drop(TAP::new(&jtag, 4));
+        assert!(jtag.lock().interface.transcript().is_empty());
+
+        // resyncすればresetする
+        jtag.lock().resync().unwrap();
+        assert!(!jtag.lock().is_desynced());
+        jtag.lock().change_state(JS::RunIdle).unwrap();
+        jtag.lock().interface.clear();
+        drop(TAP::new(&jtag, 4));
+        assert_eq!(JS::Reset, jtag.lock().interface.state());
+    }
+
+    #[test]
+    fn tap_drop_disconnected_test() {
+        let jtag = Mutex::new(initialized(MockInterface::new()));
+        jtag.lock().change_state(JS::RunIdle).unwrap();
+        // USBが抜けた
+        jtag.lock().interface.fail_with(Some(InterfaceError::Io));
+        assert_eq!(
+            Err(InterfaceError::Io),
+            jtag.lock().change_state(JS::ShiftDR)
+        );
+        assert!(jtag.lock().is_desynced());
+
+        jtag.lock().interface.fail_with(None);
+        jtag.lock().interface.clear();
+        drop(TAP::new(&jtag, 4));
+        assert!(jtag.lock().interface.transcript().is_empty());
+    }
+
+    #[test]
+    fn not_initialized_test() {
+        let mut jtag = Jtag::new(MockInterface::new());
+        assert!(!jtag.is_initialized());
+        let mut ir = [true; 4];
+        assert_eq!(
+            Err(InterfaceError::NotInitialized),
+            jtag.write_ir(&mut ir, true, false)
+        );
+        let mut dr = [false; 32];
+        assert_eq!(
+            Err(InterfaceError::NotInitialized),
+            jtag.read_write_dr(&mut dr, true, false, false)
+        );
+        // TAPに何も送っていない
+        assert!(jtag.interface.transcript().is_empty());
+
+        let interface = chain_mock(&[Some(0x4ba0_0477), None], 1);
+        let mut jtag = Jtag::new(interface);
+        let result = jtag.initialize().unwrap();
+        assert!(jtag.is_initialized());
+        assert!(result.is_complete());
+        assert_eq!(jtag.devices(), result.devices());
+        assert_eq!(2, result.devices().len());
+    }
+
+    #[test]
+    fn scan_chain_test() {
+        // TDIに近い順
+        let interface = chain_mock(&[Some(0x4ba0_0477), None, Some(0x0362_d093)], 1);
+        let jtag = initialized(interface);
+        assert_eq!(
+            vec![Some(0x0362_d093), None, Some(0x4ba0_0477)],
+            raw_idcodes(&jtag)
+        );
+        // Reset -> ShiftDRでscanし、Resetに戻る
+        let mut tms = vec![true; 5];
+        tms.extend(&[false, true, false, false]);
+        tms.extend(vec![false; (TAP_DEVICE_MAX + 1) * IDCODE_LEN - 1]);
+        tms.extend(&[true, true, false]);
+        tms.extend(&[true; 5]);
+        jtag.interface.expect_tms_sequence(&tms);
+        assert_eq!(JS::Reset, jtag.interface.state());
+        // sentinelを流し込む
+        let tdi = &jtag.interface.tdi_sequence()[0];
+        let sentinel = bits::field(tdi, 0, IDCODE_LEN) as u32;
+        assert_eq!(SCAN_SENTINEL, sentinel);
+
+        let interface = chain_mock(&[None, Some(0x4ba0_0477), None, None, Some(0x5ba0_0477)], 1);
+        let jtag = initialized(interface);
+        assert_eq!(
+            vec![Some(0x5ba0_0477), None, None, Some(0x4ba0_0477), None],
+            raw_idcodes(&jtag)
+        );
+    }
+
+    #[test]
+    fn switch_protocol_test() {
+        let interface = chain_mock(&[Some(0x4ba0_0477)], 1);
+        let jtag = {
+            let mut jtag = Jtag::new_with_switch(interface, SwitchProtocol::SwdToJtag);
+            jtag.initialize().unwrap();
+            jtag
+        };
+        assert_eq!(vec![Some(0x4ba0_0477)], raw_idcodes(&jtag));
+        // 切り替えのsequenceの後にscanする
+        let tms = jtag.interface.tms_sequence();
+        let switch: Vec<bool> = bits::to_bools_lsb_first(0xE73C, 16).collect();
+        assert_eq!(vec![true; 50], tms[..50]);
+        assert_eq!(switch, tms[50..66]);
+        assert_eq!(vec![true; 50 + 5], tms[66..121]);
+        assert_eq!([false, true, false, false], tms[121..125]);
+    }
+
+    #[test]
+    fn reset_test() {
+        let mut jtag = initialized(MockInterface::new());
+        jtag.change_state(JS::ShiftDR).unwrap();
+        jtag.interface.clear();
+        jtag.hardware_reset_tap().unwrap();
+        assert_eq!(JS::Reset, jtag.state());
+        assert_eq!(JS::Reset, jtag.interface.state());
+        jtag.reset_target().unwrap();
+        assert_eq!(JS::Reset, jtag.state());
+        assert_eq!(
+            vec![
+                MockCall::Trst(true),
+                MockCall::Trst(false),
+                MockCall::Srst(true),
+                MockCall::Srst(false)
+            ],
+            jtag.interface.transcript()
+        );
+
+        // pinを持たないbackend
+        jtag.interface.fail_with(Some(InterfaceError::Unsupported));
+        assert_eq!(Err(InterfaceError::Unsupported), jtag.reset_target());
+    }
+
+    #[test]
+    fn detect_chain_test() {
+        // TDOに近い順にIR長4(capture 0b0001)と5(capture 0b10001)のTAP
+        let capture = [true, false, false, false, true, false, false, false, true];
+        let mut ir_tdo = capture.to_vec();
+        ir_tdo.extend(vec![false; IR_TOTAL_MAX]);
+        ir_tdo.resize(IR_TOTAL_MAX * 2, true);
+        let mut dr_tdo = vec![false; 2 + TAP_DEVICE_MAX];
+        dr_tdo.resize(TAP_DEVICE_MAX * 2, true);
+
+        let jtag = Mutex::new(initialized(MockInterface::new()));
+        {
+            let jtag = jtag.lock();
+            jtag.interface.script_next_read(&ir_tdo);
+            jtag.interface
+                .script_read(jtag.interface.reads() + 1, &dr_tdo);
+        }
+        let chain = jtag.lock().detect_chain().unwrap();
+        assert_eq!(2, chain.devices());
+        assert_eq!(9, chain.ir_total());
+        assert_eq!(Some(&[4, 5][..]), chain.ir_lens());
+        assert_eq!(JS::Reset, jtag.lock().state());
+        // IRは全て1で終わり、全TAPがBYPASSになる
+        let tdi = jtag.lock().interface.tdi_sequence();
+        assert!(tdi[tdi.len() - 2].ends_with(&[true; IR_TOTAL_MAX]));
+
+        let tap = TAP::from_chain(&jtag, &chain, 1).unwrap();
+        assert_eq!((5, 4, 0), (tap.ir_len, tap.ir_before, tap.ir_after));
+        assert_eq!((1, 0), (tap.devices_before, tap.devices_after));
+        assert!(TAP::from_chain(&jtag, &chain, 2).is_err());
+    }
+
+    #[test]
+    fn split_ir_test() {
+        let capture = [true, false, false, false, true, false, true, false, true];
+        // 2つ目のTAPのcaptureに"1, 0"が含まれると区切れない
+        assert_eq!(None, ChainInfo::split_ir(&capture, 2));
+        assert_eq!(
+            Some(&[4, 2, 3][..]),
+            ChainInfo::split_ir(&capture, 3).as_ref().map(|x| &x[..3])
+        );
+        assert_eq!(None, ChainInfo::split_ir(&[false, true], 1));
+    }
+
+    #[test]
+    fn scan_stuck_tdo_test() {
+        // TDOが1に張り付いていると0xffffffffが続く
+        let interface = MockInterface::new();
+        interface.script_read(0, &[true; (TAP_DEVICE_MAX + 1) * IDCODE_LEN]);
+        let jtag = initialized(interface);
+        assert!(jtag.devices().is_empty());
+    }
+
+    #[test]
+    fn scan_limit_test() {
+        let interface = chain_mock(&[Some(0x4ba0_0477); 4], 2);
+        let mut jtag = initialized(interface);
+        assert_eq!(4, raw_idcodes(&jtag).len());
+        jtag.set_scan_limit(2);
+        jtag.scan().unwrap();
+        assert_eq!(vec![Some(0x4ba0_0477); 2], raw_idcodes(&jtag));
+        // limit+1 device分だけ読む
+        match jtag
+            .interface
+            .transcript()
+            .iter()
+            .rev()
+            .find(|x| matches!(x, MockCall::ReadData { .. }))
+        {
+            Some(MockCall::ReadData { tdi, .. }) => assert_eq!(3 * IDCODE_LEN, tdi.len()),
+            _ => panic!("no scan"),
+        }
+    }
+
+    #[test]
+    fn write_ir_test() {
+        let mut jtag = initialized(MockInterface::new());
+        jtag.interface.clear();
+        jtag.interface.script_next_read(&[true, false, false, true]);
+        let mut ir = [false, true, true, true];
+        jtag.write_ir(&mut ir, true, true).unwrap();
+        // Reset -> RunIdle -> ShiftIR -> Exit1IR -> RunIdle
+        jtag.interface.expect_tms_sequence(&[
+            false, true, true, false, false, false, false, false, true, true, false,
+        ]);
+        // reverseしてLSBから送り、captureも同じ順に戻して返す
+        assert_eq!(
+            vec![vec![true, true, true, false]],
+            jtag.interface.tdi_sequence()
+        );
+        assert_eq!([true, false, false, true], ir);
+        assert_eq!(JS::RunIdle, jtag.interface.state());
+        assert_eq!(JS::RunIdle, jtag.state());
+    }
+
+    #[test]
+    fn verify_ir_capture_test() {
+        let mut jtag = initialized(MockInterface::new());
+        // 検証しなければcaptureが全て0でも通る
+        let mut ir = [true; 4];
+        jtag.write_ir(&mut ir, true, false).unwrap();
+        assert_eq!([false; 4], ir);
+
+        jtag.set_verify_ir_capture(true);
+        jtag.interface
+            .script_next_read(&[true, false, false, false]);
+        jtag.write_ir(&mut [true; 4], true, false).unwrap();
+        // 下位2bitが11
+        jtag.interface.script_next_read(&[true, true, false, false]);
+        assert_eq!(
+            Err(InterfaceError::BadIrCapture),
+            jtag.write_ir(&mut [true; 4], true, false)
+        );
+        // errorでもRun-Test/Idleには戻っている
+        assert_eq!(JS::RunIdle, jtag.interface.state());
+
+        // TDO側のTAPのcaptureが崩れている
+        let jtag = Mutex::new(jtag);
+        let mut tap = TAP::in_chain(&jtag, &[4, 4], 1).unwrap();
+        jtag.lock()
+            .interface
+            .script_next_read(&[false, false, false, false, true, false, false, false]);
+        assert_eq!(
+            Err(InterfaceError::BadIrCapture),
+            tap.write_instruction(0xe)
+        );
+        jtag.lock()
+            .interface
+            .script_next_read(&[true, false, true, true, true, false, false, false]);
+        tap.write_instruction(0xe).unwrap();
+    }
+
+    #[test]
+    fn resync_test() {
+        let mut jtag = initialized(MockInterface::new());
+        jtag.interface.set_ir_capture(true);
+        jtag.verify_state().unwrap();
+        assert_eq!(JS::RunIdle, jtag.state());
+        assert_eq!(JS::RunIdle, jtag.interface.state());
+
+        // Run-Test/IdleからSelect-DR-Scanへ進むTMSを落とす
+        jtag.interface.drop_tms_bit(0);
+        jtag.change_state(JS::ShiftDR).unwrap();
+        assert_ne!(jtag.state(), jtag.interface.state());
+        assert_eq!(Err(InterfaceError::Desynced), jtag.verify_state());
+
+        jtag.interface.clear();
+        jtag.resync().unwrap();
+        jtag.interface.expect_tms_sequence(&[true; 5]);
+        assert_eq!(JS::Reset, jtag.state());
+        assert_eq!(JS::Reset, jtag.interface.state());
+        jtag.verify_state().unwrap();
+        assert_eq!(jtag.state(), jtag.interface.state());
+
+        assert_eq!(
+            Err(InterfaceError::NotInitialized),
+            Jtag::new(MockInterface::new()).verify_state()
+        );
+    }
+
+    #[test]
+    fn tap_padding_test() {
+        let chain = SimChain::new(vec![
+            SimDevice::new(0x4ba0_0477, 4),
+            SimDevice::new(0x0362_d093, 6),
+            SimDevice::new(0x5ba0_0477, 5),
+        ]);
+        let jtag = Mutex::new(initialized(chain));
+        jtag.lock().set_verify_ir_capture(true);
+        let ir_lens = [4, 6, 5];
+
+        for (position, idcode) in [0x4ba0_0477, 0x0362_d093, 0x5ba0_0477].iter().enumerate() {
+            let mut tap = TAP::in_chain(&jtag, &ir_lens, position).unwrap();
+            // 他のTAPはBYPASSになり、自分のIDCODEだけが読める
+            tap.write_instruction(IR_IDCODE as u8).unwrap();
+            let mut data = [false; 32];
+            tap.read_write_dr(&mut data, true, false, false).unwrap();
+            let result = bits::from_bools_lsb_first(&data) as u32;
+            assert_eq!(*idcode, result, "position {}", position);
+
+            let devices = jtag
+                .lock()
+                .interface
+                .devices
+                .borrow()
+                .iter()
+                .map(|x| x.ir)
+                .collect::<Vec<_>>();
+            for (i, ir) in devices.iter().enumerate() {
+                if i == position {
+                    assert_eq!(IR_IDCODE, *ir);
+                } else {
+                    assert_eq!((1 << ir_lens[i]) - 1, *ir);
+                }
+            }
+        }
+
+        // chainの外はpanicせずにOutOfRange
+        assert_eq!(
+            Some(InterfaceError::OutOfRange),
+            TAP::in_chain(&jtag, &ir_lens, 3).err()
+        );
+        assert_eq!(
+            Some(InterfaceError::OutOfRange),
+            TAP::in_chain(&jtag, &[], 0).err()
+        );
+    }
+
+    fn sim_irs(jtag: &Mutex<Jtag<SimChain>>) -> Vec<u32> {
+        let jtag = jtag.lock();
+        let devices = jtag.interface.devices.borrow();
+        devices.iter().map(|x| x.ir).collect()
+    }
+
+    #[test]
+    fn rescan_preserving_state_test() {
+        let chain = SimChain::new(vec![
+            SimDevice::new(0x4ba0_0477, 4),
+            SimDevice::new(0x5ba0_0477, 4),
+        ]);
+        let jtag = Mutex::new(initialized(chain));
+        assert_eq!(None, jtag.lock().last_ir());
+        let mut tap = TAP::in_chain(&jtag, &[4, 4], 1).unwrap();
+        tap.set_reset_on_drop(false);
+        tap.write_instruction(IR_DPACC as u8).unwrap();
+        // TDO側のdeviceはBYPASS
+        let mut expected = vec![true; 4];
+        expected.extend(bits::to_bools_lsb_first(IR_DPACC as u64, 4));
+        assert_eq!(Some(&expected[..]), jtag.lock().last_ir());
+
+        let mut data: Vec<bool> = bits::to_bools_lsb_first(0x1_2345_6789, 35).collect();
+        tap.read_write_dr(&mut data, true, false, false).unwrap();
+
+        // IDCODE命令で読むので、resetせずにDPACCへ戻る
+        jtag.lock()
+            .set_idcode_ir(&bits::to_bools_lsb_first(0xee, 8).collect::<Vec<_>>())
+            .unwrap();
+        let resets = jtag.lock().interface.resets.get();
+        let result = jtag.lock().rescan_preserving_state().unwrap();
+        let idcodes: Vec<_> = result.devices().iter().map(|x| x.raw()).collect();
+        assert_eq!(vec![Some(0x4ba0_0477), Some(0x5ba0_0477)], idcodes);
+        assert!(result.is_complete());
+        assert_eq!(resets, jtag.lock().interface.resets.get());
+        assert_eq!(vec![0b1111, IR_DPACC], sim_irs(&jtag));
+        assert_eq!(Some(&expected[..]), jtag.lock().last_ir());
+
+        // 途中でrescanしても、同じDPACCのDRの続きとして読める
+        let mut data = [false; 35];
+        tap.read_write_dr(&mut data, true, false, false).unwrap();
+        assert_eq!(0x1_2345_6789, bits::from_bools_lsb_first(&data));
+
+        // Test-Logic-Resetを使うscanでも元のIRに戻す
+        jtag.lock().scan().unwrap();
+        assert_eq!(resets + 1, jtag.lock().interface.resets.get());
+        assert_eq!(vec![0b1111, IR_DPACC], sim_irs(&jtag));
+
+        // reset_on_dropがfalseならIRはそのまま
+        drop(tap);
+        assert_eq!(vec![0b1111, IR_DPACC], sim_irs(&jtag));
+        jtag.lock().change_state(JS::Reset).unwrap();
+        assert_eq!(None, jtag.lock().last_ir());
+        assert_eq!(vec![IR_IDCODE, IR_IDCODE], sim_irs(&jtag));
+    }
+
+    #[test]
+    fn multi_tap_interleave_test() {
+        // DAPのTAPとvendorのTAPが並んだchain
+        let chain = SimChain::new(vec![
+            SimDevice::new(0x4ba0_0477, 4),
+            SimDevice::new(0x1000_0093, 4),
+        ]);
+        let jtag = Mutex::new(initialized(chain));
+        let mut dap = TAP::in_chain(&jtag, &[4, 4], 0).unwrap();
+        let mut vendor = TAP::in_chain(&jtag, &[4, 4], 1).unwrap();
+        dap.set_reset_on_drop(false);
+        vendor.set_reset_on_drop(false);
+        vendor.write_instruction(IR_USER as u8).unwrap();
+        assert_eq!(Some(IR_USER), jtag.lock().tap_ir(1));
+
+        let resets = jtag.lock().interface.resets.get();
+        let mut previous_dp = 0;
+        let mut previous_user = 0;
+        for i in 1..5u32 {
+            // DAPのIRを書くとvendorのTAPはBYPASSになる
+            let (_, result) = dap.dpacc(0x1111_0000 * i, 1, false).unwrap();
+            assert_eq!(previous_dp, result);
+            assert_eq!(Some(IR_DPACC), jtag.lock().tap_ir(0));
+            assert_eq!(None, jtag.lock().tap_ir(1));
+            previous_dp = 0x1111_0000 * i;
+
+            let value = 0x1234 * i as u64;
+            let mut dr: Vec<bool> = bits::to_bools_lsb_first(value, 16).collect();
+            vendor.read_write_dr(&mut dr, true, false, false).unwrap();
+            assert_eq!(previous_user, bits::from_bools_lsb_first(&dr));
+            assert_eq!(vec![0b1111, IR_USER], sim_irs(&jtag));
+            previous_user = value;
+        }
+        // resetせずに切り替えている
+        assert_eq!(resets, jtag.lock().interface.resets.get());
+
+        // 表の上で自分の命令が残っている間はIRをscanし直さない
+        jtag.lock().interface.devices.borrow_mut()[1].ir = IR_IDCODE;
+        let mut dr = [false; 16];
+        vendor.read_write_dr(&mut dr, true, false, false).unwrap();
+        assert_eq!(IR_IDCODE, sim_irs(&jtag)[1]);
+
+        // Test-Logic-Resetの後は書き直す
+        jtag.lock().change_state(JS::Reset).unwrap();
+        assert_eq!(None, jtag.lock().tap_ir(1));
+        let mut dr = [false; 16];
+        vendor.read_write_dr(&mut dr, true, false, false).unwrap();
+        assert_eq!(vec![0b1111, IR_USER], sim_irs(&jtag));
+    }
+
+    #[test]
+    fn ping_test() {
+        let chain = SimChain::new(vec![
+            SimDevice::new(0x4ba0_0477, 4),
+            SimDevice::new(0x5ba0_0477, 4),
+        ]);
+        let jtag = Mutex::new(initialized(chain));
+        let mut tap = TAP::in_chain(&jtag, &[4, 4], 1).unwrap();
+        tap.set_reset_on_drop(false);
+        tap.write_instruction(IR_DPACC as u8).unwrap();
+
+        // IRが分からなければTest-Logic-Resetを通し、元のIRへ戻す
+        let resets = jtag.lock().interface.resets.get();
+        assert_eq!(Ok(0x4ba0_0477), tap.ping());
+        assert_eq!(resets + 1, jtag.lock().interface.resets.get());
+        assert_eq!(vec![0b1111, IR_DPACC], sim_irs(&jtag));
+
+        jtag.lock()
+            .set_idcode_ir(&bits::to_bools_lsb_first(0xee, 8).collect::<Vec<_>>())
+            .unwrap();
+        assert_eq!(Ok(0x4ba0_0477), jtag.lock().ping());
+        assert_eq!(resets + 1, jtag.lock().interface.resets.get());
+        assert_eq!(vec![0b1111, IR_DPACC], sim_irs(&jtag));
+
+        // 別のtargetに繋ぎ替わった
+        jtag.lock().interface.devices.borrow_mut()[0].idcode = 0x6ba0_0477;
+        assert_eq!(Ok(0x6ba0_0477), jtag.lock().ping());
+        assert_eq!(
+            Err(DapError::IdcodeMismatch {
+                expected: 0x4ba0_0477,
+                found: 0x6ba0_0477
+            }),
+            tap.ping()
+        );
+        // TDOが1や0に張り付いた
+        for stuck in [0xffff_ffff, 0].iter() {
+            jtag.lock().interface.devices.borrow_mut()[0].idcode = *stuck;
+            assert_eq!(Err(InterfaceError::EmptyChain), jtag.lock().ping());
+        }
+
+        let mut jtag = Jtag::new(SimChain::new(vec![SimDevice::new(0x4ba0_0477, 4)]));
+        assert_eq!(Err(InterfaceError::NotInitialized), jtag.ping());
+    }
+
+    #[test]
+    fn read_write_dr_chunked_test() {
+        let tdi: Vec<bool> = (0..100).map(|i| i % 3 == 0).collect();
+        let tdo: Vec<bool> = (0..100).map(|i| i % 7 < 3).collect();
+
+        let mut single = initialized(MockInterface::new());
+        single.interface.clear();
+        single.interface.script_next_read(&tdo);
+        let mut expected = tdi.clone();
+        single
+            .read_write_dr(&mut expected, true, false, false)
+            .unwrap();
+
+        // 32bitずつ: 32, 32, 32, 4
+        let mut chunked = initialized(MockInterface::new());
+        chunked.interface.clear();
+        let reads = chunked.interface.reads();
+        for (i, chunk) in tdo.chunks(32).enumerate() {
+            chunked.interface.script_read(reads + i, chunk);
+        }
+        let mut data = tdi.clone();
+        chunked.read_write_dr_chunked(&mut data, 32).unwrap();
+        assert_eq!(reads + 4, chunked.interface.reads());
+        assert_eq!(expected, data);
+
+        let sent: Vec<bool> = chunked.interface.tdi_sequence().concat();
+        assert_eq!(tdi, sent);
+        // Reset -> RunIdle -> ShiftDR
+        let mut tms = vec![false, true, false, false];
+        for (i, chunk) in tdi.chunks(32).enumerate() {
+            // chunkの最後のbitでだけExit1-DRへ出る
+            tms.extend(vec![false; chunk.len() - 1]);
+            tms.push(true);
+            if i < 3 {
+                // Exit1 -> Pause -> Exit2 -> Shift
+                tms.extend([false, true, false]);
+            }
+        }
+        // Exit1 -> RunIdle
+        tms.extend([true, false]);
+        chunked.interface.expect_tms_sequence(&tms);
+        assert_eq!(JS::RunIdle, chunked.interface.state());
+        assert_eq!(JS::RunIdle, chunked.state());
+
+        assert_eq!(
+            Err(InterfaceError::OutOfRange),
+            chunked.read_write_dr_chunked(&mut data, 0)
+        );
+    }
+
+    #[test]
+    fn shift_chunk_test() {
+        let mut jtag = initialized(MockInterface::new());
+        jtag.change_state(JS::RunIdle).unwrap();
+        assert_eq!(
+            Err(InterfaceError::UnsupportedTransition {
+                from: JS::RunIdle,
+                to: JS::Exit1DR
+            }),
+            jtag.shift_chunk(&mut [false; 4], false)
+        );
+
+        // IRはPause-IRを経由してShift-IRへ戻る
+        jtag.change_state(JS::ShiftIR).unwrap();
+        jtag.interface.clear();
+        jtag.shift_chunk(&mut [false; 4], false).unwrap();
+        assert_eq!(JS::ShiftIR, jtag.state());
+        assert_eq!(
+            Err(InterfaceError::OutOfRange),
+            jtag.shift_chunk(&mut [], true)
+        );
+        jtag.shift_chunk(&mut [true; 2], true).unwrap();
+        assert_eq!(JS::Exit1IR, jtag.state());
+        jtag.interface
+            .expect_tms_sequence(&[false, false, false, true, false, true, false, false, true]);
+    }
+
+    #[test]
+    fn unsupported_transition_test() {
+        let mut jtag = initialized(MockInterface::new());
+        jtag.change_state(JS::ShiftDR).unwrap();
+        jtag.interface.clear();
+        assert_eq!(
+            Err(InterfaceError::UnsupportedTransition {
+                from: JS::ShiftDR,
+                to: JS::ShiftIR
+            }),
+            jtag.change_state(JS::ShiftIR)
+        );
+        // TMSは送らない
+        jtag.interface.expect_tms_sequence(&[]);
+        assert_eq!(JS::ShiftDR, jtag.state());
+    }
+
+    #[test]
+    fn idle_cycles_test() {
+        let mut jtag = initialized(MockInterface::new());
+        jtag.interface.clear();
+        // Reset -> RunIdleの後に100 cycle
+        jtag.idle_cycles(100).unwrap();
+        let mut tms = vec![false];
+        tms.extend(vec![false; 100]);
+        jtag.interface.expect_tms_sequence(&tms);
+        assert_eq!(JS::RunIdle, jtag.interface.state());
+        assert_eq!(JS::RunIdle, jtag.state());
+
+        // RunIdleにいれば遷移はしない
+        jtag.interface.clear();
+        jtag.idle_cycles(3).unwrap();
+        jtag.interface.expect_tms_sequence(&[false; 3]);
+
+        // scanの度にRunIdleで5 cycle入る
+        let jtag = Mutex::new(jtag);
+        let mut tap = TAP::new(&jtag, 4);
+        tap.set_post_scan_idle(5);
+        jtag.lock().interface.clear();
+        tap.write_instruction(0b1110).unwrap();
+        let mut data = [false; 8];
+        tap.read_write_dr(&mut data, true, false, false).unwrap();
+        let mut tms = Vec::new();
+        // RunIdle -> ShiftIR, 4bit, Exit1 -> RunIdle
+        tms.extend([true, true, false, false]);
+        tms.extend([false, false, false, true]);
+        tms.extend([true, false]);
+        tms.extend([false; 5]);
+        // RunIdle -> ShiftDR, 8bit, Exit1 -> RunIdle
+        tms.extend([true, false, false]);
+        tms.extend([false; 7]);
+        tms.extend([true, true, false]);
+        tms.extend([false; 5]);
+        jtag.lock().interface.expect_tms_sequence(&tms);
+        assert_eq!(JS::RunIdle, jtag.lock().interface.state());
+        assert_eq!(JS::RunIdle, jtag.lock().state());
+        // 0ならidle cycleは入らない
+        tap.set_post_scan_idle(0);
+        jtag.lock().interface.clear();
+        tap.read_write_dr(&mut data, true, false, false).unwrap();
+        assert_eq!(Some(&false), jtag.lock().interface.tms_sequence().last());
+        assert_eq!(13, jtag.lock().interface.tms_sequence().len());
+    }
+
+    #[test]
+    fn capabilities_test() {
+        // clock_idle/reset pinを持ち、長さの制限もないbackend
+        let mut jtag = initialized(MockInterface::new());
+        jtag.change_state(JS::RunIdle).unwrap();
+        jtag.interface.clear();
+        jtag.idle_cycles(100).unwrap();
+        assert_eq!(vec![MockCall::ClockIdle(100)], jtag.interface.transcript());
+        let mut data = vec![false; 100];
+        jtag.read_write_dr_chunked(&mut data, 64).unwrap();
+        assert_eq!(
+            vec![64, 36],
+            jtag.interface
+                .tdi_sequence()
+                .iter()
+                .map(|x| x.len())
+                .collect::<Vec<_>>()
+        );
+
+        // 何も持たず1回16bitまでしか送れないbackend
+        let mut jtag = initialized(MockInterface::new());
+        jtag.interface.set_capabilities(InterfaceCaps {
+            flags: CapFlags::empty(),
+            max_bits_per_transfer: 16,
+        });
+        jtag.change_state(JS::RunIdle).unwrap();
+        jtag.interface.clear();
+        // IDLE_CHUNKより小さく分けたwrite_tmsになる
+        jtag.idle_cycles(40).unwrap();
+        assert_eq!(
+            vec![
+                MockCall::WriteTms(vec![false; 16]),
+                MockCall::WriteTms(vec![false; 16]),
+                MockCall::WriteTms(vec![false; 8])
+            ],
+            jtag.interface.transcript()
+        );
+        assert_eq!(JS::RunIdle, jtag.interface.state());
+
+        jtag.interface.clear();
+        let mut data = vec![false; 40];
+        jtag.read_write_dr_chunked(&mut data, 64).unwrap();
+        assert_eq!(
+            vec![16, 16, 8],
+            jtag.interface
+                .tdi_sequence()
+                .iter()
+                .map(|x| x.len())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(JS::RunIdle, jtag.interface.state());
+
+        // TRSTが無いのでTMSでResetへ
+        jtag.change_state(JS::ShiftDR).unwrap();
+        jtag.interface.clear();
+        jtag.hardware_reset_tap().unwrap();
+        assert_eq!(JS::Reset, jtag.state());
+        assert_eq!(JS::Reset, jtag.interface.state());
+        assert!(jtag
+            .interface
+            .transcript()
+            .iter()
+            .all(|x| matches!(x, MockCall::WriteTms(_))));
+
+        // SRSTが無いのでinterfaceを触らずに失敗する
+        jtag.interface.clear();
+        assert_eq!(Err(InterfaceError::Unsupported), jtag.reset_target());
+        assert!(jtag.interface.transcript().is_empty());
+    }
+
+    #[test]
+    fn stats_test() {
+        let mut jtag = initialized(MockInterface::new());
+        jtag.set_collect_stats(true);
+        // Reset -> RunIdle -> ShiftIR, 4bit, Exit1 -> RunIdle
+        let mut ir = [false, true, true, true];
+        jtag.write_ir(&mut ir, true, false).unwrap();
+        // RunIdle -> ShiftDR, 35bit, Exit1 -> RunIdle
+        let mut dr = [false; 35];
+        jtag.read_write_dr(&mut dr, true, false, false).unwrap();
+        jtag.idle_cycles(10).unwrap();
+
+        let stats = jtag.stats();
+        assert_eq!(
+            (1 + 4 + 2 + 3 + 2 + 10, 4, 35),
+            (stats.tms_bits, stats.ir_bits, stats.dr_bits)
+        );
+        assert_eq!((6, 2), (stats.writes, stats.reads));
+        // MockInterfaceはUSBの転送量を数えない
+        assert_eq!(None, stats.usb);
+        let text = format!("{}", stats);
+        assert!(text.contains("calls: 6 writes, 2 reads"), "{}", text);
+
+        jtag.reset_stats();
+        assert_eq!(JtagStats::default(), jtag.stats());
+        jtag.set_collect_stats(false);
+        jtag.idle_cycles(10).unwrap();
+        assert_eq!(JtagStats::default(), jtag.stats());
+    }
+
+    #[test]
+    fn read_write_dr_packed_test() {
+        let pattern: Vec<bool> = (0..35).map(|i| i % 3 == 0).collect();
+        let tdo: Vec<bool> = (0..38).map(|i| i % 5 < 2).collect();
+        let mut packed = [0u8; 5];
+        crate::interface::bits::pack(&pattern, &mut packed);
+
+        // 前に1個、後に2個BYPASSのdeviceがある
+        let mut by_bool = initialized(MockInterface::new());
+        by_bool.set_collect_stats(true);
+        by_bool.interface.script_read(0, &tdo);
+        let mut data = pattern.clone();
+        by_bool
+            .read_write_dr_padded(&mut data, 1, 2, true, false, false)
+            .unwrap();
+
+        let mut by_byte = initialized(MockInterface::new());
+        by_byte.set_collect_stats(true);
+        by_byte.interface.script_read(0, &tdo);
+        by_byte
+            .read_write_dr_padded_packed(&mut packed, 35, 1, 2, true)
+            .unwrap();
+
+        assert_eq!(
+            by_bool.interface.transcript(),
+            by_byte.interface.transcript()
+        );
+        let mut result = [false; 35];
+        crate::interface::bits::unpack(&packed, &mut result);
+        assert_eq!(data, result.to_vec());
+        assert_eq!(JS::RunIdle, by_byte.state());
+        let (a, b) = (by_bool.stats(), by_byte.stats());
+        assert_eq!((a.dr_bits, a.reads), (b.dr_bits, b.reads));
+
+        assert_eq!(
+            Err(InterfaceError::OutOfRange),
+            by_byte.read_write_dr_packed(&mut packed, 41, true)
+        );
+        let mut jtag = Jtag::new(MockInterface::new());
+        assert_eq!(
+            Err(InterfaceError::NotInitialized),
+            jtag.read_write_dr_packed(&mut packed, 35, true)
+        );
+    }
+
+    #[test]
+    fn interface_error_test() {
+        // 最初のscanで失敗する
+        let interface = MockInterface::new();
+        interface.fail_with(Some(InterfaceError::Timeout));
+        assert_eq!(
+            Some(InterfaceError::Timeout),
+            Jtag::new(interface).initialize().err()
+        );
+
+        let jtag = Mutex::new(initialized(MockInterface::new()));
+        jtag.lock().change_state(JS::RunIdle).unwrap();
+        jtag.lock().interface.fail_with(Some(InterfaceError::Io));
+
+        // 失敗した遷移ではstateを進めない
+        assert_eq!(
+            Err(InterfaceError::Io),
+            jtag.lock().change_state(JS::ShiftDR)
+        );
+        assert_eq!(JS::RunIdle, jtag.lock().state());
+
+        let mut tap = TAP::new(&jtag, 4);
+        assert_eq!(Err(InterfaceError::Io), tap.write_instruction(0b1110));
+        let mut data = [false; 32];
+        assert_eq!(
+            Err(InterfaceError::Io),
+            tap.read_write_dr(&mut data, true, false, false)
+        );
+        // DAPまでpanicせずに伝わる
+        assert_eq!(
+            Some(DapError::Interface(InterfaceError::Io)),
+            DAP::try_new(tap).err()
+        );
+    }
 }
@@ -1,7 +1,7 @@
 use log::{debug, error, info, warn};
 use rust_fsm::*;
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum JtagState {
     Reset,
     RunIdle,
@@ -90,7 +90,9 @@ impl StateMachineImpl for JtagStateMachine {
             (JtagState::UpdateIR, &true) => Some(JtagState::SelectIRScan),
             (JtagState::UpdateIR, &false) => Some(JtagState::RunIdle),
         };
-        debug!("jtag state change: {:?} -> {:?}", state, res.unwrap());
+        if let Some(next) = res {
+            debug!("jtag state change: {:?} -> {:?}", state, next);
+        }
         res
     }
     fn output(state: &Self::State, input: &Self::Input) -> Option<Self::Output> {
@@ -0,0 +1,88 @@
+use core::fmt;
+
+// JEP106のmanufacturer名を引くためのtrait
+// 組み込み向けではjep106の表が大きいので、featureで外せるようにしている
+pub trait ManufacturerNames {
+    fn name(&self, cc: u8, id: u8) -> Option<&str>;
+}
+
+#[cfg(feature = "jep106")]
+pub struct Jep106Names;
+
+#[cfg(feature = "jep106")]
+impl ManufacturerNames for Jep106Names {
+    fn name(&self, cc: u8, id: u8) -> Option<&str> {
+        jep106::JEP106Code::new(cc, id).get()
+    }
+}
+
+pub struct NoNames;
+
+impl ManufacturerNames for NoNames {
+    fn name(&self, _cc: u8, _id: u8) -> Option<&str> {
+        None
+    }
+}
+
+#[cfg(feature = "jep106")]
+pub fn default_names() -> &'static dyn ManufacturerNames {
+    &Jep106Names
+}
+
+#[cfg(not(feature = "jep106"))]
+pub fn default_names() -> &'static dyn ManufacturerNames {
+    &NoNames
+}
+
+// 名前が引けない場合は"cc:id"のhex表記にする
+pub struct Manufacturer<'a> {
+    pub names: &'a dyn ManufacturerNames,
+    pub cc: u8,
+    pub id: u8,
+}
+
+impl<'a> Manufacturer<'a> {
+    pub fn from_idcode(names: &'a dyn ManufacturerNames, idcode: u32) -> Self {
+        Manufacturer {
+            names,
+            // Continuation code
+            cc: ((idcode >> 8) & 0b1111) as u8,
+            // Identity code
+            id: ((idcode >> 1) & 0b0111_1111) as u8,
+        }
+    }
+}
+
+impl<'a> fmt::Display for Manufacturer<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.names.name(self.cc, self.id) {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "{:#x}:{:#04x}", self.cc, self.id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_names_test() {
+        let manufacturer = Manufacturer::from_idcode(&NoNames, 0x4ba0_0477);
+        assert_eq!("0x4:0x3b", format!("{}", manufacturer));
+    }
+
+    #[cfg(feature = "jep106")]
+    #[test]
+    fn default_names_test() {
+        let manufacturer = Manufacturer::from_idcode(default_names(), 0x4ba0_0477);
+        assert_eq!("ARM Ltd", format!("{}", manufacturer));
+    }
+
+    #[cfg(not(feature = "jep106"))]
+    #[test]
+    fn default_names_test() {
+        let manufacturer = Manufacturer::from_idcode(default_names(), 0x4ba0_0477);
+        assert_eq!("0x4:0x3b", format!("{}", manufacturer));
+    }
+}
@@ -0,0 +1,239 @@
+// 1つの操作でどれだけscanやUSB転送が発生したかを数える
+// Jtag/DAPではset_collect_statsで有効にした時だけ数える
+use core::cell::Cell;
+use core::fmt;
+#[cfg(feature = "std")]
+use core::time::Duration;
+
+use crate::jtag::jtag_state_machine::JtagState as JS;
+
+// interfaceがUSBへ実際に書いた/読んだ回数とbyte数
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UsbStats {
+    pub writes: u64,
+    pub bytes_written: u64,
+    pub reads: u64,
+    pub bytes_read: u64,
+}
+
+// interfaceの実装が&selfのまま数えるためのcounter
+#[derive(Default)]
+pub struct UsbCounter {
+    stats: Cell<UsbStats>,
+}
+
+impl UsbCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn wrote(&self, bytes: usize) {
+        let mut stats = self.stats.get();
+        stats.writes += 1;
+        stats.bytes_written += bytes as u64;
+        self.stats.set(stats);
+    }
+
+    pub fn read(&self, bytes: usize) {
+        let mut stats = self.stats.get();
+        stats.reads += 1;
+        stats.bytes_read += bytes as u64;
+        self.stats.set(stats);
+    }
+
+    pub fn snapshot(&self) -> UsbStats {
+        self.stats.get()
+    }
+
+    pub fn reset(&self) {
+        self.stats.set(UsbStats::default());
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Scan {
+    Tms,
+    Ir,
+    Dr,
+}
+
+impl Scan {
+    // Shift-IR中のdataだけIRとして数え、それ以外のshiftはDRにする
+    pub(crate) fn data(state: JS) -> Self {
+        match state {
+            JS::ShiftIR => Scan::Ir,
+            _ => Scan::Dr,
+        }
+    }
+}
+
+// stdでなければ時間は測らない
+#[cfg(feature = "std")]
+pub(crate) struct Timer(std::time::Instant);
+#[cfg(not(feature = "std"))]
+pub(crate) struct Timer;
+
+impl Timer {
+    #[cfg(feature = "std")]
+    pub(crate) fn start() -> Self {
+        Timer(std::time::Instant::now())
+    }
+    #[cfg(not(feature = "std"))]
+    pub(crate) fn start() -> Self {
+        Timer
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct JtagStats {
+    pub tms_bits: u64,
+    pub ir_bits: u64,
+    pub dr_bits: u64,
+    // interfaceのwrite_tms/write_data/clock_idleとread_dataの呼び出し回数
+    pub writes: u64,
+    pub reads: u64,
+    #[cfg(feature = "std")]
+    pub tms_time: Duration,
+    #[cfg(feature = "std")]
+    pub ir_time: Duration,
+    #[cfg(feature = "std")]
+    pub dr_time: Duration,
+    // interfaceが数えていない場合はNone
+    pub usb: Option<UsbStats>,
+}
+
+impl JtagStats {
+    pub(crate) fn record(&mut self, scan: Scan, bits: usize, read: bool, _timer: Timer) {
+        if read {
+            self.reads += 1;
+        } else {
+            self.writes += 1;
+        }
+        let count = match scan {
+            Scan::Tms => &mut self.tms_bits,
+            Scan::Ir => &mut self.ir_bits,
+            Scan::Dr => &mut self.dr_bits,
+        };
+        *count += bits as u64;
+        #[cfg(feature = "std")]
+        {
+            let time = match scan {
+                Scan::Tms => &mut self.tms_time,
+                Scan::Ir => &mut self.ir_time,
+                Scan::Dr => &mut self.dr_time,
+            };
+            *time += _timer.0.elapsed();
+        }
+    }
+}
+
+impl fmt::Display for JtagStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<6}{:>12}{:>12}", "", "bits", "time[us]")?;
+        #[cfg(feature = "std")]
+        let times = [self.tms_time, self.ir_time, self.dr_time].map(|x| x.as_micros());
+        #[cfg(not(feature = "std"))]
+        let times = [0u128; 3];
+        let rows = [
+            ("tms", self.tms_bits),
+            ("ir", self.ir_bits),
+            ("dr", self.dr_bits),
+        ];
+        for ((name, bits), time) in rows.iter().zip(times.iter()) {
+            writeln!(f, "{:<6}{:>12}{:>12}", name, bits, time)?;
+        }
+        write!(f, "calls: {} writes, {} reads", self.writes, self.reads)?;
+        if let Some(usb) = self.usb {
+            write!(
+                f,
+                "\nusb: {} writes ({} bytes), {} reads ({} bytes)",
+                usb.writes, usb.bytes_written, usb.reads, usb.bytes_read
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// MemoryRouterがどの経路でrequestを処理したか
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RouteStats {
+    pub mem_ap: u64,
+    pub ma_mode: u64,
+    // ATで引いてからMEM-APで読み書きした
+    pub translated: u64,
+    // MEM-APが失敗してMA-modeでやり直した。ma_modeにも数える
+    pub fallbacks: u64,
+}
+
+impl fmt::Display for RouteStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "mem-ap: {}, ma-mode: {}, translated: {}, fallbacks: {}",
+            self.mem_ap, self.ma_mode, self.translated, self.fallbacks
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DapStats {
+    pub dp_reads: u64,
+    pub dp_writes: u64,
+    pub ap_reads: u64,
+    pub ap_writes: u64,
+    // WAITの再試行も1回ずつ数える
+    pub wait_acks: u64,
+    #[cfg(feature = "std")]
+    pub dp_time: Duration,
+    #[cfg(feature = "std")]
+    pub ap_time: Duration,
+}
+
+impl DapStats {
+    pub(crate) fn record(&mut self, ap: bool, read: bool, wait: bool, _timer: Timer) {
+        let count = match (ap, read) {
+            (false, true) => &mut self.dp_reads,
+            (false, false) => &mut self.dp_writes,
+            (true, true) => &mut self.ap_reads,
+            (true, false) => &mut self.ap_writes,
+        };
+        *count += 1;
+        if wait {
+            self.wait_acks += 1;
+        }
+        #[cfg(feature = "std")]
+        {
+            let time = if ap {
+                &mut self.ap_time
+            } else {
+                &mut self.dp_time
+            };
+            *time += _timer.0.elapsed();
+        }
+    }
+}
+
+impl fmt::Display for DapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<6}{:>10}{:>10}{:>12}",
+            "", "reads", "writes", "time[us]"
+        )?;
+        #[cfg(feature = "std")]
+        let times = [self.dp_time, self.ap_time].map(|x| x.as_micros());
+        #[cfg(not(feature = "std"))]
+        let times = [0u128; 2];
+        writeln!(
+            f,
+            "{:<6}{:>10}{:>10}{:>12}",
+            "dp", self.dp_reads, self.dp_writes, times[0]
+        )?;
+        writeln!(
+            f,
+            "{:<6}{:>10}{:>10}{:>12}",
+            "ap", self.ap_reads, self.ap_writes, times[1]
+        )?;
+        write!(f, "wait acks: {}", self.wait_acks)
+    }
+}
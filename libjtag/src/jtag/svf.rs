@@ -0,0 +1,1243 @@
+// SVF(Serial Vector Format)の再生
+// CPLD/FPGAの書き込みやboundary scanのtestでvendorのtoolが出力するfileをJtagで流す
+use log::{info, warn};
+use std::fmt;
+use std::io::{self, BufRead};
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::interface::bits::byte_len;
+use crate::interface::{InterfaceError, JtagInterface};
+use crate::jtag::jtag::Jtag;
+use crate::jtag::jtag_state_machine::JtagState as JS;
+
+// RUNTESTで一度にwrite_tmsするclock数
+const RUNTEST_CHUNK: usize = 64;
+// SIR/SDRを一度にshiftするbit数。長いscanもこの大きさのbufferで流す
+const SHIFT_CHUNK: usize = 4096;
+// これより長いscanのTDO mismatchは、最初に一致しなかった所の32bitだけを示す
+const MISMATCH_HEX_BITS: usize = 64;
+// hex以外のtokenの長さと、1つのcommandに並べられる数
+const WORD_MAX: usize = 64;
+const WORDS_MAX: usize = 32;
+// errorに表示するhexの桁数
+const HEX_PREVIEW: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScanKind {
+    Sir,
+    Sdr,
+    Hir,
+    Hdr,
+    Tir,
+    Tdr,
+}
+
+const SCAN_KINDS: usize = 6;
+
+// bit列はlen bitをLSB firstでbyteに詰める。bit 0が最初にshiftされる(hexの最下位)bit
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Scan {
+    pub len: usize,
+    pub tdi: Option<Vec<u8>>,
+    pub tdo: Option<Vec<u8>>,
+    pub mask: Option<Vec<u8>>,
+    pub smask: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrstMode {
+    On,
+    Off,
+    Z,
+    Absent,
+}
+
+// commandが始まった位置。どちらも1から数える
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+// playの進み具合。bitsはHIR/TIR等も含めてshiftしたbit数
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SvfProgress {
+    pub commands: usize,
+    pub bits: u64,
+    // 実行中のcommandが始まった行
+    pub line: usize,
+}
+
+#[derive(Debug)]
+pub enum SvfError {
+    // 入力が読めない
+    Io(io::Error),
+    // 文法や値が正しくない、または対応していないcommand
+    Syntax {
+        position: Position,
+        message: String,
+    },
+    // TDOが期待値と一致しない。値は最後にshiftされるbitを先頭にしたhex
+    // 長いscanでは、offset bit目からの値だけを示す
+    Mismatch {
+        position: Position,
+        scan: &'static str,
+        offset: Option<usize>,
+        expected: String,
+        read: String,
+        mask: String,
+    },
+    Interface {
+        position: Position,
+        error: InterfaceError,
+    },
+}
+
+impl fmt::Display for SvfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SvfError::Io(e) => write!(f, "failed to read SVF: {}", e),
+            SvfError::Syntax { position, message } => write!(f, "{}: {}", position, message),
+            SvfError::Mismatch {
+                position,
+                scan,
+                offset,
+                expected,
+                read,
+                mask,
+            } => {
+                write!(f, "{}: {} TDO mismatch", position, scan)?;
+                if let Some(offset) = offset {
+                    write!(f, " from bit {}", offset)?;
+                }
+                write!(f, ": expected {}, read {}, mask {}", expected, read, mask)
+            }
+            SvfError::Interface { position, error } => write!(f, "{}: {}", position, error),
+        }
+    }
+}
+
+impl std::error::Error for SvfError {}
+
+impl From<io::Error> for SvfError {
+    fn from(e: io::Error) -> Self {
+        SvfError::Io(e)
+    }
+}
+
+fn syntax(position: Position, message: String) -> SvfError {
+    SvfError::Syntax { position, message }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SvfCommand {
+    Scan(ScanKind, Scan),
+    EndIr(JS),
+    EndDr(JS),
+    State(Vec<JS>),
+    RunTest {
+        run_state: Option<JS>,
+        run_count: Option<u64>,
+        // 秒
+        min_time: Option<f64>,
+        max_time: Option<f64>,
+        end_state: Option<JS>,
+    },
+    Trst(TrstMode),
+    Frequency(Option<f64>),
+}
+fn parse_state(name: &str) -> Result<JS, String> {
+    Ok(match name {
+        "RESET" => JS::Reset,
+        "IDLE" => JS::RunIdle,
+        "DRSELECT" => JS::SelectDRScan,
+        "DRCAPTURE" => JS::CaptureDR,
+        "DRSHIFT" => JS::ShiftDR,
+        "DREXIT1" => JS::Exit1DR,
+        "DRPAUSE" => JS::PauseDR,
+        "DREXIT2" => JS::Exit2DR,
+        "DRUPDATE" => JS::UpdateDR,
+        "IRSELECT" => JS::SelectIRScan,
+        "IRCAPTURE" => JS::CaptureIR,
+        "IRSHIFT" => JS::ShiftIR,
+        "IREXIT1" => JS::Exit1IR,
+        "IRPAUSE" => JS::PauseIR,
+        "IREXIT2" => JS::Exit2IR,
+        "IRUPDATE" => JS::UpdateIR,
+        _ => return Err(format!("unknown state {}", name)),
+    })
+}
+
+// ENDIR/ENDDR/RUNTEST等で留まれるstate
+fn is_stable(state: JS) -> bool {
+    matches!(state, JS::Reset | JS::RunIdle | JS::PauseDR | JS::PauseIR)
+}
+
+fn parse_stable_state(name: &str) -> Result<JS, String> {
+    let state = parse_state(name)?;
+    if !is_stable(state) {
+        return Err(format!("{} is not a stable state", name));
+    }
+    Ok(state)
+}
+
+fn parse_number<T: std::str::FromStr>(token: Option<&String>) -> Result<T, String> {
+    let token = token.ok_or_else(|| "missing number".to_string())?;
+    token
+        .parse()
+        .map_err(|_| format!("invalid number {}", token))
+}
+
+// RUNTEST [run_state] [run_count TCK|SCK] [min_time SEC [MAXIMUM max_time SEC]] [ENDSTATE end_state]
+fn parse_runtest(tokens: &[String]) -> Result<SvfCommand, String> {
+    let mut tokens = tokens.iter().peekable();
+    let mut run_state = None;
+    let mut run_count = None;
+    let mut min_time = None;
+    let mut max_time = None;
+    let mut end_state = None;
+    if let Some(x) = tokens.peek() {
+        if x.parse::<f64>().is_err() && *x != "ENDSTATE" {
+            run_state = Some(parse_stable_state(x)?);
+            tokens.next();
+        }
+    }
+    while let Some(token) = tokens.next() {
+        if token == "ENDSTATE" {
+            let state = tokens
+                .next()
+                .ok_or_else(|| "missing end state".to_string())?;
+            end_state = Some(parse_stable_state(state)?);
+            continue;
+        }
+        if token == "MAXIMUM" {
+            max_time = Some(parse_number(tokens.next())?);
+            if tokens.next().map(|x| x.as_str()) != Some("SEC") {
+                return Err("MAXIMUM requires SEC".to_string());
+            }
+            continue;
+        }
+        let unit = tokens.next().map(|x| x.as_str());
+        match unit {
+            Some("TCK") | Some("SCK") => run_count = Some(parse_number(Some(token))?),
+            Some("SEC") => min_time = Some(parse_number(Some(token))?),
+            _ => return Err(format!("expected TCK, SCK or SEC after {}", token)),
+        }
+    }
+    if run_count.is_none() && min_time.is_none() {
+        return Err("RUNTEST requires run_count or min_time".to_string());
+    }
+    Ok(SvfCommand::RunTest {
+        run_state,
+        run_count,
+        min_time,
+        max_time,
+        end_state,
+    })
+}
+
+// scan以外のcommand。引数は';'までのtoken
+fn parse_command(name: &str, args: &[String]) -> Result<SvfCommand, String> {
+    let command = match name {
+        "ENDIR" | "ENDDR" => {
+            let state = match args {
+                [x] => parse_stable_state(x)?,
+                _ => return Err(format!("{} takes one state", name)),
+            };
+            if name == "ENDIR" {
+                SvfCommand::EndIr(state)
+            } else {
+                SvfCommand::EndDr(state)
+            }
+        }
+        "STATE" => {
+            if args.is_empty() {
+                return Err("STATE requires at least one state".to_string());
+            }
+            let path = args
+                .iter()
+                .map(|x| parse_state(x))
+                .collect::<Result<Vec<_>, _>>()?;
+            if !is_stable(path[path.len() - 1]) {
+                return Err("STATE must end in a stable state".to_string());
+            }
+            SvfCommand::State(path)
+        }
+        "RUNTEST" => parse_runtest(args)?,
+        "TRST" => SvfCommand::Trst(match args {
+            [x] if x == "ON" => TrstMode::On,
+            [x] if x == "OFF" => TrstMode::Off,
+            [x] if x == "Z" => TrstMode::Z,
+            [x] if x == "ABSENT" => TrstMode::Absent,
+            _ => return Err("TRST takes ON, OFF, Z or ABSENT".to_string()),
+        }),
+        "FREQUENCY" => match args {
+            [] => SvfCommand::Frequency(None),
+            [x, y] if y == "HZ" => SvfCommand::Frequency(Some(parse_number(Some(x))?)),
+            _ => return Err("FREQUENCY takes cycles HZ".to_string()),
+        },
+        x => return Err(format!("unsupported command {}", x)),
+    };
+    Ok(command)
+}
+
+fn scan_kind(name: &str) -> Option<ScanKind> {
+    Some(match name {
+        "SIR" => ScanKind::Sir,
+        "SDR" => ScanKind::Sdr,
+        "HIR" => ScanKind::Hir,
+        "HDR" => ScanKind::Hdr,
+        "TIR" => ScanKind::Tir,
+        "TDR" => ScanKind::Tdr,
+        _ => return None,
+    })
+}
+
+fn nibble(bytes: &[u8], i: usize) -> u8 {
+    (bytes[i / 2] >> (i % 2 * 4)) & 0xf
+}
+
+fn set_nibble(bytes: &mut [u8], i: usize, value: u8) {
+    let shift = i % 2 * 4;
+    bytes[i / 2] = (bytes[i / 2] & !(0xf << shift)) | (value << shift);
+}
+
+fn too_long(text: &[u8], digits: usize, len: usize) -> String {
+    if digits <= text.len() {
+        let text = String::from_utf8_lossy(&text[..digits]);
+        format!("({}) is longer than {} bits", text, len)
+    } else {
+        format!("{} hex digits are longer than {} bits", digits, len)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    // '('。中のhexはlenを知っているparserが読む
+    Open,
+    End,
+    Eof,
+}
+
+// ';'までを1つのcommandとして読む。位置はcommandが始まった所
+// 1byteずつ読み、hexも桁毎に詰めるので、入力全体やcommandの文字列を溜めない
+// stdinのようなSeekできない入力や、長いscanもそのまま流せる
+pub struct SvfParser<R> {
+    reader: R,
+    line: usize,
+    // 今の行で読んだ文字数
+    column: usize,
+    start: Position,
+    // ';'をまだ読んでいないcommandがある
+    in_command: bool,
+}
+
+impl<R: BufRead> SvfParser<R> {
+    pub fn new(reader: R) -> Self {
+        SvfParser {
+            reader,
+            line: 1,
+            column: 0,
+            start: Position::default(),
+            in_command: false,
+        }
+    }
+
+    fn error(&self, message: String) -> SvfError {
+        syntax(self.start, message)
+    }
+
+    fn eof(&self) -> SvfError {
+        self.error("missing ';' at end of file".to_string())
+    }
+
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.reader.fill_buf()?.first().copied())
+    }
+
+    fn bump(&mut self, c: u8) {
+        self.reader.consume(1);
+        if c == b'\n' {
+            self.line += 1;
+            self.column = 0;
+        } else if c & 0xc0 != 0x80 {
+            // UTF-8の継続byteは数えない
+            self.column += 1;
+        }
+    }
+
+    // 空白とcommentを読み飛ばす。commentは'!'か"//"から行末まで
+    fn skip_blank(&mut self) -> Result<(), SvfError> {
+        while let Some(c) = self.peek()? {
+            match c {
+                b'!' | b'/' => {
+                    self.bump(c);
+                    if c == b'/' && self.peek()? != Some(b'/') {
+                        return Err(self.error("unexpected '/'".to_string()));
+                    }
+                    while let Some(c) = self.peek()? {
+                        if c == b'\n' {
+                            break;
+                        }
+                        self.bump(c);
+                    }
+                }
+                c if c.is_ascii_whitespace() => self.bump(c),
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn token(&mut self) -> Result<Token, SvfError> {
+        self.skip_blank()?;
+        let c = match self.peek()? {
+            Some(c) => c,
+            None => return Ok(Token::Eof),
+        };
+        match c {
+            b';' => {
+                self.bump(c);
+                self.in_command = false;
+                return Ok(Token::End);
+            }
+            b'(' => {
+                self.bump(c);
+                return Ok(Token::Open);
+            }
+            b')' => return Err(self.error("unexpected ')'".to_string())),
+            _ => (),
+        }
+        let mut word = Vec::new();
+        while let Some(c) = self.peek()? {
+            if c.is_ascii_whitespace() || b"();!/".contains(&c) {
+                break;
+            }
+            if word.len() == WORD_MAX {
+                let word = String::from_utf8_lossy(&word);
+                return Err(self.error(format!("{}... is too long", word)));
+            }
+            word.push(c.to_ascii_uppercase());
+            self.bump(c);
+        }
+        Ok(Token::Word(String::from_utf8_lossy(&word).into_owned()))
+    }
+
+    // '('の後から')'までのhexを、len bitのbit列にする
+    // hexは最上位の桁から並ぶので、桁を読んだ順に詰めてから最後に並べ替える
+    fn hex(&mut self, len: usize) -> Result<Vec<u8>, SvfError> {
+        let mut bytes = Vec::new();
+        // 先頭の0を除いた桁数
+        let mut digits = 0;
+        let mut text = [0; HEX_PREVIEW];
+        loop {
+            self.skip_blank()?;
+            let c = match self.peek()? {
+                Some(b';') | None => return Err(self.error("missing ')'".to_string())),
+                Some(c) => c,
+            };
+            self.bump(c);
+            if c == b')' {
+                break;
+            }
+            let digit = (c as char)
+                .to_digit(16)
+                .ok_or_else(|| self.error(format!("invalid hex digit {:?}", c as char)))?;
+            if digit == 0 && digits == 0 {
+                continue;
+            }
+            if let Some(x) = text.get_mut(digits) {
+                *x = c.to_ascii_uppercase();
+            }
+            // 長すぎる値は溜めずに、桁数だけ数える
+            if digits < len.div_ceil(4) {
+                if digits % 2 == 0 {
+                    bytes.push(0);
+                }
+                set_nibble(&mut bytes, digits, digit as u8);
+            }
+            digits += 1;
+        }
+        if digits > len.div_ceil(4) {
+            return Err(self.error(too_long(&text, digits, len)));
+        }
+        for i in 0..digits / 2 {
+            let j = digits - 1 - i;
+            let (x, y) = (nibble(&bytes, i), nibble(&bytes, j));
+            set_nibble(&mut bytes, i, y);
+            set_nibble(&mut bytes, j, x);
+        }
+        bytes.resize(byte_len(len), 0);
+        if (len..bytes.len() * 8).any(|i| bit(&bytes, i)) {
+            return Err(self.error(too_long(&text, digits, len)));
+        }
+        Ok(bytes)
+    }
+
+    fn scan(&mut self, kind: ScanKind) -> Result<SvfCommand, SvfError> {
+        let len = match self.token()? {
+            Token::Word(x) => parse_number(Some(&x)).map_err(|e| self.error(e))?,
+            Token::Eof => return Err(self.eof()),
+            _ => return Err(self.error("missing number".to_string())),
+        };
+        let mut scan = Scan {
+            len,
+            ..Default::default()
+        };
+        loop {
+            let name = match self.token()? {
+                Token::Word(x) => x,
+                Token::End => return Ok(SvfCommand::Scan(kind, scan)),
+                Token::Open => return Err(self.error("unexpected (".to_string())),
+                Token::Eof => return Err(self.eof()),
+            };
+            let field = match name.as_str() {
+                "TDI" => &mut scan.tdi,
+                "TDO" => &mut scan.tdo,
+                "MASK" => &mut scan.mask,
+                "SMASK" => &mut scan.smask,
+                x => return Err(self.error(format!("unknown parameter {}", x))),
+            };
+            match self.token()? {
+                Token::Open => *field = Some(self.hex(len)?),
+                Token::Word(x) => return Err(self.error(format!("expected (hex), found {}", x))),
+                Token::End => return Err(self.error(format!("missing value for {}", name))),
+                Token::Eof => return Err(self.eof()),
+            }
+        }
+    }
+
+    fn words(&mut self) -> Result<Vec<String>, SvfError> {
+        let mut words = Vec::new();
+        loop {
+            match self.token()? {
+                Token::Word(_) if words.len() == WORDS_MAX => {
+                    return Err(self.error("too many parameters".to_string()))
+                }
+                Token::Word(x) => words.push(x),
+                Token::End => return Ok(words),
+                Token::Open => return Err(self.error("unexpected (".to_string())),
+                Token::Eof => return Err(self.eof()),
+            }
+        }
+    }
+
+    fn command(&mut self) -> Result<Option<SvfCommand>, SvfError> {
+        loop {
+            self.skip_blank()?;
+            self.start = Position {
+                line: self.line,
+                column: self.column + 1,
+            };
+            self.in_command = true;
+            let name = match self.token()? {
+                Token::Word(x) => x,
+                // 空のcommand(";;")は読み飛ばす
+                Token::End => continue,
+                Token::Open => return Err(self.error("unexpected (".to_string())),
+                Token::Eof => return Ok(None),
+            };
+            if let Some(kind) = scan_kind(&name) {
+                return self.scan(kind).map(Some);
+            }
+            if !matches!(
+                name.as_str(),
+                "ENDIR" | "ENDDR" | "STATE" | "RUNTEST" | "TRST" | "FREQUENCY"
+            ) {
+                return Err(self.error(format!("unsupported command {}", name)));
+            }
+            let args = self.words()?;
+            return parse_command(&name, &args)
+                .map(Some)
+                .map_err(|e| self.error(e));
+        }
+    }
+
+    // errorの後も次のcommandから読めるように、';'まで読み飛ばす
+    fn skip_command(&mut self) {
+        while self.in_command {
+            match self.token() {
+                Ok(Token::Eof) | Err(SvfError::Io(_)) => break,
+                Ok(_) => (),
+                // ')'のようにtokenにならない文字は1文字ずつ飛ばす
+                Err(_) => {
+                    if let Ok(Some(c)) = self.peek() {
+                        self.bump(c);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for SvfParser<R> {
+    type Item = Result<(Position, SvfCommand), SvfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.command() {
+            Ok(command) => command.map(|x| Ok((self.start, x))),
+            Err(e) => {
+                if let SvfError::Syntax { .. } = e {
+                    self.skip_command();
+                }
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+fn bit(bytes: &[u8], i: usize) -> bool {
+    (bytes[i / 8] >> (i % 8)) & 1 != 0
+}
+
+// 最後にshiftされるbitを先頭にしたhex
+fn to_hex(bits: Range<usize>, get: impl Fn(usize) -> bool) -> String {
+    let len = bits.end - bits.start;
+    (0..len.div_ceil(4))
+        .rev()
+        .map(|digit| {
+            let value = (0..4)
+                .filter(|j| digit * 4 + j < len && get(bits.start + digit * 4 + j))
+                .fold(0, |x, j| x | (1 << j));
+            b"0123456789ABCDEF"[value] as char
+        })
+        .collect()
+}
+
+// SIR/SDR/HIR等の値はlenが変わらない限り次のcommandに引き継ぐ
+#[derive(Clone, Debug, Default)]
+struct Section {
+    len: usize,
+    tdi: Vec<u8>,
+    tdo: Option<Vec<u8>>,
+    // Noneなら全bitを比べる
+    mask: Option<Vec<u8>>,
+}
+
+// header, 本体, trailerを続けて1つのbit列として見る
+struct Sections<'s>([&'s Section; 3]);
+
+impl Sections<'_> {
+    fn len(&self) -> usize {
+        self.0.iter().map(|x| x.len).sum()
+    }
+
+    fn get(&self, mut i: usize) -> (&Section, usize) {
+        for section in self.0.iter() {
+            if i < section.len {
+                return (section, i);
+            }
+            i -= section.len;
+        }
+        unreachable!("bit {} is out of scan", i)
+    }
+
+    fn tdi(&self, i: usize) -> bool {
+        let (section, i) = self.get(i);
+        bit(&section.tdi, i)
+    }
+
+    // 期待値とmask。TDOのないsectionは比べない
+    fn expected(&self, i: usize) -> (bool, bool) {
+        let (section, i) = self.get(i);
+        match &section.tdo {
+            Some(tdo) => (bit(tdo, i), section.mask.as_ref().is_none_or(|x| bit(x, i))),
+            None => (false, false),
+        }
+    }
+}
+
+// chunkの区切りを32bitに揃えておくと、mismatchを示す32bitがchunkを跨がない
+fn chunk_bits(max_bits_per_transfer: usize) -> usize {
+    let bits = SHIFT_CHUNK.min(max_bits_per_transfer).max(1);
+    if bits >= 32 {
+        bits & !31
+    } else {
+        bits
+    }
+}
+
+pub struct SvfPlayer<'a, T> {
+    jtag: &'a mut Jtag<T>,
+    endir: JS,
+    enddr: JS,
+    run_state: JS,
+    run_end_state: JS,
+    frequency: Option<f64>,
+    sections: [Section; SCAN_KINDS],
+    progress: SvfProgress,
+}
+
+impl<'a, T: JtagInterface> SvfPlayer<'a, T> {
+    pub fn new(jtag: &'a mut Jtag<T>) -> Self {
+        SvfPlayer {
+            jtag,
+            endir: JS::RunIdle,
+            enddr: JS::RunIdle,
+            run_state: JS::RunIdle,
+            run_end_state: JS::RunIdle,
+            frequency: None,
+            sections: Default::default(),
+            progress: SvfProgress::default(),
+        }
+    }
+
+    // 実行したcommandの数を返す
+    pub fn play<R: BufRead>(&mut self, reader: R) -> Result<usize, SvfError> {
+        self.play_with_progress(reader, &mut |_| ())
+    }
+
+    // commandを1つ実行する度と、長いscanのchunkを1つshiftする度にprogressを呼ぶ
+    pub fn play_with_progress<R: BufRead>(
+        &mut self,
+        reader: R,
+        progress: &mut dyn FnMut(&SvfProgress),
+    ) -> Result<usize, SvfError> {
+        self.progress = SvfProgress::default();
+        for command in SvfParser::new(reader) {
+            let (position, command) = command?;
+            self.progress.line = position.line;
+            self.run(position, command, progress)?;
+            self.progress.commands += 1;
+            progress(&self.progress);
+        }
+        Ok(self.progress.commands)
+    }
+
+    pub fn execute(&mut self, position: Position, command: &SvfCommand) -> Result<(), SvfError> {
+        self.run(position, command.clone(), &mut |_| ())
+    }
+
+    fn run(
+        &mut self,
+        position: Position,
+        command: SvfCommand,
+        progress: &mut dyn FnMut(&SvfProgress),
+    ) -> Result<(), SvfError> {
+        let interface = |error| SvfError::Interface { position, error };
+        match command {
+            SvfCommand::Scan(kind, scan) => {
+                self.update_section(kind, scan)
+                    .map_err(|e| syntax(position, e))?;
+                match kind {
+                    ScanKind::Sir => self.shift(position, true, progress)?,
+                    ScanKind::Sdr => self.shift(position, false, progress)?,
+                    _ => (),
+                }
+            }
+            SvfCommand::EndIr(state) => self.endir = state,
+            SvfCommand::EndDr(state) => self.enddr = state,
+            SvfCommand::State(path) => {
+                for state in path {
+                    if !is_stable(state) {
+                        return Err(syntax(
+                            position,
+                            format!("STATE through {:?} is not supported", state),
+                        ));
+                    }
+                    self.goto(state).map_err(interface)?;
+                }
+            }
+            SvfCommand::RunTest {
+                run_state,
+                run_count,
+                min_time,
+                max_time: _,
+                end_state,
+            } => {
+                if let Some(state) = run_state {
+                    self.run_state = state;
+                }
+                // ENDSTATEがなければrun_stateに留まる
+                self.run_end_state = end_state.or(run_state).unwrap_or(self.run_end_state);
+                self.run_test(run_count.unwrap_or(0), min_time)
+                    .map_err(interface)?;
+            }
+            SvfCommand::Trst(mode) => self.trst(position, mode).map_err(interface)?,
+            SvfCommand::Frequency(frequency) => {
+                // interfaceのclockは変えず、RUNTESTの時間をclock数に換算するのに使う
+                info!("SVF frequency {:?} Hz", frequency);
+                self.frequency = frequency;
+            }
+        }
+        Ok(())
+    }
+
+    fn update_section(&mut self, kind: ScanKind, scan: Scan) -> Result<(), String> {
+        let section = &mut self.sections[kind as usize];
+        let same_len = section.len == scan.len;
+        section.tdi = match scan.tdi {
+            Some(x) => x,
+            None if scan.len == 0 => Vec::new(),
+            None if same_len => core::mem::take(&mut section.tdi),
+            None => return Err("TDI is required when the length changes".to_string()),
+        };
+        section.mask = match scan.mask {
+            Some(x) => Some(x),
+            None if same_len => section.mask.take(),
+            None => None,
+        };
+        section.tdo = scan.tdo;
+        section.len = scan.len;
+        Ok(())
+    }
+
+    // header, 本体, trailerの順に、chunkずつshiftしながらTDOを比べる
+    fn shift(
+        &mut self,
+        position: Position,
+        ir: bool,
+        progress: &mut dyn FnMut(&SvfProgress),
+    ) -> Result<(), SvfError> {
+        let (kinds, shift_state, end_state, name) = if ir {
+            (
+                [ScanKind::Hir, ScanKind::Sir, ScanKind::Tir],
+                JS::ShiftIR,
+                self.endir,
+                "SIR",
+            )
+        } else {
+            (
+                [ScanKind::Hdr, ScanKind::Sdr, ScanKind::Tdr],
+                JS::ShiftDR,
+                self.enddr,
+                "SDR",
+            )
+        };
+        let interface = |error| SvfError::Interface { position, error };
+        // closureでselfごと借りないように、先にfieldを借りる
+        let saved = &self.sections;
+        let sections = Sections(kinds.map(|x| &saved[x as usize]));
+        let total = sections.len();
+        let mut mismatch = None;
+        if total > 0 {
+            // SvfPlayer::gotoはsectionsと同時に借りられないので、同じ遷移をここで行う
+            goto(self.jtag, shift_state).map_err(interface)?;
+            let chunk_bits = chunk_bits(self.jtag.interface.capabilities().max_bits_per_transfer);
+            let mut buffer = vec![false; total.min(chunk_bits)];
+            // 短いscanはmismatchの時に全体を示すので、読んだbitを残しておく
+            let mut read = Vec::new();
+            let mut start = 0;
+            while start < total {
+                let chunk = &mut buffer[..(total - start).min(chunk_bits)];
+                for (i, x) in chunk.iter_mut().enumerate() {
+                    *x = sections.tdi(start + i);
+                }
+                let last = start + chunk.len() == total;
+                self.jtag.shift_chunk(chunk, last).map_err(interface)?;
+                if total <= MISMATCH_HEX_BITS {
+                    read.extend_from_slice(chunk);
+                }
+                let first = (0..chunk.len()).find(|i| {
+                    let (expected, mask) = sections.expected(start + i);
+                    mask && chunk[*i] != expected
+                });
+                if let (None, Some(first)) = (&mismatch, first) {
+                    let window = (start + first) & !31;
+                    let window = window.max(start)..(window + 32).min(start + chunk.len());
+                    let hex = to_hex(window.clone(), |i| chunk[i - start]);
+                    mismatch = Some((window, hex));
+                }
+                start += chunk.len();
+                self.progress.bits += chunk.len() as u64;
+                if !last {
+                    progress(&self.progress);
+                }
+            }
+            if total <= MISMATCH_HEX_BITS && mismatch.is_some() {
+                mismatch = Some((0..total, to_hex(0..total, |i| read[i])));
+            }
+        }
+        let error = mismatch.map(|(bits, read)| SvfError::Mismatch {
+            position,
+            scan: name,
+            expected: to_hex(bits.clone(), |i| sections.expected(i).0),
+            mask: to_hex(bits.clone(), |i| sections.expected(i).1),
+            read,
+            offset: if total <= MISMATCH_HEX_BITS {
+                None
+            } else {
+                Some(bits.start)
+            },
+        });
+        goto(self.jtag, end_state).map_err(interface)?;
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn goto(&mut self, to: JS) -> Result<(), InterfaceError> {
+        goto(self.jtag, to)
+    }
+
+    fn run_test(&mut self, run_count: u64, min_time: Option<f64>) -> Result<(), InterfaceError> {
+        let mut clocks = run_count;
+        let mut wait = None;
+        if let Some(min_time) = min_time {
+            match self.frequency {
+                Some(frequency) => clocks = clocks.max((min_time * frequency).ceil() as u64),
+                None => wait = Some(Duration::from_secs_f64(min_time)),
+            }
+        }
+        self.goto(self.run_state)?;
+        // Test-Logic-Reset以外の安定したstateはTMS=Lで留まる
+        let tms = [self.run_state == JS::Reset; RUNTEST_CHUNK];
+        while clocks > 0 {
+            let length = clocks.min(RUNTEST_CHUNK as u64);
+            self.jtag.write_tms(&tms[..length as usize])?;
+            clocks -= length;
+        }
+        if let Some(wait) = wait {
+            std::thread::sleep(wait);
+        }
+        self.goto(self.run_end_state)
+    }
+
+    fn trst(&mut self, position: Position, mode: TrstMode) -> Result<(), InterfaceError> {
+        let result = match mode {
+            TrstMode::On => self.jtag.assert_trst(true),
+            TrstMode::Off | TrstMode::Z => self.jtag.assert_trst(false),
+            TrstMode::Absent => return Ok(()),
+        };
+        // TRSTのないadapterでも、解除するだけなら続けてよい
+        match result {
+            Err(InterfaceError::Unsupported) if mode != TrstMode::On => {
+                warn!("{}: TRST is not supported by interface", position);
+                Ok(())
+            }
+            x => x,
+        }
+    }
+}
+
+// change_stateが扱えない遷移はRun-Test/Idleを経由する
+fn goto<T: JtagInterface>(jtag: &mut Jtag<T>, to: JS) -> Result<(), InterfaceError> {
+    let from = jtag.state();
+    let direct = from == to
+        || matches!(
+            (from, to),
+            (_, JS::Reset)
+                | (_, JS::RunIdle)
+                | (JS::Reset, _)
+                | (JS::RunIdle, _)
+                | (JS::Exit1DR, JS::PauseDR)
+                | (JS::Exit1IR, JS::PauseIR)
+        );
+    if !direct {
+        jtag.change_state(JS::RunIdle)?;
+    }
+    jtag.change_state(to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::mock::MockInterface;
+    use crate::interface::{CapFlags, InterfaceCaps};
+    use crate::jtag::bits;
+    use crate::jtag::jtag::tests::initialized;
+
+    fn parse(text: &str) -> Vec<(Position, SvfCommand)> {
+        SvfParser::new(text.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    fn at(line: usize, column: usize) -> Position {
+        Position { line, column }
+    }
+
+    fn bits(value: u64, len: usize) -> Vec<bool> {
+        bits::to_bools_lsb_first(value, len).collect()
+    }
+
+    fn packed(value: u64, len: usize) -> Vec<u8> {
+        value.to_le_bytes()[..byte_len(len)].to_vec()
+    }
+
+    fn mock_jtag() -> Jtag<MockInterface> {
+        let jtag = initialized(MockInterface::new());
+        jtag.interface.clear();
+        jtag
+    }
+
+    #[test]
+    fn parse_test() {
+        let commands = parse(
+            "! header comment\n\
+             TRST OFF; ENDIR IDLE;\n\
+             // another comment\n\
+             sdr 12 tdi (a5\n\
+             F) TDO(000) MASK (0ff); ! trailing\n\
+             STATE RESET IDLE;\n\
+             RUNTEST IDLE 100 TCK 1.0E-3 SEC ENDSTATE DRPAUSE;\n\
+             FREQUENCY 1E6 HZ;;\n",
+        );
+        assert_eq!(
+            vec![
+                (at(2, 1), SvfCommand::Trst(TrstMode::Off)),
+                (at(2, 11), SvfCommand::EndIr(JS::RunIdle)),
+                (
+                    at(4, 1),
+                    SvfCommand::Scan(
+                        ScanKind::Sdr,
+                        Scan {
+                            len: 12,
+                            tdi: Some(packed(0xa5f, 12)),
+                            tdo: Some(packed(0, 12)),
+                            mask: Some(packed(0x0ff, 12)),
+                            smask: None,
+                        }
+                    )
+                ),
+                (at(6, 1), SvfCommand::State(vec![JS::Reset, JS::RunIdle])),
+                (
+                    at(7, 1),
+                    SvfCommand::RunTest {
+                        run_state: Some(JS::RunIdle),
+                        run_count: Some(100),
+                        min_time: Some(1.0e-3),
+                        max_time: None,
+                        end_state: Some(JS::PauseDR),
+                    }
+                ),
+                (at(8, 1), SvfCommand::Frequency(Some(1e6))),
+            ],
+            commands
+        );
+    }
+
+    #[test]
+    fn parse_error_test() {
+        let error = |text: &str| {
+            SvfParser::new(text.as_bytes())
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            "line 2, column 1: unsupported command PIO",
+            error("TRST OFF;\nPIO (HLX);")
+        );
+        assert_eq!(
+            "line 1, column 1: (1FF) is longer than 8 bits",
+            error("SIR 8 TDI (1FF);")
+        );
+        assert_eq!(
+            "line 1, column 11: DRSHIFT is not a stable state",
+            error("TRST OFF; ENDDR DRSHIFT;")
+        );
+        // 前の行から続くcommandは、始まった位置を示す
+        assert_eq!(
+            "line 2, column 11: unknown parameter TDX",
+            error("SIR 4 TDI (1);\nTRST OFF; SDR 8\n  TDX (00);")
+        );
+        assert_eq!(
+            "line 3, column 1: missing ';' at end of file",
+            error("SIR 4 TDI (1);\n\nSDR 8 TDI (00)\n")
+        );
+    }
+
+    #[test]
+    fn parse_hex_test() {
+        let tdi = |text: &str| match parse(text).remove(0).1 {
+            SvfCommand::Scan(_, scan) => scan.tdi.unwrap(),
+            x => panic!("unexpected {:?}", x),
+        };
+        // 奇数桁、先頭の0、長いlenの残りは0
+        assert_eq!(packed(0x1a5, 9), tdi("SDR 9 TDI (1a5);"));
+        assert_eq!(packed(0xbc, 8), tdi("SDR 8 TDI (0000BC);"));
+        assert_eq!(
+            packed(0x123_4567, 40),
+            tdi("SDR 40 TDI (12\n34 ! comment\n567);")
+        );
+        assert_eq!(vec![0; 0], tdi("SDR 0 TDI ();"));
+    }
+
+    #[test]
+    fn parse_resync_test() {
+        let commands: Vec<_> = SvfParser::new("SDR 8 TDI (0G) TDO (00);\nTRST OFF;\n".as_bytes())
+            .map(|x| x.map_err(|e| e.to_string()))
+            .collect();
+        // errorのcommandを読み飛ばして、次のcommandから続ける
+        assert_eq!(
+            vec![
+                Err("line 1, column 1: invalid hex digit 'G'".to_string()),
+                Ok((at(2, 1), SvfCommand::Trst(TrstMode::Off))),
+            ],
+            commands
+        );
+    }
+
+    #[test]
+    fn sdr_tdo_test() {
+        let mut jtag = mock_jtag();
+        let reads = jtag.interface.reads();
+        for n in 0..3 {
+            jtag.interface.script_read(reads + n, &bits(0x3c, 8));
+        }
+        let mut player = SvfPlayer::new(&mut jtag);
+        player
+            .play("SDR 8 TDI (A5) TDO (3C) MASK (FF);\n".as_bytes())
+            .unwrap();
+        // MASKは引き継がれる
+        let error = player
+            .play("\nSDR 8 TDI (A5) TDO (3D);\n".as_bytes())
+            .unwrap_err();
+        assert!(matches!(error, SvfError::Mismatch { scan: "SDR", .. }));
+        assert_eq!(
+            "line 2, column 1: SDR TDO mismatch: expected 3D, read 3C, mask FF",
+            error.to_string()
+        );
+        // maskしたbitは比較しない
+        player
+            .play("SDR 8 TDI (A5) TDO (3D) MASK (FE);\n".as_bytes())
+            .unwrap();
+        drop(player);
+
+        assert_eq!(vec![bits(0xa5, 8); 3], jtag.interface.tdi_sequence());
+        assert_eq!(JS::RunIdle, jtag.interface.state());
+    }
+
+    #[test]
+    fn long_mismatch_test() {
+        let mut jtag = mock_jtag();
+        let reads = jtag.interface.reads();
+        let mut tdo = vec![false; 100];
+        tdo[70] = true;
+        jtag.interface.script_read(reads, &tdo);
+        let mut player = SvfPlayer::new(&mut jtag);
+        let error = player
+            .play("SDR 100 TDI (0) TDO (0);\n".as_bytes())
+            .unwrap_err();
+        // 最初に一致しなかったbitを含む32bitだけを示す
+        assert_eq!(
+            "line 1, column 1: SDR TDO mismatch from bit 64: \
+             expected 00000000, read 00000040, mask FFFFFFFF",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn chunked_shift_test() {
+        let mut jtag = mock_jtag();
+        jtag.interface.set_capabilities(InterfaceCaps {
+            flags: CapFlags::empty(),
+            max_bits_per_transfer: 40,
+        });
+        let mut progress = Vec::new();
+        let mut player = SvfPlayer::new(&mut jtag);
+        player
+            .play_with_progress(
+                "HDR 8 TDI (A5);\nSDR 64 TDI (0123456789ABCDEF);\n".as_bytes(),
+                &mut |x| progress.push(*x),
+            )
+            .unwrap();
+        drop(player);
+        // 72bitを32bitずつに分け、chunkの間はPause-DRで止める
+        let tdi = [bits(0xa5, 8), bits(0x0123_4567_89ab_cdef, 64)].concat();
+        assert_eq!(
+            vec![tdi[..32].to_vec(), tdi[32..64].to_vec(), tdi[64..].to_vec()],
+            jtag.interface.tdi_sequence()
+        );
+        let scan = |n: usize| [vec![false; n - 1], vec![true]].concat();
+        assert_eq!(
+            [
+                vec![false, true, false, false],
+                scan(32),
+                vec![false, true, false],
+                scan(32),
+                vec![false, true, false],
+                scan(8),
+                vec![true, false],
+            ]
+            .concat(),
+            jtag.interface.tms_sequence()
+        );
+        let at = |commands, bits, line| SvfProgress {
+            commands,
+            bits,
+            line,
+        };
+        assert_eq!(
+            vec![at(1, 0, 1), at(1, 32, 2), at(1, 64, 2), at(2, 72, 2)],
+            progress
+        );
+    }
+
+    #[test]
+    fn header_trailer_test() {
+        let mut jtag = mock_jtag();
+        let mut player = SvfPlayer::new(&mut jtag);
+        player
+            .play(
+                "HIR 4 TDI (F);\n\
+                 TIR 2 TDI (3);\n\
+                 ENDIR IRPAUSE;\n\
+                 SIR 4 TDI (E);\n\
+                 HIR 0; TIR 0; ENDIR IDLE;\n\
+                 SIR 4;\n"
+                    .as_bytes(),
+            )
+            .unwrap();
+        drop(player);
+        assert_eq!(
+            vec![
+                [bits(0xf, 4), bits(0xe, 4), bits(0x3, 2)].concat(),
+                bits(0xe, 4)
+            ],
+            jtag.interface.tdi_sequence()
+        );
+        // Reset -> ShiftIR -> Exit1IR -> PauseIR -> RunIdle -> ShiftIR -> Exit1IR -> RunIdle
+        let tms = jtag.interface.tms_sequence();
+        assert_eq!(
+            [
+                vec![false, true, true, false, false],
+                bits(0x200, 10),
+                vec![false, true, true, false],
+                vec![true, true, false, false],
+                bits(0x8, 4),
+                vec![true, false],
+            ]
+            .concat(),
+            tms
+        );
+    }
+
+    #[test]
+    fn runtest_test() {
+        let mut jtag = mock_jtag();
+        let mut player = SvfPlayer::new(&mut jtag);
+        player
+            .play(
+                "FREQUENCY 1000 HZ;\n\
+                 RUNTEST 3 TCK 5E-3 SEC ENDSTATE DRPAUSE;\n\
+                 STATE RESET;\n\
+                 TRST ON; TRST OFF; TRST ABSENT;\n"
+                    .as_bytes(),
+            )
+            .unwrap();
+        drop(player);
+        // 5ms * 1kHzで5clock
+        assert_eq!(
+            [
+                vec![false],
+                vec![false; 5],
+                vec![true, false, true, false],
+                vec![true; 5],
+            ]
+            .concat(),
+            jtag.interface.tms_sequence()
+        );
+        assert_eq!(JS::Reset, jtag.state());
+    }
+}
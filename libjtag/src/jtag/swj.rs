@@ -0,0 +1,171 @@
+// SWJ-DPのJTAG/SWD切り替えとdormant state
+// SWDで起動するboardでは、JTAGに切り替えないとscanで何も見つからない
+use core::cmp;
+
+use crate::interface::{InterfaceError, JtagInterface};
+use crate::jtag::bits::fill_lsb_first;
+
+// line reset。SWDでもJTAGでも50 cycle以上TMS(SWDIO)をHにする
+const LINE_RESET_CYCLES: usize = 50;
+// JTAGのTAPをTest-Logic-Resetにする
+const TAP_RESET_CYCLES: usize = 5;
+
+// LSB firstで送る選択sequence
+const SWD_TO_JTAG: u16 = 0xE73C;
+const JTAG_TO_SWD: u16 = 0xE79E;
+const SWD_TO_DORMANT: u16 = 0xE3BC;
+// 31bit
+const JTAG_TO_DORMANT: u32 = 0x33BB_BBBA;
+const JTAG_TO_DORMANT_LEN: usize = 31;
+
+// dormantから抜けるSelection Alert sequence(128bit, LSB first)
+const SELECTION_ALERT: u128 = 0x19BC_0EA2_E3DD_AFE9_8685_2D95_6209_F392;
+// Selection Alertの前にHにしておく長さと、後にLにする長さ
+const ALERT_PREAMBLE_CYCLES: usize = 8;
+const ALERT_POSTAMBLE_CYCLES: usize = 4;
+const ACTIVATION_JTAG: u8 = 0x0A;
+const ACTIVATION_SWD: u8 = 0x1A;
+
+// Jtag::new_with_switchで指定し、initializeでscanの前に送るsequence
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SwitchProtocol {
+    None,
+    SwdToJtag,
+    DormantToJtag,
+}
+
+// valueの下位len bitをLSBから順に並べる
+fn bits<const N: usize>(value: u128, len: usize) -> [bool; N] {
+    let mut bits = [false; N];
+    let low = cmp::min(len, 64);
+    fill_lsb_first(&mut bits[..low], value as u64);
+    fill_lsb_first(&mut bits[low..len], (value >> 64) as u64);
+    bits
+}
+
+fn tms_high(interface: &impl JtagInterface, cycles: usize) -> Result<(), InterfaceError> {
+    interface.write_tms(&[true; LINE_RESET_CYCLES][..cycles])
+}
+
+pub fn switch_swd_to_jtag(interface: &mut impl JtagInterface) -> Result<(), InterfaceError> {
+    tms_high(interface, LINE_RESET_CYCLES)?;
+    interface.write_tms(&bits::<16>(SWD_TO_JTAG as u128, 16))?;
+    tms_high(interface, LINE_RESET_CYCLES)
+}
+
+pub fn switch_jtag_to_swd(interface: &mut impl JtagInterface) -> Result<(), InterfaceError> {
+    tms_high(interface, LINE_RESET_CYCLES)?;
+    interface.write_tms(&bits::<16>(JTAG_TO_SWD as u128, 16))?;
+    tms_high(interface, LINE_RESET_CYCLES)
+}
+
+pub fn enter_dormant_from_jtag(interface: &mut impl JtagInterface) -> Result<(), InterfaceError> {
+    tms_high(interface, TAP_RESET_CYCLES)?;
+    interface.write_tms(&bits::<JTAG_TO_DORMANT_LEN>(
+        JTAG_TO_DORMANT as u128,
+        JTAG_TO_DORMANT_LEN,
+    ))
+}
+
+pub fn enter_dormant_from_swd(interface: &mut impl JtagInterface) -> Result<(), InterfaceError> {
+    tms_high(interface, LINE_RESET_CYCLES)?;
+    interface.write_tms(&bits::<16>(SWD_TO_DORMANT as u128, 16))
+}
+
+// Selection Alertの後にactivation codeを送る
+fn leave_dormant(interface: &impl JtagInterface, activation: u8) -> Result<(), InterfaceError> {
+    tms_high(interface, ALERT_PREAMBLE_CYCLES)?;
+    interface.write_tms(&bits::<128>(SELECTION_ALERT, 128))?;
+    interface.write_tms(&[false; ALERT_POSTAMBLE_CYCLES])?;
+    interface.write_tms(&bits::<8>(activation as u128, 8))
+}
+
+pub fn leave_dormant_to_jtag(interface: &mut impl JtagInterface) -> Result<(), InterfaceError> {
+    leave_dormant(interface, ACTIVATION_JTAG)?;
+    tms_high(interface, TAP_RESET_CYCLES)
+}
+
+pub fn leave_dormant_to_swd(interface: &mut impl JtagInterface) -> Result<(), InterfaceError> {
+    leave_dormant(interface, ACTIVATION_SWD)?;
+    tms_high(interface, LINE_RESET_CYCLES)
+}
+
+pub fn switch_to_jtag(
+    interface: &mut impl JtagInterface,
+    switch: SwitchProtocol,
+) -> Result<(), InterfaceError> {
+    match switch {
+        SwitchProtocol::None => Ok(()),
+        SwitchProtocol::SwdToJtag => switch_swd_to_jtag(interface),
+        SwitchProtocol::DormantToJtag => leave_dormant_to_jtag(interface),
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::interface::mock::MockInterface;
+    use crate::jtag::jtag_state_machine::JtagState;
+
+    // 先頭からLSB firstのbit列にする
+    fn lsb_first(bytes: &[u8], len: usize) -> Vec<bool> {
+        (0..len)
+            .map(|i| (bytes[i / 8] >> (i % 8)) & 1 != 0)
+            .collect()
+    }
+
+    fn high(cycles: usize) -> Vec<bool> {
+        vec![true; cycles]
+    }
+
+    #[test]
+    fn swd_jtag_switch_test() {
+        let mut mock = MockInterface::new();
+        switch_swd_to_jtag(&mut mock).unwrap();
+        let expected = [high(50), lsb_first(&[0x3C, 0xE7], 16), high(50)].concat();
+        mock.expect_tms_sequence(&expected);
+        assert_eq!(JtagState::Reset, mock.state());
+
+        let mut mock = MockInterface::new();
+        switch_jtag_to_swd(&mut mock).unwrap();
+        let expected = [high(50), lsb_first(&[0x9E, 0xE7], 16), high(50)].concat();
+        mock.expect_tms_sequence(&expected);
+    }
+
+    #[test]
+    fn dormant_test() {
+        let mut mock = MockInterface::new();
+        enter_dormant_from_jtag(&mut mock).unwrap();
+        let expected = [high(5), lsb_first(&[0xBA, 0xBB, 0xBB, 0x33], 31)].concat();
+        mock.expect_tms_sequence(&expected);
+
+        let mut mock = MockInterface::new();
+        enter_dormant_from_swd(&mut mock).unwrap();
+        let expected = [high(50), lsb_first(&[0xBC, 0xE3], 16)].concat();
+        mock.expect_tms_sequence(&expected);
+
+        // OpenOCDのdormant to SWD/JTAGと同じbyte列
+        let alert = [
+            0xff, 0x92, 0xf3, 0x09, 0x62, 0x95, 0x2d, 0x85, 0x86, 0xe9, 0xaf, 0xdd, 0xe3, 0xa2,
+            0x0e, 0xbc, 0x19,
+        ];
+        let mut mock = MockInterface::new();
+        leave_dormant_to_swd(&mut mock).unwrap();
+        let expected = [
+            lsb_first(&[&alert[..], &[0xa0, 0x01]].concat(), 8 + 128 + 4 + 8),
+            high(50),
+        ]
+        .concat();
+        mock.expect_tms_sequence(&expected);
+
+        let mut mock = MockInterface::new();
+        leave_dormant_to_jtag(&mut mock).unwrap();
+        let expected = [
+            lsb_first(&[&alert[..], &[0xa0, 0x00]].concat(), 8 + 128 + 4 + 8),
+            high(5),
+        ]
+        .concat();
+        mock.expect_tms_sequence(&expected);
+        assert_eq!(JtagState::Reset, mock.state());
+    }
+}
@@ -1,8 +1,16 @@
 #![cfg_attr(all(not(feature = "std"), not(test)), no_std)]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod config;
+pub mod error;
 pub mod interface;
 pub mod jtag;
+mod regfmt;
 pub mod target;
+pub mod tools;
 
 #[cfg(feature = "std")]
 pub use crate::interface::ftdi_bitbang;
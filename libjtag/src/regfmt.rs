@@ -0,0 +1,109 @@
+// registerのbitfieldをlog向けに"NAME{STATUS=0b010011 EL=1 NS ITE}"の形で表示する
+// 1bitのflagは立っている時だけ名前を出す
+use core::fmt;
+
+pub(crate) struct RegFmt<'a, 'b> {
+    f: &'a mut fmt::Formatter<'b>,
+    result: fmt::Result,
+    first: bool,
+}
+
+impl<'a, 'b> RegFmt<'a, 'b> {
+    pub(crate) fn new(f: &'a mut fmt::Formatter<'b>, name: &str) -> Self {
+        let result = write!(f, "{}{{", name);
+        RegFmt {
+            f,
+            result,
+            first: true,
+        }
+    }
+
+    fn separator(&mut self) {
+        if !self.first {
+            self.result = self.result.and_then(|_| self.f.write_str(" "));
+        }
+        self.first = false;
+    }
+
+    pub(crate) fn flag(&mut self, name: &str, value: u32) -> &mut Self {
+        if value != 0 {
+            self.separator();
+            self.result = self.result.and_then(|_| self.f.write_str(name));
+        }
+        self
+    }
+
+    pub(crate) fn field(&mut self, name: &str, value: fmt::Arguments) -> &mut Self {
+        self.separator();
+        self.result = self
+            .result
+            .and_then(|_| write!(self.f, "{}={}", name, value));
+        self
+    }
+
+    // 0の場合は省く
+    pub(crate) fn nonzero(&mut self, name: &str, value: u32, args: fmt::Arguments) -> &mut Self {
+        if value != 0 {
+            self.field(name, args);
+        }
+        self
+    }
+
+    pub(crate) fn finish(&mut self) -> fmt::Result {
+        self.result.and_then(|_| self.f.write_str("}"))
+    }
+}
+
+// defmt向けに同じ形で書く。表示hintはformat文字列にしか書けないので、fieldの値は書く処理で受け取る
+#[cfg(feature = "defmt")]
+pub(crate) struct RegDefmt<'a> {
+    f: defmt::Formatter<'a>,
+    first: bool,
+}
+
+#[cfg(feature = "defmt")]
+impl<'a> RegDefmt<'a> {
+    pub(crate) fn new(f: defmt::Formatter<'a>, name: &str) -> Self {
+        defmt::write!(f, "{=str}{{", name);
+        RegDefmt { f, first: true }
+    }
+
+    fn separator(&mut self) {
+        if !self.first {
+            defmt::write!(self.f, " ");
+        }
+        self.first = false;
+    }
+
+    pub(crate) fn flag(&mut self, name: &str, value: u32) -> &mut Self {
+        if value != 0 {
+            self.separator();
+            defmt::write!(self.f, "{=str}", name);
+        }
+        self
+    }
+
+    pub(crate) fn field(&mut self, name: &str, value: impl FnOnce(defmt::Formatter)) -> &mut Self {
+        self.separator();
+        defmt::write!(self.f, "{=str}=", name);
+        value(self.f);
+        self
+    }
+
+    // 0の場合は省く
+    pub(crate) fn nonzero(
+        &mut self,
+        name: &str,
+        value: u32,
+        args: impl FnOnce(defmt::Formatter),
+    ) -> &mut Self {
+        if value != 0 {
+            self.field(name, args);
+        }
+        self
+    }
+
+    pub(crate) fn finish(&mut self) {
+        defmt::write!(self.f, "}}");
+    }
+}
@@ -1 +1,7 @@
+pub mod arm32;
 pub mod arm64;
+pub mod flashloader;
+#[cfg(feature = "alloc")]
+pub mod history;
+#[cfg(feature = "alloc")]
+pub mod router;
@@ -0,0 +1,316 @@
+// AArch32 stateのcoreをARMv8 external debugで操作する
+// EDITRにはT32命令を書き、DBGDTRとのやりとりはp14のMCR/MRCで行う
+use crate::error::DebugError;
+use crate::interface::InterfaceError;
+use crate::jtag::dap::*;
+use crate::target::arm64::{A64Target, AArch64Register};
+
+// AArch32での名前。offsetはArmv8DebugRegisterOffsetと同じ
+pub enum AArch32DebugRegisterOffset {
+    DBGDTRRXext = 0x080,
+    DBGITR = 0x084,
+    DBGDSCRext = 0x088,
+    DBGDTRTXext = 0x08C,
+    DBGDRCR = 0x090,
+    DBGOSLAR = 0x300,
+    DBGPRCR = 0x310,
+    DBGPRSR = 0x314,
+    DBGBVR_BASE = 0x400,
+    DBGBCR_BASE = 0x408,
+    DBGWVR_BASE = 0x800,
+    DBGWCR_BASE = 0x808,
+    MIDR = 0xD00,
+}
+
+// 番号はA32のencoding(cond=AL)。T32の32bit命令として見ても同じ値になる
+// MCR p<coproc>, <opc1>, Rt, CRn, CRm, <opc2>
+pub fn encode_mcr(coproc: u8, opc1: u8, rt: u8, crn: u8, crm: u8, opc2: u8) -> u32 {
+    0xEE00_0010
+        | ((opc1 as u32 & 0x7) << 21)
+        | ((crn as u32 & 0xf) << 16)
+        | ((rt as u32 & 0xf) << 12)
+        | ((coproc as u32 & 0xf) << 8)
+        | ((opc2 as u32 & 0x7) << 5)
+        | (crm as u32 & 0xf)
+}
+
+// MRC p<coproc>, <opc1>, Rt, CRn, CRm, <opc2>
+pub fn encode_mrc(coproc: u8, opc1: u8, rt: u8, crn: u8, crm: u8, opc2: u8) -> u32 {
+    encode_mcr(coproc, opc1, rt, crn, crm, opc2) | (1 << 20)
+}
+
+// Rt -> DBGDTRTXint
+pub fn encode_mcr_dtrtx(rt: u8) -> u32 {
+    encode_mcr(14, 0, rt, 0, 5, 0)
+}
+// DBGDTRRXint -> Rt
+pub fn encode_mrc_dtrrx(rt: u8) -> u32 {
+    encode_mrc(14, 0, rt, 0, 5, 0)
+}
+// DLR/DSPSRはdebug state中だけp15から読み書きできる
+pub fn encode_mrc_dlr(rt: u8) -> u32 {
+    encode_mrc(15, 3, rt, 4, 5, 1)
+}
+pub fn encode_mcr_dlr(rt: u8) -> u32 {
+    encode_mcr(15, 3, rt, 4, 5, 1)
+}
+pub fn encode_mrc_dspsr(rt: u8) -> u32 {
+    encode_mrc(15, 3, rt, 4, 5, 0)
+}
+pub fn encode_mcr_dspsr(rt: u8) -> u32 {
+    encode_mcr(15, 3, rt, 4, 5, 0)
+}
+
+// EDITRには1つ目のhalfwordを下位16bitにして書く
+pub fn t32_itr(instruction: u32) -> u32 {
+    instruction.rotate_left(16)
+}
+
+// read_gpr/write_gprでPCを指す番号
+pub const GPR_PC: u8 = 15;
+// PC/CPSRの転送に使うregister
+const SCRATCH: u8 = 0;
+
+pub struct A32Target<T> {
+    pub dap: DapHandle<T>,
+    pub baseaddr: u64,
+}
+
+impl<T: DebugPort + MemoryAccessPort> A32Target<T> {
+    // EDSCRのpollingやEDITRの扱いはA64Targetと共通
+    fn core(&self) -> A64Target<T> {
+        A64Target::new(self.dap.clone(), self.baseaddr)
+    }
+
+    // instructionはencode_*で作った値。T32として実行させる
+    pub fn exec_insn(&mut self, instruction: u32) -> Result<(), DebugError> {
+        self.core().exec_insn(t32_itr(instruction))
+    }
+
+    fn dtr_read(&mut self, rt: u8) -> Result<u32, DebugError> {
+        self.exec_insn(encode_mcr_dtrtx(rt))?;
+        self.core().wait_edscr(|x| x.TXfull() == 1)?;
+        Ok(self.register_u32_read(AArch32DebugRegisterOffset::DBGDTRTXext as u64)?)
+    }
+
+    fn dtr_write(&mut self, rt: u8, data: u32) -> Result<(), DebugError> {
+        self.register_u32_write(AArch32DebugRegisterOffset::DBGDTRRXext as u64, data)?;
+        self.exec_insn(encode_mrc_dtrrx(rt))
+    }
+
+    fn scratch_read(&mut self, instruction: u32) -> Result<u32, DebugError> {
+        let saved = self.dtr_read(SCRATCH)?;
+        self.exec_insn(instruction)?;
+        let result = self.dtr_read(SCRATCH);
+        self.dtr_write(SCRATCH, saved)?;
+        result
+    }
+
+    fn scratch_write(&mut self, instruction: u32, data: u32) -> Result<(), DebugError> {
+        let saved = self.dtr_read(SCRATCH)?;
+        self.dtr_write(SCRATCH, data)?;
+        self.exec_insn(instruction)?;
+        self.dtr_write(SCRATCH, saved)
+    }
+
+    // n: 0-14はRn, 15(GPR_PC)はPC
+    pub fn read_gpr(&mut self, n: u8) -> Result<u32, DebugError> {
+        match n {
+            GPR_PC => self.read_pc(),
+            n if n < GPR_PC => self.dtr_read(n),
+            _ => Err(InterfaceError::OutOfRange.into()),
+        }
+    }
+
+    pub fn write_gpr(&mut self, n: u8, value: u32) -> Result<(), DebugError> {
+        match n {
+            GPR_PC => self.write_pc(value),
+            n if n < GPR_PC => self.dtr_write(n, value),
+            _ => Err(InterfaceError::OutOfRange.into()),
+        }
+    }
+
+    // debug stateから戻る先(DLR)
+    pub fn read_pc(&mut self) -> Result<u32, DebugError> {
+        self.scratch_read(encode_mrc_dlr(SCRATCH))
+    }
+    pub fn write_pc(&mut self, value: u32) -> Result<(), DebugError> {
+        self.scratch_write(encode_mcr_dlr(SCRATCH), value)
+    }
+
+    // debug state突入前のCPSR(DSPSR)
+    pub fn read_cpsr(&mut self) -> Result<u32, DebugError> {
+        self.scratch_read(encode_mrc_dspsr(SCRATCH))
+    }
+    pub fn write_cpsr(&mut self, value: u32) -> Result<(), DebugError> {
+        self.scratch_write(encode_mcr_dspsr(SCRATCH), value)
+    }
+}
+
+impl<T: DebugPort + MemoryAccessPort> AArch64Register<T> for A32Target<T> {
+    fn baseaddr(&self) -> u64 {
+        self.baseaddr
+    }
+    fn dap_lock(&self) -> DapGuard<'_, T> {
+        self.dap.lock()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::jtag::dap::tests::{memap_dap, MemApSim};
+    use crate::target::arm64::ExecutionState;
+
+    const DEBUG_BASE: u64 = 0x8001_0000;
+
+    // EDITRのT32命令のうち、DBGDTR/DLR/DSPSRとの転送だけを模擬するAArch32のcore
+    struct A32Sim {
+        inner: MemApSim,
+        itr: Vec<u32>,
+        r: [u32; 15],
+        dlr: u32,
+        dspsr: u32,
+    }
+
+    impl A32Sim {
+        fn new() -> Self {
+            let mut inner = MemApSim::new();
+            // ITEとTXfullを常に立て、EL0だけAArch32にしておく
+            inner.memory.insert(
+                DEBUG_BASE + AArch32DebugRegisterOffset::DBGDSCRext as u64,
+                (1 << 24) | (1 << 29) | (0b1110 << 10),
+            );
+            A32Sim {
+                inner,
+                itr: Vec::new(),
+                r: [0; 15],
+                dlr: 0,
+                dspsr: 0,
+            }
+        }
+
+        fn dtr(&mut self, offset: AArch32DebugRegisterOffset) -> &mut u32 {
+            self.inner
+                .memory
+                .entry(DEBUG_BASE + offset as u64)
+                .or_insert(0)
+        }
+
+        fn run(&mut self, itr: u32) {
+            self.itr.push(itr);
+            let instruction = itr.rotate_right(16);
+            let rt = ((instruction >> 12) & 0xf) as usize;
+            match instruction & !0xf000 {
+                x if x == encode_mcr_dtrtx(0) => {
+                    *self.dtr(AArch32DebugRegisterOffset::DBGDTRTXext) = self.r[rt]
+                }
+                x if x == encode_mrc_dtrrx(0) => {
+                    self.r[rt] = *self.dtr(AArch32DebugRegisterOffset::DBGDTRRXext)
+                }
+                x if x == encode_mrc_dlr(0) => self.r[rt] = self.dlr,
+                x if x == encode_mcr_dlr(0) => self.dlr = self.r[rt],
+                x if x == encode_mrc_dspsr(0) => self.r[rt] = self.dspsr,
+                x if x == encode_mcr_dspsr(0) => self.dspsr = self.r[rt],
+                _ => panic!("unexpected instruction {:#010x}", instruction),
+            }
+        }
+    }
+
+    impl DapInterface for A32Sim {
+        fn apacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            let result = self.inner.apacc(data, a, rnw)?;
+            let itr = DEBUG_BASE + AArch32DebugRegisterOffset::DBGITR as u64;
+            if let Some(instruction) = self.inner.memory.remove(&itr) {
+                self.run(instruction);
+            }
+            Ok(result)
+        }
+        fn dpacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            self.inner.dpacc(data, a, rnw)
+        }
+    }
+
+    #[test]
+    fn encoder_test() {
+        // mcr p14, 0, r0, c0, c5, 0 / mrc p14, 0, r1, c0, c5, 0
+        assert_eq!(0xEE00_0E15, encode_mcr_dtrtx(0));
+        assert_eq!(0xEE10_1E15, encode_mrc_dtrrx(1));
+        // mrc p15, 3, r0, c4, c5, 1 / mcr p15, 3, r2, c4, c5, 1
+        assert_eq!(0xEE74_0F35, encode_mrc_dlr(0));
+        assert_eq!(0xEE64_2F35, encode_mcr_dlr(2));
+        // mrc p15, 3, r0, c4, c5, 0 / mcr p15, 3, r0, c4, c5, 0
+        assert_eq!(0xEE74_0F15, encode_mrc_dspsr(0));
+        assert_eq!(0xEE64_0F15, encode_mcr_dspsr(0));
+        // mrc p15, 0, r3, c0, c0, 0 (MIDR)
+        assert_eq!(0xEE10_3F10, encode_mrc(15, 0, 3, 0, 0, 0));
+        // 1つ目のhalfwordが下位に来る
+        assert_eq!(0x0E15_EE00, t32_itr(0xEE00_0E15));
+    }
+
+    #[test]
+    fn gpr_test() {
+        let dap = DapHandle::new(memap_dap(A32Sim::new()));
+        let mut target = A32Target {
+            dap: dap.clone(),
+            baseaddr: DEBUG_BASE,
+        };
+        target.write_gpr(5, 0x1122_3344).unwrap();
+        assert_eq!(0x1122_3344, dap.lock().dp.r[5]);
+        assert_eq!(vec![0x5E15_EE10], dap.lock().dp.itr);
+
+        dap.lock().dp.r[14] = 0xdead_beef;
+        dap.lock().dp.itr.clear();
+        assert_eq!(0xdead_beef, target.read_gpr(14).unwrap());
+        assert_eq!(vec![0xEE15_EE00], dap.lock().dp.itr);
+        assert_eq!(
+            Err(DebugError::Interface(InterfaceError::OutOfRange)),
+            target.read_gpr(16)
+        );
+    }
+
+    #[test]
+    fn pc_cpsr_test() {
+        let dap = DapHandle::new(memap_dap(A32Sim::new()));
+        let mut target = A32Target {
+            dap: dap.clone(),
+            baseaddr: DEBUG_BASE,
+        };
+        dap.lock().dp.r[0] = 0x0123_4567;
+        target.write_gpr(GPR_PC, 0x8000).unwrap();
+        assert_eq!(0x8000, dap.lock().dp.dlr);
+        assert_eq!(0x8000, target.read_pc().unwrap());
+        target.write_cpsr(0x1d3).unwrap();
+        assert_eq!(0x1d3, dap.lock().dp.dspsr);
+        assert_eq!(0x1d3, target.read_cpsr().unwrap());
+        // scratchに使ったr0は元に戻っている
+        assert_eq!(0x0123_4567, dap.lock().dp.r[0]);
+    }
+
+    #[test]
+    fn dispatch_test() {
+        let dap = DapHandle::new(memap_dap(A32Sim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        assert_eq!(ExecutionState::AArch32, target.execution_state().unwrap());
+        // registerはAArch32の命令で読み書きする
+        dap.lock().dp.r[3] = 0x5555_aaaa;
+        assert_eq!(0x5555_aaaa, target.read_gpr(3).unwrap());
+        dap.lock().dp.dlr = 0x8000;
+        assert_eq!(0x8000, target.read_pc().unwrap());
+        target.write_cpsr(0x1d3).unwrap();
+        assert_eq!(0x1d3, dap.lock().dp.dspsr);
+
+        // A64命令しかない操作は実行させない
+        dap.lock().dp.itr.clear();
+        assert_eq!(
+            Err(DebugError::WrongExecutionState),
+            target.mrs(crate::target::arm64::SysReg::MIDR_EL1)
+        );
+        let mut buf = [0; 4];
+        assert_eq!(
+            Err(DebugError::WrongExecutionState),
+            target.mem_read(0x1000, &mut buf)
+        );
+        assert!(dap.lock().dp.itr.is_empty());
+    }
+}
@@ -0,0 +1,4396 @@
+#[cfg(feature = "std")]
+use crate::config::{ConfigError, TargetConfig};
+pub use crate::error::DebugError;
+use crate::interface::InterfaceError;
+use crate::jtag::dap::*;
+#[cfg(feature = "defmt")]
+use crate::regfmt::RegDefmt;
+use crate::regfmt::RegFmt;
+use crate::target::arm32::A32Target;
+#[cfg(feature = "alloc")]
+use crate::target::history::{HaltHistory, HaltRecord, StateDiff};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use bitfield::{bitfield, bitfield_bitrange, bitfield_fields};
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use log::{debug, error, info, warn};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Armv8DebugRegisterOffset {
+    EDESR = 0x020,
+    EDECR = 0x024,
+    EDWARlo = 0x030,
+    EDWARhi = 0x034,
+    DBGDTRRX_EL0 = 0x080,
+    EDITR = 0x084,
+    EDSCR = 0x088,
+    DBGDTRTX_EL0 = 0x08C,
+    EDRCR = 0x090,
+    EDACR = 0x094,
+    EDECCR = 0x098,
+    EDPCSRlo = 0x0A0,
+    EDCIDSR = 0x0A4,
+    EDVIDSR = 0x0A8,
+    EDPCSRhi = 0x0AC,
+    OSLAR_EL1 = 0x0300,
+    EDPRCR = 0x0310,
+    EDPRSR = 0x0314,
+    DBGBVR_BASE_EL1 = 0x0400,
+    DBGBCR_BASE_EL1 = 0x0408,
+    DBGWVR_BASE_EL1 = 0x800,
+    DBGWCR_BASE_EL1 = 0x808,
+    MIDR_EL1 = 0xD00,
+    EDPFR = 0xD20,
+    EDDFR = 0xD28,
+    DBGAUTHSTATUS = 0xFB8,
+    EDPIDR0 = 0xFE0,
+    EDPIDR1 = 0xFE4,
+    EDPIDR2 = 0xFE8,
+    EDPIDR4 = 0xFEC,
+    EDDEVTYPE = 0xFCC,
+}
+
+impl Armv8DebugRegisterOffset {
+    pub const ALL: [Armv8DebugRegisterOffset; 31] = [
+        Armv8DebugRegisterOffset::EDESR,
+        Armv8DebugRegisterOffset::EDECR,
+        Armv8DebugRegisterOffset::EDWARlo,
+        Armv8DebugRegisterOffset::EDWARhi,
+        Armv8DebugRegisterOffset::DBGDTRRX_EL0,
+        Armv8DebugRegisterOffset::EDITR,
+        Armv8DebugRegisterOffset::EDSCR,
+        Armv8DebugRegisterOffset::DBGDTRTX_EL0,
+        Armv8DebugRegisterOffset::EDRCR,
+        Armv8DebugRegisterOffset::EDACR,
+        Armv8DebugRegisterOffset::EDECCR,
+        Armv8DebugRegisterOffset::EDPCSRlo,
+        Armv8DebugRegisterOffset::EDCIDSR,
+        Armv8DebugRegisterOffset::EDVIDSR,
+        Armv8DebugRegisterOffset::EDPCSRhi,
+        Armv8DebugRegisterOffset::OSLAR_EL1,
+        Armv8DebugRegisterOffset::EDPRCR,
+        Armv8DebugRegisterOffset::EDPRSR,
+        Armv8DebugRegisterOffset::DBGBVR_BASE_EL1,
+        Armv8DebugRegisterOffset::DBGBCR_BASE_EL1,
+        Armv8DebugRegisterOffset::DBGWVR_BASE_EL1,
+        Armv8DebugRegisterOffset::DBGWCR_BASE_EL1,
+        Armv8DebugRegisterOffset::MIDR_EL1,
+        Armv8DebugRegisterOffset::EDPFR,
+        Armv8DebugRegisterOffset::EDDFR,
+        Armv8DebugRegisterOffset::DBGAUTHSTATUS,
+        Armv8DebugRegisterOffset::EDPIDR0,
+        Armv8DebugRegisterOffset::EDPIDR1,
+        Armv8DebugRegisterOffset::EDPIDR2,
+        Armv8DebugRegisterOffset::EDPIDR4,
+        Armv8DebugRegisterOffset::EDDEVTYPE,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Armv8DebugRegisterOffset::EDESR => "EDESR",
+            Armv8DebugRegisterOffset::EDECR => "EDECR",
+            Armv8DebugRegisterOffset::EDWARlo => "EDWARlo",
+            Armv8DebugRegisterOffset::EDWARhi => "EDWARhi",
+            Armv8DebugRegisterOffset::DBGDTRRX_EL0 => "DBGDTRRX_EL0",
+            Armv8DebugRegisterOffset::EDITR => "EDITR",
+            Armv8DebugRegisterOffset::EDSCR => "EDSCR",
+            Armv8DebugRegisterOffset::DBGDTRTX_EL0 => "DBGDTRTX_EL0",
+            Armv8DebugRegisterOffset::EDRCR => "EDRCR",
+            Armv8DebugRegisterOffset::EDACR => "EDACR",
+            Armv8DebugRegisterOffset::EDECCR => "EDECCR",
+            Armv8DebugRegisterOffset::EDPCSRlo => "EDPCSRlo",
+            Armv8DebugRegisterOffset::EDCIDSR => "EDCIDSR",
+            Armv8DebugRegisterOffset::EDVIDSR => "EDVIDSR",
+            Armv8DebugRegisterOffset::EDPCSRhi => "EDPCSRhi",
+            Armv8DebugRegisterOffset::OSLAR_EL1 => "OSLAR_EL1",
+            Armv8DebugRegisterOffset::EDPRCR => "EDPRCR",
+            Armv8DebugRegisterOffset::EDPRSR => "EDPRSR",
+            Armv8DebugRegisterOffset::DBGBVR_BASE_EL1 => "DBGBVR_BASE_EL1",
+            Armv8DebugRegisterOffset::DBGBCR_BASE_EL1 => "DBGBCR_BASE_EL1",
+            Armv8DebugRegisterOffset::DBGWVR_BASE_EL1 => "DBGWVR_BASE_EL1",
+            Armv8DebugRegisterOffset::DBGWCR_BASE_EL1 => "DBGWCR_BASE_EL1",
+            Armv8DebugRegisterOffset::MIDR_EL1 => "MIDR_EL1",
+            Armv8DebugRegisterOffset::EDPFR => "EDPFR",
+            Armv8DebugRegisterOffset::EDDFR => "EDDFR",
+            Armv8DebugRegisterOffset::DBGAUTHSTATUS => "DBGAUTHSTATUS",
+            Armv8DebugRegisterOffset::EDPIDR0 => "EDPIDR0",
+            Armv8DebugRegisterOffset::EDPIDR1 => "EDPIDR1",
+            Armv8DebugRegisterOffset::EDPIDR2 => "EDPIDR2",
+            Armv8DebugRegisterOffset::EDPIDR4 => "EDPIDR4",
+            Armv8DebugRegisterOffset::EDDEVTYPE => "EDDEVTYPE",
+        }
+    }
+
+    // 大文字小文字は区別しない
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|x| x.name().eq_ignore_ascii_case(name))
+    }
+}
+
+// MRS/MSRで指定するsystem register (op0, op1, CRn, CRm, op2)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SysReg(pub u32, pub u32, pub u32, pub u32, pub u32);
+
+pub const DBGDTR_EL0: SysReg = SysReg(2, 3, 0, 4, 0);
+pub const DSPSR_EL0: SysReg = SysReg(3, 3, 4, 5, 0);
+pub const DLR_EL0: SysReg = SysReg(3, 3, 4, 5, 1);
+
+// よく使うsystem register
+impl SysReg {
+    pub const MIDR_EL1: SysReg = SysReg(3, 0, 0, 0, 0);
+    pub const CTR_EL0: SysReg = SysReg(3, 3, 0, 0, 1);
+    pub const MPIDR_EL1: SysReg = SysReg(3, 0, 0, 0, 5);
+    pub const SCTLR_EL1: SysReg = SysReg(3, 0, 1, 0, 0);
+    pub const TTBR0_EL1: SysReg = SysReg(3, 0, 2, 0, 0);
+    pub const TTBR1_EL1: SysReg = SysReg(3, 0, 2, 0, 1);
+    pub const TCR_EL1: SysReg = SysReg(3, 0, 2, 0, 2);
+    pub const SPSR_EL1: SysReg = SysReg(3, 0, 4, 0, 0);
+    pub const ELR_EL1: SysReg = SysReg(3, 0, 4, 0, 1);
+    pub const SP_EL0: SysReg = SysReg(3, 0, 4, 1, 0);
+    pub const CURRENT_EL: SysReg = SysReg(3, 0, 4, 2, 2);
+    pub const ESR_EL1: SysReg = SysReg(3, 0, 5, 2, 0);
+    pub const FAR_EL1: SysReg = SysReg(3, 0, 6, 0, 0);
+    pub const PAR_EL1: SysReg = SysReg(3, 0, 7, 4, 0);
+    pub const MAIR_EL1: SysReg = SysReg(3, 0, 10, 2, 0);
+    pub const VBAR_EL1: SysReg = SysReg(3, 0, 12, 0, 0);
+    pub const SCTLR_EL2: SysReg = SysReg(3, 4, 1, 0, 0);
+    pub const HCR_EL2: SysReg = SysReg(3, 4, 1, 1, 0);
+    pub const ESR_EL2: SysReg = SysReg(3, 4, 5, 2, 0);
+    pub const SCTLR_EL3: SysReg = SysReg(3, 6, 1, 0, 0);
+    pub const SCR_EL3: SysReg = SysReg(3, 6, 1, 1, 0);
+
+    // op0が0/1のencodingはMRS/MSRではなく別の命令(SYS等)になる
+    pub fn is_valid(&self) -> bool {
+        let SysReg(op0, op1, crn, crm, op2) = *self;
+        (op0 == 2 || op0 == 3) && op1 < 8 && crn < 16 && crm < 16 && op2 < 8
+    }
+
+    // アクセスに必要な最低のEL。op1で決まる
+    // 3はEL0から触れるもの、4/5はEL2、6はEL3、それ以外はEL1
+    pub fn min_el(&self) -> u8 {
+        match self.1 {
+            3 => 0,
+            4 | 5 => 2,
+            6 => 3,
+            _ => 1,
+        }
+    }
+}
+
+fn encode_sysreg(base: u32, sysreg: SysReg, rt: u8) -> u32 {
+    let SysReg(op0, op1, crn, crm, op2) = sysreg;
+    base | ((op0 & 1) << 19)
+        | (op1 << 16)
+        | (crn << 12)
+        | (crm << 8)
+        | (op2 << 5)
+        | (rt as u32 & 0x1f)
+}
+
+// MSR <sysreg>, Xt
+pub fn encode_msr(sysreg: SysReg, rt: u8) -> u32 {
+    encode_sysreg(0xD510_0000, sysreg, rt)
+}
+
+// MRS Xt, <sysreg>
+pub fn encode_mrs(sysreg: SysReg, rt: u8) -> u32 {
+    encode_sysreg(0xD530_0000, sysreg, rt)
+}
+
+// ADD Xd|SP, Xn|SP, #0 (register 31はSP)
+pub fn encode_mov_sp(rd: u8, rn: u8) -> u32 {
+    0x9100_0000 | ((rn as u32 & 0x1f) << 5) | (rd as u32 & 0x1f)
+}
+
+// LDR Wt, [Xn], #4
+pub fn encode_ldr_w_post4(rt: u8, rn: u8) -> u32 {
+    0xB840_4400 | ((rn as u32 & 0x1f) << 5) | (rt as u32 & 0x1f)
+}
+
+// STR Wt, [Xn], #4
+pub fn encode_str_w_post4(rt: u8, rn: u8) -> u32 {
+    0xB800_4400 | ((rn as u32 & 0x1f) << 5) | (rt as u32 & 0x1f)
+}
+
+// DC CIVAC, Xt
+pub fn encode_dc_civac(rt: u8) -> u32 {
+    0xD50B_7E20 | (rt as u32 & 0x1f)
+}
+
+// IC IVAU, Xt
+pub fn encode_ic_ivau(rt: u8) -> u32 {
+    0xD50B_7520 | (rt as u32 & 0x1f)
+}
+
+// AT S1E1R/S1E2R/S1E3R, Xt。elのstage 1でreadとして引き、結果をPAR_EL1に置く
+pub fn encode_at_s1r(el: u8, rt: u8) -> u32 {
+    let op1 = match el {
+        3 => 6,
+        2 => 4,
+        _ => 0,
+    };
+    0xD508_7800 | (op1 << 16) | (rt as u32 & 0x1f)
+}
+
+// DCPS1/2/3。debug state中にELnへ移る
+pub fn encode_dcps(el: u8) -> u32 {
+    0xD4A0_0000 | (el as u32 & 3)
+}
+
+// debug state中にSPSR_ELx/ELR_ELxの示すELへ戻る
+pub const DRPS: u32 = 0xD6BF_03E0;
+pub const DSB_ISH: u32 = 0xD503_3B9F;
+pub const ISB: u32 = 0xD503_3FDF;
+
+// read_gpr/write_gprでSPを指す番号
+pub const GPR_SP: u8 = 31;
+// SP/PC等の転送に使うregister
+const SCRATCH: u8 = 0;
+
+// EDSCR.ITE等を待つ回数
+const POLL_MAX: usize = 1000;
+
+// CTIのtrigger/channel割り当て
+const CTI_TRIGGER_HALT: u8 = 0;
+const CTI_TRIGGER_RESTART: u8 = 1;
+const CTI_CHANNEL_HALT: u8 = 0;
+const CTI_CHANNEL_RESTART: u8 = 1;
+
+// A64命令全体にmatchさせる
+const DBGBCR_BAS_A64: u32 = 0b1111;
+// EL1とEL0の両方でmatchさせる
+const DBG_PMC_EL1_EL0: u32 = 0b11;
+// DBGWVRはdoubleword単位で、BASで中のbyteを選ぶ
+const WATCHPOINT_GRANULE: u64 = 8;
+// PAR_EL1.PA[51:12]
+const PAR_PA_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Load = 0b01,
+    Store = 0b10,
+    Access = 0b11,
+}
+const BREAKPOINT_STRIDE: u64 = 0x10;
+
+enum CtiOffset {
+    CTICONTROL = 0x000,
+    CTIINTACK = 0x010,
+    CTIAPPSET = 0x014,
+    CTIAPPCLEAR = 0x018,
+    CTIAPPPULSE = 0x01C,
+    CTIINENn = 0x020,
+    CTIOUTENn = 0x0A0,
+    CTITRIGINSTATUS = 0x130,
+    CTITRIGOUTSTATUS = 0x134,
+    CTICHINSTATUS = 0x138,
+    CTICHOUTSTATUS = 0x13C,
+    CTIGATE = 0x140,
+    CTIDEVID2 = 0xFC0,
+    CTIDEVID1 = 0xFC4,
+    CTIDEVID = 0xFC8,
+}
+
+// PMUのexternal debug interface
+enum PmuOffset {
+    PMEVCNTRn = 0x000,
+    PMCCNTR = 0x0F8,
+    PMEVTYPERn = 0x400,
+    PMCCFILTR = 0x47C,
+    PMCNTENSET = 0xC00,
+    PMCNTENCLR = 0xC20,
+    PMOVSCLR = 0xC80,
+    PMCFGR = 0xE00,
+    PMCR = 0xE04,
+}
+const PMEVCNTR_STRIDE: u64 = 0x08;
+const PMEVTYPER_STRIDE: u64 = 0x04;
+// event counterは最大31個。PMCNTENSET/PMOVSCLRのbit 31はcycle counter
+pub const PMU_EVENT_COUNTERS_MAX: usize = 31;
+const PMU_CYCLE_COUNTER_BIT: u32 = 1 << 31;
+
+bitfield! {
+    pub struct EDSCR(u32);
+    impl Debug;
+    pub TFO, _: 31, 31;
+    pub RXfull, _: 30, 30;
+    pub TXfull, _: 29, 29;
+    pub ITO, _: 28, 28;
+    pub RXO, _: 27, 27;
+    pub TXU, _: 26, 26;
+    pub PipeAdv, _: 25, 25;
+    pub ITE, _: 24, 24;
+    pub INTdis, _: 23, 22;
+    pub TDA, _: 21, 21;
+    pub MA, _: 20, 20;
+    pub SC2, _: 19, 19;
+    pub NS, _: 18, 18;
+    reserved0, _: 17,17;
+    pub SDD, _: 16, 16;
+    reserved1, _: 15,15;
+    pub HDE, set_hde: 14, 14;
+    pub RW, _: 13, 10;
+    pub EL, _: 9, 8;
+    pub A, _: 7, 7;
+    pub ERR, _: 6, 6;
+    pub STATUS, _: 5, 0;
+}
+
+bitfield! {
+    pub struct DBGBCR(u32);
+    impl Debug;
+    pub BT, set_BT: 23, 20;
+    pub LBN, set_LBN: 19, 16;
+    pub SSC, set_SSC: 15, 14;
+    pub HMC, set_HMC: 13, 13;
+    pub BAS, set_BAS: 8, 5;
+    pub PMC, set_PMC: 2, 1;
+    pub E, set_E: 0, 0;
+}
+
+bitfield! {
+    pub struct DBGWCR(u32);
+    impl Debug;
+    pub MASK, set_MASK: 28, 24;
+    pub WT, set_WT: 20, 20;
+    pub LBN, set_LBN: 19, 16;
+    pub SSC, set_SSC: 15, 14;
+    pub HMC, set_HMC: 13, 13;
+    pub BAS, set_BAS: 12, 5;
+    pub LSC, set_LSC: 4, 3;
+    pub PAC, set_PAC: 2, 1;
+    pub E, set_E: 0, 0;
+}
+
+bitfield! {
+    pub struct PMCR(u32);
+    impl Debug;
+    pub IMP, _: 31, 24;
+    pub IDCODE, _: 23, 16;
+    pub N, _: 15, 11;
+    pub LC, set_LC: 6, 6;
+    pub DP, set_DP: 5, 5;
+    pub X, set_X: 4, 4;
+    pub D, set_D: 3, 3;
+    pub C, set_C: 2, 2;
+    pub P, set_P: 1, 1;
+    pub E, set_E: 0, 0;
+}
+
+bitfield! {
+    pub struct EDDFR(u32);
+    impl Debug;
+    pub CTX_CMPs, _: 31, 28;
+    pub WRPs, _: 23, 20;
+    pub BRPs, _: 15, 12;
+    pub PMUVer, _: 11, 8;
+    pub TraceVer, _: 7, 4;
+}
+
+// DBGWCR.BASとDBGWVRに書く値を返す
+fn watchpoint_bas(address: u64, length: u64) -> Result<(u64, u32), DapError> {
+    let offset = address % WATCHPOINT_GRANULE;
+    if length == 0 || offset + length > WATCHPOINT_GRANULE {
+        return Err(InterfaceError::OutOfRange.into());
+    }
+    let bas = ((1 << length) - 1) << offset;
+    Ok((address - offset, bas))
+}
+
+bitfield! {
+    pub struct EDECR(u32);
+    impl Debug;
+    reserved, _: 31, 3;
+    pub SS, set_SS: 2, 2;
+    pub RCE, set_RCE: 1, 1;
+    pub OSUCE, set_OSUCE: 0, 0;
+}
+
+// bit nがELnに対応する。bit 0はreserved
+bitfield! {
+    pub struct EDECCR(u32);
+    impl Debug;
+    reserved, _: 31, 8;
+    pub NSE, set_NSE: 7, 4;
+    pub SE, set_SE: 3, 0;
+}
+
+// EDSCR.STATUSでわかるdebug stateに入った理由
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HaltReason {
+    // 以下2つはNon-debug state
+    Restarting,
+    Running,
+    Breakpoint,
+    ExternalDebugRequest,
+    HaltStepNormal,
+    HaltStepExclusive,
+    HaltStepNoSyndrome,
+    OSUnlockCatch,
+    ResetCatch,
+    WatchpointMatch,
+    HltInstruction,
+    SoftwareAccess,
+    ExceptionCatch,
+    Unknown(u8),
+}
+
+impl HaltReason {
+    pub fn from_status(status: u8) -> Self {
+        match status {
+            0b000001 => HaltReason::Restarting,
+            0b000010 => HaltReason::Running,
+            0b000111 => HaltReason::Breakpoint,
+            0b010011 => HaltReason::ExternalDebugRequest,
+            0b011011 => HaltReason::HaltStepNormal,
+            0b011111 => HaltReason::HaltStepExclusive,
+            0b100011 => HaltReason::OSUnlockCatch,
+            0b100111 => HaltReason::ResetCatch,
+            0b101011 => HaltReason::WatchpointMatch,
+            0b101111 => HaltReason::HltInstruction,
+            0b110011 => HaltReason::SoftwareAccess,
+            0b110111 => HaltReason::ExceptionCatch,
+            0b111011 => HaltReason::HaltStepNoSyndrome,
+            x => HaltReason::Unknown(x),
+        }
+    }
+
+    pub fn is_halted(&self) -> bool {
+        !matches!(self, HaltReason::Restarting | HaltReason::Running)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HaltStatus {
+    pub reason: HaltReason,
+    pub el: u8,
+    // EDSCR.NS
+    pub non_secure: bool,
+    // EDSCR.SDD。SecureのEL0/1/3はdebugできない
+    pub secure_debug_disabled: bool,
+}
+
+// EDPCSRで取った走行中のcoreのPC
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PcSample {
+    pub pc: u64,
+    // EDCIDSR。sample時のCONTEXTIDR
+    pub context_id: u32,
+    // EDVIDSR.VMID
+    pub vmid: u16,
+    // EDVIDSR.NS
+    pub non_secure: bool,
+}
+
+// EDPCSRloがこの値ならsampleが取れていない(halt中やdebug不許可)
+const EDPCSR_INVALID: u32 = 0xffff_ffff;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExecutionState {
+    AArch64,
+    AArch32,
+}
+
+impl EDSCR {
+    // RWのbit nがELnの状態。debug state中だけ有効
+    pub fn execution_state(&self) -> ExecutionState {
+        if (self.RW() >> self.EL()) & 1 == 1 {
+            ExecutionState::AArch64
+        } else {
+            ExecutionState::AArch32
+        }
+    }
+}
+
+impl From<&EDSCR> for HaltStatus {
+    fn from(edscr: &EDSCR) -> Self {
+        HaltStatus {
+            reason: HaltReason::from_status(edscr.STATUS() as u8),
+            el: edscr.EL() as u8,
+            non_secure: edscr.NS() == 1,
+            secure_debug_disabled: edscr.SDD() == 1,
+        }
+    }
+}
+
+impl fmt::Display for EDSCR {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        RegFmt::new(f, "EDSCR")
+            .field(
+                "STATUS",
+                format_args!(
+                    "{:#08b}({:?})",
+                    self.STATUS(),
+                    HaltReason::from_status(self.STATUS() as u8)
+                ),
+            )
+            .field("EL", format_args!("{}", self.EL()))
+            .flag("NS", self.NS())
+            .flag("SDD", self.SDD())
+            .field("RW", format_args!("{:#06b}", self.RW()))
+            .flag("HDE", self.HDE())
+            .flag("ITE", self.ITE())
+            .flag("ERR", self.ERR())
+            .flag("A", self.A())
+            .flag("TFO", self.TFO())
+            .flag("RXfull", self.RXfull())
+            .flag("TXfull", self.TXfull())
+            .flag("ITO", self.ITO())
+            .flag("RXO", self.RXO())
+            .flag("TXU", self.TXU())
+            .flag("PipeAdv", self.PipeAdv())
+            .nonzero(
+                "INTdis",
+                self.INTdis(),
+                format_args!("{:#04b}", self.INTdis()),
+            )
+            .flag("TDA", self.TDA())
+            .flag("MA", self.MA())
+            .flag("SC2", self.SC2())
+            .finish()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for EDSCR {
+    fn format(&self, f: defmt::Formatter) {
+        RegDefmt::new(f, "EDSCR")
+            .field("STATUS", |f| {
+                defmt::write!(
+                    f,
+                    "{=u32:#08b}({})",
+                    self.STATUS(),
+                    HaltReason::from_status(self.STATUS() as u8)
+                )
+            })
+            .field("EL", |f| defmt::write!(f, "{=u32}", self.EL()))
+            .flag("NS", self.NS())
+            .flag("SDD", self.SDD())
+            .field("RW", |f| defmt::write!(f, "{=u32:#06b}", self.RW()))
+            .flag("HDE", self.HDE())
+            .flag("ITE", self.ITE())
+            .flag("ERR", self.ERR())
+            .flag("A", self.A())
+            .flag("TFO", self.TFO())
+            .flag("RXfull", self.RXfull())
+            .flag("TXfull", self.TXfull())
+            .flag("ITO", self.ITO())
+            .flag("RXO", self.RXO())
+            .flag("TXU", self.TXU())
+            .flag("PipeAdv", self.PipeAdv())
+            .nonzero("INTdis", self.INTdis(), |f| {
+                defmt::write!(f, "{=u32:#04b}", self.INTdis())
+            })
+            .flag("TDA", self.TDA())
+            .flag("MA", self.MA())
+            .flag("SC2", self.SC2())
+            .finish()
+    }
+}
+
+impl fmt::Display for EDECCR {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        RegFmt::new(f, "EDECCR")
+            .nonzero("NSE", self.NSE(), format_args!("{:#06b}", self.NSE()))
+            .nonzero("SE", self.SE(), format_args!("{:#06b}", self.SE()))
+            .finish()
+    }
+}
+
+impl fmt::Display for EDECR {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        RegFmt::new(f, "EDECR")
+            .flag("SS", self.SS())
+            .flag("RCE", self.RCE())
+            .flag("OSUCE", self.OSUCE())
+            .finish()
+    }
+}
+
+bitfield! {
+    pub struct EDESR(u32);
+    impl Debug;
+    reserved, _: 31, 3;
+    pub SS, set_SS: 2, 2;
+    pub RC, set_RC: 1, 1;
+    pub OSUC, set_OSUC: 0, 0;
+}
+
+bitfield! {
+    pub struct EDRCR(u32);
+    impl Debug;
+    reserved, _: 31,5;
+    pub CBRRQ, set_CBRRQ: 4, 4;
+    pub CSPA, _: 3, 3;
+    pub CSE, set_CSE: 2, 2;
+    reserved0, _: 1,0;
+    pub SDD, _: 16, 16;
+}
+
+bitfield! {
+    pub struct EDPRSR(u32);
+    impl Debug;
+    reserved, _: 31,12;
+    pub SDR, _: 11, 11;
+    pub SPMAD, _: 10, 10;
+    pub EPMAD, _: 9, 9;
+    pub SDAD,  _: 8, 8;
+    pub EDAD,  _: 7, 7;
+    pub DLK,  _: 6, 6;
+    pub OSLK,  _: 5, 5;
+    pub HALTED,  _: 4, 4;
+    pub SR,  _: 3, 3;
+    pub R,  _: 2, 2;
+    pub SPD,  _: 1, 1;
+    pub PU, _: 0, 0;
+}
+
+bitfield! {
+    pub struct EDPRCR(u32);
+    impl Debug;
+    reserved, _: 31, 4;
+    pub COREPURQ, set_COREPURQ: 3, 3;
+    pub CWRR, set_CWRR: 2, 2;
+    reserved0, _: 1, 1;
+    pub CORENPDRQ, set_CORENPDRQ: 0, 0;
+}
+
+impl fmt::Display for EDESR {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        RegFmt::new(f, "EDESR")
+            .flag("SS", self.SS())
+            .flag("RC", self.RC())
+            .flag("OSUC", self.OSUC())
+            .finish()
+    }
+}
+
+impl fmt::Display for EDRCR {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        RegFmt::new(f, "EDRCR")
+            .flag("CBRRQ", self.CBRRQ())
+            .flag("CSPA", self.CSPA())
+            .flag("CSE", self.CSE())
+            .finish()
+    }
+}
+
+impl fmt::Display for EDPRSR {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        RegFmt::new(f, "EDPRSR")
+            .flag("PU", self.PU())
+            .flag("SPD", self.SPD())
+            .flag("R", self.R())
+            .flag("SR", self.SR())
+            .flag("HALTED", self.HALTED())
+            .flag("OSLK", self.OSLK())
+            .flag("DLK", self.DLK())
+            .flag("EDAD", self.EDAD())
+            .flag("SDAD", self.SDAD())
+            .flag("EPMAD", self.EPMAD())
+            .flag("SPMAD", self.SPMAD())
+            .flag("SDR", self.SDR())
+            .finish()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for EDPRSR {
+    fn format(&self, f: defmt::Formatter) {
+        RegDefmt::new(f, "EDPRSR")
+            .flag("PU", self.PU())
+            .flag("SPD", self.SPD())
+            .flag("R", self.R())
+            .flag("SR", self.SR())
+            .flag("HALTED", self.HALTED())
+            .flag("OSLK", self.OSLK())
+            .flag("DLK", self.DLK())
+            .flag("EDAD", self.EDAD())
+            .flag("SDAD", self.SDAD())
+            .flag("EPMAD", self.EPMAD())
+            .flag("SPMAD", self.SPMAD())
+            .flag("SDR", self.SDR())
+            .finish()
+    }
+}
+
+// 各fieldは0b00: 未実装, 0b10: 無効, 0b11: 有効
+bitfield! {
+    #[derive(Clone, Copy)]
+    pub struct DBGAUTHSTATUS(u32);
+    impl Debug;
+    pub SNID, _: 7, 6;
+    pub SID, _: 5, 4;
+    pub NSNID, _: 3, 2;
+    pub NSID, _: 1, 0;
+}
+
+impl fmt::Display for DBGAUTHSTATUS {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        RegFmt::new(f, "DBGAUTHSTATUS")
+            .field("SNID", format_args!("{:#04b}", self.SNID()))
+            .field("SID", format_args!("{:#04b}", self.SID()))
+            .field("NSNID", format_args!("{:#04b}", self.NSNID()))
+            .field("NSID", format_args!("{:#04b}", self.NSID()))
+            .finish()
+    }
+}
+
+// DBGAUTHSTATUSとEDPRSRから読んだdebugの許可状態
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AuthStatus {
+    pub secure_implemented: bool,
+    pub secure_invasive_enabled: bool,
+    pub secure_noninvasive_enabled: bool,
+    pub nonsecure_invasive_enabled: bool,
+    pub nonsecure_noninvasive_enabled: bool,
+    // EDPRSR.EDAD/SDAD: 外部からのdebug registerへのaccessが禁止されている
+    pub external_debug_access_disabled: bool,
+    pub secure_debug_access_disabled: bool,
+    // EDPRSR.EPMAD/SPMAD: PMUのregisterへのaccessが禁止されている
+    pub external_pmu_access_disabled: bool,
+    pub secure_pmu_access_disabled: bool,
+}
+
+impl AuthStatus {
+    pub fn new(auth: DBGAUTHSTATUS, edprsr: &EDPRSR) -> Self {
+        AuthStatus {
+            secure_implemented: auth.SID() != 0,
+            secure_invasive_enabled: auth.SID() == 0b11,
+            secure_noninvasive_enabled: auth.SNID() == 0b11,
+            nonsecure_invasive_enabled: auth.NSID() == 0b11,
+            nonsecure_noninvasive_enabled: auth.NSNID() == 0b11,
+            external_debug_access_disabled: edprsr.EDAD() == 1,
+            secure_debug_access_disabled: edprsr.SDAD() == 1,
+            external_pmu_access_disabled: edprsr.EPMAD() == 1,
+            secure_pmu_access_disabled: edprsr.SPMAD() == 1,
+        }
+    }
+
+    // 少なくともnon-secure stateではhaltできる
+    pub fn can_halt(&self) -> bool {
+        !self.external_debug_access_disabled && self.nonsecure_invasive_enabled
+    }
+}
+
+impl fmt::Display for AuthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let enabled = |x: bool| if x { "enabled" } else { "disabled" };
+        write!(
+            f,
+            "non-secure invasive {}, non-secure non-invasive {}",
+            enabled(self.nonsecure_invasive_enabled),
+            enabled(self.nonsecure_noninvasive_enabled)
+        )?;
+        if self.secure_implemented {
+            write!(
+                f,
+                ", secure invasive {}, secure non-invasive {}",
+                enabled(self.secure_invasive_enabled),
+                enabled(self.secure_noninvasive_enabled)
+            )?;
+        }
+        if self.external_debug_access_disabled {
+            write!(f, ", external debug access disabled")?;
+        } else if self.secure_debug_access_disabled {
+            write!(f, ", secure debug access disabled")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for EDPRCR {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        RegFmt::new(f, "EDPRCR")
+            .flag("COREPURQ", self.COREPURQ())
+            .flag("CWRR", self.CWRR())
+            .flag("CORENPDRQ", self.CORENPDRQ())
+            .finish()
+    }
+}
+
+impl fmt::Display for DBGBCR {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        RegFmt::new(f, "DBGBCR")
+            .flag("E", self.E())
+            .nonzero("BT", self.BT(), format_args!("{:#06b}", self.BT()))
+            .nonzero("LBN", self.LBN(), format_args!("{}", self.LBN()))
+            .nonzero("SSC", self.SSC(), format_args!("{:#04b}", self.SSC()))
+            .flag("HMC", self.HMC())
+            .field("BAS", format_args!("{:#06b}", self.BAS()))
+            .field("PMC", format_args!("{:#04b}", self.PMC()))
+            .finish()
+    }
+}
+
+impl fmt::Display for DBGWCR {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        RegFmt::new(f, "DBGWCR")
+            .flag("E", self.E())
+            .nonzero("MASK", self.MASK(), format_args!("{}", self.MASK()))
+            .flag("WT", self.WT())
+            .nonzero("LBN", self.LBN(), format_args!("{}", self.LBN()))
+            .nonzero("SSC", self.SSC(), format_args!("{:#04b}", self.SSC()))
+            .flag("HMC", self.HMC())
+            .field("BAS", format_args!("{:#010b}", self.BAS()))
+            .field("LSC", format_args!("{:#04b}", self.LSC()))
+            .field("PAC", format_args!("{:#04b}", self.PAC()))
+            .finish()
+    }
+}
+
+impl fmt::Display for PMCR {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        RegFmt::new(f, "PMCR")
+            .field("IMP", format_args!("{:#04x}", self.IMP()))
+            .field("N", format_args!("{}", self.N()))
+            .flag("LC", self.LC())
+            .flag("DP", self.DP())
+            .flag("X", self.X())
+            .flag("D", self.D())
+            .flag("E", self.E())
+            .finish()
+    }
+}
+
+impl fmt::Display for EDDFR {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        RegFmt::new(f, "EDDFR")
+            .field("CTX_CMPs", format_args!("{}", self.CTX_CMPs()))
+            .field("WRPs", format_args!("{}", self.WRPs()))
+            .field("BRPs", format_args!("{}", self.BRPs()))
+            .field("PMUVer", format_args!("{}", self.PMUVer()))
+            .field("TraceVer", format_args!("{}", self.TraceVer()))
+            .finish()
+    }
+}
+
+pub struct Cti<T> {
+    pub dap: DapHandle<T>,
+    pub baseaddr: u64,
+}
+
+impl<T: DebugPort + MemoryAccessPort> Cti<T> {
+    fn init(&mut self) {}
+
+    pub fn enable(&mut self) -> Result<(), DapError> {
+        self.register_u32_write(CtiOffset::CTICONTROL as u64, 1)
+    }
+
+    pub fn disable(&mut self) -> Result<(), DapError> {
+        self.register_u32_write(CtiOffset::CTICONTROL as u64, 0)
+    }
+
+    // 読んでから書くまでの間に他のthreadがCTIを書き換えないよう、1つのwindowで行う
+    fn modify_bit(&mut self, offset: u64, bit: u8, set: bool) -> Result<(), DapError> {
+        // TODO: check bit < 32
+        self.with_window(0, |window| {
+            window.modify(
+                offset,
+                |x| {
+                    if set {
+                        x | (1 << bit)
+                    } else {
+                        x & !(1 << bit)
+                    }
+                },
+            )
+        })?;
+        Ok(())
+    }
+
+    pub fn channel_gate_enable(&mut self, channel: u8) -> Result<(), DapError> {
+        self.modify_bit(CtiOffset::CTIGATE as u64, channel, true)
+    }
+    pub fn channel_gate_disable(&mut self, channel: u8) -> Result<(), DapError> {
+        self.modify_bit(CtiOffset::CTIGATE as u64, channel, false)
+    }
+    pub fn input_trigger_enable(&mut self, trigger: u8, channel: u8) -> Result<(), DapError> {
+        let offset = CtiOffset::CTIINENn as u64 + (trigger as u64) * 0x04;
+        self.modify_bit(offset, channel, true)
+    }
+    pub fn input_trigger_disable(&mut self, trigger: u8, channel: u8) -> Result<(), DapError> {
+        let offset = CtiOffset::CTIINENn as u64 + (trigger as u64) * 0x04;
+        self.modify_bit(offset, channel, false)
+    }
+    pub fn output_trigger_enable(&mut self, trigger: u8, channel: u8) -> Result<(), DapError> {
+        let offset = CtiOffset::CTIOUTENn as u64 + (trigger as u64) * 0x04;
+        self.modify_bit(offset, channel, true)
+    }
+    pub fn output_trigger_disable(&mut self, trigger: u8, channel: u8) -> Result<(), DapError> {
+        let offset = CtiOffset::CTIOUTENn as u64 + (trigger as u64) * 0x04;
+        self.modify_bit(offset, channel, false)
+    }
+    pub fn output_trigger_ack_deactivate(&mut self, trigger: u8) -> Result<(), DapError> {
+        self.register_u32_write(CtiOffset::CTIINTACK as u64, 1 << trigger)
+    }
+    pub fn input_trigger_status(&mut self, trigger: u8) -> Result<bool, DapError> {
+        let status = self.register_u32_read(CtiOffset::CTITRIGINSTATUS as u64)?;
+        Ok((status & (1 << trigger)) != 0)
+    }
+    pub fn output_trigger_status(&mut self, trigger: u8) -> Result<bool, DapError> {
+        let status = self.register_u32_read(CtiOffset::CTITRIGOUTSTATUS as u64)?;
+        Ok((status & (1 << trigger)) != 0)
+    }
+
+    pub fn generate_pulse(&mut self, channel: u32) -> Result<(), DapError> {
+        self.register_u32_write(CtiOffset::CTIAPPPULSE as u64, 1 << channel)
+    }
+
+    // triggerがactiveになる/落ちるまで待つ
+    fn wait_output_trigger(&mut self, trigger: u8, active: bool) -> Result<(), DapError> {
+        for _ in 0..POLL_MAX {
+            if self.output_trigger_status(trigger)? == active {
+                return Ok(());
+            }
+        }
+        warn!(
+            "CTI {:#x}: output trigger {} did not {}",
+            self.baseaddr,
+            trigger,
+            if active { "assert" } else { "deassert" }
+        );
+        Err(InterfaceError::Timeout.into())
+    }
+
+    // Arm ARMの割り当て: output trigger 0(debug request)にchannel 0、
+    // output trigger 1(restart request)にchannel 1を繋ぎ、CTMへは出さない
+    pub fn setup_default_routing(&mut self) -> Result<(), DapError> {
+        self.enable()?;
+        let outen = CtiOffset::CTIOUTENn as u64;
+        self.register_u32_write(
+            outen + CTI_TRIGGER_HALT as u64 * 0x04,
+            1 << CTI_CHANNEL_HALT,
+        )?;
+        self.register_u32_write(
+            outen + CTI_TRIGGER_RESTART as u64 * 0x04,
+            1 << CTI_CHANNEL_RESTART,
+        )?;
+        let mask = !((1 << CTI_CHANNEL_HALT) | (1 << CTI_CHANNEL_RESTART));
+        self.with_window(0, |window| {
+            window.modify(CtiOffset::CTIGATE as u64, |x| x & mask)
+        })?;
+        Ok(())
+    }
+
+    // debug requestはCTIINTACKするまで出続ける
+    pub fn halt_core(&mut self) -> Result<(), DapError> {
+        self.enable()?;
+        self.channel_gate_disable(CTI_CHANNEL_HALT)?;
+        self.output_trigger_enable(CTI_TRIGGER_HALT, CTI_CHANNEL_HALT)?;
+        self.generate_pulse(CTI_CHANNEL_HALT as u32)
+    }
+
+    pub fn restart_core(&mut self) -> Result<(), DapError> {
+        // haltのtriggerを落としてからrestartを送る
+        self.output_trigger_ack_deactivate(CTI_TRIGGER_HALT)?;
+        self.wait_output_trigger(CTI_TRIGGER_HALT, false)?;
+        self.enable()?;
+        self.channel_gate_disable(CTI_CHANNEL_RESTART)?;
+        self.output_trigger_disable(CTI_TRIGGER_HALT, CTI_CHANNEL_HALT)?;
+        self.output_trigger_enable(CTI_TRIGGER_RESTART, CTI_CHANNEL_RESTART)?;
+        self.generate_pulse(CTI_CHANNEL_RESTART as u32)
+    }
+}
+
+impl<T: DebugPort + MemoryAccessPort> AArch64Register<T> for Cti<T> {
+    fn baseaddr(&self) -> u64 {
+        self.baseaddr
+    }
+    fn dap_lock(&self) -> DapGuard<'_, T> {
+        self.dap.lock()
+    }
+}
+
+// read_countersで1度に読んだcounterの値
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PmuSnapshot {
+    pub cycles: u64,
+    pub events: [u32; PMU_EVENT_COUNTERS_MAX],
+    // 実装されているevent counterの数(PMCR.N)
+    pub event_count: usize,
+    // 読んだ時点のPMOVSCLR。前回のread_countersから一周したcounterのbitが立つ
+    pub overflow: u32,
+}
+
+impl PmuSnapshot {
+    pub fn events(&self) -> &[u32] {
+        &self.events[..self.event_count]
+    }
+
+    pub fn cycle_overflowed(&self) -> bool {
+        self.overflow & PMU_CYCLE_COUNTER_BIT != 0
+    }
+
+    pub fn event_overflowed(&self, counter: usize) -> bool {
+        counter < self.event_count && self.overflow & (1 << counter) != 0
+    }
+}
+
+pub struct Pmu<T> {
+    pub dap: DapHandle<T>,
+    pub baseaddr: u64,
+}
+
+impl<T: DebugPort + MemoryAccessPort> Pmu<T> {
+    fn counter_offset(base: PmuOffset, stride: u64, counter: usize) -> Result<u64, DapError> {
+        if counter >= PMU_EVENT_COUNTERS_MAX {
+            return Err(InterfaceError::OutOfRange.into());
+        }
+        Ok(base as u64 + counter as u64 * stride)
+    }
+
+    pub fn pmcr_read(&mut self) -> Result<PMCR, DapError> {
+        Ok(PMCR(self.register_u32_read(PmuOffset::PMCR as u64)?))
+    }
+
+    pub fn pmcr_write(&mut self, pmcr: PMCR) -> Result<(), DapError> {
+        self.register_u32_write(PmuOffset::PMCR as u64, pmcr.0)
+    }
+
+    pub fn event_counters(&mut self) -> Result<usize, DapError> {
+        Ok(self.pmcr_read()?.N() as usize)
+    }
+
+    pub fn pmcfgr_read(&mut self) -> Result<u32, DapError> {
+        self.register_u32_read(PmuOffset::PMCFGR as u64)
+    }
+
+    // 1を書いたbitのcounterだけが有効/無効になる
+    pub fn pmcntenset_read(&mut self) -> Result<u32, DapError> {
+        self.register_u32_read(PmuOffset::PMCNTENSET as u64)
+    }
+
+    pub fn pmcntenset_write(&mut self, mask: u32) -> Result<(), DapError> {
+        self.register_u32_write(PmuOffset::PMCNTENSET as u64, mask)
+    }
+
+    pub fn pmcntenclr_write(&mut self, mask: u32) -> Result<(), DapError> {
+        self.register_u32_write(PmuOffset::PMCNTENCLR as u64, mask)
+    }
+
+    // 1を書いたbitのoverflow flagが落ちる
+    pub fn pmovsclr_read(&mut self) -> Result<u32, DapError> {
+        self.register_u32_read(PmuOffset::PMOVSCLR as u64)
+    }
+
+    pub fn pmovsclr_write(&mut self, mask: u32) -> Result<(), DapError> {
+        self.register_u32_write(PmuOffset::PMOVSCLR as u64, mask)
+    }
+
+    pub fn pmccntr_read(&mut self) -> Result<u64, DapError> {
+        self.register_u64_read(PmuOffset::PMCCNTR as u64)
+    }
+
+    pub fn pmccntr_write(&mut self, value: u64) -> Result<(), DapError> {
+        self.register_u64_write(PmuOffset::PMCCNTR as u64, value)
+    }
+
+    pub fn pmccfiltr_read(&mut self) -> Result<u32, DapError> {
+        self.register_u32_read(PmuOffset::PMCCFILTR as u64)
+    }
+
+    pub fn pmccfiltr_write(&mut self, value: u32) -> Result<(), DapError> {
+        self.register_u32_write(PmuOffset::PMCCFILTR as u64, value)
+    }
+
+    pub fn pmevcntr_read(&mut self, counter: usize) -> Result<u32, DapError> {
+        let offset = Self::counter_offset(PmuOffset::PMEVCNTRn, PMEVCNTR_STRIDE, counter)?;
+        self.register_u32_read(offset)
+    }
+
+    pub fn pmevcntr_write(&mut self, counter: usize, value: u32) -> Result<(), DapError> {
+        let offset = Self::counter_offset(PmuOffset::PMEVCNTRn, PMEVCNTR_STRIDE, counter)?;
+        self.register_u32_write(offset, value)
+    }
+
+    pub fn pmevtyper_read(&mut self, counter: usize) -> Result<u32, DapError> {
+        let offset = Self::counter_offset(PmuOffset::PMEVTYPERn, PMEVTYPER_STRIDE, counter)?;
+        self.register_u32_read(offset)
+    }
+
+    pub fn pmevtyper_write(&mut self, counter: usize, value: u32) -> Result<(), DapError> {
+        let offset = Self::counter_offset(PmuOffset::PMEVTYPERn, PMEVTYPER_STRIDE, counter)?;
+        self.register_u32_write(offset, value)
+    }
+
+    // PMCR.Eは全counter共通のenable
+    fn enable_pmu(&mut self, long_cycle: bool) -> Result<(), DapError> {
+        self.with_window(0, |window| {
+            window.modify(PmuOffset::PMCR as u64, |x| {
+                let mut pmcr = PMCR(x);
+                pmcr.set_E(1);
+                if long_cycle {
+                    pmcr.set_LC(1);
+                }
+                pmcr.0
+            })
+        })?;
+        Ok(())
+    }
+
+    // cycle counterは64bitで数え、64bitで一周した時にoverflowを立てる
+    pub fn enable_cycle_counter(&mut self) -> Result<(), DapError> {
+        self.enable_pmu(true)?;
+        self.pmcntenset_write(PMU_CYCLE_COUNTER_BIT)
+    }
+
+    // filterは0のまま(全ELで数える)にし、event_numberだけを設定する
+    pub fn configure_event(&mut self, counter: usize, event_number: u16) -> Result<(), DapError> {
+        if counter >= self.event_counters()? {
+            return Err(InterfaceError::OutOfRange.into());
+        }
+        self.pmevtyper_write(counter, event_number as u32)?;
+        self.pmcntenset_write(1 << counter)?;
+        self.enable_pmu(false)
+    }
+
+    // 全counterを1つのwindowで読み、読んだoverflow flagを落とす
+    pub fn read_counters(&mut self) -> Result<PmuSnapshot, DapError> {
+        self.with_window(0, |window| {
+            let event_count = PMCR(window.read(PmuOffset::PMCR as u64)?).N() as usize;
+            let event_count = event_count.min(PMU_EVENT_COUNTERS_MAX);
+            let cycles_offset = PmuOffset::PMCCNTR as u64;
+            let low = window.read(cycles_offset)?;
+            let high = window.read(cycles_offset + 4)?;
+            let mut events = [0; PMU_EVENT_COUNTERS_MAX];
+            for (n, event) in events.iter_mut().enumerate().take(event_count) {
+                *event = window.read(PmuOffset::PMEVCNTRn as u64 + n as u64 * PMEVCNTR_STRIDE)?;
+            }
+            let overflow = window.read(PmuOffset::PMOVSCLR as u64)?;
+            if overflow != 0 {
+                window.write(PmuOffset::PMOVSCLR as u64, overflow)?;
+            }
+            Ok(PmuSnapshot {
+                cycles: (high as u64) << 32 | low as u64,
+                events,
+                event_count,
+                overflow,
+            })
+        })
+    }
+}
+
+impl<T: DebugPort + MemoryAccessPort> AArch64Register<T> for Pmu<T> {
+    fn baseaddr(&self) -> u64 {
+        self.baseaddr
+    }
+    fn dap_lock(&self) -> DapGuard<'_, T> {
+        self.dap.lock()
+    }
+}
+
+// with_windowの中でDAPのlockを持ち続け、BDの16byteの窓を通してregisterに触る
+pub struct RegisterWindow<'a, T> {
+    dap: DapGuard<'a, T>,
+    baseaddr: u64,
+    // TARに書いてある窓の先頭。まだ書いていなければNone
+    bd_base: Option<u64>,
+}
+
+impl<'a, T: DebugPort + MemoryAccessPort> RegisterWindow<'a, T> {
+    fn access(&mut self, offset: u64, data: u32, read: bool) -> Result<u32, DapError> {
+        let address = self.baseaddr + offset;
+        let bd_base = address & !0x0f;
+        if self.bd_base != Some(bd_base) {
+            // 途中で失敗したらTARの値はわからない
+            self.bd_base = None;
+            self.dap.memap_tar_u64_write(bd_base)?;
+            self.bd_base = Some(bd_base);
+        }
+        let (_, result) = match address & 0x0c {
+            0x0 => self.dap.memap_bd0(data, read)?,
+            0x4 => self.dap.memap_bd1(data, read)?,
+            0x8 => self.dap.memap_bd2(data, read)?,
+            _ => self.dap.memap_bd3(data, read)?,
+        };
+        Ok(result)
+    }
+
+    pub fn read(&mut self, offset: u64) -> Result<u32, DapError> {
+        self.access(offset, 0, true)
+    }
+
+    pub fn write(&mut self, offset: u64, data: u32) -> Result<(), DapError> {
+        self.access(offset, data, false)?;
+        Ok(())
+    }
+
+    // lockを離さずに読んで書くので、他のthreadに割り込まれない。書いた値を返す
+    pub fn modify(&mut self, offset: u64, f: impl FnOnce(u32) -> u32) -> Result<u32, DapError> {
+        let value = f(self.read(offset)?);
+        self.write(offset, value)?;
+        Ok(value)
+    }
+}
+
+pub trait AArch64Register<T: DebugPort + MemoryAccessPort> {
+    fn baseaddr(&self) -> u64;
+    fn dap_lock(&self) -> DapGuard<'_, T>;
+
+    // lockを取ったままfの中のaccessをまとめて行う
+    // offsetはbaseaddr + base_offsetからの相対で、TARは窓が変わる時だけ書く
+    fn with_window<R>(
+        &mut self,
+        base_offset: u64,
+        f: impl FnOnce(&mut RegisterWindow<T>) -> R,
+    ) -> R {
+        let baseaddr = self.baseaddr() + base_offset;
+        let mut window = RegisterWindow {
+            dap: self.dap_lock(),
+            baseaddr,
+            bd_base: None,
+        };
+        f(&mut window)
+    }
+
+    fn register_u32(&mut self, offset: u64, data: u32, read: bool) -> Result<u32, DapError> {
+        self.with_window(0, |window| window.access(offset, data, read))
+    }
+    fn register_u32_read(&mut self, offset: u64) -> Result<u32, DapError> {
+        self.register_u32(offset, 0, true)
+    }
+    fn register_u32_write(&mut self, offset: u64, data: u32) -> Result<(), DapError> {
+        self.register_u32(offset, data, false)?;
+        Ok(())
+    }
+
+    // offsetが0xCで終わる場合、上位wordは次のBDの窓になるのでTARを書き直す
+    fn register_u64(&mut self, offset: u64, data: u64, read: bool) -> Result<u64, DapError> {
+        self.with_window(0, |window| {
+            let low = window.access(offset, (data & 0xffff_ffff) as u32, read)?;
+            let high = window.access(offset + 4, (data >> 32) as u32, read)?;
+            Ok(((high as u64) << 32) | (low as u64))
+        })
+    }
+
+    fn register_u64_read(&mut self, offset: u64) -> Result<u64, DapError> {
+        self.register_u64(offset, 0, true)
+    }
+    fn register_u64_write(&mut self, offset: u64, data: u64) -> Result<(), DapError> {
+        self.register_u64(offset, data, false)?;
+        Ok(())
+    }
+}
+
+// pollingをいつ諦めるか。no_stdでは回数だけで指定する
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PollBudget {
+    Iterations(usize),
+    #[cfg(feature = "std")]
+    Duration(std::time::Duration),
+}
+
+impl Default for PollBudget {
+    fn default() -> Self {
+        PollBudget::Iterations(POLL_MAX)
+    }
+}
+
+pub struct A64Target<T> {
+    pub dap: DapHandle<T>,
+    pub baseaddr: u64,
+    // prepare_debugで解除する前のOS Lock。detachで元に戻す
+    os_locked: Option<bool>,
+    // set_breakpointで使ったindexのbit。detachで消す
+    breakpoints: u32,
+}
+
+// halt中のcoreのregister一式。pcはDLR_EL0、pstateはDSPSR_EL0の値
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "std", derive(serde::Serialize, serde::Deserialize))]
+pub struct CpuContext {
+    pub x: [u64; 31],
+    pub sp: u64,
+    pub pc: u64,
+    pub pstate: u32,
+}
+
+impl<T: DebugPort + MemoryAccessPort> A64Target<T> {
+    pub fn new(dap: DapHandle<T>, baseaddr: u64) -> Self {
+        A64Target {
+            dap,
+            baseaddr,
+            os_locked: None,
+            breakpoints: 0,
+        }
+    }
+
+    // profileのtarget.coresのn番目のcore
+    #[cfg(feature = "std")]
+    pub fn new_with_config(
+        dap: DapHandle<T>,
+        target: &TargetConfig,
+        core: usize,
+    ) -> Result<Self, ConfigError> {
+        Ok(Self::new(dap, target.core(core)?.debug))
+    }
+
+    pub fn edscr_read(&mut self) -> Result<EDSCR, DapError> {
+        Ok(EDSCR(self.register_u32_read(
+            Armv8DebugRegisterOffset::EDSCR as u64,
+        )?))
+    }
+    pub fn edscr_write(&mut self, data: EDSCR) -> Result<(), DapError> {
+        self.register_u32_write(Armv8DebugRegisterOffset::EDSCR as u64, data.0)
+    }
+    pub fn edrcr_write(&mut self, data: EDRCR) -> Result<(), DapError> {
+        self.register_u32_write(Armv8DebugRegisterOffset::EDRCR as u64, data.0)
+    }
+    pub fn edrcr_read(&mut self) -> Result<EDRCR, DapError> {
+        Ok(EDRCR(self.register_u32_read(
+            Armv8DebugRegisterOffset::EDRCR as u64,
+        )?))
+    }
+    pub fn oslar_write(&mut self, oslk: u32) -> Result<(), DapError> {
+        self.register_u32_write(Armv8DebugRegisterOffset::OSLAR_EL1 as u64, oslk)
+    }
+    pub fn edprsr_read(&mut self) -> Result<EDPRSR, DapError> {
+        Ok(EDPRSR(self.register_u32_read(
+            Armv8DebugRegisterOffset::EDPRSR as u64,
+        )?))
+    }
+
+    pub fn edprcr_read(&mut self) -> Result<EDPRCR, DapError> {
+        Ok(EDPRCR(self.register_u32_read(
+            Armv8DebugRegisterOffset::EDPRCR as u64,
+        )?))
+    }
+    pub fn edprcr_write(&mut self, data: EDPRCR) -> Result<(), DapError> {
+        self.register_u32_write(Armv8DebugRegisterOffset::EDPRCR as u64, data.0)
+    }
+
+    // debug registerを触る前の準備
+    // OS Lock中やDouble Lock中の書き込みは無視されるので、HDEが立たないなどの形で現れる
+    // keep_poweredならEDPRCR.CORENPDRQでcoreのpower downを止めてもらう
+    pub fn prepare_debug(&mut self, keep_powered: bool) -> Result<(), DebugError> {
+        let edprsr = self.edprsr_read()?;
+        debug!("{}", edprsr);
+        if edprsr.PU() == 0 {
+            return Err(DebugError::CorePoweredDown);
+        }
+        if edprsr.DLK() == 1 {
+            return Err(DebugError::DoubleLocked);
+        }
+        if keep_powered {
+            let mut edprcr = self.edprcr_read()?;
+            edprcr.set_CWRR(0);
+            edprcr.set_CORENPDRQ(1);
+            self.edprcr_write(edprcr)?;
+        }
+        // 2回目以降は自分で解除した後の値になるので、最初の値だけ覚えておく
+        if self.os_locked.is_none() {
+            self.os_locked = Some(edprsr.OSLK() == 1);
+        }
+        self.oslar_write(0)?;
+        if self.edprsr_read()?.OSLK() == 1 {
+            return Err(DebugError::OsLocked);
+        }
+        Ok(())
+    }
+
+    pub fn dbgauthstatus_read(&mut self) -> Result<DBGAUTHSTATUS, DapError> {
+        Ok(DBGAUTHSTATUS(self.register_u32_read(
+            Armv8DebugRegisterOffset::DBGAUTHSTATUS as u64,
+        )?))
+    }
+
+    pub fn auth_status(&mut self) -> Result<AuthStatus, DapError> {
+        let edprsr = self.edprsr_read()?;
+        let auth = self.dbgauthstatus_read()?;
+        debug!("{} {}", auth, edprsr);
+        Ok(AuthStatus::new(auth, &edprsr))
+    }
+
+    // fuse等でinvasive debugが禁止されているとhaltは黙って失敗するので先に調べる
+    // secureだけ禁止されている場合はnon-secureでhaltできるので警告に留める
+    pub fn check_debug_permissions(&mut self) -> Result<AuthStatus, DebugError> {
+        let auth = self.auth_status()?;
+        if !auth.can_halt() {
+            return Err(DebugError::InvasiveDebugDisabled { auth });
+        }
+        if auth.secure_implemented && !auth.secure_invasive_enabled {
+            warn!("secure invasive debug is disabled: {}", auth);
+        }
+        Ok(auth)
+    }
+
+    pub fn halted(&mut self) -> Result<bool, DapError> {
+        Ok(self.edprsr_read()?.HALTED() == 1)
+    }
+
+    pub fn halting_debug_enable(&mut self) -> Result<(), DapError> {
+        let mut edscr = self.edscr_read()?;
+        edscr.set_hde(1);
+        self.edscr_write(edscr)
+    }
+
+    pub fn edecr_read(&mut self) -> Result<EDECR, DapError> {
+        Ok(EDECR(self.register_u32_read(
+            Armv8DebugRegisterOffset::EDECR as u64,
+        )?))
+    }
+    pub fn edecr_write(&mut self, data: EDECR) -> Result<(), DapError> {
+        self.register_u32_write(Armv8DebugRegisterOffset::EDECR as u64, data.0)
+    }
+    pub fn edesr_read(&mut self) -> Result<EDESR, DapError> {
+        Ok(EDESR(self.register_u32_read(
+            Armv8DebugRegisterOffset::EDESR as u64,
+        )?))
+    }
+    pub fn edesr_write(&mut self, data: EDESR) -> Result<(), DapError> {
+        self.register_u32_write(Armv8DebugRegisterOffset::EDESR as u64, data.0)
+    }
+    // EDESRは1を書いたeventだけが落ち、0を書いたeventはpendingのまま残る
+    pub fn edesr_clear(&mut self, events: EDESR) -> Result<(), DapError> {
+        self.edesr_write(events)
+    }
+
+    // EDACRの中身はIMPLEMENTATION DEFINED
+    pub fn edacr_read(&mut self) -> Result<u32, DapError> {
+        self.register_u32_read(Armv8DebugRegisterOffset::EDACR as u64)
+    }
+    pub fn edacr_write(&mut self, data: u32) -> Result<(), DapError> {
+        self.register_u32_write(Armv8DebugRegisterOffset::EDACR as u64, data)
+    }
+
+    pub fn edeccr_read(&mut self) -> Result<EDECCR, DapError> {
+        Ok(EDECCR(self.register_u32_read(
+            Armv8DebugRegisterOffset::EDECCR as u64,
+        )?))
+    }
+    pub fn edeccr_write(&mut self, data: EDECCR) -> Result<(), DapError> {
+        self.register_u32_write(Armv8DebugRegisterOffset::EDECCR as u64, data.0)
+    }
+
+    pub fn halt_status(&mut self) -> Result<HaltStatus, DapError> {
+        Ok(HaltStatus::from(&self.edscr_read()?))
+    }
+
+    pub fn halt_reason(&mut self) -> Result<HaltReason, DapError> {
+        Ok(self.halt_status()?.reason)
+    }
+
+    // EDECR.RCE。warm resetの直後にhaltする
+    pub fn enable_reset_catch(&mut self) -> Result<(), DapError> {
+        self.reset_catch_set(true)
+    }
+    pub fn disable_reset_catch(&mut self) -> Result<(), DapError> {
+        self.reset_catch_set(false)
+    }
+    fn reset_catch_set(&mut self, enable: bool) -> Result<(), DapError> {
+        let mut edecr = self.edecr_read()?;
+        edecr.set_RCE(enable as u32);
+        self.edecr_write(edecr)
+    }
+
+    // reset catchを掛けてiface_resetでresetし、resetから出た最初の命令でhaltさせる
+    // iface_resetはSRSTや電源の入れ直しなど、callerがtargetに合わせて用意する
+    pub fn halt_on_reset(
+        &mut self,
+        iface_reset: impl FnOnce() -> Result<(), InterfaceError>,
+    ) -> Result<HaltReason, DebugError> {
+        let mut reset_catch = EDESR(0);
+        reset_catch.set_RC(1);
+        // 前のresetのeventとEDPRSR.SRを残さない
+        self.edesr_clear(EDESR(reset_catch.0))?;
+        self.edprsr_read()?;
+        self.enable_reset_catch()?;
+        if let Err(e) = iface_reset() {
+            self.disable_reset_catch()?;
+            return Err(e.into());
+        }
+
+        // EDPRSR.SRは読むと落ちるので、1度でも見えたら覚えておく
+        // resetの前からhaltしていた場合に、resetを待たずに抜けないようにする
+        let mut reset_seen = false;
+        let mut edprsr = EDPRSR(0);
+        let result = self.poll_until(PollBudget::Iterations(POLL_MAX), |target| {
+            edprsr = target.edprsr_read()?;
+            reset_seen |= edprsr.SR() == 1;
+            let out_of_reset = edprsr.PU() == 1 && edprsr.R() == 0;
+            Ok(if reset_seen && out_of_reset && edprsr.HALTED() == 1 {
+                Some(())
+            } else {
+                None
+            })
+        });
+        match result {
+            Ok(()) => (),
+            Err(DebugError::Timeout) => {
+                let edscr = self.edscr_read()?;
+                warn!("core {:#x} did not halt out of reset", self.baseaddr);
+                return Err(DebugError::HaltTimeout {
+                    edprsr: edprsr.0,
+                    edscr: edscr.0,
+                });
+            }
+            Err(e) => return Err(e),
+        }
+
+        // cold resetでOS Lockが掛かり直している
+        if edprsr.OSLK() == 1 {
+            self.oslar_write(0)?;
+            if self.edprsr_read()?.OSLK() == 1 {
+                return Err(DebugError::OsLocked);
+            }
+        }
+        self.edesr_clear(reset_catch)?;
+        self.disable_reset_catch()?;
+        Ok(self.halt_reason()?)
+    }
+
+    // el_maskのbit nが立っているELnへの例外でhaltする(Secure/Non-secureとも)
+    pub fn enable_exception_catch(&mut self, el_mask: u8) -> Result<(), DapError> {
+        let mask = (el_mask & 0b1110) as u32;
+        let mut edeccr = EDECCR(0);
+        edeccr.set_NSE(mask);
+        edeccr.set_SE(mask);
+        self.edeccr_write(edeccr)
+    }
+    pub fn disable_exception_catch(&mut self) -> Result<(), DapError> {
+        self.edeccr_write(EDECCR(0))
+    }
+
+    // EDECR.SS
+    pub fn single_step_set(&mut self, enable: bool) -> Result<(), DapError> {
+        let mut edecr = self.edecr_read()?;
+        edecr.set_SS(enable as u32);
+        self.edecr_write(edecr)
+    }
+
+    // halting stepで1命令だけ実行し、次のPCを返す
+    pub fn step(&mut self, cti: &mut Cti<T>) -> Result<u64, DebugError> {
+        self.single_step_set(true)?;
+        // 前回のstepが残っていると即座にhaltしてしまう
+        if self.edesr_read()?.SS() == 1 {
+            let mut step = EDESR(0);
+            step.set_SS(1);
+            self.edesr_clear(step)?;
+        }
+        cti.restart_core()?;
+        // restartしたことを確認してからhaltを待つ
+        let result = self
+            .wait_edprsr(|x| x.SDR() == 1)
+            .and_then(|_| self.wait_edprsr(|x| x.HALTED() == 1));
+        self.single_step_set(false)?;
+        result?;
+        self.read_pc()
+    }
+
+    // fがSomeを返すまで呼び続ける。budgetを使い切ったらTimeout
+    pub fn poll_until<R>(
+        &mut self,
+        budget: PollBudget,
+        mut f: impl FnMut(&mut Self) -> Result<Option<R>, DapError>,
+    ) -> Result<R, DebugError> {
+        match budget {
+            PollBudget::Iterations(n) => {
+                for _ in 0..n {
+                    if let Some(result) = f(self)? {
+                        return Ok(result);
+                    }
+                }
+            }
+            #[cfg(feature = "std")]
+            PollBudget::Duration(timeout) => {
+                let deadline = std::time::Instant::now() + timeout;
+                loop {
+                    if let Some(result) = f(self)? {
+                        return Ok(result);
+                    }
+                    if std::time::Instant::now() > deadline {
+                        break;
+                    }
+                }
+            }
+        }
+        Err(DebugError::Timeout)
+    }
+
+    // EDPCSRloを読むとEDPCSRhi/EDCIDSR/EDVIDSRが同じ時点の値に固定されるので、loを先に読む
+    pub fn sample_pc(&mut self) -> Result<Option<PcSample>, DapError> {
+        self.with_window(0, |window| {
+            let low = window.read(Armv8DebugRegisterOffset::EDPCSRlo as u64)?;
+            if low == EDPCSR_INVALID {
+                return Ok(None);
+            }
+            let context_id = window.read(Armv8DebugRegisterOffset::EDCIDSR as u64)?;
+            let vidsr = window.read(Armv8DebugRegisterOffset::EDVIDSR as u64)?;
+            let high = window.read(Armv8DebugRegisterOffset::EDPCSRhi as u64)?;
+            Ok(Some(PcSample {
+                pc: ((high as u64) << 32) | low as u64,
+                context_id,
+                vmid: vidsr as u16,
+                non_secure: vidsr & (1 << 31) != 0,
+            }))
+        })
+    }
+
+    // durationの間intervalごとにPCを取る。取れなかったsampleは捨てる
+    #[cfg(feature = "std")]
+    pub fn profile(
+        &mut self,
+        duration: std::time::Duration,
+        interval: std::time::Duration,
+    ) -> Result<Vec<PcSample>, DapError> {
+        let mut samples = Vec::new();
+        let start = std::time::Instant::now();
+        while start.elapsed() < duration {
+            if let Some(sample) = self.sample_pc()? {
+                samples.push(sample);
+            }
+            std::thread::sleep(interval);
+        }
+        Ok(samples)
+    }
+
+    // breakpointやhalt要求の後、coreが止まるのを待って理由を返す
+    pub fn wait_for_halt(&mut self, budget: PollBudget) -> Result<HaltReason, DebugError> {
+        let mut edprsr = EDPRSR(0);
+        let result = self.poll_until(budget, |target| {
+            edprsr = target.edprsr_read()?;
+            Ok(if edprsr.HALTED() == 1 { Some(()) } else { None })
+        });
+        match result {
+            Ok(()) => Ok(self.halt_reason()?),
+            Err(DebugError::Timeout) => {
+                let edscr = self.edscr_read()?;
+                warn!("core {:#x} did not halt", self.baseaddr);
+                Err(DebugError::HaltTimeout {
+                    edprsr: edprsr.0,
+                    edscr: edscr.0,
+                })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn wait_edprsr(&mut self, ready: fn(&EDPRSR) -> bool) -> Result<EDPRSR, DebugError> {
+        for _ in 0..POLL_MAX {
+            let edprsr = self.edprsr_read()?;
+            if ready(&edprsr) {
+                return Ok(edprsr);
+            }
+        }
+        warn!("EDPRSR polling timed out");
+        Err(DebugError::Timeout)
+    }
+
+    pub fn wait_edscr(&mut self, ready: fn(&EDSCR) -> bool) -> Result<EDSCR, DebugError> {
+        for _ in 0..POLL_MAX {
+            let edscr = self.edscr_read()?;
+            if ready(&edscr) {
+                return Ok(edscr);
+            }
+        }
+        warn!("EDSCR polling timed out");
+        Err(DebugError::Timeout)
+    }
+
+    // halt中のcoreにEDITR経由で命令を1つ実行させる
+    // abortした場合はEDRCR.CSEでERRを落としてFaultを返す
+    pub fn exec_insn(&mut self, instruction: u32) -> Result<(), DebugError> {
+        self.wait_edscr(|x| x.ITE() == 1)?;
+        self.register_u32_write(Armv8DebugRegisterOffset::EDITR as u64, instruction)?;
+        let edscr = self.wait_edscr(|x| x.ITE() == 1)?;
+        if edscr.ERR() == 1 {
+            warn!(
+                "instruction {:#010x} aborted: STATUS: {:#08b}, {:?}",
+                instruction,
+                edscr.STATUS(),
+                edscr
+            );
+            let mut edrcr = EDRCR(0);
+            edrcr.set_CSE(1);
+            self.edrcr_write(edrcr)?;
+            return Err(DebugError::Fault);
+        }
+        Ok(())
+    }
+
+    // 先頭から順に実行し、abortした所で止める
+    pub fn exec_insns(&mut self, instructions: &[u32]) -> Result<(), DebugError> {
+        for instruction in instructions {
+            self.exec_insn(*instruction)?;
+        }
+        Ok(())
+    }
+
+    // coreがMSR DBGDTR_EL0, Xtで書いた64bitを受け取る
+    // TXfullを待ち、TXを読むとTXfullが落ちるのでRX(上位)から読む
+    pub fn dtr_read_u64(&mut self) -> Result<u64, DebugError> {
+        self.wait_edscr(|x| x.TXfull() == 1)?;
+        let high = self.register_u32_read(Armv8DebugRegisterOffset::DBGDTRRX_EL0 as u64)?;
+        let low = self.register_u32_read(Armv8DebugRegisterOffset::DBGDTRTX_EL0 as u64)?;
+        self.check_dtr()?;
+        Ok(((high as u64) << 32) | (low as u64))
+    }
+
+    // coreがMRS Xt, DBGDTR_EL0で読む64bitを渡す
+    // MRSはDTRTXを上位、DTRRXを下位として読む。RXを書くとRXfullが立つのでTX(上位)から書く
+    pub fn dtr_write_u64(&mut self, value: u64) -> Result<(), DebugError> {
+        // coreが前の値を読む前に書くとRXOになる
+        self.wait_edscr(|x| x.RXfull() == 0)?;
+        self.register_u32_write(
+            Armv8DebugRegisterOffset::DBGDTRTX_EL0 as u64,
+            (value >> 32) as u32,
+        )?;
+        self.register_u32_write(
+            Armv8DebugRegisterOffset::DBGDTRRX_EL0 as u64,
+            (value & 0xffff_ffff) as u32,
+        )?;
+        self.check_dtr()
+    }
+
+    // RXO/TXUはstickyなので、見つけたらEDRCR.CSEで落としてから返す
+    fn check_dtr(&mut self) -> Result<(), DebugError> {
+        let edscr = self.edscr_read()?;
+        if edscr.RXO() == 0 && edscr.TXU() == 0 {
+            return Ok(());
+        }
+        warn!("DTR transfer failed: {}", edscr);
+        let mut edrcr = EDRCR(0);
+        edrcr.set_CSE(1);
+        self.edrcr_write(edrcr)?;
+        if edscr.RXO() == 1 {
+            Err(DebugError::DtrOverrun)
+        } else {
+            Err(DebugError::DtrUnderrun)
+        }
+    }
+
+    // DBGDTR経由でXtを読み書きする。SPは扱えない
+    fn dtr_read(&mut self, rt: u8) -> Result<u64, DebugError> {
+        self.exec_insn(encode_msr(DBGDTR_EL0, rt))?;
+        self.dtr_read_u64()
+    }
+
+    fn dtr_write(&mut self, rt: u8, data: u64) -> Result<(), DebugError> {
+        self.dtr_write_u64(data)?;
+        self.exec_insn(encode_mrs(DBGDTR_EL0, rt))
+    }
+
+    // scratchを退避してからinstructionsを実行し、scratchの値を読んで戻す
+    fn scratch_read(&mut self, instruction: u32) -> Result<u64, DebugError> {
+        let saved = self.dtr_read(SCRATCH)?;
+        self.exec_insn(instruction)?;
+        let result = self.dtr_read(SCRATCH);
+        self.dtr_write(SCRATCH, saved)?;
+        result
+    }
+
+    // scratchを退避せずに、instructionsを1つずつ実行してはscratchを読む
+    fn scratch_read_each(
+        &mut self,
+        instructions: &[u32],
+        values: &mut [u64],
+    ) -> Result<(), DebugError> {
+        for (instruction, value) in instructions.iter().zip(values.iter_mut()) {
+            self.exec_insn(*instruction)?;
+            *value = self.dtr_read(SCRATCH)?;
+        }
+        Ok(())
+    }
+
+    fn scratch_write(&mut self, instruction: u32, data: u64) -> Result<(), DebugError> {
+        let saved = self.dtr_read(SCRATCH)?;
+        self.dtr_write(SCRATCH, data)?;
+        self.exec_insn(instruction)?;
+        self.dtr_write(SCRATCH, saved)
+    }
+
+    pub fn execution_state(&mut self) -> Result<ExecutionState, DapError> {
+        Ok(self.edscr_read()?.execution_state())
+    }
+
+    // AArch32のcoreがhaltしている場合はA32Targetで操作する
+    fn in_aarch32(&mut self) -> Result<Option<A32Target<T>>, DebugError> {
+        match self.execution_state()? {
+            ExecutionState::AArch64 => Ok(None),
+            ExecutionState::AArch32 => Ok(Some(A32Target {
+                dap: self.dap.clone(),
+                baseaddr: self.baseaddr,
+            })),
+        }
+    }
+
+    // A64命令しかない操作をAArch32のcoreで実行させない
+    fn require_aarch64(&mut self) -> Result<(), DebugError> {
+        match self.execution_state()? {
+            ExecutionState::AArch64 => Ok(()),
+            ExecutionState::AArch32 => Err(DebugError::WrongExecutionState),
+        }
+    }
+
+    // n: 0-30はXn, 31(GPR_SP)はSP
+    // AArch32ではRn(15はPC)
+    pub fn read_gpr(&mut self, n: u8) -> Result<u64, DebugError> {
+        if let Some(mut target) = self.in_aarch32()? {
+            return Ok(target.read_gpr(n)? as u64);
+        }
+        match n {
+            GPR_SP => self.scratch_read(encode_mov_sp(SCRATCH, GPR_SP)),
+            n if n < GPR_SP => self.dtr_read(n),
+            _ => Err(InterfaceError::OutOfRange.into()),
+        }
+    }
+
+    pub fn write_gpr(&mut self, n: u8, value: u64) -> Result<(), DebugError> {
+        if let Some(mut target) = self.in_aarch32()? {
+            return target.write_gpr(n, value as u32);
+        }
+        match n {
+            GPR_SP => self.scratch_write(encode_mov_sp(GPR_SP, SCRATCH), value),
+            n if n < GPR_SP => self.dtr_write(n, value),
+            _ => Err(InterfaceError::OutOfRange.into()),
+        }
+    }
+
+    // x0を経由してsystem registerを読み書きする。x0は元に戻す
+    pub fn read_sysreg(
+        &mut self,
+        op0: u8,
+        op1: u8,
+        crn: u8,
+        crm: u8,
+        op2: u8,
+    ) -> Result<u64, DebugError> {
+        self.mrs(SysReg(
+            op0 as u32, op1 as u32, crn as u32, crm as u32, op2 as u32,
+        ))
+    }
+
+    pub fn write_sysreg(
+        &mut self,
+        op0: u8,
+        op1: u8,
+        crn: u8,
+        crm: u8,
+        op2: u8,
+        value: u64,
+    ) -> Result<(), DebugError> {
+        self.msr(
+            SysReg(op0 as u32, op1 as u32, crn as u32, crm as u32, op2 as u32),
+            value,
+        )
+    }
+
+    pub fn mrs(&mut self, sysreg: SysReg) -> Result<u64, DebugError> {
+        self.check_sysreg_access(sysreg)?;
+        self.scratch_read(encode_mrs(sysreg, SCRATCH))
+    }
+
+    pub fn msr(&mut self, sysreg: SysReg, value: u64) -> Result<(), DebugError> {
+        self.check_sysreg_access(sysreg)?;
+        self.scratch_write(encode_msr(sysreg, SCRATCH), value)
+    }
+
+    // 今のELで触れないregisterはEDITRに入れる前に弾く
+    // 入れてしまうとUNDEFINEDになり、EDSCR.ERRが立つだけで理由が分からない
+    fn check_sysreg_access(&mut self, sysreg: SysReg) -> Result<(), DebugError> {
+        if !sysreg.is_valid() {
+            return Err(InterfaceError::OutOfRange.into());
+        }
+        let edscr = self.edscr_read()?;
+        if edscr.execution_state() == ExecutionState::AArch32 {
+            return Err(DebugError::WrongExecutionState);
+        }
+        Self::check_el(&edscr, sysreg.min_el())
+    }
+
+    fn check_el(edscr: &EDSCR, el: u8) -> Result<(), DebugError> {
+        let current_el = edscr.EL() as u8;
+        if el <= current_el {
+            return Ok(());
+        }
+        let secure = el == 3 && edscr.SDD() == 1;
+        warn!(
+            "EL{} is required, but the core is at EL{}: {}",
+            el, current_el, edscr
+        );
+        Err(DebugError::InsufficientPrivilege { current_el, secure })
+    }
+
+    // 今のELのSCTLR_ELx.M。EL0ではSCTLR_EL1を読めないのでerrorになる
+    pub fn mmu_enabled(&mut self) -> Result<bool, DebugError> {
+        let sctlr = match self.current_el()? {
+            3 => SysReg::SCTLR_EL3,
+            2 => SysReg::SCTLR_EL2,
+            _ => SysReg::SCTLR_EL1,
+        };
+        Ok(self.mrs(sctlr)? & 1 == 1)
+    }
+
+    // 今のELのstage 1でvaを引き、physical addressを返す。translation faultならNone
+    // ATが書き換えるPAR_EL1は元に戻す
+    pub fn translate(&mut self, va: u64) -> Result<Option<u64>, DebugError> {
+        let edscr = self.edscr_read()?;
+        Self::check_el(&edscr, 1)?;
+        let saved = self.mrs(SysReg::PAR_EL1)?;
+        self.scratch_write(encode_at_s1r(edscr.EL() as u8, SCRATCH), va)?;
+        let par = self.mrs(SysReg::PAR_EL1)?;
+        self.msr(SysReg::PAR_EL1, saved)?;
+        if par & 1 == 1 {
+            debug!("no translation for {:#x}: PAR_EL1 {:#x}", va, par);
+            return Ok(None);
+        }
+        Ok(Some((par & PAR_PA_MASK) | (va & 0xfff)))
+    }
+
+    // debug state中だけ有効
+    pub fn current_el(&mut self) -> Result<u8, DapError> {
+        Ok(self.edscr_read()?.EL() as u8)
+    }
+
+    pub fn is_secure(&mut self) -> Result<bool, DapError> {
+        Ok(self.edscr_read()?.NS() == 0)
+    }
+
+    // halt中にDCPSnでELnへ移る。今のELより低いELを指定しても下がらない
+    // SDDが立っているとEL3(Secure)には移れない
+    pub fn dcps(&mut self, target_el: u8) -> Result<(), DebugError> {
+        if !(1..=3).contains(&target_el) {
+            return Err(InterfaceError::OutOfRange.into());
+        }
+        let edscr = self.edscr_read()?;
+        if edscr.execution_state() == ExecutionState::AArch32 {
+            return Err(DebugError::WrongExecutionState);
+        }
+        if target_el == 3 && edscr.SDD() == 1 {
+            return Err(DebugError::InsufficientPrivilege {
+                current_el: edscr.EL() as u8,
+                secure: true,
+            });
+        }
+        self.exec_insn(encode_dcps(target_el))?;
+        Ok(())
+    }
+
+    // DCPSで移る前のELへDRPSで戻る。EL0ではUNDEFINED
+    pub fn drps(&mut self) -> Result<(), DebugError> {
+        let edscr = self.edscr_read()?;
+        if edscr.execution_state() == ExecutionState::AArch32 {
+            return Err(DebugError::WrongExecutionState);
+        }
+        Self::check_el(&edscr, 1)?;
+        self.exec_insn(DRPS)?;
+        Ok(())
+    }
+
+    // debug stateから戻る先(DLR_EL0)
+    pub fn read_pc(&mut self) -> Result<u64, DebugError> {
+        if let Some(mut target) = self.in_aarch32()? {
+            return Ok(target.read_pc()? as u64);
+        }
+        self.scratch_read(encode_mrs(DLR_EL0, SCRATCH))
+    }
+    pub fn write_pc(&mut self, value: u64) -> Result<(), DebugError> {
+        if let Some(mut target) = self.in_aarch32()? {
+            return target.write_pc(value as u32);
+        }
+        self.scratch_write(encode_msr(DLR_EL0, SCRATCH), value)
+    }
+
+    // debug state突入前のPSTATE(DSPSR_EL0)
+    pub fn read_cpsr(&mut self) -> Result<u32, DebugError> {
+        if let Some(mut target) = self.in_aarch32()? {
+            return target.read_cpsr();
+        }
+        Ok(self.scratch_read(encode_mrs(DSPSR_EL0, SCRATCH))? as u32)
+    }
+    pub fn write_cpsr(&mut self, value: u32) -> Result<(), DebugError> {
+        if let Some(mut target) = self.in_aarch32()? {
+            return target.write_cpsr(value);
+        }
+        self.scratch_write(encode_msr(DSPSR_EL0, SCRATCH), value as u64)
+    }
+
+    // 実行状態は最初に1度だけ確かめる。SP/PC/PSTATEはx0を読み終えてから借り、最後に1度だけ戻す
+    // EDITRとDTRの往復は34回で、read_gprを34回呼ぶより少ない
+    pub fn save_context(&mut self) -> Result<CpuContext, DebugError> {
+        self.require_aarch64()?;
+        let mut ctx = CpuContext::default();
+        for n in 0..ctx.x.len() {
+            ctx.x[n] = self.dtr_read(n as u8)?;
+        }
+        let special = [
+            encode_mov_sp(SCRATCH, GPR_SP),
+            encode_mrs(DLR_EL0, SCRATCH),
+            encode_mrs(DSPSR_EL0, SCRATCH),
+        ];
+        let mut values = [0; 3];
+        let result = self.scratch_read_each(&special, &mut values);
+        self.dtr_write(SCRATCH, ctx.x[SCRATCH as usize])?;
+        result?;
+        ctx.sp = values[0];
+        ctx.pc = values[1];
+        ctx.pstate = values[2] as u32;
+        Ok(ctx)
+    }
+
+    // SP/PC/PSTATEの書き込みはx0を使うが、その都度戻すのでGPRを先に書いてよい
+    // PCはrestart直前の値になるよう最後に書く
+    pub fn restore_context(&mut self, ctx: &CpuContext) -> Result<(), DebugError> {
+        self.require_aarch64()?;
+        for (n, x) in ctx.x.iter().enumerate() {
+            self.write_gpr(n as u8, *x)?;
+        }
+        self.write_gpr(GPR_SP, ctx.sp)?;
+        self.write_cpsr(ctx.pstate)?;
+        self.write_pc(ctx.pc)
+    }
+
+    // scratchに使ったx0/x1は各操作の中で戻しているので、ここではabortの痕跡だけ消す
+    // CTIのhalt triggerをackしてからrestartし、coreが走り出すのを待つ
+    // 走り出してすぐbreakpointで止まることもあるので、HALTEDではなくSDRを見る
+    pub fn resume(&mut self, cti: &mut Cti<T>) -> Result<(), DebugError> {
+        let mut edrcr = EDRCR(0);
+        edrcr.set_CSE(1);
+        self.edrcr_write(edrcr)?;
+        cti.restart_core()?;
+        self.wait_edprsr(|x| x.SDR() == 1)?;
+        Ok(())
+    }
+
+    // debuggerが変えた状態を戻し、coreを走らせたまま手を離す
+    // HDEを立てるのはdebuggerだけなので、halt中なら止めたのは自分とみなす
+    pub fn detach(&mut self, cti: &mut Cti<T>) -> Result<(), DebugError> {
+        for index in 0..32 {
+            if self.breakpoints & (1 << index) != 0 {
+                self.clear_breakpoint(index)?;
+            }
+        }
+        if self.halted()? {
+            self.resume(cti)?;
+        }
+        let mut edscr = self.edscr_read()?;
+        edscr.set_hde(0);
+        self.edscr_write(edscr)?;
+        cti.output_trigger_disable(CTI_TRIGGER_HALT, CTI_CHANNEL_HALT)?;
+        cti.output_trigger_disable(CTI_TRIGGER_RESTART, CTI_CHANNEL_RESTART)?;
+        if self.os_locked.take() == Some(true) {
+            self.oslar_write(1)?;
+        }
+        Ok(())
+    }
+
+    // x0をaddress、x1をdataにしてcoreにload/storeさせる
+    // 前後の端数はwordを読んでから書き戻す
+    pub fn mem_read(&mut self, addr: u64, buf: &mut [u8]) -> Result<(), DebugError> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.require_aarch64()?;
+        let start = addr & !3;
+        let end = (addr + buf.len() as u64 + 3) & !3;
+        let offset = (addr - start) as usize;
+        self.core_access(start, |target| {
+            for i in (0..(end - start) as usize).step_by(4) {
+                target.exec_insn(encode_ldr_w_post4(1, 0))?;
+                let word = (target.dtr_read(1)? as u32).to_le_bytes();
+                for (j, x) in word.iter().enumerate() {
+                    if let Some(y) = (i + j).checked_sub(offset).and_then(|k| buf.get_mut(k)) {
+                        *y = *x;
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // addrはcoreから見たaddressだが、DAPのWriteGuardでも確かめる
+    pub fn mem_write(&mut self, addr: u64, data: &[u8]) -> Result<(), DebugError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.dap.lock().check_write(addr, data.len() as u64)?;
+        self.require_aarch64()?;
+        let start = addr & !3;
+        let end = (addr + data.len() as u64 + 3) & !3;
+        let offset = (addr - start) as usize;
+        let mut head = [0u8; 4];
+        let mut tail = [0u8; 4];
+        if offset != 0 || !data.len().is_multiple_of(4) {
+            self.mem_read(start, &mut head)?;
+            self.mem_read(end - 4, &mut tail)?;
+        }
+        self.core_access(start, |target| {
+            for i in (0..(end - start) as usize).step_by(4) {
+                let mut word = [0u8; 4];
+                for (j, x) in word.iter_mut().enumerate() {
+                    let k = i + j;
+                    *x = if k < offset {
+                        head[j]
+                    } else if k - offset < data.len() {
+                        data[k - offset]
+                    } else {
+                        tail[j]
+                    };
+                }
+                target.dtr_write(1, u32::from_le_bytes(word) as u64)?;
+                target.exec_insn(encode_str_w_post4(1, 0))?;
+            }
+            Ok(())
+        })
+    }
+
+    // CTR_EL0.DminLine/IminLineから(dcache, icache)のline size(byte)を求める
+    pub fn cache_line_sizes(&mut self) -> Result<(u64, u64), DebugError> {
+        // CTR_EL0はEL0から読めるので権限の確認は要らない
+        self.require_aarch64()?;
+        let ctr = self.scratch_read(encode_mrs(SysReg::CTR_EL0, SCRATCH))?;
+        Ok((4 << ((ctr >> 16) & 0xf), 4 << (ctr & 0xf)))
+    }
+
+    // [addr, addr + len)を含むlineをPoCまでclean & invalidateする
+    pub fn clean_invalidate_dcache_range(&mut self, addr: u64, len: u64) -> Result<(), DebugError> {
+        let (line, _) = self.cache_line_sizes()?;
+        self.cache_maintenance(addr, len, line, encode_dc_civac, &[DSB_ISH])
+    }
+
+    pub fn invalidate_icache_range(&mut self, addr: u64, len: u64) -> Result<(), DebugError> {
+        let (_, line) = self.cache_line_sizes()?;
+        self.cache_maintenance(addr, len, line, encode_ic_ivau, &[DSB_ISH, ISB])
+    }
+
+    // 書き換えた命令をcoreに実行させるため、書いた後にdcacheを掃き出してicacheを捨てる
+    pub fn write_code(&mut self, addr: u64, data: &[u8]) -> Result<(), DebugError> {
+        self.mem_write(addr, data)?;
+        self.clean_invalidate_dcache_range(addr, data.len() as u64)?;
+        self.invalidate_icache_range(addr, data.len() as u64)
+    }
+
+    // lineごとにx0へaddressを入れてopを実行し、最後にbarriersを実行する
+    fn cache_maintenance(
+        &mut self,
+        addr: u64,
+        len: u64,
+        line: u64,
+        op: fn(u8) -> u32,
+        barriers: &[u32],
+    ) -> Result<(), DebugError> {
+        if len == 0 {
+            return Ok(());
+        }
+        let start = addr & !(line - 1);
+        let end = (addr + len + line - 1) & !(line - 1);
+        self.core_access(start, |target| {
+            for address in (start..end).step_by(line as usize) {
+                target.dtr_write(0, address)?;
+                target.exec_insn(op(0))?;
+            }
+            target.exec_insns(barriers)
+        })
+    }
+
+    // x0/x1を退避してaccessを実行し、失敗しても戻してから結果を返す
+    fn core_access<F>(&mut self, addr: u64, access: F) -> Result<(), DebugError>
+    where
+        F: FnOnce(&mut Self) -> Result<(), DebugError>,
+    {
+        let x0 = self.dtr_read(0)?;
+        let x1 = self.dtr_read(1)?;
+        let result = self.dtr_write(0, addr).and_then(|_| access(self));
+        self.dtr_write(0, x0)?;
+        self.dtr_write(1, x1)?;
+        result
+    }
+
+    pub fn eddfr_read(&mut self) -> Result<EDDFR, DapError> {
+        Ok(EDDFR(self.register_u32_read(
+            Armv8DebugRegisterOffset::EDDFR as u64,
+        )?))
+    }
+
+    // 実装されているbreakpoint/watchpointの数
+    pub fn breakpoint_slots(&mut self) -> Result<usize, DapError> {
+        Ok(self.eddfr_read()?.BRPs() as usize + 1)
+    }
+    pub fn watchpoint_slots(&mut self) -> Result<usize, DapError> {
+        Ok(self.eddfr_read()?.WRPs() as usize + 1)
+    }
+
+    // 既に使われているindexは上書きする
+    pub fn set_breakpoint(&mut self, index: usize, address: u64) -> Result<(), DebugError> {
+        if index >= self.breakpoint_slots()? {
+            return Err(InterfaceError::OutOfRange.into());
+        }
+        self.clear_breakpoint(index)?;
+        let offset = index as u64 * BREAKPOINT_STRIDE;
+        self.register_u64_write(
+            Armv8DebugRegisterOffset::DBGBVR_BASE_EL1 as u64 + offset,
+            address,
+        )?;
+        let mut dbgbcr = DBGBCR(0);
+        dbgbcr.set_BAS(DBGBCR_BAS_A64);
+        dbgbcr.set_PMC(DBG_PMC_EL1_EL0);
+        dbgbcr.set_E(1);
+        self.register_u32_write(
+            Armv8DebugRegisterOffset::DBGBCR_BASE_EL1 as u64 + offset,
+            dbgbcr.0,
+        )?;
+        self.breakpoints |= 1 << index;
+        Ok(())
+    }
+
+    pub fn clear_breakpoint(&mut self, index: usize) -> Result<(), DebugError> {
+        let offset = index as u64 * BREAKPOINT_STRIDE;
+        self.register_u32_write(Armv8DebugRegisterOffset::DBGBCR_BASE_EL1 as u64 + offset, 0)?;
+        self.breakpoints &= !(1 << index);
+        Ok(())
+    }
+
+    // addressからlength byteの範囲を監視する。doublewordをまたぐ範囲は扱えない
+    pub fn set_watchpoint(
+        &mut self,
+        index: usize,
+        address: u64,
+        length: u64,
+        access: WatchKind,
+    ) -> Result<(), DebugError> {
+        let (aligned, bas) = watchpoint_bas(address, length)?;
+        if index >= self.watchpoint_slots()? {
+            return Err(InterfaceError::OutOfRange.into());
+        }
+        self.clear_watchpoint(index)?;
+        let offset = index as u64 * BREAKPOINT_STRIDE;
+        self.register_u64_write(
+            Armv8DebugRegisterOffset::DBGWVR_BASE_EL1 as u64 + offset,
+            aligned,
+        )?;
+        let mut dbgwcr = DBGWCR(0);
+        dbgwcr.set_BAS(bas);
+        dbgwcr.set_LSC(access as u32);
+        dbgwcr.set_PAC(DBG_PMC_EL1_EL0);
+        dbgwcr.set_E(1);
+        Ok(self.register_u32_write(
+            Armv8DebugRegisterOffset::DBGWCR_BASE_EL1 as u64 + offset,
+            dbgwcr.0,
+        )?)
+    }
+
+    pub fn clear_watchpoint(&mut self, index: usize) -> Result<(), DebugError> {
+        let offset = index as u64 * BREAKPOINT_STRIDE;
+        Ok(self.register_u32_write(Armv8DebugRegisterOffset::DBGWCR_BASE_EL1 as u64 + offset, 0)?)
+    }
+}
+
+impl<T: DebugPort + MemoryAccessPort> AArch64Register<T> for A64Target<T> {
+    fn baseaddr(&self) -> u64 {
+        self.baseaddr
+    }
+    fn dap_lock(&self) -> DapGuard<'_, T> {
+        self.dap.lock()
+    }
+}
+
+// attachする時にcoreをどう止めるか
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResetPolicy {
+    // 走っているcoreをそのままCTIで止める
+    Halt,
+    // SRSTなどでresetし、reset catchで最初の命令から止める
+    ResetCatch,
+}
+
+// coreごとのdebug registerとCTIのbase address
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CoreBase {
+    pub debug: u64,
+    pub cti: u64,
+}
+
+// Raspberry Pi 4 (BCM2711)
+pub const BCM2711_CORES: [CoreBase; 4] = [
+    CoreBase {
+        debug: 0x8001_0000,
+        cti: 0x8001_8000,
+    },
+    CoreBase {
+        debug: 0x8001_2000,
+        cti: 0x8001_A000,
+    },
+    CoreBase {
+        debug: 0x8001_4000,
+        cti: 0x8001_C000,
+    },
+    CoreBase {
+        debug: 0x8001_6000,
+        cti: 0x8001_E000,
+    },
+];
+
+// PC毎のsample数を多い順に並べる
+#[cfg(feature = "std")]
+pub fn pc_histogram(samples: &[PcSample]) -> Vec<(u64, usize)> {
+    let mut counts = std::collections::HashMap::new();
+    for sample in samples {
+        *counts.entry(sample.pc).or_insert(0) += 1;
+    }
+    let mut histogram: Vec<(u64, usize)> = counts.into_iter().collect();
+    histogram.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    histogram
+}
+
+pub struct CoreHandle<T> {
+    pub target: A64Target<T>,
+    pub cti: Cti<T>,
+    // enable_historyで有効にする
+    #[cfg(feature = "alloc")]
+    history: Option<HaltHistory>,
+}
+
+impl<T: DebugPort + MemoryAccessPort> CoreHandle<T> {
+    pub fn new(target: A64Target<T>, cti: Cti<T>) -> Self {
+        CoreHandle {
+            target,
+            cti,
+            #[cfg(feature = "alloc")]
+            history: None,
+        }
+    }
+
+    pub fn is_halted(&mut self) -> Result<bool, DapError> {
+        self.target.halted()
+    }
+
+    fn wait_halted(&mut self, halted: bool) -> Result<(), DebugError> {
+        for _ in 0..POLL_MAX {
+            if self.is_halted()? == halted {
+                return Ok(());
+            }
+        }
+        warn!(
+            "core {:#x} did not {}",
+            self.target.baseaddr,
+            if halted { "halt" } else { "resume" }
+        );
+        Err(DebugError::Timeout)
+    }
+
+    // このcoreだけを止める
+    pub fn halt(&mut self) -> Result<(), DebugError> {
+        if self.is_halted()? {
+            return Ok(());
+        }
+        self.target.halting_debug_enable()?;
+        self.cti.halt_core()?;
+        self.wait_halted(true)?;
+        self.halted();
+        Ok(())
+    }
+
+    // 1命令進めてPCを返す
+    pub fn step(&mut self) -> Result<u64, DebugError> {
+        let pc = self.target.step(&mut self.cti)?;
+        self.halted();
+        Ok(pc)
+    }
+
+    // resumeした後、breakpointなどで止まるのを待つ
+    pub fn wait_for_halt(&mut self, budget: PollBudget) -> Result<HaltReason, DebugError> {
+        let reason = self.target.wait_for_halt(budget)?;
+        self.halted();
+        Ok(reason)
+    }
+
+    // 止まった時の記録に失敗しても、haltそのものは失敗させない
+    fn halted(&mut self) {
+        #[cfg(feature = "alloc")]
+        if let Err(e) = self.record_halt() {
+            warn!(
+                "core {:#x}: failed to record halt: {}",
+                self.target.baseaddr, e
+            );
+        }
+    }
+
+    // すぐにbreakpointで止まる場合もあるので、HALTEDが落ちるのではなくSDRを待つ
+    pub fn resume(&mut self) -> Result<(), DebugError> {
+        self.cti.restart_core()?;
+        self.target.wait_edprsr(|x| x.SDR() == 1)?;
+        Ok(())
+    }
+
+    pub fn detach(&mut self) -> Result<(), DebugError> {
+        self.target.detach(&mut self.cti)
+    }
+
+    // debugできる状態にしてからpolicyに従ってcoreを止める
+    // iface_resetはResetCatchの場合だけ呼ぶ
+    pub fn attach(
+        &mut self,
+        policy: ResetPolicy,
+        iface_reset: impl FnOnce() -> Result<(), InterfaceError>,
+    ) -> Result<HaltReason, DebugError> {
+        self.target.prepare_debug(true)?;
+        self.target.check_debug_permissions()?;
+        let reason = match policy {
+            ResetPolicy::Halt => {
+                self.halt()?;
+                self.target.halt_reason()?
+            }
+            ResetPolicy::ResetCatch => self.target.halt_on_reset(iface_reset)?,
+        };
+        // breakpointでhaltさせるのに要る。resetで落ちている場合もある
+        self.target.halting_debug_enable()?;
+        Ok(reason)
+    }
+}
+
+// haltごとの状態の記録。halt/step/wait_for_haltが自動で記録する
+// gdbserverのように自分でpollしてhaltを見つけた場合はrecord_haltを呼ぶ
+#[cfg(feature = "alloc")]
+impl<T: DebugPort + MemoryAccessPort> CoreHandle<T> {
+    // 既に有効ならdepthだけ変え、記録とwatchは残す
+    pub fn enable_history(&mut self, depth: usize) {
+        match self.history.as_mut() {
+            Some(history) => history.set_depth(depth),
+            None => self.history = Some(HaltHistory::new(depth)),
+        }
+    }
+
+    pub fn disable_history(&mut self) {
+        self.history = None;
+    }
+
+    pub fn halt_history(&self) -> Option<&HaltHistory> {
+        self.history.as_ref()
+    }
+
+    // watchやskip_nextの設定に使う
+    pub fn halt_history_mut(&mut self) -> Option<&mut HaltHistory> {
+        self.history.as_mut()
+    }
+
+    // 有効でなければ何もしない。記録したらtrue
+    pub fn record_halt(&mut self) -> Result<bool, DebugError> {
+        match self.history.as_mut() {
+            Some(history) => history.capture(&mut self.target),
+            None => Ok(false),
+        }
+    }
+
+    pub fn last_diff(&self) -> Option<StateDiff> {
+        self.history.as_ref()?.last_diff()
+    }
+
+    // 新しいものから最大n個
+    pub fn history(&self, n: usize) -> Vec<&HaltRecord> {
+        self.history
+            .as_ref()
+            .map(|x| x.history(n))
+            .unwrap_or_default()
+    }
+}
+
+// dropされるまでにcommitしなければcoreをdetachする
+// host側がerrorやpanicで抜けても、coreをhaltしたまま放置しない
+pub struct DebugSession<T: DebugPort + MemoryAccessPort> {
+    core: CoreHandle<T>,
+    committed: bool,
+}
+
+impl<T: DebugPort + MemoryAccessPort> DebugSession<T> {
+    pub fn new(core: CoreHandle<T>) -> Self {
+        DebugSession {
+            core,
+            committed: false,
+        }
+    }
+
+    // coreを今の状態のまま残す
+    pub fn commit(&mut self) {
+        self.committed = true;
+    }
+
+    // dropを待たずにdetachし、errorを返す
+    pub fn close(mut self) -> Result<(), DebugError> {
+        self.committed = true;
+        self.core.detach()
+    }
+}
+
+impl<T: DebugPort + MemoryAccessPort> Deref for DebugSession<T> {
+    type Target = CoreHandle<T>;
+    fn deref(&self) -> &CoreHandle<T> {
+        &self.core
+    }
+}
+
+impl<T: DebugPort + MemoryAccessPort> DerefMut for DebugSession<T> {
+    fn deref_mut(&mut self) -> &mut CoreHandle<T> {
+        &mut self.core
+    }
+}
+
+impl<T: DebugPort + MemoryAccessPort> Drop for DebugSession<T> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        // TAPのdropと同じく、panic中はinterfaceに触らない
+        // 壊れたinterfaceで二重panicになると、元のpanicを隠してabortしてしまう
+        #[cfg(feature = "std")]
+        if std::thread::panicking() {
+            warn!(
+                "skip detaching core {:#x} on drop while panicking",
+                self.core.target.baseaddr
+            );
+            return;
+        }
+        if self.core.target.dap.lock().link_lost() {
+            warn!(
+                "skip detaching core {:#x} on drop: link is lost",
+                self.core.target.baseaddr
+            );
+            return;
+        }
+        if let Err(e) = self.core.detach() {
+            warn!(
+                "failed to detach core {:#x} on drop: {}",
+                self.core.target.baseaddr, e
+            );
+        }
+    }
+}
+
+// 複数coreをまとめて扱う
+pub struct Arm64Soc<'a, T> {
+    dap: DapHandle<T>,
+    cores: &'a [CoreBase],
+}
+
+impl<'a, T: DebugPort + MemoryAccessPort> Arm64Soc<'a, T> {
+    pub fn new(dap: DapHandle<T>, cores: &'a [CoreBase]) -> Self {
+        Arm64Soc { dap, cores }
+    }
+
+    pub fn cores(&self) -> usize {
+        self.cores.len()
+    }
+
+    pub fn core(&self, n: usize) -> CoreHandle<T> {
+        let base = self.cores[n];
+        CoreHandle::new(
+            A64Target::new(self.dap.clone(), base.debug),
+            Cti {
+                dap: self.dap.clone(),
+                baseaddr: base.cti,
+            },
+        )
+    }
+
+    // 全coreのCTIでhalt channelのgateを開け、1回のpulseをCTM経由で全coreに届ける
+    pub fn halt_all(&mut self) -> Result<(), DebugError> {
+        for n in 0..self.cores() {
+            let mut core = self.core(n);
+            core.target.halting_debug_enable()?;
+            core.cti.enable()?;
+            core.cti
+                .output_trigger_enable(CTI_TRIGGER_HALT, CTI_CHANNEL_HALT)?;
+            core.cti.channel_gate_enable(CTI_CHANNEL_HALT)?;
+        }
+        if !self.cores.is_empty() {
+            self.core(0).cti.generate_pulse(CTI_CHANNEL_HALT as u32)?;
+        }
+        for n in 0..self.cores() {
+            self.core(n).wait_halted(true)?;
+        }
+        Ok(())
+    }
+
+    pub fn resume_all(&mut self) -> Result<(), DebugError> {
+        for n in 0..self.cores() {
+            let mut cti = self.core(n).cti;
+            // haltのtriggerを落としてからrestartを送る
+            cti.output_trigger_ack_deactivate(CTI_TRIGGER_HALT)?;
+            cti.wait_output_trigger(CTI_TRIGGER_HALT, false)?;
+            cti.enable()?;
+            cti.channel_gate_disable(CTI_CHANNEL_HALT)?;
+            cti.output_trigger_disable(CTI_TRIGGER_HALT, CTI_CHANNEL_HALT)?;
+            cti.output_trigger_enable(CTI_TRIGGER_RESTART, CTI_CHANNEL_RESTART)?;
+            cti.channel_gate_enable(CTI_CHANNEL_RESTART)?;
+        }
+        if !self.cores.is_empty() {
+            self.core(0)
+                .cti
+                .generate_pulse(CTI_CHANNEL_RESTART as u32)?;
+        }
+        for n in 0..self.cores() {
+            self.core(n).wait_halted(false)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+pub(crate) mod tests {
+    use super::*;
+    use crate::jtag::dap::tests::{memap_dap, MemApSim};
+    use std::collections::{HashMap, VecDeque};
+
+    pub(crate) const DEBUG_BASE: u64 = 0x8001_0000;
+    pub(crate) const CTI_BASE: u64 = 0x8001_8000;
+
+    // EDITRに書かれた命令を記録し、register転送系の命令だけ模擬するcore
+    pub(crate) struct CoreSim {
+        pub inner: MemApSim,
+        pub editr: Vec<u32>,
+        pub x: [u64; 31],
+        pub sp: u64,
+        pub dlr: u64,
+        pub dspsr: u64,
+        pub ram: HashMap<u64, u32>,
+        pub fault: Option<u64>,
+        // 上記以外のsystem register。MRS/MSRのop0-op2の位置で引く
+        pub sysregs: HashMap<u32, u64>,
+        // 実行されたcache maintenance命令とそのaddress
+        pub maintained: Vec<(u32, u64)>,
+        // trueならCTI_BASEのCTIからrestartされた時にhaltを解く
+        pub restart_runs: bool,
+        // EDPRSRを読む度に、次に読まれる値を先頭から取り出す
+        pub edprsr_sequence: VecDeque<u32>,
+        // ATで引けるVAの4KB pageとそのPA。無いpageはPAR_EL1.Fを立てる
+        pub translations: HashMap<u64, u64>,
+    }
+
+    impl CoreSim {
+        pub fn new() -> Self {
+            let mut inner = MemApSim::new();
+            // ITEとTXfullを常に立て、全ELをAArch64にしてNon-secureのEL1でhaltさせておく
+            inner.memory.insert(
+                DEBUG_BASE + Armv8DebugRegisterOffset::EDSCR as u64,
+                (1 << 24) | (1 << 29) | (0b1111 << 10) | (1 << 18) | (1 << 8),
+            );
+            CoreSim {
+                inner,
+                editr: Vec::new(),
+                x: [0; 31],
+                sp: 0,
+                dlr: 0,
+                dspsr: 0,
+                ram: HashMap::new(),
+                fault: None,
+                sysregs: HashMap::new(),
+                maintained: Vec::new(),
+                restart_runs: false,
+                edprsr_sequence: VecDeque::new(),
+                translations: HashMap::new(),
+            }
+        }
+
+        pub fn dtr(&mut self, offset: Armv8DebugRegisterOffset) -> &mut u32 {
+            self.inner
+                .memory
+                .entry(DEBUG_BASE + offset as u64)
+                .or_insert(0)
+        }
+
+        fn run(&mut self, instruction: u32) {
+            self.editr.push(instruction);
+            let rt = (instruction & 0x1f) as usize;
+            let value = match instruction & !0x1f {
+                x if x == encode_msr(DBGDTR_EL0, 0) => {
+                    let value = self.x[rt];
+                    *self.dtr(Armv8DebugRegisterOffset::DBGDTRRX_EL0) = (value >> 32) as u32;
+                    *self.dtr(Armv8DebugRegisterOffset::DBGDTRTX_EL0) = value as u32;
+                    return;
+                }
+                x if x == encode_mrs(DBGDTR_EL0, 0) => {
+                    *self.dtr(Armv8DebugRegisterOffset::EDSCR) &= !(1 << 30);
+                    let high = *self.dtr(Armv8DebugRegisterOffset::DBGDTRTX_EL0) as u64;
+                    let low = *self.dtr(Armv8DebugRegisterOffset::DBGDTRRX_EL0) as u64;
+                    (high << 32) | low
+                }
+                x if x == encode_mrs(DLR_EL0, 0) => self.dlr,
+                x if x == encode_mrs(DSPSR_EL0, 0) => self.dspsr,
+                x if x == encode_msr(DLR_EL0, 0) => {
+                    self.dlr = self.x[rt];
+                    return;
+                }
+                x if x == encode_msr(DSPSR_EL0, 0) => {
+                    self.dspsr = self.x[rt];
+                    return;
+                }
+                _ if instruction == encode_mov_sp(0, GPR_SP) => self.sp,
+                _ if instruction == encode_mov_sp(GPR_SP, 0) => {
+                    self.sp = self.x[0];
+                    return;
+                }
+                x if x == encode_ldr_w_post4(0, 0) & !0x1f => {
+                    let address = self.x[0];
+                    if self.abort(address) {
+                        return;
+                    }
+                    self.x[0] += 4;
+                    *self.ram.get(&address).unwrap_or(&0) as u64
+                }
+                x if x == encode_str_w_post4(0, 0) & !0x1f => {
+                    let address = self.x[0];
+                    if self.abort(address) {
+                        return;
+                    }
+                    self.ram.insert(address, self.x[rt] as u32);
+                    self.x[0] += 4;
+                    return;
+                }
+                x if x & 0xFFF0_0000 == 0xD530_0000 => {
+                    *self.sysregs.get(&((x >> 5) & 0x7fff)).unwrap_or(&0)
+                }
+                x if x & 0xFFF0_0000 == 0xD510_0000 => {
+                    self.sysregs.insert((x >> 5) & 0x7fff, self.x[rt]);
+                    return;
+                }
+                x if x & !(7 << 16) == encode_at_s1r(1, 0) => {
+                    let va = self.x[rt];
+                    let par = match self.translations.get(&(va & !0xfff)) {
+                        Some(pa) => *pa,
+                        None => 1,
+                    };
+                    let key = (encode_mrs(SysReg::PAR_EL1, 0) >> 5) & 0x7fff;
+                    self.sysregs.insert(key, par);
+                    return;
+                }
+                // DC/IC (SYS)はaddressだけ記録する
+                x if x & 0xFFF8_0000 == 0xD508_0000 => {
+                    self.maintained.push((instruction & !0x1f, self.x[rt]));
+                    return;
+                }
+                _ if instruction == DSB_ISH || instruction == ISB => return,
+                // DCPSnはELnより低い時だけ上げる。EL3はSecure
+                0xD4A0_0000 => {
+                    let edscr = self.dtr(Armv8DebugRegisterOffset::EDSCR);
+                    let el = core::cmp::max((*edscr >> 8) & 3, instruction & 3);
+                    *edscr = (*edscr & !(3 << 8)) | (el << 8);
+                    if el == 3 {
+                        *edscr &= !(1 << 18);
+                    }
+                    return;
+                }
+                _ if instruction == DRPS => {
+                    let el = (self.dspsr as u32 >> 2) & 3;
+                    let edscr = self.dtr(Armv8DebugRegisterOffset::EDSCR);
+                    *edscr = (*edscr & !(3 << 8)) | (el << 8) | (1 << 18);
+                    return;
+                }
+                _ => panic!("unexpected instruction {:#010x}", instruction),
+            };
+            self.x[rt] = value;
+        }
+
+        // faultを指定したaddressへのaccessはEDSCR.ERRを立てる
+        fn abort(&mut self, address: u64) -> bool {
+            if self.fault != Some(address) {
+                return false;
+            }
+            *self.dtr(Armv8DebugRegisterOffset::EDSCR) |= 1 << 6;
+            true
+        }
+    }
+
+    impl DapInterface for CoreSim {
+        fn apacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            let edesr = DEBUG_BASE + Armv8DebugRegisterOffset::EDESR as u64;
+            let edprsr = DEBUG_BASE + Armv8DebugRegisterOffset::EDPRSR as u64;
+            let pending = self.inner.memory.get(&edesr).copied().unwrap_or(0);
+            let edscr = *self.dtr(Armv8DebugRegisterOffset::EDSCR);
+            let dtrrx = DEBUG_BASE + Armv8DebugRegisterOffset::DBGDTRRX_EL0 as u64;
+            let received = self.inner.memory.get(&dtrrx).copied().unwrap_or(0);
+            let (reads, writes) = (self.inner.reads.len(), self.inner.writes.len());
+            let result = self.inner.apacc(data, a, rnw)?;
+            // EDESRは1を書いたbitだけが落ちる
+            if self.inner.writes.len() > writes && self.inner.writes[writes].0 == edesr {
+                let clear = self.inner.writes[writes].1;
+                self.inner.memory.insert(edesr, pending & !clear);
+            }
+            // RXfullのままDBGDTRRXを書くとRXOになり、値は捨てられる
+            if self.inner.writes.len() > writes && self.inner.writes[writes].0 == dtrrx {
+                if EDSCR(edscr).RXfull() == 1 {
+                    self.inner.memory.insert(dtrrx, received);
+                    *self.dtr(Armv8DebugRegisterOffset::EDSCR) |= 1 << 27;
+                } else {
+                    *self.dtr(Armv8DebugRegisterOffset::EDSCR) |= 1 << 30;
+                }
+            }
+            if self.inner.reads.len() > reads && self.inner.reads[reads] == edprsr {
+                if let Some(next) = self.edprsr_sequence.pop_front() {
+                    self.inner.memory.insert(edprsr, next);
+                }
+            }
+            let editr = DEBUG_BASE + Armv8DebugRegisterOffset::EDITR as u64;
+            if let Some(instruction) = self.inner.memory.remove(&editr) {
+                self.run(instruction);
+            }
+            let edrcr = DEBUG_BASE + Armv8DebugRegisterOffset::EDRCR as u64;
+            if let Some(value) = self.inner.memory.remove(&edrcr) {
+                // ERR, RXO, TXUを落とす
+                if EDRCR(value).CSE() == 1 {
+                    *self.dtr(Armv8DebugRegisterOffset::EDSCR) &= !((1 << 6) | (3 << 26));
+                }
+            }
+            // OSLAR.OSLKがEDPRSR.OSLKに反映される
+            let oslar = DEBUG_BASE + Armv8DebugRegisterOffset::OSLAR_EL1 as u64;
+            if let Some(value) = self.inner.memory.remove(&oslar) {
+                let edprsr = self.dtr(Armv8DebugRegisterOffset::EDPRSR);
+                *edprsr = (*edprsr & !(1 << 5)) | ((value & 1) << 5);
+            }
+            let pulse = CTI_BASE + CtiOffset::CTIAPPPULSE as u64;
+            if self.restart_runs
+                && self.inner.memory.get(&pulse) == Some(&(1 << CTI_CHANNEL_RESTART))
+            {
+                self.inner.memory.remove(&pulse);
+                let edprsr = self.dtr(Armv8DebugRegisterOffset::EDPRSR);
+                *edprsr = (*edprsr & !(1 << 4)) | (1 << 11);
+            }
+            Ok(result)
+        }
+        fn dpacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            self.inner.dpacc(data, a, rnw)
+        }
+    }
+
+    #[test]
+    fn encoder_test() {
+        assert_eq!(0xD513_0405, encode_msr(DBGDTR_EL0, 5));
+        assert_eq!(0xD533_041E, encode_mrs(DBGDTR_EL0, 30));
+        assert_eq!(0xD53B_4520, encode_mrs(DLR_EL0, 0));
+        assert_eq!(0xD51B_4520, encode_msr(DLR_EL0, 0));
+        assert_eq!(0xD53B_4500, encode_mrs(DSPSR_EL0, 0));
+        assert_eq!(0xD51B_4500, encode_msr(DSPSR_EL0, 0));
+        // mov x0, sp / mov sp, x0
+        assert_eq!(0x9100_03E0, encode_mov_sp(0, GPR_SP));
+        assert_eq!(0x9100_001F, encode_mov_sp(GPR_SP, 0));
+    }
+
+    #[test]
+    fn gpr_test() {
+        let dap = DapHandle::new(memap_dap(CoreSim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        target.write_gpr(5, 0x1122_3344_5566_7788).unwrap();
+        assert_eq!(0x1122_3344_5566_7788, dap.lock().dp.x[5]);
+        assert_eq!(vec![0xD533_0405], dap.lock().dp.editr);
+
+        dap.lock().dp.x[30] = 0xdead_beef_0000_0001;
+        dap.lock().dp.editr.clear();
+        assert_eq!(0xdead_beef_0000_0001, target.read_gpr(30).unwrap());
+        assert_eq!(vec![0xD513_041E], dap.lock().dp.editr);
+    }
+
+    #[test]
+    fn dtr_test() {
+        let dap = DapHandle::new(memap_dap(CoreSim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        let dtrrx = Armv8DebugRegisterOffset::DBGDTRRX_EL0 as u64;
+        let edscr = |dap: &DapHandle<DAP<CoreSim>>| {
+            let mut guard = dap.lock();
+            EDSCR(*guard.dp.dtr(Armv8DebugRegisterOffset::EDSCR))
+        };
+
+        // 上位をDTRTX、下位をDTRRXに書き、最後のRXでRXfullが立つ
+        target.dtr_write_u64(0x1122_3344_5566_7788).unwrap();
+        assert_eq!(1, edscr(&dap).RXfull());
+        target.exec_insn(encode_mrs(DBGDTR_EL0, 3)).unwrap();
+        assert_eq!(0x1122_3344_5566_7788, dap.lock().dp.x[3]);
+        assert_eq!(0, edscr(&dap).RXfull());
+
+        // coreが読まないままならDBGDTRRXに書かずにtimeoutする
+        target.dtr_write_u64(1).unwrap();
+        let writes = dap.lock().dp.inner.writes.len();
+        assert_eq!(Err(DebugError::Timeout), target.dtr_write_u64(2));
+        assert_eq!(writes, dap.lock().dp.inner.writes.len());
+        assert_eq!(0, edscr(&dap).RXO());
+
+        // RXfullを確認せずに書くとRXOが立ち、次の転送で検出してEDRCR.CSEで落とす
+        target.register_u32_write(dtrrx, 3).unwrap();
+        assert_eq!(1, edscr(&dap).RXO());
+        target.exec_insn(encode_mrs(DBGDTR_EL0, 3)).unwrap();
+        assert_eq!(1, dap.lock().dp.x[3]);
+        assert_eq!(Err(DebugError::DtrOverrun), target.dtr_write_u64(4));
+        assert_eq!(0, edscr(&dap).RXO());
+        target.exec_insn(encode_mrs(DBGDTR_EL0, 3)).unwrap();
+        target.write_gpr(3, 5).unwrap();
+        assert_eq!(5, dap.lock().dp.x[3]);
+
+        // TXU
+        dap.lock().dp.x[4] = 0xcafe_f00d_0000_0001;
+        target.exec_insn(encode_msr(DBGDTR_EL0, 4)).unwrap();
+        *dap.lock().dp.dtr(Armv8DebugRegisterOffset::EDSCR) |= 1 << 26;
+        assert_eq!(Err(DebugError::DtrUnderrun), target.dtr_read_u64());
+        assert_eq!(0, edscr(&dap).TXU());
+        assert_eq!(0xcafe_f00d_0000_0001, target.read_gpr(4).unwrap());
+    }
+
+    #[test]
+    fn sp_pc_test() {
+        let dap = DapHandle::new(memap_dap(CoreSim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        dap.lock().dp.x[0] = 0x0123_4567_89ab_cdef;
+
+        target.write_gpr(GPR_SP, 0xffff_0000_0008_0000).unwrap();
+        assert_eq!(0xffff_0000_0008_0000, dap.lock().dp.sp);
+        assert_eq!(0xffff_0000_0008_0000, target.read_gpr(GPR_SP).unwrap());
+        target.write_pc(0x4008_0000).unwrap();
+        assert_eq!(0x4008_0000, dap.lock().dp.dlr);
+        assert_eq!(0x4008_0000, target.read_pc().unwrap());
+        target.write_cpsr(0x3c5).unwrap();
+        assert_eq!(0x3c5, target.read_cpsr().unwrap());
+
+        // scratchに使ったx0は元に戻っている
+        assert_eq!(0x0123_4567_89ab_cdef, dap.lock().dp.x[0]);
+        dap.lock().dp.editr.clear();
+        target.read_pc().unwrap();
+        assert_eq!(
+            vec![
+                encode_msr(DBGDTR_EL0, 0),
+                encode_mrs(DLR_EL0, 0),
+                encode_msr(DBGDTR_EL0, 0),
+                encode_mrs(DBGDTR_EL0, 0),
+            ],
+            dap.lock().dp.editr
+        );
+    }
+
+    #[test]
+    fn sysreg_test() {
+        let dap = DapHandle::new(memap_dap(CoreSim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        assert_eq!(0xD538_0000, encode_mrs(SysReg::MIDR_EL1, 0));
+        assert_eq!(0xD518_1000, encode_msr(SysReg::SCTLR_EL1, 0));
+        dap.lock().dp.x[0] = 0x0123_4567_89ab_cdef;
+
+        target.msr(SysReg::SCTLR_EL1, 0x30d0_1805).unwrap();
+        assert_eq!(0x30d0_1805, target.read_sysreg(3, 0, 1, 0, 0).unwrap());
+        target.write_sysreg(3, 0, 5, 2, 0, 0x9600_0045).unwrap();
+        assert_eq!(0x9600_0045, target.mrs(SysReg::ESR_EL1).unwrap());
+        assert_eq!(0x0123_4567_89ab_cdef, dap.lock().dp.x[0]);
+
+        dap.lock().dp.editr.clear();
+        target.mrs(SysReg::TTBR0_EL1).unwrap();
+        assert_eq!(
+            vec![
+                encode_msr(DBGDTR_EL0, 0),
+                0xD538_2000,
+                encode_msr(DBGDTR_EL0, 0),
+                encode_mrs(DBGDTR_EL0, 0),
+            ],
+            dap.lock().dp.editr
+        );
+
+        // op0が0/1のencodingは命令を実行せずに弾く
+        dap.lock().dp.editr.clear();
+        assert_eq!(
+            Err(DebugError::Interface(InterfaceError::OutOfRange)),
+            target.read_sysreg(1, 0, 7, 5, 1)
+        );
+        assert_eq!(
+            Err(DebugError::Interface(InterfaceError::OutOfRange)),
+            target.write_sysreg(0, 0, 1, 0, 0, 0)
+        );
+        assert_eq!(
+            Err(DebugError::Interface(InterfaceError::OutOfRange)),
+            target.read_sysreg(3, 8, 0, 0, 0)
+        );
+        assert!(dap.lock().dp.editr.is_empty());
+    }
+
+    #[test]
+    fn translate_test() {
+        let dap = DapHandle::new(memap_dap(CoreSim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        assert_eq!(0xD508_7800, encode_at_s1r(1, 0));
+        assert_eq!(0xD50C_7801, encode_at_s1r(2, 1));
+        assert_eq!(0xD50E_7800, encode_at_s1r(3, 0));
+        let par = (encode_mrs(SysReg::PAR_EL1, 0) >> 5) & 0x7fff;
+        dap.lock().dp.sysregs.insert(par, 0x55);
+        dap.lock().dp.x[0] = 0x1111;
+        dap.lock()
+            .dp
+            .translations
+            .insert(0xffff_0000_0008_0000, 0x4008_0000);
+
+        assert!(!target.mmu_enabled().unwrap());
+        target.msr(SysReg::SCTLR_EL1, 0x30d0_1805).unwrap();
+        assert!(target.mmu_enabled().unwrap());
+
+        // pageの中のoffsetはVAのまま。PAR_EL1とx0は戻す
+        assert_eq!(
+            Some(0x4008_0123),
+            target.translate(0xffff_0000_0008_0123).unwrap()
+        );
+        assert_eq!(None, target.translate(0xffff_0000_0009_0000).unwrap());
+        assert_eq!(Some(&0x55), dap.lock().dp.sysregs.get(&par));
+        assert_eq!(0x1111, dap.lock().dp.x[0]);
+
+        // EL0ではATを実行できない
+        *dap.lock().dp.dtr(Armv8DebugRegisterOffset::EDSCR) &= !(3 << 8);
+        assert!(matches!(
+            target.translate(0),
+            Err(DebugError::InsufficientPrivilege { current_el: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn privilege_test() {
+        let dap = DapHandle::new(memap_dap(CoreSim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        assert_eq!(0xD4A0_0001, encode_dcps(1));
+        assert_eq!(0xD4A0_0002, encode_dcps(2));
+        assert_eq!(0xD4A0_0003, encode_dcps(3));
+        assert_eq!(1, target.current_el().unwrap());
+        assert!(!target.is_secure().unwrap());
+        assert_eq!(0, SysReg::CTR_EL0.min_el());
+        assert_eq!(1, SysReg::SCTLR_EL1.min_el());
+        assert_eq!(2, SysReg::HCR_EL2.min_el());
+        assert_eq!(3, SysReg::SCR_EL3.min_el());
+
+        // EL1からEL2/EL3のregisterには触らずに弾く
+        dap.lock().dp.editr.clear();
+        assert_eq!(
+            Err(DebugError::InsufficientPrivilege {
+                current_el: 1,
+                secure: false
+            }),
+            target.mrs(SysReg::HCR_EL2)
+        );
+        assert_eq!(
+            Err(DebugError::InsufficientPrivilege {
+                current_el: 1,
+                secure: false
+            }),
+            target.msr(SysReg::SCR_EL3, 0)
+        );
+        assert!(dap.lock().dp.editr.is_empty());
+
+        target.dcps(2).unwrap();
+        assert_eq!(vec![encode_dcps(2)], dap.lock().dp.editr);
+        assert_eq!(2, target.current_el().unwrap());
+        target.msr(SysReg::HCR_EL2, 0x8000_0000).unwrap();
+        assert_eq!(0x8000_0000, target.mrs(SysReg::HCR_EL2).unwrap());
+        assert!(target.mrs(SysReg::SCTLR_EL3).is_err());
+
+        target.dcps(3).unwrap();
+        assert_eq!(3, target.current_el().unwrap());
+        assert!(target.is_secure().unwrap());
+        target.mrs(SysReg::SCR_EL3).unwrap();
+
+        // DSPSRのEL1に戻る
+        dap.lock().dp.dspsr = 0b0101;
+        dap.lock().dp.editr.clear();
+        target.drps().unwrap();
+        assert_eq!(vec![DRPS], dap.lock().dp.editr);
+        assert_eq!(1, target.current_el().unwrap());
+        assert!(!target.is_secure().unwrap());
+
+        assert_eq!(
+            Err(DebugError::Interface(InterfaceError::OutOfRange)),
+            target.dcps(0)
+        );
+        assert_eq!(
+            Err(DebugError::Interface(InterfaceError::OutOfRange)),
+            target.dcps(4)
+        );
+    }
+
+    #[test]
+    fn secure_debug_disabled_test() {
+        let mut sim = CoreSim::new();
+        // SDDが立った状態でNon-secureのEL2にいる
+        let edscr = sim.dtr(Armv8DebugRegisterOffset::EDSCR);
+        *edscr = (*edscr & !(3 << 8)) | (1 << 16) | (2 << 8);
+        let dap = DapHandle::new(memap_dap(sim));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        let refused = DebugError::InsufficientPrivilege {
+            current_el: 2,
+            secure: true,
+        };
+
+        dap.lock().dp.editr.clear();
+        assert_eq!(Err(refused), target.dcps(3));
+        assert_eq!(Err(refused), target.mrs(SysReg::SCR_EL3));
+        assert!(dap.lock().dp.editr.is_empty());
+        assert!(format!("{}", refused).contains("EDSCR.SDD"));
+        // EL2までは使える
+        target.mrs(SysReg::HCR_EL2).unwrap();
+
+        // EL0ではDRPSはUNDEFINED
+        *dap.lock().dp.dtr(Armv8DebugRegisterOffset::EDSCR) &= !(3 << 8);
+        dap.lock().dp.editr.clear();
+        assert_eq!(
+            Err(DebugError::InsufficientPrivilege {
+                current_el: 0,
+                secure: false
+            }),
+            target.drps()
+        );
+        assert!(dap.lock().dp.editr.is_empty());
+    }
+
+    #[test]
+    fn register_u64_test() {
+        let dap = DapHandle::new(memap_dap(MemApSim::new()));
+        let mut target = A64Target::new(dap.clone(), 0x8001_0000);
+        // BD3とその次の窓にまたがる
+        target
+            .register_u64_write(0x40C, 0x1234_5678_9abc_def0)
+            .unwrap();
+        assert_eq!(
+            0x1234_5678_9abc_def0,
+            target.register_u64_read(0x40C).unwrap()
+        );
+        let memory = &dap.lock().dp.memory;
+        assert_eq!(Some(&0x9abc_def0), memory.get(&0x8001_040C));
+        assert_eq!(Some(&0x1234_5678), memory.get(&0x8001_0410));
+    }
+
+    #[test]
+    fn pmu_test() {
+        const PMU_BASE: u64 = 0x8003_0000;
+        let mut sim = MemApSim::new();
+        // N=6
+        sim.memory.insert(PMU_BASE + 0xE04, 6 << 11);
+        sim.memory.insert(PMU_BASE + 0x0F8, 0x89ab_cdef);
+        sim.memory.insert(PMU_BASE + 0x0FC, 0x0000_0012);
+        sim.memory.insert(PMU_BASE + 0x018, 1234);
+        sim.memory.insert(PMU_BASE + 0x028, 5678);
+        sim.memory.insert(PMU_BASE + 0xC80, (1 << 31) | (1 << 3));
+        let dap = DapHandle::new(memap_dap(sim));
+        let mut pmu = Pmu {
+            dap: dap.clone(),
+            baseaddr: PMU_BASE,
+        };
+
+        assert_eq!(1234, pmu.pmevcntr_read(3).unwrap());
+        assert_eq!(0x12_89ab_cdef, pmu.pmccntr_read().unwrap());
+        assert_eq!(
+            Err(InterfaceError::OutOfRange.into()),
+            pmu.pmevcntr_read(PMU_EVENT_COUNTERS_MAX)
+        );
+        assert_eq!(
+            Err(InterfaceError::OutOfRange.into()),
+            pmu.configure_event(6, 0x11)
+        );
+
+        dap.lock().dp.writes.clear();
+        pmu.configure_event(5, 0x11).unwrap();
+        pmu.enable_cycle_counter().unwrap();
+        assert_eq!(
+            vec![
+                (PMU_BASE + 0x414, 0x11),
+                (PMU_BASE + 0xC00, 1 << 5),
+                (PMU_BASE + 0xE04, (6 << 11) | 1),
+                (PMU_BASE + 0xE04, (6 << 11) | (1 << 6) | 1),
+                (PMU_BASE + 0xC00, 1 << 31),
+            ],
+            dap.lock().dp.writes
+        );
+
+        dap.lock().dp.writes.clear();
+        let snapshot = pmu.read_counters().unwrap();
+        assert_eq!(0x12_89ab_cdef, snapshot.cycles);
+        assert_eq!(&[0, 0, 0, 1234, 0, 5678], snapshot.events());
+        assert!(snapshot.cycle_overflowed());
+        assert!(snapshot.event_overflowed(3));
+        assert!(!snapshot.event_overflowed(5));
+        // 読んだoverflowだけを書いて落とす
+        assert_eq!(
+            vec![(PMU_BASE + 0xC80, (1 << 31) | (1 << 3))],
+            dap.lock().dp.writes
+        );
+        assert_eq!(
+            "PMCR{IMP=0x00 N=6 LC E}",
+            pmu.pmcr_read().unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn display_test() {
+        assert_eq!(
+            "EDSCR{STATUS=0b010011(ExternalDebugRequest) EL=1 NS RW=0b1111 HDE ITE}",
+            EDSCR(0x0104_7d13).to_string()
+        );
+        assert_eq!(
+            "EDSCR{STATUS=0b000010(Running) EL=0 RW=0b0000 TXfull}",
+            EDSCR(0x2000_0002).to_string()
+        );
+        assert_eq!(
+            "EDSCR{STATUS=0b111111(Unknown(63)) EL=3 SDD RW=0b0001 ERR}",
+            EDSCR(0x0001_077f).to_string()
+        );
+        assert_eq!("EDPRSR{PU R HALTED SDR}", EDPRSR(0x0000_0815).to_string());
+        assert_eq!("EDPRCR{COREPURQ CORENPDRQ}", EDPRCR(0b1001).to_string());
+        assert_eq!("EDECR{SS RCE}", EDECR(0b110).to_string());
+        assert_eq!("EDECCR{NSE=0b0100 SE=0b0100}", EDECCR(0x44).to_string());
+        assert_eq!(
+            "DBGBCR{E BAS=0b1111 PMC=0b11}",
+            DBGBCR(0x0000_01e7).to_string()
+        );
+        assert_eq!(
+            "EDDFR{CTX_CMPs=1 WRPs=3 BRPs=5 PMUVer=4 TraceVer=0}",
+            EDDFR(0x1030_5400).to_string()
+        );
+    }
+
+    #[test]
+    fn prepare_debug_test() {
+        let edprsr = DEBUG_BASE + Armv8DebugRegisterOffset::EDPRSR as u64;
+        let edprcr = DEBUG_BASE + Armv8DebugRegisterOffset::EDPRCR as u64;
+        let oslar = DEBUG_BASE + Armv8DebugRegisterOffset::OSLAR_EL1 as u64;
+        let prepare = |sim: CoreSim, value: u32, keep_powered: bool| {
+            let dap = DapHandle::new(memap_dap(sim));
+            dap.lock().dp.inner.memory.insert(edprsr, value);
+            let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+            let result = target.prepare_debug(keep_powered);
+            let memory = dap.lock().dp.inner.memory.clone();
+            (result, memory)
+        };
+
+        // PU, OSLK: OS Lockを外す
+        let (result, memory) = prepare(CoreSim::new(), 0b10_0001, true);
+        assert_eq!(Ok(()), result);
+        assert_eq!(Some(&0b1), memory.get(&edprsr));
+        assert_eq!(Some(&0b1), memory.get(&edprcr));
+
+        // 電源が落ちている
+        let (result, memory) = prepare(CoreSim::new(), 0b10_0000, true);
+        assert_eq!(Err(DebugError::CorePoweredDown), result);
+        assert_eq!(None, memory.get(&edprcr));
+
+        // Double Lock中はOSLARに書かない
+        let (result, memory) = prepare(CoreSim::new(), 0b110_0001, false);
+        assert_eq!(Err(DebugError::DoubleLocked), result);
+        assert_eq!(Some(&0b110_0001), memory.get(&edprsr));
+
+        // OSLARへの書き込みが無視される
+        let dap = DapHandle::new(memap_dap(MemApSim::new()));
+        dap.lock().dp.memory.insert(edprsr, 0b10_0001);
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        assert_eq!(Err(DebugError::OsLocked), target.prepare_debug(false));
+        assert_eq!(Some(&0), dap.lock().dp.memory.get(&oslar));
+        assert_eq!(None, dap.lock().dp.memory.get(&edprcr));
+    }
+
+    #[test]
+    fn halt_reason_test() {
+        let documented = [
+            (0x01, HaltReason::Restarting),
+            (0x02, HaltReason::Running),
+            (0x07, HaltReason::Breakpoint),
+            (0x13, HaltReason::ExternalDebugRequest),
+            (0x1B, HaltReason::HaltStepNormal),
+            (0x1F, HaltReason::HaltStepExclusive),
+            (0x23, HaltReason::OSUnlockCatch),
+            (0x27, HaltReason::ResetCatch),
+            (0x2B, HaltReason::WatchpointMatch),
+            (0x2F, HaltReason::HltInstruction),
+            (0x33, HaltReason::SoftwareAccess),
+            (0x37, HaltReason::ExceptionCatch),
+            (0x3B, HaltReason::HaltStepNoSyndrome),
+        ];
+        for (status, reason) in documented.iter() {
+            assert_eq!(*reason, HaltReason::from_status(*status));
+            assert_eq!(*status > 0x02, reason.is_halted());
+        }
+        assert_eq!(HaltReason::Unknown(0x00), HaltReason::from_status(0x00));
+        assert_eq!(HaltReason::Unknown(0x3F), HaltReason::from_status(0x3F));
+
+        let dap = DapHandle::new(memap_dap(MemApSim::new()));
+        let mut target = A64Target::new(dap.clone(), 0x8001_0000);
+        // EL2, Non-secure, exception catch
+        let edscr = 0x8001_0000 + Armv8DebugRegisterOffset::EDSCR as u64;
+        dap.lock()
+            .dp
+            .memory
+            .insert(edscr, (1 << 18) | (2 << 8) | 0x37);
+        assert_eq!(
+            HaltStatus {
+                reason: HaltReason::ExceptionCatch,
+                el: 2,
+                non_secure: true,
+                secure_debug_disabled: false,
+            },
+            target.halt_status().unwrap()
+        );
+        dap.lock()
+            .dp
+            .memory
+            .insert(edscr, (1 << 16) | (3 << 8) | 0x13);
+        let status = target.halt_status().unwrap();
+        assert_eq!(HaltReason::ExternalDebugRequest, status.reason);
+        assert_eq!(
+            (3, false, true),
+            (status.el, status.non_secure, status.secure_debug_disabled)
+        );
+    }
+
+    #[test]
+    fn catch_test() {
+        let dap = DapHandle::new(memap_dap(MemApSim::new()));
+        let mut target = A64Target::new(dap.clone(), 0x8001_0000);
+        let edecr = 0x8001_0000 + Armv8DebugRegisterOffset::EDECR as u64;
+        let edeccr = 0x8001_0000 + Armv8DebugRegisterOffset::EDECCR as u64;
+        // EDECR.SSは残す
+        dap.lock().dp.memory.insert(edecr, 1 << 2);
+        target.enable_reset_catch().unwrap();
+        assert_eq!(Some(&0b110), dap.lock().dp.memory.get(&edecr));
+        target.disable_reset_catch().unwrap();
+        assert_eq!(Some(&0b100), dap.lock().dp.memory.get(&edecr));
+
+        // EL2への例外でhalt。reservedのbit 0は落とす
+        target.enable_exception_catch(0b0101).unwrap();
+        assert_eq!(Some(&0x44), dap.lock().dp.memory.get(&edeccr));
+        assert_eq!(0b0100, target.edeccr_read().unwrap().NSE());
+        target.disable_exception_catch().unwrap();
+        assert_eq!(Some(&0), dap.lock().dp.memory.get(&edeccr));
+    }
+
+    // BRPs=6, WRPs=4
+    fn debug_target(dap: &DapHandle<DAP<MemApSim>>) -> A64Target<DAP<MemApSim>> {
+        let eddfr = 0x8001_0000 + Armv8DebugRegisterOffset::EDDFR as u64;
+        dap.lock().dp.memory.insert(eddfr, (3 << 20) | (5 << 12));
+        A64Target::new(dap.clone(), 0x8001_0000)
+    }
+
+    #[test]
+    fn breakpoint_test() {
+        let dap = DapHandle::new(memap_dap(MemApSim::new()));
+        let mut target = debug_target(&dap);
+        assert_eq!(6, target.breakpoint_slots().unwrap());
+        target.set_breakpoint(1, 0xffff_0000_4008_0000).unwrap();
+        let enabled = (0b1111 << 5) | (0b11 << 1) | 1;
+        {
+            let memory = &dap.lock().dp.memory;
+            assert_eq!(Some(&0x4008_0000), memory.get(&0x8001_0410));
+            assert_eq!(Some(&0xffff_0000), memory.get(&0x8001_0414));
+            assert_eq!(Some(&enabled), memory.get(&0x8001_0418));
+        }
+        // 同じindexは上書きする
+        target.set_breakpoint(1, 0x4008_1000).unwrap();
+        assert_eq!(Some(&0x4008_1000), dap.lock().dp.memory.get(&0x8001_0410));
+        assert_eq!(Some(&0), dap.lock().dp.memory.get(&0x8001_0414));
+        assert_eq!(
+            Some(DebugError::Interface(InterfaceError::OutOfRange)),
+            target.set_breakpoint(6, 0x4008_0000).err()
+        );
+        target.clear_breakpoint(1).unwrap();
+        assert_eq!(Some(&0), dap.lock().dp.memory.get(&0x8001_0418));
+    }
+
+    #[test]
+    fn watchpoint_bas_test() {
+        assert_eq!(Ok((0x1000, 0b1111_1111)), watchpoint_bas(0x1000, 8));
+        assert_eq!(Ok((0x1000, 0b0000_1111)), watchpoint_bas(0x1000, 4));
+        assert_eq!(Ok((0x1000, 0b0011_0000)), watchpoint_bas(0x1004, 2));
+        assert_eq!(Ok((0x1000, 0b1000_0000)), watchpoint_bas(0x1007, 1));
+        assert_eq!(Ok((0x1008, 0b0000_1110)), watchpoint_bas(0x1009, 3));
+        // doublewordをまたぐ
+        assert_eq!(
+            Err(InterfaceError::OutOfRange.into()),
+            watchpoint_bas(0x1006, 4)
+        );
+        assert_eq!(
+            Err(InterfaceError::OutOfRange.into()),
+            watchpoint_bas(0x1000, 16)
+        );
+        assert_eq!(
+            Err(InterfaceError::OutOfRange.into()),
+            watchpoint_bas(0x1000, 0)
+        );
+    }
+
+    #[test]
+    fn watchpoint_test() {
+        let dap = DapHandle::new(memap_dap(MemApSim::new()));
+        let mut target = debug_target(&dap);
+        assert_eq!(4, target.watchpoint_slots().unwrap());
+        target
+            .set_watchpoint(2, 0x4010_0003, 2, WatchKind::Store)
+            .unwrap();
+        let mut dbgwcr = DBGWCR(0);
+        dbgwcr.set_BAS(0b0001_1000);
+        dbgwcr.set_LSC(0b10);
+        dbgwcr.set_PAC(0b11);
+        dbgwcr.set_E(1);
+        {
+            let memory = &dap.lock().dp.memory;
+            assert_eq!(Some(&0x4010_0000), memory.get(&0x8001_0820));
+            assert_eq!(Some(&dbgwcr.0), memory.get(&0x8001_0828));
+        }
+        assert_eq!(
+            Some(DebugError::Interface(InterfaceError::OutOfRange)),
+            target
+                .set_watchpoint(4, 0x4010_0000, 4, WatchKind::Access)
+                .err()
+        );
+        target.clear_watchpoint(2).unwrap();
+        assert_eq!(Some(&0), dap.lock().dp.memory.get(&0x8001_0828));
+    }
+
+    #[test]
+    fn mem_access_test() {
+        let dap = DapHandle::new(memap_dap(CoreSim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        dap.lock().dp.x[0] = 0x1111;
+        dap.lock().dp.x[1] = 0x2222;
+        dap.lock().dp.ram.insert(0x4000_0000, 0x4433_2211);
+        dap.lock().dp.ram.insert(0x4000_0004, 0x8877_6655);
+        dap.lock().dp.ram.insert(0x4000_0008, 0xccbb_aa99);
+
+        let mut buf = [0; 6];
+        target.mem_read(0x4000_0003, &mut buf).unwrap();
+        assert_eq!([0x44, 0x55, 0x66, 0x77, 0x88, 0x99], buf);
+
+        // 端数のbyteは周りを壊さない
+        target.mem_write(0x4000_0002, &[0xde, 0xad, 0xbe]).unwrap();
+        {
+            let core = &dap.lock().dp;
+            assert_eq!(Some(&0xadde_2211), core.ram.get(&0x4000_0000));
+            assert_eq!(Some(&0x8877_66be), core.ram.get(&0x4000_0004));
+            assert_eq!(Some(&0xccbb_aa99), core.ram.get(&0x4000_0008));
+            assert_eq!(0x1111, core.x[0]);
+            assert_eq!(0x2222, core.x[1]);
+        }
+
+        target
+            .mem_write(0x4000_0010, &[1, 2, 3, 4, 5, 6, 7, 8])
+            .unwrap();
+        let mut buf = [0; 8];
+        target.mem_read(0x4000_0010, &mut buf).unwrap();
+        assert_eq!([1, 2, 3, 4, 5, 6, 7, 8], buf);
+    }
+
+    #[test]
+    fn mem_write_guard_test() {
+        let dap = DapHandle::new(memap_dap(CoreSim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        let rom = AddrRange::new(0x4000_0000, 0x10).unwrap();
+        dap.lock()
+            .set_write_guard(WriteGuard::new().deny("rom", rom));
+        dap.lock().dp.ram.insert(0x4000_000c, 0x4433_2211);
+
+        // coreのstoreもDAPのguardで断り、x0/x1も触らない
+        let editr = dap.lock().dp.editr.len();
+        assert_eq!(
+            Err(DebugError::Dap(DapError::WriteProtected {
+                addr: 0x4000_000f,
+                region: rom
+            })),
+            target.mem_write(0x4000_000f, &[0; 2])
+        );
+        assert_eq!(editr, dap.lock().dp.editr.len());
+        assert_eq!(Some(&0x4433_2211), dap.lock().dp.ram.get(&0x4000_000c));
+
+        let mut buf = [0; 4];
+        target.mem_read(0x4000_000c, &mut buf).unwrap();
+        assert_eq!([0x11, 0x22, 0x33, 0x44], buf);
+        target.mem_write(0x4000_0010, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(Some(&0x0403_0201), dap.lock().dp.ram.get(&0x4000_0010));
+    }
+
+    #[test]
+    fn cache_maintenance_test() {
+        let dap = DapHandle::new(memap_dap(CoreSim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        assert_eq!(0xD50B_7E20, encode_dc_civac(0));
+        assert_eq!(0xD50B_7521, encode_ic_ivau(1));
+        // DminLine = IminLine = 4 (64byte)
+        let ctr = (encode_mrs(SysReg::CTR_EL0, 0) >> 5) & 0x7fff;
+        dap.lock().dp.sysregs.insert(ctr, 0x8444_c004);
+        dap.lock().dp.x[0] = 0x1111;
+        dap.lock().dp.x[1] = 0x2222;
+        assert_eq!((64, 64), target.cache_line_sizes().unwrap());
+
+        // lineに揃っていれば100byteは2line
+        target.clean_invalidate_dcache_range(0x8_0000, 100).unwrap();
+        assert_eq!(
+            vec![(0xD50B_7E20, 0x8_0000), (0xD50B_7E20, 0x8_0040)],
+            dap.lock().dp.maintained
+        );
+        // 揃っていなければ前後に広げて3line
+        dap.lock().dp.maintained.clear();
+        dap.lock().dp.editr.clear();
+        target.invalidate_icache_range(0x8_0030, 100).unwrap();
+        assert_eq!(
+            vec![
+                (0xD50B_7520, 0x8_0000),
+                (0xD50B_7520, 0x8_0040),
+                (0xD50B_7520, 0x8_0080),
+            ],
+            dap.lock().dp.maintained
+        );
+        {
+            let core = &dap.lock().dp;
+            let editr = &core.editr;
+            let op = editr.iter().position(|x| *x == encode_ic_ivau(0)).unwrap();
+            assert_eq!(encode_mrs(DBGDTR_EL0, 0), editr[op - 1]);
+            assert_eq!(
+                [encode_ic_ivau(0), DSB_ISH, ISB],
+                editr[editr.len() - 5..editr.len() - 2]
+            );
+            assert_eq!(0x1111, core.x[0]);
+            assert_eq!(0x2222, core.x[1]);
+        }
+
+        dap.lock().dp.maintained.clear();
+        target
+            .write_code(0x8_0010, &[0x1f, 0x20, 0x03, 0xd5])
+            .unwrap();
+        assert_eq!(Some(&0xd503_201f), dap.lock().dp.ram.get(&0x8_0010));
+        assert_eq!(
+            vec![(0xD50B_7E20, 0x8_0000), (0xD50B_7520, 0x8_0000)],
+            dap.lock().dp.maintained
+        );
+    }
+
+    #[test]
+    fn mem_access_fault_test() {
+        let dap = DapHandle::new(memap_dap(CoreSim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        dap.lock().dp.x[0] = 0x1111;
+        dap.lock().dp.x[1] = 0x2222;
+        dap.lock().dp.fault = Some(0x4000_0004);
+
+        let mut buf = [0; 8];
+        assert_eq!(
+            Some(DebugError::Fault),
+            target.mem_read(0x4000_0000, &mut buf).err()
+        );
+        // ERRはCSEで落とされ、x0/x1も戻っている
+        assert_eq!(0, target.edscr_read().unwrap().ERR());
+        let core = &dap.lock().dp;
+        assert_eq!(0x1111, core.x[0]);
+        assert_eq!(0x2222, core.x[1]);
+    }
+
+    #[test]
+    fn exec_insns_fault_test() {
+        let dap = DapHandle::new(memap_dap(CoreSim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        dap.lock().dp.x[0] = 0x4000_0000;
+        dap.lock().dp.fault = Some(0x4000_0000);
+        dap.lock().dp.dlr = 0x8_0000;
+
+        let instructions = [
+            encode_mrs(DLR_EL0, 1),
+            encode_ldr_w_post4(2, 0),
+            encode_mrs(DSPSR_EL0, 3),
+        ];
+        assert_eq!(Err(DebugError::Fault), target.exec_insns(&instructions));
+        // abortした命令より後は実行しない
+        assert_eq!(instructions[..2], dap.lock().dp.editr[..]);
+        assert_eq!(0x8_0000, dap.lock().dp.x[1]);
+        assert_eq!(0, target.edscr_read().unwrap().ERR());
+
+        dap.lock().dp.fault = None;
+        target.exec_insns(&instructions).unwrap();
+        assert_eq!(5, dap.lock().dp.editr.len());
+    }
+
+    fn soc_memory(dap: &DapHandle<DAP<MemApSim>>, n: usize, offset: u64) -> Option<u32> {
+        let cti = BCM2711_CORES[n].cti;
+        dap.lock().dp.memory.get(&(cti + offset)).copied()
+    }
+
+    #[test]
+    fn soc_core_test() {
+        let dap = DapHandle::new(memap_dap(MemApSim::new()));
+        let soc = Arm64Soc::new(dap.clone(), &BCM2711_CORES);
+        assert_eq!(4, soc.cores());
+        let mut core = soc.core(2);
+        assert_eq!(0x8001_4000, core.target.baseaddr);
+        assert_eq!(0x8001_C000, core.cti.baseaddr);
+
+        // 止まったことにしておく
+        let edprsr = 0x8001_4000 + Armv8DebugRegisterOffset::EDPRSR as u64;
+        dap.lock().dp.memory.insert(edprsr, 1 << 4);
+        assert!(core.is_halted().unwrap());
+        dap.lock().dp.memory.insert(edprsr, 0);
+        assert_eq!(Some(DebugError::Timeout), core.halt().err());
+        // 他coreには伝搬させない
+        assert_eq!(Some(1), soc_memory(&dap, 2, CtiOffset::CTICONTROL as u64));
+        assert_eq!(Some(0), soc_memory(&dap, 2, CtiOffset::CTIGATE as u64));
+        assert_eq!(Some(1), soc_memory(&dap, 2, CtiOffset::CTIAPPPULSE as u64));
+        assert_eq!(None, soc_memory(&dap, 0, CtiOffset::CTIAPPPULSE as u64));
+    }
+
+    #[test]
+    fn soc_halt_resume_all_test() {
+        let dap = DapHandle::new(memap_dap(MemApSim::new()));
+        let mut soc = Arm64Soc::new(dap.clone(), &BCM2711_CORES);
+        for base in BCM2711_CORES.iter() {
+            let edprsr = base.debug + Armv8DebugRegisterOffset::EDPRSR as u64;
+            dap.lock().dp.memory.insert(edprsr, 1 << 4);
+        }
+        soc.halt_all().unwrap();
+        let halt = 1 << CTI_CHANNEL_HALT;
+        let outen_halt = CtiOffset::CTIOUTENn as u64 + CTI_TRIGGER_HALT as u64 * 4;
+        for n in 0..4 {
+            assert_eq!(Some(1), soc_memory(&dap, n, CtiOffset::CTICONTROL as u64));
+            assert_eq!(Some(halt), soc_memory(&dap, n, CtiOffset::CTIGATE as u64));
+            assert_eq!(Some(halt), soc_memory(&dap, n, outen_halt));
+        }
+        // pulseは1つのCTIからだけ出す
+        assert_eq!(
+            Some(halt),
+            soc_memory(&dap, 0, CtiOffset::CTIAPPPULSE as u64)
+        );
+        for n in 1..4 {
+            assert_eq!(None, soc_memory(&dap, n, CtiOffset::CTIAPPPULSE as u64));
+        }
+
+        for base in BCM2711_CORES.iter() {
+            let edprsr = base.debug + Armv8DebugRegisterOffset::EDPRSR as u64;
+            dap.lock().dp.memory.insert(edprsr, 0);
+        }
+        soc.resume_all().unwrap();
+        let restart = 1 << CTI_CHANNEL_RESTART;
+        let outen_restart = CtiOffset::CTIOUTENn as u64 + CTI_TRIGGER_RESTART as u64 * 4;
+        for n in 0..4 {
+            assert_eq!(
+                Some(restart),
+                soc_memory(&dap, n, CtiOffset::CTIGATE as u64)
+            );
+            assert_eq!(Some(0), soc_memory(&dap, n, outen_halt));
+            assert_eq!(Some(restart), soc_memory(&dap, n, outen_restart));
+            assert_eq!(
+                Some(1 << CTI_TRIGGER_HALT),
+                soc_memory(&dap, n, CtiOffset::CTIINTACK as u64)
+            );
+        }
+        assert_eq!(
+            Some(restart),
+            soc_memory(&dap, 0, CtiOffset::CTIAPPPULSE as u64)
+        );
+    }
+
+    #[test]
+    fn cti_halt_restart_test() {
+        let dap = DapHandle::new(memap_dap(MemApSim::new()));
+        let mut cti = Cti {
+            dap: dap.clone(),
+            baseaddr: CTI_BASE,
+        };
+        let reg = |offset: CtiOffset| CTI_BASE + offset as u64;
+        let outen = |trigger: u8| reg(CtiOffset::CTIOUTENn) + trigger as u64 * 4;
+        // 他のchannelのgateは残す
+        dap.lock().dp.memory.insert(reg(CtiOffset::CTIGATE), 0xf);
+        cti.setup_default_routing().unwrap();
+        cti.halt_core().unwrap();
+        cti.restart_core().unwrap();
+        assert_eq!(
+            vec![
+                // setup_default_routing
+                (reg(CtiOffset::CTICONTROL), 1),
+                (outen(CTI_TRIGGER_HALT), 1 << CTI_CHANNEL_HALT),
+                (outen(CTI_TRIGGER_RESTART), 1 << CTI_CHANNEL_RESTART),
+                (reg(CtiOffset::CTIGATE), 0xc),
+                // halt_core
+                (reg(CtiOffset::CTICONTROL), 1),
+                (reg(CtiOffset::CTIGATE), 0xc),
+                (outen(CTI_TRIGGER_HALT), 1 << CTI_CHANNEL_HALT),
+                (reg(CtiOffset::CTIAPPPULSE), 1 << CTI_CHANNEL_HALT),
+                // restart_core
+                (reg(CtiOffset::CTIINTACK), 1 << CTI_TRIGGER_HALT),
+                (reg(CtiOffset::CTICONTROL), 1),
+                (reg(CtiOffset::CTIGATE), 0xc),
+                (outen(CTI_TRIGGER_HALT), 0),
+                (outen(CTI_TRIGGER_RESTART), 1 << CTI_CHANNEL_RESTART),
+                (reg(CtiOffset::CTIAPPPULSE), 1 << CTI_CHANNEL_RESTART),
+            ],
+            dap.lock().dp.writes
+        );
+
+        // ackしてもdebug requestが落ちなければrestartを送らない
+        dap.lock()
+            .dp
+            .memory
+            .insert(reg(CtiOffset::CTITRIGOUTSTATUS), 1 << CTI_TRIGGER_HALT);
+        dap.lock().dp.writes.clear();
+        assert_eq!(
+            Some(DapError::Interface(InterfaceError::Timeout)),
+            cti.restart_core().err()
+        );
+        assert_eq!(
+            vec![(reg(CtiOffset::CTIINTACK), 1 << CTI_TRIGGER_HALT)],
+            dap.lock().dp.writes
+        );
+    }
+
+    #[test]
+    fn context_test() {
+        let dap = DapHandle::new(memap_dap(CoreSim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        let mut cti = Cti {
+            dap: dap.clone(),
+            baseaddr: 0x8001_8000,
+        };
+        {
+            let core = &mut dap.lock().dp;
+            for (n, x) in core.x.iter_mut().enumerate() {
+                *x = 0x1000 + n as u64;
+            }
+            core.sp = 0xffff_0000_0008_0000;
+            core.dlr = 0x4008_0000;
+            core.dspsr = 0x3c5;
+        }
+        let ctx = target.save_context().unwrap();
+        assert_eq!(0x101e, ctx.x[30]);
+        assert_eq!(0xffff_0000_0008_0000, ctx.sp);
+        assert_eq!(0x4008_0000, ctx.pc);
+        assert_eq!(0x3c5, ctx.pstate);
+
+        // debug sessionでregisterを壊す
+        target.write_gpr(0, 0).unwrap();
+        target.write_gpr(1, 0).unwrap();
+        target.write_pc(0).unwrap();
+        target.write_cpsr(0).unwrap();
+        dap.lock().dp.inner.writes.clear();
+
+        target.restore_context(&ctx).unwrap();
+        assert_eq!(ctx, target.save_context().unwrap());
+        let edprsr = DEBUG_BASE + Armv8DebugRegisterOffset::EDPRSR as u64;
+        dap.lock().dp.inner.memory.insert(edprsr, 1 << 11);
+        target.resume(&mut cti).unwrap();
+
+        // GPR -> PC -> restartの順
+        let editr = DEBUG_BASE + Armv8DebugRegisterOffset::EDITR as u64;
+        let pulse = 0x8001_8000 + CtiOffset::CTIAPPPULSE as u64;
+        let writes = dap.lock().dp.inner.writes.clone();
+        let position = |entry: (u64, u32)| writes.iter().position(|x| *x == entry).unwrap();
+        let last_gpr = position((editr, encode_mrs(DBGDTR_EL0, 30)));
+        let pc = position((editr, encode_msr(DLR_EL0, 0)));
+        let restart = position((pulse, 1 << CTI_CHANNEL_RESTART));
+        assert!(last_gpr < pc && pc < restart, "{:x?}", writes);
+    }
+
+    #[test]
+    fn shared_handle_test() {
+        // 2つのthreadが別々のcoreのregisterを叩いても、TARとBDのaccessは混ざらない
+        let dap = DapHandle::new(memap_dap(MemApSim::new()));
+        let threads: Vec<_> = [0x8001_0000, 0x8011_0000]
+            .iter()
+            .map(|baseaddr| {
+                let mut target = A64Target::new(dap.clone(), *baseaddr);
+                std::thread::spawn(move || {
+                    let offset = Armv8DebugRegisterOffset::DBGBVR_BASE_EL1 as u64;
+                    for i in 0..100 {
+                        let value = (target.baseaddr as u32) | i;
+                        target.register_u32_write(offset, value).unwrap();
+                        assert_eq!(value, target.register_u32_read(offset).unwrap());
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // lock中にpanicしたthreadがあっても使い続けられる
+        let poisoner = dap.clone();
+        let result = std::thread::spawn(move || {
+            let _guard = poisoner.lock();
+            panic!("panic while holding the DAP");
+        })
+        .join();
+        assert!(result.is_err());
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        target.register_u32_write(0x400, 1).unwrap();
+    }
+
+    #[test]
+    fn register_u64_straddle_test() {
+        let dap = DapHandle::new(memap_dap(MemApSim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        dap.lock().dp.tar_writes = 0;
+        // 0x40Cと0x410は別のBDの窓
+        target
+            .register_u64_write(0x40c, 0x1122_3344_5566_7788)
+            .unwrap();
+        assert_eq!(2, dap.lock().dp.tar_writes);
+        assert_eq!(
+            Some(&0x5566_7788),
+            dap.lock().dp.memory.get(&(DEBUG_BASE + 0x40c))
+        );
+        assert_eq!(
+            Some(&0x1122_3344),
+            dap.lock().dp.memory.get(&(DEBUG_BASE + 0x410))
+        );
+        assert_eq!(
+            0x1122_3344_5566_7788,
+            target.register_u64_read(0x40c).unwrap()
+        );
+
+        // 窓の中なら1回
+        dap.lock().dp.tar_writes = 0;
+        target.register_u64_read(0x400).unwrap();
+        assert_eq!(1, dap.lock().dp.tar_writes);
+    }
+
+    #[test]
+    fn gpr_range_test() {
+        let dap = DapHandle::new(memap_dap(CoreSim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        assert_eq!(
+            Err(DebugError::Interface(InterfaceError::OutOfRange)),
+            target.read_gpr(32)
+        );
+        assert_eq!(
+            Err(DebugError::Interface(InterfaceError::OutOfRange)),
+            target.write_gpr(32, 0)
+        );
+        assert!(dap.lock().dp.editr.is_empty());
+    }
+
+    fn auth_target(auth: u32, edprsr: u32) -> A64Target<DAP<MemApSim>> {
+        let dap = DapHandle::new(memap_dap(MemApSim::new()));
+        {
+            let memory = &mut dap.lock().dp.memory;
+            memory.insert(
+                DEBUG_BASE + Armv8DebugRegisterOffset::DBGAUTHSTATUS as u64,
+                auth,
+            );
+            memory.insert(DEBUG_BASE + Armv8DebugRegisterOffset::EDPRSR as u64, edprsr);
+        }
+        A64Target::new(dap, DEBUG_BASE)
+    }
+
+    #[test]
+    fn auth_status_test() {
+        // 全て有効
+        let mut target = auth_target(0xff, 1);
+        let auth = target.check_debug_permissions().unwrap();
+        assert!(auth.secure_implemented);
+        assert!(auth.secure_invasive_enabled && auth.nonsecure_invasive_enabled);
+        assert!(!auth.external_debug_access_disabled);
+
+        // secureだけ無効。non-secureではhaltできる
+        let mut target = auth_target(0b1010_1111, 1 | (1 << 8) | (1 << 10));
+        let auth = target.check_debug_permissions().unwrap();
+        assert!(!auth.secure_invasive_enabled && !auth.secure_noninvasive_enabled);
+        assert!(auth.nonsecure_invasive_enabled);
+        assert!(auth.secure_debug_access_disabled && auth.secure_pmu_access_disabled);
+
+        // 全て無効
+        let mut target = auth_target(0b1010_1010, 1 | (1 << 7));
+        match target.check_debug_permissions() {
+            Err(DebugError::InvasiveDebugDisabled { auth }) => {
+                assert!(!auth.nonsecure_invasive_enabled);
+                assert!(auth.external_debug_access_disabled);
+            }
+            x => panic!("unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn register_name_test() {
+        for offset in Armv8DebugRegisterOffset::ALL.iter() {
+            assert_eq!(
+                Some(*offset),
+                Armv8DebugRegisterOffset::from_name(offset.name())
+            );
+        }
+        assert_eq!(
+            Some(Armv8DebugRegisterOffset::EDSCR),
+            Armv8DebugRegisterOffset::from_name("edscr")
+        );
+        assert_eq!(None, Armv8DebugRegisterOffset::from_name("EDFOO"));
+    }
+
+    #[test]
+    fn sample_pc_test() {
+        let dap = DapHandle::new(memap_dap(MemApSim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        let reg = |offset: Armv8DebugRegisterOffset| DEBUG_BASE + offset as u64;
+        {
+            let memory = &mut dap.lock().dp.memory;
+            memory.insert(reg(Armv8DebugRegisterOffset::EDPCSRlo), 0x4008_1234);
+            memory.insert(reg(Armv8DebugRegisterOffset::EDPCSRhi), 0xffff_0000);
+            memory.insert(reg(Armv8DebugRegisterOffset::EDCIDSR), 42);
+            memory.insert(reg(Armv8DebugRegisterOffset::EDVIDSR), (1 << 31) | 7);
+        }
+        assert_eq!(
+            Some(PcSample {
+                pc: 0xffff_0000_4008_1234,
+                context_id: 42,
+                vmid: 7,
+                non_secure: true,
+            }),
+            target.sample_pc().unwrap()
+        );
+        // loを最初に読み、hiは最後に読む
+        let reads = dap.lock().dp.reads.clone();
+        let position = |offset| {
+            let address = reg(offset);
+            reads.iter().position(|x| *x == address).unwrap()
+        };
+        assert_eq!(0, position(Armv8DebugRegisterOffset::EDPCSRlo));
+        assert_eq!(3, position(Armv8DebugRegisterOffset::EDPCSRhi));
+
+        // 取れていないsampleは残りを読まない
+        dap.lock()
+            .dp
+            .memory
+            .insert(reg(Armv8DebugRegisterOffset::EDPCSRlo), 0xffff_ffff);
+        dap.lock().dp.reads.clear();
+        assert_eq!(None, target.sample_pc().unwrap());
+        assert_eq!(
+            vec![reg(Armv8DebugRegisterOffset::EDPCSRlo)],
+            dap.lock().dp.reads
+        );
+    }
+
+    #[test]
+    fn pc_histogram_test() {
+        let sample = |pc| PcSample {
+            pc,
+            context_id: 0,
+            vmid: 0,
+            non_secure: true,
+        };
+        let samples: Vec<_> = [0x10, 0x20, 0x10, 0x30, 0x20, 0x10]
+            .iter()
+            .map(|x| sample(*x))
+            .collect();
+        assert_eq!(
+            vec![(0x10, 3), (0x20, 2), (0x30, 1)],
+            pc_histogram(&samples)
+        );
+    }
+
+    #[test]
+    fn register_window_test() {
+        let dap = DapHandle::new(memap_dap(MemApSim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        dap.lock().dp.tar_writes = 0;
+        target
+            .with_window(0x400, |window| -> Result<(), DapError> {
+                for i in 0..8 {
+                    window.read((i % 4) * 4)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(1, dap.lock().dp.tar_writes);
+
+        // 窓を出るとTARを書き直す
+        target
+            .with_window(0x400, |window| -> Result<(), DapError> {
+                window.write(0xc, 1)?;
+                window.write(0x10, 2)?;
+                assert_eq!(3, window.modify(0x10, |x| x + 1)?);
+                Ok(())
+            })
+            .unwrap();
+        // 0x400の窓は前のwith_windowで書いたTARがそのまま使える
+        assert_eq!(2, dap.lock().dp.tar_writes);
+        assert_eq!(Some(&3), dap.lock().dp.memory.get(&(DEBUG_BASE + 0x410)));
+    }
+
+    #[test]
+    fn register_access_tar_test() {
+        let dap = DapHandle::new(memap_dap(MemApSim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        dap.lock().dp.tar_writes = 0;
+        // EDITRで命令を実行してDTRから結果を読む、halt中の1命令分のaccess
+        for _ in 0..8 {
+            target
+                .register_u32_write(Armv8DebugRegisterOffset::EDITR as u64, 0xd503_201f)
+                .unwrap();
+            target
+                .register_u32_read(Armv8DebugRegisterOffset::EDSCR as u64)
+                .unwrap();
+            target
+                .register_u32_read(Armv8DebugRegisterOffset::DBGDTRTX_EL0 as u64)
+                .unwrap();
+            target
+                .register_u32_read(Armv8DebugRegisterOffset::DBGDTRRX_EL0 as u64)
+                .unwrap();
+        }
+        target
+            .register_u32_write(Armv8DebugRegisterOffset::EDRCR as u64, 1 << 2)
+            .unwrap();
+        target
+            .register_u32_read(Armv8DebugRegisterOffset::EDSCR as u64)
+            .unwrap();
+        assert!(dap.lock().dp.tar_writes <= 3);
+    }
+
+    #[test]
+    fn register_window_modify_test() {
+        // 2つのthreadが同じregisterをread-modify-writeしても更新を失わない
+        let dap = DapHandle::new(memap_dap(MemApSim::new()));
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let mut cti = Cti {
+                    dap: dap.clone(),
+                    baseaddr: CTI_BASE,
+                };
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        cti.with_window(0, |window| {
+                            window.modify(CtiOffset::CTIGATE as u64, |x| x + 1)
+                        })
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let gate = CTI_BASE + CtiOffset::CTIGATE as u64;
+        assert_eq!(Some(&200), dap.lock().dp.memory.get(&gate));
+    }
+
+    // EDPRSRをhalt_after回読まれたところでExternal debug requestでhaltするcore
+    struct HaltingSim {
+        inner: MemApSim,
+        polls: usize,
+        halt_after: Option<usize>,
+    }
+
+    impl DapInterface for HaltingSim {
+        fn apacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            let edprsr = DEBUG_BASE + Armv8DebugRegisterOffset::EDPRSR as u64;
+            let bank = DpSelect(self.inner.select).apbanksel();
+            if rnw && bank == 1 && a == 1 && self.inner.tar & !0xf == edprsr & !0xf {
+                self.polls += 1;
+                if Some(self.polls) == self.halt_after {
+                    self.inner.memory.insert(edprsr, 1 << 4);
+                    let edscr = DEBUG_BASE + Armv8DebugRegisterOffset::EDSCR as u64;
+                    self.inner.memory.insert(edscr, 0b010011);
+                }
+            }
+            self.inner.apacc(data, a, rnw)
+        }
+        fn dpacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            self.inner.dpacc(data, a, rnw)
+        }
+    }
+
+    fn halting_target(halt_after: Option<usize>) -> A64Target<DAP<HaltingSim>> {
+        let sim = HaltingSim {
+            inner: MemApSim::new(),
+            polls: 0,
+            halt_after,
+        };
+        A64Target::new(DapHandle::new(memap_dap(sim)), DEBUG_BASE)
+    }
+
+    #[test]
+    fn wait_for_halt_test() {
+        let mut target = halting_target(Some(5));
+        assert_eq!(
+            Ok(HaltReason::ExternalDebugRequest),
+            target.wait_for_halt(PollBudget::Iterations(5))
+        );
+        assert_eq!(5, target.dap.lock().dp.polls);
+
+        let mut target = halting_target(Some(5));
+        let edprsr = DEBUG_BASE + Armv8DebugRegisterOffset::EDPRSR as u64;
+        target.dap.lock().dp.inner.memory.insert(edprsr, 1);
+        assert_eq!(
+            Err(DebugError::HaltTimeout {
+                edprsr: 1,
+                edscr: 0
+            }),
+            target.wait_for_halt(PollBudget::Iterations(4))
+        );
+    }
+
+    #[test]
+    fn wait_for_halt_timeout_test() {
+        let mut target = halting_target(None);
+        let result =
+            target.wait_for_halt(PollBudget::Duration(std::time::Duration::from_millis(10)));
+        assert_eq!(
+            Some(DebugError::HaltTimeout {
+                edprsr: 0,
+                edscr: 0
+            }),
+            result.err()
+        );
+        assert!(target.dap.lock().dp.polls > 0);
+
+        // fの中のerrorはそのまま返す
+        assert_eq!(
+            Err(DebugError::Dap(DapError::Interface(InterfaceError::Io))),
+            target.poll_until(PollBudget::default(), |_| -> Result<Option<()>, _> {
+                Err(InterfaceError::Io.into())
+            })
+        );
+    }
+
+    #[test]
+    fn halt_on_reset_test() {
+        const PU: u32 = 1 << 0;
+        const R: u32 = 1 << 2;
+        const SR: u32 = 1 << 3;
+        const HALTED: u32 = 1 << 4;
+        const OSLK: u32 = 1 << 5;
+        let edprsr = DEBUG_BASE + Armv8DebugRegisterOffset::EDPRSR as u64;
+        let edesr = DEBUG_BASE + Armv8DebugRegisterOffset::EDESR as u64;
+        let edecr = DEBUG_BASE + Armv8DebugRegisterOffset::EDECR as u64;
+        let edscr = DEBUG_BASE + Armv8DebugRegisterOffset::EDSCR as u64;
+        let oslar = DEBUG_BASE + Armv8DebugRegisterOffset::OSLAR_EL1 as u64;
+
+        let dap = DapHandle::new(memap_dap(CoreSim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        dap.lock().dp.inner.memory.insert(edprsr, PU);
+        let sim = dap.clone();
+        let reason = target
+            .halt_on_reset(|| {
+                let core = &mut sim.lock().dp;
+                // resetが掛かり、解けると最初の命令でhaltしてOS Lockも掛かる
+                core.inner.memory.insert(edprsr, PU | R | SR);
+                core.inner.memory.insert(edesr, 1 << 1);
+                *core.dtr(Armv8DebugRegisterOffset::EDSCR) |= 0b100111;
+                core.edprsr_sequence.extend(&[PU | R, PU | HALTED | OSLK]);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(HaltReason::ResetCatch, reason);
+
+        let guard = dap.lock();
+        let core = &guard.dp;
+        assert_eq!(Some(&(PU | HALTED)), core.inner.memory.get(&edprsr));
+        assert_eq!(Some(&0), core.inner.memory.get(&edesr));
+        let writes: Vec<_> = core
+            .inner
+            .writes
+            .iter()
+            .filter(|x| [edecr, edesr, oslar].contains(&x.0))
+            .copied()
+            .collect();
+        assert_eq!(
+            vec![
+                (edesr, 1 << 1),
+                (edecr, 1 << 1),
+                (oslar, 0),
+                (edesr, 1 << 1),
+                (edecr, 0),
+            ],
+            writes
+        );
+        drop(guard);
+
+        // resetされなければ、既にhaltしていても待ち続ける
+        let edscr_value = dap.lock().dp.inner.memory[&edscr];
+        assert_eq!(
+            Err(DebugError::HaltTimeout {
+                edprsr: PU | HALTED,
+                edscr: edscr_value,
+            }),
+            target.halt_on_reset(|| Ok(()))
+        );
+
+        // resetできなければreset catchを戻してerrorを返す
+        dap.lock().dp.inner.writes.clear();
+        assert_eq!(
+            Err(DebugError::Interface(InterfaceError::Io)),
+            target.halt_on_reset(|| Err(InterfaceError::Io))
+        );
+        assert_eq!(Some(&(edecr, 0)), dap.lock().dp.inner.writes.last());
+    }
+
+    #[test]
+    fn step_test() {
+        let dap = DapHandle::new(memap_dap(CoreSim::new()));
+        let mut target = A64Target::new(dap.clone(), DEBUG_BASE);
+        let mut cti = Cti {
+            dap: dap.clone(),
+            baseaddr: 0x8001_8000,
+        };
+        {
+            let core = &mut dap.lock().dp;
+            core.dlr = 0x4008_0004;
+            let edprsr = DEBUG_BASE + Armv8DebugRegisterOffset::EDPRSR as u64;
+            core.inner.memory.insert(edprsr, (1 << 11) | (1 << 4));
+            // 前回のstepが残っている
+            let edesr = DEBUG_BASE + Armv8DebugRegisterOffset::EDESR as u64;
+            core.inner.memory.insert(edesr, 1 << 2);
+        }
+        assert_eq!(0x4008_0004, target.step(&mut cti).unwrap());
+
+        let edecr = DEBUG_BASE + Armv8DebugRegisterOffset::EDECR as u64;
+        let edesr = DEBUG_BASE + Armv8DebugRegisterOffset::EDESR as u64;
+        let pulse = 0x8001_8000 + CtiOffset::CTIAPPPULSE as u64;
+        let writes: Vec<_> = dap
+            .lock()
+            .dp
+            .inner
+            .writes
+            .iter()
+            .filter(|x| [edecr, edesr, pulse].contains(&x.0))
+            .copied()
+            .collect();
+        assert_eq!(
+            vec![
+                (edecr, 1 << 2),
+                (edesr, 1 << 2),
+                (pulse, 1 << CTI_CHANNEL_RESTART),
+                (edecr, 0),
+            ],
+            writes
+        );
+    }
+
+    // OS Lock中でhalt済みのcore
+    pub(crate) fn attached_core() -> (DapHandle<DAP<CoreSim>>, CoreHandle<DAP<CoreSim>>) {
+        let mut sim = CoreSim::new();
+        sim.restart_runs = true;
+        let edprsr = DEBUG_BASE + Armv8DebugRegisterOffset::EDPRSR as u64;
+        // PU, OSLK
+        sim.inner.memory.insert(edprsr, 1 | (1 << 5));
+        let dap = DapHandle::new(memap_dap(sim));
+        let mut core = CoreHandle::new(
+            A64Target::new(dap.clone(), DEBUG_BASE),
+            Cti {
+                dap: dap.clone(),
+                baseaddr: CTI_BASE,
+            },
+        );
+        core.target.prepare_debug(false).unwrap();
+        core.target.halting_debug_enable().unwrap();
+        // HALTED, SDR
+        *dap.lock().dp.inner.memory.get_mut(&edprsr).unwrap() |= (1 << 4) | (1 << 11);
+        dap.lock().dp.inner.writes.clear();
+        (dap, core)
+    }
+
+    #[test]
+    fn detach_test() {
+        let (dap, mut core) = attached_core();
+        // 2回目のprepare_debugでは解除済みの状態を覚え直さない
+        core.target.prepare_debug(false).unwrap();
+        dap.lock().dp.inner.writes.clear();
+        core.detach().unwrap();
+
+        let cti = |offset: CtiOffset| CTI_BASE + offset as u64;
+        let outen = |trigger: u8| cti(CtiOffset::CTIOUTENn) + trigger as u64 * 4;
+        let edscr = DEBUG_BASE + Armv8DebugRegisterOffset::EDSCR as u64;
+        let mut edrcr = EDRCR(0);
+        edrcr.set_CSE(1);
+        assert_eq!(
+            vec![
+                // resume
+                (DEBUG_BASE + Armv8DebugRegisterOffset::EDRCR as u64, edrcr.0),
+                (cti(CtiOffset::CTIINTACK), 1 << CTI_TRIGGER_HALT),
+                (cti(CtiOffset::CTICONTROL), 1),
+                (cti(CtiOffset::CTIGATE), 0),
+                (outen(CTI_TRIGGER_HALT), 0),
+                (outen(CTI_TRIGGER_RESTART), 1 << CTI_CHANNEL_RESTART),
+                (cti(CtiOffset::CTIAPPPULSE), 1 << CTI_CHANNEL_RESTART),
+                // HDEを落とす
+                (
+                    edscr,
+                    (1 << 24) | (1 << 29) | (0b1111 << 10) | (1 << 18) | (1 << 8)
+                ),
+                (outen(CTI_TRIGGER_HALT), 0),
+                (outen(CTI_TRIGGER_RESTART), 0),
+                // OS Lockを戻す
+                (DEBUG_BASE + Armv8DebugRegisterOffset::OSLAR_EL1 as u64, 1),
+            ],
+            dap.lock().dp.inner.writes
+        );
+        assert!(!core.is_halted().unwrap());
+
+        // 走っているcoreはrestartしない
+        dap.lock().dp.inner.writes.clear();
+        core.detach().unwrap();
+        let writes = dap.lock().dp.inner.writes.clone();
+        assert_eq!(3, writes.len(), "{:x?}", writes);
+        assert_eq!(edscr, writes[0].0);
+    }
+
+    #[test]
+    fn detach_breakpoint_test() {
+        let (dap, mut core) = attached_core();
+        let eddfr = DEBUG_BASE + Armv8DebugRegisterOffset::EDDFR as u64;
+        dap.lock().dp.inner.memory.insert(eddfr, 5 << 12);
+        core.target.set_breakpoint(0, 0x4008_0000).unwrap();
+        core.target.set_breakpoint(2, 0x4008_1000).unwrap();
+        core.target.clear_breakpoint(2).unwrap();
+        dap.lock().dp.inner.writes.clear();
+        core.detach().unwrap();
+        // debuggerが残したbreakpointだけ消す
+        let dbgbcr = |index: u64| {
+            DEBUG_BASE
+                + Armv8DebugRegisterOffset::DBGBCR_BASE_EL1 as u64
+                + index * BREAKPOINT_STRIDE
+        };
+        let writes = dap.lock().dp.inner.writes.clone();
+        assert_eq!((dbgbcr(0), 0), writes[0]);
+        assert!(!writes.iter().any(|x| x.0 == dbgbcr(2)));
+
+        // 2回目は何も消さない
+        dap.lock().dp.inner.writes.clear();
+        core.detach().unwrap();
+        assert!(!dap.lock().dp.inner.writes.iter().any(|x| x.0 == dbgbcr(0)));
+    }
+
+    #[test]
+    fn resume_into_breakpoint_test() {
+        let (dap, mut core) = attached_core();
+        // restartしてすぐにbreakpointで止まり、HALTEDのままSDRが立つ
+        dap.lock().dp.restart_runs = false;
+        core.resume().unwrap();
+        assert!(core.is_halted().unwrap());
+    }
+
+    #[test]
+    fn debug_session_test() {
+        let oslar = DEBUG_BASE + Armv8DebugRegisterOffset::OSLAR_EL1 as u64;
+        let (dap, core) = attached_core();
+        {
+            let mut session = DebugSession::new(core);
+            assert!(session.is_halted().unwrap());
+        }
+        assert!(dap.lock().dp.inner.writes.contains(&(oslar, 1)));
+        let edprsr = DEBUG_BASE + Armv8DebugRegisterOffset::EDPRSR as u64;
+        let edprsr = EDPRSR(dap.lock().dp.inner.memory[&edprsr]);
+        assert_eq!((0, 1), (edprsr.HALTED(), edprsr.OSLK()));
+
+        // commitしたらhaltしたまま残す
+        let (dap, core) = attached_core();
+        {
+            let mut session = DebugSession::new(core);
+            session.commit();
+        }
+        assert_eq!(Vec::<(u64, u32)>::new(), dap.lock().dp.inner.writes);
+
+        // closeはdetachしてからdropでは何もしない
+        let (dap, core) = attached_core();
+        DebugSession::new(core).close().unwrap();
+        let writes = dap.lock().dp.inner.writes.clone();
+        assert!(writes.contains(&(oslar, 1)));
+        assert_eq!(1, writes.iter().filter(|x| x.0 == oslar).count());
+    }
+
+    #[test]
+    fn debug_session_drop_while_panicking_test() {
+        let (dap, core) = attached_core();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _session = DebugSession::new(core);
+            panic!("original error");
+        }));
+        let payload = result.unwrap_err();
+        assert_eq!(Some(&"original error"), payload.downcast_ref::<&str>());
+        // detachしていない
+        assert_eq!(Vec::<(u64, u32)>::new(), dap.lock().dp.inner.writes);
+    }
+
+    #[test]
+    fn debug_session_drop_link_lost_test() {
+        let (dap, core) = attached_core();
+        dap.lock().debug_set_link_lost();
+        drop(DebugSession::new(core));
+        assert_eq!(Vec::<(u64, u32)>::new(), dap.lock().dp.inner.writes);
+    }
+}
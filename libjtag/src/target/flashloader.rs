@@ -0,0 +1,479 @@
+// haltしたcoreにSRAMへ置いたalgorithmを呼ばせて、SPI-NORやeMMCのflashを書く
+// algorithmはAAPCS64の関数で、x0-x7で引数を受け取りx0で結果を返す
+// LRはblob直後のHLTを指すので、returnすると外部debugのhaltになる
+use crate::error::DebugError;
+use crate::interface::InterfaceError;
+use crate::jtag::dap::*;
+use crate::target::arm64::*;
+use log::{debug, warn};
+
+// HLT #0。EDSCR.HDEが立っていればdebug stateに入る
+const HLT: u32 = 0xD440_0000;
+const GPR_LR: u8 = 30;
+// AAPCS64で引数を渡せるregisterの数
+const ARG_REGS: usize = 8;
+// PSTATE.DAIF。algorithmの実行中に割り込みを受けないようにする
+const DAIF: u32 = 0xf << 6;
+const STACK_ALIGN: u64 = 16;
+
+// SRAMやflashのaddressがu64を越える場合
+fn out_of_range() -> DebugError {
+    DebugError::Interface(InterfaceError::OutOfRange)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FlashAlgorithm<'a> {
+    pub code: &'a [u8],
+    // program_page(addr, len, buf)のblob先頭からのoffset
+    pub program_page: u64,
+    pub stack_size: u64,
+}
+
+// SRAMの配置: [code][HLT][stack][page buffer]
+pub struct FlashLoader<'a, T> {
+    target: &'a mut A64Target<T>,
+    cti: &'a mut Cti<T>,
+    program_page: u64,
+    sram_base: u64,
+    // HLTのaddress。algorithmの戻り先
+    ret: u64,
+    stack_top: u64,
+    // load前のregister。unloadで戻す
+    saved: CpuContext,
+    budget: PollBudget,
+}
+
+impl<'a, T: DebugPort + MemoryAccessPort> FlashLoader<'a, T> {
+    // A64TargetのAPはdebug registerしか見えないので、blobもcoreのstore(DBGDTR経由)で書く
+    pub fn load(
+        target: &'a mut A64Target<T>,
+        cti: &'a mut Cti<T>,
+        algo: &FlashAlgorithm,
+        sram_base: u64,
+    ) -> Result<Self, DebugError> {
+        if !target.halted()? {
+            return Err(DebugError::NotHalted);
+        }
+        // 何か書き込む前に配置がaddress空間に収まるか確かめる
+        let ret = sram_base
+            .checked_add(algo.code.len() as u64 + 3)
+            .ok_or_else(out_of_range)?
+            & !3;
+        let stack_top = (ret
+            .checked_add(4 + STACK_ALIGN - 1)
+            .ok_or_else(out_of_range)?
+            & !(STACK_ALIGN - 1))
+            .checked_add(algo.stack_size)
+            .ok_or_else(out_of_range)?;
+        target.halting_debug_enable()?;
+        let saved = target.save_context()?;
+        target.write_code(sram_base, algo.code)?;
+        target.write_code(ret, &HLT.to_le_bytes())?;
+        debug!(
+            "flash algorithm loaded at {:#x} ({} bytes), return {:#x}",
+            sram_base,
+            algo.code.len(),
+            ret
+        );
+        Ok(FlashLoader {
+            target,
+            cti,
+            program_page: algo.program_page,
+            sram_base,
+            ret,
+            stack_top,
+            saved,
+            budget: PollBudget::default(),
+        })
+    }
+
+    // 1回のcallでalgorithmがreturnするまで待つ量
+    pub fn set_budget(&mut self, budget: PollBudget) {
+        self.budget = budget;
+    }
+
+    // programで1pageずつ渡すbufferの先頭
+    pub fn buffer(&self) -> u64 {
+        self.stack_top
+    }
+
+    // blob先頭からentry_offsetの関数をargsで呼び、x0を返す
+    pub fn call(&mut self, entry_offset: u64, args: &[u64]) -> Result<u64, DebugError> {
+        if args.len() > ARG_REGS {
+            return Err(out_of_range());
+        }
+        let entry = self
+            .sram_base
+            .checked_add(entry_offset)
+            .ok_or_else(out_of_range)?;
+        for (n, arg) in args.iter().enumerate() {
+            self.target.write_gpr(n as u8, *arg)?;
+        }
+        self.target.write_gpr(GPR_LR, self.ret)?;
+        self.target.write_gpr(GPR_SP, self.stack_top)?;
+        self.target.write_cpsr(self.saved.pstate | DAIF)?;
+        self.target.write_pc(entry)?;
+        debug!("calling flash algorithm at {:#x} {:x?}", entry, args);
+
+        self.target.resume(self.cti)?;
+        let reason = match self.target.wait_for_halt(self.budget) {
+            Ok(reason) => reason,
+            Err(e) => {
+                // 走り続けているalgorithmを止めておく
+                if self.cti.halt_core().is_ok() {
+                    self.target.wait_for_halt(PollBudget::default()).ok();
+                }
+                return Err(e);
+            }
+        };
+        let pc = self.target.read_pc()?;
+        if reason != HaltReason::HltInstruction || pc != self.ret {
+            warn!("flash algorithm halted: {:?} at {:#x}", reason, pc);
+            return Err(DebugError::UnexpectedHalt { reason, pc });
+        }
+        self.target.read_gpr(0)
+    }
+
+    // dataをpage_sizeごとにbufferへ書き、program_page(addr, len, buffer)を呼ぶ
+    pub fn program(&mut self, addr: u64, data: &[u8], page_size: usize) -> Result<(), DebugError> {
+        if page_size == 0 {
+            return Err(out_of_range());
+        }
+        // 途中のpageまで書いてから断らないように、先に全体を確かめる
+        self.target
+            .dap
+            .lock()
+            .check_write(addr, data.len() as u64)?;
+        for (i, page) in data.chunks(page_size).enumerate() {
+            let page_addr = (i as u64)
+                .checked_mul(page_size as u64)
+                .and_then(|x| addr.checked_add(x))
+                .ok_or_else(out_of_range)?;
+            self.target.mem_write(self.stack_top, page)?;
+            let code = self.call(
+                self.program_page,
+                &[page_addr, page.len() as u64, self.stack_top],
+            )?;
+            if code != 0 {
+                return Err(DebugError::AlgorithmFailed {
+                    addr: page_addr,
+                    code,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // algorithmが書き換えたregisterをload前に戻す
+    pub fn unload(self) -> Result<(), DebugError> {
+        self.target.restore_context(&self.saved)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::jtag::dap::tests::memap_dap;
+    use crate::target::arm64::tests::{CoreSim, CTI_BASE, DEBUG_BASE};
+    use std::collections::VecDeque;
+
+    const SRAM: u64 = 0x1000_0000;
+    const PU: u32 = 1;
+    const HALTED: u32 = 1 << 4;
+    const SDR: u32 = 1 << 11;
+    const CTIAPPPULSE: u64 = 0x1c;
+
+    enum Outcome {
+        Return(u64),
+        // EDSCR.STATUSの値でhaltする
+        Halt(u32),
+        // haltの要求が来るまで走り続ける
+        Hang,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Call {
+        entry: u64,
+        args: [u64; 3],
+        lr: u64,
+        sp: u64,
+        pstate: u64,
+        // x2が指すbufferのx1 byte
+        buffer: Vec<u8>,
+    }
+
+    // CTIからrestartされると、outcomesの先頭に従ってalgorithmを実行したことにする
+    struct AlgoSim {
+        core: CoreSim,
+        outcomes: VecDeque<Outcome>,
+        calls: Vec<Call>,
+    }
+
+    impl AlgoSim {
+        fn stop(&mut self, status: u32) {
+            let edscr = self.core.dtr(Armv8DebugRegisterOffset::EDSCR);
+            *edscr = (*edscr & !0x3f) | status;
+        }
+
+        fn run(&mut self) {
+            let core = &mut self.core;
+            let buffer = (0..core.x[1])
+                .map(|i| {
+                    let addr = core.x[2] + i;
+                    (core.ram.get(&(addr & !3)).unwrap_or(&0) >> ((addr & 3) * 8)) as u8
+                })
+                .collect();
+            self.calls.push(Call {
+                entry: core.dlr,
+                args: [core.x[0], core.x[1], core.x[2]],
+                lr: core.x[30],
+                sp: core.sp,
+                pstate: core.dspsr,
+                buffer,
+            });
+            // 一度走り出したことが見えてからhaltする
+            let edprsr = DEBUG_BASE + Armv8DebugRegisterOffset::EDPRSR as u64;
+            self.core.inner.memory.insert(edprsr, PU | SDR);
+            match self.outcomes.pop_front().unwrap_or(Outcome::Return(0)) {
+                Outcome::Return(code) => {
+                    self.core.x[0] = code;
+                    self.core.dlr = self.core.x[30];
+                    self.stop(0b101111);
+                    self.core.edprsr_sequence.push_back(PU | SDR | HALTED);
+                }
+                Outcome::Halt(status) => {
+                    self.core.dlr += 0x10;
+                    self.stop(status);
+                    self.core.edprsr_sequence.push_back(PU | SDR | HALTED);
+                }
+                Outcome::Hang => (),
+            }
+        }
+    }
+
+    impl DapInterface for AlgoSim {
+        fn apacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            let result = self.core.apacc(data, a, rnw)?;
+            let pulse = CTI_BASE + CTIAPPPULSE;
+            match self.core.inner.memory.remove(&pulse) {
+                // restart
+                Some(0b10) => self.run(),
+                // halt
+                Some(0b01) => {
+                    self.stop(0b010011);
+                    let edprsr = DEBUG_BASE + Armv8DebugRegisterOffset::EDPRSR as u64;
+                    self.core.inner.memory.insert(edprsr, PU | SDR | HALTED);
+                }
+                _ => (),
+            }
+            Ok(result)
+        }
+        fn dpacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            self.core.dpacc(data, a, rnw)
+        }
+    }
+
+    type Core = (
+        DapHandle<DAP<AlgoSim>>,
+        A64Target<DAP<AlgoSim>>,
+        Cti<DAP<AlgoSim>>,
+    );
+
+    fn halted_core() -> Core {
+        let mut core = CoreSim::new();
+        let edprsr = DEBUG_BASE + Armv8DebugRegisterOffset::EDPRSR as u64;
+        core.inner.memory.insert(edprsr, PU | SDR | HALTED);
+        core.x = [0x5a; 31];
+        core.dlr = 0x4008_0000;
+        core.dspsr = 0x3c5;
+        let dap = DapHandle::new(memap_dap(AlgoSim {
+            core,
+            outcomes: VecDeque::new(),
+            calls: Vec::new(),
+        }));
+        let target = A64Target::new(dap.clone(), DEBUG_BASE);
+        let cti = Cti {
+            dap: dap.clone(),
+            baseaddr: CTI_BASE,
+        };
+        (dap, target, cti)
+    }
+
+    const ALGO: FlashAlgorithm = FlashAlgorithm {
+        code: &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa],
+        program_page: 0x20,
+        stack_size: 0x100,
+    };
+
+    #[test]
+    fn flashloader_test() {
+        let (dap, mut target, mut cti) = halted_core();
+        let mut loader = FlashLoader::load(&mut target, &mut cti, &ALGO, SRAM).unwrap();
+        {
+            let ram = &dap.lock().dp.core.ram;
+            assert_eq!(Some(&0x4433_2211), ram.get(&SRAM));
+            assert_eq!(Some(&0x0000_aa99), ram.get(&(SRAM + 8)));
+            // 端数の後ろのwordにHLTを置く
+            assert_eq!(Some(&HLT), ram.get(&(SRAM + 0xc)));
+        }
+        // stackはHLTの後ろを16byteに揃えた所から
+        assert_eq!(SRAM + 0x110, loader.buffer());
+
+        dap.lock().dp.outcomes.push_back(Outcome::Return(0x55));
+        assert_eq!(0x55, loader.call(0x40, &[1, 0, 3]).unwrap());
+        assert_eq!(
+            vec![Call {
+                entry: SRAM + 0x40,
+                args: [1, 0, 3],
+                lr: SRAM + 0xc,
+                sp: SRAM + 0x110,
+                pstate: 0x3c5 | 0x3c0,
+                buffer: vec![],
+            }],
+            dap.lock().dp.calls
+        );
+        assert_eq!(
+            Err(DebugError::Interface(InterfaceError::OutOfRange)),
+            loader.call(0x40, &[0; 9])
+        );
+
+        // 10byteを4byteのpageに分け、最後は端数だけ渡す
+        dap.lock().dp.calls.clear();
+        let data: Vec<u8> = (0..10).collect();
+        loader.program(0x2000_0000, &data, 4).unwrap();
+        let calls: Vec<_> = dap
+            .lock()
+            .dp
+            .calls
+            .iter()
+            .map(|x| (x.entry, x.args, x.buffer.clone()))
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    SRAM + 0x20,
+                    [0x2000_0000, 4, SRAM + 0x110],
+                    vec![0, 1, 2, 3]
+                ),
+                (
+                    SRAM + 0x20,
+                    [0x2000_0004, 4, SRAM + 0x110],
+                    vec![4, 5, 6, 7]
+                ),
+                (SRAM + 0x20, [0x2000_0008, 2, SRAM + 0x110], vec![8, 9]),
+            ],
+            calls
+        );
+
+        // 失敗したpageで止める
+        dap.lock().dp.calls.clear();
+        dap.lock()
+            .dp
+            .outcomes
+            .extend([Outcome::Return(0), Outcome::Return(3)]);
+        assert_eq!(
+            Err(DebugError::AlgorithmFailed {
+                addr: 0x2000_0004,
+                code: 3,
+            }),
+            loader.program(0x2000_0000, &data, 4)
+        );
+        assert_eq!(2, dap.lock().dp.calls.len());
+
+        loader.unload().unwrap();
+        let guard = dap.lock();
+        assert_eq!([0x5a; 31], guard.dp.core.x);
+        assert_eq!(0x4008_0000, guard.dp.core.dlr);
+        assert_eq!(0x3c5, guard.dp.core.dspsr);
+    }
+
+    #[test]
+    fn flashloader_error_test() {
+        let (dap, mut target, mut cti) = halted_core();
+        let edprsr = DEBUG_BASE + Armv8DebugRegisterOffset::EDPRSR as u64;
+        dap.lock().dp.core.inner.memory.insert(edprsr, PU | SDR);
+        assert!(matches!(
+            FlashLoader::load(&mut target, &mut cti, &ALGO, SRAM),
+            Err(DebugError::NotHalted)
+        ));
+
+        dap.lock()
+            .dp
+            .core
+            .inner
+            .memory
+            .insert(edprsr, PU | SDR | HALTED);
+        let mut loader = FlashLoader::load(&mut target, &mut cti, &ALGO, SRAM).unwrap();
+        loader.set_budget(PollBudget::Iterations(10));
+
+        // 戻り先以外のbreakpointで止まった
+        dap.lock().dp.outcomes.push_back(Outcome::Halt(0b000111));
+        assert_eq!(
+            Err(DebugError::UnexpectedHalt {
+                reason: HaltReason::Breakpoint,
+                pc: SRAM + 0x30,
+            }),
+            loader.call(0x20, &[])
+        );
+
+        // returnしなければhaltさせてからtimeoutを返す
+        dap.lock().dp.outcomes.push_back(Outcome::Hang);
+        assert!(matches!(
+            loader.call(0x20, &[]),
+            Err(DebugError::HaltTimeout { .. })
+        ));
+        assert_eq!(
+            Some(&(PU | SDR | HALTED)),
+            dap.lock().dp.core.inner.memory.get(&edprsr)
+        );
+        assert_eq!(2, dap.lock().dp.calls.len());
+    }
+
+    #[test]
+    fn flashloader_guard_test() {
+        let (dap, mut target, mut cti) = halted_core();
+        let boot = AddrRange::new(0x2000_0000, 0x1000).unwrap();
+        dap.lock()
+            .set_write_guard(WriteGuard::new().deny("bootloader", boot));
+        let mut loader = FlashLoader::load(&mut target, &mut cti, &ALGO, SRAM).unwrap();
+
+        // 最後のpageだけが掛かっていても1pageも書かない
+        let data: Vec<u8> = (0..8).collect();
+        assert_eq!(
+            Err(DebugError::Dap(DapError::WriteProtected {
+                addr: 0x2000_0000,
+                region: boot
+            })),
+            loader.program(0x1fff_fffc, &data, 4)
+        );
+        assert!(dap.lock().dp.calls.is_empty());
+        loader.program(0x2000_1000, &data, 4).unwrap();
+        assert_eq!(2, dap.lock().dp.calls.len());
+    }
+
+    #[test]
+    fn flashloader_overflow_test() {
+        let (dap, mut target, mut cti) = halted_core();
+        // 配置がu64を越えるなら何も書かない
+        assert!(matches!(
+            FlashLoader::load(&mut target, &mut cti, &ALGO, u64::MAX - 0x10),
+            Err(DebugError::Interface(InterfaceError::OutOfRange))
+        ));
+        assert!(dap.lock().dp.core.ram.is_empty());
+
+        let mut loader = FlashLoader::load(&mut target, &mut cti, &ALGO, SRAM).unwrap();
+        assert_eq!(
+            Err(DebugError::Interface(InterfaceError::OutOfRange)),
+            loader.call(u64::MAX, &[])
+        );
+        assert!(dap.lock().dp.calls.is_empty());
+
+        // 最後のpageだけがu64を越える
+        let data: Vec<u8> = (0..8).collect();
+        assert_eq!(
+            Err(DebugError::Interface(InterfaceError::OutOfRange)),
+            loader.program(u64::MAX - 3, &data, 4)
+        );
+        assert_eq!(1, dap.lock().dp.calls.len());
+    }
+}
@@ -0,0 +1,468 @@
+// haltごとに取ったcoreの状態のring
+// CoreHandle::enable_historyで有効にすると、halt/step/wait_for_haltで止まる度に
+// save_contextのGPR/SP/PC/PSTATEとwatchしたwordを記録し、前のhaltとの差を出せる
+//
+// 1回の記録で増えるDAPのtransaction
+// - EDSCR 1回(halt reason)と、save_contextの34回のDTR転送
+// - watchしたword 1つにつきMA-modeの1word読み出しと、x0/x1の退避・復元
+// EDSCRのpoll回数で変わるが、CoreSimではAPへのaccessが256 + word数 * 46回に収まる(transaction_test)
+// haltの時間が足りない場合はHaltHistory::skip_nextで次の1回を飛ばす
+use alloc::collections::VecDeque;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::error::DebugError;
+use crate::jtag::dap::{DebugPort, MemoryAccessPort};
+use crate::target::arm64::{A64Target, CpuContext, HaltReason};
+
+pub const DEFAULT_HISTORY_DEPTH: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reg {
+    X(u8),
+    Sp,
+    Pc,
+    Pstate,
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reg::X(n) => write!(f, "x{}", n),
+            Reg::Sp => write!(f, "sp"),
+            Reg::Pc => write!(f, "pc"),
+            Reg::Pstate => write!(f, "pstate"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HaltRecord {
+    // 有効にしてから何回目の記録か。skipした分は数えない
+    pub seq: u64,
+    pub reason: HaltReason,
+    pub context: CpuContext,
+    // watchした順。読めなかったwordはNone
+    pub words: Vec<(u64, Option<u32>)>,
+}
+
+impl HaltRecord {
+    fn registers(&self) -> impl Iterator<Item = (Reg, u64)> + '_ {
+        let ctx = &self.context;
+        ctx.x
+            .iter()
+            .enumerate()
+            .map(|(n, x)| (Reg::X(n as u8), *x))
+            .chain([
+                (Reg::Sp, ctx.sp),
+                (Reg::Pc, ctx.pc),
+                (Reg::Pstate, ctx.pstate as u64),
+            ])
+    }
+
+    fn word(&self, addr: u64) -> Option<Option<u32>> {
+        self.words.iter().find(|x| x.0 == addr).map(|x| x.1)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RegChange {
+    pub reg: Reg,
+    pub old: u64,
+    pub new: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WordChange {
+    pub addr: u64,
+    pub old: Option<u32>,
+    pub new: Option<u32>,
+}
+
+// oldの記録からnewの記録までに変わったもの
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateDiff {
+    pub old_seq: u64,
+    pub new_seq: u64,
+    pub reason: HaltReason,
+    pub registers: Vec<RegChange>,
+    // 片方の記録にしか無いwordは含めない
+    pub words: Vec<WordChange>,
+}
+
+impl StateDiff {
+    pub fn new(old: &HaltRecord, new: &HaltRecord) -> Self {
+        let registers = old
+            .registers()
+            .zip(new.registers())
+            .filter(|(x, y)| x.1 != y.1)
+            .map(|(x, y)| RegChange {
+                reg: x.0,
+                old: x.1,
+                new: y.1,
+            })
+            .collect();
+        let words = new
+            .words
+            .iter()
+            .filter_map(|(addr, value)| {
+                let old = old.word(*addr)?;
+                (old != *value).then_some(WordChange {
+                    addr: *addr,
+                    old,
+                    new: *value,
+                })
+            })
+            .collect();
+        StateDiff {
+            old_seq: old.seq,
+            new_seq: new.seq,
+            reason: new.reason,
+            registers,
+            words,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty() && self.words.is_empty()
+    }
+}
+
+struct Word(Option<u32>);
+
+impl fmt::Display for Word {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(x) => write!(f, "{:#010x}", x),
+            None => write!(f, "(fault)"),
+        }
+    }
+}
+
+impl fmt::Display for StateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "halt #{} -> #{} ({:?})",
+            self.old_seq, self.new_seq, self.reason
+        )?;
+        if self.is_empty() {
+            return writeln!(f, "  no change");
+        }
+        for x in self.registers.iter() {
+            writeln!(
+                f,
+                "  {:<7}{:#018x} -> {:#018x}",
+                x.reg.to_string(),
+                x.old,
+                x.new
+            )?;
+        }
+        for x in self.words.iter() {
+            writeln!(f, "  [{:#x}] {} -> {}", x.addr, Word(x.old), Word(x.new))?;
+        }
+        Ok(())
+    }
+}
+
+pub struct HaltHistory {
+    depth: usize,
+    records: VecDeque<HaltRecord>,
+    watch: Vec<u64>,
+    seq: u64,
+    skip_next: bool,
+}
+
+impl HaltHistory {
+    // depthは最低1
+    pub fn new(depth: usize) -> Self {
+        HaltHistory {
+            depth: depth.max(1),
+            records: VecDeque::new(),
+            watch: Vec::new(),
+            seq: 0,
+            skip_next: false,
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    // 減らした場合は古いものから捨てる
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth.max(1);
+        while self.records.len() > self.depth {
+            self.records.pop_front();
+        }
+    }
+
+    // coreから見たaddressの32bit。MA-modeで読むのでhalt中のvirtual address
+    pub fn watch(&mut self, addr: u64) {
+        if !self.watch.contains(&addr) {
+            self.watch.push(addr);
+        }
+    }
+
+    pub fn unwatch(&mut self, addr: u64) {
+        self.watch.retain(|x| *x != addr);
+    }
+
+    pub fn watched(&self) -> &[u64] {
+        &self.watch
+    }
+
+    // 次の1回の記録を飛ばす。haltの後すぐにresumeしたい場合に使う
+    pub fn skip_next(&mut self) {
+        self.skip_next = true;
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    // haltしているcoreの状態を記録する。skip_nextの後は何も読まずにfalseを返す
+    pub fn capture<T: DebugPort + MemoryAccessPort>(
+        &mut self,
+        target: &mut A64Target<T>,
+    ) -> Result<bool, DebugError> {
+        if core::mem::take(&mut self.skip_next) {
+            return Ok(false);
+        }
+        let reason = target.halt_reason()?;
+        let context = target.save_context()?;
+        let mut words = Vec::with_capacity(self.watch.len());
+        for addr in self.watch.iter() {
+            let mut buf = [0; 4];
+            let value = match target.mem_read(*addr, &mut buf) {
+                Ok(()) => Some(u32::from_le_bytes(buf)),
+                // mapされていないaddressは記録を止めない
+                Err(DebugError::Fault) => None,
+                Err(e) => return Err(e),
+            };
+            words.push((*addr, value));
+        }
+        self.seq += 1;
+        self.push(HaltRecord {
+            seq: self.seq,
+            reason,
+            context,
+            words,
+        });
+        Ok(true)
+    }
+
+    fn push(&mut self, record: HaltRecord) {
+        if self.records.len() == self.depth {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    // 新しいものから最大n個
+    pub fn history(&self, n: usize) -> Vec<&HaltRecord> {
+        self.records.iter().rev().take(n).collect()
+    }
+
+    // 最後の2回の記録の差。2回記録していなければNone
+    pub fn last_diff(&self) -> Option<StateDiff> {
+        let mut records = self.records.iter().rev();
+        let new = records.next()?;
+        let old = records.next()?;
+        Some(StateDiff::new(old, new))
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::jtag::dap::DapHandle;
+    use crate::jtag::dap::DAP;
+    use crate::target::arm64::tests::{attached_core, CoreSim};
+    use crate::target::arm64::{Armv8DebugRegisterOffset, CoreHandle, PollBudget, GPR_SP};
+
+    type Core = (DapHandle<DAP<CoreSim>>, CoreHandle<DAP<CoreSim>>);
+
+    // 1回の記録でwatch以外に使うAPへのaccessの上限と、watchしたword 1つ分
+    const CAPTURE_AP_ACCESSES: u64 = 256;
+    const WORD_AP_ACCESSES: u64 = 46;
+
+    const WORD: u64 = 0x4000_0100;
+    const UNMAPPED: u64 = 0x4000_0200;
+
+    // halt済みで、WORDとUNMAPPEDをwatchしている
+    fn setup(depth: usize) -> Core {
+        let (dap, mut core) = attached_core();
+        {
+            let sim = &mut dap.lock().dp;
+            sim.x[3] = 0x1000;
+            sim.dlr = 0x4008_0000;
+            sim.dspsr = 0x3c5;
+            sim.ram.insert(WORD, 0x1111_1111);
+            sim.fault = Some(UNMAPPED);
+            *sim.dtr(Armv8DebugRegisterOffset::EDSCR) |= 0b010011;
+        }
+        core.enable_history(depth);
+        let history = core.halt_history_mut().unwrap();
+        history.watch(WORD);
+        history.watch(UNMAPPED);
+        (dap, core)
+    }
+
+    // 止まっている間にcoreが進んだことにする
+    fn run(dap: &DapHandle<DAP<CoreSim>>, x3: u64, pc: u64, word: u32) {
+        let sim = &mut dap.lock().dp;
+        sim.x[3] = x3;
+        sim.dlr = pc;
+        sim.ram.insert(WORD, word);
+    }
+
+    #[test]
+    fn diff_test() {
+        let (dap, mut core) = setup(4);
+        assert!(core.record_halt().unwrap());
+        assert_eq!(None, core.last_diff());
+        run(&dap, 0x1004, 0x4008_0004, 0x2222_2222);
+        assert!(core.record_halt().unwrap());
+
+        let diff = core.last_diff().unwrap();
+        assert_eq!((1, 2), (diff.old_seq, diff.new_seq));
+        assert_eq!(
+            vec![
+                RegChange {
+                    reg: Reg::X(3),
+                    old: 0x1000,
+                    new: 0x1004
+                },
+                RegChange {
+                    reg: Reg::Pc,
+                    old: 0x4008_0000,
+                    new: 0x4008_0004
+                },
+            ],
+            diff.registers
+        );
+        // 読めないwordは変わっていない
+        assert_eq!(
+            vec![WordChange {
+                addr: WORD,
+                old: Some(0x1111_1111),
+                new: Some(0x2222_2222)
+            }],
+            diff.words
+        );
+        assert_eq!(
+            Some(None),
+            core.history(1)[0].word(UNMAPPED),
+            "{:?}",
+            core.history(1)
+        );
+        // x0/x1は記録の後も元の値
+        assert_eq!(0, dap.lock().dp.x[0]);
+
+        let expected = "\
+halt #1 -> #2 (ExternalDebugRequest)
+  x3     0x0000000000001000 -> 0x0000000000001004
+  pc     0x0000000040080000 -> 0x0000000040080004
+  [0x40000100] 0x11111111 -> 0x22222222
+";
+        assert_eq!(expected, diff.to_string());
+
+        assert!(core.record_halt().unwrap());
+        assert_eq!(
+            "halt #2 -> #3 (ExternalDebugRequest)\n  no change\n",
+            core.last_diff().unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn auto_record_test() {
+        let (dap, mut core) = setup(4);
+        // stepで1命令進んですぐhaltする
+        dap.lock().dp.restart_runs = false;
+        run(&dap, 0x1000, 0x4008_0004, 0x1111_1111);
+        assert_eq!(0x4008_0004, core.step().unwrap());
+        core.wait_for_halt(PollBudget::Iterations(1)).unwrap();
+        let diff = core.last_diff().unwrap();
+        assert_eq!((1, 2), (diff.old_seq, diff.new_seq));
+        assert!(diff.is_empty());
+        // 既にhaltしていればhaltは記録しない
+        core.halt().unwrap();
+        assert_eq!(2, core.history(5).len());
+    }
+
+    #[test]
+    fn ring_test() {
+        let (dap, mut core) = setup(2);
+        for n in 0..3 {
+            run(&dap, n, 0x4008_0000 + n * 4, 0);
+            core.record_halt().unwrap();
+        }
+        // 古いものから捨てる
+        let seqs: Vec<u64> = core.history(5).iter().map(|x| x.seq).collect();
+        assert_eq!(vec![3, 2], seqs);
+        assert_eq!(
+            vec![3],
+            core.history(1).iter().map(|x| x.seq).collect::<Vec<_>>()
+        );
+        assert_eq!(1, core.history(2)[1].context.x[3]);
+
+        // skipした回は何も読まず、seqも進めない
+        core.halt_history_mut().unwrap().skip_next();
+        dap.lock().dp.inner.reads.clear();
+        dap.lock().dp.editr.clear();
+        assert!(!core.record_halt().unwrap());
+        assert!(dap.lock().dp.editr.is_empty());
+        assert!(core.record_halt().unwrap());
+        assert_eq!(4, core.history(1)[0].seq);
+
+        core.enable_history(1);
+        assert_eq!(1, core.history(5).len());
+        core.disable_history();
+        assert!(!core.record_halt().unwrap());
+        assert_eq!(None, core.last_diff());
+        assert!(core.history(5).is_empty());
+    }
+
+    #[test]
+    fn transaction_test() {
+        let (dap, mut core) = setup(4);
+        let ap_accesses = |dap: &DapHandle<DAP<CoreSim>>| {
+            let stats = dap.lock().stats();
+            stats.ap_reads + stats.ap_writes
+        };
+        dap.lock().set_collect_stats(true);
+        for words in (0..=2).rev() {
+            dap.lock().reset_stats();
+            core.record_halt().unwrap();
+            let accesses = ap_accesses(&dap);
+            assert!(
+                accesses <= CAPTURE_AP_ACCESSES + words * WORD_AP_ACCESSES,
+                "{} words: {}",
+                words,
+                accesses
+            );
+            if let Some(addr) = core.halt_history().unwrap().watched().last().copied() {
+                core.halt_history_mut().unwrap().unwatch(addr);
+            }
+        }
+
+        // registerを1つずつ読むより少ない
+        dap.lock().reset_stats();
+        for n in 0..=GPR_SP {
+            core.target.read_gpr(n).unwrap();
+        }
+        core.target.read_pc().unwrap();
+        core.target.read_cpsr().unwrap();
+        assert!(ap_accesses(&dap) > CAPTURE_AP_ACCESSES);
+    }
+}
@@ -0,0 +1,555 @@
+// addressの種類とcoreの状態から、memoryを読み書きする経路を選ぶ
+// physical: system MEM-APで直接
+// virtual + halt中: MMUが無効ならVA == PAとしてMEM-AP、小さければMA-mode、大きければATで引いてからMEM-AP
+// virtual + 実行中: NotHalted。allow_staleなら最後のhaltで引いた結果を使う
+use alloc::vec::Vec;
+use log::{debug, warn};
+
+use crate::error::{DapError, DebugError};
+use crate::interface::InterfaceError;
+use crate::jtag::dap::{DapHandle, DebugPort, MemoryAccessPort};
+use crate::jtag::stats::RouteStats;
+use crate::target::arm64::CoreHandle;
+
+// MA-modeは1wordごとにDTRとEDITRを往復するので、これより大きいrequestはATで引いてMEM-APで読み書きする
+pub const DEFAULT_MA_THRESHOLD: usize = 64;
+// ATで引く単位。最小のtranslation granule
+const PAGE_SIZE: u64 = 0x1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddrSpace {
+    Physical(u64),
+    Virtual(u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryPath {
+    MemAp,
+    MaMode,
+    // ATで引いたPAをMEM-APで読み書きした
+    Translated,
+}
+
+// 1回のhaltの間だけ有効なcoreの状態
+#[derive(Clone, Debug, PartialEq)]
+struct HaltState {
+    // 今のELのSCTLR_ELx.M
+    mmu: bool,
+    // ATで引いたVAのpageとPAのpage
+    pages: Vec<(u64, u64)>,
+}
+
+impl HaltState {
+    fn page(&self, va: u64) -> Option<u64> {
+        self.pages.iter().find(|x| x.0 == va).map(|x| x.1)
+    }
+}
+
+enum Route {
+    MaMode(u64),
+    // (physical address, byte数)をrequestの先頭から順に
+    MemAp(MemoryPath, Vec<(u64, usize)>),
+}
+
+pub struct MemoryRouter<M> {
+    // system memory用のMEM-AP
+    memory: DapHandle<M>,
+    ma_threshold: usize,
+    allow_stale: bool,
+    halted: Option<HaltState>,
+    // resume前のhaltで読んだ状態。allow_staleの場合だけ使う
+    stale: Option<HaltState>,
+    stats: RouteStats,
+}
+
+impl<M: MemoryAccessPort> MemoryRouter<M> {
+    pub fn new(memory: DapHandle<M>) -> Self {
+        MemoryRouter {
+            memory,
+            ma_threshold: DEFAULT_MA_THRESHOLD,
+            allow_stale: false,
+            halted: None,
+            stale: None,
+            stats: RouteStats::default(),
+        }
+    }
+
+    // このbyte数以下のvirtualのrequestはMA-modeで処理する
+    pub fn set_ma_threshold(&mut self, bytes: usize) {
+        self.ma_threshold = bytes;
+    }
+
+    // 実行中のcoreのvirtual addressを、最後のhaltで引いたtranslationで読み書きする
+    // その後にpage tableが変わっていれば別のmemoryに触る
+    pub fn set_allow_stale(&mut self, allow: bool) {
+        self.allow_stale = allow;
+    }
+
+    pub fn stats(&self) -> RouteStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = RouteStats::default();
+    }
+
+    // coreをresume/stepさせたら呼ぶ。SCTLRとtranslationを次のhaltで読み直させる
+    pub fn invalidate(&mut self) {
+        if let Some(state) = self.halted.take() {
+            self.stale = Some(state);
+        }
+    }
+
+    pub fn resume<T: DebugPort + MemoryAccessPort>(
+        &mut self,
+        core: &mut CoreHandle<T>,
+    ) -> Result<(), DebugError> {
+        self.invalidate();
+        core.resume()
+    }
+
+    pub fn read<T: DebugPort + MemoryAccessPort>(
+        &mut self,
+        core: &mut CoreHandle<T>,
+        space: AddrSpace,
+        buf: &mut [u8],
+    ) -> Result<MemoryPath, DebugError> {
+        let (chunks, path) = match self.route(core, space, buf.len())? {
+            Route::MaMode(va) => {
+                core.target.mem_read(va, buf)?;
+                return Ok(self.record(MemoryPath::MaMode));
+            }
+            Route::MemAp(path, chunks) => (chunks, path),
+        };
+        // check_faultsが無効なDAPでもbus errorをMA-modeへ回せるよう、最後にsticky flagを見る
+        let result = {
+            let mut memory = self.memory.lock();
+            let mut offset = 0;
+            chunks
+                .iter()
+                .try_for_each(|(pa, len)| {
+                    let view = memory.try_mem_read_view(*pa, *len)?;
+                    buf[offset..offset + len].copy_from_slice(view.bytes());
+                    offset += len;
+                    Ok(())
+                })
+                .and_then(|_| memory.check_sticky())
+        };
+        match (result, space) {
+            (Ok(()), _) => Ok(self.record(path)),
+            (Err(DapError::Fault { sticky }), AddrSpace::Virtual(va)) if self.halted.is_some() => {
+                warn!("MEM-AP read faulted ({}), retrying with MA-mode", sticky);
+                core.target.mem_read(va, buf)?;
+                Ok(self.record_fallback())
+            }
+            (Err(e), _) => Err(e.into()),
+        }
+    }
+
+    pub fn write<T: DebugPort + MemoryAccessPort>(
+        &mut self,
+        core: &mut CoreHandle<T>,
+        space: AddrSpace,
+        data: &[u8],
+    ) -> Result<MemoryPath, DebugError> {
+        let (chunks, path) = match self.route(core, space, data.len())? {
+            Route::MaMode(va) => {
+                core.target.mem_write(va, data)?;
+                return Ok(self.record(MemoryPath::MaMode));
+            }
+            Route::MemAp(path, chunks) => (chunks, path),
+        };
+        let result = {
+            let mut memory = self.memory.lock();
+            let mut offset = 0;
+            chunks
+                .iter()
+                .try_for_each(|(pa, len)| {
+                    mem_ap_write(&mut *memory, *pa, &data[offset..offset + len])?;
+                    offset += len;
+                    Ok(())
+                })
+                .and_then(|_| memory.check_sticky())
+        };
+        match (result, space) {
+            (Ok(()), _) => Ok(self.record(path)),
+            (Err(DapError::Fault { sticky }), AddrSpace::Virtual(va)) if self.halted.is_some() => {
+                warn!("MEM-AP write faulted ({}), retrying with MA-mode", sticky);
+                core.target.mem_write(va, data)?;
+                Ok(self.record_fallback())
+            }
+            (Err(e), _) => Err(e.into()),
+        }
+    }
+
+    fn record(&mut self, path: MemoryPath) -> MemoryPath {
+        let count = match path {
+            MemoryPath::MemAp => &mut self.stats.mem_ap,
+            MemoryPath::MaMode => &mut self.stats.ma_mode,
+            MemoryPath::Translated => &mut self.stats.translated,
+        };
+        *count += 1;
+        path
+    }
+
+    fn record_fallback(&mut self) -> MemoryPath {
+        self.stats.fallbacks += 1;
+        self.record(MemoryPath::MaMode)
+    }
+
+    fn route<T: DebugPort + MemoryAccessPort>(
+        &mut self,
+        core: &mut CoreHandle<T>,
+        space: AddrSpace,
+        len: usize,
+    ) -> Result<Route, DebugError> {
+        let va = match space {
+            AddrSpace::Physical(pa) => {
+                check_range(pa, len)?;
+                return Ok(Route::MemAp(MemoryPath::MemAp, [(pa, len)].to_vec()));
+            }
+            AddrSpace::Virtual(va) => va,
+        };
+        check_range(va, len)?;
+        if !core.is_halted()? {
+            // 知らない間にresumeされていた
+            self.invalidate();
+            if !self.allow_stale {
+                return Err(DebugError::NotHalted);
+            }
+            let state = self.stale.as_mut().ok_or(DebugError::NotHalted)?;
+            warn!(
+                "core is running, using the translation from the last halt for {:#x}",
+                va
+            );
+            if !state.mmu {
+                return Ok(Route::MemAp(MemoryPath::MemAp, [(va, len)].to_vec()));
+            }
+            let chunks = translate(state, None::<&mut CoreHandle<T>>, va, len)?;
+            return Ok(Route::MemAp(MemoryPath::Translated, chunks));
+        }
+
+        let state = match &mut self.halted {
+            Some(state) => state,
+            None => {
+                let mmu = core.target.mmu_enabled()?;
+                debug!("MMU is {} at this halt", if mmu { "on" } else { "off" });
+                self.halted.insert(HaltState {
+                    mmu,
+                    pages: Vec::new(),
+                })
+            }
+        };
+        if !state.mmu {
+            Ok(Route::MemAp(MemoryPath::MemAp, [(va, len)].to_vec()))
+        } else if len <= self.ma_threshold {
+            Ok(Route::MaMode(va))
+        } else {
+            let chunks = translate(state, Some(core), va, len)?;
+            Ok(Route::MemAp(MemoryPath::Translated, chunks))
+        }
+    }
+}
+
+fn check_range(addr: u64, len: usize) -> Result<(), DebugError> {
+    addr.checked_add(len as u64)
+        .map(|_| ())
+        .ok_or(DebugError::Interface(InterfaceError::OutOfRange))
+}
+
+// [va, va + len)をpage毎にPAへ引き、連続するPAはまとめる
+// coreがNoneなら引いたことのあるpageだけを使う
+fn translate<T: DebugPort + MemoryAccessPort>(
+    state: &mut HaltState,
+    mut core: Option<&mut CoreHandle<T>>,
+    va: u64,
+    len: usize,
+) -> Result<Vec<(u64, usize)>, DebugError> {
+    let mut chunks: Vec<(u64, usize)> = Vec::new();
+    let end = va + len as u64;
+    let mut addr = va;
+    while addr < end {
+        let page = addr & !(PAGE_SIZE - 1);
+        let pa_page = match (state.page(page), core.as_mut()) {
+            (Some(pa), _) => pa,
+            (None, Some(core)) => {
+                let pa = core
+                    .target
+                    .translate(page)?
+                    .ok_or(DebugError::TranslationFault { va: addr })?;
+                state.pages.push((page, pa));
+                pa
+            }
+            (None, None) => return Err(DebugError::NotHalted),
+        };
+        let n = (end - addr).min(PAGE_SIZE - (addr - page)) as usize;
+        let pa = pa_page + (addr - page);
+        match chunks.last_mut() {
+            Some(last) if last.0 + last.1 as u64 == pa => last.1 += n,
+            _ => chunks.push((pa, n)),
+        }
+        addr += n as u64;
+    }
+    Ok(chunks)
+}
+
+// 4byteに揃わない両端は元の値を読んで残す
+fn mem_ap_write<M: MemoryAccessPort>(dap: &mut M, addr: u64, data: &[u8]) -> Result<(), DapError> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    dap.check_write(addr, data.len() as u64)?;
+    let start = addr & !3;
+    let end = (addr + data.len() as u64 + 3) & !3;
+    let mut bytes = dap
+        .try_mem_read_view(start, (end - start) as usize)?
+        .bytes()
+        .to_vec();
+    let offset = (addr - start) as usize;
+    bytes[offset..offset + data.len()].copy_from_slice(data);
+    let words: Vec<u32> = bytes
+        .chunks(4)
+        .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+        .collect();
+    dap.try_mem_write_block(start, &words)
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::jtag::dap::tests::{memap_dap, MemApSim};
+    use crate::jtag::dap::DAP;
+    use crate::target::arm64::tests::{attached_core, CoreSim, DEBUG_BASE};
+    use crate::target::arm64::{encode_at_s1r, encode_mrs, Armv8DebugRegisterOffset, SysReg};
+
+    const VA: u64 = 0xffff_0000_0008_0000;
+    const PA: u64 = 0x4008_0000;
+
+    type Core = (DapHandle<DAP<CoreSim>>, CoreHandle<DAP<CoreSim>>);
+    type Memory = (DapHandle<DAP<MemApSim>>, MemoryRouter<DAP<MemApSim>>);
+
+    // halt済みのcoreと、それとは別のsystem memory
+    fn setup() -> (Core, Memory) {
+        let memory = DapHandle::new(memap_dap(MemApSim::new()));
+        let router = MemoryRouter::new(memory.clone());
+        (attached_core(), (memory, router))
+    }
+
+    fn mmu_on(dap: &DapHandle<DAP<CoreSim>>) {
+        let key = (encode_mrs(SysReg::SCTLR_EL1, 0) >> 5) & 0x7fff;
+        dap.lock().dp.sysregs.insert(key, 0x30d0_1805);
+    }
+
+    fn count(dap: &DapHandle<DAP<CoreSim>>, instruction: u32) -> usize {
+        dap.lock()
+            .dp
+            .editr
+            .iter()
+            .filter(|x| **x & !0x1f == instruction)
+            .count()
+    }
+
+    #[test]
+    fn physical_test() {
+        let ((dap, mut core), (memory, mut router)) = setup();
+        memory.lock().dp.memory.insert(0x1000, 0x4433_2211);
+        let mut buf = [0; 3];
+        assert_eq!(
+            MemoryPath::MemAp,
+            router
+                .read(&mut core, AddrSpace::Physical(0x1001), &mut buf)
+                .unwrap()
+        );
+        assert_eq!([0x22, 0x33, 0x44], buf);
+        assert_eq!(
+            MemoryPath::MemAp,
+            router
+                .write(&mut core, AddrSpace::Physical(0x1002), &[0xaa])
+                .unwrap()
+        );
+        assert_eq!(Some(&0x44aa_2211), memory.lock().dp.memory.get(&0x1000));
+        // coreには触らない
+        assert!(dap.lock().dp.editr.is_empty());
+        assert!(matches!(
+            router.read(&mut core, AddrSpace::Physical(u64::MAX), &mut buf),
+            Err(DebugError::Interface(InterfaceError::OutOfRange))
+        ));
+        assert_eq!(2, router.stats().mem_ap);
+    }
+
+    #[test]
+    fn virtual_test() {
+        let ((dap, mut core), (memory, mut router)) = setup();
+        // MMUが無効ならVA == PA
+        memory.lock().dp.memory.insert(0x2000, 0x1234_5678);
+        let mut buf = [0; 4];
+        assert_eq!(
+            MemoryPath::MemAp,
+            router
+                .read(&mut core, AddrSpace::Virtual(0x2000), &mut buf)
+                .unwrap()
+        );
+        assert_eq!(0x1234_5678, u32::from_le_bytes(buf));
+
+        // SCTLRはhaltの間覚えているので、invalidateで読み直させる。小さいrequestはMA-mode
+        mmu_on(&dap);
+        router.invalidate();
+        dap.lock().dp.ram.insert(VA, 0x8765_4321);
+        assert_eq!(
+            MemoryPath::MaMode,
+            router
+                .read(&mut core, AddrSpace::Virtual(VA), &mut buf)
+                .unwrap()
+        );
+        assert_eq!(0x8765_4321, u32::from_le_bytes(buf));
+        assert!(memory.lock().dp.reads.is_empty());
+
+        // 大きいrequestはpage毎に引いてMEM-AP。pageを跨ぐと別のPAになる
+        dap.lock().dp.translations.insert(VA, PA);
+        dap.lock().dp.translations.insert(VA + 0x1000, 0x5000_0000);
+        memory.lock().dp.memory.insert(PA + 0xffc, 0x0403_0201);
+        memory.lock().dp.memory.insert(0x5000_0000, 0x0807_0605);
+        let mut buf = [0; 128];
+        assert_eq!(
+            MemoryPath::Translated,
+            router
+                .read(&mut core, AddrSpace::Virtual(VA + 0xffc), &mut buf)
+                .unwrap()
+        );
+        assert_eq!([1, 2, 3, 4, 5, 6, 7, 8], buf[..8]);
+        let data = [0x5a; 128];
+        assert_eq!(
+            MemoryPath::Translated,
+            router
+                .write(&mut core, AddrSpace::Virtual(VA + 0xfc0), &data)
+                .unwrap()
+        );
+        assert_eq!(
+            Some(&0x5a5a_5a5a),
+            memory.lock().dp.memory.get(&(PA + 0xfc0))
+        );
+        assert_eq!(
+            Some(&0x5a5a_5a5a),
+            memory.lock().dp.memory.get(&0x5000_0000)
+        );
+        assert_eq!(None, memory.lock().dp.memory.get(&(PA + 0x1000)));
+        assert_eq!(
+            Some(&0x5a5a_5a5a),
+            memory.lock().dp.memory.get(&0x5000_003c)
+        );
+        assert_eq!(None, memory.lock().dp.memory.get(&0x5000_0040));
+
+        // 引けないpage
+        assert!(matches!(
+            router.read(&mut core, AddrSpace::Virtual(VA + 0x2f00), &mut buf),
+            Err(DebugError::TranslationFault { va }) if va == VA + 0x2f00
+        ));
+        let stats = router.stats();
+        assert_eq!(
+            (1, 1, 2, 0),
+            (
+                stats.mem_ap,
+                stats.ma_mode,
+                stats.translated,
+                stats.fallbacks
+            )
+        );
+    }
+
+    #[test]
+    fn fallback_test() {
+        let ((dap, mut core), (memory, mut router)) = setup();
+        mmu_on(&dap);
+        dap.lock().dp.translations.insert(VA, PA);
+        memory.lock().dp.poisoned = Some(PA + 0x40);
+        let data = [0xa5; 128];
+        assert_eq!(
+            MemoryPath::MaMode,
+            router
+                .write(&mut core, AddrSpace::Virtual(VA), &data)
+                .unwrap()
+        );
+        assert_eq!(Some(&0xa5a5_a5a5), dap.lock().dp.ram.get(&(VA + 0x40)));
+        assert_eq!(1, router.stats().fallbacks);
+
+        // physicalはcoreからは読み直せない
+        assert!(matches!(
+            router.write(&mut core, AddrSpace::Physical(PA), &data),
+            Err(DebugError::Dap(DapError::Fault { .. }))
+        ));
+    }
+
+    #[test]
+    fn running_test() {
+        let ((dap, mut core), (memory, mut router)) = setup();
+        mmu_on(&dap);
+        dap.lock().dp.translations.insert(VA, PA);
+        memory.lock().dp.memory.insert(PA, 0x1122_3344);
+        let mut buf = [0; 128];
+        router
+            .read(&mut core, AddrSpace::Virtual(VA), &mut buf)
+            .unwrap();
+        router.resume(&mut core).unwrap();
+        assert!(!core.is_halted().unwrap());
+
+        assert!(matches!(
+            router.read(&mut core, AddrSpace::Virtual(VA), &mut buf),
+            Err(DebugError::NotHalted)
+        ));
+        // physicalは実行中でも読める
+        router
+            .read(&mut core, AddrSpace::Physical(PA), &mut buf)
+            .unwrap();
+
+        // 最後のhaltで引いたpageだけを使う
+        router.set_allow_stale(true);
+        let mut buf = [0; 4];
+        assert_eq!(
+            MemoryPath::Translated,
+            router
+                .read(&mut core, AddrSpace::Virtual(VA), &mut buf)
+                .unwrap()
+        );
+        assert_eq!(0x1122_3344, u32::from_le_bytes(buf));
+        assert!(matches!(
+            router.read(&mut core, AddrSpace::Virtual(VA + 0x1000), &mut buf),
+            Err(DebugError::NotHalted)
+        ));
+    }
+
+    #[test]
+    fn cache_test() {
+        let ((dap, mut core), (_, mut router)) = setup();
+        mmu_on(&dap);
+        dap.lock().dp.translations.insert(VA, PA);
+        let sctlr = encode_mrs(SysReg::SCTLR_EL1, 0);
+        let at = encode_at_s1r(1, 0);
+        let mut buf = [0; 128];
+        for _ in 0..3 {
+            router
+                .read(&mut core, AddrSpace::Virtual(VA), &mut buf)
+                .unwrap();
+        }
+        assert_eq!((1, 1), (count(&dap, sctlr), count(&dap, at)));
+
+        // resumeした後のhaltでは読み直す
+        router.resume(&mut core).unwrap();
+        let edprsr = DEBUG_BASE + Armv8DebugRegisterOffset::EDPRSR as u64;
+        *dap.lock().dp.inner.memory.get_mut(&edprsr).unwrap() |= 1 << 4;
+        router
+            .read(&mut core, AddrSpace::Virtual(VA), &mut buf)
+            .unwrap();
+        assert_eq!((2, 2), (count(&dap, sctlr), count(&dap, at)));
+
+        // invalidateを呼ばずに走らせても、実行中に気付いて捨てる
+        *dap.lock().dp.inner.memory.get_mut(&edprsr).unwrap() &= !(1 << 4);
+        assert!(router
+            .read(&mut core, AddrSpace::Virtual(VA), &mut buf)
+            .is_err());
+        *dap.lock().dp.inner.memory.get_mut(&edprsr).unwrap() |= 1 << 4;
+        router
+            .read(&mut core, AddrSpace::Virtual(VA), &mut buf)
+            .unwrap();
+        assert_eq!((3, 3), (count(&dap, sctlr), count(&dap, at)));
+    }
+}
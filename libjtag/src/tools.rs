@@ -0,0 +1,10 @@
+#[cfg(feature = "async")]
+pub mod async_session;
+#[cfg(feature = "std")]
+pub mod memfile;
+#[cfg(feature = "alloc")]
+pub mod memview;
+#[cfg(feature = "probe-rs-adapter")]
+pub mod probe_rs_adapter;
+#[cfg(feature = "std")]
+pub mod snapshot;
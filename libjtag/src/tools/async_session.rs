@@ -0,0 +1,451 @@
+// blockingなcore/MEM-APの操作を専用のworker threadで行い、async側からFutureとして待つ
+// FTDIのUSB転送はworker thread上でしか行わないので、async runtimeのthreadを止めない
+// std::futureだけで作ってあり、tokio等どのruntimeからでも.awaitできる
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::error::DebugError;
+use crate::jtag::dap::{DebugPort, MemoryAccessPort};
+use crate::target::arm64::{CoreHandle, HaltReason};
+
+// read_memが1回に読むword数。この境界でcancelと他のcommandを確認する
+pub const CHUNK_WORDS: usize = 256;
+
+// workerが書いた結果と、それを待っているtaskのWaker
+type ReplyState<V> = (Option<Result<V, DebugError>>, Option<Waker>);
+
+struct Shared<V> {
+    state: Mutex<ReplyState<V>>,
+    cancelled: AtomicBool,
+}
+
+impl<V> Shared<V> {
+    // Responderはworkerのpanicでunwind中にdropされるので、poisonでpanicしない
+    fn state(&self) -> MutexGuard<'_, ReplyState<V>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// AsyncSessionの各methodが返すFuture
+// 完了前にdropするとworkerにcancelが伝わり、read_mem等は次のchunkの前で止まる
+pub struct Reply<V> {
+    shared: Arc<Shared<V>>,
+    done: bool,
+}
+
+impl<V> Future for Reply<V> {
+    type Output = Result<V, DebugError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.shared.state();
+        match state.0.take() {
+            Some(result) => {
+                drop(state);
+                self.done = true;
+                Poll::Ready(result)
+            }
+            None => {
+                state.1 = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<V> Drop for Reply<V> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.shared.cancelled.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+// worker側の返信口
+// 結果を返さずにdropされた(workerが居ない、panicした)場合はSessionClosedを返す
+struct Responder<V> {
+    shared: Arc<Shared<V>>,
+}
+
+impl<V> Responder<V> {
+    fn finish(&self, result: Result<V, DebugError>) {
+        let mut state = self.shared.state();
+        state.0 = Some(result);
+        if let Some(waker) = state.1.take() {
+            waker.wake();
+        }
+    }
+
+    fn cancelled(&self) -> bool {
+        self.shared.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl<V> Drop for Responder<V> {
+    fn drop(&mut self) {
+        let finished = self.shared.state().0.is_some();
+        if !finished {
+            self.finish(Err(DebugError::SessionClosed));
+        }
+    }
+}
+
+fn reply_pair<V>() -> (Reply<V>, Responder<V>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new((None, None)),
+        cancelled: AtomicBool::new(false),
+    });
+    (
+        Reply {
+            shared: shared.clone(),
+            done: false,
+        },
+        Responder { shared },
+    )
+}
+
+enum Request {
+    Halt(Responder<()>),
+    IsHalted(Responder<bool>),
+    ReadMem {
+        addr: u64,
+        len: usize,
+        reply: Responder<Vec<u8>>,
+    },
+    WaitForHalt {
+        deadline: Instant,
+        reply: Responder<HaltReason>,
+    },
+}
+
+// 複数回に分けて進めるcommand
+enum Job {
+    ReadMem {
+        address: u64,
+        end: u64,
+        // 4byte境界に広げた分
+        offset: usize,
+        len: usize,
+        data: Vec<u8>,
+        reply: Responder<Vec<u8>>,
+    },
+    WaitForHalt {
+        deadline: Instant,
+        reply: Responder<HaltReason>,
+    },
+}
+
+struct Worker<T, M> {
+    core: CoreHandle<T>,
+    memory: M,
+}
+
+impl<T: DebugPort + MemoryAccessPort, M: MemoryAccessPort> Worker<T, M> {
+    // 進行中のjobを1段ずつ順に進め、その合間に届いたcommandを処理する
+    // session側が閉じても、残ったjobを終えるまでは抜けない
+    fn run(mut self, requests: Receiver<Request>) {
+        let mut jobs = VecDeque::new();
+        loop {
+            let request = if jobs.is_empty() {
+                match requests.recv() {
+                    Ok(request) => Some(request),
+                    Err(_) => return,
+                }
+            } else {
+                requests.try_recv().ok()
+            };
+            if let Some(request) = request {
+                jobs.extend(self.start(request));
+                continue;
+            }
+            if let Some(job) = jobs.pop_front() {
+                jobs.extend(self.step(job));
+            }
+        }
+    }
+
+    fn start(&mut self, request: Request) -> Option<Job> {
+        match request {
+            Request::Halt(reply) => {
+                reply.finish(self.core.halt());
+                None
+            }
+            Request::IsHalted(reply) => {
+                reply.finish(self.core.is_halted().map_err(DebugError::from));
+                None
+            }
+            Request::ReadMem { addr, len, reply } => {
+                let start = addr & !3;
+                let end = (addr + len as u64 + 3) & !3;
+                Some(Job::ReadMem {
+                    address: start,
+                    end,
+                    offset: (addr - start) as usize,
+                    len,
+                    data: Vec::with_capacity((end - start) as usize),
+                    reply,
+                })
+            }
+            Request::WaitForHalt { deadline, reply } => Some(Job::WaitForHalt { deadline, reply }),
+        }
+    }
+
+    // 終わったjobはNoneを返す
+    fn step(&mut self, job: Job) -> Option<Job> {
+        match job {
+            Job::ReadMem {
+                address,
+                end,
+                offset,
+                len,
+                mut data,
+                reply,
+            } => {
+                if reply.cancelled() {
+                    debug!("memory read cancelled at {:#x}", address);
+                    return None;
+                }
+                if address >= end {
+                    reply.finish(Ok(data[offset..offset + len].to_vec()));
+                    return None;
+                }
+                let count = (((end - address) / 4) as usize).min(CHUNK_WORDS);
+                let mut words = [0u32; CHUNK_WORDS];
+                if let Err(e) = self.memory.try_mem_read_block(address, &mut words[..count]) {
+                    reply.finish(Err(e.into()));
+                    return None;
+                }
+                data.extend(words[..count].iter().flat_map(|x| x.to_le_bytes()));
+                Some(Job::ReadMem {
+                    address: address + count as u64 * 4,
+                    end,
+                    offset,
+                    len,
+                    data,
+                    reply,
+                })
+            }
+            Job::WaitForHalt { deadline, reply } => {
+                if reply.cancelled() {
+                    return None;
+                }
+                match self.core.is_halted() {
+                    Ok(true) => {
+                        reply.finish(self.core.target.halt_reason().map_err(DebugError::from))
+                    }
+                    Ok(false) if Instant::now() < deadline => {
+                        return Some(Job::WaitForHalt { deadline, reply })
+                    }
+                    Ok(false) => reply.finish(Err(DebugError::Timeout)),
+                    Err(e) => reply.finish(Err(e.into())),
+                }
+                None
+            }
+        }
+    }
+}
+
+// dropするとworkerは残ったcommandを終えてから終了する
+pub struct AsyncSession {
+    requests: Sender<Request>,
+}
+
+impl AsyncSession {
+    // coreとmemoryはworker threadへ移る
+    // memoryにはsystem memoryが見えるMEM-APを選んだDAPを渡す
+    pub fn spawn<T, M>(core: CoreHandle<T>, memory: M) -> Self
+    where
+        T: DebugPort + MemoryAccessPort + Send + 'static,
+        M: MemoryAccessPort + Send + 'static,
+    {
+        let (requests, receiver) = mpsc::channel();
+        thread::spawn(move || Worker { core, memory }.run(receiver));
+        AsyncSession { requests }
+    }
+
+    fn request<V>(&self, make: impl FnOnce(Responder<V>) -> Request) -> Reply<V> {
+        let (reply, responder) = reply_pair();
+        // workerが居なければrequestごとresponderが捨てられ、SessionClosedになる
+        let _ = self.requests.send(make(responder));
+        reply
+    }
+
+    pub fn halt(&self) -> Reply<()> {
+        self.request(Request::Halt)
+    }
+
+    pub fn is_halted(&self) -> Reply<bool> {
+        self.request(Request::IsHalted)
+    }
+
+    pub fn read_mem(&self, addr: u64, len: usize) -> Reply<Vec<u8>> {
+        self.request(|reply| Request::ReadMem { addr, len, reply })
+    }
+
+    pub fn wait_for_halt(&self, timeout: Duration) -> Reply<HaltReason> {
+        let deadline = Instant::now() + timeout;
+        self.request(|reply| Request::WaitForHalt { deadline, reply })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::InterfaceError;
+    use crate::jtag::dap::tests::{memap_dap, MemApSim};
+    use crate::jtag::dap::{DapHandle, DapInterface};
+    use crate::target::arm64::{Arm64Soc, Armv8DebugRegisterOffset, CoreBase};
+    use std::sync::atomic::AtomicUsize;
+    use std::task::Wake;
+
+    const CORE: CoreBase = CoreBase {
+        debug: 0x8001_0000,
+        cti: 0x8001_8000,
+    };
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    fn poll_once<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        Pin::new(future).poll(&mut Context::from_waker(&waker))
+    }
+
+    // TARが書かれる(chunkが始まる)度にstartedへ知らせ、testからの許可を待つMEM-AP
+    struct GateSim {
+        inner: MemApSim,
+        started: Sender<()>,
+        gate: Receiver<()>,
+        chunks: Arc<AtomicUsize>,
+    }
+
+    impl DapInterface for GateSim {
+        fn apacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            let tar_writes = self.inner.tar_writes;
+            let result = self.inner.apacc(data, a, rnw)?;
+            if self.inner.tar_writes != tar_writes {
+                let _ = self.started.send(());
+                // 止まったままにならないよう、許可が来なくても一定時間で進める
+                let _ = self.gate.recv_timeout(Duration::from_secs(5));
+                self.chunks.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(result)
+        }
+        fn dpacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            self.inner.dpacc(data, a, rnw)
+        }
+    }
+
+    #[test]
+    fn responder_drop_poisoned_test() {
+        let (reply, responder) = reply_pair::<()>();
+        // lockを持ったままpanicしてpoisonする
+        let shared = responder.shared.clone();
+        thread::spawn(move || {
+            let _state = shared.state.lock().unwrap();
+            panic!("worker panicked");
+        })
+        .join()
+        .unwrap_err();
+        drop(responder);
+        assert_eq!(Err(DebugError::SessionClosed), block_on(reply));
+    }
+
+    #[test]
+    fn read_mem_test() {
+        let dap = DapHandle::new(memap_dap(MemApSim::new()));
+        let core = Arm64Soc::new(dap, &[CORE]).core(0);
+        let mut sim = MemApSim::new();
+        for i in 0..(CHUNK_WORDS as u64 * 2) {
+            sim.memory.insert(0x1000 + i * 4, i as u32);
+        }
+        let session = AsyncSession::spawn(core, memap_dap(sim));
+
+        let data = block_on(session.read_mem(0x1002, CHUNK_WORDS * 4 + 4)).unwrap();
+        assert_eq!(CHUNK_WORDS * 4 + 4, data.len());
+        assert_eq!([0, 0, 1, 0, 0, 0, 2, 0], data[..8]);
+        assert_eq!(
+            Vec::<u8>::new(),
+            block_on(session.read_mem(0x1000, 0)).unwrap()
+        );
+    }
+
+    #[test]
+    fn status_during_read_test() {
+        let dap = DapHandle::new(memap_dap(MemApSim::new()));
+        let edprsr = CORE.debug + Armv8DebugRegisterOffset::EDPRSR as u64;
+        dap.lock().dp.memory.insert(edprsr, 1 << 4);
+        let core = Arm64Soc::new(dap, &[CORE]).core(0);
+        let (permit, gate) = mpsc::channel();
+        let (notify, started) = mpsc::channel();
+        let chunks = Arc::new(AtomicUsize::new(0));
+        let memory = memap_dap(GateSim {
+            inner: MemApSim::new(),
+            started: notify,
+            gate,
+            chunks: chunks.clone(),
+        });
+        let session = AsyncSession::spawn(core, memory);
+
+        let timeout = Duration::from_secs(5);
+        let mut read = session.read_mem(0, CHUNK_WORDS * 4 * 4);
+        // workerが1つ目のchunkで止まっている間にstatusを頼む
+        started.recv_timeout(timeout).unwrap();
+        let status = session.is_halted();
+        permit.send(()).unwrap();
+        // chunkの合間にstatusが返り、readはまだ終わっていない
+        assert_eq!(Ok(true), block_on(status));
+        assert!(poll_once(&mut read).is_pending());
+
+        // 2つ目のchunkの途中でcancelする。3つ目は始まらない
+        started.recv_timeout(timeout).unwrap();
+        drop(read);
+        permit.send(()).unwrap();
+        assert_eq!(Ok(true), block_on(session.is_halted()));
+        assert!(started.recv_timeout(Duration::from_millis(100)).is_err());
+        assert_eq!(2, chunks.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn wait_for_halt_test() {
+        let dap = DapHandle::new(memap_dap(MemApSim::new()));
+        let core = Arm64Soc::new(dap.clone(), &[CORE]).core(0);
+        let session = AsyncSession::spawn(core, memap_dap(MemApSim::new()));
+
+        assert_eq!(
+            Err(DebugError::Timeout),
+            block_on(session.wait_for_halt(Duration::from_millis(10)))
+        );
+        let wait = session.wait_for_halt(Duration::from_secs(5));
+        let edprsr = CORE.debug + Armv8DebugRegisterOffset::EDPRSR as u64;
+        dap.lock().dp.memory.insert(edprsr, 1 << 4);
+        assert!(block_on(wait).is_ok());
+        // 既にhaltしていれば何もしない
+        assert_eq!(Ok(()), block_on(session.halt()));
+    }
+}
@@ -0,0 +1,678 @@
+// target memoryとfileの間の変換。binary, Intel HEX, Motorola S-recordを扱う
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::jtag::dap::{DapError, MemoryAccessPort};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Bin,
+    Ihex,
+    Srec,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bin" => Some(Format::Bin),
+            "ihex" | "hex" => Some(Format::Ihex),
+            "srec" => Some(Format::Srec),
+            _ => None,
+        }
+    }
+
+    // 拡張子から決める。分からなければbinary
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .and_then(|x| x.to_str())
+            .unwrap_or("")
+            .to_lowercase();
+        match extension.as_str() {
+            "hex" | "ihex" | "ihx" => Format::Ihex,
+            "srec" | "s19" | "s28" | "s37" | "mot" => Format::Srec,
+            _ => Format::Bin,
+        }
+    }
+}
+
+// 1 recordあたりのdata byte数
+pub const DEFAULT_RECORD_LEN: usize = 16;
+// 1回のmem_read_block/mem_write_blockで扱うword数。MEM-APの1KB境界に合わせる
+const CHUNK_WORDS: usize = 256;
+
+// 連続したmemoryの塊
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub addr: u64,
+    pub data: Vec<u8>,
+}
+
+// load後の読み戻しで最初に一致しなかったaddress
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VerifyError {
+    pub addr: u64,
+    pub expected: u8,
+    pub actual: u8,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "verify failed at {:#x}: wrote {:#04x}, read {:#04x}",
+            self.addr, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MemfileError {
+    // fileが読めない、書けない
+    Io(String),
+    // ihex/srecのrecordが読めない。lineは1から数え、file全体の問題ならNone
+    Parse {
+        line: Option<usize>,
+        reason: &'static str,
+    },
+    // recordで表せないaddressやrecord長
+    Invalid(String),
+    // binaryはaddressを持たない
+    MissingLoadAddress,
+    Dap(DapError),
+    Verify(VerifyError),
+}
+
+impl fmt::Display for MemfileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemfileError::Io(message) => write!(f, "{}", message),
+            MemfileError::Parse {
+                line: Some(line),
+                reason,
+            } => write!(f, "line {}: {}", line, reason),
+            MemfileError::Parse { line: None, reason } => write!(f, "{}", reason),
+            MemfileError::Invalid(message) => write!(f, "{}", message),
+            MemfileError::MissingLoadAddress => {
+                write!(f, "a load address is required for binary files")
+            }
+            MemfileError::Dap(e) => write!(f, "{}", e),
+            MemfileError::Verify(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for MemfileError {}
+
+impl From<DapError> for MemfileError {
+    fn from(e: DapError) -> Self {
+        MemfileError::Dap(e)
+    }
+}
+
+impl From<VerifyError> for MemfileError {
+    fn from(e: VerifyError) -> Self {
+        MemfileError::Verify(e)
+    }
+}
+
+fn parse_error(line: Option<usize>, reason: &'static str) -> MemfileError {
+    MemfileError::Parse { line, reason }
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |x, y| x.wrapping_add(*y))
+}
+
+fn hex_line(prefix: &str, bytes: &[u8], check: u8) -> String {
+    let mut line = String::from(prefix);
+    for x in bytes {
+        line.push_str(&format!("{:02X}", x));
+    }
+    line.push_str(&format!("{:02X}\n", check));
+    line
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+// 32bitのaddressに収まるか確認する
+fn check_32bit(addr: u64, len: usize) -> Result<(), MemfileError> {
+    if addr + len as u64 > 1 << 32 {
+        return Err(MemfileError::Invalid(format!(
+            "{:#x}+{:#x} does not fit in 32-bit record addresses",
+            addr, len
+        )));
+    }
+    Ok(())
+}
+
+// 64KBを越える場合はtype 04(extended linear address)で上位16bitを切り替える
+pub fn write_ihex(addr: u64, data: &[u8], record_len: usize) -> Result<String, MemfileError> {
+    if record_len == 0 || record_len > 255 {
+        return Err(MemfileError::Invalid(format!(
+            "invalid ihex record length: {}",
+            record_len
+        )));
+    }
+    check_32bit(addr, data.len())?;
+    let mut text = String::new();
+    let mut upper = 0;
+    let mut offset = 0;
+    while offset < data.len() {
+        let address = addr + offset as u64;
+        if address >> 16 != upper {
+            upper = address >> 16;
+            let bytes = [2, 0, 0, 0x04, (upper >> 8) as u8, upper as u8];
+            text.push_str(&hex_line(":", &bytes, checksum(&bytes).wrapping_neg()));
+        }
+        // recordは64KB境界を跨がない
+        let boundary = (0x1_0000 - (address & 0xffff)) as usize;
+        let len = record_len.min(data.len() - offset).min(boundary);
+        let mut bytes = vec![len as u8, (address >> 8) as u8, address as u8, 0x00];
+        bytes.extend_from_slice(&data[offset..offset + len]);
+        text.push_str(&hex_line(":", &bytes, checksum(&bytes).wrapping_neg()));
+        offset += len;
+    }
+    text.push_str(":00000001FF\n");
+    Ok(text)
+}
+
+// 連続するrecordは1つのSegmentにまとめる
+fn push_data(segments: &mut Vec<Segment>, addr: u64, data: &[u8]) {
+    match segments.last_mut() {
+        Some(last) if last.addr + last.data.len() as u64 == addr => {
+            last.data.extend_from_slice(data)
+        }
+        _ => segments.push(Segment {
+            addr,
+            data: data.to_vec(),
+        }),
+    }
+}
+
+pub fn parse_ihex(text: &str) -> Result<Vec<Segment>, MemfileError> {
+    let mut segments = Vec::new();
+    let mut base = 0;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |reason| parse_error(Some(i + 1), reason);
+        let bytes = line
+            .strip_prefix(':')
+            .and_then(parse_hex_bytes)
+            .ok_or_else(|| error("not an ihex record"))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(error("bad record length"));
+        }
+        if checksum(&bytes) != 0 {
+            return Err(error("bad checksum"));
+        }
+        let offset = (bytes[1] as u64) << 8 | bytes[2] as u64;
+        let data = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            0x00 => push_data(&mut segments, base + offset, data),
+            0x01 => return Ok(segments),
+            0x02 if data.len() == 2 => base = ((data[0] as u64) << 8 | data[1] as u64) << 4,
+            0x04 if data.len() == 2 => base = ((data[0] as u64) << 8 | data[1] as u64) << 16,
+            // start address
+            0x03 | 0x05 => (),
+            _ => return Err(error("unsupported record type")),
+        }
+    }
+    Err(parse_error(None, "missing end of file record"))
+}
+
+// 最後のaddressに合わせてS1(16bit), S2(24bit), S3(32bit)を選ぶ
+pub fn write_srec(addr: u64, data: &[u8], record_len: usize) -> Result<String, MemfileError> {
+    check_32bit(addr, data.len())?;
+    let last = (addr + data.len() as u64).saturating_sub(1);
+    let (data_type, end_type, addr_len) = match last {
+        0..=0xffff => ('1', '9', 2),
+        0x1_0000..=0xff_ffff => ('2', '8', 3),
+        _ => ('3', '7', 4),
+    };
+    if record_len == 0 || record_len > 255 - addr_len - 1 {
+        return Err(MemfileError::Invalid(format!(
+            "invalid srec record length: {}",
+            record_len
+        )));
+    }
+    let record = |kind: char, address: u64, payload: &[u8]| {
+        let mut bytes = vec![(addr_len + payload.len() + 1) as u8];
+        bytes.extend((0..addr_len).rev().map(|i| (address >> (i * 8)) as u8));
+        bytes.extend_from_slice(payload);
+        hex_line(&format!("S{}", kind), &bytes, !checksum(&bytes))
+    };
+    let header = [3u8, 0, 0];
+    let mut text = hex_line("S0", &header, !checksum(&header));
+    let mut count = 0;
+    for (i, chunk) in data.chunks(record_len).enumerate() {
+        text.push_str(&record(data_type, addr + (i * record_len) as u64, chunk));
+        count += 1;
+    }
+    // S5/S6はdata recordの数
+    let count_bytes: Vec<u8> = if count <= 0xffff {
+        vec![3, (count >> 8) as u8, count as u8]
+    } else {
+        vec![4, (count >> 16) as u8, (count >> 8) as u8, count as u8]
+    };
+    let count_type = if count <= 0xffff { "S5" } else { "S6" };
+    text.push_str(&hex_line(count_type, &count_bytes, !checksum(&count_bytes)));
+    text.push_str(&record(end_type, 0, &[]));
+    Ok(text)
+}
+
+pub fn parse_srec(text: &str) -> Result<Vec<Segment>, MemfileError> {
+    let mut segments = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |reason| parse_error(Some(i + 1), reason);
+        if line.len() < 2 || !line.starts_with('S') {
+            return Err(error("not an S-record"));
+        }
+        let bytes = parse_hex_bytes(&line[2..]).ok_or_else(|| error("invalid hex digits"))?;
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+            return Err(error("bad record length"));
+        }
+        if checksum(&bytes) != 0xff {
+            return Err(error("bad checksum"));
+        }
+        let addr_len = match &line[1..2] {
+            "0" | "1" | "5" | "9" => 2,
+            "2" | "6" | "8" => 3,
+            "3" | "7" => 4,
+            _ => return Err(error("unsupported record type")),
+        };
+        if bytes.len() < addr_len + 2 {
+            return Err(error("bad record length"));
+        }
+        let address = bytes[1..1 + addr_len]
+            .iter()
+            .fold(0u64, |x, y| (x << 8) | *y as u64);
+        match &line[1..2] {
+            "1" | "2" | "3" => push_data(
+                &mut segments,
+                address,
+                &bytes[1 + addr_len..bytes.len() - 1],
+            ),
+            "7" | "8" | "9" => return Ok(segments),
+            _ => (),
+        }
+    }
+    Err(parse_error(None, "missing termination record"))
+}
+
+pub fn encode(
+    format: Format,
+    addr: u64,
+    data: &[u8],
+    record_len: usize,
+) -> Result<Vec<u8>, MemfileError> {
+    match format {
+        Format::Bin => Ok(data.to_vec()),
+        Format::Ihex => Ok(write_ihex(addr, data, record_len)?.into_bytes()),
+        Format::Srec => Ok(write_srec(addr, data, record_len)?.into_bytes()),
+    }
+}
+
+// binaryはaddressを持たないので0に置く
+pub fn decode(format: Format, bytes: &[u8]) -> Result<Vec<Segment>, MemfileError> {
+    match format {
+        Format::Bin => Ok(vec![Segment {
+            addr: 0,
+            data: bytes.to_vec(),
+        }]),
+        Format::Ihex => parse_ihex(
+            std::str::from_utf8(bytes).map_err(|_| parse_error(None, "ihex is not text"))?,
+        ),
+        Format::Srec => parse_srec(
+            std::str::from_utf8(bytes).map_err(|_| parse_error(None, "srec is not text"))?,
+        ),
+    }
+}
+
+// progressには(済んだbyte数, 全体のbyte数)を渡す
+pub fn read_memory<M: MemoryAccessPort>(
+    dap: &mut M,
+    addr: u64,
+    len: usize,
+    progress: &mut dyn FnMut(usize, usize),
+) -> Result<Vec<u8>, DapError> {
+    // 4byte境界に広げて読む
+    let start = addr & !3;
+    let end = (addr + len as u64 + 3) & !3;
+    let mut data = Vec::with_capacity((end - start) as usize);
+    let mut words = [0u32; CHUNK_WORDS];
+    let mut address = start;
+    while address < end {
+        let count = (((end - address) / 4) as usize).min(CHUNK_WORDS);
+        dap.try_mem_read_block(address, &mut words[..count])?;
+        data.extend(words[..count].iter().flat_map(|x| x.to_le_bytes()));
+        address += count as u64 * 4;
+        progress((address.saturating_sub(addr) as usize).min(len), len);
+    }
+    let offset = (addr - start) as usize;
+    Ok(data[offset..offset + len].to_vec())
+}
+
+pub fn write_memory<M: MemoryAccessPort>(
+    dap: &mut M,
+    addr: u64,
+    data: &[u8],
+    progress: &mut dyn FnMut(usize, usize),
+) -> Result<(), DapError> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    dap.check_write(addr, data.len() as u64)?;
+    let start = addr & !3;
+    let end = (addr + data.len() as u64 + 3) & !3;
+    let mut buffer = vec![0u8; (end - start) as usize];
+    // 端が4byteに揃っていなければ元の値を残す
+    if start != addr {
+        buffer[..4].copy_from_slice(&dap.try_mem_read_u32(start)?.to_le_bytes());
+    }
+    if end != addr + data.len() as u64 {
+        let last = buffer.len() - 4;
+        buffer[last..].copy_from_slice(&dap.try_mem_read_u32(end - 4)?.to_le_bytes());
+    }
+    let offset = (addr - start) as usize;
+    buffer[offset..offset + data.len()].copy_from_slice(data);
+
+    let words: Vec<u32> = buffer
+        .chunks(4)
+        .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+        .collect();
+    for (i, chunk) in words.chunks(CHUNK_WORDS).enumerate() {
+        let address = start + (i * CHUNK_WORDS * 4) as u64;
+        dap.try_mem_write_block(address, chunk)?;
+        let done = (address + chunk.len() as u64 * 4).saturating_sub(addr) as usize;
+        progress(done.min(data.len()), data.len());
+    }
+    Ok(())
+}
+
+pub fn dump_memory<M: MemoryAccessPort>(
+    dap: &mut M,
+    addr: u64,
+    len: usize,
+    path: &Path,
+    format: Format,
+    progress: &mut dyn FnMut(usize, usize),
+) -> Result<(), MemfileError> {
+    dump_memory_with_record_len(dap, addr, len, path, format, DEFAULT_RECORD_LEN, progress)
+}
+
+pub fn dump_memory_with_record_len<M: MemoryAccessPort>(
+    dap: &mut M,
+    addr: u64,
+    len: usize,
+    path: &Path,
+    format: Format,
+    record_len: usize,
+    progress: &mut dyn FnMut(usize, usize),
+) -> Result<(), MemfileError> {
+    let data = read_memory(dap, addr, len, progress)?;
+    let bytes = encode(format, addr, &data, record_len)?;
+    fs::write(path, bytes)
+        .map_err(|e| MemfileError::Io(format!("failed to write {}: {}", path.display(), e)))
+}
+
+// 書いた後に読み戻して比べる。書いたbyte数を返す
+// base_overrideを指定すると、file内の最も低いaddressがそこに来るようにずらす
+// binaryはaddressを持たないのでbase_overrideが必須
+// progressはverifyの読み出しも含めて数える
+pub fn load_memory<M: MemoryAccessPort>(
+    dap: &mut M,
+    path: &Path,
+    base_override: Option<u64>,
+    progress: &mut dyn FnMut(usize, usize),
+) -> Result<usize, MemfileError> {
+    let format = Format::from_path(path);
+    let bytes = fs::read(path)
+        .map_err(|e| MemfileError::Io(format!("failed to read {}: {}", path.display(), e)))?;
+    let mut segments = decode(format, &bytes)?;
+    match (format, base_override) {
+        (Format::Bin, None) => return Err(MemfileError::MissingLoadAddress),
+        (_, Some(base)) => {
+            let lowest = segments.iter().map(|x| x.addr).min().unwrap_or(0);
+            for segment in segments.iter_mut() {
+                segment.addr = segment.addr - lowest + base;
+            }
+        }
+        (_, None) => (),
+    }
+    // 1つでもprotectedな範囲に掛かるsegmentがあれば何も書かない
+    for segment in segments.iter() {
+        dap.check_write(segment.addr, segment.data.len() as u64)?;
+    }
+    let total: usize = segments.iter().map(|x| x.data.len()).sum();
+    let mut done = 0;
+    for segment in segments.iter() {
+        write_memory(dap, segment.addr, &segment.data, &mut |x, _| {
+            progress(done + x, total * 2)
+        })?;
+        done += segment.data.len();
+    }
+    for segment in segments.iter() {
+        let actual = read_memory(dap, segment.addr, segment.data.len(), &mut |x, _| {
+            progress(done + x, total * 2)
+        })?;
+        if let Some(i) = (0..actual.len()).find(|i| actual[*i] != segment.data[*i]) {
+            return Err(VerifyError {
+                addr: segment.addr + i as u64,
+                expected: segment.data[i],
+                actual: actual[i],
+            }
+            .into());
+        }
+        done += segment.data.len();
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::InterfaceError;
+    use crate::jtag::dap::tests::{memap_dap, MemApSim};
+    use crate::jtag::dap::{AddrRange, DapError, WriteGuard};
+    use crate::jtag::dap::{DapInterface, DpSelect};
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|x| (x * 7 + 3) as u8).collect()
+    }
+
+    #[test]
+    fn ihex_test() {
+        let text = write_ihex(0x1000, &[0x01, 0x02, 0x03], 16).unwrap();
+        assert_eq!(":03100000010203E7\n:00000001FF\n", text);
+
+        // 64KB境界を跨ぐ
+        let data = pattern(100);
+        let text = write_ihex(0x8000_ffe0, &data, 32).unwrap();
+        assert!(text.starts_with(":020000048000"));
+        assert!(text.contains(":020000048001"));
+        assert_eq!(
+            vec![Segment {
+                addr: 0x8000_ffe0,
+                data: data.clone(),
+            }],
+            parse_ihex(&text).unwrap()
+        );
+
+        assert_eq!(
+            Err(parse_error(Some(1), "bad checksum")),
+            parse_ihex(":03100000010203E8\n:00000001FF\n")
+        );
+        assert_eq!(
+            Err(parse_error(None, "missing end of file record")),
+            parse_ihex(":03100000010203E7\n")
+        );
+        assert!(write_ihex(0xffff_fff0, &data, 16).is_err());
+    }
+
+    #[test]
+    fn srec_test() {
+        let text = write_srec(0x1000, &[0x01, 0x02, 0x03], 16).unwrap();
+        assert_eq!(
+            "S0030000FC\nS1061000010203E3\nS5030001FB\nS9030000FC\n",
+            text
+        );
+
+        for addr in [0x1000, 0x12_3400, 0x8000_0000].iter() {
+            let data = pattern(77);
+            let text = write_srec(*addr, &data, 20).unwrap();
+            assert_eq!(
+                vec![Segment {
+                    addr: *addr,
+                    data: data.clone(),
+                }],
+                parse_srec(&text).unwrap(),
+                "{:#x}",
+                addr
+            );
+        }
+        assert!(write_srec(0x8000_0000, &[0], 251).is_err());
+        assert!(parse_srec("S1061000010203E4\nS9030000FC\n").is_err());
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let data = pattern(300);
+        for format in [Format::Bin, Format::Ihex, Format::Srec].iter() {
+            let bytes = encode(*format, 0x2_0000, &data, 32).unwrap();
+            let segments = decode(*format, &bytes).unwrap();
+            assert_eq!(1, segments.len());
+            assert_eq!(data, segments[0].data);
+            if *format != Format::Bin {
+                assert_eq!(0x2_0000, segments[0].addr);
+            }
+        }
+        assert_eq!(Format::Ihex, Format::from_path(Path::new("a.HEX")));
+        assert_eq!(Format::Srec, Format::from_path(Path::new("a.s19")));
+        assert_eq!(Format::Bin, Format::from_path(Path::new("a.img")));
+    }
+
+    #[test]
+    fn load_verify_test() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("libjtag_memfile_{}.srec", std::process::id()));
+        let data = pattern(2000);
+        fs::write(&path, write_srec(0x1003, &data, 32).unwrap()).unwrap();
+
+        let mut dap = memap_dap(MemApSim::new());
+        // 端の4byteの残りは壊さない
+        dap.dp.memory.insert(0x8000_0000, 0xaabb_ccdd);
+        let mut calls = Vec::new();
+        let written = load_memory(&mut dap, &path, Some(0x8000_0001), &mut |x, y| {
+            calls.push((x, y))
+        })
+        .unwrap();
+        assert_eq!(2000, written);
+        assert_eq!(Some(&(4000, 4000)), calls.last());
+        assert_eq!(0xdd, *dap.dp.memory.get(&0x8000_0000).unwrap() as u8);
+
+        let mut progress = |_, _| ();
+        assert_eq!(
+            data,
+            read_memory(&mut dap, 0x8000_0001, 2000, &mut progress).unwrap()
+        );
+
+        let out = dir.join(format!("libjtag_memfile_{}.hex", std::process::id()));
+        dump_memory(
+            &mut dap,
+            0x8000_0001,
+            2000,
+            &out,
+            Format::Ihex,
+            &mut progress,
+        )
+        .unwrap();
+        let segments = parse_ihex(&fs::read_to_string(&out).unwrap()).unwrap();
+        assert_eq!(data, segments[0].data);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&out).unwrap();
+    }
+
+    #[test]
+    fn load_guard_test() {
+        let path =
+            std::env::temp_dir().join(format!("libjtag_memfile_guard_{}.srec", std::process::id()));
+        fs::write(&path, write_srec(0x1000, &pattern(0x40), 32).unwrap()).unwrap();
+
+        // 最後のbyteだけがprotectedでも、先頭から何も書かない
+        let mut dap = memap_dap(MemApSim::new());
+        let range = AddrRange::new(0x103f, 1).unwrap();
+        dap.set_write_guard(WriteGuard::new().deny("otp", range));
+        let e = load_memory(&mut dap, &path, None, &mut |_, _| ()).unwrap_err();
+        assert_eq!(
+            MemfileError::Dap(DapError::WriteProtected {
+                addr: 0x103f,
+                region: range
+            }),
+            e
+        );
+        assert!(dap.dp.writes.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    // 0x8000_0100への書き込みだけ無視するmemory
+    struct StuckSim(MemApSim);
+
+    impl DapInterface for StuckSim {
+        fn apacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            let bank = DpSelect(self.0.select).apbanksel();
+            if !rnw && bank == 0 && a == 3 && self.0.tar == 0x8000_0100 {
+                self.0.tar += 4;
+                return Ok((0x02, 0));
+            }
+            self.0.apacc(data, a, rnw)
+        }
+        fn dpacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            self.0.dpacc(data, a, rnw)
+        }
+    }
+
+    #[test]
+    fn verify_mismatch_test() {
+        let path = std::env::temp_dir().join(format!("libjtag_verify_{}.bin", std::process::id()));
+        fs::write(&path, pattern(512)).unwrap();
+        let mut dap = memap_dap(StuckSim(MemApSim::new()));
+        let e = load_memory(&mut dap, &path, Some(0x8000_0000), &mut |_, _| ()).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            MemfileError::Verify(VerifyError {
+                addr: 0x8000_0100,
+                expected: pattern(512)[0x100],
+                actual: 0,
+            }),
+            e
+        );
+        assert!(matches!(
+            load_memory(
+                &mut dap,
+                Path::new("/nonexistent.bin"),
+                None,
+                &mut |_, _| ()
+            ),
+            Err(MemfileError::Io(_))
+        ));
+    }
+}
@@ -0,0 +1,198 @@
+// block readで読んだbyte列を、読んだ範囲のaddressで引けるようにする
+// 同じMEM-APの先にlittle-endianのmemoryとbig-endianのdevice registerが並ぶ場合向け
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::interface::InterfaceError;
+
+const HEXDUMP_LINE: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemView {
+    base: u64,
+    data: Vec<u8>,
+}
+
+impl MemView {
+    pub fn new(base: u64, data: Vec<u8>) -> Self {
+        MemView { base, data }
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    // 読んだ範囲の直後のaddress
+    pub fn end(&self) -> u64 {
+        self.base + self.data.len() as u64
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    // addrからsize byteが全て読んだ範囲に入っていなければOutOfRange
+    fn slice(&self, addr: u64, size: usize) -> Result<&[u8], InterfaceError> {
+        if addr < self.base || addr - self.base > self.data.len() as u64 {
+            return Err(InterfaceError::OutOfRange);
+        }
+        let offset = (addr - self.base) as usize;
+        self.data
+            .get(offset..offset + size)
+            .ok_or(InterfaceError::OutOfRange)
+    }
+
+    fn value_at(&self, addr: u64, size: usize, endian: Endian) -> Result<u64, InterfaceError> {
+        let bytes = self.slice(addr, size)?;
+        let fold = |value: u64, x: &u8| (value << 8) | *x as u64;
+        Ok(match endian {
+            Endian::Little => bytes.iter().rev().fold(0, fold),
+            Endian::Big => bytes.iter().fold(0, fold),
+        })
+    }
+
+    pub fn u8_at(&self, addr: u64) -> Result<u8, InterfaceError> {
+        Ok(self.slice(addr, 1)?[0])
+    }
+
+    pub fn u16_at(&self, addr: u64, endian: Endian) -> Result<u16, InterfaceError> {
+        Ok(self.value_at(addr, 2, endian)? as u16)
+    }
+
+    pub fn u32_at(&self, addr: u64, endian: Endian) -> Result<u32, InterfaceError> {
+        Ok(self.value_at(addr, 4, endian)? as u32)
+    }
+
+    pub fn u64_at(&self, addr: u64, endian: Endian) -> Result<u64, InterfaceError> {
+        self.value_at(addr, 8, endian)
+    }
+
+    // baseから4byteずつの(address, 値)。4byteに満たない末尾は含めない
+    pub fn words(&self, endian: Endian) -> impl Iterator<Item = (u64, u32)> + '_ {
+        (0..self.data.len() / 4).map(move |i| {
+            let addr = self.base + i as u64 * 4;
+            (addr, self.u32_at(addr, endian).unwrap())
+        })
+    }
+
+    // 1行16byteで、16進の後ろにASCIIを並べる
+    pub fn hexdump(&self) -> HexDump<'_> {
+        HexDump(self)
+    }
+}
+
+pub struct HexDump<'a>(&'a MemView);
+
+impl<'a> fmt::Display for HexDump<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let view = self.0;
+        for (i, line) in view.data.chunks(HEXDUMP_LINE).enumerate() {
+            write!(f, "{:#010x}:", view.base + (i * HEXDUMP_LINE) as u64)?;
+            for x in line {
+                write!(f, " {:02x}", x)?;
+            }
+            // 短い最終行もASCIIの列を揃える
+            for _ in line.len()..HEXDUMP_LINE {
+                write!(f, "   ")?;
+            }
+            write!(f, "  |")?;
+            for x in line {
+                let c = if x.is_ascii_graphic() || *x == b' ' {
+                    *x as char
+                } else {
+                    '.'
+                };
+                write!(f, "{}", c)?;
+            }
+            writeln!(f, "|")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed_getter_test() {
+        let view = MemView::new(0x1000, (0..16).collect());
+        assert_eq!(0x1010, view.end());
+        assert_eq!(Ok(0x00), view.u8_at(0x1000));
+        assert_eq!(Ok(0x0f), view.u8_at(0x100f));
+        assert_eq!(Ok(0x0201), view.u16_at(0x1001, Endian::Little));
+        assert_eq!(Ok(0x0102), view.u16_at(0x1001, Endian::Big));
+        assert_eq!(Ok(0x0f0e_0d0c), view.u32_at(0x100c, Endian::Little));
+        assert_eq!(Ok(0x0c0d_0e0f), view.u32_at(0x100c, Endian::Big));
+        assert_eq!(
+            Ok(0x0f0e_0d0c_0b0a_0908),
+            view.u64_at(0x1008, Endian::Little)
+        );
+        assert_eq!(Ok(0x0809_0a0b_0c0d_0e0f), view.u64_at(0x1008, Endian::Big));
+        // 境界をはみ出すもの
+        assert_eq!(Err(InterfaceError::OutOfRange), view.u8_at(0x0fff));
+        assert_eq!(Err(InterfaceError::OutOfRange), view.u8_at(0x1010));
+        assert_eq!(
+            Err(InterfaceError::OutOfRange),
+            view.u32_at(0x100d, Endian::Little)
+        );
+        assert_eq!(
+            Err(InterfaceError::OutOfRange),
+            view.u64_at(0x1009, Endian::Big)
+        );
+        assert_eq!(
+            Err(InterfaceError::OutOfRange),
+            view.u16_at(u64::MAX, Endian::Big)
+        );
+    }
+
+    #[test]
+    fn words_test() {
+        // 奇数長の末尾は含めない
+        let view = MemView::new(0x2002, (0..11).collect());
+        assert_eq!(
+            vec![(0x2002, 0x0302_0100), (0x2006, 0x0706_0504)],
+            view.words(Endian::Little).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![(0x2002, 0x0001_0203), (0x2006, 0x0405_0607)],
+            view.words(Endian::Big).collect::<Vec<_>>()
+        );
+        assert_eq!(Ok(0x0a), view.u8_at(0x200c));
+        assert_eq!(
+            0,
+            MemView::new(0, vec![1, 2, 3]).words(Endian::Little).count()
+        );
+    }
+
+    #[test]
+    fn hexdump_test() {
+        let mut data: Vec<u8> = (0..16).collect();
+        data.extend(b"AB c~\x7f");
+        let view = MemView::new(0x1000, data);
+        assert_eq!(
+            "0x00001000: 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f  |................|\n\
+             0x00001010: 41 42 20 63 7e 7f                                |AB c~.|\n",
+            format!("{}", view.hexdump())
+        );
+        assert_eq!("", format!("{}", MemView::new(0, Vec::new()).hexdump()));
+    }
+}
@@ -0,0 +1,667 @@
+// probe-rsのDebugProbe/JtagAccess/RawDapAccessをJtag<T>の上に実装する
+// probe-rsのflash algorithmやgdb serverから、このcrateのbackendを使えるようにする
+// FTDIのcontextはthread間で渡せないので、interfaceはworker threadで開いてそこでだけ触る
+use std::fmt;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+
+use bitvec::vec::BitVec;
+use log::*;
+use probe_rs::architecture::arm::sequences::ArmDebugSequence;
+use probe_rs::architecture::arm::{
+    ArmCommunicationInterface, ArmDebugInterface, ArmError, DapError as ArmDapError, DapProbe,
+    RawDapAccess, RegisterAddress,
+};
+use probe_rs::config::ScanChainElement;
+use probe_rs::probe::list::{ProbeListItem, ProbeLister};
+use probe_rs::probe::{
+    DebugProbe, DebugProbeError, DebugProbeInfo, DebugProbeSelector, JtagAccess, JtagSequence,
+    Probe, ProbeCreationError, ProbeError, ProbeFactory, WireProtocol,
+};
+use probe_rs::CoreStatus;
+use spin::mutex::Mutex;
+
+use crate::error::JtagError;
+use crate::interface::ftdi::{self, ProbeInfo};
+use crate::interface::ftdi_bitbang::{BitBangDevice, FtdiBitBang};
+use crate::interface::ftdi_builder::Pin;
+use crate::interface::ftdi_mpsse::{FtdiMpsse, MpsseDevice};
+use crate::interface::jlink::{JLink, JLinkDevice};
+use crate::interface::{InterfaceError, JtagInterface};
+use crate::jtag::dap::{DapAck, DapInterface, DpAddress};
+use crate::jtag::jtag::{Jtag, TAP};
+
+// probe-rsのDebugProbeErrorに包んで返す
+impl ProbeError for JtagError {}
+
+// set_speedで呼ぶ。実際に設定されたTCKの周波数を返す
+pub trait TckControl {
+    fn set_tck_hz(&mut self, hz: u32) -> Result<u32, InterfaceError>;
+}
+
+impl<D: MpsseDevice> TckControl for FtdiMpsse<D> {
+    fn set_tck_hz(&mut self, hz: u32) -> Result<u32, InterfaceError> {
+        FtdiMpsse::set_tck_hz(self, hz)
+    }
+}
+
+impl<D: BitBangDevice> TckControl for FtdiBitBang<D> {
+    fn set_tck_hz(&mut self, hz: u32) -> Result<u32, InterfaceError> {
+        FtdiBitBang::set_tck_hz(self, hz)
+    }
+}
+
+// J-Linkはkhz単位でしか設定できない
+impl<D: JLinkDevice> TckControl for JLink<D> {
+    fn set_tck_hz(&mut self, hz: u32) -> Result<u32, InterfaceError> {
+        let khz = (hz / 1000).min(u16::MAX as u32) as u16;
+        self.set_speed_khz(khz)?;
+        Ok(khz as u32 * 1000)
+    }
+}
+
+type Job<T> = Box<dyn FnOnce(&Mutex<Jtag<T>>) + Send>;
+
+// scan chain上の対象のTAP。jobの度にTAPを作り直す
+#[derive(Clone, Debug)]
+struct Chain {
+    ir_lens: Vec<usize>,
+    position: usize,
+    idle: usize,
+}
+
+impl Chain {
+    fn tap<'a, T: JtagInterface>(
+        &self,
+        jtag: &'a Mutex<Jtag<T>>,
+    ) -> Result<TAP<'a, T>, InterfaceError> {
+        let mut tap = TAP::in_chain(jtag, &self.ir_lens, self.position)?;
+        tap.set_post_scan_idle(self.idle);
+        // 同じchainを次のjobでも使う
+        tap.set_reset_on_drop(false);
+        Ok(tap)
+    }
+}
+
+pub struct JtagProbe<T> {
+    name: String,
+    jobs: Sender<Job<T>>,
+    speed_khz: u32,
+    attached: bool,
+    scan_chain: Vec<ScanChainElement>,
+    expected_scan_chain: Option<Vec<ScanChainElement>>,
+    target: usize,
+    idle_cycles: u8,
+}
+
+impl<T: JtagInterface + TckControl + 'static> JtagProbe<T> {
+    // openはworker threadで呼ばれる
+    pub fn spawn<F, E>(name: &str, open: F) -> Result<Self, DebugProbeError>
+    where
+        F: FnOnce() -> Result<T, E> + Send + 'static,
+        E: fmt::Display,
+    {
+        let (jobs, receiver) = mpsc::channel::<Job<T>>();
+        let (started, opened) = mpsc::channel();
+        thread::spawn(move || {
+            let interface = match open() {
+                Ok(interface) => interface,
+                Err(e) => {
+                    let _ = started.send(Err(e.to_string()));
+                    return;
+                }
+            };
+            let _ = started.send(Ok(()));
+            let jtag = Mutex::new(Jtag::new(interface));
+            for job in receiver {
+                job(&jtag);
+            }
+        });
+        match opened.recv() {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => return Err(DebugProbeError::Other(e)),
+            Err(_) => return Err(ProbeCreationError::CouldNotOpen.into()),
+        }
+        Ok(JtagProbe {
+            name: name.to_string(),
+            jobs,
+            speed_khz: 0,
+            attached: false,
+            scan_chain: Vec::new(),
+            expected_scan_chain: None,
+            target: 0,
+            idle_cycles: 0,
+        })
+    }
+
+    // worker threadでfを実行して結果を待つ
+    // workerが居なくなっていればIo
+    fn call<R, F>(&self, f: F) -> Result<R, DebugProbeError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Mutex<Jtag<T>>) -> Result<R, InterfaceError> + Send + 'static,
+    {
+        let (reply, result) = mpsc::channel();
+        let job: Job<T> = Box::new(move |jtag| {
+            let _ = reply.send(f(jtag));
+        });
+        self.jobs
+            .send(job)
+            .map_err(|_| DebugProbeError::from(InterfaceError::Io))?;
+        match result.recv() {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(InterfaceError::Io.into()),
+        }
+    }
+
+    fn chain(&self) -> Result<Chain, DebugProbeError> {
+        if !self.attached {
+            return Err(DebugProbeError::NotAttached);
+        }
+        if self.target >= self.scan_chain.len() {
+            return Err(DebugProbeError::TargetNotFound);
+        }
+        Ok(Chain {
+            ir_lens: self
+                .scan_chain
+                .iter()
+                .map(|x| x.ir_len() as usize)
+                .collect(),
+            position: self.target,
+            idle: self.idle_cycles as usize,
+        })
+    }
+
+    // JTAG-DPでは結果が次のscanで返るので、RDBUFFを読んで取り出す
+    // writeもRDBUFFのACKで完了したことを確かめる
+    fn dap_access(
+        &mut self,
+        address: RegisterAddress,
+        data: u32,
+        read: bool,
+    ) -> Result<u32, ArmError> {
+        let chain = self.chain()?;
+        let ap = address.is_ap();
+        let a = address.a2_and_3() >> 2;
+        let abort: u8 = DpAddress::PDIDR_ABORT.into();
+        let result = self.call(move |jtag| {
+            let mut tap = chain.tap(jtag)?;
+            // DPのABORTはDPACCではなくABORT命令で書く
+            if !ap && !read && a == abort {
+                tap.abort(data)?;
+                return Ok((DapAck::OkFault as u8, DapAck::OkFault as u8, 0));
+            }
+            let (ack, _) = if ap {
+                tap.apacc(data, a, read)?
+            } else {
+                tap.dpacc(data, a, read)?
+            };
+            let (rdbuff_ack, value) = tap.dpacc(0, DpAddress::RDBUFF.into(), true)?;
+            Ok((ack, rdbuff_ack, value))
+        })?;
+        let (ack, rdbuff_ack, value) = result;
+        for ack in [ack, rdbuff_ack].iter() {
+            match DapAck::from(*ack) {
+                DapAck::OkFault => (),
+                DapAck::Wait => return Err(ArmDapError::WaitResponse.into()),
+                DapAck::InvalidAck => return Err(ArmDapError::NoAcknowledge.into()),
+            }
+        }
+        Ok(value)
+    }
+}
+
+// dataはLSB firstで詰めたlen bit
+fn unpack_bits(data: &[u8], len: u32) -> Vec<bool> {
+    (0..len as usize)
+        .map(|i| data.get(i / 8).is_some_and(|x| x & (1 << (i % 8)) != 0))
+        .collect()
+}
+
+impl<T> fmt::Debug for JtagProbe<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JtagProbe")
+            .field("name", &self.name)
+            .field("speed_khz", &self.speed_khz)
+            .field("attached", &self.attached)
+            .field("scan_chain", &self.scan_chain)
+            .field("target", &self.target)
+            .finish()
+    }
+}
+
+impl<T: JtagInterface + TckControl + 'static> DebugProbe for JtagProbe<T> {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    // set_speedの前は分からないので0
+    fn speed_khz(&self) -> u32 {
+        self.speed_khz
+    }
+
+    fn set_speed(&mut self, speed_khz: u32) -> Result<u32, DebugProbeError> {
+        let hz = speed_khz.saturating_mul(1000);
+        let achieved = self.call(move |jtag| jtag.lock().interface.set_tck_hz(hz))?;
+        self.speed_khz = achieved / 1000;
+        Ok(self.speed_khz)
+    }
+
+    // JTAGへ切り替えてchainを調べる
+    // expected_scan_chainがあればそのIR長を使い、なければIR-Captureから求める
+    fn attach(&mut self) -> Result<(), DebugProbeError> {
+        let expected = self.expected_scan_chain.clone();
+        let ir_lens = self.call(move |jtag| {
+            let mut jtag = jtag.lock();
+            jtag.initialize()?;
+            if let Some(expected) = expected {
+                return Ok(expected.iter().map(|x| x.ir_len() as usize).collect());
+            }
+            match jtag.detect_chain()?.ir_lens() {
+                Some(ir_lens) => Ok(ir_lens.to_vec()),
+                None => Err(InterfaceError::BadIrCapture),
+            }
+        })?;
+        info!("attached, IR lengths {:?}", ir_lens);
+        self.scan_chain = match self.expected_scan_chain.take() {
+            Some(expected) => {
+                self.expected_scan_chain = Some(expected.clone());
+                expected
+            }
+            None => ir_lens
+                .iter()
+                .map(|x| ScanChainElement {
+                    name: None,
+                    ir_len: Some(*x as u8),
+                })
+                .collect(),
+        };
+        self.attached = true;
+        Ok(())
+    }
+
+    fn detach(&mut self) -> Result<(), probe_rs::Error> {
+        self.attached = false;
+        Ok(())
+    }
+
+    fn target_reset(&mut self) -> Result<(), DebugProbeError> {
+        self.call(|jtag| jtag.lock().reset_target())
+    }
+
+    fn target_reset_assert(&mut self) -> Result<(), DebugProbeError> {
+        self.call(|jtag| jtag.lock().interface.assert_srst(true))
+    }
+
+    fn target_reset_deassert(&mut self) -> Result<(), DebugProbeError> {
+        self.call(|jtag| jtag.lock().interface.assert_srst(false))
+    }
+
+    // backendはJTAGのpinしか持たない
+    fn select_protocol(&mut self, protocol: WireProtocol) -> Result<(), DebugProbeError> {
+        match protocol {
+            WireProtocol::Jtag => Ok(()),
+            _ => Err(DebugProbeError::UnsupportedProtocol(protocol)),
+        }
+    }
+
+    fn active_protocol(&self) -> Option<WireProtocol> {
+        Some(WireProtocol::Jtag)
+    }
+
+    fn has_arm_interface(&self) -> bool {
+        true
+    }
+
+    fn try_as_jtag_probe(&mut self) -> Option<&mut dyn JtagAccess> {
+        Some(self)
+    }
+
+    fn try_get_arm_debug_interface<'probe>(
+        self: Box<Self>,
+        sequence: Arc<dyn ArmDebugSequence>,
+    ) -> Result<Box<dyn ArmDebugInterface + 'probe>, (Box<dyn DebugProbe>, ArmError)> {
+        Ok(ArmCommunicationInterface::create(self, sequence, true))
+    }
+
+    fn into_probe(self: Box<Self>) -> Box<dyn DebugProbe> {
+        self
+    }
+
+    fn try_as_dap_probe(&mut self) -> Option<&mut dyn DapProbe> {
+        Some(self)
+    }
+}
+
+impl<T: JtagInterface + TckControl + 'static> JtagAccess for JtagProbe<T> {
+    fn set_expected_scan_chain(
+        &mut self,
+        scan_chain: &[ScanChainElement],
+    ) -> Result<(), DebugProbeError> {
+        self.expected_scan_chain = Some(scan_chain.to_vec());
+        Ok(())
+    }
+
+    fn set_scan_chain(&mut self, scan_chain: &[ScanChainElement]) -> Result<(), DebugProbeError> {
+        self.scan_chain = scan_chain.to_vec();
+        Ok(())
+    }
+
+    fn scan_chain(&mut self) -> Result<&[ScanChainElement], DebugProbeError> {
+        if !self.attached {
+            self.attach()?;
+        }
+        Ok(&self.scan_chain)
+    }
+
+    // TMSを固定したままのshiftはJtagのstate machineで追えない
+    fn shift_raw_sequence(&mut self, _sequence: JtagSequence) -> Result<BitVec, DebugProbeError> {
+        Err(DebugProbeError::NotImplemented {
+            function_name: "shift_raw_sequence",
+        })
+    }
+
+    fn tap_reset(&mut self) -> Result<(), DebugProbeError> {
+        self.call(|jtag| jtag.lock().resync())
+    }
+
+    fn set_idle_cycles(&mut self, idle_cycles: u8) -> Result<(), DebugProbeError> {
+        self.idle_cycles = idle_cycles;
+        Ok(())
+    }
+
+    fn idle_cycles(&self) -> u8 {
+        self.idle_cycles
+    }
+
+    fn select_target(&mut self, index: usize) -> Result<(), DebugProbeError> {
+        if index >= self.scan_chain.len() {
+            return Err(DebugProbeError::TargetNotFound);
+        }
+        self.target = index;
+        Ok(())
+    }
+
+    // addressをIRに書き、len bitのDRをscanする
+    fn write_register(
+        &mut self,
+        address: u32,
+        data: &[u8],
+        len: u32,
+    ) -> Result<BitVec, DebugProbeError> {
+        let chain = self.chain()?;
+        let mut bits = unpack_bits(data, len);
+        let bits = self.call(move |jtag| {
+            chain.tap(jtag)?.scan_ir_dr(address, &mut bits)?;
+            Ok(bits)
+        })?;
+        Ok(bits.into_iter().collect())
+    }
+
+    // IRは前に書いたまま
+    fn write_dr(&mut self, data: &[u8], len: u32) -> Result<BitVec, DebugProbeError> {
+        let chain = self.chain()?;
+        let mut bits = unpack_bits(data, len);
+        let bits = self.call(move |jtag| {
+            chain
+                .tap(jtag)?
+                .read_write_dr(&mut bits, true, false, false)?;
+            Ok(bits)
+        })?;
+        Ok(bits.into_iter().collect())
+    }
+}
+
+impl<T: JtagInterface + TckControl + 'static> RawDapAccess for JtagProbe<T> {
+    fn raw_read_register(&mut self, address: RegisterAddress) -> Result<u32, ArmError> {
+        self.dap_access(address, 0, true)
+    }
+
+    fn raw_write_register(&mut self, address: RegisterAddress, value: u32) -> Result<(), ArmError> {
+        self.dap_access(address, value, false)?;
+        Ok(())
+    }
+
+    // TDIは使わず、TMSだけをcycles回出す
+    fn jtag_sequence(&mut self, cycles: u8, tms: bool, _tdi: u64) -> Result<(), DebugProbeError> {
+        self.call(move |jtag| jtag.lock().write_tms(&vec![tms; cycles as usize]))
+    }
+
+    // SWDIOはTMSと同じpinなので、SWJの切り替え列はTMSとして出す
+    fn swj_sequence(&mut self, bit_len: u8, bits: u64) -> Result<(), DebugProbeError> {
+        let tms: Vec<bool> = (0..bit_len).map(|i| bits & (1 << i) != 0).collect();
+        self.call(move |jtag| jtag.lock().write_tms(&tms))
+    }
+
+    fn swj_pins(
+        &mut self,
+        _pin_out: u32,
+        _pin_select: u32,
+        _pin_wait: u32,
+    ) -> Result<u32, DebugProbeError> {
+        Err(DebugProbeError::NotImplemented {
+            function_name: "swj_pins",
+        })
+    }
+
+    fn into_probe(self: Box<Self>) -> Box<dyn DebugProbe> {
+        self
+    }
+
+    fn core_status_notification(&mut self, _state: CoreStatus) -> Result<(), DebugProbeError> {
+        Ok(())
+    }
+}
+
+impl<T: JtagInterface + TckControl + 'static> DapProbe for JtagProbe<T> {}
+
+// SRST/TRSTはCLIの既定と同じADBUS4/5
+const FTDI_SRST: u8 = 4;
+const FTDI_TRST: u8 = 5;
+
+// ftdi::list_devicesで見つけたFTDIのadapterを、MPSSEのJtagProbeとして開く
+#[derive(Debug)]
+pub struct FtdiAdapterFactory;
+
+pub static FTDI_ADAPTER: FtdiAdapterFactory = FtdiAdapterFactory;
+
+impl fmt::Display for FtdiAdapterFactory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "libjtag FTDI")
+    }
+}
+
+fn debug_probe_info(probe: &ProbeInfo) -> DebugProbeInfo {
+    let identifier = probe
+        .description
+        .clone()
+        .unwrap_or_else(|| "FTDI".to_string());
+    DebugProbeInfo::new(
+        identifier,
+        probe.vid,
+        probe.pid,
+        probe.serial.clone(),
+        &FTDI_ADAPTER,
+        None,
+        false,
+    )
+}
+
+impl ProbeFactory for FtdiAdapterFactory {
+    fn open(&self, selector: &DebugProbeSelector) -> Result<Box<dyn DebugProbe>, DebugProbeError> {
+        let found = ftdi::list_devices()
+            .iter()
+            .any(|x| selector.matches_probe(&debug_probe_info(x)));
+        if !found {
+            return Err(ProbeCreationError::NotFound.into());
+        }
+        let (vid, pid) = (selector.vendor_id, selector.product_id);
+        let serial = selector.serial_number.clone();
+        let probe = JtagProbe::spawn("libjtag FTDI MPSSE", move || {
+            let mut builder = FtdiMpsse::builder()
+                .vid(vid)
+                .pid(pid)
+                .pin(Pin::Srst, FTDI_SRST)
+                .pin(Pin::Trst, FTDI_TRST);
+            if let Some(serial) = &serial {
+                builder = builder.serial(serial);
+            }
+            builder.open()
+        })?;
+        Ok(Box::new(probe))
+    }
+
+    fn list_probes(&self) -> Vec<ProbeListItem> {
+        ftdi::list_devices()
+            .iter()
+            .map(|x| ProbeListItem::accessible(debug_probe_info(x)))
+            .collect()
+    }
+}
+
+// probe_rs::probe::list::Lister::with_listerに渡すと、probe-rsのlist/openでFTDIをこのcrateで開く
+#[derive(Debug)]
+pub struct FtdiAdapterLister;
+
+impl ProbeLister for FtdiAdapterLister {
+    fn open(&self, selector: &DebugProbeSelector) -> Result<Probe, DebugProbeError> {
+        FTDI_ADAPTER.open(selector).map(Probe::from_specific_probe)
+    }
+
+    fn list_with_access(&self, selector: Option<&DebugProbeSelector>) -> Vec<ProbeListItem> {
+        FTDI_ADAPTER.list_probes_filtered(selector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::replay::tests::{SimDap, AP_IDR, IDCODE};
+    use bitvec::field::BitField;
+    use probe_rs::architecture::arm::dp::DpRegisterAddress;
+
+    const IR_IDCODE: u32 = 0b1110;
+    // simのTCKの上限
+    const SIM_MAX_HZ: u32 = 1_000_000;
+
+    impl TckControl for SimDap {
+        fn set_tck_hz(&mut self, hz: u32) -> Result<u32, InterfaceError> {
+            Ok(hz.min(SIM_MAX_HZ))
+        }
+    }
+
+    fn sim_probe() -> JtagProbe<SimDap> {
+        JtagProbe::spawn("sim", || Ok::<_, InterfaceError>(SimDap::new())).unwrap()
+    }
+
+    fn dp(address: u8) -> RegisterAddress {
+        RegisterAddress::DpRegister(DpRegisterAddress {
+            address,
+            bank: None,
+        })
+    }
+
+    // traitとして使えること
+    fn as_dap_probe(probe: &mut dyn DebugProbe) -> bool {
+        probe.try_as_dap_probe().is_some() && probe.try_as_jtag_probe().is_some()
+    }
+
+    #[test]
+    fn debug_probe_test() {
+        let mut probe = sim_probe();
+        assert!(as_dap_probe(&mut probe));
+        assert_eq!("sim", probe.get_name());
+        assert_eq!(Some(WireProtocol::Jtag), probe.active_protocol());
+        assert!(probe.select_protocol(WireProtocol::Jtag).is_ok());
+        assert!(matches!(
+            probe.select_protocol(WireProtocol::Swd),
+            Err(DebugProbeError::UnsupportedProtocol(WireProtocol::Swd))
+        ));
+        assert_eq!(400, probe.set_speed(400).unwrap());
+        assert_eq!(400, probe.speed_khz());
+        // simの上限に丸められる
+        assert_eq!(1000, probe.set_speed(4000).unwrap());
+        assert_eq!(1000, probe.speed_khz());
+    }
+
+    #[test]
+    fn jtag_access_test() {
+        let mut probe = sim_probe();
+        assert!(matches!(
+            probe.read_register(IR_IDCODE, 32),
+            Err(DebugProbeError::NotAttached)
+        ));
+        let chain = probe.scan_chain().unwrap().to_vec();
+        assert_eq!(1, chain.len());
+        assert_eq!(4, chain[0].ir_len());
+        assert!(matches!(
+            probe.select_target(1),
+            Err(DebugProbeError::TargetNotFound)
+        ));
+        probe.select_target(0).unwrap();
+
+        let idcode = probe.read_register(IR_IDCODE, 32).unwrap();
+        assert_eq!(32, idcode.len());
+        assert_eq!(IDCODE, idcode.load_le::<u32>());
+        // IRはIDCODEのまま
+        let idcode = probe.write_dr(&[0; 4], 32).unwrap();
+        assert_eq!(IDCODE, idcode.load_le::<u32>());
+        assert!(probe.tap_reset().is_ok());
+    }
+
+    #[test]
+    fn raw_dap_access_test() {
+        let mut probe = sim_probe();
+        probe.attach().unwrap();
+        assert_eq!(IDCODE, probe.raw_read_register(dp(0x0)).unwrap());
+        // SELECTでAPBANKSELを0xfにしてIDRを読む
+        probe.raw_write_register(dp(0x8), 0xf0).unwrap();
+        assert_eq!(
+            AP_IDR,
+            probe
+                .raw_read_register(RegisterAddress::ApRegister(0xfc))
+                .unwrap()
+        );
+        probe.raw_write_register(dp(0x8), 0x00).unwrap();
+        probe
+            .raw_write_register(RegisterAddress::ApRegister(0x00), 0x2300_0012)
+            .unwrap();
+        assert_eq!(
+            0x2300_0012,
+            probe
+                .raw_read_register(RegisterAddress::ApRegister(0x00))
+                .unwrap()
+        );
+        // ABORTはABORT命令で書く
+        assert!(probe.raw_write_register(dp(0x0), 1).is_ok());
+    }
+
+    #[test]
+    fn spawn_failure_test() {
+        let result = JtagProbe::<SimDap>::spawn("sim", || Err("no device"));
+        assert!(matches!(result, Err(DebugProbeError::Other(x)) if x == "no device"));
+    }
+
+    #[test]
+    fn ftdi_probe_info_test() {
+        let probe = ProbeInfo {
+            vid: 0x0403,
+            pid: 0x6010,
+            serial: Some("FT1234".to_string()),
+            description: Some("Dual RS232-HS".to_string()),
+            bus: 1,
+            ports: vec![2],
+        };
+        let info = debug_probe_info(&probe);
+        assert_eq!("Dual RS232-HS", info.identifier);
+        assert_eq!(Some("FT1234".to_string()), info.serial_number);
+        assert!(info.is_probe_type::<FtdiAdapterFactory>());
+        assert_eq!("libjtag FTDI", info.probe_type());
+
+        let selector: DebugProbeSelector = "0403:6010:FT1234".parse().unwrap();
+        assert!(selector.matches_probe(&info));
+        let selector: DebugProbeSelector = "0403:6010:FT9999".parse().unwrap();
+        assert!(!selector.matches_probe(&info));
+    }
+}
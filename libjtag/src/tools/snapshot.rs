@@ -0,0 +1,625 @@
+// attach時に見えるdebug registerを保存し、別のfirmwareのbuildと比べる
+// registerは"core0.EDPRSR"のような名前で持つので、versionの違うlibjtagで取ったsnapshotとも比べられる
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::error::DapError;
+use crate::jtag::dap::{DapInterface, DebugPort, MemoryAccessPort, DAP};
+use crate::jtag::idcode::TapDevice;
+use crate::target::arm64::{Armv8DebugRegisterOffset, CoreBase};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    // lock, power ack, device数など、attachできなくなる原因になりうる違い
+    Suspicious,
+    // revisionなど
+    Informational,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Suspicious => "suspicious",
+            Severity::Informational => "informational",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FieldDesc {
+    pub name: &'static str,
+    pub msb: u8,
+    pub lsb: u8,
+    pub severity: Severity,
+}
+
+impl FieldDesc {
+    pub fn mask(&self) -> u64 {
+        (u64::MAX >> (63 - (self.msb - self.lsb))) << self.lsb
+    }
+
+    pub fn extract(&self, value: u64) -> u64 {
+        (value & self.mask()) >> self.lsb
+    }
+}
+
+// registerのfieldの位置と、違っていた場合の重要度
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RegDesc {
+    pub name: &'static str,
+    pub fields: &'static [FieldDesc],
+}
+
+impl RegDesc {
+    pub fn find(name: &str) -> Option<&'static RegDesc> {
+        REGISTERS.iter().find(|x| x.name == name)
+    }
+
+    // どのfieldにも入らないbit
+    pub fn undescribed(&self) -> u64 {
+        !self.fields.iter().fold(0, |mask, x| mask | x.mask())
+    }
+}
+
+const fn field(name: &'static str, msb: u8, lsb: u8, severity: Severity) -> FieldDesc {
+    FieldDesc {
+        name,
+        msb,
+        lsb,
+        severity,
+    }
+}
+
+use Severity::{Informational as I, Suspicious as S};
+
+pub const REGISTERS: &[RegDesc] = &[
+    RegDesc {
+        name: "IDCODE",
+        fields: &[
+            field("VERSION", 31, 28, I),
+            field("PARTNO", 27, 12, S),
+            field("MANUFACTURER", 11, 1, S),
+        ],
+    },
+    RegDesc {
+        name: "DPIDR",
+        fields: &[
+            field("REVISION", 31, 28, I),
+            field("PARTNO", 27, 20, S),
+            field("MIN", 16, 16, I),
+            field("VERSION", 15, 12, S),
+            field("DESIGNER", 11, 1, S),
+        ],
+    },
+    RegDesc {
+        name: "CTRL/STAT",
+        fields: &[
+            field("CSYSPWRUPACK", 31, 31, S),
+            field("CSYSPWRUPREQ", 30, 30, I),
+            field("CDBGPWRUPACK", 29, 29, S),
+            field("CDBGPWRUPREQ", 28, 28, I),
+            field("CDBGRSTACK", 27, 27, S),
+            field("CDBGRSTREQ", 26, 26, I),
+            field("TRNCNT", 23, 12, I),
+            field("MASKLANE", 11, 8, I),
+            field("WDATAERR", 7, 7, S),
+            field("READOK", 6, 6, I),
+            field("STICKYERR", 5, 5, S),
+            field("STICKYCMP", 4, 4, I),
+            field("TRNMODE", 3, 2, I),
+            field("STICKYORUN", 1, 1, S),
+            field("ORUNDETECT", 0, 0, I),
+        ],
+    },
+    RegDesc {
+        name: "IDR",
+        fields: &[
+            field("REVISION", 31, 28, I),
+            field("DESIGNER", 27, 17, S),
+            field("CLASS", 16, 13, S),
+            field("VARIANT", 7, 4, I),
+            field("TYPE", 3, 0, S),
+        ],
+    },
+    RegDesc {
+        name: "BASE",
+        fields: &[
+            field("BASEADDR", 63, 12, S),
+            field("FORMAT", 1, 1, I),
+            field("P", 0, 0, S),
+        ],
+    },
+    RegDesc {
+        name: "EDPRSR",
+        fields: &[
+            field("SDR", 11, 11, I),
+            field("SPMAD", 10, 10, S),
+            field("EPMAD", 9, 9, S),
+            field("SDAD", 8, 8, S),
+            field("EDAD", 7, 7, S),
+            field("DLK", 6, 6, S),
+            field("OSLK", 5, 5, S),
+            field("HALTED", 4, 4, I),
+            field("SR", 3, 3, I),
+            field("R", 2, 2, S),
+            field("SPD", 1, 1, S),
+            field("PU", 0, 0, S),
+        ],
+    },
+    RegDesc {
+        name: "EDDFR",
+        fields: &[
+            field("CTX_CMPs", 31, 28, S),
+            field("WRPs", 23, 20, S),
+            field("BRPs", 15, 12, S),
+            field("PMUVer", 11, 8, I),
+            field("TraceVer", 7, 4, I),
+        ],
+    },
+    RegDesc {
+        name: "MIDR_EL1",
+        fields: &[
+            field("Implementer", 31, 24, S),
+            field("Variant", 23, 20, I),
+            field("Architecture", 19, 16, S),
+            field("PartNum", 15, 4, S),
+            field("Revision", 3, 0, I),
+        ],
+    },
+    RegDesc {
+        name: "DBGAUTHSTATUS",
+        fields: &[
+            field("SNID", 7, 6, S),
+            field("SID", 5, 4, S),
+            field("NSNID", 3, 2, S),
+            field("NSID", 1, 0, S),
+        ],
+    },
+];
+
+// coreごとに読むdebug register
+const CORE_REGISTERS: [Armv8DebugRegisterOffset; 4] = [
+    Armv8DebugRegisterOffset::EDPRSR,
+    Armv8DebugRegisterOffset::EDDFR,
+    Armv8DebugRegisterOffset::MIDR_EL1,
+    Armv8DebugRegisterOffset::DBGAUTHSTATUS,
+];
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DebugSnapshot {
+    // 取ったlibjtagのversion
+    pub version: String,
+    // "<scope>.<register>"とその値。scopeはDP, AP0, tap0, core0など
+    pub registers: BTreeMap<String, u64>,
+}
+
+impl DebugSnapshot {
+    pub fn new() -> Self {
+        DebugSnapshot {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            registers: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, scope: &str, register: &str, value: u64) {
+        self.registers
+            .insert(format!("{}.{}", scope, register), value);
+    }
+
+    // DP、apnumのAP、各coreのdebug registerを読む
+    // EDPRSRのSDR/SPDは読むと落ちるので、attach直後に取ること
+    pub fn capture<T: DapInterface>(
+        dap: &mut DAP<T>,
+        apnum: u8,
+        cores: &[CoreBase],
+    ) -> Result<Self, DapError> {
+        let mut snapshot = Self::new();
+        snapshot.insert("DP", "DPIDR", dap.dpidr().0 as u64);
+        snapshot.insert("DP", "CTRL/STAT", dap.try_dp_ctrlstat_read()?.0 as u64);
+        dap.select_ap(apnum);
+        let ap = format!("AP{}", apnum);
+        let (ack, idr) = dap.memap_idr_read()?;
+        dap.check_ack(ack, true)?;
+        snapshot.insert(&ap, "IDR", idr as u64);
+        let (ack, base) = dap.memap_base_u64_read()?;
+        dap.check_ack(ack, true)?;
+        snapshot.insert(&ap, "BASE", base);
+        for (i, core) in cores.iter().enumerate() {
+            let scope = format!("core{}", i);
+            for offset in CORE_REGISTERS.iter() {
+                match dap.try_mem_read_u32(core.debug + *offset as u64) {
+                    Ok(value) => snapshot.insert(&scope, offset.name(), value as u64),
+                    // 電源の落ちたcoreは読めるものだけ残す
+                    Err(e) => warn!("{}.{}: {}", scope, offset.name(), e),
+                }
+            }
+        }
+        Ok(snapshot)
+    }
+
+    // BYPASSのdeviceはIDCODEが無いので数えない
+    pub fn add_idcodes(&mut self, devices: &[TapDevice]) {
+        for (i, device) in devices.iter().enumerate() {
+            if let TapDevice::IdCode(idcode) = device {
+                self.insert(&format!("tap{}", i), "IDCODE", idcode.raw as u64);
+            }
+        }
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    // 片方にしか無いregisterはadded/removedにする
+    pub fn diff(&self, other: &Self) -> SnapshotDiff {
+        let mut entries = Vec::new();
+        let (old, new) = (self.device_counts(), other.device_counts());
+        for kind in old.keys().chain(new.keys()).collect::<BTreeSet<_>>() {
+            let old = old.get(kind).copied().unwrap_or(0);
+            let new = new.get(kind).copied().unwrap_or(0);
+            if old != new {
+                entries.push(DiffEntry {
+                    severity: Severity::Suspicious,
+                    name: format!("{} count", kind),
+                    change: Change::Changed { old, new },
+                });
+            }
+        }
+        let names: BTreeSet<&String> = self
+            .registers
+            .keys()
+            .chain(other.registers.keys())
+            .collect();
+        for name in names {
+            match (self.registers.get(name), other.registers.get(name)) {
+                (Some(old), Some(new)) => diff_register(&mut entries, name, *old, *new),
+                (Some(old), None) => entries.push(DiffEntry {
+                    severity: Severity::Informational,
+                    name: name.clone(),
+                    change: Change::Removed { old: *old },
+                }),
+                (None, Some(new)) => entries.push(DiffEntry {
+                    severity: Severity::Informational,
+                    name: name.clone(),
+                    change: Change::Added { new: *new },
+                }),
+                (None, None) => unreachable!(),
+            }
+        }
+        entries.sort_by_key(|x| x.severity);
+        SnapshotDiff {
+            old_version: self.version.clone(),
+            new_version: other.version.clone(),
+            entries,
+        }
+    }
+
+    // scopeの番号を除いた名前ごとの数。core0とcore1なら"core"が2
+    fn device_counts(&self) -> BTreeMap<String, u64> {
+        let scopes: BTreeSet<&str> = self
+            .registers
+            .keys()
+            .filter_map(|x| x.split_once('.').map(|x| x.0))
+            .collect();
+        let mut counts = BTreeMap::new();
+        for scope in scopes {
+            let kind = scope.trim_end_matches(|x: char| x.is_ascii_digit());
+            if kind.len() != scope.len() {
+                *counts.entry(kind.to_string()).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+}
+
+// RegDescのあるregisterはfieldごとに比べる
+fn diff_register(entries: &mut Vec<DiffEntry>, name: &str, old: u64, new: u64) {
+    if old == new {
+        return;
+    }
+    let register = name.rsplit('.').next().unwrap_or(name);
+    let desc = match RegDesc::find(register) {
+        Some(desc) => desc,
+        None => {
+            entries.push(DiffEntry {
+                severity: Severity::Informational,
+                name: name.to_string(),
+                change: Change::Changed { old, new },
+            });
+            return;
+        }
+    };
+    for field in desc.fields {
+        let (old, new) = (field.extract(old), field.extract(new));
+        if old != new {
+            entries.push(DiffEntry {
+                severity: field.severity,
+                name: format!("{}.{}", name, field.name),
+                change: Change::Changed { old, new },
+            });
+        }
+    }
+    let mask = desc.undescribed();
+    if old & mask != new & mask {
+        entries.push(DiffEntry {
+            severity: Severity::Informational,
+            name: format!("{}.<other bits>", name),
+            change: Change::Changed {
+                old: old & mask,
+                new: new & mask,
+            },
+        });
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Changed { old: u64, new: u64 },
+    Added { new: u64 },
+    Removed { old: u64 },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Changed { old, new } => write!(f, "{:#x} -> {:#x}", old, new),
+            Change::Added { new } => write!(f, "added ({:#x})", new),
+            Change::Removed { old } => write!(f, "removed (was {:#x})", old),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DiffEntry {
+    pub severity: Severity,
+    // "core0.EDPRSR.OSLK"や"core count"
+    pub name: String,
+    pub change: Change,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SnapshotDiff {
+    // snapshotを取ったlibjtagのversion
+    pub old_version: String,
+    pub new_version: String,
+    // suspiciousが先
+    pub entries: Vec<DiffEntry>,
+}
+
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn suspicious(&self) -> impl Iterator<Item = &DiffEntry> {
+        self.entries
+            .iter()
+            .filter(|x| x.severity == Severity::Suspicious)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+impl fmt::Display for SnapshotDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // versionを持たないsnapshotもある
+        let version = |x: &str| if x.is_empty() { "(unknown)" } else { x }.to_string();
+        if self.old_version != self.new_version {
+            writeln!(
+                f,
+                "snapshots taken with libjtag {} and {}",
+                version(&self.old_version),
+                version(&self.new_version)
+            )?;
+        }
+        if self.is_empty() {
+            return write!(f, "no differences");
+        }
+        let mut category = None;
+        for (i, entry) in self.entries.iter().enumerate() {
+            if category != Some(entry.severity) {
+                if i != 0 {
+                    writeln!(f)?;
+                }
+                write!(f, "{}:", entry.severity)?;
+                category = Some(entry.severity);
+            }
+            write!(f, "\n  {}: {}", entry.name, entry.change)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jtag::dap::tests::{memap_dap, MemApSim};
+    use crate::jtag::idcode::IdCode;
+    use std::convert::TryFrom;
+
+    fn snapshot(version: &str, registers: &[(&str, u64)]) -> DebugSnapshot {
+        DebugSnapshot {
+            version: version.to_string(),
+            registers: registers
+                .iter()
+                .map(|(name, value)| (name.to_string(), *value))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn field_test() {
+        let desc = RegDesc::find("EDDFR").unwrap();
+        let brps = desc.fields.iter().find(|x| x.name == "BRPs").unwrap();
+        assert_eq!(0xf000, brps.mask());
+        assert_eq!(5, brps.extract(0x1030_5000));
+        let base = RegDesc::find("BASE").unwrap();
+        assert_eq!(0xffff_ffff_ffff_f000, base.fields[0].mask());
+        assert_eq!(0xffc, base.undescribed());
+        assert!(RegDesc::find("EDCIDSR").is_none());
+    }
+
+    #[test]
+    fn diff_test() {
+        let old = snapshot(
+            "0.0.9",
+            &[
+                ("DP.DPIDR", 0x2ba0_1477),
+                ("DP.CTRL/STAT", 0xf000_0000),
+                ("DP.TARGETID", 1),
+                ("core0.EDPRSR", 0x1),
+                ("core0.EDDFR", 0x1030_5000),
+                ("core0.MIDR_EL1", 0x410f_d083),
+                ("core0.EDCIDSR", 0x5),
+                ("core1.EDPRSR", 0x1),
+            ],
+        );
+        let new = snapshot(
+            "0.1.0",
+            &[
+                ("DP.DPIDR", 0x3ba0_1476),
+                ("DP.CTRL/STAT", 0x5000_0000),
+                ("DP.TARGETID", 2),
+                ("core0.EDPRSR", 0x21),
+                ("core0.EDDFR", 0x1030_3000),
+                ("core0.MIDR_EL1", 0x410f_d084),
+                ("core0.EDVIDSR", 0x10),
+            ],
+        );
+        let diff = old.diff(&new);
+        assert_eq!(5, diff.suspicious().count());
+        assert_eq!(
+            "\
+snapshots taken with libjtag 0.0.9 and 0.1.0
+suspicious:
+  core count: 0x2 -> 0x1
+  DP.CTRL/STAT.CSYSPWRUPACK: 0x1 -> 0x0
+  DP.CTRL/STAT.CDBGPWRUPACK: 0x1 -> 0x0
+  core0.EDDFR.BRPs: 0x5 -> 0x3
+  core0.EDPRSR.OSLK: 0x0 -> 0x1
+informational:
+  DP.DPIDR.REVISION: 0x2 -> 0x3
+  DP.DPIDR.<other bits>: 0x1 -> 0x0
+  DP.TARGETID: 0x1 -> 0x2
+  core0.EDCIDSR: removed (was 0x5)
+  core0.EDVIDSR: added (0x10)
+  core0.MIDR_EL1.Revision: 0x3 -> 0x4
+  core1.EDPRSR: removed (was 0x1)",
+            diff.to_string()
+        );
+
+        assert!(old.diff(&old).is_empty());
+        assert_eq!("no differences", old.diff(&old).to_string());
+    }
+
+    #[test]
+    fn json_test() {
+        let old = snapshot("0.1.0", &[("core0.EDPRSR", 0x1), ("core0.EDCIDSR", 0x5)]);
+        let new = snapshot(
+            "0.1.0",
+            &[("core0.EDPRSR", 0x21), ("tap0.IDCODE", 0x4ba0_0477)],
+        );
+        assert_eq!(old, DebugSnapshot::from_json(&old.to_json()).unwrap());
+        let diff = old.diff(&new);
+        assert_eq!(
+            r#"{
+  "old_version": "0.1.0",
+  "new_version": "0.1.0",
+  "entries": [
+    {
+      "severity": "suspicious",
+      "name": "tap count",
+      "change": {
+        "changed": {
+          "old": 0,
+          "new": 1
+        }
+      }
+    },
+    {
+      "severity": "suspicious",
+      "name": "core0.EDPRSR.OSLK",
+      "change": {
+        "changed": {
+          "old": 0,
+          "new": 1
+        }
+      }
+    },
+    {
+      "severity": "informational",
+      "name": "core0.EDCIDSR",
+      "change": {
+        "removed": {
+          "old": 5
+        }
+      }
+    },
+    {
+      "severity": "informational",
+      "name": "tap0.IDCODE",
+      "change": {
+        "added": {
+          "new": 1268778103
+        }
+      }
+    }
+  ]
+}"#,
+            diff.to_json()
+        );
+        assert_eq!(diff, serde_json::from_str(&diff.to_json()).unwrap());
+    }
+
+    // 古いversionのsnapshotは知らないfieldを持たず、新しいものは増えたfieldを持つ
+    #[test]
+    fn compatibility_test() {
+        let old = DebugSnapshot::from_json(r#"{"registers": {"core0.EDPRSR": 1}}"#).unwrap();
+        assert_eq!("", old.version);
+        let new = DebugSnapshot::from_json(
+            r#"{"version": "9.0.0", "registers": {"core0.EDPRSR": 33}, "rom_table": []}"#,
+        )
+        .unwrap();
+        let diff = old.diff(&new);
+        assert_eq!(1, diff.entries.len());
+        assert!(diff
+            .to_string()
+            .starts_with("snapshots taken with libjtag (unknown) and 9.0.0\n"));
+        assert!(DebugSnapshot::from_json("42").is_err());
+    }
+
+    #[test]
+    fn capture_test() {
+        let core = CoreBase {
+            debug: 0x8001_0000,
+            cti: 0x8001_8000,
+        };
+        let mut sim = MemApSim::new();
+        sim.memory.insert(core.debug + 0x314, 0x21);
+        sim.memory.insert(core.debug + 0xD00, 0x410f_d083);
+        let mut dap = memap_dap(sim);
+        let mut snapshot = DebugSnapshot::capture(&mut dap, 0, &[core]).unwrap();
+        snapshot.add_idcodes(&[
+            TapDevice::Bypass,
+            TapDevice::IdCode(IdCode::try_from(0x4ba0_0477).unwrap()),
+        ]);
+        assert_eq!(Some(&0x21), snapshot.registers.get("core0.EDPRSR"));
+        assert_eq!(Some(&0x410f_d083), snapshot.registers.get("core0.MIDR_EL1"));
+        assert_eq!(Some(&0x4ba0_0477), snapshot.registers.get("tap1.IDCODE"));
+        assert!(snapshot.registers.contains_key("DP.CTRL/STAT"));
+        assert!(snapshot.registers.contains_key("AP0.BASE"));
+        assert_eq!(env!("CARGO_PKG_VERSION"), snapshot.version);
+    }
+}
@@ -0,0 +1,959 @@
+// jtag_testのcommand line
+// 依存を増やさないように引数は手で解析する
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+use std::time::Duration;
+
+use libjtag::config::{AdapterConfig, ChainConfig, Config, BUILTIN_PROFILES};
+use libjtag::error::{DapError, DebugError};
+use libjtag::interface::ftdi::DeviceSelector;
+use libjtag::interface::ftdi_builder::Pin;
+use libjtag::interface::InterfaceError;
+use libjtag::jtag::dap::{DapAck, WriteGuard};
+use libjtag::jtag::svf::SvfProgress;
+use libjtag::target::arm64::{CoreBase, BCM2711_CORES};
+use libjtag::tools::memfile::{Format, DEFAULT_RECORD_LEN};
+
+pub const USAGE: &str = "\
+usage: jtag_test [options] <command>
+
+commands:
+    probes                            list connected FTDI adapters
+    scan                              print devices on the JTAG chain
+    ping                              re-read the IDCODE of the first device
+                                      and check that it has not changed
+    dap-info                          print DPIDR, MEM-AP IDR and BASE
+    halt --core N                     halt core N through its CTI
+    resume --core N                   resume core N through its CTI
+    read-mem --addr A --len N [--out FILE]
+                                      read N bytes from the system MEM-AP
+    write-mem --addr A (--value V | --in FILE)
+                                      write a 32bit word or a file
+    dump --addr A --len N --out FILE [--format bin|ihex|srec] [--record-len N]
+                                      dump memory to a file (format from the
+                                      extension unless --format is given)
+    load --in FILE [--addr A]         load a bin/ihex/srec file and verify it
+                                      (--addr is required for bin, and moves
+                                      ihex/srec to start at A)
+    fill --addr A --len N [--value V] fill N bytes with a 32bit pattern
+                                      (default: 0)
+    verify --addr A --in FILE         compare memory with a binary file
+    gdb [--port P]                    start the gdb server on core 0
+    watch (--addr A | --core N --reg OFFSET) [--mask M] [--interval T]
+                                      print changes of a 32bit word, or of a
+                                      debug register of core N, until Ctrl-C
+                                      (T: e.g. 100ms, 1s; default: 100ms)
+    svf play FILE|-                   play an SVF file on the whole chain
+                                      (-: read it from stdin as it arrives)
+    report snapshot --out FILE        save IDCODEs, DP/AP and core debug
+                                      registers as JSON
+    report diff OLD NEW [--out FILE]  compare two snapshots field by field
+                                      (--out: also write the diff as JSON)
+    tui                               dashboard of all cores with halt/resume/
+                                      step keys (needs the tui feature; a CTI
+                                      base of 0 disables them for that core)
+    run-script FILE [--core N]        run a rhai script (target/cti: core N,
+                                      default 0; see scripts/)
+    repl [--core N]                   evaluate rhai lines interactively
+
+options:
+    --config FILE|NAME                adapter/chain/target profile (TOML file or
+                                      built-in: arm-usb-ocd-h, rpi4). Options
+                                      given on the command line override it
+    --backend bitbang|mpsse           FTDI backend (default: bitbang)
+    --vid V --pid P                   USB VID/PID (default: 0x15ba:0x002a)
+    --serial S                        USB serial number of the adapter
+    --probe serial:S|desc:D|index:N|usb:BUS-PORT[.PORT...]
+                                      choose one of several adapters with the
+                                      same VID/PID (see the probes command)
+    --pin NAME=N                      pin position, e.g. --pin srst=4
+    --tck-hz HZ                       TCK frequency (default: 100000)
+    --ir-len N                        IR length of the DAP (default: 4)
+    --ap N                            MEM-AP for dap-info (default: 0)
+    --memory-ap N                     MEM-AP for read-mem/write-mem (default: 1)
+    --debug-base A,A,...              debug register base of each core
+    --cti-base A,A,...                CTI base of each core (default: BCM2711)
+    -v, --verbose                     print debug logs
+";
+
+// 終了コード
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_NO_DEVICE: i32 = 3;
+pub const EXIT_NO_TARGET: i32 = 4;
+pub const EXIT_WAIT_TIMEOUT: i32 = 5;
+
+#[derive(Debug, PartialEq)]
+pub enum CliError {
+    Usage(String),
+    // USBのadapterが開けない
+    NoDevice(String),
+    // DAPがWAITを返し続けた
+    WaitTimeout,
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Usage(message) => write!(f, "{}", message),
+            CliError::NoDevice(message) => write!(f, "adapter not found: {}", message),
+            CliError::WaitTimeout => write!(f, "DAP kept returning WAIT"),
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+pub fn exit_code(e: &anyhow::Error) -> i32 {
+    if let Some(e) = e.downcast_ref::<CliError>() {
+        return match e {
+            CliError::Usage(_) => EXIT_USAGE,
+            CliError::NoDevice(_) => EXIT_NO_DEVICE,
+            CliError::WaitTimeout => EXIT_WAIT_TIMEOUT,
+        };
+    }
+    if let Some(e) = e.downcast_ref::<DebugError>() {
+        return match e {
+            DebugError::Interface(e) => interface_exit_code(e),
+            DebugError::Dap(e) => dap_exit_code(e),
+            DebugError::CorePoweredDown => EXIT_NO_TARGET,
+            _ => EXIT_FAILURE,
+        };
+    }
+    if let Some(e) = e.downcast_ref::<DapError>() {
+        return dap_exit_code(e);
+    }
+    e.downcast_ref::<InterfaceError>()
+        .map_or(EXIT_FAILURE, interface_exit_code)
+}
+
+fn dap_exit_code(e: &DapError) -> i32 {
+    match e {
+        DapError::Interface(e) => interface_exit_code(e),
+        DapError::IdcodeMismatch { .. } | DapError::NoTarget | DapError::PowerUpTimeout => {
+            EXIT_NO_TARGET
+        }
+        DapError::WaitTimeout => EXIT_WAIT_TIMEOUT,
+        _ => EXIT_FAILURE,
+    }
+}
+
+fn interface_exit_code(e: &InterfaceError) -> i32 {
+    match e {
+        InterfaceError::EmptyChain | InterfaceError::BadIrCapture => EXIT_NO_TARGET,
+        InterfaceError::Timeout => EXIT_WAIT_TIMEOUT,
+        _ => EXIT_FAILURE,
+    }
+}
+
+// OK以外のACKをerrorにする
+pub fn check_ack(ack: DapAck) -> Result<()> {
+    match ack {
+        DapAck::OkFault => Ok(()),
+        DapAck::Wait => Err(CliError::WaitTimeout.into()),
+        ack => bail!("unexpected ACK: {:?}", ack),
+    }
+}
+
+pub use libjtag::config::Backend;
+
+#[derive(Debug, PartialEq)]
+pub enum WriteData {
+    Value(u32),
+    File(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WatchTarget {
+    Addr(u64),
+    // coreのdebug register baseからのoffset
+    Register { core: usize, offset: u64 },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Probes,
+    Scan,
+    Ping,
+    DapInfo,
+    Halt {
+        core: usize,
+    },
+    Resume {
+        core: usize,
+    },
+    ReadMem {
+        addr: u64,
+        len: usize,
+        out: Option<String>,
+    },
+    WriteMem {
+        addr: u64,
+        data: WriteData,
+    },
+    Dump {
+        addr: u64,
+        len: usize,
+        out: String,
+        format: Format,
+        record_len: usize,
+    },
+    Load {
+        input: String,
+        addr: Option<u64>,
+    },
+    Fill {
+        addr: u64,
+        len: usize,
+        pattern: u32,
+    },
+    Verify {
+        addr: u64,
+        input: String,
+    },
+    Gdb {
+        port: u16,
+    },
+    Tui,
+    // "-"はstdin
+    SvfPlay {
+        input: String,
+    },
+    Watch {
+        target: WatchTarget,
+        mask: u32,
+        interval: Duration,
+    },
+    ReportSnapshot {
+        out: String,
+    },
+    // adapterは使わない
+    ReportDiff {
+        old: String,
+        new: String,
+        out: Option<String>,
+    },
+    // coreはscriptのtarget/ctiにするcore
+    RunScript {
+        input: String,
+        core: usize,
+    },
+    Repl {
+        core: usize,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub backend: Backend,
+    pub vid: u16,
+    pub pid: u16,
+    pub pins: Vec<(Pin, u8)>,
+    pub tck_hz: u32,
+    pub serial: Option<String>,
+    pub probe: Option<DeviceSelector>,
+    pub chain: ChainConfig,
+    pub apnum: u8,
+    pub memory_apnum: u8,
+    pub cores: Vec<CoreBase>,
+    // profileのtarget.memory。DAPを作る度にset_write_guardする
+    pub write_guard: WriteGuard,
+    pub verbose: bool,
+    pub command: Command,
+}
+
+impl Options {
+    // create_interfaceに渡すadapterの設定。probeは別に渡す
+    pub fn adapter(&self) -> AdapterConfig {
+        AdapterConfig {
+            backend: self.backend,
+            vid: self.vid,
+            pid: self.pid,
+            serial: self.serial.clone(),
+            tck_hz: Some(self.tck_hz),
+            pins: self.pins.clone(),
+        }
+    }
+}
+
+const DEFAULT_PINS: [(Pin, u8); 7] = [
+    (Pin::Tck, 0),
+    (Pin::Tdi, 1),
+    (Pin::Tdo, 2),
+    (Pin::Tms, 3),
+    (Pin::Srst, 4),
+    (Pin::Trst, 5),
+    (Pin::Rtck, 7),
+];
+const DEFAULT_GDB_PORT: u16 = 3333;
+const DEFAULT_WATCH_INTERVAL: Duration = Duration::from_millis(100);
+// TODO: ボードに合わせてsystem memoryにつながるAPを設定する
+const DEFAULT_MEMORY_APNUM: u8 = 1;
+
+fn usage(message: String) -> anyhow::Error {
+    CliError::Usage(message).into()
+}
+
+// 0x付きなら16進数
+pub fn parse_number(value: &str) -> Result<u64> {
+    let parsed = match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => value.replace('_', "").parse(),
+    };
+    parsed.map_err(|_| usage(format!("invalid number: {}", value)))
+}
+
+fn parse_as<T: std::convert::TryFrom<u64>>(name: &str, value: &str) -> Result<T> {
+    T::try_from(parse_number(value)?)
+        .map_err(|_| usage(format!("{} is out of range: {}", name, value)))
+}
+
+// 単位なしはms
+fn parse_interval(value: &str) -> Result<Duration> {
+    let (number, scale) = if let Some(ms) = value.strip_suffix("ms") {
+        (ms, 1)
+    } else if let Some(s) = value.strip_suffix('s') {
+        (s, 1000)
+    } else {
+        (value, 1)
+    };
+    let ms = parse_number(number)?
+        .checked_mul(scale)
+        .filter(|x| *x > 0)
+        .ok_or_else(|| usage(format!("invalid interval: {}", value)))?;
+    Ok(Duration::from_millis(ms))
+}
+
+fn parse_pin(value: &str) -> Result<(Pin, u8)> {
+    let (name, position) = value
+        .split_once('=')
+        .ok_or_else(|| usage(format!("expected NAME=N: {}", value)))?;
+    let pin = Pin::ALL
+        .iter()
+        .find(|x| x.name() == name.to_lowercase())
+        .ok_or_else(|| usage(format!("unknown pin: {}", name)))?;
+    Ok((*pin, parse_as("pin position", position)?))
+}
+
+// 同名のfileが無ければ組み込みのprofileを探す
+fn load_config(value: &str) -> Result<Config> {
+    if !std::path::Path::new(value).exists() && BUILTIN_PROFILES.contains(&value) {
+        if let Some(config) = Config::builtin(value) {
+            return Ok(config);
+        }
+    }
+    Config::from_path(value).map_err(|e| usage(format!("{}", e)))
+}
+
+fn apply_config(options: &mut Options, config: Config) -> Result<()> {
+    options.backend = config.adapter.backend;
+    options.vid = config.adapter.vid;
+    options.pid = config.adapter.pid;
+    options.serial = config.adapter.serial;
+    if let Some(hz) = config.adapter.tck_hz {
+        options.tck_hz = hz;
+    }
+    if !config.adapter.pins.is_empty() {
+        options.pins = config.adapter.pins;
+    }
+    options.chain = config.chain;
+    options.apnum = config.target.ap;
+    if let Some(apnum) = config.target.memory_ap {
+        options.memory_apnum = apnum;
+    }
+    options.write_guard = WriteGuard::from_config(&config.target);
+    options.cores = config
+        .target
+        .cores()
+        .map_err(|e| usage(format!("{}", e)))?
+        .to_vec();
+    Ok(())
+}
+
+fn parse_list(value: &str) -> Result<Vec<u64>> {
+    value.split(',').map(parse_number).collect()
+}
+
+// --nameの次の値を取り出す
+struct Args<'a> {
+    args: std::slice::Iter<'a, String>,
+}
+
+impl<'a> Args<'a> {
+    fn value(&mut self, name: &str) -> Result<&'a str> {
+        self.args
+            .next()
+            .map(|x| x.as_str())
+            .ok_or_else(|| usage(format!("{} requires a value", name)))
+    }
+}
+
+// argsにはprogram名を含めない
+pub fn parse(args: &[String]) -> Result<Options> {
+    let mut options = Options {
+        backend: Backend::BitBang,
+        vid: 0x15ba,
+        pid: 0x002a,
+        pins: DEFAULT_PINS.to_vec(),
+        tck_hz: 100_000,
+        serial: None,
+        probe: None,
+        chain: ChainConfig::single(4),
+        apnum: 0,
+        memory_apnum: DEFAULT_MEMORY_APNUM,
+        cores: BCM2711_CORES.to_vec(),
+        write_guard: WriteGuard::new(),
+        verbose: false,
+        command: Command::Scan,
+    };
+    let mut debug_bases = None;
+    let mut cti_bases = None;
+    let mut command = None;
+    // commandの後に続く引数(svf play FILE)
+    let mut operands: Vec<String> = Vec::new();
+    // subcommandの引数
+    let mut core = None;
+    let mut addr = None;
+    let mut len = None;
+    let mut out = None;
+    let mut value = None;
+    let mut input = None;
+    let mut port = DEFAULT_GDB_PORT;
+    let mut format = None;
+    let mut record_len = DEFAULT_RECORD_LEN;
+    let mut reg = None;
+    let mut mask = 0xffff_ffff;
+    let mut interval = DEFAULT_WATCH_INTERVAL;
+
+    // profileの値を先に入れ、他の引数で上書きする
+    let mut config = None;
+    let mut pre = Args { args: args.iter() };
+    while let Some(arg) = pre.args.next() {
+        if arg == "--config" {
+            config = Some(pre.value(arg)?);
+        }
+    }
+    if let Some(config) = config {
+        apply_config(&mut options, load_config(config)?)?;
+    }
+
+    let mut args = Args { args: args.iter() };
+    while let Some(arg) = args.args.next() {
+        let arg = arg.as_str();
+        match arg {
+            "-h" | "--help" => return Err(usage(USAGE.to_string())),
+            "-v" | "--verbose" => options.verbose = true,
+            "--backend" => {
+                options.backend = match args.value(arg)? {
+                    "bitbang" => Backend::BitBang,
+                    "mpsse" => Backend::Mpsse,
+                    x => return Err(usage(format!("unknown backend: {}", x))),
+                }
+            }
+            "--vid" => options.vid = parse_as(arg, args.value(arg)?)?,
+            "--pid" => options.pid = parse_as(arg, args.value(arg)?)?,
+            "--pin" => {
+                let (pin, position) = parse_pin(args.value(arg)?)?;
+                options.pins.retain(|x| x.0 != pin);
+                options.pins.push((pin, position));
+            }
+            "--tck-hz" => options.tck_hz = parse_as(arg, args.value(arg)?)?,
+            "--config" => {
+                args.value(arg)?;
+            }
+            "--serial" => options.serial = Some(args.value(arg)?.to_string()),
+            "--probe" => {
+                let value = args.value(arg)?;
+                options.probe = Some(
+                    value
+                        .parse()
+                        .map_err(|e| usage(format!("invalid --probe: {}", e)))?,
+                )
+            }
+            "--ir-len" => options.chain = ChainConfig::single(parse_as(arg, args.value(arg)?)?),
+            "--ap" => options.apnum = parse_as(arg, args.value(arg)?)?,
+            "--memory-ap" => options.memory_apnum = parse_as(arg, args.value(arg)?)?,
+            "--debug-base" => debug_bases = Some(parse_list(args.value(arg)?)?),
+            "--cti-base" => cti_bases = Some(parse_list(args.value(arg)?)?),
+            "--core" => core = Some(parse_as(arg, args.value(arg)?)?),
+            "--addr" => addr = Some(parse_number(args.value(arg)?)?),
+            "--len" => len = Some(parse_as(arg, args.value(arg)?)?),
+            "--out" => out = Some(args.value(arg)?.to_string()),
+            "--value" => value = Some(parse_as(arg, args.value(arg)?)?),
+            "--in" => input = Some(args.value(arg)?.to_string()),
+            "--port" => port = parse_as(arg, args.value(arg)?)?,
+            "--format" => {
+                let value = args.value(arg)?;
+                format = Some(
+                    Format::from_name(value)
+                        .ok_or_else(|| usage(format!("unknown format: {}", value)))?,
+                )
+            }
+            "--record-len" => record_len = parse_as(arg, args.value(arg)?)?,
+            "--reg" => reg = Some(parse_number(args.value(arg)?)?),
+            "--mask" => mask = parse_as(arg, args.value(arg)?)?,
+            "--interval" => interval = parse_interval(args.value(arg)?)?,
+            x if x.starts_with('-') && x != "-" => {
+                return Err(usage(format!("unknown option: {}", x)))
+            }
+            x if command.is_none() => command = Some(x.to_string()),
+            x => operands.push(x.to_string()),
+        }
+    }
+
+    match (debug_bases, cti_bases) {
+        (None, None) => (),
+        (Some(debug), Some(cti)) if debug.len() == cti.len() => {
+            options.cores = debug
+                .iter()
+                .zip(cti.iter())
+                .map(|(debug, cti)| CoreBase {
+                    debug: *debug,
+                    cti: *cti,
+                })
+                .collect();
+        }
+        _ => {
+            return Err(usage(
+                "--debug-base and --cti-base must list the same number of cores".to_string(),
+            ))
+        }
+    }
+
+    fn required<T>(x: Option<T>, name: &str) -> Result<T> {
+        x.ok_or_else(|| usage(format!("{} is required", name)))
+    }
+    let core_index = |x: Option<usize>, cores: usize| match x {
+        Some(n) if n < cores => Ok(n),
+        Some(n) => Err(usage(format!(
+            "core {} does not exist ({} cores)",
+            n, cores
+        ))),
+        None => Err(usage("--core is required".to_string())),
+    };
+    let command = command.ok_or_else(|| usage(USAGE.to_string()))?;
+    if command != "svf" && command != "report" && command != "run-script" {
+        if let Some(x) = operands.first() {
+            return Err(usage(format!("unexpected argument: {}", x)));
+        }
+    }
+    options.command = match command.as_str() {
+        "probes" => Command::Probes,
+        "scan" => Command::Scan,
+        "ping" => Command::Ping,
+        "dap-info" => Command::DapInfo,
+        "halt" => Command::Halt {
+            core: core_index(core, options.cores.len())?,
+        },
+        "resume" => Command::Resume {
+            core: core_index(core, options.cores.len())?,
+        },
+        "read-mem" => Command::ReadMem {
+            addr: required(addr, "--addr")?,
+            len: required(len, "--len")?,
+            out,
+        },
+        "write-mem" => Command::WriteMem {
+            addr: required(addr, "--addr")?,
+            data: match (value, input) {
+                (Some(value), None) => WriteData::Value(value),
+                (None, Some(input)) => WriteData::File(input),
+                _ => return Err(usage("write-mem needs either --value or --in".to_string())),
+            },
+        },
+        "dump" => {
+            let out = required(out, "--out")?;
+            Command::Dump {
+                addr: required(addr, "--addr")?,
+                len: required(len, "--len")?,
+                format: format.unwrap_or_else(|| Format::from_path(out.as_ref())),
+                out,
+                record_len,
+            }
+        }
+        "load" => Command::Load {
+            input: required(input, "--in")?,
+            addr,
+        },
+        "fill" => Command::Fill {
+            addr: required(addr, "--addr")?,
+            len: required(len, "--len")?,
+            pattern: value.unwrap_or(0),
+        },
+        "verify" => Command::Verify {
+            addr: required(addr, "--addr")?,
+            input: required(input, "--in")?,
+        },
+        "gdb" => Command::Gdb { port },
+        "tui" => Command::Tui,
+        "svf" => match operands.as_slice() {
+            [play, input] if play == "play" => Command::SvfPlay {
+                input: input.to_string(),
+            },
+            _ => return Err(usage("usage: svf play FILE|-".to_string())),
+        },
+        "report" => match operands.as_slice() {
+            [snapshot] if snapshot == "snapshot" => Command::ReportSnapshot {
+                out: required(out, "--out")?,
+            },
+            [diff, old, new] if diff == "diff" => Command::ReportDiff {
+                old: old.to_string(),
+                new: new.to_string(),
+                out,
+            },
+            _ => {
+                return Err(usage(
+                    "usage: report snapshot --out FILE | report diff OLD NEW [--out FILE]"
+                        .to_string(),
+                ))
+            }
+        },
+        "run-script" => match operands.as_slice() {
+            [input] => Command::RunScript {
+                input: input.to_string(),
+                core: core_index(Some(core.unwrap_or(0)), options.cores.len())?,
+            },
+            _ => return Err(usage("usage: run-script FILE [--core N]".to_string())),
+        },
+        "repl" => Command::Repl {
+            core: core_index(Some(core.unwrap_or(0)), options.cores.len())?,
+        },
+        "watch" => Command::Watch {
+            target: match (addr, reg) {
+                (Some(addr), None) if core.is_none() => WatchTarget::Addr(addr),
+                (None, Some(offset)) => WatchTarget::Register {
+                    core: core_index(core, options.cores.len())?,
+                    offset,
+                },
+                _ => {
+                    return Err(usage(
+                        "watch needs either --addr or --core and --reg".to_string(),
+                    ))
+                }
+            },
+            mask,
+            interval,
+        },
+        x => return Err(usage(format!("unknown command: {}\n\n{}", x, USAGE))),
+    };
+    if let Command::ReadMem { addr, .. }
+    | Command::WriteMem { addr, .. }
+    | Command::Watch {
+        target: WatchTarget::Addr(addr),
+        ..
+    }
+    | Command::Watch {
+        target: WatchTarget::Register { offset: addr, .. },
+        ..
+    } = options.command
+    {
+        if addr % 4 != 0 {
+            return Err(usage(format!("--addr must be 4-byte aligned: {:#x}", addr)));
+        }
+    }
+    Ok(options)
+}
+
+// 16byteずつ16進数で表示する
+// 端末にだけ進捗を出す。100%で改行する
+pub fn progress(done: usize, total: usize) {
+    if total == 0 {
+        return;
+    }
+    eprint!("\r{}/{} bytes ({}%)", done, total, done * 100 / total);
+    if done == total {
+        eprintln!();
+    }
+}
+
+// 同じ行を書き換える。改行は再生が終わった側で入れる
+pub fn svf_progress(progress: &SvfProgress) {
+    eprint!(
+        "\rline {}: {} commands, {} bits",
+        progress.line, progress.commands, progress.bits
+    );
+}
+
+pub fn no_device<E: fmt::Display>(e: E) -> anyhow::Error {
+    anyhow!(CliError::NoDevice(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &str) -> Result<Options> {
+        let args: Vec<String> = args.split_whitespace().map(|x| x.to_string()).collect();
+        parse(&args)
+    }
+
+    #[test]
+    fn parse_test() {
+        let options = parse_str("scan").unwrap();
+        assert_eq!(Command::Scan, options.command);
+        assert_eq!(Backend::BitBang, options.backend);
+        assert_eq!(BCM2711_CORES.to_vec(), options.cores);
+
+        let options = parse_str(
+            "--backend mpsse --vid 0x0403 --pid 0x6010 --pin srst=9 -v \
+             read-mem --addr 0x8000_0000 --len 64 --out dump.bin",
+        )
+        .unwrap();
+        assert_eq!(Backend::Mpsse, options.backend);
+        assert_eq!((0x0403, 0x6010), (options.vid, options.pid));
+        assert!(options.pins.contains(&(Pin::Srst, 9)));
+        assert!(!options.pins.contains(&(Pin::Srst, 4)));
+        assert!(options.verbose);
+        let adapter = options.adapter();
+        assert_eq!(Backend::Mpsse, adapter.backend);
+        assert_eq!(options.pins, adapter.pins);
+        assert_eq!(Some(options.tck_hz), adapter.tck_hz);
+        assert_eq!(
+            Command::ReadMem {
+                addr: 0x8000_0000,
+                len: 64,
+                out: Some("dump.bin".to_string()),
+            },
+            options.command
+        );
+
+        let options = parse_str("ping").unwrap();
+        assert_eq!(Command::Ping, options.command);
+        assert_eq!(Command::Tui, parse_str("tui").unwrap().command);
+        assert_eq!(
+            Command::SvfPlay {
+                input: "-".to_string()
+            },
+            parse_str("-v svf play -").unwrap().command
+        );
+        assert_eq!(
+            Command::SvfPlay {
+                input: "erase.svf".to_string()
+            },
+            parse_str("svf play erase.svf --tck-hz 1000000")
+                .unwrap()
+                .command
+        );
+        assert_eq!(
+            Command::ReportSnapshot {
+                out: "good.json".to_string()
+            },
+            parse_str("report snapshot --out good.json").unwrap().command
+        );
+        assert_eq!(
+            Command::ReportDiff {
+                old: "good.json".to_string(),
+                new: "bad.json".to_string(),
+                out: None,
+            },
+            parse_str("report diff good.json bad.json").unwrap().command
+        );
+        assert!(parse_str("report snapshot").is_err());
+        assert!(parse_str("report diff good.json").is_err());
+        assert_eq!(
+            Command::RunScript {
+                input: "scripts/midr.rhai".to_string(),
+                core: 0,
+            },
+            parse_str("run-script scripts/midr.rhai").unwrap().command
+        );
+        assert_eq!(
+            Command::Repl { core: 2 },
+            parse_str("repl --core 2").unwrap().command
+        );
+
+        let options = parse_str("--probe usb:1-3.1 probes").unwrap();
+        assert_eq!(Command::Probes, options.command);
+        assert_eq!(
+            Some(DeviceSelector::ByBusPort {
+                bus: 1,
+                ports: vec![3, 1]
+            }),
+            options.probe
+        );
+
+        let options =
+            parse_str("--debug-base 0x1000,0x2000 --cti-base 0x1800,0x2800 halt --core 1").unwrap();
+        assert_eq!(Command::Halt { core: 1 }, options.command);
+        assert_eq!(
+            CoreBase {
+                debug: 0x2000,
+                cti: 0x2800,
+            },
+            options.cores[1]
+        );
+
+        // profileの後に書いた引数が優先される
+        let options = parse_str("--ir-len 5 --config rpi4 --tck-hz 500000 halt --core 3").unwrap();
+        assert_eq!((0x15ba, 0x002a), (options.vid, options.pid));
+        assert_eq!(500_000, options.tck_hz);
+        assert_eq!(5, options.chain.ir_len());
+        assert_eq!(BCM2711_CORES.to_vec(), options.cores);
+
+        assert_eq!(
+            Command::WriteMem {
+                addr: 0x100,
+                data: WriteData::Value(0xdead_beef),
+            },
+            parse_str("write-mem --addr 256 --value 0xdeadbeef")
+                .unwrap()
+                .command
+        );
+
+        assert_eq!(
+            Command::Dump {
+                addr: 0x8000_0000,
+                len: 0x100,
+                out: "dump.s19".to_string(),
+                format: Format::Srec,
+                record_len: DEFAULT_RECORD_LEN,
+            },
+            parse_str("dump --addr 0x8000_0000 --len 0x100 --out dump.s19")
+                .unwrap()
+                .command
+        );
+        assert_eq!(
+            Command::Load {
+                input: "boot.bin".to_string(),
+                addr: Some(0x8_0000),
+            },
+            parse_str("load --in boot.bin --addr 0x80000")
+                .unwrap()
+                .command
+        );
+        assert_eq!(
+            Command::Fill {
+                addr: 0x8000_0001,
+                len: 0x1000,
+                pattern: 0,
+            },
+            parse_str("fill --addr 0x8000_0001 --len 0x1000")
+                .unwrap()
+                .command
+        );
+        assert_eq!(
+            Command::Verify {
+                addr: 0x8_0000,
+                input: "boot.bin".to_string(),
+            },
+            parse_str("verify --addr 0x80000 --in boot.bin")
+                .unwrap()
+                .command
+        );
+        assert_eq!(
+            Command::Watch {
+                target: WatchTarget::Addr(0x8001_0314),
+                mask: 0x10,
+                interval: Duration::from_millis(100),
+            },
+            parse_str("watch --addr 0x80010314 --mask 0x10 --interval 100ms")
+                .unwrap()
+                .command
+        );
+        assert_eq!(
+            Command::Watch {
+                target: WatchTarget::Register {
+                    core: 2,
+                    offset: 0x88,
+                },
+                mask: 0xffff_ffff,
+                interval: Duration::from_secs(2),
+            },
+            parse_str("watch --core 2 --reg 0x88 --interval 2s")
+                .unwrap()
+                .command
+        );
+        assert_eq!(Duration::from_millis(250), parse_interval("250").unwrap());
+    }
+
+    #[test]
+    fn parse_error_test() {
+        for args in [
+            "",
+            "unknown",
+            "halt",
+            "halt --core 4",
+            "resume --core x",
+            "read-mem --addr 0x1000",
+            "read-mem --addr 0x1002 --len 4",
+            "write-mem --addr 0x1000",
+            "--backend jlink scan",
+            "--pin foo=1 scan",
+            "--pin tck=256 scan",
+            "--debug-base 0x1000 scan",
+            "scan --vid",
+            "--probe 2 scan",
+            "--probe index:x scan",
+            "dump --addr 0 --len 4",
+            "dump --addr 0 --len 4 --out x.bin --format elf",
+            "load",
+            "fill --addr 0",
+            "verify --in boot.bin",
+            "--config /nonexistent/board.toml scan",
+            "--config arm-usb-ocd-h halt --core 1",
+            "watch",
+            "watch --addr 0x1002",
+            "watch --addr 0x1000 --mask 0x1_0000_0000",
+            "watch --addr 0x1000 --interval 0ms",
+            "watch --addr 0x1000 --interval 1min",
+            "watch --reg 0x88",
+            "watch --core 4 --reg 0x88",
+            "watch --core 0 --reg 0x8a",
+            "watch --addr 0x1000 --core 0",
+            "watch --addr 0x1000 --reg 0x88",
+            "svf",
+            "svf play",
+            "svf run erase.svf",
+            "svf play a.svf b.svf",
+            "scan -",
+            "run-script",
+            "run-script a.rhai b.rhai",
+            "run-script a.rhai --core 4",
+            "repl --core 4",
+        ]
+        .iter()
+        {
+            let e = parse_str(args).unwrap_err();
+            assert_eq!(EXIT_USAGE, exit_code(&e), "{}", args);
+        }
+    }
+
+    #[test]
+    fn exit_code_test() {
+        assert_eq!(
+            EXIT_NO_DEVICE,
+            exit_code(&no_device("unable to open device"))
+        );
+        assert_eq!(
+            EXIT_WAIT_TIMEOUT,
+            exit_code(&check_ack(DapAck::Wait).unwrap_err())
+        );
+        assert_eq!(
+            EXIT_NO_TARGET,
+            exit_code(&anyhow::Error::from(InterfaceError::EmptyChain))
+        );
+        assert_eq!(
+            EXIT_NO_TARGET,
+            exit_code(&anyhow::Error::from(DebugError::CorePoweredDown))
+        );
+        assert_eq!(
+            EXIT_NO_TARGET,
+            exit_code(&anyhow::Error::from(DapError::IdcodeMismatch {
+                expected: 0x4ba0_0477,
+                found: 0x6ba0_0477
+            }))
+        );
+        assert_eq!(
+            EXIT_WAIT_TIMEOUT,
+            exit_code(&anyhow::Error::from(DapError::WaitTimeout))
+        );
+        assert_eq!(
+            EXIT_WAIT_TIMEOUT,
+            exit_code(&anyhow::Error::from(DebugError::Interface(
+                InterfaceError::Timeout
+            )))
+        );
+        assert_eq!(EXIT_FAILURE, exit_code(&anyhow!("something else")));
+        assert!(check_ack(DapAck::OkFault).is_ok());
+    }
+}
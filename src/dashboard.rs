@@ -0,0 +1,656 @@
+// 全coreの状態、最後に読んだregister、JTAGのtransactionを表示するTUI
+// targetへのaccessは全てworker threadで行い、画面はchannelで受け取ったUpdateだけから描く
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::Frame;
+
+use libjtag::jtag::dap::*;
+use libjtag::jtag::stats::DapStats;
+use libjtag::target::arm64::*;
+
+// workerが何も頼まれていない時にcoreの状態を読み直す間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// keyを待つ間隔。この間隔でworkerからのUpdateも取り込む
+const INPUT_INTERVAL: Duration = Duration::from_millis(50);
+const LOG_LINES: usize = 200;
+
+// CTIのbaseが0のcoreはCTIが分からないものとして扱い、halt/resume/stepを無効にする
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CoreSlot {
+    pub debug: u64,
+    pub cti: Option<u64>,
+}
+
+pub fn slots(cores: &[CoreBase]) -> Vec<CoreSlot> {
+    cores
+        .iter()
+        .map(|x| CoreSlot {
+            debug: x.debug,
+            cti: if x.cti == 0 { None } else { Some(x.cti) },
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RunState {
+    // まだ読んでいないか、読めなかった
+    Unknown,
+    PoweredDown,
+    Running,
+    // ELはdebug state中しか読めない
+    Halted { el: u8 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Halt,
+    Resume,
+    Step,
+    // GPRを読み直す
+    Registers,
+}
+
+impl Op {
+    const ALL: [Op; 4] = [Op::Halt, Op::Resume, Op::Step, Op::Registers];
+
+    fn key(&self) -> char {
+        match self {
+            Op::Halt => 'h',
+            Op::Resume => 'r',
+            Op::Step => 's',
+            Op::Registers => 'g',
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Op::Halt => "halt",
+            Op::Resume => "resume",
+            Op::Step => "step",
+            Op::Registers => "registers",
+        }
+    }
+}
+
+// UIからworkerへの依頼
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Poll,
+    Core(Op, usize),
+}
+
+// workerからUIへの通知
+#[derive(Clone, Debug, PartialEq)]
+pub enum Update {
+    State { core: usize, state: RunState },
+    // EDPRSRなどが読めなかった
+    PollFailed { core: usize, message: String },
+    Context { core: usize, context: CpuContext },
+    Failed { core: usize, message: String },
+    Log(String),
+    // 1つのActionの終わり。statsはそのActionで発生したDAPのtransaction
+    Activity { action: Action, stats: DapStats },
+    // workerが止まった。以降のActionは処理されない
+    Fatal(String),
+}
+
+pub struct CoreView {
+    pub slot: CoreSlot,
+    pub state: RunState,
+    // 最後に読んだregister。coreが走り出したら古い値として残す
+    pub context: Option<CpuContext>,
+    // 最後の操作の失敗。次にこのcoreを操作するまで残す
+    pub error: Option<String>,
+    pub poll_error: Option<String>,
+}
+
+// 画面に出す状態。描画とは切り離してあるので、端末なしでtestできる
+pub struct Dashboard {
+    pub cores: Vec<CoreView>,
+    pub selected: usize,
+    pub log: VecDeque<String>,
+    pub polls: u64,
+    pub poll_transactions: u64,
+    pub fatal: Option<String>,
+}
+
+fn transactions(stats: &DapStats) -> u64 {
+    stats.dp_reads + stats.dp_writes + stats.ap_reads + stats.ap_writes
+}
+
+fn summary(stats: &DapStats) -> String {
+    format!(
+        "dp {}r/{}w, ap {}r/{}w, {} waits, {}us",
+        stats.dp_reads,
+        stats.dp_writes,
+        stats.ap_reads,
+        stats.ap_writes,
+        stats.wait_acks,
+        (stats.dp_time + stats.ap_time).as_micros()
+    )
+}
+
+impl Dashboard {
+    pub fn new(slots: &[CoreSlot]) -> Self {
+        Dashboard {
+            cores: slots
+                .iter()
+                .map(|slot| CoreView {
+                    slot: *slot,
+                    state: RunState::Unknown,
+                    context: None,
+                    error: None,
+                    poll_error: None,
+                })
+                .collect(),
+            selected: 0,
+            log: VecDeque::new(),
+            polls: 0,
+            poll_transactions: 0,
+            fatal: None,
+        }
+    }
+
+    fn push_log(&mut self, line: String) {
+        if self.log.len() == LOG_LINES {
+            self.log.pop_front();
+        }
+        self.log.push_back(line);
+    }
+
+    pub fn available(&self, core: usize, op: Op) -> bool {
+        let view = &self.cores[core];
+        let halted = matches!(view.state, RunState::Halted { .. });
+        match op {
+            Op::Halt => {
+                view.slot.cti.is_some()
+                    && matches!(view.state, RunState::Running | RunState::Unknown)
+            }
+            Op::Resume | Op::Step => view.slot.cti.is_some() && halted,
+            Op::Registers => halted,
+        }
+    }
+
+    pub fn apply(&mut self, update: Update) {
+        match update {
+            Update::State { core, state } => {
+                self.cores[core].state = state;
+                self.cores[core].poll_error = None;
+            }
+            Update::PollFailed { core, message } => {
+                self.cores[core].state = RunState::Unknown;
+                self.cores[core].poll_error = Some(message);
+            }
+            Update::Context { core, context } => self.cores[core].context = Some(context),
+            Update::Failed { core, message } => {
+                self.push_log(format!("core {}: {}", core, message));
+                self.cores[core].error = Some(message);
+            }
+            Update::Log(line) => self.push_log(line),
+            Update::Activity {
+                action: Action::Poll,
+                stats,
+            } => {
+                self.polls += 1;
+                self.poll_transactions += transactions(&stats);
+            }
+            Update::Activity {
+                action: Action::Core(op, core),
+                stats,
+            } => self.push_log(format!("core {} {}: {}", core, op.name(), summary(&stats))),
+            Update::Fatal(message) => {
+                self.push_log(format!("worker stopped: {}", message));
+                self.fatal = Some(message);
+            }
+        }
+    }
+
+    // j/kか数字でcoreを選び、それ以外は選択中のcoreへの操作にする
+    // 使えない操作のkeyはNoneを返す
+    pub fn on_key(&mut self, key: char) -> Option<Action> {
+        match key {
+            'j' => self.selected = (self.selected + 1) % self.cores.len(),
+            'k' => self.selected = (self.selected + self.cores.len() - 1) % self.cores.len(),
+            x if x.is_ascii_digit() => {
+                let n = x.to_digit(10).unwrap() as usize;
+                if n < self.cores.len() {
+                    self.selected = n;
+                }
+            }
+            x => {
+                let op = Op::ALL.iter().find(|op| op.key() == x)?;
+                if self.fatal.is_none() && self.available(self.selected, *op) {
+                    self.cores[self.selected].error = None;
+                    return Some(Action::Core(*op, self.selected));
+                }
+            }
+        }
+        None
+    }
+}
+
+// 以下はworker threadで動く
+struct Worker<'a, T: DapInterface> {
+    dap: DapHandle<DAP<T>>,
+    slots: &'a [CoreSlot],
+}
+
+impl<'a, T: DapInterface> Worker<'a, T> {
+    fn target(&self, core: usize) -> A64Target<DAP<T>> {
+        A64Target::new(self.dap.clone(), self.slots[core].debug)
+    }
+
+    fn core(&self, core: usize) -> Result<CoreHandle<DAP<T>>> {
+        let cti = match self.slots[core].cti {
+            Some(cti) => cti,
+            None => anyhow::bail!("CTI base of core {} is unknown", core),
+        };
+        Ok(CoreHandle::new(
+            self.target(core),
+            Cti {
+                dap: self.dap.clone(),
+                baseaddr: cti,
+            },
+        ))
+    }
+
+    fn poll(&self, core: usize) -> Update {
+        let mut target = self.target(core);
+        let state = target.edprsr_read().and_then(|edprsr| {
+            if edprsr.PU() == 0 {
+                Ok(RunState::PoweredDown)
+            } else if edprsr.HALTED() == 0 {
+                Ok(RunState::Running)
+            } else {
+                Ok(RunState::Halted {
+                    el: target.current_el()?,
+                })
+            }
+        });
+        match state {
+            Ok(state) => Update::State { core, state },
+            Err(e) => Update::PollFailed {
+                core,
+                message: format!("{}", e),
+            },
+        }
+    }
+
+    fn perform(&self, op: Op, n: usize) -> Result<Option<Update>> {
+        match op {
+            Op::Halt => {
+                let mut core = self.core(n)?;
+                core.target.prepare_debug(true)?;
+                core.target.check_debug_permissions()?;
+                core.halt()?;
+                Ok(None)
+            }
+            Op::Resume => {
+                self.core(n)?.resume()?;
+                Ok(None)
+            }
+            Op::Step => {
+                let mut core = self.core(n)?;
+                let pc = core.target.step(&mut core.cti)?;
+                Ok(Some(Update::Log(format!(
+                    "core {} stepped to {:#x}",
+                    n, pc
+                ))))
+            }
+            Op::Registers => Ok(Some(Update::Context {
+                core: n,
+                context: self.target(n).save_context()?,
+            })),
+        }
+    }
+
+    fn handle(&self, action: Action, updates: &Sender<Update>) -> Result<()> {
+        self.dap.lock().reset_stats();
+        let mut results = Vec::new();
+        match action {
+            Action::Poll => results.extend((0..self.slots.len()).map(|n| self.poll(n))),
+            Action::Core(op, n) => {
+                match self.perform(op, n) {
+                    Ok(update) => results.extend(update),
+                    Err(e) => results.push(Update::Failed {
+                        core: n,
+                        message: format!("{} failed: {:#}", op.name(), e),
+                    }),
+                }
+                results.push(self.poll(n));
+            }
+        }
+        let stats = self.dap.lock().stats();
+        results.push(Update::Activity { action, stats });
+        for update in results {
+            updates.send(update)?;
+        }
+        Ok(())
+    }
+}
+
+// actionsが閉じられるまで依頼を処理し、暇な時はcoreの状態を読む
+pub fn serve<T: DapInterface>(
+    dap: DapHandle<DAP<T>>,
+    slots: &[CoreSlot],
+    actions: Receiver<Action>,
+    updates: Sender<Update>,
+) {
+    dap.lock().set_collect_stats(true);
+    let worker = Worker { dap, slots };
+    loop {
+        let action = match actions.recv_timeout(POLL_INTERVAL) {
+            Ok(action) => action,
+            Err(RecvTimeoutError::Timeout) => Action::Poll,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        // UIが先に終わった
+        if worker.handle(action, &updates).is_err() {
+            return;
+        }
+    }
+}
+
+fn state_span(view: &CoreView) -> Span<'static> {
+    let (text, color) = match view.state {
+        RunState::Unknown => ("unknown".to_string(), Color::DarkGray),
+        RunState::PoweredDown => ("powered down".to_string(), Color::DarkGray),
+        RunState::Running => ("running".to_string(), Color::Green),
+        RunState::Halted { el } => (format!("halted  EL{}", el), Color::Yellow),
+    };
+    Span::styled(text, Style::default().fg(color))
+}
+
+fn draw(frame: &mut Frame, dashboard: &Dashboard) {
+    let [cores_area, main_area, help_area] = Layout::vertical([
+        Constraint::Length(dashboard.cores.len() as u16 + 2),
+        Constraint::Min(0),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [registers_area, log_area] =
+        Layout::horizontal([Constraint::Length(48), Constraint::Min(0)]).areas(main_area);
+
+    let cores: Vec<Line> = dashboard
+        .cores
+        .iter()
+        .enumerate()
+        .map(|(n, view)| {
+            let cti = match view.slot.cti {
+                Some(cti) => format!("{:#010x}", cti),
+                None => "-".to_string(),
+            };
+            let mut spans = vec![
+                Span::raw(if n == dashboard.selected { "> " } else { "  " }),
+                Span::raw(format!(
+                    "core {}  debug {:#010x}  cti {:<10}  ",
+                    n, view.slot.debug, cti
+                )),
+                state_span(view),
+            ];
+            if let Some(error) = view.error.as_ref().or(view.poll_error.as_ref()) {
+                spans.push(Span::styled(
+                    format!("  {}", error),
+                    Style::default().fg(Color::Red),
+                ));
+            }
+            let line = Line::from(spans);
+            if n == dashboard.selected {
+                line.style(Style::default().add_modifier(Modifier::BOLD))
+            } else {
+                line
+            }
+        })
+        .collect();
+    frame.render_widget(
+        Paragraph::new(cores).block(Block::bordered().title("cores")),
+        cores_area,
+    );
+
+    let view = &dashboard.cores[dashboard.selected];
+    let registers = match &view.context {
+        Some(ctx) => {
+            let mut lines: Vec<Line> = (0..16)
+                .map(|i| {
+                    let right = match i + 16 {
+                        31 => format!("sp  {:016x}", ctx.sp),
+                        n => format!("x{:<2} {:016x}", n, ctx.x[n]),
+                    };
+                    Line::raw(format!("x{:<2} {:016x}  {}", i, ctx.x[i], right))
+                })
+                .collect();
+            lines.push(Line::raw(format!(
+                "pc  {:016x}  cpsr {:08x}",
+                ctx.pc, ctx.pstate
+            )));
+            lines
+        }
+        None => vec![Line::raw("press g while the core is halted")],
+    };
+    let title = match (&view.context, view.state) {
+        (Some(_), RunState::Halted { .. }) | (None, _) => {
+            format!("registers (core {})", dashboard.selected)
+        }
+        (Some(_), _) => format!("registers (core {}, stale)", dashboard.selected),
+    };
+    frame.render_widget(
+        Paragraph::new(registers).block(Block::bordered().title(title)),
+        registers_area,
+    );
+
+    // 新しい行が下に来るように、入る分だけ末尾から出す
+    let rows = log_area.height.saturating_sub(2) as usize;
+    let skip = dashboard.log.len().saturating_sub(rows);
+    let log: Vec<Line> = dashboard
+        .log
+        .iter()
+        .skip(skip)
+        .map(|x| Line::raw(x.as_str()))
+        .collect();
+    let title = format!(
+        "JTAG activity ({} polls, {} transactions)",
+        dashboard.polls, dashboard.poll_transactions
+    );
+    frame.render_widget(
+        Paragraph::new(log).block(Block::bordered().title(title)),
+        log_area,
+    );
+
+    // 選択中のcoreで使えない操作は灰色にする
+    let mut help = Vec::new();
+    for op in Op::ALL.iter() {
+        let style = if dashboard.fatal.is_none() && dashboard.available(dashboard.selected, *op) {
+            Style::default()
+        } else {
+            Style::default().fg(Color::DarkGray)
+        };
+        help.push(Span::styled(format!("{} {}  ", op.key(), op.name()), style));
+    }
+    help.push(Span::raw("j/k select  q quit"));
+    frame.render_widget(Paragraph::new(Line::from(help)), help_area);
+}
+
+// workerはinterfaceを開いてserveを呼ぶ。interfaceはthreadを跨げないのでworkerの中で開く
+// workerがerrorで終わった場合は、画面を閉じた後にそのerrorを返す
+pub fn run<F>(slots: &[CoreSlot], worker: F) -> Result<()>
+where
+    F: FnOnce(Receiver<Action>, Sender<Update>) -> Result<()> + Send,
+{
+    let (action_tx, action_rx) = channel();
+    let (update_tx, update_rx) = channel();
+    let mut dashboard = Dashboard::new(slots);
+    // 画面が崩れるので、TUIの間はlogを出さない。errorはUpdate::Failedで表示する
+    let level = log::max_level();
+    log::set_max_level(log::LevelFilter::Off);
+    let result = std::thread::scope(|scope| -> Result<()> {
+        let handle = scope.spawn(move || {
+            let fatal = update_tx.clone();
+            let result = worker(action_rx, update_tx);
+            if let Err(e) = &result {
+                let _ = fatal.send(Update::Fatal(format!("{:#}", e)));
+            }
+            result
+        });
+        let mut terminal = ratatui::try_init()?;
+        let ui = (|| -> Result<()> {
+            loop {
+                while let Ok(update) = update_rx.try_recv() {
+                    dashboard.apply(update);
+                }
+                terminal.draw(|frame| draw(frame, &dashboard))?;
+                if !event::poll(INPUT_INTERVAL)? {
+                    continue;
+                }
+                let key = match event::read()? {
+                    Event::Key(key) if key.kind == KeyEventKind::Press => key.code,
+                    _ => continue,
+                };
+                let key = match key {
+                    KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                    KeyCode::Down => 'j',
+                    KeyCode::Up => 'k',
+                    KeyCode::Char(x) => x,
+                    _ => continue,
+                };
+                if let Some(action) = dashboard.on_key(key) {
+                    // workerが止まっていたら何もしない
+                    let _ = action_tx.send(action);
+                }
+            }
+        })();
+        ratatui::restore();
+        // actionsを閉じるとworkerが終わる
+        drop(action_tx);
+        let worker = handle
+            .join()
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("worker panicked")));
+        ui.and(worker)
+    });
+    log::set_max_level(level);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{SocSim, CTI, DEBUG};
+
+    // actionを送り、そのActivityが返るまでのUpdateを反映する
+    fn exchange(
+        dashboard: &mut Dashboard,
+        actions: &Sender<Action>,
+        updates: &Receiver<Update>,
+        action: Action,
+    ) {
+        actions.send(action).unwrap();
+        loop {
+            let update = updates.recv_timeout(Duration::from_secs(5)).unwrap();
+            let done = matches!(&update, Update::Activity { action: x, .. } if *x == action);
+            dashboard.apply(update);
+            if done {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn dashboard_test() {
+        let slots = slots(&[
+            CoreBase {
+                debug: DEBUG[0],
+                cti: CTI,
+            },
+            CoreBase {
+                debug: DEBUG[1],
+                cti: 0,
+            },
+        ]);
+        assert_eq!(None, slots[1].cti);
+        let (action_tx, action_rx) = channel();
+        let (update_tx, update_rx) = channel();
+        let worker_slots = slots.clone();
+        let worker = std::thread::spawn(move || {
+            let dap = DapHandle::new(DAP::new(SocSim::new()));
+            serve(dap, &worker_slots, action_rx, update_tx);
+        });
+
+        let mut dashboard = Dashboard::new(&slots);
+        exchange(&mut dashboard, &action_tx, &update_rx, Action::Poll);
+        assert_eq!(RunState::Halted { el: 2 }, dashboard.cores[0].state);
+        assert_eq!(RunState::Running, dashboard.cores[1].state);
+        assert_eq!(1, dashboard.polls);
+        assert!(dashboard.poll_transactions > 0);
+
+        // CTIが分からないcoreはhaltできない
+        assert_eq!(None, dashboard.on_key('1'));
+        assert!(!dashboard.available(1, Op::Halt));
+        assert_eq!(None, dashboard.on_key('h'));
+        // 走っているcoreのregisterは読まない
+        assert_eq!(None, dashboard.on_key('g'));
+        // workerも断る
+        exchange(
+            &mut dashboard,
+            &action_tx,
+            &update_rx,
+            Action::Core(Op::Halt, 1),
+        );
+        assert!(dashboard.cores[1].error.is_some());
+        assert!(dashboard.cores[1].error.as_ref().unwrap().contains("CTI"));
+
+        assert_eq!(None, dashboard.on_key('j'));
+        assert_eq!(0, dashboard.selected);
+        assert_eq!(None, dashboard.on_key('h'));
+        let action = dashboard.on_key('g').unwrap();
+        assert_eq!(Action::Core(Op::Registers, 0), action);
+        exchange(&mut dashboard, &action_tx, &update_rx, action);
+        let context = dashboard.cores[0].context.unwrap();
+        assert_eq!([0x1234; 31], context.x);
+
+        let action = dashboard.on_key('s').unwrap();
+        exchange(&mut dashboard, &action_tx, &update_rx, action);
+        assert_eq!(RunState::Halted { el: 2 }, dashboard.cores[0].state);
+        assert!(dashboard.log.iter().any(|x| x.contains("stepped to")));
+
+        let action = dashboard.on_key('r').unwrap();
+        exchange(&mut dashboard, &action_tx, &update_rx, action);
+        assert_eq!(RunState::Running, dashboard.cores[0].state);
+        assert!(dashboard
+            .log
+            .back()
+            .unwrap()
+            .starts_with("core 0 resume: dp"));
+        assert_eq!(None, dashboard.on_key('s'));
+
+        let action = dashboard.on_key('h').unwrap();
+        exchange(&mut dashboard, &action_tx, &update_rx, action);
+        assert_eq!(RunState::Halted { el: 2 }, dashboard.cores[0].state);
+        assert_eq!(None, dashboard.cores[0].error);
+
+        drop(action_tx);
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn fatal_test() {
+        let mut dashboard = Dashboard::new(&[CoreSlot {
+            debug: DEBUG[0],
+            cti: Some(CTI),
+        }]);
+        dashboard.apply(Update::State {
+            core: 0,
+            state: RunState::Running,
+        });
+        assert!(dashboard.on_key('h').is_some());
+        dashboard.apply(Update::Fatal("no device".to_string()));
+        assert_eq!(None, dashboard.on_key('h'));
+        assert_eq!("worker stopped: no device", dashboard.log.back().unwrap());
+    }
+}
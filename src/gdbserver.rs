@@ -0,0 +1,601 @@
+// GDB Remote Serial Protocolのサーバ
+// gdb -ex 'target remote :3333' で接続する
+use anyhow::Result;
+use log::{debug, info, warn};
+use std::convert::TryInto;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use libjtag::error::DebugError;
+use libjtag::jtag::dap::*;
+use libjtag::target::arm64::*;
+use libjtag::target::history::DEFAULT_HISTORY_DEPTH;
+use libjtag::target::router::{AddrSpace, MemoryRouter};
+
+const PACKET_SIZE: usize = 0x1000;
+// haltを待つ間に割り込み(0x03)を確認する間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const INTERRUPT: u8 = 0x03;
+const SIGTRAP: &str = "S05";
+// x0-x30, sp, pc
+const GPR_COUNT: usize = 31;
+const REG_COUNT: usize = GPR_COUNT + 2;
+const REG_SP: usize = 31;
+const REG_PC: usize = 32;
+const REG_CPSR: usize = 33;
+const BREAKPOINT_MAX: usize = 6;
+
+// resumeの間だけread timeoutを付けて割り込みを待つ
+trait Connection: Read + Write {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+enum Packet {
+    Command(String),
+    Interrupt,
+    Closed,
+}
+
+pub struct GdbServer<T, M> {
+    core: CoreHandle<T>,
+    // system memoryとcoreのvirtual addressの読み書き
+    router: MemoryRouter<M>,
+    breakpoints: [Option<u64>; BREAKPOINT_MAX],
+}
+
+impl<T, M> GdbServer<T, M>
+where
+    T: DebugPort + MemoryAccessPort,
+    M: MemoryAccessPort,
+{
+    // monitor diffのために、止まる度にregisterを記録する
+    pub fn new(mut core: CoreHandle<T>, memory: DapHandle<M>) -> Self {
+        core.enable_history(DEFAULT_HISTORY_DEPTH);
+        GdbServer {
+            core,
+            router: MemoryRouter::new(memory),
+            breakpoints: [None; BREAKPOINT_MAX],
+        }
+    }
+
+    pub fn listen(&mut self, port: u16) -> Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        info!("waiting for gdb on port {}", port);
+        for stream in listener.incoming() {
+            let stream = stream?;
+            info!("gdb connected from {}", stream.peer_addr()?);
+            match self.session(stream) {
+                Ok(()) => info!("gdb disconnected"),
+                Err(e) => warn!("gdb session closed: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    fn session(&mut self, mut stream: TcpStream) -> Result<()> {
+        self.halt()?;
+        // 接続時に既にhaltしていた場合も、最初の差の基準にする
+        if self.core.history(1).is_empty() {
+            self.core.record_halt()?;
+        }
+        loop {
+            let packet = match read_packet(&mut stream)? {
+                Packet::Command(x) => x,
+                // 停止中の割り込みは無視する
+                Packet::Interrupt => continue,
+                Packet::Closed => return Ok(()),
+            };
+            debug!("gdb <- {}", packet);
+            let reply = match self.handle(&packet, &mut stream) {
+                Ok(Some(x)) => x,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    warn!("failed to handle {}: {}", packet, e);
+                    "E01".to_string()
+                }
+            };
+            debug!("gdb -> {}", reply);
+            stream.write_all(&encode_packet(&reply))?;
+        }
+    }
+
+    // Noneを返すとsessionを終了する
+    fn handle<S: Connection>(&mut self, packet: &str, stream: &mut S) -> Result<Option<String>> {
+        let command = match packet.chars().next() {
+            Some(x) => x,
+            None => return Ok(Some(String::new())),
+        };
+        let args = &packet[command.len_utf8()..];
+        let reply = match command {
+            '?' => SIGTRAP.to_string(),
+            'g' => self.registers_read()?,
+            'G' => {
+                self.registers_write(&hex_decode(args)?)?;
+                "OK".to_string()
+            }
+            'p' => {
+                let n = usize::from_str_radix(args, 16)?;
+                hex_encode(&self.register_read(n)?)
+            }
+            'P' => {
+                let (n, value) = args.split_once('=').ok_or(ParseError)?;
+                let n = usize::from_str_radix(n, 16)?;
+                self.register_write(n, &hex_decode(value)?)?;
+                "OK".to_string()
+            }
+            'm' => {
+                let (address, length) = parse_address_length(args)?;
+                hex_encode(&self.memory_read(address, length)?)
+            }
+            'M' => {
+                let (range, data) = args.split_once(':').ok_or(ParseError)?;
+                let (address, _) = parse_address_length(range)?;
+                self.memory_write(address, &hex_decode(data)?)?;
+                "OK".to_string()
+            }
+            'c' => self.resume(stream)?,
+            's' => self.step()?,
+            'Z' | 'z' => self.breakpoint(command == 'Z', args)?,
+            'H' => "OK".to_string(),
+            'D' => {
+                self.router.invalidate();
+                self.core.cti.restart_core()?;
+                stream.write_all(&encode_packet("OK"))?;
+                return Ok(None);
+            }
+            'k' => return Ok(None),
+            'q' if args.starts_with("Supported") => format!("PacketSize={:x}", PACKET_SIZE),
+            'q' if args == "Attached" => "1".to_string(),
+            'q' if args.starts_with("Rcmd,") => self.monitor(&hex_decode(&args[5..])?)?,
+            // 未対応のpacketには空で返す
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn halt(&mut self) -> Result<(), DebugError> {
+        self.core.halt()
+    }
+
+    fn resume<S: Connection>(&mut self, stream: &mut S) -> Result<String> {
+        self.router.invalidate();
+        self.core.cti.restart_core()?;
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        let result = self.wait_halt(stream);
+        stream.set_read_timeout(None)?;
+        result?;
+        Ok(SIGTRAP.to_string())
+    }
+
+    fn wait_halt<S: Connection>(&mut self, stream: &mut S) -> Result<()> {
+        let mut buffer = [0; 1];
+        while !self.core.target.halted()? {
+            match stream.read(&mut buffer) {
+                Ok(0) => return Err(anyhow::anyhow!("connection closed while running")),
+                Ok(_) if buffer[0] == INTERRUPT => {
+                    info!("interrupted by gdb");
+                    // haltが記録する
+                    return Ok(self.halt()?);
+                }
+                Ok(_) => (),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.core.record_halt()?;
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<String> {
+        self.router.invalidate();
+        let pc = self.core.step()?;
+        debug!("stepped to {:#x}", pc);
+        Ok(SIGTRAP.to_string())
+    }
+
+    // qRcmd。出力をhexで返す。知らないcommandには空で返し、gdbに未対応と表示させる
+    fn monitor(&mut self, command: &[u8]) -> Result<String> {
+        let output = match std::str::from_utf8(command)?.trim() {
+            "diff" => match self.core.last_diff() {
+                Some(diff) => diff.to_string(),
+                None => "no previous halt recorded\n".to_string(),
+            },
+            _ => return Ok(String::new()),
+        };
+        Ok(hex_encode(output.as_bytes()))
+    }
+
+    fn breakpoint(&mut self, insert: bool, args: &str) -> Result<String> {
+        let mut fields = args.split(',');
+        // hardware breakpointに割り当てるので0と1だけを受け付ける
+        if !matches!(fields.next(), Some("0") | Some("1")) {
+            return Ok(String::new());
+        }
+        let address = u64::from_str_radix(fields.next().ok_or(ParseError)?, 16)?;
+        if insert {
+            let n = match self.breakpoints.iter().position(|x| x.is_none()) {
+                Some(n) => n,
+                None => return Ok("E0E".to_string()),
+            };
+            self.core.target.set_breakpoint(n, address)?;
+            self.breakpoints[n] = Some(address);
+        } else if let Some(n) = self.breakpoints.iter().position(|x| *x == Some(address)) {
+            self.core.target.clear_breakpoint(n)?;
+            self.breakpoints[n] = None;
+        }
+        Ok("OK".to_string())
+    }
+
+    fn registers_read(&mut self) -> Result<String> {
+        let mut data = Vec::new();
+        for n in 0..GPR_COUNT {
+            data.extend_from_slice(&self.core.target.read_gpr(n as u8)?.to_le_bytes());
+        }
+        data.extend_from_slice(&self.core.target.read_gpr(REG_SP as u8)?.to_le_bytes());
+        data.extend_from_slice(&self.core.target.read_pc()?.to_le_bytes());
+        data.extend_from_slice(&self.core.target.read_cpsr()?.to_le_bytes());
+        Ok(hex_encode(&data))
+    }
+
+    fn registers_write(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() < REG_COUNT * 8 + 4 {
+            return Err(ParseError.into());
+        }
+        let value = |n: usize| u64::from_le_bytes(data[n * 8..n * 8 + 8].try_into().unwrap());
+        for n in 0..GPR_COUNT {
+            self.core.target.write_gpr(n as u8, value(n))?;
+        }
+        self.core.target.write_gpr(REG_SP as u8, value(REG_SP))?;
+        self.core.target.write_pc(value(REG_PC))?;
+        let cpsr = &data[REG_COUNT * 8..REG_COUNT * 8 + 4];
+        self.core
+            .target
+            .write_cpsr(u32::from_le_bytes(cpsr.try_into().unwrap()))?;
+        Ok(())
+    }
+
+    fn register_read(&mut self, n: usize) -> Result<Vec<u8>> {
+        let value = match n {
+            n if n <= REG_SP => self.core.target.read_gpr(n as u8)?,
+            REG_PC => self.core.target.read_pc()?,
+            REG_CPSR => return Ok(self.core.target.read_cpsr()?.to_le_bytes().to_vec()),
+            _ => return Err(ParseError.into()),
+        };
+        Ok(value.to_le_bytes().to_vec())
+    }
+
+    fn register_write(&mut self, n: usize, data: &[u8]) -> Result<()> {
+        let mut buffer = [0; 8];
+        let length = data.len().min(8);
+        buffer[..length].copy_from_slice(&data[..length]);
+        let value = u64::from_le_bytes(buffer);
+        match n {
+            n if n <= REG_SP => self.core.target.write_gpr(n as u8, value)?,
+            REG_PC => self.core.target.write_pc(value)?,
+            REG_CPSR => self.core.target.write_cpsr(value as u32)?,
+            _ => return Err(ParseError.into()),
+        }
+        Ok(())
+    }
+
+    // gdbのaddressはcoreから見たvirtual address。経路はMemoryRouterが選ぶ
+    // packetに入りきらない長さとaddress空間の末尾を越える範囲はerrorにする
+    fn memory_read(&mut self, address: u64, length: usize) -> Result<Vec<u8>> {
+        if length > PACKET_SIZE {
+            return Err(ParseError.into());
+        }
+        address.checked_add(length as u64).ok_or(ParseError)?;
+        let mut bytes = vec![0; length];
+        let path = self
+            .router
+            .read(&mut self.core, AddrSpace::Virtual(address), &mut bytes)?;
+        debug!("read {:#x}+{:#x} via {:?}", address, length, path);
+        Ok(bytes)
+    }
+
+    fn memory_write(&mut self, address: u64, data: &[u8]) -> Result<()> {
+        address.checked_add(data.len() as u64).ok_or(ParseError)?;
+        let path = self
+            .router
+            .write(&mut self.core, AddrSpace::Virtual(address), data)?;
+        debug!("wrote {:#x}+{:#x} via {:?}", address, data.len(), path);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ParseError;
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "malformed packet")
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |x, y| x.wrapping_add(*y))
+}
+
+fn encode_packet(data: &str) -> Vec<u8> {
+    format!("${}#{:02x}", data, checksum(data.as_bytes())).into_bytes()
+}
+
+fn read_byte(stream: &mut impl Read) -> Result<Option<u8>> {
+    let mut buffer = [0; 1];
+    match stream.read(&mut buffer)? {
+        0 => Ok(None),
+        _ => Ok(Some(buffer[0])),
+    }
+}
+
+fn read_packet<S: Read + Write>(stream: &mut S) -> Result<Packet> {
+    loop {
+        match read_byte(stream)? {
+            None => return Ok(Packet::Closed),
+            Some(b'$') => (),
+            Some(INTERRUPT) => return Ok(Packet::Interrupt),
+            // +/-などのack
+            Some(_) => continue,
+        }
+        let mut data = Vec::new();
+        loop {
+            match read_byte(stream)? {
+                None => return Ok(Packet::Closed),
+                Some(b'#') => break,
+                Some(x) => data.push(x),
+            }
+        }
+        let mut sum = [0; 2];
+        stream.read_exact(&mut sum)?;
+        let expected = u8::from_str_radix(std::str::from_utf8(&sum)?, 16)?;
+        if expected != checksum(&data) {
+            warn!("checksum mismatch");
+            stream.write_all(b"-")?;
+            continue;
+        }
+        stream.write_all(b"+")?;
+        return Ok(Packet::Command(String::from_utf8(data)?));
+    }
+}
+
+fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+// byte単位で見るので、ASCIIでない文字もerrorになる
+fn hex_decode(data: &str) -> Result<Vec<u8>> {
+    if !data.len().is_multiple_of(2) {
+        return Err(ParseError.into());
+    }
+    let digit = |x: u8| (x as char).to_digit(16).ok_or(ParseError);
+    data.as_bytes()
+        .chunks(2)
+        .map(|x| Ok((digit(x[0])? << 4 | digit(x[1])?) as u8))
+        .collect()
+}
+
+// "addr,length"
+fn parse_address_length(data: &str) -> Result<(u64, usize)> {
+    let (address, length) = data.split_once(',').ok_or(ParseError)?;
+    Ok((
+        u64::from_str_radix(address, 16)?,
+        usize::from_str_radix(length, 16)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{SocSim, CTI, DEBUG};
+    use std::io::Cursor;
+
+    // 読み込みはCursorから、書き込みはVecへ
+    struct Loopback {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Loopback {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Loopback {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Loopback {
+        fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn loopback() -> Loopback {
+        Loopback {
+            input: Cursor::new(Vec::new()),
+            output: Vec::new(),
+        }
+    }
+
+    // core 0とsystem memoryが同じMEM-APの先にある
+    fn server() -> GdbServer<DAP<SocSim>, DAP<SocSim>> {
+        let dap = DapHandle::new(DAP::new(SocSim::new()));
+        let soc = Arm64Soc::new(
+            dap.clone(),
+            &[CoreBase {
+                debug: DEBUG[0],
+                cti: CTI,
+            }],
+        );
+        GdbServer::new(soc.core(0), dap)
+    }
+
+    // errorはsessionでE01になる
+    fn reply(server: &mut GdbServer<DAP<SocSim>, DAP<SocSim>>, packet: &str) -> Option<String> {
+        server
+            .handle(packet, &mut loopback())
+            .unwrap_or(Some("E01".to_string()))
+    }
+
+    #[test]
+    fn encode_packet_test() {
+        assert_eq!(b"$OK#9a".to_vec(), encode_packet("OK"));
+        assert_eq!(b"$#00".to_vec(), encode_packet(""));
+    }
+
+    #[test]
+    fn read_packet_test() {
+        let mut stream = Loopback {
+            input: Cursor::new(b"+$m1000,4#8e$g#00\x03".to_vec()),
+            output: Vec::new(),
+        };
+        assert!(matches!(read_packet(&mut stream).unwrap(), Packet::Command(x) if x == "m1000,4"));
+        // checksumが合わないpacketは読み飛ばされる
+        assert!(matches!(
+            read_packet(&mut stream).unwrap(),
+            Packet::Interrupt
+        ));
+        assert!(matches!(read_packet(&mut stream).unwrap(), Packet::Closed));
+        assert_eq!(b"+-".to_vec(), stream.output);
+    }
+
+    #[test]
+    fn hex_test() {
+        assert_eq!("00ff10", hex_encode(&[0x00, 0xff, 0x10]));
+        assert_eq!(vec![0x00, 0xff, 0x10], hex_decode("00ff10").unwrap());
+        assert!(hex_decode("0").is_err());
+        assert_eq!(
+            (0x4008_0000, 0x10),
+            parse_address_length("40080000,10").unwrap()
+        );
+    }
+
+    #[test]
+    fn malformed_packet_test() {
+        let mut server = server();
+        // commandの無いpacketと未対応の文字には空で返す
+        assert_eq!(Some(String::new()), reply(&mut server, ""));
+        assert_eq!(Some(String::new()), reply(&mut server, "\u{e9}1000,4"));
+        assert_eq!(Some("E01".to_string()), reply(&mut server, "G0"));
+        assert_eq!(
+            Some("E01".to_string()),
+            reply(&mut server, "M1000,2:\u{e9}")
+        );
+        assert_eq!(Some("E01".to_string()), reply(&mut server, "M1000,1:+f"));
+        // address空間の末尾を越える
+        assert_eq!(
+            Some("E01".to_string()),
+            reply(&mut server, "mfffffffffffffffe,4")
+        );
+        assert_eq!(Some("E01".to_string()), reply(&mut server, "m0,100000"));
+    }
+
+    #[test]
+    fn registers_test() {
+        let mut server = server();
+        let registers = reply(&mut server, "g").unwrap();
+        assert_eq!((REG_COUNT * 8 + 4) * 2, registers.len());
+        assert_eq!("3412000000000000", &registers[..16]);
+
+        let data: Vec<u8> = (0..REG_COUNT * 8 + 4).map(|x| x as u8).collect();
+        assert_eq!(
+            Some("OK".to_string()),
+            reply(&mut server, &format!("G{}", hex_encode(&data)))
+        );
+        assert_eq!(Some(hex_encode(&data)), reply(&mut server, "g"));
+        // 足りない
+        assert_eq!(Some("E01".to_string()), reply(&mut server, "G00"));
+    }
+
+    #[test]
+    fn memory_test() {
+        let mut server = server();
+        assert_eq!(Some("00000000".to_string()), reply(&mut server, "m1000,4"));
+        // wordをまたぐ書き込みは前後を残す
+        assert_eq!(
+            Some("OK".to_string()),
+            reply(&mut server, "M1002,4:a1b2c3d4")
+        );
+        assert_eq!(Some("OK".to_string()), reply(&mut server, "M1000,1:ff"));
+        assert_eq!(
+            Some("ff00a1b2c3d40000".to_string()),
+            reply(&mut server, "m1000,8")
+        );
+        assert_eq!(Some("b2c3".to_string()), reply(&mut server, "m1003,2"));
+    }
+
+    #[test]
+    fn breakpoint_test() {
+        let mut server = server();
+        let dbgbvr = DEBUG[0] + Armv8DebugRegisterOffset::DBGBVR_BASE_EL1 as u64;
+        let dbgbcr = DEBUG[0] + Armv8DebugRegisterOffset::DBGBCR_BASE_EL1 as u64;
+        assert_eq!(Some("OK".to_string()), reply(&mut server, "Z1,40080000,4"));
+        assert_eq!(
+            Some("00000840".to_string()),
+            reply(&mut server, &format!("m{:x},4", dbgbvr))
+        );
+        let enabled = reply(&mut server, &format!("m{:x},4", dbgbcr)).unwrap();
+        assert_eq!(
+            Some(1),
+            hex_decode(&enabled).unwrap().first().map(|x| x & 1)
+        );
+
+        assert_eq!(Some("OK".to_string()), reply(&mut server, "z1,40080000,4"));
+        assert_eq!(
+            Some("00000000".to_string()),
+            reply(&mut server, &format!("m{:x},4", dbgbcr))
+        );
+        // software breakpointとwatchpointは未対応
+        assert_eq!(Some(String::new()), reply(&mut server, "Z2,40080000,4"));
+        // 空きが無くなるとE0E
+        for i in 0..BREAKPOINT_MAX {
+            let packet = format!("Z1,{:x},4", 0x4008_0000 + i * 4);
+            assert_eq!(Some("OK".to_string()), reply(&mut server, &packet));
+        }
+        assert_eq!(Some("E0E".to_string()), reply(&mut server, "Z1,40090000,4"));
+    }
+
+    #[test]
+    fn monitor_diff_test() {
+        let mut server = server();
+        let diff = hex_encode(b"diff");
+        let monitor = |server: &mut GdbServer<DAP<SocSim>, DAP<SocSim>>, packet: &str| {
+            let reply = reply(server, packet).unwrap();
+            String::from_utf8(hex_decode(&reply).unwrap()).unwrap()
+        };
+        assert_eq!(
+            "no previous halt recorded\n",
+            monitor(&mut server, &format!("qRcmd,{}", diff))
+        );
+        assert_eq!(Some(SIGTRAP.to_string()), reply(&mut server, "s"));
+        assert_eq!(
+            Some("OK".to_string()),
+            reply(&mut server, "P3=0100000000000000")
+        );
+        assert_eq!(Some(SIGTRAP.to_string()), reply(&mut server, "s"));
+        assert_eq!(
+            "halt #1 -> #2 (ExternalDebugRequest)\n  x3     0x0000000000001234 -> 0x0000000000000001\n",
+            monitor(&mut server, &format!("qRcmd,{}", diff))
+        );
+        // 知らないcommand
+        assert_eq!(
+            Some(String::new()),
+            reply(&mut server, &format!("qRcmd,{}", hex_encode(b"reset")))
+        );
+    }
+}
@@ -1,18 +1,40 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use bingen::bingen;
 use chrono;
 use log::{debug, error, info, trace, warn};
 use spin::mutex::Mutex;
+use std::fs;
+use std::time::Instant;
 
 extern crate libjtag;
 
-use libjtag::interface::ftdi_bitbang::FtdiBitBang;
-use libjtag::interface::ftdi_mpsse::FtdiMpsse;
+mod cli;
+#[cfg(feature = "tui")]
+mod dashboard;
+mod gdbserver;
+mod monitor;
+#[cfg(feature = "script")]
+mod script;
+#[cfg(test)]
+mod sim;
+
+use libjtag::interface::ftdi;
+use libjtag::interface::ftdi_builder::create_interface;
+use libjtag::interface::JtagInterface;
 use libjtag::jtag::dap::*;
-use libjtag::jtag::jtag::{Jtag, TAP};
+use libjtag::jtag::idcode::TapDevice;
+use libjtag::jtag::jtag::{Jtag, ScanResult, TAP};
+use libjtag::jtag::manufacturer::ManufacturerNames;
+use libjtag::jtag::svf::{SvfPlayer, SvfProgress};
 use libjtag::target::arm64::*;
+use libjtag::tools::memfile;
+use libjtag::tools::snapshot::DebugSnapshot;
+
+use cli::{check_ack, Command, Options, WatchTarget, WriteData};
+use gdbserver::GdbServer;
+use monitor::{Monitor, WatchRead};
 
-fn setup_logger() -> Result<(), fern::InitError> {
+fn setup_logger(verbose: bool) -> Result<(), fern::InitError> {
     fern::Dispatch::new()
         .format(|out, message, record| {
             out.finish(format_args!(
@@ -23,8 +45,13 @@ fn setup_logger() -> Result<(), fern::InitError> {
                 message
             ))
         })
-        .level(log::LevelFilter::Debug)
-        .chain(std::io::stdout())
+        .level(if verbose {
+            log::LevelFilter::Debug
+        } else {
+            log::LevelFilter::Warn
+        })
+        // 標準出力はcommandの結果に使う
+        .chain(std::io::stderr())
         .filter(|meta| {
             !meta.target().contains("jtag_state_machine")
                 && !meta.target().contains("dap")
@@ -34,9 +61,357 @@ fn setup_logger() -> Result<(), fern::InitError> {
     Ok(())
 }
 
-#[inline(never)]
-fn main() -> Result<()> {
-    setup_logger().unwrap();
+fn open(options: &Options) -> Result<Box<dyn JtagInterface>> {
+    create_interface(&options.adapter(), options.probe.clone()).map_err(cli::no_device)
+}
+
+fn probes() -> Result<()> {
+    let probes = ftdi::list_devices();
+    if probes.is_empty() {
+        println!("no FTDI adapters found");
+    }
+    for (i, probe) in probes.iter().enumerate() {
+        println!("{}: {}", i, probe);
+    }
+    Ok(())
+}
+
+fn scan(result: &ScanResult, names: &dyn ManufacturerNames) -> Result<()> {
+    for (i, device) in result.devices().iter().enumerate() {
+        match device {
+            TapDevice::IdCode(idcode) => println!("{}: {}", i, idcode.display(names)),
+            TapDevice::Bypass => println!("{}: BYPASS", i),
+        }
+    }
+    Ok(())
+}
+
+// initializeでscanした後にもう一度IDCODEを読み、linkが生きているか確かめる
+fn ping<I: JtagInterface>(jtag: &mut Jtag<I>) -> Result<()> {
+    let start = Instant::now();
+    let idcode = check_idcode(jtag)?;
+    println!(
+        "IDCODE {:#010x}: alive ({} us)",
+        idcode,
+        start.elapsed().as_micros()
+    );
+    Ok(())
+}
+
+// stdinは読んだ分から流すので、SVFの全体を待たない
+fn svf_play<I: JtagInterface>(jtag: &mut Jtag<I>, input: &str) -> Result<()> {
+    let mut player = SvfPlayer::new(jtag);
+    // 進捗は0.1秒毎に同じ行を書き換える
+    let mut shown: Option<Instant> = None;
+    let mut progress = |x: &SvfProgress| {
+        if shown.is_none_or(|t| t.elapsed().as_millis() >= 100) {
+            cli::svf_progress(x);
+            shown = Some(Instant::now());
+        }
+    };
+    let result = if input == "-" {
+        player.play_with_progress(std::io::stdin().lock(), &mut progress)
+    } else {
+        let file = fs::File::open(input).with_context(|| format!("failed to open {}", input))?;
+        player.play_with_progress(std::io::BufReader::new(file), &mut progress)
+    };
+    if shown.is_some() {
+        eprintln!();
+    }
+    let count = result?;
+    println!("played {} SVF commands", count);
+    Ok(())
+}
+
+fn dap_info<T: DapInterface>(dap: &mut DAP<T>, apnum: u8) -> Result<()> {
+    let dpidr = dap.dpidr();
+    println!(
+        "DPIDR: {:#010x} (DPv{}, designer {:#05x}, partno {:#04x}, revision {})",
+        dpidr.0,
+        dpidr.VERSION(),
+        dpidr.DESIGNER(),
+        dpidr.PARTNO(),
+        dpidr.REVISION()
+    );
+    if dap.dp_version() >= DpBank::TargetId.min_version() {
+        println!("{}", dap.dp_targetid_read()?);
+    }
+    dap.select_ap(apnum);
+    let (ack, idr) = dap.memap_idr_read()?;
+    check_ack(ack)?;
+    println!("AP{} IDR: {:#010x}", apnum, idr);
+    let (ack, base) = dap.memap_base_u64_read()?;
+    check_ack(ack)?;
+    println!("AP{} BASE: {:#018x}", apnum, base);
+    Ok(())
+}
+
+fn read_mem<T: DapInterface>(
+    memory: &mut DAP<T>,
+    addr: u64,
+    len: usize,
+    out: &Option<String>,
+) -> Result<()> {
+    let view = memory.try_mem_read_view(addr, len)?;
+    match out {
+        Some(path) => {
+            fs::write(path, view.bytes()).with_context(|| format!("failed to write {}", path))?
+        }
+        None => print!("{}", view.hexdump()),
+    }
+    Ok(())
+}
+
+fn write_mem<T: DapInterface>(memory: &mut DAP<T>, addr: u64, data: &WriteData) -> Result<()> {
+    match data {
+        WriteData::Value(value) => check_ack(memory.mem_write_u32(addr, *value)?),
+        WriteData::File(path) => {
+            let data = fs::read(path).with_context(|| format!("failed to read {}", path))?;
+            // 4byteに満たない末尾は0で埋める
+            let words: Vec<u32> = data
+                .chunks(4)
+                .map(|x| {
+                    let mut word = [0; 4];
+                    word[..x.len()].copy_from_slice(x);
+                    u32::from_le_bytes(word)
+                })
+                .collect();
+            check_ack(memory.mem_write_block(addr, &words)?)
+        }
+    }
+}
 
+fn fill<T: DapInterface>(memory: &mut DAP<T>, addr: u64, len: usize, pattern: u32) -> Result<()> {
+    memory.mem_fill(addr, len as u64, pattern)?;
+    println!("filled {} bytes with {:#010x}", len, pattern);
     Ok(())
 }
+
+fn verify<T: DapInterface>(memory: &mut DAP<T>, addr: u64, input: &str) -> Result<()> {
+    let data = fs::read(input).with_context(|| format!("failed to read {}", input))?;
+    if let Some(mismatch) = memory.mem_compare(addr, &data)? {
+        bail!(
+            "mismatch at {:#x}: expected {:#04x}",
+            mismatch,
+            data[(mismatch - addr) as usize]
+        );
+    }
+    println!("verified {} bytes", data.len());
+    Ok(())
+}
+
+fn report_snapshot<T: DapInterface>(
+    dap: &mut DAP<T>,
+    result: &ScanResult,
+    options: &Options,
+    out: &str,
+) -> Result<()> {
+    let mut snapshot = DebugSnapshot::capture(dap, options.apnum, &options.cores)?;
+    snapshot.add_idcodes(result.devices());
+    fs::write(out, snapshot.to_json()).with_context(|| format!("failed to write {}", out))?;
+    println!("saved {} registers to {}", snapshot.registers.len(), out);
+    Ok(())
+}
+
+fn report_diff(old: &str, new: &str, out: &Option<String>) -> Result<()> {
+    let load = |path: &str| -> Result<DebugSnapshot> {
+        let json = fs::read_to_string(path).with_context(|| format!("failed to read {}", path))?;
+        DebugSnapshot::from_json(&json).with_context(|| format!("failed to parse {}", path))
+    };
+    let diff = load(old)?.diff(&load(new)?);
+    println!("{}", diff);
+    if let Some(out) = out {
+        fs::write(out, diff.to_json()).with_context(|| format!("failed to write {}", out))?;
+    }
+    Ok(())
+}
+
+fn watch<T: WatchRead>(monitor: &mut Monitor<T>, name: &str, mask: u32) -> Result<()> {
+    let start = Instant::now();
+    println!("watching {} (mask {:#010x}), Ctrl-C to stop", name, mask);
+    monitor.run(|event| {
+        println!(
+            "[{:>10.3}s] {:#x}: {:#010x} -> {:#010x}",
+            event.timestamp.duration_since(start).as_secs_f64(),
+            event.addr,
+            event.old,
+            event.new
+        );
+    })?;
+    Ok(())
+}
+
+// USBのinterfaceはthreadを跨げないので、dashboardのworkerの中で開く
+#[cfg(feature = "tui")]
+fn tui(options: &Options) -> Result<()> {
+    let slots = dashboard::slots(&options.cores);
+    dashboard::run(&slots, |actions, updates| {
+        let mut jtag = Jtag::new(open(options)?);
+        jtag.initialize()?;
+        let jtag = Mutex::new(jtag);
+        let dap = DAP::try_new(TAP::new_with_config(&jtag, &options.chain)?)?;
+        dashboard::serve(DapHandle::new(dap), &slots, actions, updates);
+        Ok(())
+    })
+}
+
+#[cfg(not(feature = "tui"))]
+fn tui(_options: &Options) -> Result<()> {
+    bail!("jtag_test was built without the tui feature (cargo run --features tui)")
+}
+
+// scriptに渡すobjectは'staticでないといけないので、jtagはprocessが終わるまで残す
+#[cfg(feature = "script")]
+fn script<I: JtagInterface + 'static>(jtag: Jtag<I>, options: &Options) -> Result<()> {
+    let jtag: &'static Mutex<Jtag<I>> = Box::leak(Box::new(Mutex::new(jtag)));
+    let mut dap = DAP::try_new(TAP::new_with_config(jtag, &options.chain)?)?;
+    dap.set_write_guard(options.write_guard.clone());
+    let mut memory = DAP::try_new(TAP::new_with_config(jtag, &options.chain)?)?;
+    memory.select_ap(options.memory_apnum);
+    memory.set_write_guard(options.write_guard.clone());
+    let soc = Arm64Soc::new(DapHandle::new(dap), &options.cores);
+    let cores = (0..soc.cores()).map(|n| soc.core(n)).collect();
+    let (input, core) = match &options.command {
+        Command::RunScript { input, core } => (Some(input), *core),
+        Command::Repl { core } => (None, *core),
+        _ => unreachable!(),
+    };
+    let mut host = script::ScriptHost::new(
+        move || jtag.lock().scan(),
+        DapHandle::new(memory),
+        cores,
+        core,
+    );
+    match input {
+        Some(input) => script::run_file(&mut host, input),
+        None => script::repl(&mut host),
+    }
+}
+
+#[cfg(not(feature = "script"))]
+fn script<I: JtagInterface>(_jtag: Jtag<I>, _options: &Options) -> Result<()> {
+    bail!("jtag_test was built without the script feature (cargo run --features script)")
+}
+
+fn run<I: JtagInterface + 'static>(interface: I, options: &Options) -> Result<()> {
+    let mut jtag = Jtag::new(interface);
+    let result = jtag.initialize()?;
+    match &options.command {
+        Command::Scan => return scan(&result, jtag.manufacturer_names()),
+        Command::Ping => return ping(&mut jtag),
+        Command::SvfPlay { input } => return svf_play(&mut jtag, input),
+        Command::RunScript { .. } | Command::Repl { .. } => return script(jtag, options),
+        _ => (),
+    }
+    let jtag = Mutex::new(jtag);
+    let mut dap = DAP::try_new(TAP::new_with_config(&jtag, &options.chain)?)?;
+    dap.set_write_guard(options.write_guard.clone());
+    let memory = || -> Result<DAP<TAP<I>>> {
+        let mut memory = DAP::try_new(TAP::new_with_config(&jtag, &options.chain)?)?;
+        memory.select_ap(options.memory_apnum);
+        memory.set_write_guard(options.write_guard.clone());
+        Ok(memory)
+    };
+    match &options.command {
+        Command::Probes
+        | Command::Scan
+        | Command::Ping
+        | Command::Tui
+        | Command::SvfPlay { .. }
+        | Command::RunScript { .. }
+        | Command::Repl { .. }
+        | Command::ReportDiff { .. } => unreachable!(),
+        Command::DapInfo => dap_info(&mut dap, options.apnum),
+        Command::ReportSnapshot { out } => report_snapshot(&mut dap, &result, options, out),
+        Command::ReadMem { addr, len, out } => read_mem(&mut memory()?, *addr, *len, out),
+        Command::WriteMem { addr, data } => write_mem(&mut memory()?, *addr, data),
+        Command::Dump {
+            addr,
+            len,
+            out,
+            format,
+            record_len,
+        } => Ok(memfile::dump_memory_with_record_len(
+            &mut memory()?,
+            *addr,
+            *len,
+            out.as_ref(),
+            *format,
+            *record_len,
+            &mut cli::progress,
+        )?),
+        Command::Load { input, addr } => {
+            let written =
+                memfile::load_memory(&mut memory()?, input.as_ref(), *addr, &mut cli::progress)?;
+            println!("loaded and verified {} bytes", written);
+            Ok(())
+        }
+        Command::Fill { addr, len, pattern } => fill(&mut memory()?, *addr, *len, *pattern),
+        Command::Verify { addr, input } => verify(&mut memory()?, *addr, input),
+        Command::Watch {
+            target: WatchTarget::Addr(addr),
+            mask,
+            interval,
+        } => {
+            let mut monitor = Monitor::new(DapHandle::new(memory()?), *interval);
+            monitor.watch_addr(*addr, *mask);
+            watch(&mut monitor, &format!("{:#x}", addr), *mask)
+        }
+        Command::Watch {
+            target: WatchTarget::Register { core: n, offset },
+            mask,
+            interval,
+        } => {
+            let soc = Arm64Soc::new(DapHandle::new(dap), &options.cores);
+            let core = soc.core(*n);
+            let mut monitor = Monitor::new(core.target.dap.clone(), *interval);
+            monitor.watch_register(&core.target, *offset, *mask);
+            watch(&mut monitor, &format!("core {} +{:#x}", n, offset), *mask)
+        }
+        Command::Halt { core } | Command::Resume { core } => {
+            let soc = Arm64Soc::new(DapHandle::new(dap), &options.cores);
+            let mut core = soc.core(*core);
+            core.target.prepare_debug(true)?;
+            core.target.check_debug_permissions()?;
+            if let Command::Halt { .. } = options.command {
+                core.halt()?;
+                println!("halted, PC: {:#018x}", core.target.read_pc()?);
+            } else {
+                core.resume()?;
+                println!("resumed");
+            }
+            Ok(())
+        }
+        Command::Gdb { port } => {
+            let memory = DapHandle::new(memory()?);
+            let soc = Arm64Soc::new(DapHandle::new(dap), &options.cores);
+            let mut core = soc.core(0);
+            core.target.prepare_debug(true)?;
+            core.target.check_debug_permissions()?;
+            let mut server = GdbServer::new(core, memory);
+            server.listen(*port)
+        }
+    }
+}
+
+#[inline(never)]
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = cli::parse(&args).and_then(|options| {
+        setup_logger(options.verbose)?;
+        if options.command == Command::Probes {
+            return probes();
+        }
+        if options.command == Command::Tui {
+            return tui(&options);
+        }
+        if let Command::ReportDiff { old, new, out } = &options.command {
+            return report_diff(old, new, out);
+        }
+        run(open(&options)?, &options)
+    });
+    if let Err(e) = result {
+        eprintln!("{:#}", e);
+        std::process::exit(cli::exit_code(&e));
+    }
+}
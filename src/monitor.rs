@@ -0,0 +1,203 @@
+// registerやmemoryを一定間隔で読み、値が変わった時だけ通知する
+// 1回のpollで全てのentryを読むので、DAPのlockも1回で済む
+use std::time::{Duration, Instant};
+
+use libjtag::jtag::dap::*;
+use libjtag::target::arm64::A64Target;
+
+// pollで読めるDAP。testではscriptedな値を返すmockに差し替える
+pub trait WatchRead {
+    fn watch_read_u32(&mut self, addr: u64) -> Result<u32, DapError>;
+}
+
+impl<T: DapInterface> WatchRead for DAP<T> {
+    fn watch_read_u32(&mut self, addr: u64) -> Result<u32, DapError> {
+        self.try_mem_read_u32(addr)
+    }
+}
+
+struct WatchEntry {
+    addr: u64,
+    mask: u32,
+    // 最初のpollで読んだ値が基準になる
+    last: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchEvent {
+    // watch_*が返した番号
+    pub index: usize,
+    pub addr: u64,
+    // maskを掛けた後の値
+    pub old: u32,
+    pub new: u32,
+    pub timestamp: Instant,
+}
+
+pub struct Monitor<T> {
+    dap: DapHandle<T>,
+    interval: Duration,
+    entries: Vec<WatchEntry>,
+}
+
+impl<T: WatchRead> Monitor<T> {
+    pub fn new(dap: DapHandle<T>, interval: Duration) -> Self {
+        Monitor {
+            dap,
+            interval,
+            entries: Vec::new(),
+        }
+    }
+
+    // MEM-APの絶対address。maskで立てたbitだけを比べる
+    pub fn watch_addr(&mut self, addr: u64, mask: u32) -> usize {
+        self.entries.push(WatchEntry {
+            addr,
+            mask,
+            last: None,
+        });
+        self.entries.len() - 1
+    }
+
+    // debug registerのoffset。targetはこのMonitorと同じDAPから作ったもの
+    pub fn watch_register(&mut self, target: &A64Target<T>, offset: u64, mask: u32) -> usize {
+        self.watch_addr(target.baseaddr + offset, mask)
+    }
+
+    // 全entryを読み、前回から変わったものを返す
+    // 1つでも読めなければ、どのentryの値も更新しない
+    pub fn poll(&mut self) -> Result<Vec<WatchEvent>, DapError> {
+        let values = {
+            let mut dap = self.dap.lock();
+            self.entries
+                .iter()
+                .map(|entry| dap.watch_read_u32(entry.addr))
+                .collect::<Result<Vec<u32>, DapError>>()?
+        };
+        let timestamp = Instant::now();
+        let mut events = Vec::new();
+        for (index, (entry, value)) in self.entries.iter_mut().zip(values).enumerate() {
+            let new = value & entry.mask;
+            match entry.last {
+                Some(old) if old != new => events.push(WatchEvent {
+                    index,
+                    addr: entry.addr,
+                    old,
+                    new,
+                    timestamp,
+                }),
+                _ => (),
+            }
+            entry.last = Some(new);
+        }
+        Ok(events)
+    }
+
+    // errorになるまでpollを続ける。Ctrl-Cで止める前提
+    pub fn run<F: FnMut(&WatchEvent)>(&mut self, mut callback: F) -> Result<(), DapError> {
+        loop {
+            let start = Instant::now();
+            for event in self.poll()? {
+                callback(&event);
+            }
+            // JTAGが遅い分は間隔から差し引く
+            if let Some(rest) = self.interval.checked_sub(start.elapsed()) {
+                std::thread::sleep(rest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // addressごとにpoll毎の値を並べておく。最後の値はそのまま続く
+    struct ScriptedDap {
+        script: HashMap<u64, Vec<u32>>,
+        reads: HashMap<u64, usize>,
+        fail_at: Option<u64>,
+    }
+
+    impl ScriptedDap {
+        fn new(script: &[(u64, &[u32])]) -> Self {
+            ScriptedDap {
+                script: script.iter().map(|(a, v)| (*a, v.to_vec())).collect(),
+                reads: HashMap::new(),
+                fail_at: None,
+            }
+        }
+    }
+
+    impl WatchRead for ScriptedDap {
+        fn watch_read_u32(&mut self, addr: u64) -> Result<u32, DapError> {
+            if self.fail_at == Some(addr) {
+                return Err(DapError::WaitTimeout);
+            }
+            let values = &self.script[&addr];
+            let count = self.reads.entry(addr).or_insert(0);
+            let value = values[(*count).min(values.len() - 1)];
+            *count += 1;
+            Ok(value)
+        }
+    }
+
+    fn changes(events: &[WatchEvent]) -> Vec<(usize, u32, u32)> {
+        events.iter().map(|x| (x.index, x.old, x.new)).collect()
+    }
+
+    #[test]
+    fn change_detection_test() {
+        let dap = DapHandle::new(ScriptedDap::new(&[
+            (0x8001_0314, &[0, 0, 1, 1, 2]),
+            (0x8001_0318, &[7, 8]),
+        ]));
+        let mut monitor = Monitor::new(dap.clone(), Duration::from_millis(0));
+        assert_eq!(0, monitor.watch_addr(0x8001_0314, 0xffff_ffff));
+        assert_eq!(1, monitor.watch_addr(0x8001_0318, 0xffff_ffff));
+
+        // 最初のpollは基準値を取るだけ
+        assert!(monitor.poll().unwrap().is_empty());
+        assert_eq!(vec![(1, 7, 8)], changes(&monitor.poll().unwrap()));
+        assert_eq!(vec![(0, 0, 1)], changes(&monitor.poll().unwrap()));
+        assert!(monitor.poll().unwrap().is_empty());
+        let events = monitor.poll().unwrap();
+        assert_eq!(vec![(0, 1, 2)], changes(&events));
+        assert_eq!(0x8001_0314, events[0].addr);
+        assert_eq!(5, dap.lock().reads[&0x8001_0318]);
+    }
+
+    #[test]
+    fn mask_test() {
+        let dap = DapHandle::new(ScriptedDap::new(&[(
+            0x8001_0314,
+            &[0x00, 0x0f, 0x1f, 0xff, 0xef],
+        )]));
+        let mut monitor = Monitor::new(dap, Duration::from_millis(0));
+        monitor.watch_addr(0x8001_0314, 0x10);
+
+        assert!(monitor.poll().unwrap().is_empty());
+        // mask外のbitの変化は無視する
+        assert!(monitor.poll().unwrap().is_empty());
+        assert_eq!(vec![(0, 0x00, 0x10)], changes(&monitor.poll().unwrap()));
+        assert!(monitor.poll().unwrap().is_empty());
+        assert_eq!(vec![(0, 0x10, 0x00)], changes(&monitor.poll().unwrap()));
+    }
+
+    #[test]
+    fn read_error_test() {
+        let dap = DapHandle::new(ScriptedDap::new(&[(0x1000, &[1, 2]), (0x2000, &[3, 3])]));
+        let mut monitor = Monitor::new(dap.clone(), Duration::from_millis(0));
+        monitor.watch_addr(0x1000, 0xffff_ffff);
+        monitor.watch_addr(0x2000, 0xffff_ffff);
+        assert!(monitor.poll().unwrap().is_empty());
+
+        dap.lock().fail_at = Some(0x2000);
+        assert!(monitor.poll().is_err());
+        // 失敗したpollの値は基準にしない
+        dap.lock().fail_at = None;
+        dap.lock().reads.insert(0x1000, 1);
+        assert_eq!(vec![(0, 1, 2)], changes(&monitor.poll().unwrap()));
+    }
+}
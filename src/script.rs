@@ -0,0 +1,588 @@
+// run-script/replのrhai interpreter
+// scriptから見えるのはjtag/dap/target/ctiとdebug registerの名前だけで、DAPを直接は触らせない
+//
+//     jtag.scan()                  chainのIDCODEの配列(BYPASSは())
+//     dap.mem_read_u32(addr)       system MEM-APの32bit
+//     dap.mem_write_u32(addr, v)
+//     target.halt()                止まった理由の名前を返す
+//     target.resume() / target.halted()
+//     target.read_gpr(n) / target.read_pc()
+//     target.read_reg(name|offset) / target.write_reg(name|offset, v)
+//     target.cti.pulse(ch) / cti.pulse(ch)
+//     cores() / core(n)            --coreで選んだ以外のcore
+//     reg(name) / reg_name(offset) debug registerのoffsetと名前
+//     sleep(ms)
+//
+// debug layerのerrorは例外になり、catchしたe.message/e.ack/e.edprsr/e.edscrで中身を見られる
+use anyhow::{anyhow, Result};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Position, Scope, INT};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::cell::RefCell;
+use std::fs;
+use std::rc::Rc;
+use std::time::Duration;
+
+use libjtag::error::{DapError, DebugError};
+use libjtag::interface::InterfaceError;
+use libjtag::jtag::dap::*;
+use libjtag::jtag::idcode::TapDevice;
+use libjtag::jtag::jtag::ScanResult;
+use libjtag::target::arm64::*;
+
+const EDPRSR: u64 = Armv8DebugRegisterOffset::EDPRSR as u64;
+const EDSCR: u64 = Armv8DebugRegisterOffset::EDSCR as u64;
+
+type Scan = dyn FnMut() -> Result<ScanResult, InterfaceError>;
+
+#[derive(Clone)]
+struct JtagObject {
+    scan: Rc<RefCell<Scan>>,
+}
+
+struct DapObject<M> {
+    memory: DapHandle<M>,
+}
+
+struct TargetObject<T> {
+    core: Rc<RefCell<CoreHandle<T>>>,
+}
+
+struct CtiObject<T> {
+    core: Rc<RefCell<CoreHandle<T>>>,
+}
+
+// deriveするとT: Cloneが要るので、handleだけを複製する
+impl<M> Clone for DapObject<M> {
+    fn clone(&self) -> Self {
+        DapObject {
+            memory: self.memory.clone(),
+        }
+    }
+}
+
+impl<T> Clone for TargetObject<T> {
+    fn clone(&self) -> Self {
+        TargetObject {
+            core: self.core.clone(),
+        }
+    }
+}
+
+impl<T> Clone for CtiObject<T> {
+    fn clone(&self) -> Self {
+        CtiObject {
+            core: self.core.clone(),
+        }
+    }
+}
+
+// scriptへ投げる例外。Mapなのでscriptからfieldで読める
+fn exception(
+    message: String,
+    ack: Option<DapAck>,
+    status: Option<(u32, u32)>,
+) -> Box<EvalAltResult> {
+    let mut map = Map::new();
+    map.insert("message".into(), message.into());
+    if let Some(ack) = ack {
+        map.insert("ack".into(), format!("{:?}", ack).into());
+    }
+    if let Some((edprsr, edscr)) = status {
+        map.insert("edprsr".into(), (edprsr as INT).into());
+        map.insert("edscr".into(), (edscr as INT).into());
+    }
+    EvalAltResult::ErrorRuntime(map.into(), Position::NONE).into()
+}
+
+// errorの元になったACK。sticky flagで落ちた場合もACKはOK/FAULT
+fn error_ack(e: &DapError) -> Option<DapAck> {
+    match e {
+        DapError::WaitTimeout => Some(DapAck::Wait),
+        DapError::InvalidAck | DapError::LinkLost => Some(DapAck::InvalidAck),
+        DapError::Fault { .. } => Some(DapAck::OkFault),
+        _ => None,
+    }
+}
+
+fn dap_exception(e: DapError) -> Box<EvalAltResult> {
+    exception(e.to_string(), error_ack(&e), None)
+}
+
+fn check_ack(ack: DapAck) -> Result<(), Box<EvalAltResult>> {
+    match ack {
+        DapAck::OkFault => Ok(()),
+        ack => Err(exception(
+            format!("unexpected ACK: {:?}", ack),
+            Some(ack),
+            None,
+        )),
+    }
+}
+
+// errorの後でcoreのEDPRSR/EDSCRを読んで付ける。読めなければ付けない
+fn debug_exception<T: DebugPort + MemoryAccessPort>(
+    core: &mut CoreHandle<T>,
+    e: DebugError,
+) -> Box<EvalAltResult> {
+    let ack = match &e {
+        DebugError::Dap(e) => error_ack(e),
+        _ => None,
+    };
+    let status = match e {
+        DebugError::HaltTimeout { edprsr, edscr } => Some((edprsr, edscr)),
+        _ => core
+            .target
+            .register_u32_read(EDPRSR)
+            .and_then(|edprsr| Ok((edprsr, core.target.register_u32_read(EDSCR)?)))
+            .ok(),
+    };
+    exception(e.to_string(), ack, status)
+}
+
+fn register_offset(name: &str) -> Result<u64, Box<EvalAltResult>> {
+    Armv8DebugRegisterOffset::from_name(name)
+        .map(|x| x as u64)
+        .ok_or_else(|| format!("unknown debug register: {}", name).into())
+}
+
+impl<T: DebugPort + MemoryAccessPort> TargetObject<T> {
+    fn call<R>(
+        &mut self,
+        f: impl FnOnce(&mut CoreHandle<T>) -> Result<R, DebugError>,
+    ) -> Result<R, Box<EvalAltResult>> {
+        let mut core = self.core.borrow_mut();
+        f(&mut core).map_err(|e| debug_exception(&mut core, e))
+    }
+
+    fn read_reg(&mut self, offset: u64) -> Result<INT, Box<EvalAltResult>> {
+        self.call(|core| Ok(core.target.register_u32_read(offset)? as INT))
+    }
+
+    fn write_reg(&mut self, offset: u64, value: INT) -> Result<(), Box<EvalAltResult>> {
+        self.call(|core| Ok(core.target.register_u32_write(offset, value as u32)?))
+    }
+}
+
+pub struct ScriptHost {
+    engine: Engine,
+    scope: Scope<'static>,
+}
+
+impl ScriptHost {
+    // targetとctiはcores[current]を指す
+    pub fn new<T, M>(
+        scan: impl FnMut() -> Result<ScanResult, InterfaceError> + 'static,
+        memory: DapHandle<M>,
+        cores: Vec<CoreHandle<T>>,
+        current: usize,
+    ) -> Self
+    where
+        T: DebugPort + MemoryAccessPort + 'static,
+        M: DebugPort + MemoryAccessPort + 'static,
+    {
+        let mut engine = Engine::new();
+        let cores: Vec<TargetObject<T>> = cores
+            .into_iter()
+            .map(|core| TargetObject {
+                core: Rc::new(RefCell::new(core)),
+            })
+            .collect();
+
+        engine
+            .register_type_with_name::<JtagObject>("Jtag")
+            .register_fn("scan", |jtag: &mut JtagObject| {
+                let result =
+                    (jtag.scan.borrow_mut())().map_err(|e| exception(e.to_string(), None, None))?;
+                Ok::<_, Box<EvalAltResult>>(
+                    result
+                        .devices()
+                        .iter()
+                        .map(|device| match device {
+                            TapDevice::IdCode(idcode) => (idcode.raw as INT).into(),
+                            TapDevice::Bypass => Dynamic::UNIT,
+                        })
+                        .collect::<Array>(),
+                )
+            });
+
+        engine
+            .register_type_with_name::<DapObject<M>>("Dap")
+            .register_fn("mem_read_u32", |dap: &mut DapObject<M>, addr: INT| {
+                let (ack, data) = dap
+                    .memory
+                    .lock()
+                    .mem_read_u32(addr as u64)
+                    .map_err(dap_exception)?;
+                check_ack(ack)?;
+                Ok::<_, Box<EvalAltResult>>(data as INT)
+            })
+            .register_fn(
+                "mem_write_u32",
+                |dap: &mut DapObject<M>, addr: INT, value: INT| {
+                    let ack = dap
+                        .memory
+                        .lock()
+                        .mem_write_u32(addr as u64, value as u32)
+                        .map_err(dap_exception)?;
+                    check_ack(ack)
+                },
+            );
+
+        // 64bitの値はINT(i64)にそのまま入れる
+        engine
+            .register_type_with_name::<TargetObject<T>>("Target")
+            .register_fn("halt", |target: &mut TargetObject<T>| {
+                target.call(|core| {
+                    let reason = core.attach(ResetPolicy::Halt, || Ok(()))?;
+                    Ok(format!("{:?}", reason))
+                })
+            })
+            .register_fn("resume", |target: &mut TargetObject<T>| {
+                target.call(|core| core.resume())
+            })
+            .register_fn("halted", |target: &mut TargetObject<T>| {
+                target.call(|core| Ok(core.is_halted()?))
+            })
+            .register_fn("read_gpr", |target: &mut TargetObject<T>, n: INT| {
+                target.call(|core| Ok(core.target.read_gpr(n as u8)? as INT))
+            })
+            .register_fn("read_pc", |target: &mut TargetObject<T>| {
+                target.call(|core| Ok(core.target.read_pc()? as INT))
+            })
+            .register_fn("read_reg", |target: &mut TargetObject<T>, offset: INT| {
+                target.read_reg(offset as u64)
+            })
+            .register_fn("read_reg", |target: &mut TargetObject<T>, name: &str| {
+                target.read_reg(register_offset(name)?)
+            })
+            .register_fn(
+                "write_reg",
+                |target: &mut TargetObject<T>, offset: INT, value: INT| {
+                    target.write_reg(offset as u64, value)
+                },
+            )
+            .register_fn(
+                "write_reg",
+                |target: &mut TargetObject<T>, name: &str, value: INT| {
+                    target.write_reg(register_offset(name)?, value)
+                },
+            )
+            .register_get("cti", |target: &mut TargetObject<T>| CtiObject {
+                core: target.core.clone(),
+            });
+
+        engine
+            .register_type_with_name::<CtiObject<T>>("Cti")
+            .register_fn("pulse", |cti: &mut CtiObject<T>, channel: INT| {
+                let mut core = cti.core.borrow_mut();
+                let result = core.cti.generate_pulse(channel as u32);
+                result.map_err(|e| debug_exception(&mut core, e.into()))
+            });
+
+        let count = cores.len() as INT;
+        let all = cores.clone();
+        engine
+            .register_fn("cores", move || count)
+            .register_fn("core", move |n: INT| {
+                all.get(n as usize)
+                    .cloned()
+                    .ok_or_else(|| -> Box<EvalAltResult> {
+                        format!("core {} does not exist ({} cores)", n, count).into()
+                    })
+            })
+            .register_fn("reg", |name: &str| register_offset(name).map(|x| x as INT))
+            .register_fn("reg_name", |offset: INT| {
+                Armv8DebugRegisterOffset::ALL
+                    .iter()
+                    .find(|x| **x as INT == offset)
+                    .map_or(Dynamic::UNIT, |x| x.name().into())
+            })
+            .register_fn("sleep", |ms: INT| {
+                std::thread::sleep(Duration::from_millis(ms.max(0) as u64))
+            });
+
+        let mut scope = Scope::new();
+        scope.push_constant(
+            "jtag",
+            JtagObject {
+                scan: Rc::new(RefCell::new(scan)),
+            },
+        );
+        scope.push_constant("dap", DapObject { memory });
+        if let Some(target) = cores.get(current) {
+            scope.push_constant(
+                "cti",
+                CtiObject {
+                    core: target.core.clone(),
+                },
+            );
+            scope.push_constant("target", target.clone());
+        }
+        ScriptHost { engine, scope }
+    }
+
+    // printの出力先を変える
+    #[cfg(test)]
+    pub fn on_print(&mut self, f: impl Fn(&str) + 'static) {
+        self.engine.on_print(f);
+    }
+
+    // letした変数は次のrun/evalでも使える
+    pub fn run(&mut self, script: &str) -> Result<(), Box<EvalAltResult>> {
+        self.engine.run_with_scope(&mut self.scope, script)
+    }
+
+    pub fn eval(&mut self, script: &str) -> Result<Dynamic, Box<EvalAltResult>> {
+        self.engine.eval_with_scope(&mut self.scope, script)
+    }
+}
+
+// catchされなかった例外。debug layerのerrorならACKとEDPRSR/EDSCRも出す
+pub fn describe(e: &EvalAltResult) -> String {
+    let (value, position) = match e {
+        EvalAltResult::ErrorRuntime(value, position) => (value, position),
+        e => return e.to_string(),
+    };
+    let map = match value.read_lock::<Map>() {
+        Some(map) => map,
+        None => return e.to_string(),
+    };
+    let mut text = map
+        .get("message")
+        .map_or_else(|| value.to_string(), |x| x.to_string());
+    if let Some(ack) = map.get("ack") {
+        text += &format!(" (ACK: {})", ack);
+    }
+    if let (Some(edprsr), Some(edscr)) = (map.get("edprsr"), map.get("edscr")) {
+        text += &format!(
+            " (EDPRSR: {:#010x}, EDSCR: {:#010x})",
+            edprsr.as_int().unwrap_or(0),
+            edscr.as_int().unwrap_or(0)
+        );
+    }
+    if !position.is_none() {
+        text += &format!(" at {}", position);
+    }
+    text
+}
+
+pub fn run_file(host: &mut ScriptHost, path: &str) -> Result<()> {
+    let script = fs::read_to_string(path).map_err(|e| anyhow!("failed to read {}: {}", path, e))?;
+    host.run(&script)
+        .map_err(|e| anyhow!("{}: {}", path, describe(&e)))
+}
+
+// 1行ずつ評価し、()以外の値は表示する。Ctrl-Dで終わる
+pub fn repl(host: &mut ScriptHost) -> Result<()> {
+    let mut editor = DefaultEditor::new()?;
+    loop {
+        let line = match editor.readline("jtag> ") {
+            Ok(line) => line,
+            // Ctrl-Cは入力中の行を捨てるだけ
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line.as_str())?;
+        match host.eval(&line) {
+            Ok(value) if value.is_unit() => (),
+            Ok(value) => println!("{}", value),
+            Err(e) => eprintln!("{}", describe(&e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{SocSim, CTI, DEBUG};
+    use std::cell::Cell;
+
+    const MIDR: u32 = 0x410f_d083;
+
+    fn soc() -> DapHandle<DAP<SocSim>> {
+        let dap = DapHandle::new(DAP::new(SocSim::new()));
+        for base in DEBUG.iter() {
+            dap.lock()
+                .mem_write_u32(base + Armv8DebugRegisterOffset::MIDR_EL1 as u64, MIDR)
+                .unwrap();
+        }
+        dap
+    }
+
+    // printした行を返すhost。core 1にはCTIが無い
+    fn new_host<T: DapInterface + 'static>(
+        dap: DapHandle<DAP<T>>,
+        current: usize,
+    ) -> (ScriptHost, Rc<RefCell<Vec<String>>>) {
+        let cores = [
+            CoreBase {
+                debug: DEBUG[0],
+                cti: CTI,
+            },
+            CoreBase {
+                debug: DEBUG[1],
+                cti: 0,
+            },
+        ];
+        let soc = Arm64Soc::new(dap.clone(), &cores);
+        let cores = (0..soc.cores()).map(|n| soc.core(n)).collect();
+        let mut host = ScriptHost::new(|| Err(InterfaceError::Unsupported), dap, cores, current);
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let lines = output.clone();
+        host.on_print(move |x| lines.borrow_mut().push(x.to_string()));
+        (host, output)
+    }
+
+    #[test]
+    fn midr_script_test() {
+        let (mut host, output) = new_host(soc(), 0);
+        host.run(include_str!("../scripts/midr.rhai")).unwrap();
+        assert_eq!(
+            vec![
+                "core 0: MIDR_EL1 0x410fd083".to_string(),
+                "core 1: MIDR_EL1 0x410fd083".to_string(),
+            ],
+            *output.borrow()
+        );
+    }
+
+    #[test]
+    fn wait_halt_script_test() {
+        let dap = soc();
+        let (mut host, output) = new_host(dap.clone(), 0);
+        host.run("target.resume(); cti.pulse(0);").unwrap();
+        host.run(include_str!("../scripts/wait_halt.rhai")).unwrap();
+        assert_eq!(
+            Some("core halted: EDPRSR 0x811"),
+            output.borrow().last().map(|x| x.as_str())
+        );
+
+        // core 1は走ったまま止まらない
+        let (mut host, _) = new_host(dap, 1);
+        let e = host
+            .run(include_str!("../scripts/wait_halt.rhai"))
+            .unwrap_err();
+        assert!(describe(&e).contains("did not halt"), "{}", describe(&e));
+    }
+
+    #[test]
+    fn binding_test() {
+        let (mut host, _) = new_host(soc(), 0);
+        host.run("dap.mem_write_u32(0x1000, 0xdead_beef)").unwrap();
+        assert_eq!(
+            0xdead_beef,
+            host.eval("dap.mem_read_u32(0x1000)")
+                .unwrap()
+                .as_int()
+                .unwrap()
+        );
+        assert_eq!(
+            0x314,
+            host.eval("reg(\"edprsr\")").unwrap().as_int().unwrap()
+        );
+        assert_eq!(
+            "MIDR_EL1",
+            host.eval("reg_name(0xd00)").unwrap().to_string()
+        );
+        assert!(host.eval("reg_name(0x1)").unwrap().is_unit());
+        assert_eq!(2, host.eval("cores()").unwrap().as_int().unwrap());
+
+        // letした変数は次のevalでも見える
+        host.run("let reason = target.halt();").unwrap();
+        assert_eq!(
+            "ExternalDebugRequest",
+            host.eval("reason").unwrap().to_string()
+        );
+        assert_eq!("true", host.eval("target.halted()").unwrap().to_string());
+        assert_eq!(
+            0x1234,
+            host.eval("target.read_gpr(3)").unwrap().as_int().unwrap()
+        );
+        host.run("target.write_reg(\"EDECR\", 4)").unwrap();
+        assert_eq!(
+            4,
+            host.eval("target.read_reg(0x24)")
+                .unwrap()
+                .as_int()
+                .unwrap()
+        );
+        assert_eq!("false", host.eval("core(1).halted()").unwrap().to_string());
+        assert!(host.eval("core(2)").is_err());
+        assert!(host.eval("reg(\"EDFOO\")").is_err());
+
+        // scanのerrorも例外になる
+        let e = host.run("jtag.scan()").unwrap_err();
+        assert!(describe(&e).contains("not supported"), "{}", describe(&e));
+    }
+
+    // stallが立っている間はAPへのaccessにWAITを返し続ける
+    struct Stall {
+        sim: SocSim,
+        stall: Rc<Cell<bool>>,
+    }
+
+    impl DapInterface for Stall {
+        fn apacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            if self.stall.get() {
+                return Ok((0x01, 0));
+            }
+            self.sim.apacc(data, a, rnw)
+        }
+        fn dpacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+            self.sim.dpacc(data, a, rnw)
+        }
+    }
+
+    #[test]
+    fn exception_test() {
+        // errorの後に読んだEDPRSR/EDSCRが付く
+        let (mut host, output) = new_host(soc(), 0);
+        host.run(
+            "try { target.read_gpr(40) } catch (e) { print(e.message); print(e.edprsr); print(e.edscr) }",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                InterfaceError::OutOfRange.to_string(),
+                "17".to_string(),
+                0x2100_3e13_u32.to_string(),
+            ],
+            *output.borrow()
+        );
+        let e = host.run("\n\ntarget.read_gpr(40)").unwrap_err();
+        assert_eq!(
+            format!(
+                "{} (EDPRSR: 0x00000011, EDSCR: 0x21003e13) at line 3, position 8",
+                InterfaceError::OutOfRange
+            ),
+            describe(&e)
+        );
+
+        // WAITが続くとACKが付く
+        let stall = Rc::new(Cell::new(false));
+        let dap = DAP::new(Stall {
+            sim: SocSim::new(),
+            stall: stall.clone(),
+        });
+        let (mut host, output) = new_host(DapHandle::new(dap), 0);
+        stall.set(true);
+        host.run("try { dap.mem_read_u32(0x1000) } catch (e) { print(e.ack) }")
+            .unwrap();
+        assert_eq!(vec!["Wait".to_string()], *output.borrow());
+        let e = host.run("target.read_reg(\"EDSCR\")").unwrap_err();
+        assert!(
+            describe(&e).ends_with("(ACK: Wait) at line 1, position 8"),
+            "{}",
+            describe(&e)
+        );
+
+        // script自身のerrorはそのまま
+        let e = host.run("let x = ;").unwrap_err();
+        assert_eq!(e.to_string(), describe(&e));
+    }
+}
@@ -0,0 +1,181 @@
+// testで使うSoCのmodel
+// MEM-APの先にcoreのdebug registerとmemoryがある
+use std::collections::HashMap;
+
+use libjtag::interface::InterfaceError;
+use libjtag::jtag::dap::*;
+use libjtag::target::arm64::*;
+
+pub const DEBUG: [u64; 2] = [0x8001_0000, 0x8001_2000];
+pub const CTI: u64 = 0x8001_8000;
+const EDPRSR: u64 = Armv8DebugRegisterOffset::EDPRSR as u64;
+const EDSCR: u64 = Armv8DebugRegisterOffset::EDSCR as u64;
+const EDECR: u64 = Armv8DebugRegisterOffset::EDECR as u64;
+const EDITR: u64 = Armv8DebugRegisterOffset::EDITR as u64;
+const DTRRX: u64 = Armv8DebugRegisterOffset::DBGDTRRX_EL0 as u64;
+const DTRTX: u64 = Armv8DebugRegisterOffset::DBGDTRTX_EL0 as u64;
+const APPPULSE: u64 = 0x01C;
+
+// BDかDRWでaccessされるMEM-APとcoreのdebug register
+// CTIのpulseでcore 0をhalt/restartさせる
+// core 0はEDITRのregister転送命令だけを実行し、それ以外の命令は無視する
+pub struct SocSim {
+    select: u32,
+    csw: u32,
+    tar: u64,
+    rdbuff: u32,
+    ctrlstat: u32,
+    memory: HashMap<u64, u32>,
+    x: [u64; 31],
+    sp: u64,
+    dlr: u64,
+    dspsr: u64,
+}
+
+impl SocSim {
+    pub fn new() -> Self {
+        let mut memory = HashMap::new();
+        // core 0はEL2でhalt、core 1は走っている
+        memory.insert(DEBUG[0] + EDPRSR, 0x11);
+        memory.insert(DEBUG[1] + EDPRSR, 0x01);
+        // ITE, TXfull, 全ELがAArch64, EL2
+        memory.insert(
+            DEBUG[0] + EDSCR,
+            (1 << 24) | (1 << 29) | (0b1111 << 10) | (2 << 8) | 0b010011,
+        );
+        // breakpointは6個
+        memory.insert(DEBUG[0] + Armv8DebugRegisterOffset::EDDFR as u64, 5 << 12);
+        for base in DEBUG.iter() {
+            memory.insert(base + Armv8DebugRegisterOffset::DBGAUTHSTATUS as u64, 0xff);
+        }
+        SocSim {
+            select: 0,
+            csw: 0,
+            tar: 0,
+            rdbuff: 0,
+            ctrlstat: 0,
+            memory,
+            // GPRは全てこの値から始まる
+            x: [0x1234; 31],
+            sp: 0x1234,
+            dlr: 0,
+            dspsr: 0,
+        }
+    }
+
+    fn read(&self, address: u64) -> u32 {
+        *self.memory.get(&address).unwrap_or(&0)
+    }
+
+    fn write(&mut self, address: u64, data: u32) {
+        self.memory.insert(address, data);
+        if address == DEBUG[0] + EDITR {
+            self.run(data);
+            return;
+        }
+        if address != CTI + APPPULSE {
+            return;
+        }
+        let edprsr = self.read(DEBUG[0] + EDPRSR);
+        let stepping = self.read(DEBUG[0] + EDECR) & (1 << 2) != 0;
+        let edprsr = match data {
+            // halt
+            1 => edprsr | (1 << 4),
+            // restart。SDRを立て、stepなら1命令後にまたhaltする
+            _ if stepping => edprsr | (1 << 11) | (1 << 4),
+            _ => (edprsr | (1 << 11)) & !(1 << 4),
+        };
+        self.memory.insert(DEBUG[0] + EDPRSR, edprsr);
+    }
+
+    // CSW.AddrIncがSingleならDRWのaccess毎にTARを進める
+    fn increment_tar(&mut self) {
+        if (self.csw >> 4) & 0b11 == 0b01 {
+            self.tar += 4;
+        }
+    }
+
+    fn run(&mut self, instruction: u32) {
+        let rt = (instruction & 0x1f) as usize;
+        let value = match instruction & !0x1f {
+            x if x == encode_msr(DBGDTR_EL0, 0) => {
+                let value = self.x[rt];
+                self.memory.insert(DEBUG[0] + DTRRX, (value >> 32) as u32);
+                self.memory.insert(DEBUG[0] + DTRTX, value as u32);
+                return;
+            }
+            x if x == encode_mrs(DBGDTR_EL0, 0) => {
+                let high = self.read(DEBUG[0] + DTRTX) as u64;
+                let low = self.read(DEBUG[0] + DTRRX) as u64;
+                (high << 32) | low
+            }
+            x if x == encode_mrs(DLR_EL0, 0) => self.dlr,
+            x if x == encode_mrs(DSPSR_EL0, 0) => self.dspsr,
+            x if x == encode_msr(DLR_EL0, 0) => {
+                self.dlr = self.x[rt];
+                return;
+            }
+            x if x == encode_msr(DSPSR_EL0, 0) => {
+                self.dspsr = self.x[rt];
+                return;
+            }
+            _ if instruction == encode_mov_sp(0, GPR_SP) => self.sp,
+            _ if instruction == encode_mov_sp(GPR_SP, 0) => {
+                self.sp = self.x[0];
+                return;
+            }
+            _ => return,
+        };
+        self.x[rt] = value;
+    }
+}
+
+impl DapInterface for SocSim {
+    fn apacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+        let address = (DpSelect(self.select).apbanksel() << 4) as u8 | (a << 2);
+        let result = match (address, rnw) {
+            (0x00, true) => self.csw,
+            (0x00, false) => {
+                self.csw = data;
+                0
+            }
+            (0x04, false) => {
+                self.tar = data as u64;
+                0
+            }
+            (0x0C, true) => {
+                let result = self.read(self.tar);
+                self.increment_tar();
+                result
+            }
+            (0x0C, false) => {
+                self.write(self.tar, data);
+                self.increment_tar();
+                0
+            }
+            (0x10..=0x1C, true) => self.read((self.tar & !0xf) + (address - 0x10) as u64),
+            (0x10..=0x1C, false) => {
+                self.write((self.tar & !0xf) + (address - 0x10) as u64, data);
+                0
+            }
+            _ => 0,
+        };
+        let previous = self.rdbuff;
+        if rnw {
+            self.rdbuff = result;
+        }
+        Ok((0x02, previous))
+    }
+    fn dpacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+        match (a, rnw) {
+            // Cortex-A72のJTAG-DP
+            (0b00, true) => self.rdbuff = 0x4ba0_1477,
+            (0b01, true) => self.rdbuff = self.ctrlstat,
+            (0b01, false) => self.ctrlstat = data | ((data & (1 << 28 | 1 << 30)) << 1),
+            (0b10, false) => self.select = data,
+            (0b11, true) => return Ok((0x02, self.rdbuff)),
+            _ => (),
+        }
+        Ok((0x02, 0))
+    }
+}
@@ -0,0 +1,399 @@
+// examples/boot_payload.rsのbootを、payloadを実行するcoreのmodelで通す
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use libjtag::config::Config;
+use libjtag::interface::InterfaceError;
+use libjtag::jtag::dap::*;
+use libjtag::target::arm64::*;
+
+#[path = "../examples/boot_payload.rs"]
+#[allow(dead_code)]
+mod boot_payload;
+
+use boot_payload::{boot, parse_args, Payload};
+
+const DEBUG: u64 = 0x8001_0000;
+const CTI: u64 = 0x8001_8000;
+const EDSCR: u64 = DEBUG + Armv8DebugRegisterOffset::EDSCR as u64;
+const EDECR: u64 = DEBUG + Armv8DebugRegisterOffset::EDECR as u64;
+const EDESR: u64 = DEBUG + Armv8DebugRegisterOffset::EDESR as u64;
+const EDPRSR: u64 = DEBUG + Armv8DebugRegisterOffset::EDPRSR as u64;
+const EDITR: u64 = DEBUG + Armv8DebugRegisterOffset::EDITR as u64;
+const OSLAR: u64 = DEBUG + Armv8DebugRegisterOffset::OSLAR_EL1 as u64;
+const DTRRX: u64 = DEBUG + Armv8DebugRegisterOffset::DBGDTRRX_EL0 as u64;
+const DTRTX: u64 = DEBUG + Armv8DebugRegisterOffset::DBGDTRTX_EL0 as u64;
+const DBGBVR0: u64 = DEBUG + Armv8DebugRegisterOffset::DBGBVR_BASE_EL1 as u64;
+const DBGBCR0: u64 = DEBUG + Armv8DebugRegisterOffset::DBGBCR_BASE_EL1 as u64;
+const APPPULSE: u64 = CTI + 0x01C;
+
+// EDPRSR
+const PU: u32 = 1 << 0;
+const SR: u32 = 1 << 3;
+const HALTED: u32 = 1 << 4;
+const OSLK: u32 = 1 << 5;
+const SDR: u32 = 1 << 11;
+// EDSCR.STATUS
+const STATUS_MASK: u32 = 0b11_1111;
+const BREAKPOINT: u32 = 0b000111;
+const EXTERNAL_DEBUG_REQUEST: u32 = 0b010011;
+const RESET_CATCH: u32 = 0b100111;
+
+const PAYLOAD: Payload = Payload {
+    image: std::path::PathBuf::new(),
+    load: 0x1000_0000,
+    entry: 0x1000_0000,
+    done: 0x1000_0010,
+    mailbox: 0x1000_0100,
+};
+const FIRMWARE: u64 = 0x0008_0000;
+
+// MEM-APの先にcore 0のdebug register、CTIとSRAMがある
+// restartするとDLR_EL0から走り出し、loadしたimageの最初のwordをmailboxに書いて
+// doneまで進む。doneにbreakpointがあればそこでhaltする
+// entry以外から走らせた場合は元のfirmwareが走り続ける
+struct Soc {
+    select: u32,
+    csw: u32,
+    tar: u64,
+    rdbuff: u32,
+    ctrlstat: u32,
+    memory: HashMap<u64, u32>,
+    x: [u64; 31],
+    dlr: u64,
+    resets: usize,
+}
+
+#[derive(Clone)]
+struct SimTarget(Arc<Mutex<Soc>>);
+
+impl SimTarget {
+    // firmwareが走っていて、OS Lockが掛かっている
+    fn new() -> Self {
+        let mut memory = HashMap::new();
+        memory.insert(EDPRSR, PU | OSLK);
+        // ITE, TXfull, 全ELがAArch64, EL1
+        memory.insert(
+            EDSCR,
+            (1 << 24) | (1 << 29) | (0b1111 << 10) | (1 << 8) | 0b000010,
+        );
+        // breakpointは6個
+        memory.insert(DEBUG + Armv8DebugRegisterOffset::EDDFR as u64, 5 << 12);
+        memory.insert(DEBUG + Armv8DebugRegisterOffset::DBGAUTHSTATUS as u64, 0xff);
+        SimTarget(Arc::new(Mutex::new(Soc {
+            select: 0,
+            csw: 0,
+            tar: 0,
+            rdbuff: 0,
+            ctrlstat: 0,
+            memory,
+            x: [0; 31],
+            dlr: FIRMWARE,
+            resets: 0,
+        })))
+    }
+
+    fn soc(&self) -> std::sync::MutexGuard<'_, Soc> {
+        self.0.lock().unwrap()
+    }
+
+    // SRST。EDECR.RCEが立っていればresetから出た最初の命令でhaltする
+    fn reset(&self) -> Result<(), InterfaceError> {
+        let mut soc = self.soc();
+        soc.resets += 1;
+        soc.dlr = FIRMWARE;
+        // resetでhalting debugとbreakpointは落ち、OS Lockが掛かる
+        soc.memory.insert(DBGBCR0, 0);
+        soc.set_status(0b000010);
+        *soc.word_mut(EDSCR) &= !(1 << 14);
+        if soc.read(EDECR) & (1 << 1) != 0 {
+            soc.memory.insert(EDPRSR, PU | SR | HALTED | OSLK);
+            soc.memory.insert(EDESR, 1 << 1);
+            soc.set_status(RESET_CATCH);
+        } else {
+            soc.memory.insert(EDPRSR, PU | SR | OSLK);
+        }
+        Ok(())
+    }
+
+    fn read(&self, address: u64) -> u32 {
+        self.soc().read(address)
+    }
+}
+
+impl Soc {
+    fn read(&self, address: u64) -> u32 {
+        *self.memory.get(&address).unwrap_or(&0)
+    }
+
+    fn word_mut(&mut self, address: u64) -> &mut u32 {
+        self.memory.entry(address).or_insert(0)
+    }
+
+    fn set_status(&mut self, status: u32) {
+        let edscr = self.word_mut(EDSCR);
+        *edscr = (*edscr & !STATUS_MASK) | status;
+    }
+
+    fn halted(&self) -> bool {
+        self.read(EDPRSR) & HALTED != 0
+    }
+
+    // EDPRSR.SRとSDRは読むと落ちる
+    fn bus_read(&mut self, address: u64) -> u32 {
+        let value = self.read(address);
+        if address == EDPRSR {
+            *self.word_mut(EDPRSR) &= !(SR | SDR);
+        }
+        value
+    }
+
+    fn bus_write(&mut self, address: u64, data: u32) {
+        match address {
+            EDITR => self.run(data),
+            // 1を書いたeventだけ落ちる
+            EDESR => *self.word_mut(EDESR) &= !data,
+            OSLAR => {
+                let edprsr = self.word_mut(EDPRSR);
+                *edprsr = (*edprsr & !OSLK) | ((data & 1) << 5);
+            }
+            // channel 0でhalt、channel 1でrestart
+            APPPULSE if data == 1 => {
+                if !self.halted() {
+                    *self.word_mut(EDPRSR) |= HALTED;
+                    self.set_status(EXTERNAL_DEBUG_REQUEST);
+                }
+            }
+            APPPULSE if data == 2 => self.restart(),
+            _ => {
+                self.memory.insert(address, data);
+            }
+        }
+    }
+
+    fn restart(&mut self) {
+        *self.word_mut(EDPRSR) = (self.read(EDPRSR) & !HALTED) | SDR;
+        if self.dlr != PAYLOAD.entry {
+            return;
+        }
+        let value = self.read(PAYLOAD.load);
+        self.memory.insert(PAYLOAD.mailbox, value);
+        let breakpoint = (self.read(DBGBVR0 + 4) as u64) << 32 | self.read(DBGBVR0) as u64;
+        if self.read(DBGBCR0) & 1 == 1 && breakpoint == PAYLOAD.done {
+            self.dlr = PAYLOAD.done;
+            *self.word_mut(EDPRSR) |= HALTED;
+            self.set_status(BREAKPOINT);
+        }
+    }
+
+    // EDITRのregister転送命令だけを実行する
+    fn run(&mut self, instruction: u32) {
+        let rt = (instruction & 0x1f) as usize;
+        let value = match instruction & !0x1f {
+            x if x == encode_msr(DBGDTR_EL0, 0) => {
+                let value = self.x[rt];
+                self.memory.insert(DTRRX, (value >> 32) as u32);
+                self.memory.insert(DTRTX, value as u32);
+                return;
+            }
+            x if x == encode_mrs(DBGDTR_EL0, 0) => {
+                let high = self.read(DTRTX) as u64;
+                let low = self.read(DTRRX) as u64;
+                (high << 32) | low
+            }
+            x if x == encode_mrs(DLR_EL0, 0) => self.dlr,
+            x if x == encode_msr(DLR_EL0, 0) => {
+                self.dlr = self.x[rt];
+                return;
+            }
+            _ => return,
+        };
+        self.x[rt] = value;
+    }
+
+    // CSW.AddrIncがSingleならDRWのaccess毎にTARを進める
+    fn increment_tar(&mut self) {
+        if (self.csw >> 4) & 0b11 == 0b01 {
+            self.tar += 4;
+        }
+    }
+}
+
+impl DapInterface for SimTarget {
+    fn apacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+        let mut soc = self.soc();
+        let address = (DpSelect(soc.select).apbanksel() << 4) as u8 | (a << 2);
+        let bd_base = soc.tar & !0xf;
+        let result = match (address, rnw) {
+            (0x00, true) => soc.csw,
+            (0x00, false) => {
+                soc.csw = data;
+                0
+            }
+            (0x04, false) => {
+                soc.tar = data as u64;
+                0
+            }
+            (0x0C, true) => {
+                let tar = soc.tar;
+                let result = soc.bus_read(tar);
+                soc.increment_tar();
+                result
+            }
+            (0x0C, false) => {
+                let tar = soc.tar;
+                soc.bus_write(tar, data);
+                soc.increment_tar();
+                0
+            }
+            (0x10..=0x1C, true) => soc.bus_read(bd_base + (address - 0x10) as u64),
+            (0x10..=0x1C, false) => {
+                soc.bus_write(bd_base + (address - 0x10) as u64, data);
+                0
+            }
+            _ => 0,
+        };
+        let previous = soc.rdbuff;
+        if rnw {
+            soc.rdbuff = result;
+        }
+        Ok((0x02, previous))
+    }
+    fn dpacc(&mut self, data: u32, a: u8, rnw: bool) -> Result<(u8, u32), InterfaceError> {
+        let mut soc = self.soc();
+        match (a, rnw) {
+            // Cortex-A72のJTAG-DP
+            (0b00, true) => soc.rdbuff = 0x4ba0_1477,
+            (0b01, true) => soc.rdbuff = soc.ctrlstat,
+            (0b01, false) => soc.ctrlstat = data | ((data & (1 << 28 | 1 << 30)) << 1),
+            (0b10, false) => soc.select = data,
+            (0b11, true) => return Ok((0x02, soc.rdbuff)),
+            _ => (),
+        }
+        Ok((0x02, 0))
+    }
+}
+
+// core 0とSRAMが同じMEM-APの先にある
+fn setup(name: &str) -> (SimTarget, DapHandle<DAP<SimTarget>>, Payload) {
+    let sim = SimTarget::new();
+    let dap = DapHandle::new(DAP::new(sim.clone()));
+    let image =
+        std::env::temp_dir().join(format!("boot_payload_{}_{}.bin", name, std::process::id()));
+    std::fs::write(&image, 0xcafe_f00du32.to_le_bytes()).unwrap();
+    let payload = Payload { image, ..PAYLOAD };
+    (sim, dap, payload)
+}
+
+fn core(dap: &DapHandle<DAP<SimTarget>>) -> CoreHandle<DAP<SimTarget>> {
+    Arm64Soc::new(
+        dap.clone(),
+        &[CoreBase {
+            debug: DEBUG,
+            cti: CTI,
+        }],
+    )
+    .core(0)
+}
+
+fn check_detached(sim: &SimTarget) {
+    let edprsr = sim.read(EDPRSR);
+    assert_eq!((0, OSLK), (edprsr & HALTED, edprsr & OSLK));
+    assert_eq!(0, sim.read(DBGBCR0) & 1);
+    assert_eq!(0, sim.read(EDSCR) & (1 << 14));
+}
+
+#[test]
+fn boot_halt_test() {
+    let (sim, dap, payload) = setup("halt");
+    let value = boot(
+        core(&dap),
+        &dap,
+        ResetPolicy::Halt,
+        || panic!("reset is not used"),
+        &payload,
+    )
+    .unwrap();
+    std::fs::remove_file(&payload.image).unwrap();
+    assert_eq!(0xcafe_f00d, value);
+    assert_eq!(0, sim.soc().resets);
+    check_detached(&sim);
+}
+
+#[test]
+fn boot_reset_catch_test() {
+    let (sim, dap, payload) = setup("reset");
+    let reset = sim.clone();
+    let value = boot(
+        core(&dap),
+        &dap,
+        ResetPolicy::ResetCatch,
+        || reset.reset(),
+        &payload,
+    )
+    .unwrap();
+    std::fs::remove_file(&payload.image).unwrap();
+    assert_eq!(0xcafe_f00d, value);
+    assert_eq!(1, sim.soc().resets);
+    // reset catchは戻してある
+    assert_eq!(0, sim.read(EDECR) & (1 << 1));
+    check_detached(&sim);
+}
+
+#[test]
+fn boot_error_test() {
+    // SRSTが無いadapter
+    let (sim, dap, payload) = setup("no_srst");
+    let error = boot(
+        core(&dap),
+        &dap,
+        ResetPolicy::ResetCatch,
+        || Err(InterfaceError::Unsupported),
+        &payload,
+    )
+    .unwrap_err();
+    assert_eq!(
+        Some(&DebugError::Interface(InterfaceError::Unsupported)),
+        error.downcast_ref::<DebugError>()
+    );
+    assert_eq!(0, sim.read(EDECR) & (1 << 1));
+
+    // entryを間違えるとdoneに来ない。breakpointを消して元のfirmwareに戻す
+    let (sim, dap, payload) = setup("wrong_entry");
+    let payload = Payload {
+        entry: PAYLOAD.entry + 4,
+        ..payload
+    };
+    let error = boot(core(&dap), &dap, ResetPolicy::Halt, || Ok(()), &payload).unwrap_err();
+    std::fs::remove_file(&payload.image).unwrap();
+    assert!(
+        matches!(
+            error.downcast_ref::<DebugError>(),
+            Some(DebugError::HaltTimeout { .. })
+        ),
+        "{:?}",
+        error
+    );
+    check_detached(&sim);
+}
+
+#[test]
+fn parse_args_test() {
+    let args = |text: &str| -> Vec<String> { text.split(' ').map(String::from).collect() };
+    let (config, payload) = parse_args(&args(
+        "--image a.bin --load 0x10000000 --entry 0x10000000 --done 0x10000010 --mailbox 4096",
+    ))
+    .unwrap();
+    assert_eq!(Config::builtin("arm-usb-ocd-h").unwrap(), config);
+    assert_eq!(
+        Payload {
+            image: "a.bin".into(),
+            mailbox: 0x1000,
+            ..PAYLOAD
+        },
+        payload
+    );
+    assert!(parse_args(&args("--image a.bin --load 0x1000")).is_err());
+    assert!(parse_args(&args("--image a.bin --load zz")).is_err());
+    assert!(parse_args(&args("--image")).is_err());
+}
@@ -0,0 +1,151 @@
+// SVFを読んだ分から流し、入力の大きさに比例したmemoryを使わないことを確かめる
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{self, BufReader, Read};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use libjtag::interface::{InterfaceError, JtagInterface};
+use libjtag::jtag::jtag::Jtag;
+use libjtag::jtag::svf::{SvfError, SvfPlayer, SvfProgress};
+use libjtag::jtag::JtagBit;
+
+// 確保中のbyte数と、その最大値を数えるallocator
+struct Counting;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let current = CURRENT.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+        PEAK.fetch_max(current, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        CURRENT.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+// 他のtestが確保した分を数えないように、1つずつ実行する
+static SERIAL: Mutex<()> = Mutex::new(());
+
+// fを実行する間に、始めより多く確保したbyte数の最大値
+fn peak_during<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    let base = CURRENT.load(Ordering::SeqCst);
+    PEAK.store(base, Ordering::SeqCst);
+    let result = f();
+    (result, PEAK.load(Ordering::SeqCst) - base)
+}
+
+// TDOは常に0を返すadapter
+struct Null;
+
+impl JtagInterface for Null {
+    fn raw_write(&self, _pins: &[JtagBit]) -> Result<(), InterfaceError> {
+        Ok(())
+    }
+    fn raw_read(&self, _pins: &mut [JtagBit]) -> Result<(), InterfaceError> {
+        Ok(())
+    }
+}
+
+// 同じ文を繰り返すSVFを、読まれる度に作る
+struct Repeat {
+    statement: &'static [u8],
+    count: usize,
+    offset: usize,
+    // 最後の文の代わり
+    last: &'static [u8],
+}
+
+impl Read for Repeat {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let statement = match self.count {
+            0 => return Ok(0),
+            1 => self.last,
+            _ => self.statement,
+        };
+        let length = buffer.len().min(statement.len() - self.offset);
+        buffer[..length].copy_from_slice(&statement[self.offset..self.offset + length]);
+        self.offset += length;
+        if self.offset == statement.len() {
+            self.offset = 0;
+            self.count -= 1;
+        }
+        Ok(length)
+    }
+}
+
+// 1行に1文のSVFを再生する
+fn play(
+    statement: &'static [u8],
+    count: usize,
+    last: &'static [u8],
+) -> (Result<usize, SvfError>, SvfProgress, usize) {
+    let mut jtag = Jtag::new(Null);
+    let reader = BufReader::new(Repeat {
+        statement,
+        count,
+        offset: 0,
+        last,
+    });
+    let mut progress = SvfProgress::default();
+    let (result, peak) = peak_during(|| {
+        SvfPlayer::new(&mut jtag).play_with_progress(reader, &mut |x| progress = *x)
+    });
+    (result, progress, peak)
+}
+
+#[test]
+fn many_commands_test() {
+    let _serial = SERIAL.lock().unwrap();
+    let statement = b"SDR 32 TDI (DEADBEEF) TDO (00000000) MASK (FFFFFFFF);\n";
+    let (result, progress, peak) = play(statement, 100_000, statement);
+    assert_eq!(100_000, result.unwrap());
+    assert_eq!(
+        SvfProgress {
+            commands: 100_000,
+            bits: 3_200_000,
+            line: 100_000,
+        },
+        progress
+    );
+    // 全体は5MB以上あるが、1文分しか確保しない
+    assert!(peak < 16 * 1024, "peak {} bytes", peak);
+}
+
+#[test]
+fn long_scan_test() {
+    let _serial = SERIAL.lock().unwrap();
+    // 1M bitのSDRを桁毎に読む。TDIとTDOをbyteに詰めた分だけ確保する
+    let bits = 1_000_000;
+    let mut text = format!("SDR {} TDI (", bits).into_bytes();
+    text.resize(text.len() + bits / 4, b'5');
+    text.extend_from_slice(b")\n  TDO (0);\n");
+    let text: &'static [u8] = Box::leak(text.into_boxed_slice());
+
+    let (result, progress, peak) = play(text, 1, text);
+    assert_eq!(1, result.unwrap());
+    assert_eq!(bits as u64, progress.bits);
+    // bool列なら1Mbyteずつ必要
+    assert!(peak < bits / 3, "peak {} bytes", peak);
+}
+
+#[test]
+fn syntax_error_test() {
+    let _serial = SERIAL.lock().unwrap();
+    let statement = b"SIR 8 TDI (A5);\n";
+    let (result, progress, _) = play(statement, 50_000, b"  SDR 8 TDI (A5) TDX (00);\n");
+    // 50000行目の3文字目から始まるcommand
+    assert_eq!(
+        "line 50000, column 3: unknown parameter TDX",
+        result.unwrap_err().to_string()
+    );
+    assert_eq!(49_999, progress.commands);
+    assert_eq!(49_999, progress.line);
+}